@@ -178,9 +178,8 @@ impl Hosts {
             )
         })?;
         let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
+        // A clock set before the epoch only makes the name less unique.
+        let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap_or_default();
         let mut temp_filename = hosts_path
             .file_name()
             .ok_or_else(|| {
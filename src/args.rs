@@ -3,7 +3,7 @@ use crate::standard::{
     DEFAULT_INTERFACE_ADDRESS, DEFAULT_INTERFACE_LISTEN_PORT, DEFAULT_MTU,
     DEFAULT_PEER_ENDPOINT_ALLOWED_IPS, DEFAULT_PEER_PERSISTENT_KEEPALIVE,
 };
-use clap::{Args, Subcommand};
+use clap::{Args, Subcommand, ValueEnum};
 use ipnet::IpNet;
 use std::path::PathBuf;
 
@@ -40,6 +40,14 @@ pub(crate) enum SubCommands {
     /// Print WireGuard configuration
     PrintPeer,
 
+    /// Set WireGuard peers of an interface
+    #[command(arg_required_else_help = true)]
+    Set(SetPeers),
+
+    /// Add a client to an interface, printing its configuration and QR code
+    #[command(arg_required_else_help = true)]
+    AddClient(AddClient),
+
     Up,
 
     Down,
@@ -131,3 +139,64 @@ pub(crate) struct AddPeer {
     #[arg(long)]
     pub pre_down: Option<String>,
 }
+
+#[derive(Args)]
+pub(crate) struct SetPeers {
+    /// Interface's name
+    #[arg(long, short)]
+    pub name: String,
+
+    /// Read the peers from standard input
+    #[arg(long, required = true)]
+    pub stdin: bool,
+
+    /// Format of the peers: JSON lines or the `wg setconf` format
+    #[arg(long, value_enum, default_value_t = PeerFormat::Auto)]
+    pub format: PeerFormat,
+
+    /// Replace the interface's peers instead of adding to them
+    #[arg(long)]
+    pub replace: bool,
+}
+
+#[allow(unused_qualifications)]
+#[derive(Args)]
+pub(crate) struct AddClient {
+    /// Interface's name
+    #[arg(long, short)]
+    pub name: String,
+
+    /// Client's name, kept in its configuration
+    #[arg(long)]
+    pub client_name: Option<String>,
+
+    /// Where the client reaches the interface, e.g. vpn.example.com:51820
+    #[arg(long, value_name = "HOST:PORT")]
+    pub endpoint: String,
+
+    /// Networks to allocate the client's addresses from, one address each
+    #[arg(long, short, required = true, value_parser = parser::parser_address_in_range)]
+    pub pool: std::vec::Vec<IpNet>,
+
+    /// Client's AllowedIPs
+    #[arg(long, default_value = DEFAULT_PEER_ENDPOINT_ALLOWED_IPS, value_parser = parser::parser_address_in_range)]
+    pub allowed_ips: std::vec::Vec<IpNet>,
+
+    /// Client's DNS servers
+    #[arg(long)]
+    pub dns: Vec<String>,
+
+    /// Client's persistent keepalive, 0 to disable
+    #[arg(long, default_value = DEFAULT_PEER_PERSISTENT_KEEPALIVE)]
+    pub persistent_keepalive: u16,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum PeerFormat {
+    /// JSON lines if the input starts with `{`, otherwise `wg setconf`
+    Auto,
+    /// One peer per line, with the fields of `PeerConfig`
+    Json,
+    /// `[Peer]` sections, as read by `wg setconf`
+    Setconf,
+}
@@ -1,6 +1,11 @@
 use crate::args;
 
 use anyhow::Context;
+use tokio::io::AsyncReadExt;
+use wireguard_uapi::{
+    provision::{self, ClientOptions},
+    Backend, DeviceUpdate, InterfaceName, PeerConfig, PeerConfigBuilder,
+};
 
 use std::path::PathBuf;
 
@@ -29,6 +34,74 @@ pub(crate) async fn subcommand_print_peer_handler(_config: PathBuf) -> anyhow::R
     Ok(())
 }
 
+pub(crate) async fn subcommand_set_handler(set: args::SetPeers) -> anyhow::Result<()> {
+    crate::sudo()?;
+    let name: InterfaceName = set.name.parse().context("Invalid interface name")?;
+    let mut input = String::new();
+    tokio::io::stdin()
+        .read_to_string(&mut input)
+        .await
+        .context("Failed to read peers from standard input")?;
+    let peers = parse_peers(&input, set.format)?;
+
+    let count = peers.len();
+    let mut update = DeviceUpdate::new();
+    if set.replace {
+        update = update.replace_peers();
+    }
+    let peers: Vec<_> = peers
+        .into_iter()
+        .map(PeerConfigBuilder::from_peer_config)
+        .collect();
+    update
+        .add_peers(&peers)
+        .apply(&name, Backend::auto())
+        .with_context(|| format!("Failed to set peers of {}", name))?;
+    log::info!("set {} peer(s) on {}", count, name);
+    Ok(())
+}
+
+pub(crate) async fn subcommand_add_client_handler(add: args::AddClient) -> anyhow::Result<()> {
+    crate::sudo()?;
+    let name: InterfaceName = add.name.parse().context("Invalid interface name")?;
+    let options = ClientOptions {
+        name: add.client_name,
+        allowed_ips: add.allowed_ips,
+        dns: add.dns,
+        persistent_keepalive: Some(add.persistent_keepalive).filter(|keepalive| *keepalive != 0),
+        backend: Backend::auto(),
+        ..ClientOptions::new(&add.endpoint, add.pool)
+    };
+    let client = provision::add_client(&name, &options)
+        .with_context(|| format!("Failed to add a client to {}", name))?;
+    log::info!(
+        "added client {} to {}",
+        client.keypair.public.to_base64(),
+        name
+    );
+    print_and_qrcode(client.config_string())
+}
+
+// peer list parser, JSON lines or `wg setconf`
+fn parse_peers(input: &str, format: args::PeerFormat) -> anyhow::Result<Vec<PeerConfig>> {
+    let json = match format {
+        args::PeerFormat::Auto => input.trim_start().starts_with('{'),
+        format => format == args::PeerFormat::Json,
+    };
+    if !json {
+        return wireguard_uapi::conf::parse_peers(input).context("Invalid peer list");
+    }
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid peer on line {}", index + 1))
+        })
+        .collect()
+}
+
 fn print_and_qrcode(string: String) -> anyhow::Result<()> {
     let repeat_bounds = "-".repeat(70);
     println!(
@@ -37,3 +110,20 @@ fn print_and_qrcode(string: String) -> anyhow::Result<()> {
     );
     qr2term::print_qr(string).context("Failed to generate QRCode configuration")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_peers_json() {
+        let input = r#"{"public_key":"xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=","endpoint":"192.0.2.1:51820"}"#;
+        let peers = parse_peers(input, args::PeerFormat::Auto).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].endpoint, Some("192.0.2.1:51820".parse().unwrap()));
+        assert!(peers[0].allowed_ips.is_empty());
+
+        let error = parse_peers("{}\n", args::PeerFormat::Json).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid peer on line 1");
+    }
+}
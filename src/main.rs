@@ -37,6 +37,17 @@ async fn main() -> anyhow::Result<(), Box<dyn std::error::Error>> {
     let wgsdc = args::Opt::parse();
     // enabled debug mode
     init_log(wgsdc.debug);
+    match wgsdc.commands {
+        Some(args::SubCommands::Set(set)) => {
+            handler::subcommand_set_handler(set).await?;
+            return Ok(());
+        }
+        Some(args::SubCommands::AddClient(add_client)) => {
+            handler::subcommand_add_client_handler(add_client).await?;
+            return Ok(());
+        }
+        _ => {}
+    }
     // match wgsdc.commands {
     //     Some(SubCommands::New(add_interface)) => {
     //         handler::subcommand_new_handler(add_interface, wgsdc.dir).await?
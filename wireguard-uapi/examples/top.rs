@@ -0,0 +1,11 @@
+use std::time::Duration;
+use wireguard_uapi::{tui, Backend};
+
+#[cfg(target_os = "linux")]
+const BACKEND: Backend = Backend::Kernel;
+#[cfg(not(target_os = "linux"))]
+const BACKEND: Backend = Backend::Userspace;
+
+fn main() {
+    tui::run(BACKEND, Duration::from_secs(1)).unwrap();
+}
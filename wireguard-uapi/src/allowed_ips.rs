@@ -0,0 +1,253 @@
+//! Prefix math on lists of [`AllowedIp`]s, and conversions to the common prefix
+//! types of the ecosystem (`ipnet`, and `cidr` with the `cidr` feature).
+use crate::AllowedIp;
+
+use ipnet::IpNet;
+use std::{convert::TryFrom, fmt, io, net::IpAddr};
+
+/// An allowed IP had a CIDR mask longer than its address family allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCidr(pub AllowedIp);
+
+impl fmt::Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid CIDR mask /{} for {} (max /{})",
+            self.0.cidr,
+            self.0.address,
+            max_cidr(&self.0.address)
+        )
+    }
+}
+
+impl std::error::Error for InvalidCidr {}
+
+impl From<InvalidCidr> for io::Error {
+    fn from(e: InvalidCidr) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+    }
+}
+
+fn max_cidr(address: &IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// A prefix in a family-independent form: the address bits left-aligned in a `u128`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Prefix {
+    bits: u128,
+    cidr: u8,
+}
+
+impl Prefix {
+    fn mask(cidr: u8) -> u128 {
+        u128::MAX.checked_shl(128 - cidr as u32).unwrap_or(0)
+    }
+
+    fn contains(&self, other: &Prefix) -> bool {
+        self.cidr <= other.cidr && other.bits & Self::mask(self.cidr) == self.bits
+    }
+
+    /// Returns the parent prefix if `self` and `other` are the two halves of it.
+    fn merge(&self, other: &Prefix) -> Option<Prefix> {
+        if self.cidr != other.cidr || self.cidr == 0 {
+            return None;
+        }
+        let parent = Prefix {
+            bits: self.bits & Self::mask(self.cidr - 1),
+            cidr: self.cidr - 1,
+        };
+        let sibling_bit = 1u128 << (128 - self.cidr as u32);
+        (self.bits == parent.bits && other.bits == parent.bits | sibling_bit).then_some(parent)
+    }
+}
+
+/// Returns `ip` with all host bits (those past the CIDR mask) cleared,
+/// e.g. `10.1.2.3/8` becomes `10.0.0.0/8`.
+pub fn normalize(ip: &AllowedIp) -> Result<AllowedIp, InvalidCidr> {
+    if ip.cidr > max_cidr(&ip.address) {
+        return Err(InvalidCidr(ip.clone()));
+    }
+    let address = match ip.address {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - ip.cidr as u32).unwrap_or(0);
+            IpAddr::V4((u32::from(addr) & mask).into())
+        }
+        IpAddr::V6(addr) => IpAddr::V6((u128::from(addr) & Prefix::mask(ip.cidr)).into()),
+    };
+    Ok(AllowedIp::new(address, ip.cidr))
+}
+
+fn aggregate_family(mut prefixes: Vec<Prefix>) -> Vec<Prefix> {
+    prefixes.sort();
+    let mut merged: Vec<Prefix> = Vec::with_capacity(prefixes.len());
+    for prefix in prefixes {
+        if merged.last().is_some_and(|last| last.contains(&prefix)) {
+            continue;
+        }
+        merged.push(prefix);
+        // Collapse sibling halves into their parent for as long as possible.
+        while merged.len() >= 2 {
+            let len = merged.len();
+            match merged[len - 2].merge(&merged[len - 1]) {
+                Some(parent) => {
+                    merged.truncate(len - 2);
+                    merged.push(parent);
+                }
+                None => break,
+            }
+        }
+    }
+    merged
+}
+
+/// Normalizes and merges a list of allowed IPs into the smallest equivalent list.
+///
+/// Host bits are cleared, prefixes contained in a larger one are dropped, and
+/// adjacent halves are merged into their parent (`10.0.0.0/25` + `10.0.0.128/25`
+/// becomes `10.0.0.0/24`). IPv4 prefixes come first in the result, each family
+/// sorted by address.
+///
+/// Peers with thousands of routes produce much smaller kernel tries this way.
+/// Fails if any entry has a mask longer than its address family allows.
+pub fn aggregate(ips: &[AllowedIp]) -> Result<Vec<AllowedIp>, InvalidCidr> {
+    let mut v4 = vec![];
+    let mut v6 = vec![];
+    for ip in ips {
+        match normalize(ip)?.address {
+            IpAddr::V4(addr) => v4.push(Prefix {
+                bits: (u32::from(addr) as u128) << 96,
+                cidr: ip.cidr,
+            }),
+            IpAddr::V6(addr) => v6.push(Prefix {
+                bits: u128::from(addr),
+                cidr: ip.cidr,
+            }),
+        }
+    }
+
+    let v4 = aggregate_family(v4)
+        .into_iter()
+        .map(|prefix| AllowedIp::new(IpAddr::V4(((prefix.bits >> 96) as u32).into()), prefix.cidr));
+    let v6 = aggregate_family(v6)
+        .into_iter()
+        .map(|prefix| AllowedIp::new(IpAddr::V6(prefix.bits.into()), prefix.cidr));
+    Ok(v4.chain(v6).collect())
+}
+
+impl From<IpNet> for AllowedIp {
+    fn from(net: IpNet) -> Self {
+        AllowedIp::new(net.addr(), net.prefix_len())
+    }
+}
+
+impl TryFrom<AllowedIp> for IpNet {
+    type Error = InvalidCidr;
+
+    /// Host bits are kept as they are; use [`normalize`] first to clear them.
+    fn try_from(ip: AllowedIp) -> Result<Self, Self::Error> {
+        IpNet::new(ip.address, ip.cidr).map_err(|_| InvalidCidr(ip))
+    }
+}
+
+#[cfg(feature = "cidr")]
+impl TryFrom<cidr::AnyIpCidr> for AllowedIp {
+    type Error = cidr::AnyIpCidr;
+
+    /// Fails (returning the input) for `AnyIpCidr::Any`, which spans both address
+    /// families and needs [`AllowedIp::ALL_V4`] and [`AllowedIp::ALL_V6`] together.
+    fn try_from(cidr: cidr::AnyIpCidr) -> Result<Self, Self::Error> {
+        match (cidr.first_address(), cidr.network_length()) {
+            (Some(address), Some(len)) => Ok(AllowedIp::new(address, len)),
+            _ => Err(cidr),
+        }
+    }
+}
+
+#[cfg(feature = "cidr")]
+impl TryFrom<AllowedIp> for cidr::AnyIpCidr {
+    type Error = InvalidCidr;
+
+    /// `cidr` rejects networks with host bits set; use [`normalize`] first to clear them.
+    fn try_from(ip: AllowedIp) -> Result<Self, Self::Error> {
+        cidr::AnyIpCidr::new(ip.address, ip.cidr).map_err(|_| InvalidCidr(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(list: &[&str]) -> Vec<AllowedIp> {
+        list.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_normalize_host_bits() {
+        assert_eq!(
+            normalize(&"10.1.2.3/8".parse().unwrap()),
+            Ok("10.0.0.0/8".parse().unwrap())
+        );
+        assert_eq!(
+            normalize(&"fd00::1/64".parse().unwrap()),
+            Ok("fd00::/64".parse().unwrap())
+        );
+        assert_eq!(
+            normalize(&"1.2.3.4/0".parse().unwrap()),
+            Ok("0.0.0.0/0".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_invalid_mask() {
+        let bad: AllowedIp = "10.0.0.1/33".parse().unwrap();
+        assert_eq!(aggregate(std::slice::from_ref(&bad)), Err(InvalidCidr(bad)));
+    }
+
+    #[test]
+    fn test_aggregate_merges_siblings_and_contained() {
+        let input = ips(&[
+            "10.0.0.128/25",
+            "10.0.0.0/25",
+            "10.0.1.0/24",
+            "10.0.1.7/32",
+            "192.168.1.1/32",
+            "fd00::/65",
+            "fd00:0:0:0:8000::/65",
+        ]);
+        assert_eq!(
+            aggregate(&input).unwrap(),
+            ips(&["10.0.0.0/23", "192.168.1.1/32", "fd00::/64"])
+        );
+    }
+
+    #[test]
+    fn test_ipnet_roundtrip() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        let ip = AllowedIp::from(net);
+        assert_eq!(ip, "10.1.0.0/16".parse().unwrap());
+        assert_eq!(IpNet::try_from(ip), Ok(net));
+        assert!(IpNet::try_from("::/129".parse::<AllowedIp>().unwrap()).is_err());
+    }
+
+    #[cfg(feature = "cidr")]
+    #[test]
+    fn test_cidr_roundtrip() {
+        let cidr: cidr::AnyIpCidr = "fd00::/64".parse().unwrap();
+        let ip = AllowedIp::try_from(cidr).unwrap();
+        assert_eq!(ip, "fd00::/64".parse().unwrap());
+        assert_eq!(cidr::AnyIpCidr::try_from(ip), Ok(cidr));
+        assert!(AllowedIp::try_from(cidr::AnyIpCidr::Any).is_err());
+        assert!(cidr::AnyIpCidr::try_from("10.0.0.1/8".parse::<AllowedIp>().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_aggregate_default_routes() {
+        let input = ips(&["0.0.0.0/1", "128.0.0.0/1", "::/0", "fd00::/8"]);
+        assert_eq!(aggregate(&input).unwrap(), ips(&["0.0.0.0/0", "::/0"]));
+    }
+}
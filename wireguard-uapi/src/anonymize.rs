@@ -0,0 +1,163 @@
+//! Anonymizing devices and statistics before sharing them.
+//!
+//! An [`Anonymizer`] replaces public keys with salted hashes, truncates endpoints
+//! to their /24 (IPv4) or /48 (IPv6) network and drops private and preshared
+//! keys, so dumps and session exports can go into bug reports or to third parties
+//! without identifying peers. The same salt maps a key to the same hash, so
+//! records of one peer can still be correlated within an export; a
+//! [random](Anonymizer::random) salt keeps them from being correlated across
+//! exports.
+use crate::{report::Report, sessions::SessionRecord, Device, Key};
+
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Anonymizes keys and endpoints with a salt.
+#[derive(Clone)]
+pub struct Anonymizer {
+    salt: Vec<u8>,
+}
+
+impl std::fmt::Debug for Anonymizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The salt is what keeps the hashes from being reversed by brute force.
+        f.debug_struct("Anonymizer").finish_non_exhaustive()
+    }
+}
+
+impl Anonymizer {
+    /// Hashes with `salt`. Keep it secret: anyone knowing it can check whether a
+    /// hash belongs to a given public key.
+    pub fn new(salt: &[u8]) -> Self {
+        Self {
+            salt: salt.to_vec(),
+        }
+    }
+
+    /// Hashes with a salt from the OS RNG.
+    pub fn random() -> Self {
+        let mut salt = [0u8; 32];
+        OsRng.fill_bytes(&mut salt);
+        Self::new(&salt)
+    }
+
+    /// The salted hash of `key`, as a key so it serializes like one.
+    pub fn key(&self, key: &Key) -> Key {
+        Key(Sha256::new()
+            .chain(&self.salt)
+            .chain(key.as_bytes())
+            .finalize()
+            .into())
+    }
+
+    /// The network `endpoint` is in, with the host bits and port cleared.
+    pub fn endpoint(&self, endpoint: SocketAddr) -> SocketAddr {
+        let ip = match endpoint.ip() {
+            IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & !0u32 << 8)),
+            IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !0u128 << 80)),
+        };
+        SocketAddr::new(ip, 0)
+    }
+
+    /// A copy of `device` with its keys and endpoints anonymized. The private key
+    /// and preshared keys are dropped.
+    pub fn device(&self, device: &Device) -> Device {
+        let mut device = device.clone();
+        device.public_key = device.public_key.as_ref().map(|key| self.key(key));
+        device.private_key = None;
+        for peer in &mut device.peers {
+            peer.config.public_key = self.key(&peer.config.public_key);
+            peer.config.preshared_key = None;
+            peer.config.endpoint = peer.config.endpoint.map(|endpoint| self.endpoint(endpoint));
+        }
+        device
+    }
+
+    /// A copy of `record` with its key and endpoint anonymized.
+    pub fn session(&self, record: &SessionRecord) -> SessionRecord {
+        SessionRecord {
+            public_key: self.key(&record.public_key),
+            endpoint: record.endpoint.map(|endpoint| self.endpoint(endpoint)),
+            ..record.clone()
+        }
+    }
+
+    /// A copy of `report` with its keys anonymized.
+    pub fn report(&self, report: &Report) -> Report {
+        let mut report = report.clone();
+        for peer in &mut report.peers {
+            peer.public_key = self.key(&peer.public_key);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerInfo;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_key() {
+        let key = Key([1u8; 32]);
+        let anonymizer = Anonymizer::new(b"salt");
+        assert_eq!(anonymizer.key(&key), anonymizer.key(&key));
+        assert_ne!(anonymizer.key(&key), key);
+        assert_ne!(anonymizer.key(&key), Anonymizer::new(b"pepper").key(&key));
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let anonymizer = Anonymizer::random();
+        assert_eq!(
+            anonymizer.endpoint("192.0.2.123:51820".parse().unwrap()),
+            "192.0.2.0:0".parse().unwrap()
+        );
+        assert_eq!(
+            anonymizer.endpoint("[2001:db8:1234:5678::1]:51820".parse().unwrap()),
+            "[2001:db8:1234::]:0".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_device_and_session() {
+        let anonymizer = Anonymizer::new(b"salt");
+        let peer_key = Key([2u8; 32]);
+        let mut peer = PeerInfo::fixture(peer_key.clone())
+            .with_endpoint("198.51.100.7:4500")
+            .with_allowed_ips(&["10.0.0.2/32"]);
+        peer.config.preshared_key = Some(Key([4u8; 32]));
+        peer.config.persistent_keepalive_interval = Some(25);
+        let device = Device {
+            public_key: Some(Key([1u8; 32])),
+            private_key: Some(Key([3u8; 32])),
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", vec![peer])
+        };
+        let anonymized = anonymizer.device(&device);
+        assert_eq!(anonymized.private_key, None);
+        assert_eq!(anonymized.public_key, Some(anonymizer.key(&Key([1u8; 32]))));
+        let peer = &anonymized.peers[0].config;
+        assert_eq!(peer.public_key, anonymizer.key(&peer_key));
+        assert_eq!(peer.preshared_key, None);
+        assert_eq!(peer.endpoint, Some("198.51.100.0:0".parse().unwrap()));
+        assert_eq!(peer.allowed_ips, device.peers[0].config.allowed_ips);
+
+        let record = SessionRecord {
+            iface: "wg0".parse().unwrap(),
+            public_key: peer_key.clone(),
+            endpoint: Some("198.51.100.7:4500".parse().unwrap()),
+            start: UNIX_EPOCH,
+            end: UNIX_EPOCH,
+            rx_bytes: 1,
+            tx_bytes: 2,
+        };
+        let anonymized = anonymizer.session(&record);
+        // The same peer hashes the same across exports with one salt.
+        assert_eq!(anonymized.public_key, peer.public_key);
+        assert_eq!(anonymized.endpoint, peer.endpoint);
+        assert_eq!(anonymized.rx_bytes, 1);
+    }
+}
@@ -0,0 +1,937 @@
+use crate::{
+    allowed_ips, backup::DeviceBackup, AllowedIp, AllowedIpConflicts, Backend, Device,
+    DeviceUpdate, DuplicatePeers, Error, InterfaceName, Key, PeerConfig, PeerConfigBuilder,
+    PeerInfo,
+};
+
+use std::{
+    collections::{HashMap, HashSet},
+    error, fmt, io,
+    net::IpAddr,
+};
+
+/// Identifies a peer within a [`DeviceUpdate`] by its position and public key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerRef {
+    /// Position of the peer in the update, starting at 0.
+    pub index: usize,
+    /// Public key of the peer.
+    pub public_key: Key,
+}
+
+impl fmt::Display for PeerRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = self.public_key.to_base64();
+        write!(f, "peer #{} (pubkey {}…)", self.index, &key[..8])
+    }
+}
+
+/// The part of a [`DeviceUpdate`] an [`ApplyError`] was attributed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyField {
+    PublicKey,
+    Endpoint,
+    AllowedIps,
+}
+
+impl fmt::Display for ApplyField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::PublicKey => "public key",
+            Self::Endpoint => "endpoint",
+            Self::AllowedIps => "allowed ips",
+        })
+    }
+}
+
+/// An apply failure mapped back to the offending peer and field, where possible.
+///
+/// Displays as e.g. `peer #3 (pubkey q3NPYzg9…): endpoint: invalid port 0`.
+#[derive(Debug)]
+pub struct ApplyError {
+    /// The peer the failure was attributed to, `None` for interface-level failures
+    /// or when attribution wasn't possible.
+    pub peer: Option<PeerRef>,
+    /// The field the failure was attributed to, if known.
+    pub field: Option<ApplyField>,
+    /// The underlying error.
+    pub source: io::Error,
+}
+
+impl ApplyError {
+    fn new(source: io::Error) -> Self {
+        Self {
+            peer: None,
+            field: None,
+            source,
+        }
+    }
+
+    pub(crate) fn invalid(peer: PeerRef, field: ApplyField, message: String) -> Self {
+        Self {
+            peer: Some(peer),
+            field: Some(field),
+            source: io::Error::new(io::ErrorKind::InvalidInput, message),
+        }
+    }
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.peer, &self.field) {
+            (Some(peer), Some(field)) => write!(f, "{}: {}: {}", peer, field, self.source),
+            (Some(peer), None) => write!(f, "{}: {}", peer, self.source),
+            (None, _) => write!(f, "{}", self.source),
+        }
+    }
+}
+
+impl error::Error for ApplyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<io::Error> for ApplyError {
+    fn from(source: io::Error) -> Self {
+        Self::new(source)
+    }
+}
+
+impl From<ApplyError> for io::Error {
+    fn from(e: ApplyError) -> Self {
+        io::Error::new(e.source.kind(), e.to_string())
+    }
+}
+
+fn validate_peer(index: usize, peer: &PeerConfigBuilder) -> Result<(), ApplyError> {
+    let peer_ref = || PeerRef {
+        index,
+        public_key: peer.public_key.clone(),
+    };
+
+    if let Some(endpoint) = peer.endpoint {
+        if endpoint.port() == 0 {
+            return Err(ApplyError::invalid(
+                peer_ref(),
+                ApplyField::Endpoint,
+                format!("invalid port 0 in {}", endpoint),
+            ));
+        }
+        if endpoint.ip().is_unspecified() || endpoint.ip().is_multicast() {
+            return Err(ApplyError::invalid(
+                peer_ref(),
+                ApplyField::Endpoint,
+                format!("{} is not a unicast address", endpoint.ip()),
+            ));
+        }
+    }
+
+    for ip in &peer.allowed_ips {
+        if let Err(e) = allowed_ips::normalize(ip) {
+            return Err(ApplyError::invalid(
+                peer_ref(),
+                ApplyField::AllowedIps,
+                e.to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Fails on the first peer whose public key appeared earlier in `peers`.
+fn check_duplicates(peers: &[PeerConfigBuilder]) -> Result<(), ApplyError> {
+    let mut first = HashMap::new();
+    for (index, peer) in peers.iter().enumerate() {
+        if let Some(first) = first.insert(&peer.public_key, index) {
+            return Err(ApplyError::invalid(
+                PeerRef {
+                    index,
+                    public_key: peer.public_key.clone(),
+                },
+                ApplyField::PublicKey,
+                format!("duplicate of peer #{}", first),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Folds `later` into `earlier` as if both were applied in order. `earlier` must
+/// not be a removal: a peer added after one starts over, which takes both.
+fn merge_peer(earlier: &mut PeerConfigBuilder, later: PeerConfigBuilder) {
+    if later.remove_me {
+        *earlier = later;
+        return;
+    }
+
+    if later.preshared_key.is_some() {
+        earlier.preshared_key = later.preshared_key;
+    }
+    if later.endpoint.is_some() {
+        earlier.endpoint = later.endpoint;
+    }
+    if later.persistent_keepalive_interval.is_some() {
+        earlier.persistent_keepalive_interval = later.persistent_keepalive_interval;
+    }
+    if later.replace_allowed_ips {
+        earlier.allowed_ips = later.allowed_ips;
+        earlier.replace_allowed_ips = true;
+    } else {
+        for ip in later.allowed_ips {
+            if !earlier.allowed_ips.contains(&ip) {
+                earlier.allowed_ips.push(ip);
+            }
+        }
+    }
+}
+
+/// The prefix of `ip` with host bits cleared, for comparing allowed IPs.
+fn prefix(ip: &AllowedIp) -> (IpAddr, u8) {
+    let ip = allowed_ips::normalize(ip).unwrap_or_else(|_| ip.clone());
+    (ip.address, ip.cidr)
+}
+
+impl DeviceUpdate {
+    /// Checks the update for values every backend would reject, attributing the
+    /// first problem found to its peer and field.
+    ///
+    /// With [`DuplicatePeers::Error`], repeated public keys are reported too.
+    pub fn validate(&self) -> Result<(), ApplyError> {
+        if self.duplicate_peers == DuplicatePeers::Error {
+            check_duplicates(&self.peers)?;
+        }
+        self.peers
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, peer)| validate_peer(index, peer))
+    }
+
+    /// Leaves one peer per public key, as set by
+    /// [`on_duplicate_peers`](DeviceUpdate::on_duplicate_peers). When merging, a
+    /// peer added after its removal follows the removal instead, since only
+    /// removing the peer clears its endpoint.
+    pub(crate) fn resolve_duplicate_peers(mut self) -> Result<Self, ApplyError> {
+        if self.duplicate_peers == DuplicatePeers::Error {
+            check_duplicates(&self.peers)?;
+            return Ok(self);
+        }
+
+        let mut peers: Vec<PeerConfigBuilder> = Vec::with_capacity(self.peers.len());
+        let mut positions: HashMap<Key, usize> = HashMap::new();
+        for peer in std::mem::take(&mut self.peers) {
+            match positions.get(&peer.public_key) {
+                Some(&position) => match self.duplicate_peers {
+                    DuplicatePeers::LastWins => peers[position] = peer,
+                    _ if peers[position].remove_me && !peer.remove_me => {
+                        positions.insert(peer.public_key.clone(), peers.len());
+                        peers.push(peer);
+                    }
+                    _ => merge_peer(&mut peers[position], peer),
+                },
+                None => {
+                    positions.insert(peer.public_key.clone(), peers.len());
+                    peers.push(peer);
+                }
+            }
+        }
+        self.peers = peers;
+        Ok(self)
+    }
+
+    /// Enforces the [`on_allowed_ip_conflict`](DeviceUpdate::on_allowed_ip_conflict)
+    /// policy against `current`, the interface as it is before the update.
+    pub(crate) fn resolve_allowed_ip_conflicts(
+        mut self,
+        current: Option<&Device>,
+    ) -> Result<Self, ApplyError> {
+        if self.allowed_ip_conflicts == AllowedIpConflicts::Steal {
+            return Ok(self);
+        }
+
+        let mut owners = HashMap::new();
+        if let (Some(device), false) = (current, self.replace_peers) {
+            let released: HashSet<&Key> = self
+                .peers
+                .iter()
+                .filter(|peer| peer.remove_me || peer.replace_allowed_ips)
+                .map(|peer| &peer.public_key)
+                .collect();
+            for peer in &device.peers {
+                if released.contains(&peer.config.public_key) {
+                    continue;
+                }
+                for ip in &peer.config.allowed_ips {
+                    owners.insert(prefix(ip), peer.config.public_key.clone());
+                }
+            }
+        }
+
+        let policy = self.allowed_ip_conflicts;
+        for (index, peer) in self.peers.iter_mut().enumerate() {
+            if peer.remove_me {
+                continue;
+            }
+            let mut kept = Vec::with_capacity(peer.allowed_ips.len());
+            for ip in std::mem::take(&mut peer.allowed_ips) {
+                match owners.get(&prefix(&ip)) {
+                    Some(owner) if *owner != peer.public_key => {
+                        let message = format!(
+                            "{}/{} is already routed to peer {}",
+                            ip.address,
+                            ip.cidr,
+                            owner.to_base64()
+                        );
+                        if policy == AllowedIpConflicts::Error {
+                            return Err(ApplyError::invalid(
+                                PeerRef {
+                                    index,
+                                    public_key: peer.public_key.clone(),
+                                },
+                                ApplyField::AllowedIps,
+                                message,
+                            ));
+                        }
+                        log::warn!("skipping allowed ip of peer #{}: {}", index, message);
+                    }
+                    _ => {
+                        owners.insert(prefix(&ip), peer.public_key.clone());
+                        kept.push(ip);
+                    }
+                }
+            }
+            peer.allowed_ips = kept;
+        }
+        Ok(self)
+    }
+
+    /// Like [`apply`](DeviceUpdate::apply), but maps failures back to the offending
+    /// peer and field where possible.
+    ///
+    /// The update is [validated](DeviceUpdate::validate) first. If the backend
+    /// still rejects it, the interface settings and then halves of the peer list
+    /// are re-applied separately to find the first peer that fails on its own.
+    /// Re-applying is harmless for additive updates, so this bisection is skipped
+    /// when [`replace_peers`](DeviceUpdate::replace_peers) is set, in which case
+    /// only the unattributed error is returned.
+    pub fn apply_checked(self, iface: &InterfaceName, backend: Backend) -> Result<(), ApplyError> {
+        self.validate()?;
+        let source = match self.clone().apply(iface, backend) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        if self.replace_peers || self.peers.is_empty() {
+            return Err(ApplyError::new(source.into()));
+        }
+
+        log::debug!(
+            "apply to {} failed ({}), bisecting {} peer(s)",
+            iface,
+            source,
+            self.peers.len()
+        );
+
+        let interface_only = DeviceUpdate {
+            peers: vec![],
+            ..self.clone()
+        };
+        if let Err(e) = interface_only.clone().apply(iface, backend) {
+            return Err(ApplyError::new(e.into()));
+        }
+
+        let peers: Vec<_> = self.peers.into_iter().enumerate().collect();
+        match bisect(&peers, &interface_only, iface, backend) {
+            Some(error) => Err(error),
+            None => Err(ApplyError::new(source.into())),
+        }
+    }
+}
+
+/// `wanted` if it differs from `current`, treating `unset` like `None`.
+fn changed<T: PartialEq>(wanted: Option<T>, current: Option<T>, unset: T) -> Option<T> {
+    let wanted = wanted?;
+    let set = |value: &T| *value != unset;
+    match (set(&wanted), current.filter(set)) {
+        (false, None) => None,
+        (true, Some(current)) if current == wanted => None,
+        _ => Some(wanted),
+    }
+}
+
+/// The part of `peer` that changes `current`, or `None` if it changes nothing.
+///
+/// With `full`, as for peers of an update replacing them, settings the peer leaves
+/// out are reset instead of kept. The endpoint can't be unset, so it is kept.
+fn peer_changes(
+    peer: PeerConfigBuilder,
+    current: &PeerConfig,
+    full: bool,
+) -> Option<PeerConfigBuilder> {
+    let mut changes = PeerConfigBuilder::new(&peer.public_key);
+    let preshared_key = match (peer.preshared_key, full) {
+        (None, true) => Some(Key::zero()),
+        (key, _) => key,
+    };
+    changes.preshared_key = changed(preshared_key, current.preshared_key.clone(), Key::zero());
+    changes.endpoint = peer
+        .endpoint
+        .filter(|endpoint| Some(*endpoint) != current.endpoint);
+    let keepalive = match (peer.persistent_keepalive_interval, full) {
+        (None, true) => Some(0),
+        (interval, _) => interval,
+    };
+    changes.persistent_keepalive_interval =
+        changed(keepalive, current.persistent_keepalive_interval, 0);
+
+    let current_ips: HashSet<_> = current.allowed_ips.iter().map(prefix).collect();
+    if peer.replace_allowed_ips || full {
+        let wanted: HashSet<_> = peer.allowed_ips.iter().map(prefix).collect();
+        if wanted != current_ips {
+            changes.replace_allowed_ips = true;
+            changes.allowed_ips = peer.allowed_ips;
+        }
+    } else {
+        changes.allowed_ips = peer
+            .allowed_ips
+            .into_iter()
+            .filter(|ip| !current_ips.contains(&prefix(ip)))
+            .collect();
+    }
+
+    (changes != PeerConfigBuilder::new(&changes.public_key)).then_some(changes)
+}
+
+impl DeviceUpdate {
+    /// The part of the update that changes `current`, the interface as it is.
+    ///
+    /// Peers left unchanged are dropped. With
+    /// [`replace_peers`](DeviceUpdate::replace_peers), peers missing from the
+    /// update are removed one by one instead of replacing the whole list, and the
+    /// settings the remaining peers leave out are reset.
+    pub(crate) fn changes(self, current: &Device) -> Result<Self, ApplyError> {
+        let update = self.resolve_duplicate_peers()?;
+        let full = update.replace_peers;
+        let mut changes = DeviceUpdate {
+            public_key: changed(update.public_key, current.public_key.clone(), Key::zero()),
+            private_key: changed(update.private_key, current.private_key.clone(), Key::zero()),
+            fwmark: changed(update.fwmark, current.fwmark, 0),
+            // A random port that was already picked is kept.
+            listen_port: update
+                .listen_port
+                .filter(|port| Some(*port) != current.listen_port)
+                .filter(|port| *port != 0 || current.listen_port.is_none()),
+            peers: vec![],
+            replace_peers: false,
+            ..update
+        };
+
+        let by_key: HashMap<&Key, &PeerInfo> = current
+            .peers
+            .iter()
+            .map(|existing| (&existing.config.public_key, existing))
+            .collect();
+        let mut wanted = HashSet::new();
+        let mut removed = HashSet::new();
+        for peer in update.peers {
+            wanted.insert(peer.public_key.clone());
+            // A peer re-added after its removal is new again.
+            let existing = by_key
+                .get(&peer.public_key)
+                .filter(|_| !removed.contains(&peer.public_key));
+            if peer.remove_me {
+                removed.insert(peer.public_key.clone());
+            }
+            match existing {
+                None if peer.remove_me => {}
+                Some(_) if peer.remove_me => changes.peers.push(peer),
+                None => changes.peers.push(peer),
+                Some(existing) => changes
+                    .peers
+                    .extend(peer_changes(peer, &existing.config, full)),
+            }
+        }
+        if full {
+            for existing in &current.peers {
+                if !wanted.contains(&existing.config.public_key) {
+                    let mut peer = PeerConfigBuilder::new(&existing.config.public_key);
+                    peer.remove_me = true;
+                    changes.peers.push(peer);
+                }
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Applies only what the update changes on the running interface, like
+    /// `wg syncconf`.
+    ///
+    /// Unlike [`apply`](DeviceUpdate::apply) with
+    /// [`replace_peers`](DeviceUpdate::replace_peers), peers whose settings stay
+    /// the same are not touched, so their sessions survive. Interfaces that don't
+    /// exist yet are created with the whole update.
+    pub fn sync(self, iface: &InterfaceName, backend: Backend) -> Result<(), Error> {
+        if !Device::list(backend)?.contains(iface) {
+            return self.apply(iface, backend);
+        }
+        let current = Device::get(iface, backend)?;
+        let changes = self.changes(&current)?;
+        log::debug!(
+            "syncing {}: {} of {} peer(s) changed",
+            iface,
+            changes.peers.len(),
+            current.peers.len()
+        );
+        if changes.peers.is_empty()
+            && changes.public_key.is_none()
+            && changes.private_key.is_none()
+            && changes.fwmark.is_none()
+            && changes.listen_port.is_none()
+        {
+            return Ok(());
+        }
+        changes.apply(iface, backend)
+    }
+
+    /// Splits the update into the steps [`apply_staged`](DeviceUpdate::apply_staged)
+    /// applies to `current`, the interface as it is, in order. Steps that would
+    /// change nothing are left out.
+    pub fn stages(
+        self,
+        current: Option<&Device>,
+    ) -> Result<Vec<(ApplyStage, DeviceUpdate)>, ApplyError> {
+        let Some(current) = current else {
+            return Ok(vec![(ApplyStage::Create, self)]);
+        };
+        let mut interface = self.changes(current)?;
+        let peers = DeviceUpdate {
+            public_key: None,
+            private_key: None,
+            fwmark: None,
+            listen_port: None,
+            peers: std::mem::take(&mut interface.peers),
+            ..interface.clone()
+        };
+
+        let mut stages = vec![];
+        if !peers.peers.is_empty() {
+            stages.push((ApplyStage::Peers, peers));
+        }
+        if interface.public_key.is_some()
+            || interface.private_key.is_some()
+            || interface.fwmark.is_some()
+            || interface.listen_port.is_some()
+        {
+            stages.push((ApplyStage::Interface, interface));
+        }
+        Ok(stages)
+    }
+
+    /// Applies what the update changes, like [`sync`](DeviceUpdate::sync), in
+    /// steps that disturb established sessions as little as possible, returning
+    /// the steps taken.
+    ///
+    /// Peer changes come first, while the interface still listens where the other
+    /// peers expect it, and only touch the peers that change. Interface settings
+    /// come last: a new listen port moves every session at once, and the remote
+    /// peers follow it as soon as they receive a packet from the new port, which
+    /// peers with a persistent keepalive send right away. If the peer step fails,
+    /// the interface is left on its old port, so a partial failure never strands
+    /// peers on a port they don't know yet.
+    pub fn apply_staged(
+        self,
+        iface: &InterfaceName,
+        backend: Backend,
+    ) -> Result<Vec<ApplyStage>, Error> {
+        let current = if Device::list(backend)?.contains(iface) {
+            Some(Device::get(iface, backend)?)
+        } else {
+            None
+        };
+        let mut applied = vec![];
+        for (stage, update) in self.stages(current.as_ref())? {
+            log::debug!("applying {:?} stage to {}", stage, iface);
+            update.apply(iface, backend)?;
+            applied.push(stage);
+        }
+        Ok(applied)
+    }
+}
+
+impl Device {
+    /// The update that makes this device, as read from an interface, match
+    /// `desired`: the peers to add and remove, and the settings to change on the
+    /// others. Applying it leaves the peers that stay the same untouched.
+    ///
+    /// Interface settings `desired` leaves unset are kept, and so are endpoints,
+    /// which can't be unset.
+    pub fn diff(&self, desired: &Device) -> DeviceUpdate {
+        // Duplicate peers are merged, which can't fail.
+        DeviceBackup::from(desired)
+            .to_update()
+            .changes(self)
+            .expect("merging duplicate peers failed")
+    }
+}
+
+/// A step of [`DeviceUpdate::apply_staged`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyStage {
+    /// The interface didn't exist, and was created with the whole update.
+    Create,
+    /// Peers were added, changed or removed.
+    Peers,
+    /// The listen port, fwmark or keys of the interface were changed.
+    Interface,
+}
+
+/// Finds the first peer that fails to apply on its own. The peers are applied
+/// with the rest of `update`, so its duplicate and conflict policies still hold.
+fn bisect(
+    peers: &[(usize, PeerConfigBuilder)],
+    update: &DeviceUpdate,
+    iface: &InterfaceName,
+    backend: Backend,
+) -> Option<ApplyError> {
+    let subset = DeviceUpdate {
+        peers: peers.iter().map(|(_, peer)| peer.clone()).collect(),
+        ..update.clone()
+    };
+    let error = subset.apply(iface, backend).err()?;
+
+    match peers {
+        [(index, peer)] => Some(ApplyError {
+            peer: Some(PeerRef {
+                index: *index,
+                public_key: peer.public_key.clone(),
+            }),
+            field: None,
+            source: error.into(),
+        }),
+        _ => {
+            let (left, right) = peers.split_at(peers.len() / 2);
+            bisect(left, update, iface, backend).or_else(|| bisect(right, update, iface, backend))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AllowedIp;
+
+    #[test]
+    fn test_validate_attributes_peer_and_field() {
+        let good = PeerConfigBuilder::new(&Key([1u8; 32]))
+            .set_endpoint("192.0.2.1:51820".parse().unwrap());
+        let bad =
+            PeerConfigBuilder::new(&Key([2u8; 32])).set_endpoint("192.0.2.2:0".parse().unwrap());
+        let update = DeviceUpdate::new().add_peer(good).add_peer(bad);
+
+        let error = update.validate().unwrap_err();
+        assert_eq!(error.peer.as_ref().map(|peer| peer.index), Some(1));
+        assert_eq!(error.field, Some(ApplyField::Endpoint));
+        assert_eq!(
+            error.to_string(),
+            "peer #1 (pubkey AgICAgIC…): endpoint: invalid port 0 in 192.0.2.2:0"
+        );
+    }
+
+    #[test]
+    fn test_validate_allowed_ips() {
+        let peer = PeerConfigBuilder::new(&Key([3u8; 32]))
+            .add_allowed_ips(&[AllowedIp::new("10.0.0.0".parse().unwrap(), 40)]);
+        let error = DeviceUpdate::new().add_peer(peer).validate().unwrap_err();
+        assert_eq!(error.field, Some(ApplyField::AllowedIps));
+        assert_eq!(io::Error::from(error).kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_duplicate_peers() {
+        let ip = |s: &str| s.parse::<AllowedIp>().unwrap();
+        let first = PeerConfigBuilder::new(&Key([1u8; 32]))
+            .set_endpoint("192.0.2.1:51820".parse().unwrap())
+            .add_allowed_ips(&[ip("10.0.0.1/32")]);
+        let other = PeerConfigBuilder::new(&Key([2u8; 32]));
+        let second = PeerConfigBuilder::new(&Key([1u8; 32]))
+            .set_persistent_keepalive_interval(25)
+            .add_allowed_ips(&[ip("10.0.0.1/32"), ip("10.0.1.0/24")]);
+        let update = DeviceUpdate::new()
+            .add_peer(first)
+            .add_peer(other)
+            .add_peer(second.clone());
+
+        let merged = update.clone().resolve_duplicate_peers().unwrap();
+        assert_eq!(merged.peers.len(), 2);
+        assert_eq!(
+            merged.peers[0].endpoint,
+            Some("192.0.2.1:51820".parse().unwrap())
+        );
+        assert_eq!(merged.peers[0].persistent_keepalive_interval, Some(25));
+        assert_eq!(
+            merged.peers[0].allowed_ips,
+            vec![ip("10.0.0.1/32"), ip("10.0.1.0/24")]
+        );
+
+        let last = update
+            .clone()
+            .on_duplicate_peers(DuplicatePeers::LastWins)
+            .resolve_duplicate_peers()
+            .unwrap();
+        assert_eq!(last.peers[0], second);
+
+        let strict = update.on_duplicate_peers(DuplicatePeers::Error);
+        let error = strict.validate().unwrap_err();
+        assert_eq!(error.peer.as_ref().map(|peer| peer.index), Some(2));
+        assert_eq!(error.field, Some(ApplyField::PublicKey));
+        assert!(strict.resolve_duplicate_peers().is_err());
+    }
+
+    #[test]
+    fn test_allowed_ip_conflicts() {
+        use crate::PeerInfo;
+
+        let ip = |s: &str| s.parse::<AllowedIp>().unwrap();
+        let existing =
+            |key: u8, ips: &[&str]| PeerInfo::fixture(Key([key; 32])).with_allowed_ips(ips);
+        let device = Device::fixture(
+            "wg0",
+            vec![existing(1, &["10.0.1.0/24"]), existing(2, &["10.0.2.0/24"])],
+        );
+
+        // 10.0.1.7/24 is 10.0.1.0/24 once normalized; the /25 only overlaps.
+        let update = DeviceUpdate::new()
+            .add_peer(
+                PeerConfigBuilder::new(&Key([3u8; 32]))
+                    .add_allowed_ips(&[ip("10.0.1.7/24"), ip("10.0.2.0/25")]),
+            )
+            .add_peer(
+                PeerConfigBuilder::new(&Key([2u8; 32])).add_allowed_ips(&[ip("10.0.2.0/24")]),
+            );
+
+        let stolen = update
+            .clone()
+            .resolve_allowed_ip_conflicts(Some(&device))
+            .unwrap();
+        assert_eq!(stolen, update);
+
+        let error = update
+            .clone()
+            .on_allowed_ip_conflict(AllowedIpConflicts::Error)
+            .resolve_allowed_ip_conflicts(Some(&device))
+            .unwrap_err();
+        assert_eq!(error.peer.as_ref().map(|peer| peer.index), Some(0));
+        assert_eq!(error.field, Some(ApplyField::AllowedIps));
+
+        let skipped = update
+            .clone()
+            .on_allowed_ip_conflict(AllowedIpConflicts::Skip)
+            .resolve_allowed_ip_conflicts(Some(&device))
+            .unwrap();
+        assert_eq!(skipped.peers[0].allowed_ips, vec![ip("10.0.2.0/25")]);
+        assert_eq!(skipped.peers[1].allowed_ips, vec![ip("10.0.2.0/24")]);
+
+        // Removing the owner in the same update frees its prefix.
+        let moved = update
+            .remove_peer_by_key(&Key([1u8; 32]))
+            .on_allowed_ip_conflict(AllowedIpConflicts::Error)
+            .resolve_allowed_ip_conflicts(Some(&device));
+        assert!(moved.is_ok());
+    }
+
+    #[test]
+    fn test_sync_changes() {
+        use crate::PeerInfo;
+
+        let ip = |s: &str| s.parse::<AllowedIp>().unwrap();
+        let existing = |key: u8, ips: &[&str]| {
+            let mut peer = PeerInfo::fixture(Key([key; 32]))
+                .with_endpoint("192.0.2.1:51820")
+                .with_allowed_ips(ips);
+            peer.config.persistent_keepalive_interval = Some(25);
+            peer
+        };
+        let device = Device {
+            listen_port: Some(51820),
+            ..Device::fixture(
+                "wg0",
+                vec![
+                    existing(1, &["10.0.1.0/24"]),
+                    existing(2, &["10.0.2.0/24"]),
+                    existing(3, &["10.0.3.0/24"]),
+                ],
+            )
+        };
+        let peer = |key: u8, ips: &[AllowedIp]| {
+            PeerConfigBuilder::new(&Key([key; 32]))
+                .set_endpoint("192.0.2.1:51820".parse().unwrap())
+                .set_persistent_keepalive_interval(25)
+                .add_allowed_ips(ips)
+        };
+
+        // Peer 1 is unchanged, peer 2 gains a prefix, peer 3 is gone and peer 4 is new.
+        let changes = DeviceUpdate::new()
+            .set_listen_port(51820)
+            .replace_peers()
+            .add_peer(peer(1, &[ip("10.0.1.7/24")]))
+            .add_peer(peer(2, &[ip("10.0.2.0/24"), ip("10.0.5.0/24")]))
+            .add_peer(peer(4, &[ip("10.0.4.0/24")]))
+            .changes(&device)
+            .unwrap();
+        assert!(!changes.replace_peers);
+        assert_eq!(changes.listen_port, None);
+        let keys: Vec<_> = changes
+            .peers
+            .iter()
+            .map(|peer| peer.public_key.0[0])
+            .collect();
+        assert_eq!(keys, [2, 4, 3]);
+        assert!(changes.peers[0].replace_allowed_ips);
+        assert_eq!(changes.peers[0].endpoint, None);
+        assert_eq!(changes.peers[0].persistent_keepalive_interval, None);
+        assert!(changes.peers[2].remove_me);
+
+        // Without replacing, only what is set is compared.
+        let changes = DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(&Key([1u8; 32])).unset_persistent_keepalive())
+            .add_peer(PeerConfigBuilder::new(&Key([2u8; 32])).add_allowed_ips(&[ip("10.0.2.0/24")]))
+            .remove_peer_by_key(&Key([9u8; 32]))
+            .changes(&device)
+            .unwrap();
+        assert_eq!(changes.peers.len(), 1);
+        assert_eq!(changes.peers[0].persistent_keepalive_interval, Some(0));
+    }
+
+    #[test]
+    fn test_diff() {
+        use crate::PeerInfo;
+
+        let ip = |s: &str| s.parse::<AllowedIp>().unwrap();
+        let peer = |key: u8, keepalive: Option<u16>, ips: &[AllowedIp]| {
+            let mut peer = PeerInfo::fixture(Key([key; 32])).with_endpoint("192.0.2.1:51820");
+            peer.config.persistent_keepalive_interval = keepalive;
+            peer.config.allowed_ips = ips.to_vec();
+            peer
+        };
+        let device = |listen_port, peers| Device {
+            listen_port,
+            ..Device::fixture("wg0", peers)
+        };
+        let current = device(
+            Some(51820),
+            vec![
+                peer(1, Some(25), &[ip("10.0.1.0/24")]),
+                peer(2, None, &[ip("10.0.2.0/24")]),
+            ],
+        );
+        assert_eq!(current.diff(&current), DeviceUpdate::new());
+
+        let desired = device(
+            Some(51821),
+            vec![
+                peer(1, None, &[ip("10.0.1.0/24")]),
+                peer(3, None, &[ip("10.0.3.0/24")]),
+            ],
+        );
+        let update = current.diff(&desired);
+        assert_eq!(update.listen_port, Some(51821));
+        assert!(!update.replace_peers);
+        let keys: Vec<_> = update
+            .peers
+            .iter()
+            .map(|peer| peer.public_key.0[0])
+            .collect();
+        assert_eq!(keys, [1, 3, 2]);
+        assert_eq!(update.peers[0].persistent_keepalive_interval, Some(0));
+        assert!(update.peers[0].allowed_ips.is_empty());
+        assert_eq!(update.peers[1].allowed_ips, vec![ip("10.0.3.0/24")]);
+        assert!(update.peers[2].remove_me);
+    }
+
+    #[test]
+    fn test_stages() {
+        use crate::PeerInfo;
+
+        let device = Device {
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", vec![PeerInfo::fixture(Key([1u8; 32]))])
+        };
+        let update = DeviceUpdate::new()
+            .set_listen_port(4500)
+            .add_peer(PeerConfigBuilder::new(&Key([1u8; 32])))
+            .add_peer(PeerConfigBuilder::new(&Key([2u8; 32])));
+
+        let stages = update.clone().stages(Some(&device)).unwrap();
+        assert_eq!(stages.len(), 2);
+        let (stage, peers) = &stages[0];
+        assert_eq!(*stage, ApplyStage::Peers);
+        assert_eq!(peers.listen_port, None);
+        // The unchanged peer is left alone.
+        assert_eq!(peers.peers.len(), 1);
+        assert_eq!(peers.peers[0].public_key, Key([2u8; 32]));
+        let (stage, interface) = &stages[1];
+        assert_eq!(*stage, ApplyStage::Interface);
+        assert_eq!(interface.listen_port, Some(4500));
+        assert!(interface.peers.is_empty());
+
+        let stages = update.stages(None).unwrap();
+        assert_eq!(stages[0].0, ApplyStage::Create);
+        let unchanged = DeviceUpdate::new().set_listen_port(51820);
+        assert!(unchanged.stages(Some(&device)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_merge_after_removal() {
+        use crate::PeerInfo;
+
+        let key = Key([1u8; 32]);
+        let update = DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(&key).set_persistent_keepalive_interval(25))
+            .remove_peer_by_key(&key)
+            .add_peer(PeerConfigBuilder::new(&key).set_persistent_keepalive_interval(5))
+            .add_peer(PeerConfigBuilder::new(&key).add_allowed_ip("10.0.0.1".parse().unwrap(), 32));
+        let merged = update.clone().resolve_duplicate_peers().unwrap();
+        // The removal stays, so nothing of the old peer survives the re-add.
+        assert_eq!(merged.peers.len(), 2);
+        assert!(merged.peers[0].remove_me);
+        assert!(!merged.peers[1].remove_me);
+        assert_eq!(merged.peers[1].persistent_keepalive_interval, Some(5));
+        assert_eq!(merged.peers[1].allowed_ips.len(), 1);
+
+        let mut peer = PeerInfo::fixture(key.clone()).with_endpoint("192.0.2.1:51820");
+        peer.config.preshared_key = Some(Key([2u8; 32]));
+        peer.config.persistent_keepalive_interval = Some(5);
+        let device = Device::fixture("wg0", vec![peer]);
+        // Against an interface with the peer, it's still removed and added anew.
+        let changes = update.changes(&device).unwrap();
+        assert_eq!(changes.peers, merged.peers);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn test_apply_checked_bisects_with_policies() {
+        let ip = |s: &str| s.parse::<AllowedIp>().unwrap();
+        let iface: InterfaceName = "mock-bisect".parse().unwrap();
+        DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(&Key([1u8; 32])).add_allowed_ips(&[ip("10.0.0.0/24")]))
+            .apply(&iface, Backend::Mock)
+            .unwrap();
+
+        let error = DeviceUpdate::new()
+            .on_allowed_ip_conflict(AllowedIpConflicts::Error)
+            .add_peer(PeerConfigBuilder::new(&Key([2u8; 32])).add_allowed_ips(&[ip("10.0.1.0/24")]))
+            .add_peer(PeerConfigBuilder::new(&Key([3u8; 32])).add_allowed_ips(&[ip("10.0.0.0/24")]))
+            .apply_checked(&iface, Backend::Mock)
+            .unwrap_err();
+        assert_eq!(error.peer.as_ref().map(|peer| peer.index), Some(1));
+
+        // Bisecting didn't steal the protected prefix.
+        let device = Device::get(&iface, Backend::Mock).unwrap();
+        let owner = device
+            .peers
+            .iter()
+            .find(|peer| peer.config.allowed_ips.contains(&ip("10.0.0.0/24")))
+            .unwrap();
+        assert_eq!(owner.config.public_key, Key([1u8; 32]));
+        device.delete().unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+//! Per-operation authorization for management layers built on this crate.
+//!
+//! Agents and APIs exposing device control to several users route every call
+//! through a [`Guard`], which asks an [`Authorizer`] whether the caller may
+//! perform each [`Operation`] before touching the backend. [`RoleAuthorizer`]
+//! implements the usual role-based scheme.
+use crate::{Backend, Device, DeviceUpdate, Error, InterfaceName};
+
+use std::{
+    collections::{HashMap, HashSet},
+    error, fmt,
+    hash::Hash,
+    io,
+};
+
+/// A class of operation an authorizer decides on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Listing interface names.
+    ListDevices,
+    /// Reading an interface's configuration and peer stats.
+    ReadDevice,
+    /// Changing the listen port or fwmark.
+    ModifyInterface,
+    /// Adding, updating or removing peers.
+    ModifyPeers,
+    /// Setting the interface's keys.
+    RotateKeys,
+    /// Creating an interface, which applying an update to a missing one does.
+    CreateDevice,
+    /// Deleting the interface.
+    DeleteDevice,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::ListDevices => "list devices",
+            Self::ReadDevice => "read device",
+            Self::ModifyInterface => "modify interface",
+            Self::ModifyPeers => "modify peers",
+            Self::RotateKeys => "rotate keys",
+            Self::CreateDevice => "create device",
+            Self::DeleteDevice => "delete device",
+        })
+    }
+}
+
+impl DeviceUpdate {
+    /// The operations applying this update performs, for authorization.
+    pub fn operations(&self) -> Vec<Operation> {
+        let mut operations = vec![];
+        if self.public_key.is_some() || self.private_key.is_some() {
+            operations.push(Operation::RotateKeys);
+        }
+        if self.listen_port.is_some() || self.fwmark.is_some() {
+            operations.push(Operation::ModifyInterface);
+        }
+        if self.replace_peers || !self.peers.is_empty() {
+            operations.push(Operation::ModifyPeers);
+        }
+        operations
+    }
+}
+
+/// An operation was refused by an [`Authorizer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Denied {
+    pub operation: Operation,
+    pub interface: Option<InterfaceName>,
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.interface {
+            Some(interface) => write!(f, "not allowed to {} on {}", self.operation, interface),
+            None => write!(f, "not allowed to {}", self.operation),
+        }
+    }
+}
+
+impl error::Error for Denied {}
+
+impl From<Denied> for io::Error {
+    fn from(e: Denied) -> Self {
+        io::Error::new(io::ErrorKind::PermissionDenied, e.to_string())
+    }
+}
+
+/// Decides whether `principal` (e.g. a user name or a client certificate
+/// fingerprint) may perform an operation.
+pub trait Authorizer<P> {
+    /// Returns whether `principal` may perform `operation`, on `interface` when
+    /// the operation targets one.
+    fn is_allowed(
+        &self,
+        principal: &P,
+        operation: Operation,
+        interface: Option<&InterfaceName>,
+    ) -> bool;
+}
+
+/// A named set of allowed operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Role {
+    pub name: String,
+    pub operations: HashSet<Operation>,
+    /// The interfaces the role applies to, `None` meaning all of them.
+    pub interfaces: Option<HashSet<InterfaceName>>,
+}
+
+impl Role {
+    pub fn new(name: &str, operations: &[Operation]) -> Self {
+        Self {
+            name: name.to_string(),
+            operations: operations.iter().copied().collect(),
+            interfaces: None,
+        }
+    }
+
+    /// Read-only access to every interface.
+    pub fn viewer() -> Self {
+        Self::new("viewer", &[Operation::ListDevices, Operation::ReadDevice])
+    }
+
+    /// Peer management without access to keys or interface settings.
+    pub fn operator() -> Self {
+        Self::new(
+            "operator",
+            &[
+                Operation::ListDevices,
+                Operation::ReadDevice,
+                Operation::ModifyPeers,
+            ],
+        )
+    }
+
+    /// Every operation.
+    pub fn admin() -> Self {
+        Self::new(
+            "admin",
+            &[
+                Operation::ListDevices,
+                Operation::ReadDevice,
+                Operation::ModifyInterface,
+                Operation::ModifyPeers,
+                Operation::RotateKeys,
+                Operation::CreateDevice,
+                Operation::DeleteDevice,
+            ],
+        )
+    }
+
+    /// Restricts the role to `interfaces`.
+    pub fn on_interfaces(mut self, interfaces: &[InterfaceName]) -> Self {
+        self.interfaces = Some(interfaces.iter().copied().collect());
+        self
+    }
+
+    fn allows(&self, operation: Operation, interface: Option<&InterfaceName>) -> bool {
+        // A role scoped to interfaces doesn't allow operations on none of them.
+        let interface_allowed = match (&self.interfaces, interface) {
+            (Some(interfaces), Some(interface)) => interfaces.contains(interface),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        interface_allowed && self.operations.contains(&operation)
+    }
+}
+
+/// Grants each principal the union of its roles. Unknown principals are denied
+/// everything.
+#[derive(Debug, Clone)]
+pub struct RoleAuthorizer<P> {
+    roles: HashMap<P, Vec<Role>>,
+}
+
+impl<P: Eq + Hash> Default for RoleAuthorizer<P> {
+    fn default() -> Self {
+        Self {
+            roles: HashMap::new(),
+        }
+    }
+}
+
+impl<P: Eq + Hash> RoleAuthorizer<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grants `role` to `principal`.
+    pub fn grant(mut self, principal: P, role: Role) -> Self {
+        self.roles.entry(principal).or_default().push(role);
+        self
+    }
+}
+
+impl<P: Eq + Hash> Authorizer<P> for RoleAuthorizer<P> {
+    fn is_allowed(
+        &self,
+        principal: &P,
+        operation: Operation,
+        interface: Option<&InterfaceName>,
+    ) -> bool {
+        self.roles
+            .get(principal)
+            .is_some_and(|roles| roles.iter().any(|role| role.allows(operation, interface)))
+    }
+}
+
+/// Performs device operations on behalf of `principal`, checking each one with
+/// the authorizer first.
+pub struct Guard<'a, P, A> {
+    authorizer: &'a A,
+    principal: P,
+    backend: Backend,
+}
+
+impl<'a, P, A: Authorizer<P>> Guard<'a, P, A> {
+    pub fn new(authorizer: &'a A, principal: P, backend: Backend) -> Self {
+        Self {
+            authorizer,
+            principal,
+            backend,
+        }
+    }
+
+    /// Checks that the principal may perform `operation`.
+    pub fn check(
+        &self,
+        operation: Operation,
+        interface: Option<&InterfaceName>,
+    ) -> Result<(), Denied> {
+        if self
+            .authorizer
+            .is_allowed(&self.principal, operation, interface)
+        {
+            Ok(())
+        } else {
+            Err(Denied {
+                operation,
+                interface: interface.copied(),
+            })
+        }
+    }
+
+    pub fn list(&self) -> io::Result<Vec<InterfaceName>> {
+        self.check(Operation::ListDevices, None)?;
+        Ok(Device::list(self.backend)?)
+    }
+
+    pub fn get(&self, iface: &InterfaceName) -> io::Result<Device> {
+        self.check(Operation::ReadDevice, Some(iface))?;
+        Ok(Device::get(iface, self.backend)?)
+    }
+
+    /// Applies `update` if every operation it performs is allowed. An update
+    /// that changes nothing still needs [`Operation::ModifyInterface`], and one
+    /// to a missing interface, which creates it, [`Operation::CreateDevice`].
+    pub fn apply(&self, update: DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
+        let operations = update.operations();
+        if operations.is_empty() {
+            self.check(Operation::ModifyInterface, Some(iface))?;
+        }
+        for operation in operations {
+            self.check(operation, Some(iface))?;
+        }
+        match Device::get(iface, self.backend) {
+            Ok(_) => {}
+            Err(Error::InterfaceNotFound(_)) => self.check(Operation::CreateDevice, Some(iface))?,
+            Err(e) => return Err(e.into()),
+        }
+        Ok(update.apply(iface, self.backend)?)
+    }
+
+    pub fn delete(&self, iface: &InterfaceName) -> io::Result<()> {
+        self.check(Operation::DeleteDevice, Some(iface))?;
+        Ok(Device::get(iface, self.backend)?.delete()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, PeerConfigBuilder};
+
+    #[test]
+    fn test_update_operations() {
+        let update = DeviceUpdate::new().add_peer(PeerConfigBuilder::new(&Key([1u8; 32])));
+        assert_eq!(update.operations(), [Operation::ModifyPeers]);
+
+        let update = DeviceUpdate::new()
+            .set_private_key(Key([2u8; 32]))
+            .set_listen_port(51820);
+        assert_eq!(
+            update.operations(),
+            [Operation::RotateKeys, Operation::ModifyInterface]
+        );
+    }
+
+    #[test]
+    fn test_role_authorizer() {
+        let wg0: InterfaceName = "wg0".parse().unwrap();
+        let wg1: InterfaceName = "wg1".parse().unwrap();
+        let authorizer = RoleAuthorizer::new()
+            .grant("alice", Role::admin())
+            .grant("bob", Role::viewer())
+            .grant("bob", Role::operator().on_interfaces(&[wg0]));
+
+        let bob = Guard::new(&authorizer, "bob", Backend::Userspace);
+        assert!(bob.check(Operation::ReadDevice, Some(&wg1)).is_ok());
+        assert!(bob.check(Operation::ModifyPeers, Some(&wg0)).is_ok());
+        assert!(bob.check(Operation::ModifyPeers, Some(&wg1)).is_err());
+
+        // Key rotation is refused before the backend is touched.
+        let error = bob
+            .apply(DeviceUpdate::new().set_private_key(Key([3u8; 32])), &wg0)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(error.to_string(), "not allowed to rotate keys on wg0");
+
+        // An empty update would create the interface, so it isn't free.
+        let error = bob.apply(DeviceUpdate::new(), &wg0).unwrap_err();
+        assert_eq!(error.to_string(), "not allowed to modify interface on wg0");
+        // Interface-scoped roles don't extend to operations on no interface.
+        let carol = RoleAuthorizer::new().grant("carol", Role::admin().on_interfaces(&[wg0]));
+        assert!(Guard::new(&carol, "carol", Backend::Userspace)
+            .list()
+            .is_err());
+
+        let mallory = Guard::new(&authorizer, "mallory", Backend::Userspace);
+        assert!(mallory.list().is_err());
+        let alice = Guard::new(&authorizer, "alice", Backend::Userspace);
+        assert!(alice.check(Operation::DeleteDevice, Some(&wg1)).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn test_create_device() {
+        use crate::backends::mock;
+
+        let iface: InterfaceName = "mock-authz".parse().unwrap();
+        let authorizer = RoleAuthorizer::new()
+            .grant("bob", Role::operator())
+            .grant("alice", Role::admin());
+        let update = DeviceUpdate::new().add_peer(PeerConfigBuilder::new(&Key([1u8; 32])));
+
+        let bob = Guard::new(&authorizer, "bob", Backend::Mock);
+        let error = bob.apply(update.clone(), &iface).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "not allowed to create device on mock-authz"
+        );
+        assert!(!mock::enumerate().unwrap().contains(&iface));
+
+        let alice = Guard::new(&authorizer, "alice", Backend::Mock);
+        alice.apply(update.clone(), &iface).unwrap();
+        bob.apply(update, &iface).unwrap();
+        alice.delete(&iface).unwrap();
+    }
+}
@@ -0,0 +1,605 @@
+//! A fallback backend driving the `wg(8)` command line tool.
+//!
+//! Interfaces are read with `wg show ... dump` and configured with `wg set`, so
+//! this works wherever the tool does, e.g. in containers that can't open netlink
+//! or userspace sockets themselves. `wg` can't create or delete interfaces, so
+//! they must be set up by other means. Set `WG_CLI` to use a binary other than
+//! the `wg` in `PATH`.
+//!
+//! Keys are handed to `wg` through inherited pipes and configs through its
+//! standard input, so they never touch the disk. Adding allowed IPs uses the
+//! `+` prefix of `wg set`, which needs `wg` 1.0.20210914 or later.
+use crate::{
+    key::SecretBuf, AllowedIp, Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfig,
+    PeerInfo, PeerStats,
+};
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write as _},
+    process::{Command, Output, Stdio},
+    time::{Duration, UNIX_EPOCH},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+pub(crate) fn wg_binary() -> String {
+    std::env::var("WG_CLI").unwrap_or_else(|_| "wg".to_string())
+}
+
+/// The output of `wg`, wiped when dropped as dumps include the private key.
+fn check_output(output: Output) -> io::Result<Zeroizing<String>> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        // wg reports failed calls with their errno strings. Missing interfaces
+        // are reported as ENODEV, since NotFound also means a missing `wg`.
+        let message = format!("wg: {}", stderr.trim());
+        return Err(if stderr.contains("No such device") {
+            io::Error::from_raw_os_error(libc::ENODEV)
+        } else if stderr.contains("Operation not permitted") {
+            io::Error::new(io::ErrorKind::PermissionDenied, message)
+        } else {
+            io::Error::other(message)
+        });
+    }
+    String::from_utf8(output.stdout)
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            // Not the error itself, which holds on to the output.
+            let error = e.utf8_error();
+            e.into_bytes().zeroize();
+            io::Error::new(io::ErrorKind::InvalidData, error)
+        })
+}
+
+fn wg(args: &[String]) -> io::Result<Zeroizing<String>> {
+    check_output(Command::new(wg_binary()).args(args).output()?)
+}
+
+#[cfg(feature = "tokio")]
+async fn wg_async(args: &[String]) -> io::Result<Zeroizing<String>> {
+    check_output(
+        tokio::process::Command::new(wg_binary())
+            .args(args)
+            .output()
+            .await?,
+    )
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected wg dump line {:?}", line),
+    )
+}
+
+/// `None` for the placeholders `wg` prints for unset values.
+fn value(field: &str) -> Option<&str> {
+    match field {
+        "(none)" | "off" | "0" => None,
+        field => Some(field),
+    }
+}
+
+fn parse_key(field: &str) -> Option<Key> {
+    value(field).and_then(|key| Key::from_base64(key).ok())
+}
+
+fn parse_stats(handshake: &str, rx_bytes: &str, tx_bytes: &str) -> Option<PeerStats> {
+    Some(PeerStats {
+        last_handshake_time: value(handshake)
+            .map(|secs| {
+                secs.parse()
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+            })
+            .transpose()
+            .ok()?,
+        rx_bytes: rx_bytes.parse().ok()?,
+        tx_bytes: tx_bytes.parse().ok()?,
+    })
+}
+
+fn parse_peer(fields: &[&str]) -> Option<PeerInfo> {
+    let [public_key, preshared_key, endpoint, allowed_ips, handshake, rx_bytes, tx_bytes, keepalive] =
+        fields
+    else {
+        return None;
+    };
+    let allowed_ips = match value(allowed_ips) {
+        Some(ips) => ips
+            .split(',')
+            .map(|ip| ip.parse().ok())
+            .collect::<Option<Vec<AllowedIp>>>()?,
+        None => vec![],
+    };
+    Some(PeerInfo {
+        config: PeerConfig {
+            public_key: Key::from_base64(public_key).ok()?,
+            preshared_key: parse_key(preshared_key),
+            endpoint: value(endpoint).and_then(|endpoint| endpoint.parse().ok()),
+            persistent_keepalive_interval: value(keepalive).map(str::parse).transpose().ok()?,
+            allowed_ips,
+            __cant_construct_me: (),
+        },
+        stats: parse_stats(handshake, rx_bytes, tx_bytes)?,
+    })
+}
+
+/// Parses the peer lines of `wg show <name> dump` into statistics only, leaving
+/// the allowed IPs and other configuration unparsed.
+fn parse_peer_stats(output: &str) -> io::Result<Vec<(Key, PeerStats)>> {
+    output
+        .lines()
+        .skip(1)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [public_key, _, _, _, handshake, rx_bytes, tx_bytes, _] = fields[..] else {
+                return Err(invalid(line));
+            };
+            Key::from_base64(public_key)
+                .ok()
+                .zip(parse_stats(handshake, rx_bytes, tx_bytes))
+                .ok_or_else(|| invalid(line))
+        })
+        .collect()
+}
+
+/// Parses the output of `wg show all dump`, where every line starts with the
+/// interface name. An interface's line precedes the lines of its peers.
+fn parse_dump(output: &str) -> io::Result<Vec<Device>> {
+    let mut devices: Vec<Device> = vec![];
+    for line in output.lines().filter(|line| !line.is_empty()) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let name: InterfaceName = fields[0].parse().map_err(|_| invalid(line))?;
+        match &fields[1..] {
+            [private_key, _public_key, listen_port, fwmark] => {
+                let private_key = parse_key(private_key);
+                devices.push(Device {
+                    name,
+                    public_key: private_key.as_ref().map(Key::get_public),
+                    private_key,
+                    fwmark: value(fwmark)
+                        .map(|fwmark| match fwmark.strip_prefix("0x") {
+                            Some(hex) => u32::from_str_radix(hex, 16),
+                            None => fwmark.parse(),
+                        })
+                        .transpose()
+                        .map_err(|_| invalid(line))?,
+                    listen_port: value(listen_port)
+                        .map(str::parse)
+                        .transpose()
+                        .map_err(|_| invalid(line))?,
+                    peers: vec![],
+                    linked_name: None,
+                    backend: Backend::Cli,
+                    __cant_construct_me: (),
+                });
+            }
+            peer => match devices.last_mut() {
+                Some(device) if device.name == name => device
+                    .peers
+                    .push(parse_peer(peer).ok_or_else(|| invalid(line))?),
+                _ => return Err(invalid(line)),
+            },
+        }
+    }
+    Ok(devices)
+}
+
+fn parse_interfaces(output: &str) -> io::Result<Vec<InterfaceName>> {
+    output
+        .split_whitespace()
+        .map(|name| name.parse().map_err(io::Error::from))
+        .collect()
+}
+
+fn show_args(name: &InterfaceName) -> Vec<String> {
+    vec!["show".into(), name.to_string(), "dump".into()]
+}
+
+/// Parses the output of `wg show <name> dump`, which lacks the interface column.
+fn parse_device(name: &InterfaceName, output: &str) -> io::Result<Device> {
+    let mut prefixed = SecretBuf::default();
+    for line in output.lines() {
+        writeln!(prefixed, "{}\t{}", name, line).ok();
+    }
+    parse_dump(&prefixed)?
+        .pop()
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::ENODEV))
+}
+
+pub fn enumerate() -> io::Result<Vec<InterfaceName>> {
+    parse_interfaces(&wg(&["show".into(), "interfaces".into()])?)
+}
+
+/// Reads every interface with a single `wg show all dump`.
+pub fn get_all() -> io::Result<Vec<Device>> {
+    parse_dump(&wg(&["show".into(), "all".into(), "dump".into()])?)
+}
+
+pub fn get_by_name(name: &InterfaceName) -> io::Result<Device> {
+    parse_device(name, &wg(&show_args(name))?)
+}
+
+pub fn get_stats(name: &InterfaceName) -> io::Result<Vec<(Key, PeerStats)>> {
+    parse_peer_stats(&wg(&show_args(name))?)
+}
+
+/// Secret keys handed to `wg set`, which only reads keys from files, through
+/// pipes the `wg` process inherits and reads as `/dev/fd/<n>`. The read ends
+/// are closed when dropped.
+#[derive(Default)]
+struct KeyPipes {
+    readers: Vec<io::PipeReader>,
+}
+
+impl KeyPipes {
+    /// Writes `key` into a new pipe, returning the path `wg` reads it from.
+    #[cfg(unix)]
+    fn write(&mut self, key: &Key) -> io::Result<String> {
+        use std::os::fd::AsRawFd;
+
+        let (reader, mut writer) = io::pipe()?;
+        // A key is far below the capacity of a pipe, so this doesn't wait for `wg`.
+        writer.write_all(Zeroizing::new(format!("{}\n", key.to_base64())).as_bytes())?;
+        let path = format!("/dev/fd/{}", reader.as_raw_fd());
+        self.readers.push(reader);
+        Ok(path)
+    }
+
+    #[cfg(not(unix))]
+    fn write(&mut self, _key: &Key) -> io::Result<String> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "passing keys to wg(8) needs /dev/fd",
+        ))
+    }
+
+    /// The descriptors `wg` inherits. Like everything std opens, the pipes are
+    /// close-on-exec, so other processes spawned meanwhile don't get them.
+    #[cfg(unix)]
+    fn fds(&self) -> Vec<std::os::fd::RawFd> {
+        use std::os::fd::AsRawFd;
+
+        self.readers.iter().map(AsRawFd::as_raw_fd).collect()
+    }
+
+    /// A `wg` command inheriting the pipes.
+    fn command(&self) -> Command {
+        let mut command = Command::new(wg_binary());
+        #[cfg(unix)]
+        {
+            let fds = self.fds();
+            // Only async-signal-safe calls happen between fork and exec.
+            unsafe {
+                std::os::unix::process::CommandExt::pre_exec(&mut command, move || inherit(&fds))
+            };
+        }
+        command
+    }
+
+    #[cfg(feature = "tokio")]
+    fn async_command(&self) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new(wg_binary());
+        #[cfg(unix)]
+        {
+            let fds = self.fds();
+            unsafe { command.pre_exec(move || inherit(&fds)) };
+        }
+        command
+    }
+}
+
+/// Clears close-on-exec on `fds`, in the child about to exec `wg`.
+#[cfg(unix)]
+fn inherit(fds: &[std::os::fd::RawFd]) -> io::Result<()> {
+    for &fd in fds {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The `wg setconf` arguments reading a config from standard input.
+///
+/// `wg set` can't replace all peers, so updates that do are applied as a whole
+/// config, which replaces the peers like the other backends: peers kept by the
+/// update lose their preshared key, endpoint and keepalive unless it sets them.
+fn setconf_args(iface: &InterfaceName) -> Vec<String> {
+    vec!["setconf".into(), iface.to_string(), "/dev/stdin".into()]
+}
+
+/// The config `wg setconf` reads for `update`, wiped when dropped.
+///
+/// `wg setconf` also resets the private key, listen port and fwmark a config
+/// leaves out, so those the update doesn't set are kept from `current`, the
+/// interface as it is, and replacing the peers touches only the peers.
+fn setconf_input(update: &DeviceUpdate, current: &Device) -> Zeroizing<String> {
+    let update = DeviceUpdate {
+        private_key: update
+            .private_key
+            .clone()
+            .or_else(|| current.private_key.clone()),
+        listen_port: update.listen_port.or(current.listen_port),
+        fwmark: update.fwmark.or(current.fwmark),
+        ..update.clone()
+    };
+    Zeroizing::new(update.to_wg_quick_config())
+}
+
+/// Runs `wg` with `input` on its standard input.
+fn wg_with_input(args: &[String], input: &str) -> io::Result<Zeroizing<String>> {
+    let mut child = Command::new(wg_binary())
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let written = stdin.write_all(input.as_bytes());
+    drop(stdin);
+    // What `wg` reports explains a failed write better than the write does.
+    let output = check_output(child.wait_with_output()?)?;
+    written.map(|()| output)
+}
+
+#[cfg(feature = "tokio")]
+async fn wg_with_input_async(args: &[String], input: &str) -> io::Result<Zeroizing<String>> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut child = tokio::process::Command::new(wg_binary())
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let written = stdin.write_all(input.as_bytes()).await;
+    drop(stdin);
+    let output = check_output(child.wait_with_output().await?)?;
+    written.map(|()| output)
+}
+
+/// The `wg set` arguments applying `update`, which doesn't replace the peers,
+/// writing secret keys with `key_file`.
+///
+/// Allowed IPs are added with the `+` prefix unless they replace the peer's, so
+/// `wg` merges them with the current ones itself rather than this racing other
+/// writers with a read first.
+fn set_args(
+    update: &DeviceUpdate,
+    iface: &InterfaceName,
+    mut key_file: impl FnMut(&Key) -> io::Result<String>,
+) -> io::Result<Vec<String>> {
+    let mut args = vec!["set".to_string(), iface.to_string()];
+    if let Some(listen_port) = update.listen_port {
+        args.extend(["listen-port".into(), listen_port.to_string()]);
+    }
+    if let Some(fwmark) = update.fwmark {
+        args.extend(["fwmark".into(), fwmark.to_string()]);
+    }
+    if let Some(private_key) = &update.private_key {
+        args.extend(["private-key".into(), key_file(private_key)?]);
+    }
+
+    for peer in &update.peers {
+        args.extend(["peer".into(), peer.public_key.to_base64()]);
+        if peer.remove_me {
+            args.push("remove".into());
+            continue;
+        }
+        if let Some(preshared_key) = &peer.preshared_key {
+            args.extend(["preshared-key".into(), key_file(preshared_key)?]);
+        }
+        if let Some(endpoint) = peer.endpoint {
+            args.extend(["endpoint".into(), endpoint.to_string()]);
+        }
+        if let Some(keepalive) = peer.persistent_keepalive_interval {
+            args.extend(["persistent-keepalive".into(), keepalive.to_string()]);
+        }
+        if peer.replace_allowed_ips || !peer.allowed_ips.is_empty() {
+            let prefix = if peer.replace_allowed_ips { "" } else { "+" };
+            let list: Vec<_> = peer
+                .allowed_ips
+                .iter()
+                .map(|ip| format!("{}{}/{}", prefix, ip.address, ip.cidr))
+                .collect();
+            args.extend(["allowed-ips".into(), list.join(",")]);
+        }
+    }
+    Ok(args)
+}
+
+pub fn apply(update: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
+    if update.replace_peers {
+        let input = setconf_input(update, &get_by_name(iface)?);
+        return wg_with_input(&setconf_args(iface), &input).map(|_| ());
+    }
+    let mut key_pipes = KeyPipes::default();
+    let args = set_args(update, iface, |key| key_pipes.write(key))?;
+    check_output(key_pipes.command().args(&args).output()?).map(|_| ())
+}
+
+/// `wg` can't delete interfaces, so this always fails.
+pub fn delete_interface(iface: &InterfaceName) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("wg(8) can't delete interface {}", iface),
+    ))
+}
+
+#[cfg(feature = "tokio")]
+pub async fn enumerate_async() -> io::Result<Vec<InterfaceName>> {
+    parse_interfaces(&wg_async(&["show".into(), "interfaces".into()]).await?)
+}
+
+#[cfg(feature = "tokio")]
+pub async fn get_by_name_async(name: &InterfaceName) -> io::Result<Device> {
+    parse_device(name, &wg_async(&show_args(name)).await?)
+}
+
+#[cfg(feature = "tokio")]
+pub async fn apply_async(update: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
+    if update.replace_peers {
+        let input = setconf_input(update, &get_by_name_async(iface).await?);
+        return wg_with_input_async(&setconf_args(iface), &input)
+            .await
+            .map(|_| ());
+    }
+    let mut key_pipes = KeyPipes::default();
+    let args = set_args(update, iface, |key| key_pipes.write(key))?;
+    check_output(key_pipes.async_command().args(&args).output().await?).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerConfigBuilder;
+
+    const PRIVATE: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+    const PEER: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+
+    fn dump() -> String {
+        [
+            format!("wg0\t{PRIVATE}\t(none)\t51820\toff"),
+            format!("wg0\t{PEER}\t(none)\t192.0.2.1:51820\t10.0.0.2/32,10.0.1.0/24\t1700000000\t100\t200\t25"),
+            "wg1\t(none)\t(none)\t0\t0x2a".to_string(),
+        ]
+        .join("\n")
+    }
+
+    #[test]
+    fn test_parse_dump() {
+        let devices = parse_dump(&dump()).unwrap();
+        assert_eq!(devices.len(), 2);
+        let wg0 = &devices[0];
+        assert_eq!(wg0.listen_port, Some(51820));
+        assert_eq!(wg0.fwmark, None);
+        assert_eq!(
+            wg0.public_key,
+            Some(Key::from_base64(PRIVATE).unwrap().get_public())
+        );
+        let peer = &wg0.peers[0];
+        assert_eq!(peer.config.allowed_ips.len(), 2);
+        assert_eq!(peer.config.persistent_keepalive_interval, Some(25));
+        assert_eq!(
+            peer.stats.last_handshake_time,
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        assert_eq!(devices[1].fwmark, Some(42));
+        assert_eq!(devices[1].listen_port, None);
+
+        assert!(parse_dump("wg0\tgarbage").is_err());
+
+        let name = "wg0".parse().unwrap();
+        let own = format!("{PRIVATE}\t(none)\t51820\toff\n");
+        assert_eq!(parse_device(&name, &own).unwrap().listen_port, Some(51820));
+    }
+
+    #[test]
+    fn test_parse_peer_stats() {
+        let output: String = dump()
+            .lines()
+            .take(2)
+            .map(|line| format!("{}\n", line.strip_prefix("wg0\t").unwrap()))
+            .collect();
+        let stats = parse_peer_stats(&output).unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].0, Key::from_base64(PEER).unwrap());
+        assert_eq!(stats[0].1.rx_bytes, 100);
+        assert_eq!(stats[0].1.tx_bytes, 200);
+        assert!(parse_peer_stats("header\ngarbage\n").is_err());
+    }
+
+    #[test]
+    fn test_set_args() {
+        let name: InterfaceName = "wg0".parse().unwrap();
+        let peer = Key::from_base64(PEER).unwrap();
+        let update = DeviceUpdate::new()
+            .set_listen_port(4500)
+            .set_private_key(Key::from_base64(PRIVATE).unwrap())
+            .add_peer(
+                PeerConfigBuilder::new(&peer).add_allowed_ip("10.0.2.0".parse().unwrap(), 24),
+            );
+        let args = set_args(&update, &name, |_| Ok("/dev/fd/9".into())).unwrap();
+        assert_eq!(
+            args.join(" "),
+            format!(
+                "set wg0 listen-port 4500 private-key /dev/fd/9 peer {PEER} \
+                 allowed-ips +10.0.2.0/24"
+            )
+        );
+
+        let update = DeviceUpdate::new().add_peer(
+            PeerConfigBuilder::new(&peer)
+                .replace_allowed_ips()
+                .add_allowed_ip("10.0.3.0".parse().unwrap(), 24),
+        );
+        let args = set_args(&update, &name, |_| unreachable!()).unwrap();
+        assert_eq!(
+            args.join(" "),
+            format!("set wg0 peer {PEER} allowed-ips 10.0.3.0/24")
+        );
+
+        // Replacing the peers goes through `wg setconf`, without the peers' old
+        // settings.
+        let update = DeviceUpdate::new()
+            .replace_peers()
+            .add_peer(PeerConfigBuilder::new(&peer));
+        assert_eq!(setconf_args(&name).join(" "), "setconf wg0 /dev/stdin");
+        let current = Device::fixture("wg0", vec![]);
+        let input = setconf_input(&update, &current);
+        assert!(input.contains(&format!("PublicKey = {PEER}")));
+        assert!(!input.contains("Endpoint"));
+
+        // It keeps the interface settings the update leaves unset, which
+        // `wg setconf` would reset.
+        let current = Device {
+            private_key: Some(Key::from_base64(PRIVATE).unwrap()),
+            listen_port: Some(51820),
+            fwmark: Some(42),
+            ..current
+        };
+        let input = setconf_input(&update, &current);
+        assert!(input.contains(&format!("PrivateKey = {PRIVATE}")));
+        assert!(input.contains("ListenPort = 51820"));
+        assert!(input.contains("FwMark = 0x2a"));
+        let input = setconf_input(&update.set_listen_port(4500), &current);
+        assert!(input.contains("ListenPort = 4500"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_check_output() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let failed = |stderr: &str| Output {
+            status: std::process::ExitStatus::from_raw(1 << 8),
+            stdout: vec![],
+            stderr: stderr.as_bytes().to_vec(),
+        };
+        let error = check_output(failed("Unable to access interface: No such device")).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::ENODEV));
+        let error = check_output(failed(
+            "Unable to modify interface: Operation not permitted",
+        ))
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        let error = check_output(failed("Invalid argument")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_key_pipes() {
+        let key = Key::from_base64(PRIVATE).unwrap();
+        let mut key_pipes = KeyPipes::default();
+        let path = key_pipes.write(&key).unwrap();
+        assert!(path.starts_with("/dev/fd/"));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{PRIVATE}\n")
+        );
+    }
+}
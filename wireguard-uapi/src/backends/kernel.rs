@@ -1,4 +1,11 @@
-use crate::netlink_request::{netlink_request_genl, netlink_request_rtnl, MAX_GENL_PAYLOAD_LENGTH};
+use crate::netlink_request::{
+    family_request, netlink_request_genl, netlink_request_genl_wiped, netlink_request_rtnl,
+    MAX_GENL_PAYLOAD_LENGTH,
+};
+#[cfg(feature = "tokio")]
+use crate::netlink_request::{
+    netlink_request_genl_async, netlink_request_genl_wiped_async, netlink_request_rtnl_async,
+};
 use crate::{
     device::AllowedIp, Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfig,
     PeerConfigBuilder, PeerInfo, PeerStats,
@@ -6,7 +13,10 @@ use crate::{
 use netlink_packet_core::{
     NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL, NLM_F_REQUEST,
 };
-use netlink_packet_generic::GenlMessage;
+use netlink_packet_generic::{
+    ctrl::{nlas::GenlCtrlAttrs, GenlCtrl},
+    GenlMessage,
+};
 use netlink_packet_route::{
     constants::*,
     link::{
@@ -23,7 +33,12 @@ use netlink_packet_wireguard::{
     Wireguard, WireguardCmd,
 };
 
-use std::{convert::TryFrom, io};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    convert::TryFrom,
+    fs, io,
+};
+use zeroize::Zeroize;
 
 macro_rules! get_nla_value {
     ($nlas:expr, $e:ident, $v:ident) => {
@@ -34,6 +49,45 @@ macro_rules! get_nla_value {
     };
 }
 
+/// Wipes the private and preshared keys in `nlas`, which `netlink_packet_wireguard`
+/// keeps as plain arrays that aren't wiped when dropped.
+fn wipe_keys(nlas: &mut [WgDeviceAttrs]) {
+    for nla in nlas {
+        match nla {
+            WgDeviceAttrs::PrivateKey(key) => key.zeroize(),
+            WgDeviceAttrs::Peers(peers) => {
+                for peer in peers {
+                    wipe_peer(&mut peer.0);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Wipes the preshared key in the attributes of a peer.
+fn wipe_peer(attrs: &mut [WgPeerAttrs]) {
+    for attr in attrs {
+        if let WgPeerAttrs::PresharedKey(key) = attr {
+            key.zeroize();
+        }
+    }
+}
+
+/// Wipes the keys in a request or a response, see [`wipe_keys`].
+fn wipe_message(message: &mut GenlMessage<Wireguard>) {
+    wipe_keys(&mut message.payload.nlas);
+}
+
+/// Wipes the keys in every response, see [`wipe_keys`].
+fn wipe_responses(responses: &mut [NetlinkMessage<GenlMessage<Wireguard>>]) {
+    for response in responses {
+        if let NetlinkPayload::InnerMessage(message) = &mut response.payload {
+            wipe_message(message);
+        }
+    }
+}
+
 impl TryFrom<WgAllowedIp> for AllowedIp {
     type Error = io::Error;
 
@@ -88,10 +142,10 @@ impl PeerConfigBuilder {
     }
 }
 
-impl TryFrom<WgPeer> for PeerInfo {
+impl TryFrom<&WgPeer> for PeerInfo {
     type Error = io::Error;
 
-    fn try_from(attrs: WgPeer) -> Result<Self, Self::Error> {
+    fn try_from(attrs: &WgPeer) -> Result<Self, Self::Error> {
         let public_key = get_nla_value!(attrs, WgPeerAttrs, PublicKey)
             .map(|key| Key(*key))
             .ok_or(io::ErrorKind::NotFound)?;
@@ -130,6 +184,26 @@ impl TryFrom<WgPeer> for PeerInfo {
     }
 }
 
+/// The key and statistics of a peer, without converting the rest of its
+/// attributes.
+fn peer_stats(attrs: &WgPeer) -> io::Result<(Key, PeerStats)> {
+    let public_key = get_nla_value!(attrs, WgPeerAttrs, PublicKey)
+        .map(|key| Key(*key))
+        .ok_or(io::ErrorKind::NotFound)?;
+    Ok((
+        public_key,
+        PeerStats {
+            last_handshake_time: get_nla_value!(attrs, WgPeerAttrs, LastHandshake).cloned(),
+            rx_bytes: get_nla_value!(attrs, WgPeerAttrs, RxBytes)
+                .cloned()
+                .unwrap_or_default(),
+            tx_bytes: get_nla_value!(attrs, WgPeerAttrs, TxBytes)
+                .cloned()
+                .unwrap_or_default(),
+        },
+    ))
+}
+
 impl<'a> TryFrom<&'a [WgDeviceAttrs]> for Device {
     type Error = io::Error;
 
@@ -144,12 +218,25 @@ impl<'a> TryFrom<&'a [WgDeviceAttrs]> for Device {
         let peers = nlas
             .iter()
             .filter_map(|nla| match nla {
-                WgDeviceAttrs::Peers(peers) => Some(peers.clone()),
+                WgDeviceAttrs::Peers(peers) => Some(peers),
                 _ => None,
             })
-            .flatten()
+            .flatten();
+        // A dump of tens of thousands of peers spends most of its time here.
+        #[cfg(feature = "parallel")]
+        let peers = {
+            use rayon::prelude::*;
+            peers
+                .collect::<Vec<_>>()
+                .into_par_iter()
+                .map(PeerInfo::try_from)
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        #[cfg(not(feature = "parallel"))]
+        let peers = peers
             .map(PeerInfo::try_from)
             .collect::<Result<Vec<_>, _>>()?;
+        let peers = merge_split_peers(peers);
         Ok(Device {
             name,
             public_key,
@@ -164,12 +251,129 @@ impl<'a> TryFrom<&'a [WgDeviceAttrs]> for Device {
     }
 }
 
+/// Joins the parts of peers split across dump messages. A peer whose allowed IPs
+/// don't fit in one message is continued in the next with only its public key
+/// and the rest of its allowed IPs.
+fn merge_split_peers(peers: Vec<PeerInfo>) -> Vec<PeerInfo> {
+    let mut merged: Vec<PeerInfo> = Vec::with_capacity(peers.len());
+    let mut positions = HashMap::with_capacity(peers.len());
+    for peer in peers {
+        match positions.entry(peer.config.public_key.clone()) {
+            Entry::Occupied(position) => merged[*position.get()]
+                .config
+                .allowed_ips
+                .extend(peer.config.allowed_ips),
+            Entry::Vacant(position) => {
+                position.insert(merged.len());
+                merged.push(peer);
+            }
+        }
+    }
+    merged
+}
+
+/// The `wireguard` generic netlink family, as registered by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FamilyInfo {
+    pub id: u16,
+    /// The version of the family's protocol, 1 for every release so far.
+    pub version: u32,
+    pub max_attr: u32,
+}
+
+/// What the running kernel offers the kernel backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelSupport {
+    /// The generic netlink family, registered while the module is loaded or when
+    /// it's built in.
+    pub family: Option<FamilyInfo>,
+    /// Whether the module is installed for the running kernel, so that creating
+    /// an interface loads it.
+    pub module_installed: bool,
+}
+
+impl KernelSupport {
+    /// Asks the kernel for the `wireguard` family and looks for the module of
+    /// the running kernel.
+    pub fn probe() -> io::Result<Self> {
+        let family = match netlink_request_genl(
+            family_request::<Wireguard>(),
+            Some(NLM_F_REQUEST | NLM_F_ACK),
+        ) {
+            Ok(responses) => family_info(&responses),
+            // The kernel answers ENOENT for unknown families.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let module_installed = fs::read_to_string("/proc/sys/kernel/osrelease")
+            .map(|release| {
+                ["modules.builtin", "modules.dep"].iter().any(|list| {
+                    fs::read_to_string(format!("/lib/modules/{}/{}", release.trim(), list))
+                        .is_ok_and(|list| lists_wireguard(&list))
+                })
+            })
+            .unwrap_or(false);
+        Ok(Self {
+            family,
+            module_installed,
+        })
+    }
+
+    /// Whether interfaces can be created, now or by loading the module.
+    pub fn is_usable(&self) -> bool {
+        self.family.is_some() || self.module_installed
+    }
+}
+
+/// Whether the kernel backend can be used, without attempting to create an
+/// interface. See [`KernelSupport`] for the details.
+pub fn is_available() -> bool {
+    KernelSupport::probe().is_ok_and(|support| support.is_usable())
+}
+
+fn family_info(responses: &[NetlinkMessage<GenlMessage<GenlCtrl>>]) -> Option<FamilyInfo> {
+    responses
+        .iter()
+        .find_map(|response| match &response.payload {
+            NetlinkPayload::InnerMessage(GenlMessage {
+                payload: GenlCtrl { nlas, .. },
+                ..
+            }) => Some(FamilyInfo {
+                id: *get_nla_value!(nlas, GenlCtrlAttrs, FamilyId)?,
+                version: get_nla_value!(nlas, GenlCtrlAttrs, Version)
+                    .copied()
+                    .unwrap_or(0),
+                max_attr: get_nla_value!(nlas, GenlCtrlAttrs, MaxAttr)
+                    .copied()
+                    .unwrap_or(0),
+            }),
+            _ => None,
+        })
+}
+
+/// Whether a `modules.dep` or `modules.builtin` file lists the module, possibly
+/// compressed.
+fn lists_wireguard(list: &str) -> bool {
+    list.lines().any(|line| {
+        let path = line.split(':').next().unwrap_or_default();
+        let file = path.rsplit('/').next().unwrap_or_default();
+        file == "wireguard.ko" || file.starts_with("wireguard.ko.")
+    })
+}
+
+const ENUMERATE_FLAGS: u16 = NLM_F_DUMP | NLM_F_REQUEST;
+
 pub fn enumerate() -> Result<Vec<InterfaceName>, io::Error> {
     let link_responses = netlink_request_rtnl(
         RtnlMessage::GetLink(LinkMessage::default()),
-        Some(NLM_F_DUMP | NLM_F_REQUEST),
+        Some(ENUMERATE_FLAGS),
     )?;
-    let links = link_responses
+    Ok(wireguard_links(link_responses))
+}
+
+/// The names of the WireGuard links in a link dump.
+fn wireguard_links(link_responses: Vec<NetlinkMessage<RtnlMessage>>) -> Vec<InterfaceName> {
+    link_responses
         .into_iter()
         // Filter out non-link messages
         .filter_map(|response| match response {
@@ -196,12 +400,11 @@ pub fn enumerate() -> Result<Vec<InterfaceName>, io::Error> {
             })
         })
         .filter_map(|name| name.parse().ok())
-        .collect::<Vec<_>>();
-
-    Ok(links)
+        .collect::<Vec<_>>()
 }
 
-fn add_del(iface: &InterfaceName, add: bool) -> io::Result<()> {
+/// The request creating (or deleting) `iface`, with its flags.
+fn add_del_message(iface: &InterfaceName, add: bool) -> (RtnlMessage, u16) {
     let mut message = LinkMessage::default();
     message
         .nlas
@@ -215,17 +418,51 @@ fn add_del(iface: &InterfaceName, add: bool) -> io::Result<()> {
     } else {
         RtnlMessage::DelLink(message)
     };
-    match netlink_request_rtnl(rtnl_message, Some(NLM_F_REQUEST | NLM_F_ACK | extra_flags)) {
+    (rtnl_message, NLM_F_REQUEST | NLM_F_ACK | extra_flags)
+}
+
+/// Ignores the interface already existing when creating it.
+fn add_del_result<T>(result: io::Result<T>) -> io::Result<()> {
+    match result {
         Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e),
         _ => Ok(()),
     }
 }
 
+fn add_del(iface: &InterfaceName, add: bool) -> io::Result<()> {
+    let (message, flags) = add_del_message(iface, add);
+    add_del_result(netlink_request_rtnl(message, Some(flags)))
+}
+
 pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
     add_del(iface, true)?;
+    let mut messages = apply_messages(builder, iface)?.into_iter();
+    let result = messages.try_for_each(|message| {
+        netlink_request_genl_wiped(message, Some(NLM_F_REQUEST | NLM_F_ACK), wipe_message).map(drop)
+    });
+    // The messages left after a failure.
+    messages.for_each(|mut message| wipe_message(&mut message));
+    result
+}
+
+/// The messages setting `builder` on `iface`, split to fit netlink's limits.
+fn apply_messages(
+    builder: &DeviceUpdate,
+    iface: &InterfaceName,
+) -> io::Result<Vec<GenlMessage<Wireguard>>> {
     let mut payload = ApplyPayload::new(iface);
-    if let Some(Key(k)) = builder.private_key {
-        payload.push(WgDeviceAttrs::PrivateKey(k))?;
+    match push_update(&mut payload, builder) {
+        Ok(()) => Ok(payload.finish()),
+        Err(e) => {
+            payload.wipe();
+            Err(e)
+        }
+    }
+}
+
+fn push_update(payload: &mut ApplyPayload, builder: &DeviceUpdate) -> io::Result<()> {
+    if let Some(ref key) = builder.private_key {
+        payload.push(WgDeviceAttrs::PrivateKey(key.0))?;
     }
     if let Some(f) = builder.fwmark {
         payload.push(WgDeviceAttrs::Fwmark(f))?;
@@ -240,13 +477,7 @@ pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
     builder
         .peers
         .iter()
-        .map(|peer| payload.push_peer(peer.to_nla()))
-        .collect::<Result<Vec<_>, _>>()?;
-
-    for message in payload.finish() {
-        netlink_request_genl(message, Some(NLM_F_REQUEST | NLM_F_ACK))?;
-    }
-    Ok(())
+        .try_for_each(|peer| payload.push_peer(peer.to_nla()))
 }
 
 struct ApplyPayload {
@@ -298,10 +529,8 @@ impl ApplyPayload {
         if (self.current_buffer_len + nla_buffer_len) > MAX_GENL_PAYLOAD_LENGTH {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                format!(
-                    "encoded NLA ({} bytes) is too large: {:?}",
-                    nla_buffer_len, nla
-                ),
+                // Not the NLA itself, which may be the private key.
+                format!("encoded NLA ({} bytes) is too large", nla_buffer_len),
             ));
         }
         self.nlas.push(nla);
@@ -310,7 +539,7 @@ impl ApplyPayload {
     }
 
     /// A helper function to assist in breaking up large peer lists across multiple netlink messages
-    pub fn push_peer(&mut self, peer: WgPeer) -> io::Result<()> {
+    pub fn push_peer(&mut self, mut peer: WgPeer) -> io::Result<()> {
         const EMPTY_PEERS: WgDeviceAttrs = WgDeviceAttrs::Peers(vec![]);
         let mut needs_peer_nla = !self
             .nlas
@@ -332,11 +561,15 @@ impl ApplyPayload {
 
         // If the peer *still* doesn't fit...
         if (self.current_buffer_len + peer_buffer_len) > MAX_GENL_PAYLOAD_LENGTH {
+            wipe_peer(&mut peer.0);
+            // Not the peer itself, which may have a preshared key.
+            let public_key = get_nla_value!(peer.0, WgPeerAttrs, PublicKey).map(|key| Key(*key));
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 format!(
-                    "encoded peer ({} bytes) is too large: {:?}",
-                    peer_buffer_len, peer
+                    "encoded peer {} ({} bytes) is too large",
+                    public_key.map(|key| key.to_base64()).unwrap_or_default(),
+                    peer_buffer_len
                 ),
             ));
         }
@@ -356,41 +589,59 @@ impl ApplyPayload {
         Ok(())
     }
 
+    /// Wipes the keys of the messages pushed so far, when giving up on them.
+    fn wipe(&mut self) {
+        wipe_keys(&mut self.nlas);
+        self.queue.iter_mut().for_each(wipe_message);
+    }
+
     pub fn finish(mut self) -> Vec<GenlMessage<Wireguard>> {
         self.flush_nlas();
         self.queue
     }
 }
 
-pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
-    let genlmsg: GenlMessage<Wireguard> = GenlMessage::from_payload(Wireguard {
+const GET_FLAGS: u16 = NLM_F_REQUEST | NLM_F_DUMP | NLM_F_ACK;
+
+fn get_message(name: &InterfaceName) -> GenlMessage<Wireguard> {
+    GenlMessage::from_payload(Wireguard {
         cmd: WireguardCmd::GetDevice,
         nlas: vec![WgDeviceAttrs::IfName(name.as_str_lossy().to_string())],
-    });
-    let responses = netlink_request_genl(genlmsg, Some(NLM_F_REQUEST | NLM_F_DUMP | NLM_F_ACK))?;
+    })
+}
+
+pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
+    let responses = netlink_request_genl(get_message(name), Some(GET_FLAGS))?;
+    parse_device(responses)
+}
+
+/// The device described by the responses to a [`get_message`].
+///
+/// The keys in the responses are wiped once converted.
+fn parse_device(mut responses: Vec<NetlinkMessage<GenlMessage<Wireguard>>>) -> io::Result<Device> {
     log::debug!(
         "get_by_name: got {} response message(s) from netlink request",
         responses.len()
     );
 
-    let nlas = responses.into_iter().fold(Ok(vec![]), |nlas_res, nlmsg| {
-        let mut nlas = nlas_res?;
-        let mut message = match nlmsg {
-            NetlinkMessage {
-                payload: NetlinkPayload::InnerMessage(message),
-                ..
-            } => message,
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("unexpected netlink payload: {:?}", nlmsg),
-                ))
+    let mut nlas = vec![];
+    let mut unexpected = None;
+    for nlmsg in &mut responses {
+        match &mut nlmsg.payload {
+            NetlinkPayload::InnerMessage(message) => nlas.append(&mut message.payload.nlas),
+            payload => {
+                unexpected = Some(format!("unexpected netlink payload: {:?}", payload));
+                break;
             }
-        };
-        nlas.append(&mut message.payload.nlas);
-        Ok(nlas)
-    })?;
-    let device = Device::try_from(&nlas[..])?;
+        }
+    }
+    let device = match unexpected {
+        Some(message) => Err(io::Error::new(io::ErrorKind::InvalidData, message)),
+        None => Device::try_from(&nlas[..]),
+    };
+    wipe_keys(&mut nlas);
+    wipe_responses(&mut responses);
+    let device = device?;
     log::debug!(
         "get_by_name: parsed wireguard device {} with {} peer(s)",
         device.name,
@@ -399,17 +650,235 @@ pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
     Ok(device)
 }
 
+/// The statistics of every peer of `name`, skipping the conversion of their
+/// allowed IPs and other configuration.
+pub fn get_stats(name: &InterfaceName) -> io::Result<Vec<(Key, PeerStats)>> {
+    let mut responses = netlink_request_genl(get_message(name), Some(GET_FLAGS))?;
+    let stats = parse_stats(&responses);
+    wipe_responses(&mut responses);
+    stats
+}
+
+/// The peer statistics in the responses to a [`get_message`].
+///
+/// A peer with more allowed IPs than fit in one message continues in the next
+/// with only its public key and the rest of the allowed IPs, so later entries
+/// of a key are merged into the first, which has the statistics.
+fn parse_stats(
+    responses: &[NetlinkMessage<GenlMessage<Wireguard>>],
+) -> io::Result<Vec<(Key, PeerStats)>> {
+    let mut stats = vec![];
+    let mut seen = HashSet::new();
+    for response in responses {
+        let NetlinkPayload::InnerMessage(message) = &response.payload else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected netlink payload: {:?}", response),
+            ));
+        };
+        for nla in &message.payload.nlas {
+            if let WgDeviceAttrs::Peers(peers) = nla {
+                for peer in peers {
+                    let (key, peer_stats) = peer_stats(peer)?;
+                    if seen.insert(key.clone()) {
+                        stats.push((key, peer_stats));
+                    }
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
+
 pub fn delete_interface(iface: &InterfaceName) -> io::Result<()> {
     add_del(iface, false)
 }
 
+#[cfg(feature = "tokio")]
+pub async fn enumerate_async() -> io::Result<Vec<InterfaceName>> {
+    let link_responses = netlink_request_rtnl_async(
+        RtnlMessage::GetLink(LinkMessage::default()),
+        Some(ENUMERATE_FLAGS),
+    )
+    .await?;
+    Ok(wireguard_links(link_responses))
+}
+
+#[cfg(feature = "tokio")]
+pub async fn get_by_name_async(name: &InterfaceName) -> io::Result<Device> {
+    parse_device(netlink_request_genl_async(get_message(name), Some(GET_FLAGS)).await?)
+}
+
+#[cfg(feature = "tokio")]
+pub async fn apply_async(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
+    let (message, flags) = add_del_message(iface, true);
+    add_del_result(netlink_request_rtnl_async(message, Some(flags)).await)?;
+    let mut messages = apply_messages(builder, iface)?.into_iter();
+    let mut result = Ok(());
+    for message in &mut messages {
+        let flags = Some(NLM_F_REQUEST | NLM_F_ACK);
+        if let Err(e) = netlink_request_genl_wiped_async(message, flags, wipe_message).await {
+            result = Err(e);
+            break;
+        }
+    }
+    // The messages left after a failure.
+    messages.for_each(|mut message| wipe_message(&mut message));
+    result
+}
+
+#[cfg(feature = "tokio")]
+pub async fn delete_interface_async(iface: &InterfaceName) -> io::Result<()> {
+    let (message, flags) = add_del_message(iface, false);
+    add_del_result(netlink_request_rtnl_async(message, Some(flags)).await)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::netlink_request::MAX_NETLINK_BUFFER_LENGTH;
+    use netlink_packet_generic::ctrl::GenlCtrlCmd;
     use netlink_packet_wireguard::nlas::WgAllowedIp;
-    use netlink_request::MAX_NETLINK_BUFFER_LENGTH;
     use std::str::FromStr;
 
+    #[test]
+    fn test_family_info() {
+        let response = NetlinkMessage::from(GenlMessage::from_payload(GenlCtrl {
+            cmd: GenlCtrlCmd::NewFamily,
+            nlas: vec![
+                GenlCtrlAttrs::FamilyId(30),
+                GenlCtrlAttrs::FamilyName("wireguard".to_string()),
+                GenlCtrlAttrs::Version(1),
+                GenlCtrlAttrs::MaxAttr(8),
+            ],
+        }));
+        assert_eq!(
+            family_info(&[response]),
+            Some(FamilyInfo {
+                id: 30,
+                version: 1,
+                max_attr: 8
+            })
+        );
+        assert_eq!(family_info(&[]), None);
+    }
+
+    #[test]
+    fn test_lists_wireguard() {
+        assert!(lists_wireguard(
+            "kernel/net/ipv4/udp_tunnel.ko.zst:\nkernel/drivers/net/wireguard/wireguard.ko.zst: kernel/net/ipv4/udp_tunnel.ko.zst\n"
+        ));
+        assert!(lists_wireguard(
+            "kernel/drivers/net/wireguard/wireguard.ko\n"
+        ));
+        assert!(!lists_wireguard("kernel/drivers/net/wireguard-extra.ko:\n"));
+    }
+
+    #[test]
+    fn test_parse_stats_split_peer() {
+        let message = |peers| {
+            NetlinkMessage::from(GenlMessage::from_payload(Wireguard {
+                cmd: WireguardCmd::GetDevice,
+                nlas: vec![WgDeviceAttrs::Peers(peers)],
+            }))
+        };
+        let allowed_ip = |octet: u8| {
+            WgAllowedIp(vec![
+                WgAllowedIpAttrs::Family(AF_INET),
+                WgAllowedIpAttrs::IpAddr([10, 0, 0, octet].into()),
+                WgAllowedIpAttrs::Cidr(32),
+            ])
+        };
+        let responses = [
+            message(vec![
+                WgPeer(vec![
+                    WgPeerAttrs::PublicKey([1u8; 32]),
+                    WgPeerAttrs::RxBytes(100),
+                    WgPeerAttrs::TxBytes(200),
+                ]),
+                WgPeer(vec![
+                    WgPeerAttrs::PublicKey([2u8; 32]),
+                    WgPeerAttrs::RxBytes(300),
+                    WgPeerAttrs::AllowedIps(vec![allowed_ip(1)]),
+                ]),
+            ]),
+            // The rest of the allowed IPs of the second peer.
+            message(vec![WgPeer(vec![
+                WgPeerAttrs::PublicKey([2u8; 32]),
+                WgPeerAttrs::AllowedIps(vec![allowed_ip(2)]),
+            ])]),
+        ];
+        let stats = parse_stats(&responses).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].0, Key([1u8; 32]));
+        assert_eq!(stats[1].0, Key([2u8; 32]));
+        assert_eq!(stats[1].1.rx_bytes, 300);
+    }
+
+    #[test]
+    fn test_parse_device_split_peer() {
+        let message = |peers| {
+            NetlinkMessage::from(GenlMessage::from_payload(Wireguard {
+                cmd: WireguardCmd::GetDevice,
+                nlas: vec![
+                    WgDeviceAttrs::IfName("wg0".to_string()),
+                    WgDeviceAttrs::Peers(peers),
+                ],
+            }))
+        };
+        let allowed_ip = |octet: u8| {
+            WgAllowedIp(vec![
+                WgAllowedIpAttrs::Family(AF_INET),
+                WgAllowedIpAttrs::IpAddr([10, 0, 0, octet].into()),
+                WgAllowedIpAttrs::Cidr(32),
+            ])
+        };
+        let responses = vec![
+            message(vec![
+                WgPeer(vec![
+                    WgPeerAttrs::PublicKey([1u8; 32]),
+                    WgPeerAttrs::AllowedIps(vec![allowed_ip(1)]),
+                ]),
+                WgPeer(vec![
+                    WgPeerAttrs::PublicKey([2u8; 32]),
+                    WgPeerAttrs::PersistentKeepalive(25),
+                    WgPeerAttrs::RxBytes(300),
+                    WgPeerAttrs::AllowedIps(vec![allowed_ip(2), allowed_ip(3)]),
+                ]),
+            ]),
+            // The second peer goes on over two more messages.
+            message(vec![WgPeer(vec![
+                WgPeerAttrs::PublicKey([2u8; 32]),
+                WgPeerAttrs::AllowedIps(vec![allowed_ip(4)]),
+            ])]),
+            message(vec![
+                WgPeer(vec![
+                    WgPeerAttrs::PublicKey([2u8; 32]),
+                    WgPeerAttrs::AllowedIps(vec![allowed_ip(5)]),
+                ]),
+                WgPeer(vec![
+                    WgPeerAttrs::PublicKey([3u8; 32]),
+                    WgPeerAttrs::AllowedIps(vec![allowed_ip(6)]),
+                ]),
+            ]),
+        ];
+        let device = parse_device(responses).unwrap();
+        let keys: Vec<_> = device
+            .peers
+            .iter()
+            .map(|peer| peer.config.public_key.clone())
+            .collect();
+        assert_eq!(keys, [Key([1u8; 32]), Key([2u8; 32]), Key([3u8; 32])]);
+        let split = &device.peers[1];
+        assert_eq!(split.config.persistent_keepalive_interval, Some(25));
+        assert_eq!(split.stats.rx_bytes, 300);
+        assert_eq!(
+            split.config.allowed_ips,
+            ["10.0.0.2/32", "10.0.0.3/32", "10.0.0.4/32", "10.0.0.5/32"]
+                .map(|ip| ip.parse::<AllowedIp>().unwrap())
+        );
+    }
+
     #[test]
     fn test_simple_payload() {
         let mut payload = ApplyPayload::new(&InterfaceName::from_str("wg0").unwrap());
@@ -0,0 +1,311 @@
+//! An in-memory backend for testing code that drives WireGuard interfaces,
+//! behind the `mock` feature.
+//!
+//! [`Backend::Mock`] interfaces live in a table shared by the whole process, and
+//! updates are applied the way the kernel applies them: peers merge unless the
+//! update replaces them, allowed IPs are stored as their network address and
+//! accumulate unless replaced, an allowed IP added to one peer is taken from any
+//! other, zero keys and values clear their
+//! setting, and listen ports can't be shared between interfaces. Tests running in
+//! parallel should use distinct interface names.
+use crate::{
+    allowed_ips::normalize, Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfig,
+    PeerInfo, PeerStats,
+};
+
+use std::{
+    io,
+    sync::{Mutex, MutexGuard},
+};
+
+/// The first port handed out for a listen port of 0.
+const EPHEMERAL_PORTS: u16 = 49152;
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(vec![]);
+
+fn devices() -> MutexGuard<'static, Vec<Device>> {
+    // A test that panicked while holding the lock leaves a consistent table.
+    DEVICES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn not_found(name: &InterfaceName) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no mock interface {}", name),
+    )
+}
+
+pub fn enumerate() -> io::Result<Vec<InterfaceName>> {
+    let mut names: Vec<_> = devices().iter().map(|device| device.name).collect();
+    names.sort_by_cached_key(|name| name.as_str_lossy().into_owned());
+    Ok(names)
+}
+
+pub fn get_by_name(name: &InterfaceName) -> io::Result<Device> {
+    devices()
+        .iter()
+        .find(|device| device.name == *name)
+        .cloned()
+        .ok_or_else(|| not_found(name))
+}
+
+pub fn delete_interface(name: &InterfaceName) -> io::Result<()> {
+    let mut devices = devices();
+    let index = devices
+        .iter()
+        .position(|device| device.name == *name)
+        .ok_or_else(|| not_found(name))?;
+    devices.remove(index);
+    Ok(())
+}
+
+/// Removes every mock interface.
+pub fn reset() {
+    devices().clear();
+}
+
+/// Sets the statistics of a peer, as if it had handshaken and passed traffic.
+pub fn set_stats(name: &InterfaceName, public_key: &Key, stats: PeerStats) -> io::Result<()> {
+    let mut devices = devices();
+    let device = devices
+        .iter_mut()
+        .find(|device| device.name == *name)
+        .ok_or_else(|| not_found(name))?;
+    let peer = device
+        .peers
+        .iter_mut()
+        .find(|peer| peer.config.public_key == *public_key)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such peer"))?;
+    peer.stats = stats;
+    Ok(())
+}
+
+fn new_device(name: &InterfaceName) -> Device {
+    Device {
+        name: *name,
+        public_key: None,
+        private_key: None,
+        fwmark: None,
+        listen_port: None,
+        peers: vec![],
+        linked_name: None,
+        backend: Backend::Mock,
+        __cant_construct_me: (),
+    }
+}
+
+/// Applies `update` to `device`, with `ports_in_use` taken by other interfaces.
+fn apply_to(device: &mut Device, update: &DeviceUpdate, ports_in_use: &[u16]) -> io::Result<()> {
+    update.validate()?;
+    if let Some(port) = update.listen_port {
+        let port = match port {
+            0 => (EPHEMERAL_PORTS..=u16::MAX)
+                .find(|port| !ports_in_use.contains(port))
+                .ok_or_else(|| io::Error::from(io::ErrorKind::AddrInUse))?,
+            port if ports_in_use.contains(&port) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("port {} is in use", port),
+                ))
+            }
+            port => port,
+        };
+        device.listen_port = Some(port);
+    }
+    if let Some(key) = &update.private_key {
+        let key = Some(key.clone()).filter(|key| *key != Key::zero());
+        device.public_key = key.as_ref().map(Key::get_public);
+        device.private_key = key;
+    }
+    if let Some(fwmark) = update.fwmark {
+        device.fwmark = Some(fwmark).filter(|fwmark| *fwmark != 0);
+    }
+
+    if update.replace_peers {
+        device.peers.clear();
+    }
+    for peer in &update.peers {
+        let position = device
+            .peers
+            .iter()
+            .position(|existing| existing.config.public_key == peer.public_key);
+        if peer.remove_me {
+            if let Some(position) = position {
+                device.peers.remove(position);
+            }
+            continue;
+        }
+        let position = position.unwrap_or_else(|| {
+            device.peers.push(PeerInfo {
+                config: PeerConfig {
+                    public_key: peer.public_key.clone(),
+                    preshared_key: None,
+                    endpoint: None,
+                    persistent_keepalive_interval: None,
+                    allowed_ips: vec![],
+                    __cant_construct_me: (),
+                },
+                stats: PeerStats::default(),
+            });
+            device.peers.len() - 1
+        });
+
+        // The kernel stores the network address, clearing any host bits.
+        let allowed_ips = peer
+            .allowed_ips
+            .iter()
+            .map(normalize)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Cryptokey routing: an allowed IP belongs to one peer at a time.
+        for (index, other) in device.peers.iter_mut().enumerate() {
+            if index != position {
+                other
+                    .config
+                    .allowed_ips
+                    .retain(|ip| !allowed_ips.contains(ip));
+            }
+        }
+        let config = &mut device.peers[position].config;
+        if let Some(key) = &peer.preshared_key {
+            config.preshared_key = Some(key.clone()).filter(|key| *key != Key::zero());
+        }
+        if let Some(endpoint) = peer.endpoint {
+            config.endpoint = Some(endpoint);
+        }
+        if let Some(interval) = peer.persistent_keepalive_interval {
+            config.persistent_keepalive_interval = Some(interval).filter(|interval| *interval != 0);
+        }
+        if peer.replace_allowed_ips {
+            config.allowed_ips.clear();
+        }
+        for ip in allowed_ips {
+            if !config.allowed_ips.contains(&ip) {
+                config.allowed_ips.push(ip);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies `update` to the mock interface `name`, creating it if needed. Like
+/// the kernel, a failed update leaves the interface as it was.
+pub fn apply(update: &DeviceUpdate, name: &InterfaceName) -> io::Result<()> {
+    let mut devices = devices();
+    let ports_in_use: Vec<u16> = devices
+        .iter()
+        .filter(|device| device.name != *name)
+        .filter_map(|device| device.listen_port)
+        .collect();
+    let mut device = devices
+        .iter()
+        .find(|device| device.name == *name)
+        .cloned()
+        .unwrap_or_else(|| new_device(name));
+    apply_to(&mut device, update, &ports_in_use)?;
+    match devices.iter_mut().find(|existing| existing.name == *name) {
+        Some(existing) => *existing = device,
+        None => devices.push(device),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerConfigBuilder;
+
+    fn name(name: &str) -> InterfaceName {
+        name.parse().unwrap()
+    }
+
+    fn peer(key: u8) -> PeerConfigBuilder {
+        PeerConfigBuilder::new(&Key([key; 32]))
+    }
+
+    #[test]
+    fn test_apply_semantics() {
+        let iface = name("mock-apply");
+        DeviceUpdate::new()
+            .set_listen_port(51820)
+            .add_peer(peer(1).add_allowed_ip("10.0.0.1".parse().unwrap(), 32))
+            .add_peer(peer(2).set_persistent_keepalive_interval(25))
+            .apply(&iface, Backend::Mock)
+            .unwrap();
+
+        // Merging appends allowed IPs and moves the ones other peers had.
+        DeviceUpdate::new()
+            .add_peer(peer(2).add_allowed_ip("10.0.0.1".parse().unwrap(), 32))
+            .add_peer(peer(2).add_allowed_ip("10.0.0.2".parse().unwrap(), 32))
+            .add_peer(peer(1).set_persistent_keepalive_interval(0))
+            .apply(&iface, Backend::Mock)
+            .unwrap();
+        let device = Device::get(&iface, Backend::Mock).unwrap();
+        assert_eq!(device.listen_port, Some(51820));
+        assert!(device.peers[0].config.allowed_ips.is_empty());
+        assert_eq!(device.peers[1].config.allowed_ips.len(), 2);
+        assert_eq!(
+            device.peers[1].config.persistent_keepalive_interval,
+            Some(25)
+        );
+
+        DeviceUpdate::new()
+            .remove_peer_by_key(&Key([1; 32]))
+            .add_peer(
+                peer(2)
+                    .replace_allowed_ips()
+                    .add_allowed_ip("10.0.0.3".parse().unwrap(), 32)
+                    .add_allowed_ip("10.1.2.3".parse().unwrap(), 16),
+            )
+            .apply(&iface, Backend::Mock)
+            .unwrap();
+        let device = Device::get(&iface, Backend::Mock).unwrap();
+        assert_eq!(device.peers.len(), 1);
+        assert_eq!(
+            device.peers[0].config.allowed_ips,
+            vec![
+                "10.0.0.3/32".parse().unwrap(),
+                "10.1.0.0/16".parse().unwrap()
+            ]
+        );
+
+        DeviceUpdate::new()
+            .replace_peers()
+            .add_peer(peer(3))
+            .apply(&iface, Backend::Mock)
+            .unwrap();
+        let device = Device::get(&iface, Backend::Mock).unwrap();
+        assert_eq!(device.peers.len(), 1);
+        assert_eq!(device.peers[0].config.public_key, Key([3; 32]));
+
+        device.delete().unwrap();
+        let error = Device::get(&iface, Backend::Mock).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_listen_ports() {
+        let (a, b) = (name("mock-port-a"), name("mock-port-b"));
+        DeviceUpdate::new()
+            .set_listen_port(51821)
+            .apply(&a, Backend::Mock)
+            .unwrap();
+        let error = DeviceUpdate::new()
+            .set_listen_port(51821)
+            .set_private_key(Key::generate_private())
+            .apply(&b, Backend::Mock)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        // The failed update created nothing.
+        assert!(!Device::list(Backend::Mock).unwrap().contains(&b));
+
+        DeviceUpdate::new()
+            .set_listen_port(0)
+            .apply(&b, Backend::Mock)
+            .unwrap();
+        let port = Device::get(&b, Backend::Mock).unwrap().listen_port;
+        assert!(port.is_some_and(|port| port >= EPHEMERAL_PORTS));
+        delete_interface(&a).unwrap();
+        delete_interface(&b).unwrap();
+    }
+}
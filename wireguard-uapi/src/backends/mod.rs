@@ -1,4 +1,23 @@
+pub mod cli;
+
 #[cfg(target_os = "linux")]
 pub mod kernel;
 
+#[cfg(feature = "mock")]
+pub mod mock;
+
+#[cfg(unix)]
 pub mod userspace;
+
+#[cfg(any(windows, test))]
+pub mod wireguard_nt;
+
+/// The error of [`Backend::Userspace`](crate::Backend::Userspace) on targets
+/// without Unix sockets, which its implementations are reached over.
+#[cfg(not(unix))]
+pub(crate) fn userspace_unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "the userspace backend needs Unix sockets",
+    )
+}
@@ -1,6 +1,10 @@
-use crate::{Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfig, PeerInfo, PeerStats};
+use crate::{
+    key::SecretBuf, Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfig, PeerInfo,
+    PeerStats,
+};
 
 use std::{
+    error, fmt,
     fmt::Write as _,
     fs,
     io::{self, prelude::*, BufReader},
@@ -9,9 +13,119 @@ use std::{
     process::{Command, Output},
     time::{Duration, SystemTime},
 };
+use zeroize::{Zeroize, Zeroizing};
 const VAR_RUN_PATH: &str = "/var/run/wireguard";
 const RUN_PATH: &str = "/run/wireguard";
 
+/// The capacity of the buffers responses are read into line by line, enough for
+/// any line with a key (`preshared_key=` and 64 hex digits). Reading one then
+/// never reallocates the buffer, which would leave an unwiped copy behind.
+const LINE_CAPACITY: usize = 128;
+
+/// A buffer for the lines of a response, wiped when dropped and wiped with
+/// [`Zeroize::zeroize`] rather than cleared between lines.
+fn line_buffer() -> Zeroizing<String> {
+    Zeroizing::new(String::with_capacity(LINE_CAPACITY))
+}
+
+/// What an `errno` reported by a userspace implementation means, per the
+/// cross-platform userspace API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UapiErrorKind {
+    /// `EIO`: the implementation failed to carry out the request, e.g. to bind.
+    Io,
+    /// `EPROTO`: the request was malformed, e.g. an unknown key or bad hex.
+    Protocol,
+    /// `EINVAL`: a value was invalid, e.g. a key of the wrong length.
+    Invalid,
+    /// `EADDRINUSE`: the listen port is taken.
+    PortInUse,
+    /// `ENODEV`: the interface is gone, e.g. its tun device was deleted.
+    NoDevice,
+    /// `EPERM` or `EACCES`: the implementation isn't allowed to carry out the
+    /// request, e.g. to bind a privileged port.
+    PermissionDenied,
+    /// A code the protocol doesn't document.
+    Unknown,
+}
+
+/// A request was rejected by a userspace implementation with a non-zero `errno`.
+///
+/// Converted into an [`io::Error`] whose kind reflects the [`UapiErrorKind`], and
+/// which can be turned back with [`UapiError::from_io`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UapiError {
+    pub errno: i32,
+}
+
+impl UapiError {
+    pub fn kind(&self) -> UapiErrorKind {
+        match self.errno {
+            libc::EIO => UapiErrorKind::Io,
+            libc::EPROTO => UapiErrorKind::Protocol,
+            libc::EINVAL => UapiErrorKind::Invalid,
+            libc::EADDRINUSE => UapiErrorKind::PortInUse,
+            libc::ENODEV => UapiErrorKind::NoDevice,
+            libc::EPERM | libc::EACCES => UapiErrorKind::PermissionDenied,
+            _ => UapiErrorKind::Unknown,
+        }
+    }
+
+    /// The [`UapiError`] an I/O error was created from, if any.
+    pub fn from_io(error: &io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for UapiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let meaning = match self.kind() {
+            UapiErrorKind::Io => "failed to apply",
+            UapiErrorKind::Protocol => "malformed request",
+            UapiErrorKind::Invalid => "invalid value",
+            UapiErrorKind::PortInUse => "listen port in use",
+            UapiErrorKind::NoDevice => "no such device",
+            UapiErrorKind::PermissionDenied => "permission denied",
+            UapiErrorKind::Unknown => "unknown error",
+        };
+        write!(
+            f,
+            "userspace WireGuard returned errno {} ({})",
+            self.errno, meaning
+        )
+    }
+}
+
+impl error::Error for UapiError {}
+
+impl From<UapiError> for io::Error {
+    fn from(e: UapiError) -> Self {
+        let kind = match e.kind() {
+            UapiErrorKind::Protocol | UapiErrorKind::Invalid => io::ErrorKind::InvalidInput,
+            UapiErrorKind::PortInUse => io::ErrorKind::AddrInUse,
+            UapiErrorKind::NoDevice => io::ErrorKind::NotFound,
+            UapiErrorKind::PermissionDenied => io::ErrorKind::PermissionDenied,
+            UapiErrorKind::Io | UapiErrorKind::Unknown => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// Checks the value of an `errno=` line. Implementations differ in whether they
+/// report the code negated.
+fn check_errno(value: &str) -> io::Result<()> {
+    let errno: i32 = value.trim_start_matches('-').parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid errno {:?}", value),
+        )
+    })?;
+    match errno {
+        0 => Ok(()),
+        errno => Err(UapiError { errno }.into()),
+    }
+}
+
 fn get_base_folder() -> io::Result<PathBuf> {
     let path = [VAR_RUN_PATH, RUN_PATH]
         .iter()
@@ -125,7 +239,9 @@ impl DeviceConfigParser {
         }
     }
 
-    fn add_line(&mut self, line: &str) -> io::Result<()> {
+    /// Parses one line of a `get` response, returning the previous peer once its
+    /// last attribute was seen.
+    fn add_line(&mut self, line: &str) -> io::Result<Option<PeerInfo>> {
         use io::ErrorKind::InvalidData;
 
         let split: Vec<&str> = line.splitn(2, '=').collect();
@@ -135,7 +251,7 @@ impl DeviceConfigParser {
         }
     }
 
-    fn add_pair(&mut self, key: &str, value: &str) -> io::Result<()> {
+    fn add_pair(&mut self, key: &str, value: &str) -> io::Result<Option<PeerInfo>> {
         use io::ErrorKind::InvalidData;
 
         match key {
@@ -150,9 +266,7 @@ impl DeviceConfigParser {
             "public_key" => {
                 let new_peer = new_peer_info(Key::from_hex(value).map_err(|_| InvalidData)?);
 
-                if let Some(finished_peer) = self.current_peer.replace(new_peer) {
-                    self.device.peers.push(finished_peer);
-                }
+                return Ok(self.current_peer.replace(new_peer));
             }
             "preshared_key" => {
                 self.current_peer
@@ -211,45 +325,254 @@ impl DeviceConfigParser {
             }
             "errno" => {
                 // "errno" indicates an end of the stream, along with the error return code.
-                if value != "0" {
-                    return Err(io::Error::from_raw_os_error(
-                        value
-                            .parse()
-                            .expect("Unable to parse userspace wg errno return code"),
-                    ));
+                check_errno(value)?;
+                return Ok(self.current_peer.take());
+            }
+            "protocol_version" | "last_handshake_time_nsec" => {}
+            _ => println!("got unsupported info: {}={}", key, value),
+        }
+
+        Ok(None)
+    }
+
+    /// Parses a whole `get` response, handing each peer to `on_peer` as soon as it
+    /// is complete.
+    fn read(
+        &mut self,
+        mut reader: impl BufRead,
+        mut on_peer: impl FnMut(PeerInfo) -> io::Result<()>,
+    ) -> io::Result<()> {
+        // The response carries the private key.
+        let mut buf = line_buffer();
+        loop {
+            buf.zeroize();
+            match reader.read_line(&mut buf)? {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "response ended before errno",
+                    ))
+                }
+                1 if *buf == "\n" => return Ok(()),
+                _ => {
+                    if let Some(peer) = self.add_line(buf.trim_end())? {
+                        on_peer(peer)?;
+                    }
                 }
+            }
+        }
+    }
 
-                if let Some(finished_peer) = self.current_peer.take() {
-                    self.device.peers.push(finished_peer);
+    /// Like [`read`](DeviceConfigParser::read), from an async reader.
+    #[cfg(feature = "tokio")]
+    async fn read_async(
+        &mut self,
+        mut reader: impl tokio::io::AsyncBufRead + Unpin,
+        mut on_peer: impl FnMut(PeerInfo) -> io::Result<()>,
+    ) -> io::Result<()> {
+        use tokio::io::AsyncBufReadExt;
+
+        // The response carries the private key.
+        let mut buf = line_buffer();
+        loop {
+            buf.zeroize();
+            match reader.read_line(&mut buf).await? {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "response ended before errno",
+                    ))
+                }
+                1 if *buf == "\n" => return Ok(()),
+                _ => {
+                    if let Some(peer) = self.add_line(buf.trim_end())? {
+                        on_peer(peer)?;
+                    }
                 }
             }
-            "protocol_version" | "last_handshake_time_nsec" => {}
-            _ => println!("got unsupported info: {}={}", key, value),
         }
+    }
 
-        Ok(())
+    /// Parses a whole `get` response read into memory. With the `parallel`
+    /// feature, the peers are parsed on the rayon thread pool.
+    #[cfg(any(feature = "parallel", test))]
+    fn parse(mut self, response: &str) -> io::Result<Device> {
+        let (header, chunks) = split_peers(response);
+        for line in header.lines() {
+            self.add_line(line)?;
+        }
+        let template = self.device;
+        let parse_peer = |chunk: &str| {
+            let mut parser = DeviceConfigParser {
+                device: template.clone(),
+                current_peer: None,
+            };
+            let mut peer = None;
+            for line in chunk.lines() {
+                // Only the closing `errno` hands the peer back.
+                peer = parser.add_line(line)?.or(peer);
+            }
+            peer.or(parser.current_peer)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))
+        };
+        #[cfg(feature = "parallel")]
+        let peers = {
+            use rayon::prelude::*;
+            chunks
+                .into_par_iter()
+                .map(parse_peer)
+                .collect::<io::Result<Vec<_>>>()?
+        };
+        #[cfg(not(feature = "parallel"))]
+        let peers = chunks
+            .into_iter()
+            .map(parse_peer)
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Device { peers, ..template })
     }
 }
 
-pub fn get_by_name(name: &InterfaceName) -> io::Result<Device> {
-    let mut sock = open_socket(name)?;
+/// Splits the lines of a `get` response into those before the first peer and
+/// those of each peer, including the closing `errno` in the last one.
+#[cfg(any(feature = "parallel", test))]
+fn split_peers(response: &str) -> (&str, Vec<&str>) {
+    let mut starts = response
+        .match_indices("public_key=")
+        .map(|(i, _)| i)
+        .filter(|i| *i == 0 || response.as_bytes()[i - 1] == b'\n');
+    let Some(first) = starts.next() else {
+        return (response, vec![]);
+    };
+    let mut chunks = vec![];
+    let mut start = first;
+    for next in starts {
+        chunks.push(&response[start..next]);
+        start = next;
+    }
+    chunks.push(&response[start..]);
+    (&response[..first], chunks)
+}
+
+/// Reads a `get` response up to the blank line ending it, without the blank line.
+#[cfg(feature = "parallel")]
+fn read_response(mut reader: impl BufRead) -> io::Result<Zeroizing<String>> {
+    let mut response = SecretBuf::default();
+    let mut line = line_buffer();
+    loop {
+        line.zeroize();
+        match reader.read_line(&mut line)? {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "response ended before errno",
+                ))
+            }
+            1 if *line == "\n" => return Ok(response.into_inner()),
+            _ => response.push_str(&line),
+        }
+    }
+}
+
+/// Connects to the socket of `name` and asks for its configuration.
+fn request_get(name: &InterfaceName) -> io::Result<UnixStream> {
+    let mut sock = match open_socket(name) {
+        Ok(sock) => sock,
+        Err(e) => {
+            #[cfg(target_os = "macos")]
+            crate::macos::ensure_not_managed(name)?;
+            return Err(e);
+        }
+    };
     sock.write_all(b"get=1\n\n")?;
-    let mut reader = BufReader::new(sock);
-    let mut buf = String::new();
+    Ok(sock)
+}
 
+#[cfg(not(feature = "parallel"))]
+pub fn get_by_name(name: &InterfaceName) -> io::Result<Device> {
+    let mut peers = vec![];
+    let mut device = get_by_name_streaming(name, |peer| {
+        peers.push(peer);
+        Ok(())
+    })?;
+    device.peers = peers;
+    Ok(device)
+}
+
+/// Reads the whole response before parsing its peers in parallel, trading the
+/// memory of holding it for the latency of parsing devices with tens of thousands
+/// of peers on one core.
+#[cfg(feature = "parallel")]
+pub fn get_by_name(name: &InterfaceName) -> io::Result<Device> {
+    let sock = request_get(name)?;
+    let response = read_response(BufReader::with_capacity(64 * 1024, sock))?;
+    DeviceConfigParser::new(name).parse(&response)
+}
+
+/// Like [`get_by_name`], but hands each peer to `on_peer` as soon as it is parsed
+/// instead of collecting them, so memory use doesn't grow with the peer count of
+/// interfaces with hundreds of thousands of peers. The returned device has no
+/// peers.
+///
+/// An error returned by `on_peer` stops reading and is passed through.
+pub fn get_by_name_streaming(
+    name: &InterfaceName,
+    on_peer: impl FnMut(PeerInfo) -> io::Result<()>,
+) -> io::Result<Device> {
+    let sock = request_get(name)?;
     let mut parser = DeviceConfigParser::new(name);
+    parser.read(BufReader::with_capacity(64 * 1024, sock), on_peer)?;
+    Ok(parser.into())
+}
 
+/// Parses a `get` response into the statistics of its peers, skipping the
+/// attributes that don't affect them without parsing their values.
+fn read_stats(mut reader: impl BufRead) -> io::Result<Vec<(Key, PeerStats)>> {
+    use io::ErrorKind::InvalidData;
+
+    let mut stats: Vec<(Key, PeerStats)> = vec![];
+    // The response carries the private key, though it's skipped.
+    let mut buf = line_buffer();
     loop {
-        match reader.read_line(&mut buf)? {
-            0 | 1 if buf == "\n" => break,
-            _ => {
-                parser.add_line(buf.trim_end())?;
-                buf.clear();
+        buf.zeroize();
+        if reader.read_line(&mut buf)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "response ended before errno",
+            ));
+        }
+        let line = buf.trim_end();
+        if line.is_empty() {
+            return Ok(stats);
+        }
+        let (key, value) = line.split_once('=').ok_or(InvalidData)?;
+        match key {
+            "public_key" => stats.push((
+                Key::from_hex(value).map_err(|_| InvalidData)?,
+                PeerStats::default(),
+            )),
+            "rx_bytes" | "tx_bytes" | "last_handshake_time_sec" => {
+                let (_, current) = stats.last_mut().ok_or(InvalidData)?;
+                let value: u64 = value.parse().map_err(|_| InvalidData)?;
+                match key {
+                    "rx_bytes" => current.rx_bytes = value,
+                    "tx_bytes" => current.tx_bytes = value,
+                    _ if value > 0 => {
+                        current.last_handshake_time =
+                            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(value))
+                    }
+                    _ => {}
+                }
             }
-        };
+            "errno" => check_errno(value)?,
+            _ => {}
+        }
     }
+}
 
-    Ok(parser.into())
+/// The statistics of every peer of `name`, without parsing their allowed IPs and
+/// other configuration.
+pub fn get_stats(name: &InterfaceName) -> io::Result<Vec<(Key, PeerStats)>> {
+    read_stats(BufReader::with_capacity(64 * 1024, request_get(name)?))
 }
 
 /// Following the rough logic of wg-quick(8), use the wireguard-go userspace
@@ -259,25 +582,28 @@ pub fn get_by_name(name: &InterfaceName) -> io::Result<Device> {
 /// wgctrl-rs will look for WG_USERSPACE_IMPLEMENTATION first, but will also
 /// respect the WG_QUICK_USERSPACE_IMPLEMENTATION choice if the former isn't
 /// available.
-fn get_userspace_implementation() -> String {
+pub(crate) fn get_userspace_implementation() -> String {
     std::env::var("WG_USERSPACE_IMPLEMENTATION")
         .or_else(|_| std::env::var("WG_QUICK_USERSPACE_IMPLEMENTATION"))
         .unwrap_or_else(|_| "wireguard-go".to_string())
 }
 
-fn start_userspace_wireguard(iface: &InterfaceName) -> io::Result<Output> {
-    let mut command = Command::new(&get_userspace_implementation());
-    let output = if cfg!(target_os = "linux") {
-        command.args(&[iface.to_string()]).output()?
+fn userspace_command(iface: &InterfaceName) -> Command {
+    let mut command = Command::new(get_userspace_implementation());
+    if cfg!(target_os = "linux") {
+        command.args([iface.to_string()]);
     } else {
         command
             .env(
                 "WG_TUN_NAME_FILE",
-                &format!("{}/{}.name", VAR_RUN_PATH, iface),
+                format!("{}/{}.name", VAR_RUN_PATH, iface),
             )
-            .args(["utun"])
-            .output()?
-    };
+            .args(["utun"]);
+    }
+    command
+}
+
+fn check_started(output: Output) -> io::Result<Output> {
     if !output.status.success() {
         Err(io::ErrorKind::AddrNotAvailable.into())
     } else {
@@ -285,10 +611,17 @@ fn start_userspace_wireguard(iface: &InterfaceName) -> io::Result<Output> {
     }
 }
 
+fn start_userspace_wireguard(iface: &InterfaceName) -> io::Result<Output> {
+    check_started(userspace_command(iface).output()?)
+}
+
 pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
     // If we can't open a configuration socket to an existing interface, try starting it.
     let mut sock = match open_socket(iface) {
         Err(_) => {
+            // Tunnels of the WireGuard app have no socket, and mustn't be shadowed.
+            #[cfg(target_os = "macos")]
+            crate::macos::ensure_not_managed(iface)?;
             fs::create_dir_all(VAR_RUN_PATH)?;
             // Clear out any old namefiles if they didn't lead to a connected socket.
             let _ = fs::remove_file(get_alias_name_file(iface)?);
@@ -300,10 +633,28 @@ pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
         Ok(sock) => sock,
     };
 
-    let mut request = String::from("set=1\n");
+    sock.write_all(set_request(builder).as_bytes())?;
+
+    let mut reader = BufReader::new(sock);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    check_set_response(&line)
+}
+
+/// Writes `name=<key in hex>`, wiping the intermediate hex string.
+fn write_key(request: &mut SecretBuf, name: &str, key: &Key) {
+    let hex = Zeroizing::new(hex::encode(key.as_bytes()));
+    writeln!(request, "{}={}", name, *hex).ok();
+}
+
+/// The `set` request applying `builder`, wiped when dropped as it may carry the
+/// private and preshared keys.
+fn set_request(builder: &DeviceUpdate) -> Zeroizing<String> {
+    let mut request = SecretBuf::default();
+    request.push_str("set=1\n");
 
     if let Some(ref k) = builder.private_key {
-        writeln!(request, "private_key={}", hex::encode(k.as_bytes())).ok();
+        write_key(&mut request, "private_key", k);
     }
 
     if let Some(f) = builder.fwmark {
@@ -335,7 +686,7 @@ pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
         }
 
         if let Some(ref k) = peer.preshared_key {
-            writeln!(request, "preshared_key={}", hex::encode(k.as_bytes())).ok();
+            write_key(&mut request, "preshared_key", k);
         }
 
         if let Some(endpoint) = peer.endpoint {
@@ -361,21 +712,263 @@ pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
         }
     }
 
-    request.push('\n');
-
-    sock.write_all(request.as_bytes())?;
-
-    let mut reader = BufReader::new(sock);
-    let mut line = String::new();
+    request.push_str("\n");
+    request.into_inner()
+}
 
-    reader.read_line(&mut line)?;
+fn check_set_response(line: &str) -> io::Result<()> {
     let split: Vec<&str> = line.trim_end().splitn(2, '=').collect();
     match &split[..] {
-        ["errno", "0"] => Ok(()),
-        ["errno", val] => {
-            println!("ERROR {}", val);
-            Err(io::ErrorKind::InvalidInput.into())
+        ["errno", value] => check_errno(value),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected response {:?}", line.trim_end()),
+        )),
+    }
+}
+
+#[cfg(feature = "tokio")]
+async fn open_socket_async(name: &InterfaceName) -> io::Result<tokio::net::UnixStream> {
+    tokio::net::UnixStream::connect(get_socket_file(name)?).await
+}
+
+#[cfg(feature = "tokio")]
+pub async fn enumerate_async() -> io::Result<Vec<InterfaceName>> {
+    use std::ffi::OsStr;
+
+    let mut interfaces = vec![];
+    let mut entries = tokio::fs::read_dir(get_base_folder()?).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension() != Some(OsStr::new("name")) {
+            continue;
+        }
+        let iface = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|name| name.parse::<InterfaceName>().ok());
+        if let Some(iface) = iface {
+            if open_socket_async(&iface).await.is_ok() {
+                interfaces.push(iface);
+            }
+        }
+    }
+
+    Ok(interfaces)
+}
+
+#[cfg(feature = "tokio")]
+pub async fn get_by_name_async(name: &InterfaceName) -> io::Result<Device> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut sock = match open_socket_async(name).await {
+        Ok(sock) => sock,
+        Err(e) => {
+            #[cfg(target_os = "macos")]
+            crate::macos::ensure_not_managed(name)?;
+            return Err(e);
+        }
+    };
+    sock.write_all(b"get=1\n\n").await?;
+
+    let mut parser = DeviceConfigParser::new(name);
+    let mut peers = vec![];
+    let reader = tokio::io::BufReader::with_capacity(64 * 1024, sock);
+    parser
+        .read_async(reader, |peer| {
+            peers.push(peer);
+            Ok(())
+        })
+        .await?;
+    let mut device = Device::from(parser);
+    device.peers = peers;
+    Ok(device)
+}
+
+#[cfg(feature = "tokio")]
+pub async fn apply_async(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let mut sock = match open_socket_async(iface).await {
+        Err(_) => {
+            #[cfg(target_os = "macos")]
+            crate::macos::ensure_not_managed(iface)?;
+            tokio::fs::create_dir_all(VAR_RUN_PATH).await?;
+            let _ = tokio::fs::remove_file(get_alias_name_file(iface)?).await;
+            let mut command = tokio::process::Command::from(userspace_command(iface));
+            check_started(command.output().await?)?;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            open_socket_async(iface)
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("failed to open socket ({})", e)))?
         }
-        _ => Err(io::ErrorKind::Other.into()),
+        Ok(sock) => sock,
+    };
+
+    sock.write_all(set_request(builder).as_bytes()).await?;
+
+    let mut reader = tokio::io::BufReader::new(sock);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    check_set_response(&line)
+}
+
+#[cfg(feature = "tokio")]
+pub async fn delete_interface_async(name: &InterfaceName) -> io::Result<()> {
+    tokio::fs::remove_file(get_socket_file(name)?).await?;
+    tokio::fs::remove_file(get_alias_name_file(name)?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_streams_peers() {
+        let response = format!(
+            "private_key={}\nlisten_port=51820\n\
+             public_key={}\nrx_bytes=10\nallowed_ip=10.0.0.2/32\n\
+             public_key={}\ntx_bytes=20\n\
+             errno=0\n\n",
+            hex::encode([1u8; 32]),
+            hex::encode([2u8; 32]),
+            hex::encode([3u8; 32]),
+        );
+
+        let mut parser = DeviceConfigParser::new(&"wg0".parse().unwrap());
+        let mut peers = vec![];
+        parser
+            .read(response.as_bytes(), |peer| {
+                peers.push(peer);
+                Ok(())
+            })
+            .unwrap();
+        let device = Device::from(parser);
+        assert_eq!(device.listen_port, Some(51820));
+        assert!(device.peers.is_empty());
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].config.public_key, Key([2u8; 32]));
+        assert_eq!(peers[0].stats.rx_bytes, 10);
+        assert_eq!(peers[1].stats.tx_bytes, 20);
+
+        let mut parser = DeviceConfigParser::new(&"wg0".parse().unwrap());
+        let truncated = &response[..response.find("errno").unwrap()];
+        let error = parser.read(truncated.as_bytes(), |_| Ok(())).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_parse() {
+        let response = format!(
+            "private_key={}\nlisten_port=51820\n\
+             public_key={}\nrx_bytes=10\nallowed_ip=10.0.0.2/32\n\
+             public_key={}\ntx_bytes=20\n\
+             errno=0\n",
+            hex::encode([1u8; 32]),
+            hex::encode([2u8; 32]),
+            hex::encode([3u8; 32]),
+        );
+        let (header, chunks) = split_peers(&response);
+        assert!(header.starts_with("private_key="));
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[1].ends_with("errno=0\n"));
+
+        let device = DeviceConfigParser::new(&"wg0".parse().unwrap())
+            .parse(&response)
+            .unwrap();
+        assert_eq!(device.listen_port, Some(51820));
+        assert_eq!(device.peers.len(), 2);
+        assert_eq!(device.peers[0].config.public_key, Key([2u8; 32]));
+        assert_eq!(device.peers[0].config.allowed_ips.len(), 1);
+        assert_eq!(device.peers[1].stats.tx_bytes, 20);
+
+        let device = DeviceConfigParser::new(&"wg0".parse().unwrap())
+            .parse("listen_port=51820\nerrno=0\n")
+            .unwrap();
+        assert!(device.peers.is_empty());
+
+        let error = DeviceConfigParser::new(&"wg0".parse().unwrap())
+            .parse(&response.replace("errno=0", "errno=-5"))
+            .unwrap_err();
+        assert_eq!(UapiError::from_io(&error), Some(&UapiError { errno: 5 }));
+    }
+
+    #[test]
+    fn test_read_stats() {
+        let response = format!(
+            "private_key={}\nlisten_port=51820\n\
+             public_key={}\nrx_bytes=10\nallowed_ip=10.0.0.2/32\nlast_handshake_time_sec=1000\n\
+             public_key={}\nallowed_ip=not an ip\ntx_bytes=20\n\
+             errno=0\n\n",
+            hex::encode([1u8; 32]),
+            hex::encode([2u8; 32]),
+            hex::encode([3u8; 32]),
+        );
+        let stats = read_stats(response.as_bytes()).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].0, Key([2u8; 32]));
+        assert_eq!(stats[0].1.rx_bytes, 10);
+        assert_eq!(
+            stats[0].1.last_handshake_time,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1000))
+        );
+        assert_eq!(stats[1].1.tx_bytes, 20);
+
+        let error = read_stats("errno=-5\n\n".as_bytes()).unwrap_err();
+        assert_eq!(UapiError::from_io(&error), Some(&UapiError { errno: 5 }));
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn test_read_async() {
+        let response = format!(
+            "listen_port=51820\npublic_key={}\nallowed_ip=10.0.0.2/32\nerrno=0\n\n",
+            hex::encode([2u8; 32]),
+        );
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let mut parser = DeviceConfigParser::new(&"wg0".parse().unwrap());
+        let mut peers = vec![];
+        runtime
+            .block_on(parser.read_async(response.as_bytes(), |peer| {
+                peers.push(peer);
+                Ok(())
+            }))
+            .unwrap();
+        assert_eq!(Device::from(parser).listen_port, Some(51820));
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].config.allowed_ips.len(), 1);
+
+        let mut parser = DeviceConfigParser::new(&"wg0".parse().unwrap());
+        let truncated = &response[..response.find("errno").unwrap()];
+        let error = runtime
+            .block_on(parser.read_async(truncated.as_bytes(), |_| Ok(())))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_check_errno() {
+        assert!(check_errno("0").is_ok());
+
+        let error = check_errno("-22").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let uapi = UapiError::from_io(&error).unwrap();
+        assert_eq!(uapi.errno, libc::EINVAL);
+        assert_eq!(uapi.kind(), UapiErrorKind::Invalid);
+
+        let error = check_errno(&libc::EADDRINUSE.to_string()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(
+            UapiError::from_io(&error).map(UapiError::kind),
+            Some(UapiErrorKind::PortInUse)
+        );
+
+        assert_eq!(
+            check_errno("nope").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
     }
 }
@@ -0,0 +1,661 @@
+//! The WireGuardNT kernel driver on Windows, through the adapter API of
+//! `wireguard.dll`.
+//!
+//! `wireguard.dll` is loaded at runtime from the application directory or
+//! System32, so binaries run (and report the backend as unavailable) on hosts
+//! without it. Configurations cross the API as one buffer: a
+//! `WIREGUARD_INTERFACE`, then each `WIREGUARD_PEER` followed by its
+//! `WIREGUARD_ALLOWED_IP`s, all 8-byte aligned. Building and reading that buffer
+//! is platform independent.
+//!
+//! Adapters created by [`apply`] are owned by this process and removed by the
+//! driver once it exits, like `wireguard-go` interfaces on Unix. Adapters of other
+//! processes, such as the WireGuard for Windows tunnel services, can be read and
+//! configured but not deleted.
+//!
+//! The buffers carrying the private and preshared keys are wiped once used.
+use crate::{
+    device::AllowedIp, DeviceUpdate, Key, PeerConfig, PeerConfigBuilder, PeerInfo, PeerStats,
+};
+
+use std::{
+    convert::TryInto,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::{Duration, UNIX_EPOCH},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+const INTERFACE_LEN: usize = 80;
+const PEER_LEN: usize = 136;
+const ALLOWED_IP_LEN: usize = 24;
+
+const INTERFACE_HAS_PUBLIC_KEY: u32 = 1 << 0;
+const INTERFACE_HAS_PRIVATE_KEY: u32 = 1 << 1;
+const INTERFACE_HAS_LISTEN_PORT: u32 = 1 << 2;
+const INTERFACE_REPLACE_PEERS: u32 = 1 << 3;
+
+const PEER_HAS_PUBLIC_KEY: u32 = 1 << 0;
+const PEER_HAS_PRESHARED_KEY: u32 = 1 << 1;
+const PEER_HAS_PERSISTENT_KEEPALIVE: u32 = 1 << 2;
+const PEER_HAS_ENDPOINT: u32 = 1 << 3;
+const PEER_REPLACE_ALLOWED_IPS: u32 = 1 << 5;
+const PEER_REMOVE: u32 = 1 << 6;
+
+/// The Windows `AF_INET` and `AF_INET6`.
+const AF_INET: u16 = 2;
+const AF_INET6: u16 = 23;
+
+/// Seconds from 1601-01-01, the epoch of `FILETIME`, to the Unix epoch.
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+/// What `WireGuardGetConfiguration` reports about an adapter.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Interface {
+    public_key: Option<Key>,
+    private_key: Option<Key>,
+    listen_port: Option<u16>,
+    peers: Vec<PeerInfo>,
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn key_at(buf: &[u8], offset: usize) -> Key {
+    Key(buf[offset..offset + 32].try_into().unwrap())
+}
+
+/// Writes `endpoint` as a 28-byte `SOCKADDR_INET` at the start of `buf`.
+fn put_endpoint(buf: &mut [u8], endpoint: SocketAddr) {
+    // The port is in network byte order.
+    buf[2..4].copy_from_slice(&endpoint.port().to_be_bytes());
+    match endpoint {
+        SocketAddr::V4(endpoint) => {
+            put_u16(buf, 0, AF_INET);
+            buf[4..8].copy_from_slice(&endpoint.ip().octets());
+        }
+        SocketAddr::V6(endpoint) => {
+            put_u16(buf, 0, AF_INET6);
+            buf[4..8].copy_from_slice(&endpoint.flowinfo().to_be_bytes());
+            buf[8..24].copy_from_slice(&endpoint.ip().octets());
+            put_u32(buf, 24, endpoint.scope_id());
+        }
+    }
+}
+
+fn endpoint_at(buf: &[u8]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    match u16_at(buf, 0) {
+        AF_INET => {
+            let ip: [u8; 4] = buf[4..8].try_into().unwrap();
+            Some(SocketAddrV4::new(ip.into(), port).into())
+        }
+        AF_INET6 => {
+            let ip: [u8; 16] = buf[8..24].try_into().unwrap();
+            let flowinfo = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+            Some(SocketAddrV6::new(ip.into(), port, flowinfo, u32_at(buf, 24)).into())
+        }
+        _ => None,
+    }
+}
+
+fn encode_allowed_ip(buf: &mut Vec<u8>, allowed_ip: &AllowedIp) {
+    let mut entry = [0u8; ALLOWED_IP_LEN];
+    let family = match allowed_ip.address {
+        IpAddr::V4(address) => {
+            entry[..4].copy_from_slice(&address.octets());
+            AF_INET
+        }
+        IpAddr::V6(address) => {
+            entry[..16].copy_from_slice(&address.octets());
+            AF_INET6
+        }
+    };
+    put_u16(&mut entry, 16, family);
+    entry[18] = allowed_ip.cidr;
+    buf.extend_from_slice(&entry);
+}
+
+fn encode_peer(buf: &mut Vec<u8>, peer: &PeerConfigBuilder) -> io::Result<()> {
+    let mut entry = [0u8; PEER_LEN];
+    let mut flags = PEER_HAS_PUBLIC_KEY;
+    entry[8..40].copy_from_slice(peer.public_key.as_bytes());
+    if let Some(ref key) = peer.preshared_key {
+        flags |= PEER_HAS_PRESHARED_KEY;
+        entry[40..72].copy_from_slice(key.as_bytes());
+    }
+    if let Some(interval) = peer.persistent_keepalive_interval {
+        flags |= PEER_HAS_PERSISTENT_KEEPALIVE;
+        put_u16(&mut entry, 72, interval);
+    }
+    if let Some(endpoint) = peer.endpoint {
+        flags |= PEER_HAS_ENDPOINT;
+        put_endpoint(&mut entry[76..104], endpoint);
+    }
+    if peer.replace_allowed_ips {
+        flags |= PEER_REPLACE_ALLOWED_IPS;
+    }
+    if peer.remove_me {
+        flags |= PEER_REMOVE;
+    }
+    put_u32(&mut entry, 0, flags);
+    let count = peer
+        .allowed_ips
+        .len()
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many allowed IPs"))?;
+    put_u32(&mut entry, 128, count);
+    buf.extend_from_slice(&entry);
+    entry[..].zeroize();
+    for allowed_ip in &peer.allowed_ips {
+        encode_allowed_ip(buf, allowed_ip);
+    }
+    Ok(())
+}
+
+/// The `WireGuardSetConfiguration` buffer for `update`, allocated at its final
+/// size so that growing it leaves no copy of the keys behind.
+fn encode(update: &DeviceUpdate) -> io::Result<Zeroizing<Vec<u8>>> {
+    if update.fwmark.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "WireGuardNT doesn't support fwmarks",
+        ));
+    }
+    let mut header = [0u8; INTERFACE_LEN];
+    let mut flags = 0;
+    if let Some(ref key) = update.private_key {
+        flags |= INTERFACE_HAS_PRIVATE_KEY;
+        header[6..38].copy_from_slice(key.as_bytes());
+    }
+    if let Some(port) = update.listen_port {
+        flags |= INTERFACE_HAS_LISTEN_PORT;
+        put_u16(&mut header, 4, port);
+    }
+    if update.replace_peers {
+        flags |= INTERFACE_REPLACE_PEERS;
+    }
+    put_u32(&mut header, 0, flags);
+    let count = update
+        .peers
+        .len()
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many peers"))?;
+    put_u32(&mut header, 72, count);
+
+    let len = INTERFACE_LEN
+        + update
+            .peers
+            .iter()
+            .map(|peer| PEER_LEN + peer.allowed_ips.len() * ALLOWED_IP_LEN)
+            .sum::<usize>();
+    let mut buf = Zeroizing::new(Vec::with_capacity(len));
+    buf.extend_from_slice(&header);
+    header[..].zeroize();
+    for peer in &update.peers {
+        encode_peer(&mut buf, peer)?;
+    }
+    Ok(buf)
+}
+
+/// Takes the next `len` bytes of `buf`.
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated WireGuardNT configuration",
+        ));
+    }
+    let (taken, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(taken)
+}
+
+fn decode_allowed_ip(entry: &[u8]) -> io::Result<AllowedIp> {
+    let address = match u16_at(entry, 16) {
+        AF_INET => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&entry[..4]).unwrap())),
+        AF_INET6 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&entry[..16]).unwrap())),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown allowed IP address family",
+            ))
+        }
+    };
+    Ok(AllowedIp {
+        address,
+        cidr: entry[18],
+    })
+}
+
+fn decode_peer(buf: &mut &[u8]) -> io::Result<PeerInfo> {
+    let entry = take(buf, PEER_LEN)?;
+    let flags = u32_at(entry, 0);
+    let allowed_ips = (0..u32_at(entry, 128))
+        .map(|_| decode_allowed_ip(take(buf, ALLOWED_IP_LEN)?))
+        .collect::<io::Result<_>>()?;
+    // 100 ns intervals since 1601, or zero without a handshake.
+    let last_handshake_time = match u64_at(entry, 120) {
+        0 => None,
+        time => {
+            let since_1601 = Duration::from_nanos(time.saturating_mul(100));
+            since_1601
+                .checked_sub(Duration::from_secs(FILETIME_UNIX_OFFSET))
+                .map(|since_1970| UNIX_EPOCH + since_1970)
+        }
+    };
+    Ok(PeerInfo {
+        config: PeerConfig {
+            public_key: key_at(entry, 8),
+            preshared_key: (flags & PEER_HAS_PRESHARED_KEY != 0)
+                .then(|| key_at(entry, 40))
+                .filter(|key| *key != Key::zero()),
+            endpoint: (flags & PEER_HAS_ENDPOINT != 0)
+                .then(|| endpoint_at(&entry[76..104]))
+                .flatten(),
+            persistent_keepalive_interval: (flags & PEER_HAS_PERSISTENT_KEEPALIVE != 0)
+                .then(|| u16_at(entry, 72)),
+            allowed_ips,
+            __cant_construct_me: (),
+        },
+        stats: PeerStats {
+            last_handshake_time,
+            tx_bytes: u64_at(entry, 104),
+            rx_bytes: u64_at(entry, 112),
+        },
+    })
+}
+
+/// Reads a `WireGuardGetConfiguration` buffer.
+fn decode(mut buf: &[u8]) -> io::Result<Interface> {
+    let header = take(&mut buf, INTERFACE_LEN)?;
+    let flags = u32_at(header, 0);
+    let peers = (0..u32_at(header, 72))
+        .map(|_| decode_peer(&mut buf))
+        .collect::<io::Result<_>>()?;
+    Ok(Interface {
+        public_key: (flags & INTERFACE_HAS_PUBLIC_KEY != 0).then(|| key_at(header, 38)),
+        private_key: (flags & INTERFACE_HAS_PRIVATE_KEY != 0).then(|| key_at(header, 6)),
+        listen_port: (flags & INTERFACE_HAS_LISTEN_PORT != 0).then(|| u16_at(header, 4)),
+        peers,
+    })
+}
+
+#[cfg(windows)]
+pub use self::windows::*;
+
+#[cfg(windows)]
+mod windows {
+    use super::{decode, encode, Interface};
+    use crate::{Backend, Device, DeviceUpdate, InterfaceName};
+
+    use std::{
+        collections::HashMap,
+        ffi::{c_void, OsStr, OsString},
+        io, iter, mem,
+        os::windows::ffi::{OsStrExt, OsStringExt},
+        ptr,
+        sync::{Mutex, OnceLock},
+    };
+    use zeroize::Zeroizing;
+
+    type Handle = *mut c_void;
+
+    const LOAD_LIBRARY_SEARCH_APPLICATION_DIR: u32 = 0x0000_0200;
+    const LOAD_LIBRARY_SEARCH_SYSTEM32: u32 = 0x0000_0800;
+    const ERROR_MORE_DATA: i32 = 234;
+    const ERROR_BUFFER_OVERFLOW: u32 = 111;
+    const GAA_FLAG_SKIP_ADDRESSES: u32 = 0x1 | 0x2 | 0x4 | 0x8;
+    const ADAPTER_STATE_UP: i32 = 1;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LoadLibraryExW(name: *const u16, file: Handle, flags: u32) -> Handle;
+        fn GetProcAddress(module: Handle, name: *const u8) -> *mut c_void;
+    }
+
+    #[link(name = "iphlpapi")]
+    extern "system" {
+        fn GetAdaptersAddresses(
+            family: u32,
+            flags: u32,
+            reserved: *mut c_void,
+            addresses: *mut AdapterAddresses,
+            size: *mut u32,
+        ) -> u32;
+    }
+
+    /// The leading fields of `IP_ADAPTER_ADDRESSES`, up to the friendly name.
+    #[repr(C)]
+    struct AdapterAddresses {
+        length_and_index: u64,
+        next: *mut AdapterAddresses,
+        adapter_name: *const u8,
+        first_unicast_address: *mut c_void,
+        first_anycast_address: *mut c_void,
+        first_multicast_address: *mut c_void,
+        first_dns_server_address: *mut c_void,
+        dns_suffix: *const u16,
+        description: *const u16,
+        friendly_name: *const u16,
+    }
+
+    struct Api {
+        create_adapter: unsafe extern "system" fn(*const u16, *const u16, *const c_void) -> Handle,
+        open_adapter: unsafe extern "system" fn(*const u16) -> Handle,
+        close_adapter: unsafe extern "system" fn(Handle),
+        set_adapter_state: unsafe extern "system" fn(Handle, i32) -> i32,
+        get_configuration: unsafe extern "system" fn(Handle, *mut u8, *mut u32) -> i32,
+        set_configuration: unsafe extern "system" fn(Handle, *const u8, u32) -> i32,
+    }
+
+    fn wide(s: &OsStr) -> Vec<u16> {
+        s.encode_wide().chain(iter::once(0)).collect()
+    }
+
+    fn load() -> Option<Api> {
+        let module = unsafe {
+            LoadLibraryExW(
+                wide(OsStr::new("wireguard.dll")).as_ptr(),
+                ptr::null_mut(),
+                LOAD_LIBRARY_SEARCH_APPLICATION_DIR | LOAD_LIBRARY_SEARCH_SYSTEM32,
+            )
+        };
+        if module.is_null() {
+            return None;
+        }
+        macro_rules! function {
+            ($name:literal) => {{
+                let function = unsafe { GetProcAddress(module, concat!($name, "\0").as_ptr()) };
+                if function.is_null() {
+                    return None;
+                }
+                unsafe { mem::transmute(function) }
+            }};
+        }
+        // The library stays loaded for the rest of the process.
+        Some(Api {
+            create_adapter: function!("WireGuardCreateAdapter"),
+            open_adapter: function!("WireGuardOpenAdapter"),
+            close_adapter: function!("WireGuardCloseAdapter"),
+            set_adapter_state: function!("WireGuardSetAdapterState"),
+            get_configuration: function!("WireGuardGetConfiguration"),
+            set_configuration: function!("WireGuardSetConfiguration"),
+        })
+    }
+
+    fn api() -> io::Result<&'static Api> {
+        static API: OnceLock<Option<Api>> = OnceLock::new();
+        API.get_or_init(load).as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "wireguard.dll could not be loaded")
+        })
+    }
+
+    /// Whether `wireguard.dll` can be loaded.
+    pub fn is_available() -> bool {
+        api().is_ok()
+    }
+
+    struct Adapter(Handle);
+
+    // Adapter handles may be used from any thread.
+    unsafe impl Send for Adapter {}
+
+    impl Drop for Adapter {
+        fn drop(&mut self) {
+            if let Ok(api) = api() {
+                unsafe { (api.close_adapter)(self.0) };
+            }
+        }
+    }
+
+    /// The adapters this process created, which exist as long as they are open.
+    fn owned() -> &'static Mutex<HashMap<InterfaceName, Adapter>> {
+        static OWNED: OnceLock<Mutex<HashMap<InterfaceName, Adapter>>> = OnceLock::new();
+        OWNED.get_or_init(Default::default)
+    }
+
+    fn open(name: &InterfaceName) -> io::Result<Adapter> {
+        let api = api()?;
+        let name = wide(OsStr::new(&*name.as_str_lossy()));
+        let handle = unsafe { (api.open_adapter)(name.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Adapter(handle))
+    }
+
+    fn create(name: &InterfaceName) -> io::Result<Adapter> {
+        let api = api()?;
+        let tunnel_type = wide(OsStr::new("WireGuard"));
+        let name = wide(OsStr::new(&*name.as_str_lossy()));
+        let handle =
+            unsafe { (api.create_adapter)(name.as_ptr(), tunnel_type.as_ptr(), ptr::null()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let adapter = Adapter(handle);
+        if unsafe { (api.set_adapter_state)(adapter.0, ADAPTER_STATE_UP) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(adapter)
+    }
+
+    fn get_configuration(adapter: &Adapter) -> io::Result<Interface> {
+        let api = api()?;
+        let mut bytes = 0u32;
+        loop {
+            // The driver wants the buffer 8-byte aligned.
+            // Wiped when dropped, as it carries the private key.
+            let mut buf = Zeroizing::new(vec![0u64; (bytes as usize).div_ceil(8)]);
+            let ok = unsafe {
+                (api.get_configuration)(adapter.0, buf.as_mut_ptr() as *mut u8, &mut bytes)
+            };
+            if ok != 0 {
+                let buf = unsafe {
+                    std::slice::from_raw_parts(buf.as_ptr() as *const u8, bytes as usize)
+                };
+                return decode(buf);
+            }
+            let e = io::Error::last_os_error();
+            // Peers may have been added since the size was reported.
+            if e.raw_os_error() != Some(ERROR_MORE_DATA) {
+                return Err(e);
+            }
+        }
+    }
+
+    /// The friendly names of all network adapters.
+    fn adapter_names() -> io::Result<Vec<OsString>> {
+        let mut size = 16 * 1024;
+        loop {
+            let mut buf = vec![0u64; (size as usize).div_ceil(8)];
+            let first = buf.as_mut_ptr() as *mut AdapterAddresses;
+            let result = unsafe {
+                GetAdaptersAddresses(
+                    0,
+                    GAA_FLAG_SKIP_ADDRESSES,
+                    ptr::null_mut(),
+                    first,
+                    &mut size,
+                )
+            };
+            if result == ERROR_BUFFER_OVERFLOW {
+                continue;
+            }
+            if result != 0 {
+                return Err(io::Error::from_raw_os_error(result as i32));
+            }
+            let mut names = vec![];
+            let mut adapter = first as *const AdapterAddresses;
+            while !adapter.is_null() {
+                let name = unsafe { (*adapter).friendly_name };
+                let len = (0..).take_while(|&i| unsafe { *name.add(i) } != 0).count();
+                names.push(OsString::from_wide(unsafe {
+                    std::slice::from_raw_parts(name, len)
+                }));
+                adapter = unsafe { (*adapter).next };
+            }
+            return Ok(names);
+        }
+    }
+
+    /// The adapters the WireGuardNT driver can open.
+    pub fn enumerate() -> io::Result<Vec<InterfaceName>> {
+        api()?;
+        Ok(adapter_names()?
+            .iter()
+            .filter_map(|name| name.to_str()?.parse().ok())
+            .filter(|name| open(name).is_ok())
+            .collect())
+    }
+
+    pub fn get_by_name(name: &InterfaceName) -> io::Result<Device> {
+        let interface = get_configuration(&open(name)?)?;
+        Ok(Device {
+            name: *name,
+            public_key: interface.public_key,
+            private_key: interface.private_key,
+            fwmark: None,
+            listen_port: interface.listen_port,
+            peers: interface.peers,
+            linked_name: None,
+            backend: Backend::Windows,
+            __cant_construct_me: (),
+        })
+    }
+
+    pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
+        let api = api()?;
+        let config = encode(builder)?;
+        let mut owned = owned().lock().unwrap();
+        let adapter = match open(iface) {
+            Ok(adapter) => adapter,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let adapter = create(iface)?;
+                owned.entry(*iface).or_insert(adapter);
+                open(iface)?
+            }
+            Err(e) => return Err(e),
+        };
+        let mut buf = Zeroizing::new(vec![0u64; config.len().div_ceil(8)]);
+        unsafe {
+            ptr::copy_nonoverlapping(config.as_ptr(), buf.as_mut_ptr() as *mut u8, config.len())
+        };
+        let ok = unsafe {
+            (api.set_configuration)(adapter.0, buf.as_ptr() as *const u8, config.len() as u32)
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Deletes an adapter this process created, by closing its last handle.
+    pub fn delete_interface(iface: &InterfaceName) -> io::Result<()> {
+        match owned().lock().unwrap().remove(iface) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "WireGuardNT adapters can only be deleted by the process that created them",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let update = DeviceUpdate::new()
+            .set_private_key(Key([1; 32]))
+            .set_listen_port(51820)
+            .replace_peers()
+            .add_peer(
+                PeerConfigBuilder::new(&Key([2; 32]))
+                    .set_endpoint("[2001:db8::1]:51820".parse().unwrap())
+                    .set_persistent_keepalive_interval(25)
+                    .add_allowed_ip("10.0.0.2".parse().unwrap(), 32)
+                    .add_allowed_ip("fd00::2".parse().unwrap(), 128),
+            )
+            .add_peer(PeerConfigBuilder::new(&Key([3; 32])).remove());
+        let buf = encode(&update).unwrap();
+        assert_eq!(buf.len(), INTERFACE_LEN + 2 * PEER_LEN + 2 * ALLOWED_IP_LEN);
+        // Allocated once, so no reallocation left a copy of the key behind.
+        assert_eq!(buf.capacity(), buf.len());
+        assert_eq!(
+            u32_at(&buf, 0),
+            INTERFACE_HAS_PRIVATE_KEY | INTERFACE_HAS_LISTEN_PORT | INTERFACE_REPLACE_PEERS
+        );
+        assert_eq!(u16_at(&buf, 4), 51820);
+        let peer = &buf[INTERFACE_LEN..];
+        assert_eq!(
+            u32_at(peer, 0),
+            PEER_HAS_PUBLIC_KEY | PEER_HAS_PERSISTENT_KEEPALIVE | PEER_HAS_ENDPOINT
+        );
+        assert_eq!(&peer[76..80], &[23, 0, 0xca, 0x6c]);
+        let allowed_ip = &peer[PEER_LEN..];
+        assert_eq!(&allowed_ip[..4], &[10, 0, 0, 2]);
+        assert_eq!(&allowed_ip[16..19], &[2, 0, 32]);
+        let removed = &buf[INTERFACE_LEN + PEER_LEN + 2 * ALLOWED_IP_LEN..];
+        assert_eq!(u32_at(removed, 0), PEER_HAS_PUBLIC_KEY | PEER_REMOVE);
+
+        let interface = decode(&buf).unwrap();
+        assert_eq!(interface.private_key, Some(Key([1; 32])));
+        assert_eq!(interface.public_key, None);
+        assert_eq!(interface.listen_port, Some(51820));
+        assert_eq!(interface.peers.len(), 2);
+        let peer = &interface.peers[0].config;
+        assert_eq!(peer.endpoint, Some("[2001:db8::1]:51820".parse().unwrap()));
+        assert_eq!(peer.persistent_keepalive_interval, Some(25));
+        assert_eq!(peer.allowed_ips, update.peers[0].allowed_ips);
+        assert_eq!(interface.peers[1].config.allowed_ips, vec![]);
+
+        assert!(decode(&buf[..buf.len() - 1]).is_err());
+        assert!(encode(&DeviceUpdate::new().set_fwmark(1)).is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut buf = vec![0u8; INTERFACE_LEN + PEER_LEN];
+        put_u32(&mut buf, 72, 1);
+        let peer = &mut buf[INTERFACE_LEN..];
+        put_endpoint(&mut peer[76..104], "192.0.2.1:51820".parse().unwrap());
+        put_u32(peer, 0, PEER_HAS_PUBLIC_KEY | PEER_HAS_ENDPOINT);
+        peer[104..112].copy_from_slice(&300u64.to_le_bytes());
+        peer[112..120].copy_from_slice(&200u64.to_le_bytes());
+        // 2000-01-01 as a FILETIME.
+        peer[120..128].copy_from_slice(&125_911_584_000_000_000u64.to_le_bytes());
+
+        let peer = &decode(&buf).unwrap().peers[0];
+        assert_eq!(
+            peer.config.endpoint,
+            Some("192.0.2.1:51820".parse().unwrap())
+        );
+        assert_eq!(peer.stats.tx_bytes, 300);
+        assert_eq!(peer.stats.rx_bytes, 200);
+        assert_eq!(
+            peer.stats.last_handshake_time,
+            Some(UNIX_EPOCH + Duration::from_secs(946_684_800))
+        );
+    }
+}
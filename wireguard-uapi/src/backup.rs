@@ -0,0 +1,290 @@
+//! Backup and restore of every interface on a host.
+//!
+//! [`export_all`] captures the configuration (not the statistics) of all
+//! interfaces into an [`Archive`], which serializes to a single text document of
+//! wg-quick style sections, optionally encrypted with a 32-byte key (with the
+//! `backup-encryption` feature). [`restore`] re-creates the interfaces from it,
+//! e.g. after reinstalling a VPN concentrator.
+use crate::{
+    conf::{
+        interface_section, parse_fwmark, parse_key, parse_peer, parse_value, peer_section,
+        ConfFile, SectionKind,
+    },
+    Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfig, PeerConfigBuilder,
+};
+
+#[cfg(feature = "backup-encryption")]
+use crate::key::SecretBuf;
+#[cfg(feature = "backup-encryption")]
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+#[cfg(feature = "backup-encryption")]
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Nonce,
+};
+#[cfg(feature = "backup-encryption")]
+use rand_core::RngCore;
+#[cfg(feature = "backup-encryption")]
+use std::fmt::Write as _;
+use std::{fmt, io, str::FromStr};
+#[cfg(feature = "backup-encryption")]
+use zeroize::Zeroizing;
+
+#[cfg(feature = "backup-encryption")]
+const ENCRYPTED_MAGIC: &[u8] = b"wgbackup1";
+#[cfg(feature = "backup-encryption")]
+const NONCE_LENGTH: usize = 12;
+
+/// The configuration of one interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceBackup {
+    pub name: InterfaceName,
+    pub private_key: Option<Key>,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    pub peers: Vec<PeerConfig>,
+}
+
+impl From<&Device> for DeviceBackup {
+    fn from(device: &Device) -> Self {
+        Self {
+            name: device.name,
+            private_key: device.private_key.clone(),
+            listen_port: device.listen_port,
+            fwmark: device.fwmark,
+            peers: device
+                .peers
+                .iter()
+                .map(|peer| peer.config.clone())
+                .collect(),
+        }
+    }
+}
+
+impl DeviceBackup {
+    /// An update that makes an interface match this backup exactly.
+    pub fn to_update(&self) -> DeviceUpdate {
+        let mut update = DeviceUpdate::new().replace_peers();
+        if let Some(key) = &self.private_key {
+            update = update.set_private_key(key.clone());
+        }
+        if let Some(port) = self.listen_port {
+            update = update.set_listen_port(port);
+        }
+        if let Some(fwmark) = self.fwmark {
+            update = update.set_fwmark(fwmark);
+        }
+        update.add_peers(
+            &self
+                .peers
+                .iter()
+                .cloned()
+                .map(PeerConfigBuilder::from_peer_config)
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+/// The configuration of every interface on a host.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Archive {
+    pub devices: Vec<DeviceBackup>,
+}
+
+/// Reads the configuration of every interface on `backend`.
+pub fn export_all(backend: Backend) -> io::Result<Archive> {
+    let mut devices = vec![];
+    for name in Device::list(backend)? {
+        devices.push(DeviceBackup::from(&Device::get(&name, backend)?));
+    }
+    Ok(Archive { devices })
+}
+
+/// Creates (or overwrites) every interface in `archive` on `backend`.
+///
+/// Each interface is set to exactly the backed-up configuration, replacing its
+/// current peers. Interfaces not in the archive are left alone.
+pub fn restore(archive: &Archive, backend: Backend) -> io::Result<()> {
+    for device in &archive.devices {
+        device
+            .to_update()
+            .apply(&device.name, backend)
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to restore {}: {}", device.name, e),
+                )
+            })?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "backup-encryption")]
+impl Archive {
+    /// Encrypts the serialized archive with ChaCha20-Poly1305 under `key`,
+    /// returning it base64 encoded.
+    ///
+    /// A fresh [preshared key](Key::generate_preshared) works well as a backup key.
+    /// The plaintext, which holds private keys, is wiped once encrypted.
+    pub fn encrypt(&self, key: &Key) -> String {
+        let mut plaintext = SecretBuf::default();
+        write!(plaintext, "{}", self).ok();
+        let mut nonce = [0u8; NONCE_LENGTH];
+        rand_core::OsRng.fill_bytes(&mut nonce);
+        let cipher = ChaCha20Poly1305::new(key.as_bytes().into());
+        let ciphertext = cipher
+            .encrypt(&Nonce::from(nonce), plaintext.as_bytes())
+            .expect("in-memory encryption cannot fail");
+
+        let mut blob = ENCRYPTED_MAGIC.to_vec();
+        blob.extend_from_slice(&nonce);
+        blob.extend_from_slice(&ciphertext);
+        STANDARD.encode(blob)
+    }
+
+    /// Decrypts an archive produced by [`encrypt`](Archive::encrypt).
+    pub fn decrypt(encrypted: &str, key: &Key) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let blob = STANDARD
+            .decode(encrypted.trim())
+            .map_err(|_| invalid("encrypted backup isn't valid base64"))?;
+        let rest = blob
+            .strip_prefix(ENCRYPTED_MAGIC)
+            .filter(|rest| rest.len() >= NONCE_LENGTH)
+            .ok_or_else(|| invalid("not an encrypted backup"))?;
+        let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+        let nonce: [u8; NONCE_LENGTH] = nonce.try_into().expect("split at nonce length");
+
+        let cipher = ChaCha20Poly1305::new(key.as_bytes().into());
+        let plaintext = Zeroizing::new(
+            cipher
+                .decrypt(&Nonce::from(nonce), ciphertext)
+                .map_err(|_| invalid("wrong key or corrupted backup"))?,
+        );
+        std::str::from_utf8(&plaintext)
+            .map_err(|_| invalid("backup isn't valid UTF-8"))?
+            .parse()
+    }
+}
+
+impl fmt::Display for Archive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut file = ConfFile::default();
+        for device in &self.devices {
+            let mut interface = interface_section(
+                device.private_key.as_ref(),
+                device.listen_port,
+                device.fwmark,
+            );
+            interface.set_annotation("Device", &device.name.as_str_lossy());
+            file.sections.push(interface);
+
+            for peer in &device.peers {
+                file.sections.push(peer_section(peer));
+            }
+        }
+        write!(f, "{}", file)
+    }
+}
+
+impl FromStr for Archive {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let file = ConfFile::check(s)?;
+        let mut devices: Vec<DeviceBackup> = vec![];
+        for section in &file.sections {
+            match section.kind {
+                SectionKind::Interface => {
+                    let name = section
+                        .annotation("Device")
+                        .ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::InvalidData, "interface without a name")
+                        })?
+                        .parse()?;
+                    devices.push(DeviceBackup {
+                        name,
+                        private_key: parse_key(section, "PrivateKey")?,
+                        listen_port: parse_value(section, "ListenPort")?,
+                        fwmark: parse_fwmark(section)?,
+                        peers: vec![],
+                    });
+                }
+                SectionKind::Peer => {
+                    let device = devices.last_mut().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "peer before any interface")
+                    })?;
+                    device.peers.push(parse_peer(section)?);
+                }
+                SectionKind::Other(_) => {}
+            }
+        }
+        Ok(Archive { devices })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> Archive {
+        Archive {
+            devices: vec![
+                DeviceBackup {
+                    name: "wg0".parse().unwrap(),
+                    private_key: Some(Key([1u8; 32])),
+                    listen_port: Some(51820),
+                    fwmark: Some(0xca6c),
+                    peers: vec![PeerConfig {
+                        public_key: Key([2u8; 32]),
+                        preshared_key: Some(Key([3u8; 32])),
+                        endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+                        persistent_keepalive_interval: Some(25),
+                        allowed_ips: vec![
+                            "10.0.0.2/32".parse().unwrap(),
+                            "fd00::2/128".parse().unwrap(),
+                        ],
+                        __cant_construct_me: (),
+                    }],
+                },
+                DeviceBackup {
+                    name: "wg1".parse().unwrap(),
+                    private_key: None,
+                    listen_port: None,
+                    fwmark: None,
+                    peers: vec![],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_archive_roundtrip() {
+        let archive = archive();
+        let text = archive.to_string();
+        assert!(text.starts_with("# Device: wg0\n[Interface]\n"));
+        assert!(text.contains("AllowedIPs = 10.0.0.2/32, fd00::2/128\n"));
+        assert_eq!(text.parse::<Archive>().unwrap(), archive);
+    }
+
+    #[test]
+    #[cfg(feature = "backup-encryption")]
+    fn test_encrypted_roundtrip() {
+        let archive = archive();
+        let key = Key([9u8; 32]);
+        let encrypted = archive.encrypt(&key);
+        assert!(!encrypted.contains("51820"));
+        assert_eq!(Archive::decrypt(&encrypted, &key).unwrap(), archive);
+
+        let error = Archive::decrypt(&encrypted, &Key([8u8; 32])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_to_update_replaces_peers() {
+        let update = archive().devices[0].to_update();
+        assert!(update.replace_peers);
+        assert_eq!(update.peers.len(), 1);
+        assert_eq!(update.listen_port, Some(51820));
+    }
+}
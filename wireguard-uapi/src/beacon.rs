@@ -0,0 +1,481 @@
+//! Optional in-tunnel beacons announcing the friendly names of peers.
+//!
+//! WireGuard peers are only known by their keys, so every node signs an
+//! [`Announcement`] of its name and hostname with its WireGuard private key, and a
+//! [`Beacon`] periodically sends it over the tunnel to the other peers of the
+//! interface. Received announcements are only accepted from the peer that routes
+//! the address they came from and signed by that peer's key, and are recorded in
+//! [`PeerNames`], which [renders](crate::render::RenderOptions::peer_names) and
+//! [exports](PeerNames::annotate) can use to show names without a central
+//! database.
+use crate::{
+    clock::{Clock, SystemClock},
+    conf::ConfFile,
+    invite::{open_token, sign_token, verify_token, Fields},
+    Device, Key,
+};
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The [context](sign_token) of announcement signatures.
+const CONTEXT: &[u8] = b"wireguard-uapi beacon v1\n";
+
+/// What a node announces about itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    pub public_key: Key,
+    pub name: String,
+    pub hostname: String,
+    /// When the node signed the announcement. Later announcements replace
+    /// earlier ones.
+    pub issued: SystemTime,
+}
+
+impl Announcement {
+    /// The signed text. The issue time is kept to the second.
+    fn payload(&self) -> String {
+        format!(
+            "PublicKey = {}\nName = {}\nHostname = {}\nIssued = {}\n",
+            self.public_key.to_base64(),
+            self.name,
+            self.hostname,
+            self.issued
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        )
+    }
+
+    fn from_payload(payload: &str) -> Option<Self> {
+        let mut fields = Fields::new(payload);
+        let announcement = Self {
+            public_key: Key::from_base64(fields.field("PublicKey")?).ok()?,
+            name: fields.field("Name")?.to_string(),
+            hostname: fields.field("Hostname")?.to_string(),
+            issued: UNIX_EPOCH
+                .checked_add(Duration::from_secs(fields.field("Issued")?.parse().ok()?))?,
+        };
+        (!announcement.has_control_characters()).then_some(announcement)
+    }
+
+    /// Whether the names contain control characters, which would break the
+    /// payload's lines or end up in terminals and config files.
+    fn has_control_characters(&self) -> bool {
+        [&self.name, &self.hostname]
+            .iter()
+            .any(|s| s.chars().any(char::is_control))
+    }
+
+    /// Signs the announcement with the node's private key, which also sets its
+    /// public key, returning the token to send. Fails if the names contain
+    /// control characters.
+    pub fn sign(mut self, private_key: &Key) -> io::Result<String> {
+        if self.has_control_characters() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "names can't contain control characters",
+            ));
+        }
+        self.public_key = private_key.get_public();
+        Ok(sign_token(private_key, CONTEXT, &self.payload()))
+    }
+
+    /// Decodes `token`, returning `None` unless it's signed by the key it
+    /// announces.
+    pub fn verify(token: &str) -> Option<Self> {
+        let (payload, signature) = open_token(token)?;
+        let announcement = Self::from_payload(&payload)?;
+        verify_token(&announcement.public_key, CONTEXT, &payload, &signature)
+            .then_some(announcement)
+    }
+}
+
+/// The verified announcements of the peers, by public key.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PeerNames {
+    /// The announcements with their tokens, which [`save`](Self::save) keeps so
+    /// they can be verified again when loaded.
+    known: HashMap<Key, (Announcement, String)>,
+}
+
+impl PeerNames {
+    /// Verifies `token` and records its announcement, returning its key if it was
+    /// new or changed. Tokens that don't verify or are older than the recorded
+    /// announcement are ignored.
+    pub fn record(&mut self, token: &str) -> Option<Key> {
+        let announcement = Announcement::verify(token)?;
+        match self.known.get(&announcement.public_key) {
+            Some((known, _)) if known.issued > announcement.issued => return None,
+            Some((known, _)) if *known == announcement => return None,
+            _ => {}
+        }
+        let key = announcement.public_key.clone();
+        self.known
+            .insert(key.clone(), (announcement, token.to_string()));
+        Some(key)
+    }
+
+    pub fn get(&self, public_key: &Key) -> Option<&Announcement> {
+        self.known
+            .get(public_key)
+            .map(|(announcement, _)| announcement)
+    }
+
+    pub fn name(&self, public_key: &Key) -> Option<&str> {
+        self.get(public_key)
+            .map(|announcement| announcement.name.as_str())
+    }
+
+    /// The names by key, as taken by
+    /// [`RenderOptions::peer_names`](crate::render::RenderOptions::peer_names).
+    pub fn to_map(&self) -> HashMap<Key, String> {
+        self.known
+            .iter()
+            .map(|(key, (announcement, _))| (key.clone(), announcement.name.clone()))
+            .collect()
+    }
+
+    /// Sets the `Name` and `Hostname` annotations of the peer sections of `file`
+    /// whose peers announced themselves.
+    pub fn annotate(&self, file: &mut ConfFile) {
+        for section in file.peers_mut() {
+            let Some(announcement) = section
+                .get("PublicKey")
+                .and_then(|key| Key::from_base64(key).ok())
+                .and_then(|key| self.get(&key))
+            else {
+                continue;
+            };
+            section.set_annotation("Name", &announcement.name);
+            if !announcement.hostname.is_empty() {
+                section.set_annotation("Hostname", &announcement.hostname);
+            }
+        }
+    }
+
+    /// Reads the tokens saved at `path`, verifying each again. A missing file has
+    /// no names.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut names = Self::default();
+        match fs::read_to_string(path) {
+            Ok(tokens) => {
+                for token in tokens.lines() {
+                    names.record(token);
+                }
+                Ok(names)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(names),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the tokens of the recorded announcements to `path`, one per line.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut tokens: Vec<_> = self
+            .known
+            .values()
+            .map(|(_, token)| token.as_str())
+            .collect();
+        tokens.sort_unstable();
+        fs::write(
+            path,
+            tokens
+                .iter()
+                .map(|token| format!("{}\n", token))
+                .collect::<String>(),
+        )
+    }
+}
+
+/// A node announcing itself to, and recording the announcements of, the peers of
+/// an interface.
+#[derive(Debug)]
+pub struct Beacon {
+    socket: UdpSocket,
+    token: String,
+    names: PeerNames,
+}
+
+impl Beacon {
+    /// Announces `name` and `hostname` on `socket` as the node with
+    /// `private_key`. The socket should be bound to the node's tunnel address, or
+    /// the unspecified address, on the port every node of the mesh uses.
+    pub fn new(
+        socket: UdpSocket,
+        private_key: &Key,
+        name: &str,
+        hostname: &str,
+    ) -> io::Result<Self> {
+        Self::new_with_clock(socket, private_key, name, hostname, &SystemClock)
+    }
+
+    /// Like [`new`](Self::new), issuing the announcement at `clock`'s now.
+    pub fn new_with_clock(
+        socket: UdpSocket,
+        private_key: &Key,
+        name: &str,
+        hostname: &str,
+        clock: &dyn Clock,
+    ) -> io::Result<Self> {
+        let token = Announcement {
+            public_key: private_key.get_public(),
+            name: name.to_string(),
+            hostname: hostname.to_string(),
+            issued: clock.now(),
+        }
+        .sign(private_key)?;
+        Ok(Self {
+            socket,
+            token,
+            names: PeerNames::default(),
+        })
+    }
+
+    /// Starts from previously recorded names, e.g. [loaded](PeerNames::load)
+    /// from disk.
+    pub fn set_names(mut self, names: PeerNames) -> Self {
+        self.names = names;
+        self
+    }
+
+    pub fn names(&self) -> &PeerNames {
+        &self.names
+    }
+
+    /// Sends the announcement through the tunnel to every peer of `device`, at
+    /// each of its single-address allowed IPs. Peers routing only whole subnets,
+    /// such as site gateways, aren't sent to.
+    ///
+    /// Addresses that can't be sent to are skipped, so one unreachable peer
+    /// doesn't keep the others from hearing the announcement. Fails only if no
+    /// address could be sent to.
+    pub fn announce(&self, device: &Device) -> io::Result<()> {
+        let port = self.socket.local_addr()?.port();
+        let mut failed = None;
+        let mut reached = 0;
+        for peer in &device.peers {
+            for ip in &peer.config.allowed_ips {
+                let host = match ip.address {
+                    IpAddr::V4(_) => ip.cidr == 32,
+                    IpAddr::V6(_) => ip.cidr == 128,
+                };
+                if !host {
+                    continue;
+                }
+                let target = SocketAddr::new(ip.address, port);
+                match self.socket.send_to(self.token.as_bytes(), target) {
+                    Ok(_) => reached += 1,
+                    Err(e) => {
+                        log::warn!("failed to announce to {}: {}", target, e);
+                        failed = Some(e);
+                    }
+                }
+            }
+        }
+        match failed {
+            Some(e) if reached == 0 => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the announcement `token` that arrived from `source`, returning its
+    /// key if it was new or changed. It's ignored unless it's signed by the peer
+    /// of `device` whose allowed IPs contain `source`, so a peer can't name
+    /// another.
+    pub fn receive(&mut self, token: &str, source: IpAddr, device: &Device) -> Option<Key> {
+        let announcement = Announcement::verify(token)?;
+        let sender = device
+            .peers
+            .iter()
+            .filter_map(|peer| {
+                let route = peer
+                    .config
+                    .allowed_ips
+                    .iter()
+                    .filter(|ip| ip.contains(&source))
+                    .map(|ip| ip.cidr)
+                    .max()?;
+                Some((route, &peer.config.public_key))
+            })
+            .max_by_key(|(route, _)| *route)?
+            .1;
+        if *sender != announcement.public_key {
+            return None;
+        }
+        self.names.record(token)
+    }
+
+    /// Receives announcements for up to `timeout`, returning the keys whose
+    /// names were new or changed.
+    pub fn listen(&mut self, device: &Device, timeout: Duration) -> io::Result<Vec<Key>> {
+        let deadline = Instant::now() + timeout;
+        let mut changed = vec![];
+        let mut buf = [0u8; 2048];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(changed);
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+            let (len, source) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(changed)
+                }
+                Err(e) => return Err(e),
+            };
+            let Ok(token) = std::str::from_utf8(&buf[..len]) else {
+                continue;
+            };
+            if let Some(key) = self.receive(token.trim(), source.ip(), device) {
+                if !changed.contains(&key) {
+                    changed.push(key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerInfo;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+
+    fn token(private_key: &Key, name: &str, issued: u64) -> String {
+        Announcement {
+            public_key: Key::zero(),
+            name: name.to_string(),
+            hostname: format!("{}.example.com", name),
+            issued: UNIX_EPOCH + Duration::from_secs(issued),
+        }
+        .sign(private_key)
+        .unwrap()
+    }
+
+    fn device(peers: &[(&Key, &str)]) -> Device {
+        let peers = peers
+            .iter()
+            .map(|(key, allowed_ip)| {
+                PeerInfo::fixture((*key).clone()).with_allowed_ips(&[allowed_ip])
+            })
+            .collect();
+        Device::fixture("wg0", peers)
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let private_key = Key::generate_private();
+        let token = token(&private_key, "laptop", 1000);
+        let announcement = Announcement::verify(&token).unwrap();
+        assert_eq!(announcement.public_key, private_key.get_public());
+        assert_eq!(announcement.hostname, "laptop.example.com");
+
+        let (payload, signature) = token.split_once('.').unwrap();
+        let forged = Announcement {
+            name: "server".to_string(),
+            ..announcement
+        };
+        let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode(forged.payload()), signature);
+        assert_eq!(Announcement::verify(&forged), None);
+        assert!(Announcement::verify(payload).is_none());
+
+        let escape = Announcement {
+            name: "\x1b[2J".to_string(),
+            ..Announcement::verify(&token).unwrap()
+        };
+        assert_eq!(
+            escape.sign(&private_key).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_issued_out_of_range() {
+        let payload = format!(
+            "PublicKey = {}\nName = laptop\nHostname = \nIssued = {}\n",
+            Key::zero().to_base64(),
+            u64::MAX
+        );
+        assert_eq!(Announcement::from_payload(&payload), None);
+    }
+
+    #[test]
+    fn test_announce() {
+        let beacon = Beacon::new(
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            &Key::generate_private(),
+            "me",
+            "",
+        )
+        .unwrap();
+        let (a, b) = (Key::generate_private(), Key::generate_private());
+        // The IPv6 peer can't be sent to from an IPv4 socket, the other one (the
+        // beacon itself) still hears the announcement.
+        let device = device(&[
+            (&a.get_public(), "::1/128"),
+            (&b.get_public(), "127.0.0.1/32"),
+        ]);
+        beacon.announce(&device).unwrap();
+        let mut buf = [0u8; 2048];
+        beacon
+            .socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let (len, _) = beacon.socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], beacon.token.as_bytes());
+    }
+
+    #[test]
+    fn test_receive() {
+        let (a, b) = (Key::generate_private(), Key::generate_private());
+        let device = device(&[
+            (&a.get_public(), "10.0.0.2/32"),
+            (&b.get_public(), "10.0.0.0/24"),
+        ]);
+        let mut beacon = Beacon::new(
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            &Key::generate_private(),
+            "me",
+            "",
+        )
+        .unwrap();
+        let source = "10.0.0.2".parse().unwrap();
+
+        // Only the peer routing the source can name itself.
+        assert_eq!(beacon.receive(&token(&b, "b", 1000), source, &device), None);
+        assert_eq!(
+            beacon.receive(&token(&a, "a", 1000), source, &device),
+            Some(a.get_public())
+        );
+        assert_eq!(beacon.receive(&token(&a, "a", 1000), source, &device), None);
+        assert_eq!(
+            beacon.receive(&token(&a, "old", 900), source, &device),
+            None
+        );
+        beacon.receive(&token(&a, "renamed", 1100), source, &device);
+        assert_eq!(beacon.names().name(&a.get_public()), Some("renamed"));
+
+        let mut file: ConfFile = format!("[Peer]\nPublicKey = {}\n", a.get_public().to_base64())
+            .parse()
+            .unwrap();
+        beacon.names().annotate(&mut file);
+        let section = file.peers().next().unwrap();
+        assert_eq!(section.name().as_deref(), Some("renamed"));
+        assert_eq!(
+            section.annotation("Hostname").as_deref(),
+            Some("renamed.example.com")
+        );
+    }
+}
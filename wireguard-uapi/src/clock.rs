@@ -0,0 +1,81 @@
+//! Pluggable sources of the current time.
+//!
+//! Handshake ages and staleness checks compare kernel timestamps against "now".
+//! Taking the time from a [`Clock`] instead of calling [`SystemTime::now`] lets
+//! tests simulate the passing of time with a [`ManualClock`], and makes the age
+//! computations tolerate clocks that step backwards (e.g. embedded systems
+//! without an RTC before NTP sync) instead of failing or panicking.
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// A source of the current wall clock time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// How long ago `earlier` was, or zero if it is in the future.
+    fn since(&self, earlier: SystemTime) -> Duration {
+        self.now().duration_since(earlier).unwrap_or_default()
+    }
+}
+
+/// The system's wall clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests and simulations.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<SystemTime>,
+}
+
+impl ManualClock {
+    /// Creates a clock that reads `start` until changed.
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            now: Mutex::new(start),
+        }
+    }
+
+    /// Sets the time, which may go backwards.
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// Moves the time forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_manual_clock() {
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        let clock = ManualClock::new(start);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(clock.since(start), Duration::from_secs(30));
+
+        // A clock stepping backwards makes past events look like they just happened.
+        clock.set(UNIX_EPOCH);
+        assert_eq!(clock.since(start), Duration::ZERO);
+    }
+}
@@ -0,0 +1,214 @@
+//! The signatures of `wireguard-control` and `wgctrl-rs`, for projects switching
+//! to this crate.
+//!
+//! This crate started as a fork of `wireguard-control`, so most types are the
+//! same and re-exported as they are. [`Device`] and [`DeviceUpdate`] differ in
+//! returning [`io::Error`]s rather than the typed [`Error`](crate::Error), and
+//! dereference to the types of this crate for everything else. The [`wgctrl`]
+//! module has the older `wgctrl-rs` names. Usually, replacing the crate name in
+//! `use` paths with `wireguard_uapi::compat` is enough, and
+//! [`Device::into_inner`] gets to the rest of this crate from there.
+pub use crate::{
+    backends, AllowedIp, Backend, InterfaceName, InvalidInterfaceName, InvalidKey, Key, KeyPair,
+    PeerConfig, PeerConfigBuilder, PeerInfo, PeerStats,
+};
+
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+};
+
+/// Implements the builder methods `wireguard-control` and `wgctrl-rs` have on
+/// their device updates, for a newtype of [`crate::DeviceUpdate`].
+macro_rules! forward_update_methods {
+    ($($name:ident($($arg:ident: $ty:ty),*);)*) => {
+        pub fn new() -> Self {
+            Self(crate::DeviceUpdate::new())
+        }
+
+        $(
+            pub fn $name(self, $($arg: $ty),*) -> Self {
+                Self(self.0.$name($($arg),*))
+            }
+        )*
+
+        pub fn into_inner(self) -> crate::DeviceUpdate {
+            self.0
+        }
+    };
+}
+
+macro_rules! update_newtype {
+    ($name:ident) => {
+        impl $name {
+            forward_update_methods! {
+                set_keypair(keypair: KeyPair);
+                set_public_key(key: Key);
+                unset_public_key();
+                set_private_key(key: Key);
+                unset_private_key();
+                set_fwmark(fwmark: u32);
+                unset_fwmark();
+                set_listen_port(port: u16);
+                randomize_listen_port();
+                add_peer(peer: PeerConfigBuilder);
+                add_peer_with(
+                    pubkey: &Key,
+                    builder: impl Fn(PeerConfigBuilder) -> PeerConfigBuilder
+                );
+                add_peers(peers: &[PeerConfigBuilder]);
+                replace_peers();
+                remove_peer_by_key(public_key: &Key);
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl From<crate::DeviceUpdate> for $name {
+            fn from(update: crate::DeviceUpdate) -> Self {
+                Self(update)
+            }
+        }
+    };
+}
+
+macro_rules! device_newtype {
+    ($name:ident) => {
+        impl $name {
+            pub fn into_inner(self) -> crate::Device {
+                self.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = crate::Device;
+
+            fn deref(&self) -> &crate::Device {
+                &self.0
+            }
+        }
+
+        impl DerefMut for $name {
+            fn deref_mut(&mut self) -> &mut crate::Device {
+                &mut self.0
+            }
+        }
+
+        impl From<crate::Device> for $name {
+            fn from(device: crate::Device) -> Self {
+                Self(device)
+            }
+        }
+    };
+}
+
+/// `wireguard_control::Device`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device(crate::Device);
+
+device_newtype!(Device);
+
+impl Device {
+    pub fn list(backend: Backend) -> io::Result<Vec<InterfaceName>> {
+        Ok(crate::Device::list(backend)?)
+    }
+
+    pub fn get(name: &InterfaceName, backend: Backend) -> io::Result<Self> {
+        Ok(Self(crate::Device::get(name, backend)?))
+    }
+
+    pub fn delete(self) -> io::Result<()> {
+        Ok(self.0.delete()?)
+    }
+}
+
+/// `wireguard_control::DeviceUpdate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceUpdate(crate::DeviceUpdate);
+
+update_newtype!(DeviceUpdate);
+
+impl DeviceUpdate {
+    pub fn apply(self, iface: &InterfaceName, backend: Backend) -> io::Result<()> {
+        Ok(self.0.apply(iface, backend)?)
+    }
+}
+
+/// The names of `wgctrl-rs`, which take interface names as strings and always
+/// use the [default backend](Backend::default). Unlike there,
+/// [`name`](crate::Device::name) is an [`InterfaceName`], which displays the
+/// same.
+pub mod wgctrl {
+    use super::*;
+
+    pub use crate::{
+        AllowedIp, InvalidKey, Key, KeyPair, PeerConfig, PeerConfigBuilder, PeerInfo, PeerStats,
+    };
+
+    /// `wgctrl::DeviceInfo`.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct DeviceInfo(crate::Device);
+
+    device_newtype!(DeviceInfo);
+
+    impl DeviceInfo {
+        pub fn enumerate() -> io::Result<Vec<String>> {
+            Ok(crate::Device::list(Backend::default())?
+                .iter()
+                .map(ToString::to_string)
+                .collect())
+        }
+
+        pub fn get_by_name(name: &str) -> io::Result<Self> {
+            Ok(Self(crate::Device::get(
+                &name.parse()?,
+                Backend::default(),
+            )?))
+        }
+
+        pub fn delete(self) -> io::Result<()> {
+            Ok(self.0.delete()?)
+        }
+    }
+
+    /// `wgctrl::DeviceConfigBuilder`.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct DeviceConfigBuilder(crate::DeviceUpdate);
+
+    update_newtype!(DeviceConfigBuilder);
+
+    impl DeviceConfigBuilder {
+        pub fn apply(self, iface: &str) -> io::Result<()> {
+            Ok(self.0.apply(&iface.parse()?, Backend::default())?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    #[cfg(feature = "mock")]
+    fn test_device() {
+        use super::*;
+
+        let iface: InterfaceName = "mock-compat".parse().unwrap();
+        let error = Device::get(&iface, Backend::Mock).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        DeviceUpdate::new()
+            .set_listen_port(51830)
+            .add_peer_with(&Key([1; 32]), |peer| {
+                peer.set_persistent_keepalive_interval(25)
+            })
+            .apply(&iface, Backend::Mock)
+            .unwrap();
+        let device = Device::get(&iface, Backend::Mock).unwrap();
+        assert_eq!(device.listen_port, Some(51830));
+        assert_eq!(device.peers.len(), 1);
+        device.delete().unwrap();
+    }
+}
@@ -0,0 +1,355 @@
+//! Read-only checks of live interfaces against their config files.
+//!
+//! [`check`] loads a wg-quick config, reads the interface it configures and lists
+//! every way the interface differs from it as a [`Finding`], without changing
+//! anything. A [`Compliance`] serializes to JSON, so CI jobs and cron checks on
+//! fleet hosts can fail on drift and report it.
+//!
+//! The config is compared the way `wg setconf` would apply it: peers missing from
+//! it are extra, settings it leaves out aren't checked, and endpoints given as
+//! host names aren't compared since they're resolved when the interface comes up.
+use crate::{
+    allowed_ips,
+    conf::QuickConfig,
+    json::{or_null, quote},
+    AllowedIp, Backend, Device, InterfaceName, Key,
+};
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::Path,
+};
+
+/// A difference between a config and the live interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// A peer of the config the interface doesn't have.
+    MissingPeer { public_key: Key },
+    /// A peer of the interface the config doesn't have.
+    ExtraPeer { public_key: Key },
+    /// A setting of the interface, or of one of its peers, with another value than
+    /// in the config. Keys are given by their public key, and preshared keys only
+    /// as `set` or `none`.
+    Mismatch {
+        peer: Option<Key>,
+        field: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// The result of checking an interface against its config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compliance {
+    pub iface: InterfaceName,
+    pub findings: Vec<Finding>,
+}
+
+impl Compliance {
+    pub fn is_compliant(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// The result as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        let key = |key: &Option<Key>| or_null(key.as_ref().map(|key| quote(&key.to_base64())));
+        let findings: Vec<_> = self
+            .findings
+            .iter()
+            .map(|finding| match finding {
+                Finding::MissingPeer { public_key } => format!(
+                    "{{\"kind\":\"missing_peer\",\"public_key\":{}}}",
+                    quote(&public_key.to_base64())
+                ),
+                Finding::ExtraPeer { public_key } => format!(
+                    "{{\"kind\":\"extra_peer\",\"public_key\":{}}}",
+                    quote(&public_key.to_base64())
+                ),
+                Finding::Mismatch {
+                    peer,
+                    field,
+                    expected,
+                    actual,
+                } => format!(
+                    "{{\"kind\":\"mismatch\",\"public_key\":{},\"field\":{},\"expected\":{},\"actual\":{}}}",
+                    key(peer),
+                    quote(field),
+                    quote(expected),
+                    quote(actual)
+                ),
+            })
+            .collect();
+        format!(
+            "{{\"interface\":{},\"compliant\":{},\"findings\":[{}]}}",
+            quote(&self.iface.as_str_lossy()),
+            self.is_compliant(),
+            findings.join(",")
+        )
+    }
+}
+
+fn or_none<T>(value: Option<T>, show: impl Fn(T) -> String) -> String {
+    value.map(show).unwrap_or_else(|| "none".to_string())
+}
+
+/// A preshared key that is set, as the all-zero key means none.
+fn preshared_key(key: &Option<Key>) -> Option<&Key> {
+    key.as_ref().filter(|key| **key != Key::zero())
+}
+
+/// Loads the config at `path` and checks the interface named like the file, as
+/// wg-quick names it, against it.
+pub fn check(path: impl AsRef<Path>, backend: Backend) -> io::Result<Compliance> {
+    let path = path.as_ref();
+    let iface: InterfaceName = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "config without a name"))?
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let config = QuickConfig::from_path(path)?;
+    let device = Device::get(&iface, backend)?;
+    Ok(Compliance {
+        iface,
+        findings: compare(&config, &device),
+    })
+}
+
+/// The differences between `config` and the live `device`.
+pub fn compare(config: &QuickConfig, device: &Device) -> Vec<Finding> {
+    let update = &config.update;
+    let mut findings = vec![];
+    let mut mismatch = |peer: Option<&Key>, field, expected: String, actual: String| {
+        if expected != actual {
+            findings.push(Finding::Mismatch {
+                peer: peer.cloned(),
+                field,
+                expected,
+                actual,
+            });
+        }
+    };
+
+    if let Some(key) = &update.private_key {
+        mismatch(
+            None,
+            "public_key",
+            key.get_public().to_base64(),
+            or_none(device.public_key.as_ref(), Key::to_base64),
+        );
+    }
+    if let Some(port) = update.listen_port.filter(|port| *port != 0) {
+        mismatch(
+            None,
+            "listen_port",
+            port.to_string(),
+            or_none(device.listen_port, |port| port.to_string()),
+        );
+    }
+    if let Some(expected) = update.fwmark {
+        let fwmark = |fwmark: Option<u32>| {
+            or_none(fwmark.filter(|fwmark| *fwmark != 0), |fwmark| {
+                format!("{:#x}", fwmark)
+            })
+        };
+        mismatch(
+            None,
+            "fwmark",
+            fwmark(Some(expected)),
+            fwmark(device.fwmark),
+        );
+    }
+
+    let actual_peers: HashMap<_, _> = device
+        .peers
+        .iter()
+        .map(|peer| (&peer.config.public_key, peer))
+        .collect();
+    let hosts = &config.interface.endpoint_hosts;
+    let mut missing = vec![];
+    for expected in &update.peers {
+        let Some(actual) = actual_peers.get(&expected.public_key) else {
+            missing.push(expected.public_key.clone());
+            continue;
+        };
+        let actual = &actual.config;
+        let peer = Some(&expected.public_key);
+        // Compared by value, but never printed.
+        let expected_psk = preshared_key(&expected.preshared_key);
+        let describe = |key: Option<&Key>| {
+            match key {
+                None => "none",
+                Some(key) if expected_psk.is_some_and(|expected| expected != key) => {
+                    "a different key"
+                }
+                Some(_) => "set",
+            }
+            .to_string()
+        };
+        mismatch(
+            peer,
+            "preshared_key",
+            describe(expected_psk),
+            describe(preshared_key(&actual.preshared_key)),
+        );
+        // Without an endpoint in the config, the peer's endpoint is whatever it
+        // roamed to last.
+        if !hosts.iter().any(|(key, _)| *key == expected.public_key) {
+            if let Some(endpoint) = expected.endpoint {
+                mismatch(
+                    peer,
+                    "endpoint",
+                    endpoint.to_string(),
+                    or_none(actual.endpoint, |endpoint| endpoint.to_string()),
+                );
+            }
+        }
+        let keepalive = |interval: Option<u16>| {
+            or_none(interval.filter(|interval| *interval != 0), |interval| {
+                interval.to_string()
+            })
+        };
+        mismatch(
+            peer,
+            "persistent_keepalive",
+            keepalive(expected.persistent_keepalive_interval),
+            keepalive(actual.persistent_keepalive_interval),
+        );
+        // The kernel clears host bits, so `10.0.0.1/24` in the config is
+        // `10.0.0.0/24` on the interface.
+        let allowed_ips = |ips: &[AllowedIp]| {
+            let mut ips: Vec<_> = ips
+                .iter()
+                .map(|ip| allowed_ips::normalize(ip).unwrap_or_else(|_| ip.clone()))
+                .map(|ip| format!("{}/{}", ip.address, ip.cidr))
+                .collect();
+            ips.sort();
+            ips.dedup();
+            ips.join(",")
+        };
+        mismatch(
+            peer,
+            "allowed_ips",
+            allowed_ips(&expected.allowed_ips),
+            allowed_ips(&actual.allowed_ips),
+        );
+    }
+
+    findings.extend(
+        missing
+            .into_iter()
+            .map(|public_key| Finding::MissingPeer { public_key }),
+    );
+    let expected_keys: HashSet<_> = update.peers.iter().map(|peer| &peer.public_key).collect();
+    findings.extend(
+        device
+            .peers
+            .iter()
+            .filter(|peer| !expected_keys.contains(&peer.config.public_key))
+            .map(|peer| Finding::ExtraPeer {
+                public_key: peer.config.public_key.clone(),
+            }),
+    );
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerInfo;
+
+    fn peer(key: u8, allowed_ips: &[&str], keepalive: Option<u16>) -> PeerInfo {
+        let mut peer = PeerInfo::fixture(Key([key; 32]))
+            .with_endpoint(&format!("192.0.2.{}:51820", key))
+            .with_allowed_ips(allowed_ips);
+        peer.config.persistent_keepalive_interval = keepalive;
+        peer
+    }
+
+    #[test]
+    fn test_compare() {
+        let private_key = Key([9; 32]);
+        let config: QuickConfig = format!(
+            "[Interface]\nPrivateKey = {}\nListenPort = 51820\n\n\
+             [Peer]\nPublicKey = {}\nAllowedIPs = 10.0.0.2/32, 10.0.1.1/24\nEndpoint = 192.0.2.1:51820\n\n\
+             [Peer]\nPublicKey = {}\nAllowedIPs = 10.0.0.3/32\nEndpoint = vpn.example.com:51820\n\n\
+             [Peer]\nPublicKey = {}\nAllowedIPs = 10.0.0.4/32\n",
+            private_key.to_base64(),
+            Key([1; 32]).to_base64(),
+            Key([2; 32]).to_base64(),
+            Key([3; 32]).to_base64(),
+        )
+        .parse()
+        .unwrap();
+        let device = Device {
+            public_key: Some(private_key.get_public()),
+            private_key: Some(private_key),
+            listen_port: Some(51820),
+            // Not in the config, so not checked.
+            fwmark: Some(0xca6c),
+            ..Device::fixture(
+                "wg0",
+                vec![
+                    peer(1, &["10.0.1.0/24", "10.0.0.2/32"], None),
+                    peer(2, &["10.0.0.3/32"], Some(25)),
+                    peer(4, &[], None),
+                ],
+            )
+        };
+
+        let findings = compare(&config, &device);
+        assert_eq!(
+            findings,
+            vec![
+                Finding::Mismatch {
+                    peer: Some(Key([2; 32])),
+                    field: "persistent_keepalive",
+                    expected: "none".to_string(),
+                    actual: "25".to_string(),
+                },
+                Finding::MissingPeer {
+                    public_key: Key([3; 32])
+                },
+                Finding::ExtraPeer {
+                    public_key: Key([4; 32])
+                },
+            ]
+        );
+
+        let compliance = Compliance {
+            iface: device.name,
+            findings: findings[1..2].to_vec(),
+        };
+        assert_eq!(
+            compliance.to_json(),
+            format!(
+                "{{\"interface\":\"wg0\",\"compliant\":false,\"findings\":[{{\"kind\":\"missing_peer\",\"public_key\":\"{}\"}}]}}",
+                Key([3; 32]).to_base64()
+            )
+        );
+
+        // Different preshared keys are told apart without printing either.
+        let mut config = config;
+        config.update.peers[0].preshared_key = Some(Key([7; 32]));
+        let mut device = device;
+        device.peers[0].config.preshared_key = Some(Key([8; 32]));
+        assert_eq!(
+            compare(&config, &device)[0],
+            Finding::Mismatch {
+                peer: Some(Key([1; 32])),
+                field: "preshared_key",
+                expected: "set".to_string(),
+                actual: "a different key".to_string(),
+            }
+        );
+        device.peers[0].config.preshared_key = Some(Key([7; 32]));
+        assert!(!matches!(
+            compare(&config, &device)[0],
+            Finding::Mismatch {
+                field: "preshared_key",
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,1181 @@
+//! A lossless model of wg-quick style `.conf` files.
+//!
+//! [`ConfFile`] keeps every comment and blank line where it was, so a file can be
+//! parsed, edited and written back without destroying the notes users keep in it.
+//! Comments of the form `# Key: value` (e.g. `# Name: alice-laptop`) are exposed as
+//! [annotations](Section::annotations), which is the usual way of naming peers.
+//!
+//! [`ConfFile::check`] additionally validates the values of the keys WireGuard
+//! knows, so broken files are rejected with their positions before anything is
+//! applied.
+//!
+//! [`QuickConfig`] interprets a wg-quick config: the WireGuard settings become a
+//! [`DeviceUpdate`], and the host settings only wg-quick understands (addresses,
+//! DNS, hooks, ...) are kept in a [`QuickInterface`]. A [`ClientConfig`] writes
+//! the config of a client reaching a single server.
+use crate::{key::SecretBuf, AllowedIp, Device, DeviceUpdate, Key, PeerConfig, PeerConfigBuilder};
+
+use ipnet::IpNet;
+use std::{
+    error,
+    fmt::{self, Write as _},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr,
+};
+use zeroize::Zeroize;
+
+/// An error parsing a config file, with the (1-based) line and column it occurred
+/// on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl error::Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    }
+}
+
+/// Every problem [`ConfFile::check`] found, in file order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckErrors(pub Vec<ParseError>);
+
+impl fmt::Display for CheckErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for CheckErrors {}
+
+impl From<CheckErrors> for io::Error {
+    fn from(e: CheckErrors) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    }
+}
+
+/// The kind of a `[Section]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SectionKind {
+    Interface,
+    Peer,
+    /// Any other section name, kept verbatim.
+    Other(String),
+}
+
+impl fmt::Display for SectionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Interface => f.write_str("Interface"),
+            Self::Peer => f.write_str("Peer"),
+            Self::Other(name) => f.write_str(name),
+        }
+    }
+}
+
+/// One line within a section (or before the first one).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    Blank,
+    /// A full-line comment, without the leading `#`.
+    Comment(String),
+    /// A `Key = Value` line, with its trailing comment if any (without the `#`).
+    Entry {
+        key: String,
+        value: String,
+        comment: Option<String>,
+    },
+}
+
+/// A `[Section]` with the comments leading up to its header and its lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub kind: SectionKind,
+    /// Comment lines directly above the header, without the leading `#`.
+    pub leading_comments: Vec<String>,
+    /// The trailing comment of the header line, without the `#`.
+    pub header_comment: Option<String>,
+    pub lines: Vec<Line>,
+}
+
+impl Section {
+    pub fn new(kind: SectionKind) -> Self {
+        Self {
+            kind,
+            leading_comments: vec![],
+            header_comment: None,
+            lines: vec![],
+        }
+    }
+
+    /// The first value of `key`. Keys are case-insensitive, as in wg-quick.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.lines.iter().find_map(|line| match line {
+            Line::Entry { key: k, value, .. } if k.eq_ignore_ascii_case(key) => {
+                Some(value.as_str())
+            }
+            _ => None,
+        })
+    }
+
+    /// Every value of `key`, in file order (e.g. repeated `AllowedIPs` lines).
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.lines.iter().filter_map(move |line| match line {
+            Line::Entry { key: k, value, .. } if k.eq_ignore_ascii_case(key) => {
+                Some(value.as_str())
+            }
+            _ => None,
+        })
+    }
+
+    /// Sets `key` to `value`, replacing the first existing value (and dropping any
+    /// repeats) or appending a new entry after the last one.
+    pub fn set(&mut self, key: &str, value: impl Into<String>) {
+        let value = value.into();
+        let mut found = false;
+        self.lines.retain_mut(|line| match line {
+            Line::Entry {
+                key: k, value: v, ..
+            } if k.eq_ignore_ascii_case(key) => {
+                if found {
+                    return false;
+                }
+                found = true;
+                *v = value.clone();
+                true
+            }
+            _ => true,
+        });
+        if !found {
+            let at = self
+                .lines
+                .iter()
+                .rposition(|line| matches!(line, Line::Entry { .. }))
+                .map_or(0, |i| i + 1);
+            self.lines.insert(
+                at,
+                Line::Entry {
+                    key: key.to_string(),
+                    value,
+                    comment: None,
+                },
+            );
+        }
+    }
+
+    /// Removes every value of `key`.
+    pub fn remove(&mut self, key: &str) {
+        self.lines.retain(
+            |line| !matches!(line, Line::Entry { key: k, .. } if k.eq_ignore_ascii_case(key)),
+        );
+    }
+
+    fn comments_mut(&mut self) -> impl Iterator<Item = &mut String> {
+        self.leading_comments
+            .iter_mut()
+            .chain(self.lines.iter_mut().filter_map(|line| match line {
+                Line::Comment(comment) => Some(comment),
+                _ => None,
+            }))
+    }
+
+    /// The `# Key: value` annotations above the header and within the section, in
+    /// file order.
+    pub fn annotations(&self) -> Vec<(String, String)> {
+        self.leading_comments
+            .iter()
+            .chain(self.lines.iter().filter_map(|line| match line {
+                Line::Comment(comment) => Some(comment),
+                _ => None,
+            }))
+            .filter_map(|comment| parse_annotation(comment))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// The value of the `key` annotation. Annotation keys are case-insensitive.
+    pub fn annotation(&self, key: &str) -> Option<String> {
+        self.annotations()
+            .into_iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value)
+    }
+
+    /// The `# Name:` annotation, the usual way peers are labelled.
+    pub fn name(&self) -> Option<String> {
+        self.annotation("Name")
+    }
+
+    /// Sets the `key` annotation, rewriting it in place if present or adding it
+    /// above the header otherwise.
+    pub fn set_annotation(&mut self, key: &str, value: &str) {
+        for comment in self.comments_mut() {
+            if let Some((k, _)) = parse_annotation(comment) {
+                if k.eq_ignore_ascii_case(key) {
+                    *comment = format!(" {}: {}", k, value);
+                    return;
+                }
+            }
+        }
+        self.leading_comments.push(format!(" {}: {}", key, value));
+    }
+}
+
+fn parse_annotation(comment: &str) -> Option<(&str, &str)> {
+    let (key, value) = comment.split_once(':')?;
+    let key = key.trim();
+    let valid = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| (key, value.trim()))
+}
+
+/// A parsed config file that serializes back to the same text (modulo whitespace
+/// around `=` and `#`) via [`Display`](fmt::Display).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConfFile {
+    /// Lines before the first section that aren't attached to it.
+    pub preamble: Vec<Line>,
+    pub sections: Vec<Section>,
+}
+
+impl ConfFile {
+    /// The `[Interface]` section, if any.
+    pub fn interface(&self) -> Option<&Section> {
+        self.sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Interface)
+    }
+
+    /// The `[Peer]` sections, in file order.
+    pub fn peers(&self) -> impl Iterator<Item = &Section> {
+        self.sections
+            .iter()
+            .filter(|section| section.kind == SectionKind::Peer)
+    }
+
+    /// The `[Peer]` sections, in file order, for editing.
+    pub fn peers_mut(&mut self) -> impl Iterator<Item = &mut Section> {
+        self.sections
+            .iter_mut()
+            .filter(|section| section.kind == SectionKind::Peer)
+    }
+}
+
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    match line.split_once('#') {
+        Some((content, comment)) => (content, Some(comment)),
+        None => (line, None),
+    }
+}
+
+/// Moves the comment block at the end of `lines` (after the last blank line or
+/// entry) out, as it belongs to the header that follows.
+fn take_trailing_comments(lines: &mut Vec<Line>) -> Vec<String> {
+    let start = lines
+        .iter()
+        .rposition(|line| !matches!(line, Line::Comment(_)))
+        .map_or(0, |i| i + 1);
+    lines
+        .drain(start..)
+        .map(|line| match line {
+            Line::Comment(comment) => comment,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+impl FromStr for ConfFile {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut file = ConfFile::default();
+        for (index, raw) in s.lines().enumerate() {
+            let trimmed = raw.trim();
+            let column = indent(raw) + 1;
+            let in_section = !file.sections.is_empty();
+            let lines = match file.sections.last_mut() {
+                Some(section) => &mut section.lines,
+                None => &mut file.preamble,
+            };
+
+            if trimmed.is_empty() {
+                lines.push(Line::Blank);
+            } else if let Some(comment) = trimmed.strip_prefix('#') {
+                lines.push(Line::Comment(comment.to_string()));
+            } else if trimmed.starts_with('[') {
+                let (header, header_comment) = split_comment(trimmed);
+                let name = header.trim_end()[1..]
+                    .strip_suffix(']')
+                    .ok_or_else(|| ParseError {
+                        line: index + 1,
+                        column,
+                        message: format!("unterminated section header {:?}", trimmed),
+                    })?;
+                let kind = match name.trim() {
+                    name if name.eq_ignore_ascii_case("Interface") => SectionKind::Interface,
+                    name if name.eq_ignore_ascii_case("Peer") => SectionKind::Peer,
+                    name => SectionKind::Other(name.to_string()),
+                };
+                let leading_comments = take_trailing_comments(lines);
+                file.sections.push(Section {
+                    kind,
+                    leading_comments,
+                    header_comment: header_comment.map(str::to_string),
+                    lines: vec![],
+                });
+            } else {
+                let (content, comment) = split_comment(trimmed);
+                let (key, value) = content.split_once('=').ok_or_else(|| ParseError {
+                    line: index + 1,
+                    column,
+                    message: format!("expected `Key = Value`, found {:?}", trimmed),
+                })?;
+                if !in_section {
+                    return Err(ParseError {
+                        line: index + 1,
+                        column,
+                        message: format!("{:?} outside of a section", key.trim()),
+                    });
+                }
+                lines.push(Line::Entry {
+                    key: key.trim().to_string(),
+                    value: value.trim().to_string(),
+                    comment: comment.map(str::to_string),
+                });
+            }
+        }
+        Ok(file)
+    }
+}
+
+/// The number of characters before the first non-whitespace one.
+fn indent(s: &str) -> usize {
+    s.chars().take_while(|c| c.is_whitespace()).count()
+}
+
+/// Checks a key. Errors carry the character offset of the problem in `value`.
+fn check_key(value: &str) -> Result<(), (usize, String)> {
+    if let Some(offset) = value
+        .chars()
+        .position(|c| !(c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '='))
+    {
+        return Err((offset, "invalid base64 character".to_string()));
+    }
+    Key::from_base64(value).map(drop).map_err(|_| {
+        (
+            0,
+            format!(
+                "expected a 32 byte key (44 base64 characters), found {} characters",
+                value.len()
+            ),
+        )
+    })
+}
+
+fn check_port(value: &str) -> Result<u16, (usize, String)> {
+    value
+        .parse()
+        .map_err(|_| (0, format!("{:?} is not a port number (0-65535)", value)))
+}
+
+fn check_endpoint(value: &str) -> Result<(), (usize, String)> {
+    let (host, port) = value
+        .rsplit_once(':')
+        .ok_or_else(|| (0, "expected `host:port`".to_string()))?;
+    if host.is_empty() {
+        return Err((0, "missing host".to_string()));
+    }
+    if let Some(inner) = host.strip_prefix('[') {
+        let valid = inner
+            .strip_suffix(']')
+            .is_some_and(|address| address.parse::<std::net::Ipv6Addr>().is_ok());
+        if !valid {
+            return Err((0, format!("invalid IPv6 address {:?}", host)));
+        }
+    }
+    let port_offset = host.chars().count() + 1;
+    match check_port(port) {
+        Ok(0) => Err((port_offset, "port 0 can't be connected to".to_string())),
+        Ok(_) => Ok(()),
+        Err((_, message)) => Err((port_offset, message)),
+    }
+}
+
+fn check_cidr(value: &str) -> Result<(), (usize, String)> {
+    let (address, prefix) = match value.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (value, None),
+    };
+    let address: IpAddr = address
+        .parse()
+        .map_err(|_| (0, format!("{:?} is not an IP address", address)))?;
+    if let Some(prefix) = prefix {
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let offset = value.len() - prefix.len();
+        match prefix.parse::<u8>() {
+            Ok(prefix) if prefix <= max => {}
+            _ => {
+                return Err((
+                    offset,
+                    format!("invalid prefix length /{} (max /{})", prefix, max),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_off_or<T: FromStr>(value: &str, what: &str) -> Result<(), (usize, String)> {
+    if value == "off" || value.parse::<T>().is_ok() {
+        return Ok(());
+    }
+    Err((0, format!("expected {} or `off`, found {:?}", what, value)))
+}
+
+fn check_fwmark(value: &str) -> Result<(), (usize, String)> {
+    match value.strip_prefix("0x") {
+        Some(hex) if u32::from_str_radix(hex, 16).is_ok() => Ok(()),
+        _ => check_off_or::<u32>(value, "a 32-bit mark"),
+    }
+}
+
+/// Checks one value of `key` in a section of `kind`. Unknown keys (e.g. wg-quick's
+/// `PostUp`) are accepted as they are.
+fn check_value(kind: &SectionKind, key: &str, value: &str) -> Vec<(usize, String)> {
+    let single = |result: Result<(), (usize, String)>| result.err().into_iter().collect();
+    let list = |check: fn(&str) -> Result<(), (usize, String)>| {
+        let mut errors = vec![];
+        let mut offset = 0;
+        for item in value.split(',') {
+            let start = offset + indent(item);
+            // Empty items, e.g. from a trailing comma, are skipped by wg-quick.
+            let item_value = item.trim();
+            if !item_value.is_empty() {
+                if let Err((at, message)) = check(item_value) {
+                    errors.push((start + at, message));
+                }
+            }
+            offset += item.chars().count() + 1;
+        }
+        errors
+    };
+    let key = key.to_ascii_lowercase();
+    match (kind, key.as_str()) {
+        (SectionKind::Interface, "privatekey")
+        | (SectionKind::Peer, "publickey" | "presharedkey") => single(check_key(value)),
+        (SectionKind::Interface, "listenport") => single(check_port(value).map(drop)),
+        (SectionKind::Interface, "fwmark") => single(check_fwmark(value)),
+        (SectionKind::Interface, "mtu") => single(match value.parse::<u16>() {
+            Ok(mtu) if mtu > 0 => Ok(()),
+            _ => Err((0, format!("invalid MTU {:?}", value))),
+        }),
+        (SectionKind::Interface, "address") | (SectionKind::Peer, "allowedips") => list(check_cidr),
+        (SectionKind::Peer, "endpoint") => single(check_endpoint(value)),
+        (SectionKind::Peer, "persistentkeepalive") => {
+            single(check_off_or::<u16>(value, "an interval in seconds"))
+        }
+        _ => vec![],
+    }
+}
+
+impl ConfFile {
+    /// Parses `text` and checks every key WireGuard knows (keys, ports, endpoints,
+    /// CIDRs, fwmarks, ...), reporting all problems with their positions.
+    ///
+    /// Host names in `Endpoint` are accepted, as wg-quick resolves them.
+    pub fn check(text: &str) -> Result<Self, CheckErrors> {
+        let file: ConfFile = text.parse().map_err(|e| CheckErrors(vec![e]))?;
+
+        let mut errors = vec![];
+        // The kind, header line and whether a PublicKey was seen, of the current
+        // section.
+        let mut section: Option<(SectionKind, usize, bool)> = None;
+        let mut headers = 0;
+        let finish = |section: Option<(SectionKind, usize, bool)>, errors: &mut Vec<_>| {
+            if let Some((SectionKind::Peer, line, false)) = section {
+                errors.push(ParseError {
+                    line,
+                    column: 1,
+                    message: "[Peer] without PublicKey".to_string(),
+                });
+            }
+        };
+        for (index, raw) in text.lines().enumerate() {
+            let (content, _) = split_comment(raw);
+            let trimmed = content.trim();
+            if trimmed.starts_with('[') {
+                finish(section.take(), &mut errors);
+                let kind = file.sections[headers].kind.clone();
+                headers += 1;
+                section = Some((kind, index + 1, false));
+                continue;
+            }
+            let (key, value) = match trimmed.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            let Some((kind, _, has_public_key)) = section.as_mut() else {
+                continue;
+            };
+            *has_public_key |= key.eq_ignore_ascii_case("PublicKey");
+
+            let equals = content.find('=').expect("entry has an `=`");
+            let value_column =
+                content[..equals].chars().count() + 1 + indent(&content[equals + 1..]) + 1;
+            for (offset, message) in check_value(kind, key, value) {
+                errors.push(ParseError {
+                    line: index + 1,
+                    column: value_column + offset,
+                    message: format!("{}: {}", key, message),
+                });
+            }
+        }
+        finish(section, &mut errors);
+
+        match errors.is_empty() {
+            true => Ok(file),
+            false => Err(CheckErrors(errors)),
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Blank => Ok(()),
+            Self::Comment(comment) => write!(f, "#{}", comment),
+            Self::Entry {
+                key,
+                value,
+                comment,
+            } => {
+                write!(f, "{} = {}", key, value)?;
+                match comment {
+                    Some(comment) => write!(f, " #{}", comment),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Splits a comma-separated list value, skipping empty items.
+pub(crate) fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// The `[Interface]` settings of a wg-quick config that configure the host rather
+/// than WireGuard.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct QuickInterface {
+    pub addresses: Vec<IpNet>,
+    /// The `DNS` servers and search domains, verbatim.
+    pub dns: Vec<String>,
+    pub mtu: Option<u32>,
+    /// The routing table for the allowed IPs, verbatim (`off`, `auto` or a table).
+    pub table: Option<String>,
+    pub pre_up: Vec<String>,
+    pub post_up: Vec<String>,
+    pub pre_down: Vec<String>,
+    pub post_down: Vec<String>,
+    pub save_config: bool,
+    /// Endpoints given as host names, which wg-quick resolves when bringing the
+    /// interface up. The matching peers are added without an endpoint.
+    pub endpoint_hosts: Vec<(Key, String)>,
+}
+
+/// A parsed wg-quick config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuickConfig {
+    /// The WireGuard settings, replacing the peers of the interface like
+    /// `wg setconf` does.
+    pub update: DeviceUpdate,
+    pub interface: QuickInterface,
+}
+
+impl QuickConfig {
+    /// Reads and parses the config at `path`.
+    pub fn from_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        std::fs::read_to_string(path)?.parse()
+    }
+}
+
+impl FromStr for QuickConfig {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let file = ConfFile::check(s)?;
+        let section = file.interface().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "config without [Interface]")
+        })?;
+
+        let mut update = DeviceUpdate::new().replace_peers();
+        if let Some(key) = parse_key(section, "PrivateKey")? {
+            update = update.set_private_key(key);
+        }
+        if let Some(port) = parse_value(section, "ListenPort")? {
+            update = update.set_listen_port(port);
+        }
+        if let Some(fwmark) = parse_fwmark(section)? {
+            update = update.set_fwmark(fwmark);
+        }
+
+        let all = |key| section.get_all(key).map(str::to_string).collect();
+        let mut interface = QuickInterface {
+            addresses: section
+                .get_all("Address")
+                .flat_map(split_list)
+                .map(|address| {
+                    // A bare address is a single host, as for `AllowedIPs`.
+                    address
+                        .parse()
+                        .or_else(|_| address.parse::<IpAddr>().map(IpNet::from))
+                        .map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("invalid Address {:?}", address),
+                            )
+                        })
+                })
+                .collect::<io::Result<_>>()?,
+            dns: section
+                .get_all("DNS")
+                .flat_map(split_list)
+                .map(str::to_string)
+                .collect(),
+            mtu: parse_value(section, "MTU")?,
+            table: section.get("Table").map(str::to_string),
+            pre_up: all("PreUp"),
+            post_up: all("PostUp"),
+            pre_down: all("PreDown"),
+            post_down: all("PostDown"),
+            save_config: parse_value(section, "SaveConfig")?.unwrap_or(false),
+            endpoint_hosts: vec![],
+        };
+
+        for section in file.peers() {
+            let peer = match section.get("Endpoint") {
+                Some(host) if host.parse::<SocketAddr>().is_err() => {
+                    let mut without_endpoint = section.clone();
+                    without_endpoint.remove("Endpoint");
+                    let peer = parse_peer(&without_endpoint)?;
+                    interface
+                        .endpoint_hosts
+                        .push((peer.public_key.clone(), host.to_string()));
+                    peer
+                }
+                _ => parse_peer(section)?,
+            };
+            update = update.add_peer(PeerConfigBuilder::from_peer_config(peer));
+        }
+
+        Ok(Self { update, interface })
+    }
+}
+
+/// The peers of a `wg setconf` style config, e.g. a batch of peers generated to
+/// be added to an interface. An `[Interface]` section, if any, is ignored.
+pub fn parse_peers(text: &str) -> io::Result<Vec<PeerConfig>> {
+    ConfFile::check(text)?.peers().map(parse_peer).collect()
+}
+
+pub(crate) fn parse_value<T: FromStr>(section: &Section, key: &str) -> io::Result<Option<T>> {
+    section
+        .get(key)
+        .map(|value| {
+            value.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid {} {:?}", key, value),
+                )
+            })
+        })
+        .transpose()
+}
+
+pub(crate) fn parse_key(section: &Section, key: &str) -> io::Result<Option<Key>> {
+    section
+        .get(key)
+        .map(|value| {
+            Key::from_base64(value)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid {}", key)))
+        })
+        .transpose()
+}
+
+pub(crate) fn parse_peer(section: &Section) -> io::Result<PeerConfig> {
+    let public_key = parse_key(section, "PublicKey")?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "peer without PublicKey"))?;
+    // The key may be repeated, each time adding to the list.
+    let allowed_ips = section
+        .get_all("AllowedIPs")
+        .flat_map(split_list)
+        .map(|ip| {
+            ip.parse::<AllowedIp>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid AllowedIPs entry {:?}", ip),
+                )
+            })
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(PeerConfig {
+        public_key,
+        preshared_key: parse_key(section, "PresharedKey")?,
+        endpoint: parse_value(section, "Endpoint")?,
+        persistent_keepalive_interval: parse_value(section, "PersistentKeepalive")?,
+        allowed_ips,
+        __cant_construct_me: (),
+    })
+}
+
+/// The `FwMark` of `section`, in decimal or hex. `off` is none.
+pub(crate) fn parse_fwmark(section: &Section) -> io::Result<Option<u32>> {
+    match section.get("FwMark") {
+        None | Some("off") => Ok(None),
+        Some(value) => {
+            let fwmark = match value.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => value.parse().ok(),
+            };
+            fwmark.map(Some).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid FwMark {:?}", value),
+                )
+            })
+        }
+    }
+}
+
+/// An `[Interface]` section with the given WireGuard settings.
+pub(crate) fn interface_section(
+    private_key: Option<&Key>,
+    listen_port: Option<u16>,
+    fwmark: Option<u32>,
+) -> Section {
+    let mut section = Section::new(SectionKind::Interface);
+    if let Some(key) = private_key {
+        section.set("PrivateKey", key.to_base64());
+    }
+    if let Some(port) = listen_port {
+        section.set("ListenPort", port.to_string());
+    }
+    if let Some(fwmark) = fwmark.filter(|fwmark| *fwmark != 0) {
+        section.set("FwMark", format!("{:#x}", fwmark));
+    }
+    section
+}
+
+/// A `[Peer]` section describing `peer`.
+pub(crate) fn peer_section(peer: &PeerConfig) -> Section {
+    let mut section = Section::new(SectionKind::Peer);
+    section.set("PublicKey", peer.public_key.to_base64());
+    if let Some(key) = &peer.preshared_key {
+        section.set("PresharedKey", key.to_base64());
+    }
+    if let Some(endpoint) = peer.endpoint {
+        section.set("Endpoint", endpoint.to_string());
+    }
+    if let Some(interval) = peer.persistent_keepalive_interval {
+        section.set("PersistentKeepalive", interval.to_string());
+    }
+    if !peer.allowed_ips.is_empty() {
+        let ips: Vec<_> = peer
+            .allowed_ips
+            .iter()
+            .map(|ip| format!("{:?}", ip))
+            .collect();
+        section.set("AllowedIPs", ips.join(", "));
+    }
+    section
+}
+
+impl Device {
+    /// The interface and its peers as a wg-quick config, which
+    /// [`QuickConfig`] parses back.
+    ///
+    /// Only WireGuard settings are included: addresses, DNS and the like aren't
+    /// known to the device. The text includes the private key, and is built so
+    /// that wrapping it in [`Zeroizing`](zeroize::Zeroizing) wipes every copy.
+    pub fn to_wg_quick_config(&self) -> String {
+        let mut file = ConfFile::default();
+        file.sections.push(interface_section(
+            self.private_key.as_ref(),
+            self.listen_port,
+            self.fwmark,
+        ));
+        for peer in &self.peers {
+            file.sections.push(peer_section(&peer.config));
+        }
+        secret_text(file)
+    }
+}
+
+impl DeviceUpdate {
+    /// The settings and peers of this update as a wg-quick config. Peers being
+    /// removed are left out. Keys are handled as in
+    /// [`Device::to_wg_quick_config`].
+    pub fn to_wg_quick_config(&self) -> String {
+        let mut file = ConfFile::default();
+        file.sections.push(interface_section(
+            self.private_key.as_ref(),
+            self.listen_port,
+            self.fwmark,
+        ));
+        for peer in self.peers.iter().filter(|peer| !peer.remove_me) {
+            file.sections
+                .push(peer_section(&peer.clone().into_peer_config()));
+        }
+        secret_text(file)
+    }
+}
+
+/// The config of a client of a single server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientConfig {
+    /// Shown as the tunnel's name by the apps that read `# Name:` annotations.
+    pub name: Option<String>,
+    pub private_key: Key,
+    pub addresses: Vec<IpNet>,
+    pub dns: Vec<String>,
+    pub mtu: Option<u16>,
+    pub server_public_key: Key,
+    pub preshared_key: Option<Key>,
+    /// Where the client reaches the server, e.g. `vpn.example.com:51820`.
+    pub endpoint: String,
+    /// What the client routes through the tunnel, everything by default.
+    pub allowed_ips: Vec<IpNet>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl ClientConfig {
+    pub fn new(private_key: Key, server_public_key: Key, endpoint: &str) -> Self {
+        Self {
+            name: None,
+            private_key,
+            addresses: vec![],
+            dns: vec![],
+            mtu: None,
+            server_public_key,
+            preshared_key: None,
+            endpoint: endpoint.to_string(),
+            allowed_ips: vec![
+                "0.0.0.0/0".parse().expect("valid network"),
+                "::/0".parse().expect("valid network"),
+            ],
+            persistent_keepalive: None,
+        }
+    }
+
+    pub fn to_conf(&self) -> ConfFile {
+        let join = |nets: &[IpNet]| {
+            nets.iter()
+                .map(IpNet::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut interface = Section::new(SectionKind::Interface);
+        if let Some(name) = &self.name {
+            interface.set_annotation("Name", name);
+        }
+        interface.set("PrivateKey", self.private_key.to_base64());
+        if !self.addresses.is_empty() {
+            interface.set("Address", join(&self.addresses));
+        }
+        if !self.dns.is_empty() {
+            interface.set("DNS", self.dns.join(", "));
+        }
+        if let Some(mtu) = self.mtu {
+            interface.set("MTU", mtu.to_string());
+        }
+
+        let mut server = Section::new(SectionKind::Peer);
+        server.set("PublicKey", self.server_public_key.to_base64());
+        if let Some(key) = &self.preshared_key {
+            server.set("PresharedKey", key.to_base64());
+        }
+        server.set("Endpoint", self.endpoint.clone());
+        server.set("AllowedIPs", join(&self.allowed_ips));
+        if let Some(keepalive) = self.persistent_keepalive {
+            server.set("PersistentKeepalive", keepalive.to_string());
+        }
+
+        let mut config = ConfFile::default();
+        config.sections.push(interface);
+        config.sections.push(server);
+        config
+    }
+}
+
+impl fmt::Display for ClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_conf().fmt(f)
+    }
+}
+
+/// `file` as text, for files with keys: the text is written without leaving
+/// reallocated copies behind, and the values in `file` are wiped once written.
+/// Wiping the returned text is up to the caller.
+fn secret_text(mut file: ConfFile) -> String {
+    let mut text = SecretBuf::default();
+    write!(text, "{}", file).ok();
+    for line in file
+        .sections
+        .iter_mut()
+        .flat_map(|section| &mut section.lines)
+    {
+        if let Line::Entry { value, .. } = line {
+            value.zeroize();
+        }
+    }
+    std::mem::take(&mut *text.into_inner())
+}
+
+impl fmt::Display for ConfFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.preamble {
+            writeln!(f, "{}", line)?;
+        }
+        for section in &self.sections {
+            for comment in &section.leading_comments {
+                writeln!(f, "#{}", comment)?;
+            }
+            write!(f, "[{}]", section.kind)?;
+            match &section.header_comment {
+                Some(comment) => writeln!(f, " #{}", comment)?,
+                None => writeln!(f)?,
+            }
+            for line in &section.lines {
+                writeln!(f, "{}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONF: &str = "\
+# Managed by hand, keep sorted.
+
+[Interface]
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+ListenPort = 51820 # the default
+
+# Name: alice-laptop
+# Owner: alice@example.com
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+AllowedIPs = 10.0.0.2/32
+AllowedIPs = fd00::2/128
+
+[Peer]
+# Name: bob-phone
+PublicKey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+AllowedIPs = 10.0.0.3/32
+";
+
+    #[test]
+    fn test_roundtrip() {
+        let file: ConfFile = CONF.parse().unwrap();
+        assert_eq!(file.to_string(), CONF);
+        assert_eq!(file.preamble.len(), 2);
+        assert_eq!(file.interface().unwrap().get("listenport"), Some("51820"));
+    }
+
+    #[test]
+    fn test_annotations() {
+        let mut file: ConfFile = CONF.parse().unwrap();
+        let peers: Vec<_> = file.peers().collect();
+        assert_eq!(peers[0].name().as_deref(), Some("alice-laptop"));
+        assert_eq!(
+            peers[0].annotation("owner").as_deref(),
+            Some("alice@example.com")
+        );
+        assert_eq!(
+            peers[0].get_all("AllowedIPs").collect::<Vec<_>>(),
+            ["10.0.0.2/32", "fd00::2/128"]
+        );
+        assert_eq!(peers[1].name().as_deref(), Some("bob-phone"));
+
+        let bob = file.peers_mut().nth(1).unwrap();
+        bob.set_annotation("Name", "bob-tablet");
+        bob.set("AllowedIPs", "10.0.0.4/32");
+        let text = file.to_string();
+        assert!(text.contains("# Name: bob-tablet\nPublicKey"));
+        assert!(text.ends_with("AllowedIPs = 10.0.0.4/32\n"));
+        assert!(text.contains("# Owner: alice@example.com\n[Peer]"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = "[Interface]\nListenPort 51820\n"
+            .parse::<ConfFile>()
+            .unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!("ListenPort = 1\n".parse::<ConfFile>().is_err());
+        assert!("[Peer\n".parse::<ConfFile>().is_err());
+        assert!("[Peer # laptop]\n".parse::<ConfFile>().is_err());
+    }
+
+    #[test]
+    fn test_header_comment() {
+        let text = "[Peer] # laptop\nPublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\n";
+        let file = ConfFile::check(text).unwrap();
+        let peer = file.peers().next().unwrap();
+        assert_eq!(peer.header_comment.as_deref(), Some(" laptop"));
+        assert_eq!(file.to_string(), text);
+    }
+
+    #[test]
+    fn test_parse_peers() {
+        let peers = parse_peers(CONF).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(
+            peers[0].allowed_ips,
+            vec![
+                "10.0.0.2/32".parse().unwrap(),
+                "fd00::2/128".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            peers[1].public_key.to_base64(),
+            "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0="
+        );
+        assert!(parse_peers("[Peer]\nAllowedIPs = 10.0.0.1/32\n").is_err());
+
+        // Bare addresses are single hosts, as `check` and `wg` accept them.
+        let text = "[Peer]\n\
+                    PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\n\
+                    AllowedIPs = 10.0.0.2, fd00::2\n";
+        assert_eq!(
+            parse_peers(text).unwrap()[0].allowed_ips,
+            vec![
+                "10.0.0.2/32".parse().unwrap(),
+                "fd00::2/128".parse().unwrap()
+            ]
+        );
+        assert!(parse_peers("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_quick_config() {
+        let text = "\
+[Interface]
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+Address = 10.0.0.1/24, fd00::1/64, 10.0.1.1
+DNS = 10.0.0.53, internal.example
+FwMark = 0xca6c
+Table = off
+PostUp = iptables -A FORWARD -i %i -j ACCEPT
+PostUp = sysctl -w net.ipv4.ip_forward=1
+SaveConfig = true
+
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+Endpoint = vpn.example.com:51820
+AllowedIPs = 10.0.0.2/32
+";
+        let config: QuickConfig = text.parse().unwrap();
+        assert_eq!(config.update.fwmark, Some(51820));
+        assert!(config.update.replace_peers);
+        assert_eq!(config.update.peers.len(), 1);
+        assert_eq!(config.update.peers[0].endpoint, None);
+
+        let interface = config.interface;
+        assert_eq!(interface.addresses.len(), 3);
+        assert_eq!(
+            interface.addresses[2],
+            "10.0.1.1/32".parse::<IpNet>().unwrap()
+        );
+        assert_eq!(interface.dns, ["10.0.0.53", "internal.example"]);
+        assert_eq!(interface.table.as_deref(), Some("off"));
+        assert_eq!(interface.post_up.len(), 2);
+        assert!(interface.save_config);
+        assert_eq!(interface.endpoint_hosts[0].1, "vpn.example.com:51820");
+
+        assert!("[Peer]\nPublicKey = x\n".parse::<QuickConfig>().is_err());
+    }
+
+    #[test]
+    fn test_to_wg_quick_config() {
+        let peer = PeerConfigBuilder::new(&Key([2u8; 32]))
+            .set_endpoint("192.0.2.1:51820".parse().unwrap())
+            .add_allowed_ip("10.0.0.2".parse().unwrap(), 32);
+        let update = DeviceUpdate::new()
+            .set_private_key(Key([1u8; 32]))
+            .set_listen_port(51820)
+            .set_fwmark(51820)
+            .add_peer(peer)
+            .remove_peer_by_key(&Key([3u8; 32]));
+
+        let text = update.to_wg_quick_config();
+        assert_eq!(
+            text,
+            format!(
+                "[Interface]\nPrivateKey = {}\nListenPort = 51820\nFwMark = 0xca6c\n\
+                 [Peer]\nPublicKey = {}\nEndpoint = 192.0.2.1:51820\nAllowedIPs = 10.0.0.2/32\n",
+                Key([1u8; 32]).to_base64(),
+                Key([2u8; 32]).to_base64()
+            )
+        );
+
+        let parsed: QuickConfig = text.parse().unwrap();
+        assert_eq!(parsed.update.fwmark, Some(51820));
+        assert_eq!(parsed.update.peers[0].allowed_ips.len(), 1);
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(ConfFile::check(CONF).is_ok());
+
+        let text = "[Interface]\n\
+                    PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJB\n\
+                    ListenPort = 70000\n\
+                    [Peer]\n\
+                    PublicKey = xTIBA5rboUvnH4htodjb6e697Qj!ERt1NAB4mZqp8Dg=\n\
+                    Endpoint = [fd00::1]:0\n\
+                    AllowedIPs = 10.0.0.2/32, 10.0.0.3/33\n\
+                    [Peer]\n\
+                    AllowedIPs = 10.0.0.4\n";
+        let errors = ConfFile::check(text).unwrap_err().0;
+        let positions: Vec<_> = errors.iter().map(|e| (e.line, e.column)).collect();
+        assert_eq!(
+            positions,
+            [(2, 14), (3, 14), (5, 40), (6, 22), (7, 36), (8, 1)]
+        );
+        assert!(errors[1].message.starts_with("ListenPort: "));
+        assert_eq!(errors[5].message, "[Peer] without PublicKey");
+    }
+}
@@ -1,9 +1,10 @@
 use crate::{
+    allowed_ips::{self, InvalidCidr},
     device::{AllowedIp, PeerConfig},
     key::Key,
 };
 
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
 
 /// Builds and represents a single peer in a WireGuard interface configuration.
 ///
@@ -160,8 +161,45 @@ impl PeerConfigBuilder {
     /// that all traffic should be routed through.
     #[must_use]
     pub fn allow_all_ips(self) -> Self {
-        self.add_allowed_ip(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
-            .add_allowed_ip(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0)
+        self.add_allowed_ips(&[AllowedIp::ALL_V4, AllowedIp::ALL_V6])
+    }
+
+    /// Specifies that this peer's allowed IPs should be exactly
+    /// [`ALL_V4`](AllowedIp::ALL_V4) and [`ALL_V6`](AllowedIp::ALL_V6),
+    /// replacing anything configured before.
+    #[must_use]
+    pub fn allow_all(mut self) -> Self {
+        self.allowed_ips.clear();
+        self.replace_allowed_ips().allow_all_ips()
+    }
+
+    /// Specifies that all traffic should be routed to this peer except traffic to
+    /// `endpoint`, replacing the existing allowed IPs.
+    ///
+    /// The address family of `endpoint` is split with
+    /// [`AllowedIp::default_route_excluding`], the other family is allowed entirely.
+    /// Full-tunnel clients need this so the encrypted packets themselves don't get
+    /// routed back into the tunnel.
+    #[must_use]
+    pub fn allow_all_excluding(mut self, endpoint: IpAddr) -> Self {
+        let other_family = if endpoint.is_ipv4() {
+            AllowedIp::ALL_V6
+        } else {
+            AllowedIp::ALL_V4
+        };
+        self.allowed_ips.clear();
+        self.replace_allowed_ips()
+            .add_allowed_ips(&AllowedIp::default_route_excluding(endpoint))
+            .add_allowed_ips(&[other_family])
+    }
+
+    /// Merges and normalizes the allowed IPs added so far.
+    ///
+    /// See [`allowed_ips::aggregate`](crate::allowed_ips::aggregate) for details;
+    /// this fails if any of the allowed IPs has an invalid CIDR mask.
+    pub fn aggregate_allowed_ips(mut self) -> Result<Self, InvalidCidr> {
+        self.allowed_ips = allowed_ips::aggregate(&self.allowed_ips)?;
+        Ok(self)
     }
 
     /// Specifies that the allowed IP addresses in this configuration should replace
@@ -0,0 +1,279 @@
+//! Experimental: peer sets that several controllers can edit and merge without
+//! coordination.
+//!
+//! A [`PeerSet`] is an observed-remove set of peers: every addition is tagged with
+//! a unique [`Dot`], and a removal only removes the tags its replica has seen. Two
+//! controllers that edited their copies during a partition can exchange and
+//! [`merge`](PeerSet::merge) them in any order and end up with the same peers, and
+//! a peer added on one side concurrently with its removal on the other survives.
+//! When both sides changed the same peer concurrently, the change with the higher
+//! dot wins. [`materialize`](PeerSet::materialize) turns the converged set into
+//! the changes to apply on each node, and [`sync`](PeerSet::sync) applies them.
+//!
+//! Instead of a tombstone per removal, each replica keeps the highest counter it
+//! has seen from every replica: an addition covered by it but missing from the
+//! entries was removed. The state stays proportional to the peers and replicas.
+use crate::{Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfig, PeerConfigBuilder};
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+};
+
+/// A unique tag of one addition: the replica that made it and its counter there.
+///
+/// Dots are ordered by counter, then replica, so later changes win conflicts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Dot {
+    pub counter: u64,
+    pub replica: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Entry {
+    dot: Dot,
+    peer: PeerConfig,
+}
+
+/// One replica's copy of a replicated peer set. With the `serde` feature it can be
+/// serialized to send it to the other replicas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerSet {
+    replica: String,
+    /// The highest counter seen from any replica.
+    counter: u64,
+    entries: Vec<Entry>,
+    /// The highest counter seen from each replica. A replica's counters only
+    /// reach others along with its earlier ones, so every addition up to it has
+    /// been seen, and those not in `entries` were removed or replaced.
+    seen: BTreeMap<String, u64>,
+}
+
+impl PeerSet {
+    /// An empty set edited by `replica`, which must be unique among the replicas.
+    pub fn new(replica: impl Into<String>) -> Self {
+        Self {
+            replica: replica.into(),
+            counter: 0,
+            entries: vec![],
+            seen: BTreeMap::new(),
+        }
+    }
+
+    pub fn replica(&self) -> &str {
+        &self.replica
+    }
+
+    /// Whether the addition tagged `dot` has been seen by this replica.
+    fn has_seen(&self, dot: &Dot) -> bool {
+        self.seen
+            .get(&dot.replica)
+            .is_some_and(|counter| dot.counter <= *counter)
+    }
+
+    fn drop_entries(&mut self, public_key: &Key) -> bool {
+        let count = self.entries.len();
+        self.entries
+            .retain(|entry| entry.peer.public_key != *public_key);
+        self.entries.len() != count
+    }
+
+    /// Adds `peer`, replacing the versions of it this replica has seen.
+    pub fn insert(&mut self, peer: PeerConfig) -> Dot {
+        self.drop_entries(&peer.public_key);
+        self.counter += 1;
+        let dot = Dot {
+            counter: self.counter,
+            replica: self.replica.clone(),
+        };
+        self.seen.insert(self.replica.clone(), self.counter);
+        self.entries.push(Entry {
+            dot: dot.clone(),
+            peer,
+        });
+        dot
+    }
+
+    /// Removes the peer, returning whether this replica had seen it.
+    pub fn remove(&mut self, public_key: &Key) -> bool {
+        self.drop_entries(public_key)
+    }
+
+    /// Merges the changes of another replica into this one.
+    pub fn merge(&mut self, other: &PeerSet) {
+        let own: HashSet<Dot> = self.entries.iter().map(|entry| entry.dot.clone()).collect();
+        let theirs: HashSet<&Dot> = other.entries.iter().map(|entry| &entry.dot).collect();
+        // An entry the other replica has seen but doesn't have was removed there.
+        self.entries
+            .retain(|entry| theirs.contains(&entry.dot) || !other.has_seen(&entry.dot));
+        for entry in &other.entries {
+            if !own.contains(&entry.dot) && !self.has_seen(&entry.dot) {
+                self.entries.push(entry.clone());
+            }
+        }
+        for (replica, counter) in &other.seen {
+            let seen = self.seen.entry(replica.clone()).or_insert(0);
+            *seen = (*seen).max(*counter);
+        }
+        self.counter = self.counter.max(other.counter);
+        // Keep the state identical across replicas that saw the same changes.
+        self.entries.sort_by(|a, b| a.dot.cmp(&b.dot));
+    }
+
+    /// The peers in the set, one per public key, ordered by public key.
+    pub fn peers(&self) -> Vec<PeerConfig> {
+        let mut winners: HashMap<&Key, &Entry> = HashMap::new();
+        for entry in &self.entries {
+            let winner = winners.entry(&entry.peer.public_key).or_insert(entry);
+            if entry.dot > winner.dot {
+                *winner = entry;
+            }
+        }
+        let mut peers: Vec<_> = winners
+            .into_values()
+            .map(|entry| entry.peer.clone())
+            .collect();
+        peers.sort_by_cached_key(|peer| peer.public_key.to_base64());
+        peers
+    }
+
+    pub fn contains(&self, public_key: &Key) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.peer.public_key == *public_key)
+    }
+
+    /// The update replacing an interface's peers with the peers in the set.
+    fn update(&self) -> DeviceUpdate {
+        let peers: Vec<_> = self
+            .peers()
+            .into_iter()
+            .map(PeerConfigBuilder::from_peer_config)
+            .collect();
+        DeviceUpdate::new().replace_peers().add_peers(&peers)
+    }
+
+    /// The changes making `current`, as read from an interface, have exactly the
+    /// peers in the set, like [`Device::diff`]. Peers that stay the same are left
+    /// out, so their sessions survive.
+    pub fn materialize(&self, current: &Device) -> DeviceUpdate {
+        // The set holds one peer per key, so there are no duplicates to merge.
+        self.update()
+            .changes(current)
+            .expect("merging duplicate peers failed")
+    }
+
+    /// Makes the peers of `iface` exactly the peers in the set, like
+    /// [`DeviceUpdate::sync`], creating the interface if it doesn't exist.
+    pub fn sync(&self, iface: &InterfaceName, backend: Backend) -> io::Result<()> {
+        Ok(self.update().sync(iface, backend)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(key: u8, port: u16) -> PeerConfig {
+        PeerConfig {
+            public_key: Key([key; 32]),
+            preshared_key: None,
+            endpoint: Some(([192, 0, 2, key], port).into()),
+            persistent_keepalive_interval: None,
+            allowed_ips: vec![],
+            __cant_construct_me: (),
+        }
+    }
+
+    fn merged(a: &PeerSet, b: &PeerSet) -> PeerSet {
+        let mut merged = a.clone();
+        merged.merge(b);
+        merged
+    }
+
+    #[test]
+    fn test_convergence() {
+        let mut a = PeerSet::new("a");
+        a.insert(peer(1, 51820));
+        a.insert(peer(2, 51820));
+        let mut b = PeerSet::new("b");
+        b.merge(&a);
+
+        // Partitioned: a removes peer 1 and adds peer 3, b updates peer 2.
+        a.remove(&Key([1; 32]));
+        a.insert(peer(3, 51820));
+        b.insert(peer(2, 4500));
+
+        let ab = merged(&a, &b);
+        let ba = merged(&b, &a);
+        assert_eq!((&ab.entries, &ab.seen), (&ba.entries, &ba.seen));
+        assert_eq!(ab.peers(), vec![peer(2, 4500), peer(3, 51820)]);
+        // Merging is idempotent.
+        assert_eq!(merged(&ab, &a).peers(), ab.peers());
+    }
+
+    #[test]
+    fn test_add_wins() {
+        let mut a = PeerSet::new("a");
+        a.insert(peer(1, 51820));
+        let mut b = a.clone();
+        b.replica = "b".to_string();
+
+        // b re-adds the peer it hasn't seen a remove.
+        a.remove(&Key([1; 32]));
+        b.insert(peer(1, 4500));
+        assert_eq!(merged(&a, &b).peers(), vec![peer(1, 4500)]);
+
+        // Once the removal has seen every addition, the peer stays removed.
+        let mut c = merged(&a, &b);
+        c.remove(&Key([1; 32]));
+        assert!(!merged(&c, &b).contains(&Key([1; 32])));
+    }
+
+    #[test]
+    fn test_removals_stay_bounded() {
+        let mut a = PeerSet::new("a");
+        let mut b = PeerSet::new("b");
+        for round in 0..100 {
+            a.insert(peer(1, round));
+            b.merge(&a);
+            b.remove(&Key([1; 32]));
+            a.merge(&b);
+        }
+        assert!(!a.contains(&Key([1; 32])));
+        assert!(a.entries.is_empty());
+        assert_eq!(a.seen.len(), 1);
+    }
+
+    #[test]
+    fn test_materialize() {
+        use crate::PeerInfo;
+
+        let mut set = PeerSet::new("a");
+        set.insert(peer(1, 51820));
+        set.insert(peer(2, 51820));
+        let current = Device::fixture(
+            "wg0",
+            [peer(1, 51820), peer(3, 51820)]
+                .into_iter()
+                .map(|config| PeerInfo {
+                    config,
+                    stats: Default::default(),
+                })
+                .collect(),
+        );
+
+        // Peer 1 is left alone, peer 2 added and peer 3 removed.
+        let update = set.materialize(&current);
+        assert!(!update.replace_peers);
+        let changed: Vec<_> = update
+            .peers
+            .iter()
+            .map(|peer| (peer.public_key.clone(), peer.remove_me))
+            .collect();
+        assert_eq!(changed, [(Key([2; 32]), false), (Key([3; 32]), true)]);
+    }
+}
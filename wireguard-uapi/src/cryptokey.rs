@@ -0,0 +1,513 @@
+//! The cryptokey routing table, as the kernel keeps it.
+//!
+//! WireGuard routes an outgoing packet to the peer whose allowed IPs have the
+//! longest prefix matching its destination, and accepts an incoming packet only if
+//! its source routes back to the peer that sent it. A [`CryptokeyTable`] is that
+//! table: a binary trie per address family mapping prefixes to peers, with the
+//! kernel's semantics. A prefix belongs to one peer, so adding it to another
+//! moves it, and host bits are ignored. Userspace dataplanes and tools that
+//! predict where traffic goes can reuse it instead of reimplementing them.
+use crate::{
+    allowed_ips::{normalize, InvalidCidr},
+    AllowedIp, AllowedIpConflicts, ApplyError, ApplyField, Device, DeviceUpdate, Key, PeerRef,
+};
+
+use std::{collections::HashSet, net::IpAddr};
+
+/// A node of a [`Trie`]: its children by the next bit, as indexes into the node
+/// list where 0 (the root, which is nobody's child) means none, and the value of
+/// the prefix ending here.
+#[derive(Debug, Clone)]
+struct Node<T> {
+    children: [u32; 2],
+    value: Option<T>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self {
+            children: [0; 2],
+            value: None,
+        }
+    }
+}
+
+/// A binary trie of the prefixes of one address family. Addresses are
+/// left-aligned in a `u128`. Nodes that no longer lead to a value are unlinked
+/// and their slots reused, so the trie doesn't grow with churn.
+#[derive(Debug, Clone)]
+struct Trie<T> {
+    nodes: Vec<Node<T>>,
+    /// Unlinked nodes, to be reused before the list grows.
+    free: Vec<u32>,
+}
+
+impl<T> Trie<T> {
+    fn new() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+            free: vec![],
+        }
+    }
+
+    fn alloc(&mut self) -> u32 {
+        match self.free.pop() {
+            Some(node) => {
+                self.nodes[node as usize] = Node::default();
+                node
+            }
+            None => {
+                self.nodes.push(Node::default());
+                self.nodes.len() as u32 - 1
+            }
+        }
+    }
+
+    fn bit(bits: u128, depth: u8) -> usize {
+        (bits >> (127 - depth)) as usize & 1
+    }
+
+    /// The node of the prefix, if it was ever inserted.
+    fn find(&self, bits: u128, cidr: u8) -> Option<usize> {
+        let mut node = 0;
+        for depth in 0..cidr {
+            match self.nodes[node].children[Self::bit(bits, depth)] {
+                0 => return None,
+                child => node = child as usize,
+            }
+        }
+        Some(node)
+    }
+
+    fn insert(&mut self, bits: u128, cidr: u8, value: T) -> Option<T> {
+        let mut node = 0;
+        for depth in 0..cidr {
+            let bit = Self::bit(bits, depth);
+            if self.nodes[node].children[bit] == 0 {
+                self.nodes[node].children[bit] = self.alloc();
+            }
+            node = self.nodes[node].children[bit] as usize;
+        }
+        self.nodes[node].value.replace(value)
+    }
+
+    /// Removes the value of the prefix, unlinking the nodes on its path that are
+    /// left without a value or children.
+    fn remove(&mut self, bits: u128, cidr: u8) -> Option<T> {
+        let mut path = Vec::with_capacity(cidr as usize);
+        let mut node = 0;
+        for depth in 0..cidr {
+            let bit = Self::bit(bits, depth);
+            match self.nodes[node].children[bit] {
+                0 => return None,
+                child => {
+                    path.push((node, bit));
+                    node = child as usize;
+                }
+            }
+        }
+        let value = self.nodes[node].value.take()?;
+        while let Some((parent, bit)) = path.pop() {
+            let child = self.nodes[parent].children[bit];
+            let Node { children, value } = &self.nodes[child as usize];
+            if value.is_some() || *children != [0; 2] {
+                break;
+            }
+            self.nodes[parent].children[bit] = 0;
+            self.free.push(child);
+        }
+        Some(value)
+    }
+
+    /// Rebuilds the node list without the nodes that don't lead to a value.
+    fn compact(&mut self) {
+        let mut old = std::mem::replace(&mut self.nodes, vec![Node::default()]);
+        self.free.clear();
+        self.nodes[0].value = old[0].value.take();
+        let children = old[0].children;
+        for (bit, child) in children.into_iter().enumerate() {
+            self.nodes[0].children[bit] = self.copy(&mut old, child);
+        }
+    }
+
+    /// Moves the subtree at `node` of `old` over, returning its new index, or 0 if
+    /// it has no values.
+    fn copy(&mut self, old: &mut [Node<T>], node: u32) -> u32 {
+        if node == 0 {
+            return 0;
+        }
+        let index = self.nodes.len();
+        let children = old[node as usize].children;
+        self.nodes.push(Node {
+            children: [0; 2],
+            value: old[node as usize].value.take(),
+        });
+        for (bit, child) in children.into_iter().enumerate() {
+            self.nodes[index].children[bit] = self.copy(old, child);
+        }
+        if self.nodes[index].value.is_none() && self.nodes[index].children == [0; 2] {
+            // Pruned children popped themselves, so this is the last node.
+            self.nodes.pop();
+            return 0;
+        }
+        index as u32
+    }
+
+    fn lookup(&self, bits: u128, max_cidr: u8) -> Option<&T> {
+        let mut node = 0;
+        let mut found = self.nodes[0].value.as_ref();
+        for depth in 0..max_cidr {
+            match self.nodes[node].children[Self::bit(bits, depth)] {
+                0 => break,
+                child => node = child as usize,
+            }
+            found = self.nodes[node].value.as_ref().or(found);
+        }
+        found
+    }
+
+    /// Every prefix with a value, depth first so shorter prefixes come first.
+    fn entries(&self) -> Vec<(u128, u8, &T)> {
+        let mut entries = vec![];
+        let mut stack = vec![(0usize, 0u128, 0u8)];
+        while let Some((node, bits, depth)) = stack.pop() {
+            if let Some(value) = &self.nodes[node].value {
+                entries.push((bits, depth, value));
+            }
+            for bit in [1, 0] {
+                let child = self.nodes[node].children[bit];
+                if child != 0 {
+                    let bits = bits | (bit as u128) << (127 - depth);
+                    stack.push((child as usize, bits, depth + 1));
+                }
+            }
+        }
+        entries
+    }
+}
+
+/// The address bits of `ip` left-aligned in a `u128`, and the family's prefix
+/// length.
+fn left_aligned(ip: &IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(ip) => ((u32::from(*ip) as u128) << 96, 32),
+        IpAddr::V6(ip) => (u128::from(*ip), 128),
+    }
+}
+
+/// A longest prefix match table from allowed IPs to peers, by default identified
+/// by their public keys.
+#[derive(Debug, Clone)]
+pub struct CryptokeyTable<T = Key> {
+    v4: Trie<T>,
+    v6: Trie<T>,
+}
+
+impl<T> Default for CryptokeyTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> CryptokeyTable<T> {
+    pub fn new() -> Self {
+        Self {
+            v4: Trie::new(),
+            v6: Trie::new(),
+        }
+    }
+
+    fn trie(&self, ip: &IpAddr) -> &Trie<T> {
+        match ip {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        }
+    }
+
+    /// Routes `prefix` to `value`, returning the value it was routed to before.
+    /// Host bits are ignored, as by the kernel.
+    pub fn insert(&mut self, prefix: &AllowedIp, value: T) -> Result<Option<T>, InvalidCidr> {
+        let prefix = normalize(prefix)?;
+        let (bits, _) = left_aligned(&prefix.address);
+        let trie = match prefix.address {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        };
+        Ok(trie.insert(bits, prefix.cidr, value))
+    }
+
+    /// Stops routing exactly `prefix`, returning the value it was routed to.
+    /// Addresses in it fall back to the next shorter prefix.
+    pub fn remove(&mut self, prefix: &AllowedIp) -> Option<T> {
+        let prefix = normalize(prefix).ok()?;
+        let (bits, _) = left_aligned(&prefix.address);
+        let trie = match prefix.address {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        };
+        trie.remove(bits, prefix.cidr)
+    }
+
+    /// The value exactly `prefix` is routed to.
+    pub fn get(&self, prefix: &AllowedIp) -> Option<&T> {
+        let prefix = normalize(prefix).ok()?;
+        let (bits, _) = left_aligned(&prefix.address);
+        let trie = self.trie(&prefix.address);
+        trie.nodes[trie.find(bits, prefix.cidr)?].value.as_ref()
+    }
+
+    /// The value `ip` routes to: that of the longest prefix containing it.
+    pub fn lookup(&self, ip: &IpAddr) -> Option<&T> {
+        let (bits, max_cidr) = left_aligned(ip);
+        self.trie(ip).lookup(bits, max_cidr)
+    }
+
+    /// Every routed prefix and its value, IPv4 first, each family in address
+    /// order with shorter prefixes before the longer ones they contain.
+    pub fn entries(&self) -> Vec<(AllowedIp, &T)> {
+        let v4 = self.v4.entries().into_iter().map(|(bits, cidr, value)| {
+            let address = IpAddr::V4(((bits >> 96) as u32).into());
+            (AllowedIp::new(address, cidr), value)
+        });
+        let v6 = self
+            .v6
+            .entries()
+            .into_iter()
+            .map(|(bits, cidr, value)| (AllowedIp::new(IpAddr::V6(bits.into()), cidr), value));
+        v4.chain(v6).collect()
+    }
+
+    /// Removes every value `remove` returns `true` for, returning how many
+    /// prefixes were routed to them.
+    pub fn remove_where(&mut self, mut remove: impl FnMut(&T) -> bool) -> usize {
+        let mut removed = 0;
+        for trie in [&mut self.v4, &mut self.v6] {
+            let before = removed;
+            for node in &mut trie.nodes {
+                if node.value.as_ref().is_some_and(&mut remove) {
+                    node.value = None;
+                    removed += 1;
+                }
+            }
+            if removed > before {
+                trie.compact();
+            }
+        }
+        removed
+    }
+}
+
+impl<T: PartialEq> CryptokeyTable<T> {
+    /// Stops routing every prefix to `value`, as removing a peer does.
+    pub fn remove_value(&mut self, value: &T) -> usize {
+        self.remove_where(|other| other == value)
+    }
+
+    /// The prefixes routed to `value`.
+    pub fn allowed_ips(&self, value: &T) -> Vec<AllowedIp> {
+        self.entries()
+            .into_iter()
+            .filter(|(_, other)| *other == value)
+            .map(|(prefix, _)| prefix)
+            .collect()
+    }
+}
+
+impl CryptokeyTable<Key> {
+    /// The table of `device`. Of peers sharing a prefix, which a device read
+    /// from the kernel never has, the later one gets it.
+    pub fn from_device(device: &Device) -> Self {
+        let mut table = Self::new();
+        for peer in &device.peers {
+            for prefix in &peer.config.allowed_ips {
+                let _ = table.insert(prefix, peer.config.public_key.clone());
+            }
+        }
+        table
+    }
+
+    /// Applies the allowed IPs of `update` the way the kernel does: removed peers
+    /// and peers replacing their allowed IPs lose theirs first, then each prefix
+    /// goes to the last peer it's given to, or as the update's
+    /// [`AllowedIpConflicts`] says when another peer has it. Fails on the first
+    /// invalid or, with [`AllowedIpConflicts::Error`], conflicting prefix, leaving
+    /// the changes before it applied.
+    pub fn apply(&mut self, update: &DeviceUpdate) -> Result<(), ApplyError> {
+        if update.replace_peers {
+            *self = Self::new();
+        }
+        // Prefixes of peers giving theirs up don't conflict, as in `apply`.
+        let released: HashSet<&Key> = update
+            .peers
+            .iter()
+            .filter(|peer| peer.remove_me || peer.replace_allowed_ips)
+            .map(|peer| &peer.public_key)
+            .collect();
+        for (index, peer) in update.peers.iter().enumerate() {
+            if peer.remove_me || peer.replace_allowed_ips {
+                self.remove_value(&peer.public_key);
+            }
+            if peer.remove_me {
+                continue;
+            }
+            let invalid = |message: String| {
+                let peer = PeerRef {
+                    index,
+                    public_key: peer.public_key.clone(),
+                };
+                ApplyError::invalid(peer, ApplyField::AllowedIps, message)
+            };
+            for prefix in &peer.allowed_ips {
+                let prefix = normalize(prefix).map_err(|e| invalid(e.to_string()))?;
+                match self.get(&prefix) {
+                    Some(owner) if *owner != peer.public_key && !released.contains(owner) => {
+                        let message = format!(
+                            "{}/{} is already routed to peer {}",
+                            prefix.address,
+                            prefix.cidr,
+                            owner.to_base64()
+                        );
+                        match update.allowed_ip_conflicts {
+                            AllowedIpConflicts::Steal => {}
+                            AllowedIpConflicts::Error => return Err(invalid(message)),
+                            AllowedIpConflicts::Skip => {
+                                log::warn!("skipping allowed ip of peer #{}: {}", index, message);
+                                continue;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                self.insert(&prefix, peer.public_key.clone())
+                    .map_err(|e| invalid(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerConfigBuilder;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn prefix(s: &str) -> AllowedIp {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut table = CryptokeyTable::new();
+        table.insert(&prefix("10.0.0.0/8"), 1).unwrap();
+        table.insert(&prefix("10.1.2.3/16"), 2).unwrap();
+        table.insert(&prefix("0.0.0.0/0"), 3).unwrap();
+        table.insert(&prefix("fd00::/8"), 4).unwrap();
+        assert_eq!(table.lookup(&ip("10.1.7.7")), Some(&2));
+        assert_eq!(table.lookup(&ip("10.2.0.1")), Some(&1));
+        assert_eq!(table.lookup(&ip("192.0.2.1")), Some(&3));
+        assert_eq!(table.lookup(&ip("fd00::1")), Some(&4));
+        assert_eq!(table.lookup(&ip("2001:db8::1")), None);
+        assert_eq!(table.get(&prefix("10.1.0.0/16")), Some(&2));
+        assert!(table.insert(&prefix("10.0.0.0/33"), 5).is_err());
+
+        // The most recent owner of a prefix wins.
+        assert_eq!(table.insert(&prefix("10.1.0.0/16"), 1).unwrap(), Some(2));
+        assert_eq!(table.lookup(&ip("10.1.7.7")), Some(&1));
+        assert_eq!(table.remove(&prefix("10.1.0.0/16")), Some(1));
+        assert_eq!(table.remove_value(&1), 1);
+        assert_eq!(table.lookup(&ip("10.1.7.7")), Some(&3));
+        assert_eq!(
+            table.entries(),
+            vec![(prefix("0.0.0.0/0"), &3), (prefix("fd00::/8"), &4)]
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let (a, b) = (Key([1; 32]), Key([2; 32]));
+        let mut table = CryptokeyTable::new();
+        table
+            .apply(
+                &DeviceUpdate::new()
+                    .add_peer(PeerConfigBuilder::new(&a).add_allowed_ip(ip("10.0.0.1"), 32))
+                    .add_peer(PeerConfigBuilder::new(&b).add_allowed_ip(ip("10.0.0.2"), 32)),
+            )
+            .unwrap();
+        table
+            .apply(
+                &DeviceUpdate::new().add_peer(
+                    PeerConfigBuilder::new(&a)
+                        .replace_allowed_ips()
+                        .add_allowed_ip(ip("10.0.0.2"), 32)
+                        .add_allowed_ip(ip("10.0.1.0"), 24),
+                ),
+            )
+            .unwrap();
+        assert_eq!(table.lookup(&ip("10.0.0.1")), None);
+        assert_eq!(table.lookup(&ip("10.0.0.2")), Some(&a));
+        assert_eq!(
+            table.allowed_ips(&a),
+            vec![prefix("10.0.0.2/32"), prefix("10.0.1.0/24")]
+        );
+        assert!(table.allowed_ips(&b).is_empty());
+
+        table
+            .apply(&DeviceUpdate::new().remove_peer_by_key(&a))
+            .unwrap();
+        assert!(table.entries().is_empty());
+    }
+
+    #[test]
+    fn test_apply_conflicts() {
+        let (a, b) = (Key([1; 32]), Key([2; 32]));
+        let mut table = CryptokeyTable::new();
+        table.insert(&prefix("10.0.0.1/32"), a.clone()).unwrap();
+        let claim = |conflicts| {
+            DeviceUpdate::new()
+                .on_allowed_ip_conflict(conflicts)
+                .add_peer(
+                    PeerConfigBuilder::new(&b)
+                        .add_allowed_ip(ip("10.0.0.1"), 32)
+                        .add_allowed_ip(ip("10.0.0.2"), 32),
+                )
+        };
+
+        let error = table.apply(&claim(AllowedIpConflicts::Error)).unwrap_err();
+        assert_eq!(error.field, Some(ApplyField::AllowedIps));
+        assert_eq!(table.lookup(&ip("10.0.0.1")), Some(&a));
+
+        table.apply(&claim(AllowedIpConflicts::Skip)).unwrap();
+        assert_eq!(table.lookup(&ip("10.0.0.1")), Some(&a));
+        assert_eq!(table.lookup(&ip("10.0.0.2")), Some(&b));
+
+        // A peer replacing its allowed IPs gives them up.
+        let update = claim(AllowedIpConflicts::Error)
+            .add_peer(PeerConfigBuilder::new(&a).replace_allowed_ips());
+        table.apply(&update).unwrap();
+        assert_eq!(table.lookup(&ip("10.0.0.1")), Some(&b));
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut table = CryptokeyTable::new();
+        table.insert(&prefix("10.0.0.0/8"), 1).unwrap();
+        for round in 0..100u32 {
+            let host = AllowedIp::new(IpAddr::V4((0x0a00_0000 | round << 8).into()), 32);
+            table.insert(&host, 2).unwrap();
+            assert_eq!(table.remove(&host), Some(2));
+        }
+        // The /8 and the root, plus the free slots reused for each /32.
+        assert_eq!(table.v4.nodes.len() - table.v4.free.len(), 9);
+        assert!(table.v4.nodes.len() <= 33);
+
+        table.insert(&prefix("fd00::1/128"), 3).unwrap();
+        table.insert(&prefix("fd00::/8"), 4).unwrap();
+        assert_eq!(table.remove_value(&3), 1);
+        assert_eq!(table.v6.nodes.len(), 9);
+        assert_eq!(table.lookup(&ip("fd00::1")), Some(&4));
+    }
+}
@@ -1,17 +1,21 @@
 use libc::c_char;
 
-use crate::{backends, key::Key, Backend, KeyPair, PeerConfigBuilder};
-
-#[cfg(feature = "print")]
-use colored::Colorize;
+use crate::{
+    allowed_ips, backends,
+    clock::{Clock, SystemClock},
+    key::Key,
+    Backend, Error, KeyPair, PeerConfigBuilder,
+};
 
 use std::{
     borrow::Cow,
+    collections::HashMap,
     ffi::CStr,
     fmt, io,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
-    time::SystemTime,
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 /// Represents an IP address a peer is allowed to have, in CIDR notation.
@@ -24,9 +28,67 @@ pub struct AllowedIp {
 }
 
 impl AllowedIp {
+    /// Every IPv4 address (`0.0.0.0/0`).
+    pub const ALL_V4: AllowedIp = AllowedIp {
+        address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        cidr: 0,
+    };
+
+    /// Every IPv6 address (`::/0`).
+    pub const ALL_V6: AllowedIp = AllowedIp {
+        address: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        cidr: 0,
+    };
+
     pub fn new(address: IpAddr, cidr: u8) -> Self {
         Self { address, cidr }
     }
+
+    /// Whether `ip` falls within this prefix. Always `false` across address
+    /// families or for an invalid CIDR mask.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        if self.address.is_ipv4() != ip.is_ipv4() {
+            return false;
+        }
+        match (
+            allowed_ips::normalize(self),
+            allowed_ips::normalize(&AllowedIp::new(*ip, self.cidr)),
+        ) {
+            (Ok(network), Ok(other)) => network == other,
+            _ => false,
+        }
+    }
+
+    /// Computes the smallest set of prefixes covering the whole address family of
+    /// `excluded` except `excluded` itself.
+    ///
+    /// This is the usual way to route everything through a tunnel while keeping the
+    /// tunnel's own endpoint reachable over the regular default route, e.g.
+    /// excluding `1.2.3.4` yields `128.0.0.0/1`, `64.0.0.0/2`, ..., `1.2.3.5/32`.
+    pub fn default_route_excluding(excluded: IpAddr) -> Vec<AllowedIp> {
+        match excluded {
+            IpAddr::V4(addr) => {
+                let bits = u32::from(addr);
+                (1..=32u8)
+                    .map(|cidr| {
+                        let flipped = bits ^ (1 << (32 - cidr));
+                        let network = flipped & (u32::MAX << (32 - cidr));
+                        AllowedIp::new(IpAddr::V4(network.into()), cidr)
+                    })
+                    .collect()
+            }
+            IpAddr::V6(addr) => {
+                let bits = u128::from(addr);
+                (1..=128u8)
+                    .map(|cidr| {
+                        let flipped = bits ^ (1 << (128 - cidr as u32));
+                        let network = flipped & (u128::MAX << (128 - cidr as u32));
+                        AllowedIp::new(IpAddr::V6(network.into()), cidr)
+                    })
+                    .collect()
+            }
+        }
+    }
 }
 
 impl fmt::Debug for AllowedIp {
@@ -35,19 +97,38 @@ impl fmt::Debug for AllowedIp {
     }
 }
 
+/// Allowed IPs are (de)serialized as `address/cidr` strings.
+#[cfg(feature = "serde")]
+impl serde::Serialize for AllowedIp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{}/{}", self.address, self.cidr))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for AllowedIp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ip = String::deserialize(deserializer)?;
+        ip.parse()
+            .map_err(|()| serde::de::Error::custom(format!("invalid allowed ip {:?}", ip)))
+    }
+}
+
 impl FromStr for AllowedIp {
     type Err = ();
 
+    /// Parses `address/cidr`. A bare address is a single host, /32 or /128, as
+    /// `wg` reads it.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts: Vec<_> = s.split('/').collect();
-        if parts.len() != 2 {
-            return Err(());
-        }
-
-        Ok(AllowedIp {
-            address: parts[0].parse().map_err(|_| ())?,
-            cidr: parts[1].parse().map_err(|_| ())?,
-        })
+        let address: IpAddr = parts[0].parse().map_err(|_| ())?;
+        let cidr = match parts[1..] {
+            [] if address.is_ipv4() => 32,
+            [] => 128,
+            [cidr] => cidr.parse().map_err(|_| ())?,
+            _ => return Err(()),
+        };
+        Ok(AllowedIp { address, cidr })
     }
 }
 
@@ -55,27 +136,67 @@ impl FromStr for AllowedIp {
 ///
 /// These are the attributes that don't change over time and are part of the configuration.
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PeerConfig {
     /// The public key of the peer.
     pub public_key: Key,
     /// The preshared key available to both peers (`None` means no PSK is used).
+    /// Read when deserializing, but never serialized.
+    #[cfg_attr(feature = "serde", serde(skip_serializing, default))]
     pub preshared_key: Option<Key>,
     /// The endpoint this peer listens for connections on (`None` means any).
     pub endpoint: Option<SocketAddr>,
     /// The interval for sending keepalive packets (`None` means disabled).
     pub persistent_keepalive_interval: Option<u16>,
-    /// The IP addresses this peer is allowed to have.
+    /// The IP addresses this peer is allowed to have. Empty when deserializing
+    /// without them.
+    #[cfg_attr(feature = "serde", serde(default))]
     pub allowed_ips: Vec<AllowedIp>,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub(crate) __cant_construct_me: (),
 }
 
+/// (De)serializes an optional time as whole seconds since the Unix epoch.
+#[cfg(feature = "serde")]
+mod unix_seconds {
+    use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<SystemTime>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        time.map(|time| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        })
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<SystemTime>, D::Error> {
+        let Some(seconds) = Option::<u64>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        UNIX_EPOCH
+            .checked_add(Duration::from_secs(seconds))
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("time {} is out of range", seconds)))
+    }
+}
+
 /// Represents a single peer's current statistics (i.e. the data from the current session).
 ///
 /// These are the attributes that will change over time; to update them,
 /// re-read the information from the interface.
 #[derive(Debug, PartialEq, Eq, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PeerStats {
-    /// Time of the last handshake/rekey with this peer.
+    /// Time of the last handshake/rekey with this peer. Serialized as whole
+    /// seconds since the Unix epoch.
+    #[cfg_attr(feature = "serde", serde(with = "unix_seconds"))]
     pub last_handshake_time: Option<SystemTime>,
     /// Number of bytes received from this peer.
     pub rx_bytes: u64,
@@ -83,16 +204,269 @@ pub struct PeerStats {
     pub tx_bytes: u64,
 }
 
+impl PeerStats {
+    /// How long ago the last handshake was according to `clock`, or `None` if there
+    /// was none. Linux reports the Unix epoch for peers that never handshaked.
+    pub fn handshake_age(&self, clock: &dyn Clock) -> Option<Duration> {
+        self.last_handshake_time
+            .filter(|&time| time > SystemTime::UNIX_EPOCH)
+            .map(|time| clock.since(time))
+    }
+
+    /// The statistics of every peer of `iface`, by public key.
+    ///
+    /// The kernel, userspace and `wg` backends skip converting the allowed IPs and
+    /// the rest of the configuration, which dominates reading large devices, so
+    /// this suits scrapers that already have the configuration cached.
+    pub fn get_all(
+        iface: &InterfaceName,
+        backend: Backend,
+    ) -> Result<Vec<(Key, PeerStats)>, Error> {
+        let stats = match backend {
+            #[cfg(target_os = "linux")]
+            Backend::Kernel => backends::kernel::get_stats(iface),
+            #[cfg(unix)]
+            Backend::Userspace => backends::userspace::get_stats(iface),
+            #[cfg(not(unix))]
+            Backend::Userspace => Err(backends::userspace_unsupported()),
+            Backend::Cli => backends::cli::get_stats(iface),
+            #[cfg(windows)]
+            Backend::Windows => Self::from_device(iface, backend),
+            #[cfg(feature = "mock")]
+            Backend::Mock => Self::from_device(iface, backend),
+        };
+        stats.map_err(|e| Error::from_backend(e, backend, Some(iface)))
+    }
+
+    #[cfg(any(windows, feature = "mock"))]
+    fn from_device(iface: &InterfaceName, backend: Backend) -> io::Result<Vec<(Key, PeerStats)>> {
+        Ok(Device::get(iface, backend)?
+            .peers
+            .into_iter()
+            .map(|peer| (peer.config.public_key, peer.stats))
+            .collect())
+    }
+}
+
 /// Represents the complete status of a peer.
 ///
 /// This struct simply combines [`PeerInfo`](PeerInfo) and [`PeerStats`](PeerStats)
 /// to represent all available information about a peer.
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PeerInfo {
     pub config: PeerConfig,
     pub stats: PeerStats,
 }
 
+/// The parts of the `wg show` layout a [`ShowStyle`] can decorate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Part {
+    /// The `interface: <name>` heading.
+    Interface,
+    /// The `peer: <key>` heading.
+    Peer,
+    /// A field name, e.g. `listen port`.
+    Label,
+    /// A unit, e.g. `seconds` or the `/` of a prefix.
+    Unit,
+}
+
+/// How [`Device::write_wg_show`] decorates the layout. The defaults are the plain
+/// text of the `Display` impls; the `print` feature's
+/// [`RenderOptions`](crate::render::RenderOptions) adds colors, keys and names.
+pub(crate) trait ShowStyle {
+    fn paint(&self, text: &str, _part: Part) -> String {
+        text.to_string()
+    }
+
+    /// What's shown for the private and preshared keys.
+    fn secret(&self, _key: &Key) -> String {
+        "(hidden)".to_string()
+    }
+
+    /// A name shown below the peer's key.
+    fn peer_name(&self, _key: &Key) -> Option<&str> {
+        None
+    }
+
+    /// Formats a byte count like `wg show`: plain bytes below 1 KiB, otherwise
+    /// scaled to two decimals.
+    fn bytes(&self, bytes: u64) -> String {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+        if bytes < 1024 {
+            return format!("{} {}", bytes, self.paint("B", Part::Unit));
+        }
+        let mut value = bytes as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        format!("{:.2} {}", value, self.paint(UNITS[unit], Part::Unit))
+    }
+}
+
+/// The plain text layout.
+struct Plain;
+
+impl ShowStyle for Plain {}
+
+/// Formats `seconds` like `wg show`: `1 day, 2 hours, 5 seconds`.
+fn write_duration(out: &mut dyn fmt::Write, seconds: u64, style: &dyn ShowStyle) -> fmt::Result {
+    const UNITS: [(&str, u64); 5] = [
+        ("year", 365 * 24 * 60 * 60),
+        ("day", 24 * 60 * 60),
+        ("hour", 60 * 60),
+        ("minute", 60),
+        ("second", 1),
+    ];
+    let mut remaining = seconds;
+    let mut first = true;
+    for (unit, length) in UNITS {
+        let count = remaining / length;
+        remaining %= length;
+        if count == 0 {
+            continue;
+        }
+        let separator = if first { "" } else { ", " };
+        let plural = if count == 1 { "" } else { "s" };
+        let unit = style.paint(&format!("{}{}", unit, plural), Part::Unit);
+        write!(out, "{}{} {}", separator, count, unit)?;
+        first = false;
+    }
+    Ok(())
+}
+
+impl PeerInfo {
+    /// Writes the peer in the `wg show` layout, decorated by `style`, with the
+    /// handshake age measured against `clock`.
+    fn write_wg_show(
+        &self,
+        out: &mut dyn fmt::Write,
+        clock: &dyn Clock,
+        style: &dyn ShowStyle,
+    ) -> fmt::Result {
+        let label = |text| style.paint(text, Part::Label);
+        let public_key = self.config.public_key.to_base64();
+        writeln!(
+            out,
+            "{}: {}",
+            style.paint("peer", Part::Peer),
+            style.paint(&public_key, Part::Peer)
+        )?;
+        if let Some(name) = style.peer_name(&self.config.public_key) {
+            writeln!(out, "  {}: {}", label("name"), name)?;
+        }
+        if let Some(preshared_key) = &self.config.preshared_key {
+            writeln!(
+                out,
+                "  {}: {}",
+                label("preshared key"),
+                style.secret(preshared_key)
+            )?;
+        }
+        if let Some(endpoint) = self.config.endpoint {
+            writeln!(out, "  {}: {}", label("endpoint"), endpoint)?;
+        }
+        write!(out, "  {}: ", label("allowed ips"))?;
+        if self.config.allowed_ips.is_empty() {
+            write!(out, "(none)")?;
+        }
+        for (i, ip) in self.config.allowed_ips.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            let slash = style.paint("/", Part::Unit);
+            write!(out, "{}{}{}{}", separator, ip.address, slash, ip.cidr)?;
+        }
+        writeln!(out)?;
+        if let Some(age) = self.stats.handshake_age(clock) {
+            write!(out, "  {}: ", label("latest handshake"))?;
+            if age.as_secs() == 0 {
+                writeln!(out, "Now")?;
+            } else {
+                write_duration(out, age.as_secs(), style)?;
+                writeln!(out, " ago")?;
+            }
+        }
+        if self.stats.rx_bytes > 0 || self.stats.tx_bytes > 0 {
+            writeln!(
+                out,
+                "  {}: {} received, {} sent",
+                label("transfer"),
+                style.bytes(self.stats.rx_bytes),
+                style.bytes(self.stats.tx_bytes)
+            )?;
+        }
+        if let Some(interval) = self.config.persistent_keepalive_interval.filter(|i| *i > 0) {
+            write!(out, "  {}: every ", label("persistent keepalive"))?;
+            write_duration(out, interval.into(), style)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `wg show` layout of the peer, in plain text. Unlike
+/// [`Device::print`], this needs no features and never emits colors.
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_wg_show(f, &SystemClock, &Plain)
+    }
+}
+
+/// What to look a peer up by in [`Device::find_peer`] and [`Device::find_peer_global`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerQuery {
+    /// The peer's public key.
+    PublicKey(Key),
+    /// The peer's exact endpoint (address and port).
+    Endpoint(SocketAddr),
+    /// Any peer whose endpoint has this address, whatever the port.
+    EndpointIp(IpAddr),
+    /// The peer whose allowed IPs route this address. The longest matching prefix
+    /// wins, as it does in the kernel.
+    AllowedIp(IpAddr),
+}
+
+impl From<Key> for PeerQuery {
+    fn from(key: Key) -> Self {
+        Self::PublicKey(key)
+    }
+}
+
+impl From<SocketAddr> for PeerQuery {
+    fn from(endpoint: SocketAddr) -> Self {
+        Self::Endpoint(endpoint)
+    }
+}
+
+impl PeerQuery {
+    /// How well `peer` matches: `None` if it doesn't, otherwise a score where
+    /// higher is better (the prefix length for [`PeerQuery::AllowedIp`]).
+    fn score(&self, peer: &PeerInfo) -> Option<u8> {
+        match self {
+            Self::PublicKey(key) => (peer.config.public_key == *key).then_some(0),
+            Self::Endpoint(endpoint) => (peer.config.endpoint == Some(*endpoint)).then_some(0),
+            Self::EndpointIp(ip) => peer
+                .config
+                .endpoint
+                .is_some_and(|endpoint| endpoint.ip() == *ip)
+                .then_some(0),
+            Self::AllowedIp(ip) => peer
+                .config
+                .allowed_ips
+                .iter()
+                .filter(|allowed_ip| allowed_ip.contains(ip))
+                .map(|allowed_ip| allowed_ip.cidr)
+                .max(),
+        }
+    }
+
+    fn is_exact(&self) -> bool {
+        !matches!(self, Self::AllowedIp(_))
+    }
+}
+
 /// Represents all available information about a WireGuard device (interface).
 ///
 /// This struct contains the current configuration of the device
@@ -100,12 +474,15 @@ pub struct PeerInfo {
 /// The peer statistics are retrieved once at construction time,
 /// and need to be updated manually by calling [`get_by_name`](DeviceInfo::get_by_name).
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Device {
     /// The interface name of this device
     pub name: InterfaceName,
     /// The public encryption key of this interface (if present)
     pub public_key: Option<Key>,
-    /// The private encryption key of this interface (if present)
+    /// The private encryption key of this interface (if present). Read when
+    /// deserializing, but never serialized.
+    #[cfg_attr(feature = "serde", serde(skip_serializing, default))]
     pub private_key: Option<Key>,
     /// The [fwmark](https://www.linux.org/docs/man8/tc-fw.html) of this interface
     pub fwmark: Option<u32>,
@@ -118,13 +495,73 @@ pub struct Device {
     /// The backend the device exists on (userspace or kernel).
     pub backend: Backend,
 
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub(crate) __cant_construct_me: (),
 }
 
-type RawInterfaceName = [c_char; libc::IFNAMSIZ];
+impl Device {
+    /// Writes the device in the `wg show` layout, decorated by `style`, with
+    /// handshake ages measured against `clock`. Like `wg show`, peers are listed
+    /// from the most recent handshake to those that never had one.
+    pub(crate) fn write_wg_show(
+        &self,
+        out: &mut dyn fmt::Write,
+        clock: &dyn Clock,
+        style: &dyn ShowStyle,
+    ) -> fmt::Result {
+        let label = |text| style.paint(text, Part::Label);
+        let name = self.name.as_str_lossy();
+        writeln!(
+            out,
+            "{}: {}",
+            style.paint("interface", Part::Interface),
+            style.paint(&name, Part::Interface)
+        )?;
+        if let Some(public_key) = &self.public_key {
+            writeln!(out, "  {}: {}", label("public key"), public_key.to_base64())?;
+        }
+        if let Some(private_key) = &self.private_key {
+            writeln!(
+                out,
+                "  {}: {}",
+                label("private key"),
+                style.secret(private_key)
+            )?;
+        }
+        if let Some(listen_port) = self.listen_port {
+            writeln!(out, "  {}: {}", label("listen port"), listen_port)?;
+        }
+        if let Some(fwmark) = self.fwmark.filter(|fwmark| *fwmark != 0) {
+            writeln!(out, "  {}: {:#x}", label("fwmark"), fwmark)?;
+        }
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.stats.last_handshake_time));
+        for peer in peers {
+            writeln!(out)?;
+            peer.write_wg_show(out, clock, style)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `wg show` layout of the device and its peers, in plain text. Unlike
+/// [`Device::print`], this needs no features and never emits colors.
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_wg_show(f, &SystemClock, &Plain)
+    }
+}
+
+#[cfg(unix)]
+const IFNAMSIZ: usize = libc::IFNAMSIZ;
+/// WireGuardNT's `MAX_ADAPTER_NAME`.
+#[cfg(windows)]
+const IFNAMSIZ: usize = 128;
+
+type RawInterfaceName = [c_char; IFNAMSIZ];
 
 /// The name of a Wireguard interface device.
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub struct InterfaceName(RawInterfaceName);
 
 impl FromStr for InterfaceName {
@@ -140,11 +577,11 @@ impl FromStr for InterfaceName {
         }
 
         // Ensure its short enough to include a trailing NUL
-        if len > (libc::IFNAMSIZ - 1) {
+        if len > (IFNAMSIZ - 1) {
             return Err(InvalidInterfaceName::TooLong);
         }
 
-        let mut buf = [c_char::default(); libc::IFNAMSIZ];
+        let mut buf = [c_char::default(); IFNAMSIZ];
         // Check for interior NULs and other invalid characters.
         for (out, b) in buf.iter_mut().zip(name.as_bytes().iter()) {
             if *b == 0 || *b == b'/' || b.is_ascii_whitespace() {
@@ -185,6 +622,21 @@ impl fmt::Display for InterfaceName {
     }
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for InterfaceName {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.as_str_lossy())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for InterfaceName {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 /// An interface name was bad.
 #[derive(Debug, PartialEq, Eq)]
 pub enum InvalidInterfaceName {
@@ -206,7 +658,7 @@ impl fmt::Display for InvalidInterfaceName {
             Self::TooLong => write!(
                 f,
                 "interface name longer than system max of {} chars",
-                libc::IFNAMSIZ
+                IFNAMSIZ
             ),
             Self::Empty => f.write_str("an empty interface name was provided"),
             Self::InvalidChars => f.write_str("interface name contained slash or space characters"),
@@ -228,196 +680,252 @@ impl Device {
     ///
     /// You can use [`get_by_name`](DeviceInfo::get_by_name) to retrieve more
     /// detailed information on each interface.
-    pub fn list(backend: Backend) -> Result<Vec<InterfaceName>, io::Error> {
-        match backend {
+    pub fn list(backend: Backend) -> Result<Vec<InterfaceName>, Error> {
+        let names = match backend {
             #[cfg(target_os = "linux")]
             Backend::Kernel => backends::kernel::enumerate(),
+            #[cfg(unix)]
             Backend::Userspace => backends::userspace::enumerate(),
-        }
+            #[cfg(not(unix))]
+            Backend::Userspace => Err(backends::userspace_unsupported()),
+            Backend::Cli => backends::cli::enumerate(),
+            #[cfg(windows)]
+            Backend::Windows => backends::wireguard_nt::enumerate(),
+            #[cfg(feature = "mock")]
+            Backend::Mock => backends::mock::enumerate(),
+        };
+        names.map_err(|e| Error::from_backend(e, backend, None))
     }
 
-    pub fn get(name: &InterfaceName, backend: Backend) -> Result<Self, io::Error> {
-        match backend {
+    pub fn get(name: &InterfaceName, backend: Backend) -> Result<Self, Error> {
+        let device = match backend {
             #[cfg(target_os = "linux")]
             Backend::Kernel => backends::kernel::get_by_name(name),
+            #[cfg(unix)]
             Backend::Userspace => backends::userspace::get_by_name(name),
-        }
+            #[cfg(not(unix))]
+            Backend::Userspace => Err(backends::userspace_unsupported()),
+            Backend::Cli => backends::cli::get_by_name(name),
+            #[cfg(windows)]
+            Backend::Windows => backends::wireguard_nt::get_by_name(name),
+            #[cfg(feature = "mock")]
+            Backend::Mock => backends::mock::get_by_name(name),
+        };
+        device.map_err(|e| Error::from_backend(e, backend, Some(name)))
     }
 
-    #[cfg(feature = "print")]
-    pub fn print(&self) -> Result<(), std::time::SystemTimeError> {
-        println!(
-            "{}: {}",
-            "interface".green(),
-            self.name.as_str_lossy().green()
-        );
-        if let Some(public_key) = &self.public_key {
-            println!(
-                "  {}: {}",
-                "public key".white().bold(),
-                public_key.to_base64()
-            );
-        }
-
-        if let Some(_private_key) = &self.public_key {
-            println!("  {}: {}", "private key".white().bold(), "(hidden)");
-        }
+    /// Re-reads the statistics of the peers from the backend and updates them in
+    /// place, leaving the configuration as it was read.
+    ///
+    /// Returns `false` if peers were added or removed since: the added ones aren't
+    /// picked up and the removed ones keep their last statistics, so callers
+    /// wanting the current peers should [`get`](Self::get) the device again.
+    pub fn refresh(&mut self) -> Result<bool, Error> {
+        let stats = PeerStats::get_all(&self.name, self.backend)?;
+        Ok(self.update_stats(stats))
+    }
 
-        if let Some(listen_port) = self.listen_port {
-            println!("  {}: {}", "listen port".white().bold(), listen_port);
+    /// Takes the first statistics of each key, in case a backend reports a peer
+    /// more than once.
+    fn update_stats(&mut self, stats: Vec<(Key, PeerStats)>) -> bool {
+        let mut by_key = HashMap::with_capacity(stats.len());
+        for (key, stats) in stats {
+            by_key.entry(key).or_insert(stats);
         }
-
-        for peer in &self.peers {
-            println!();
-            Self::print_peer(peer)?;
+        let count = by_key.len();
+        let mut found = 0;
+        for peer in &mut self.peers {
+            if let Some(stats) = by_key.remove(&peer.config.public_key) {
+                peer.stats = stats;
+                found += 1;
+            }
         }
-
-        Ok(())
+        found == self.peers.len() && found == count
     }
 
-    #[cfg(feature = "print")]
-    fn print_peer(peer: &PeerInfo) -> Result<(), std::time::SystemTimeError> {
-        println!(
-            "{}: {}",
-            "peer".yellow(),
-            peer.config.public_key.to_base64().as_str().yellow()
-        );
-
-        if let Some(_preshare_key) = &peer.config.preshared_key {
-            println!("  {}: {}", "preshared key".white().bold(), "(hidden)");
-        }
-        if let Some(endpoint) = peer.config.endpoint {
-            println!("  {}: {}", "endpoint".white().bold(), endpoint);
-        }
+    /// Blocks until the peer with `public_key` on `iface` has handshaken, e.g. to
+    /// confirm a newly provisioned client connected, and returns when it did.
+    ///
+    /// The interface is read after 50ms, then at twice the interval each time up
+    /// to once a second. Fails with an [`Error::Io`] of kind
+    /// [`TimedOut`](io::ErrorKind::TimedOut) after `timeout`, or of kind
+    /// [`NotFound`](io::ErrorKind::NotFound) if the interface doesn't have the peer.
+    pub fn wait_for_handshake(
+        iface: &InterfaceName,
+        public_key: &Key,
+        timeout: Duration,
+        backend: Backend,
+    ) -> Result<SystemTime, Error> {
+        Self::wait_for_handshake_since(iface, public_key, SystemTime::UNIX_EPOCH, timeout, backend)
+    }
 
-        if !peer.config.allowed_ips.is_empty() {
-            print!("  {}: ", "allowed ips".white().bold());
-            for (i, allowed_ip) in peer.config.allowed_ips.iter().enumerate() {
-                print!("{}{}{}", allowed_ip.address, "/".cyan(), allowed_ip.cidr);
-                if i < peer.config.allowed_ips.len() - 1 {
-                    print!(", ");
-                } else {
-                    println!()
-                }
+    /// Like [`wait_for_handshake`](Self::wait_for_handshake), ignoring handshakes
+    /// before `since`.
+    pub(crate) fn wait_for_handshake_since(
+        iface: &InterfaceName,
+        public_key: &Key,
+        since: SystemTime,
+        timeout: Duration,
+        backend: Backend,
+    ) -> Result<SystemTime, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut interval = Duration::from_millis(50);
+        loop {
+            let (_, stats) = PeerStats::get_all(iface, backend)?
+                .into_iter()
+                .find(|(key, _)| key == public_key)
+                .ok_or_else(|| {
+                    Error::Io(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no such peer on {}", iface),
+                    ))
+                })?;
+            // Linux reports the Unix epoch for peers that never handshaked.
+            let handshake = stats
+                .last_handshake_time
+                .filter(|&time| time > SystemTime::UNIX_EPOCH && time >= since);
+            if let Some(time) = handshake {
+                return Ok(time);
             }
-        }
-
-        if let Some(keepalive) = peer.config.persistent_keepalive_interval {
-            if keepalive > 0 {
-                println!(
-                    "  {}: every {} {}",
-                    "persistent keepalive".white().bold(),
-                    keepalive,
-                    "seconds".cyan()
-                )
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no handshake with peer on {}", iface),
+                )));
             }
+            thread::sleep(interval.min(deadline - now));
+            interval = (interval * 2).min(Duration::from_secs(1));
         }
+    }
+
+    /// Finds the peer of this device matching `query`, scanning every peer. See
+    /// [`index`](Self::index) for doing many lookups.
+    pub fn find_peer(&self, query: impl Into<PeerQuery>) -> Option<&PeerInfo> {
+        let query = query.into();
+        self.peers
+            .iter()
+            .filter_map(|peer| query.score(peer).map(|score| (score, peer)))
+            .max_by_key(|(score, _)| *score)
+            .map(|(_, peer)| peer)
+    }
 
-        if let Some(latest_handshake) = &peer.stats.last_handshake_time {
-            // latest handshake may be 0 on Linux devices
-            let timestamp = latest_handshake
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("Time went backwards");
-            if timestamp.as_secs() > 0 {
-                println!(
-                    "  {}: {}",
-                    "latest handshake".white().bold(),
-                    Self::calculate_time(latest_handshake)?
-                );
+    /// Searches every interface on `backend` for a peer matching `query`, e.g. to
+    /// find which interface a client belongs to when only its public key is known.
+    ///
+    /// The search stops at the first match, except for [`PeerQuery::AllowedIp`]
+    /// which returns the longest matching prefix across all interfaces. Interfaces
+    /// that disappear while searching are skipped.
+    pub fn find_peer_global(
+        query: impl Into<PeerQuery>,
+        backend: Backend,
+    ) -> Result<Option<(InterfaceName, PeerInfo)>, Error> {
+        let query = query.into();
+        let mut best: Option<(u8, InterfaceName, PeerInfo)> = None;
+        for name in Self::list(backend)? {
+            let device = match Self::get(&name, backend) {
+                Ok(device) => device,
+                Err(Error::InterfaceNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            let found = device
+                .peers
+                .into_iter()
+                .filter_map(|peer| query.score(&peer).map(|score| (score, peer)))
+                .max_by_key(|(score, _)| *score);
+            if let Some((score, peer)) = found {
+                if query.is_exact() {
+                    return Ok(Some((name, peer)));
+                }
+                if best
+                    .as_ref()
+                    .is_none_or(|(best_score, ..)| score > *best_score)
+                {
+                    best = Some((score, name, peer));
+                }
             }
         }
+        Ok(best.map(|(_, name, peer)| (name, peer)))
+    }
 
-        if peer.stats.tx_bytes > 0 || peer.stats.rx_bytes > 0 {
-            use byte_unit::Byte;
-
-            let rx_byte = Byte::from(peer.stats.rx_bytes).get_appropriate_unit(true);
-
-            let tx_byte = Byte::from(peer.stats.tx_bytes).get_appropriate_unit(true);
-            println!(
-                "  {}: {} {}, {} {}",
-                "transfer".white().bold(),
-                format!(
-                    "{:.2} {}",
-                    rx_byte.get_value(),
-                    rx_byte.get_unit().to_string().cyan()
-                ),
-                "received",
-                format!(
-                    "{:.2} {}",
-                    tx_byte.get_value(),
-                    tx_byte.get_unit().to_string().cyan()
-                ),
-                "sent"
-            );
-        }
+    /// Prints the device and its peers to stdout in the `wg show` style layout.
+    ///
+    /// See [`render::human`](crate::render::human) to get the output as a `String`.
+    #[cfg(feature = "print")]
+    pub fn print(&self) -> Result<(), std::time::SystemTimeError> {
+        let options = crate::render::RenderOptions {
+            color: crate::render::color_from_env(),
+            ..Default::default()
+        };
+        // Like `print!`, panics if stdout can't be written to.
+        self.print_with(&mut io::stdout().lock(), &options)
+            .expect("failed printing to stdout");
         Ok(())
     }
 
+    /// Writes what [`print`](Self::print) prints to `writer`, e.g. a log file or
+    /// a buffer for a TUI pane, without colors; use
+    /// [`print_with`](Self::print_with) to turn them on.
     #[cfg(feature = "print")]
-    fn calculate_time(latest_handshake: &SystemTime) -> Result<String, std::time::SystemTimeError> {
-        // Convert 100000 seconds to specific year, month, day, hour, minute, second
-        let mut seconds = SystemTime::now()
-            .duration_since(*latest_handshake)?
-            .as_secs();
-        let years: u64;
-        let months: u64;
-        let days: u64;
-        let hours: u64;
-        let minutes: u64;
-        // Calculate the number of years used
-        years = seconds / 31_536_000; // 365 * 86400
-        seconds %= 31_536_000;
-
-        // Calculate the number of months used
-        months = seconds / 2_628_000; // 30 * 86400
-        seconds %= 2_628_000;
-
-        // Calculate the number of days used
-        days = seconds / 86_400; // 86400
-        seconds %= 86_400;
-
-        // Calculate hours used
-        hours = seconds / 3_600; // 3600
-        seconds %= 3_600;
-
-        // Calculate the number of minutes used
-        minutes = seconds / 60;
-        seconds %= 60;
-
-        let mut format_time = String::new();
-        if years > 0 {
-            format_time.push_str(&format!(" {} {},", years, "years".cyan()));
-        }
-
-        if months > 0 {
-            format_time.push_str(&format!(" {} {},", months, "months".cyan()));
-        }
-
-        if days > 0 {
-            format_time.push_str(&format!(" {} {},", days, "days".cyan()));
-        }
+    pub fn print_to(&self, writer: &mut impl io::Write) -> io::Result<()> {
+        self.print_with(writer, &crate::render::RenderOptions::default())
+    }
 
-        if hours > 0 {
-            format_time.push_str(&format!(" {} {},", hours, "hours".cyan()));
-        }
+    /// Like [`print_to`](Self::print_to), with colors and the display of keys
+    /// controlled by `options`.
+    #[cfg(feature = "print")]
+    pub fn print_with(
+        &self,
+        writer: &mut impl io::Write,
+        options: &crate::render::RenderOptions,
+    ) -> io::Result<()> {
+        writer.write_all(crate::render::human_with_clock(self, options, &SystemClock).as_bytes())
+    }
 
-        if minutes > 0 {
-            format_time.push_str(&format!(" {} {},", minutes, "minutes".cyan()));
-        }
+    /// The device and its peers as a single-line JSON object for monitoring agents
+    /// and scripts.
+    ///
+    /// The layout is stable: fields are only ever added. Unset values are `null`,
+    /// times are in seconds since the Unix epoch, byte counts are raw, and the
+    /// private and preshared keys are never included, only whether the latter is
+    /// set:
+    ///
+    /// ```json
+    /// {"interface":"wg0","backend":"kernel","public_key":"...","listen_port":51820,"fwmark":null,
+    ///  "peers":[{"public_key":"...","preshared_key":false,"endpoint":"192.0.2.1:51820",
+    ///  "allowed_ips":["10.0.0.2/32"],"persistent_keepalive_interval":25,
+    ///  "last_handshake_time":1700000000,"rx_bytes":2048,"tx_bytes":512}]}
+    /// ```
+    pub fn to_json(&self) -> String {
+        crate::json::device(self)
+    }
 
-        if seconds > 0 {
-            format_time.push_str(&format!(" {} {}", seconds, "seconds ago".cyan()));
-        }
+    /// Prints [`to_json`](Self::to_json) to stdout, followed by a newline.
+    pub fn print_json(&self) {
+        self.print_json_to(&mut io::stdout().lock())
+            .expect("failed printing to stdout");
+    }
 
-        Ok(format_time)
+    /// Writes what [`print_json`](Self::print_json) prints to `writer`.
+    pub fn print_json_to(&self, writer: &mut impl io::Write) -> io::Result<()> {
+        writeln!(writer, "{}", self.to_json())
     }
 
-    pub fn delete(self) -> io::Result<()> {
-        match self.backend {
+    pub fn delete(self) -> Result<(), Error> {
+        let deleted = match self.backend {
             #[cfg(target_os = "linux")]
             Backend::Kernel => backends::kernel::delete_interface(&self.name),
+            #[cfg(unix)]
             Backend::Userspace => backends::userspace::delete_interface(&self.name),
-        }
+            #[cfg(not(unix))]
+            Backend::Userspace => Err(backends::userspace_unsupported()),
+            Backend::Cli => backends::cli::delete_interface(&self.name),
+            #[cfg(windows)]
+            Backend::Windows => backends::wireguard_nt::delete_interface(&self.name),
+            #[cfg(feature = "mock")]
+            Backend::Mock => backends::mock::delete_interface(&self.name),
+        };
+        deleted.map_err(|e| Error::from_backend(e, self.backend, Some(&self.name)))
     }
 }
 
@@ -463,6 +971,43 @@ pub struct DeviceUpdate {
     pub(crate) listen_port: Option<u16>,
     pub(crate) peers: Vec<PeerConfigBuilder>,
     pub(crate) replace_peers: bool,
+    pub(crate) duplicate_peers: DuplicatePeers,
+    pub(crate) allowed_ip_conflicts: AllowedIpConflicts,
+}
+
+/// What applying a [`DeviceUpdate`] does with several peers of the same public key.
+///
+/// The resulting peer takes the place of the first one in the update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePeers {
+    /// Combine them as if they were applied one after another: later endpoints,
+    /// keepalives and preshared keys override earlier ones, and allowed IPs
+    /// accumulate unless a later peer replaces them. A removal overrides
+    /// everything before it, and a peer added after it is added anew after the
+    /// removal, without any of the old peer's settings.
+    #[default]
+    Merge,
+    /// Keep only the last one.
+    LastWins,
+    /// Reject the update.
+    Error,
+}
+
+/// What applying a [`DeviceUpdate`] does with an allowed IP that another peer
+/// already has, either on the interface or earlier in the update.
+///
+/// Only exact prefixes conflict: a more specific prefix on another peer just takes
+/// precedence for its addresses. Peers the update removes or whose allowed IPs it
+/// replaces give up their prefixes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllowedIpConflicts {
+    /// Move the prefix to the new peer, as the backends do.
+    #[default]
+    Steal,
+    /// Reject the update.
+    Error,
+    /// Leave the prefix with its current peer and apply the rest of the update.
+    Skip,
 }
 
 impl DeviceUpdate {
@@ -476,6 +1021,8 @@ impl DeviceUpdate {
             listen_port: None,
             peers: vec![],
             replace_peers: false,
+            duplicate_peers: DuplicatePeers::default(),
+            allowed_ip_conflicts: AllowedIpConflicts::default(),
         }
     }
 
@@ -580,12 +1127,32 @@ impl DeviceUpdate {
 
     /// Specifies that the peer configurations in this `DeviceConfigBuilder` should
     /// replace the existing configurations on the interface, not modify or append to them.
+    ///
+    /// Applying resets the sessions of every peer; [`sync`](DeviceUpdate::sync)
+    /// only touches the peers that change.
     #[must_use]
     pub fn replace_peers(mut self) -> Self {
         self.replace_peers = true;
         self
     }
 
+    /// Specifies how peers added more than once are handled, [merging](DuplicatePeers::Merge)
+    /// them by default.
+    #[must_use]
+    pub fn on_duplicate_peers(mut self, policy: DuplicatePeers) -> Self {
+        self.duplicate_peers = policy;
+        self
+    }
+
+    /// Specifies how allowed IPs already routed to another peer are handled,
+    /// [stealing](AllowedIpConflicts::Steal) them by default. Other policies read
+    /// the interface before applying the update.
+    #[must_use]
+    pub fn on_allowed_ip_conflict(mut self, policy: AllowedIpConflicts) -> Self {
+        self.allowed_ip_conflicts = policy;
+        self
+    }
+
     /// Specifies that the peer with this public key should be removed from the interface.
     #[must_use]
     pub fn remove_peer_by_key(self, public_key: &Key) -> Self {
@@ -597,12 +1164,47 @@ impl DeviceUpdate {
     /// Build and apply the configuration to a WireGuard interface by name.
     ///
     /// An interface with the provided name will be created if one does not exist already.
-    pub fn apply(self, iface: &InterfaceName, backend: Backend) -> io::Result<()> {
-        match backend {
-            #[cfg(target_os = "linux")]
-            Backend::Kernel => backends::kernel::apply(&self, iface),
-            Backend::Userspace => backends::userspace::apply(&self, iface),
+    ///
+    /// Peers added more than once and allowed IPs of other peers are handled as set
+    /// by [`on_duplicate_peers`](DeviceUpdate::on_duplicate_peers) and
+    /// [`on_allowed_ip_conflict`](DeviceUpdate::on_allowed_ip_conflict).
+    pub fn apply(self, iface: &InterfaceName, backend: Backend) -> Result<(), Error> {
+        let mut update = self.resolve_duplicate_peers()?;
+        if update.allowed_ip_conflicts != AllowedIpConflicts::Steal {
+            let current = if Device::list(backend)?.contains(iface) {
+                Some(Device::get(iface, backend)?)
+            } else {
+                None
+            };
+            update = update.resolve_allowed_ip_conflicts(current.as_ref())?;
         }
+        let applied = match backend {
+            #[cfg(target_os = "linux")]
+            Backend::Kernel => backends::kernel::apply(&update, iface),
+            #[cfg(unix)]
+            Backend::Userspace => backends::userspace::apply(&update, iface),
+            #[cfg(not(unix))]
+            Backend::Userspace => Err(backends::userspace_unsupported()),
+            Backend::Cli => backends::cli::apply(&update, iface),
+            #[cfg(windows)]
+            Backend::Windows => backends::wireguard_nt::apply(&update, iface),
+            #[cfg(feature = "mock")]
+            Backend::Mock => backends::mock::apply(&update, iface),
+        };
+        applied.map_err(|e| Error::from_backend(e, backend, Some(iface)))
+    }
+
+    /// Applies the update like [`apply`](Self::apply), then reads the interface
+    /// with [`Device::get`].
+    ///
+    /// This only saves the second call; it isn't atomic. The backends don't
+    /// report what they applied, so the listen port picked for a
+    /// [random](Self::randomize_listen_port) one, the
+    /// [`linked_name`](Device::linked_name) and the peers are whatever the
+    /// interface has when it's read, including changes made by others in between.
+    pub fn apply_and_get(self, iface: &InterfaceName, backend: Backend) -> Result<Device, Error> {
+        self.apply(iface, backend)?;
+        Device::get(iface, backend)
     }
 }
 
@@ -611,3 +1213,337 @@ impl Default for DeviceUpdate {
         Self::new()
     }
 }
+
+/// Fixtures for the tests of the crate, which build devices by hand.
+#[cfg(test)]
+impl PeerInfo {
+    /// A peer with `public_key` and nothing else set.
+    pub(crate) fn fixture(public_key: Key) -> Self {
+        Self {
+            config: PeerConfig {
+                public_key,
+                preshared_key: None,
+                endpoint: None,
+                persistent_keepalive_interval: None,
+                allowed_ips: vec![],
+                __cant_construct_me: (),
+            },
+            stats: PeerStats::default(),
+        }
+    }
+
+    pub(crate) fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.config.endpoint = Some(endpoint.parse().unwrap());
+        self
+    }
+
+    pub(crate) fn with_allowed_ips(mut self, allowed_ips: &[&str]) -> Self {
+        self.config.allowed_ips = allowed_ips.iter().map(|ip| ip.parse().unwrap()).collect();
+        self
+    }
+
+    pub(crate) fn with_handshake(mut self, time: SystemTime) -> Self {
+        self.stats.last_handshake_time = Some(time);
+        self
+    }
+
+    pub(crate) fn with_transfer(mut self, rx_bytes: u64, tx_bytes: u64) -> Self {
+        self.stats.rx_bytes = rx_bytes;
+        self.stats.tx_bytes = tx_bytes;
+        self
+    }
+}
+
+#[cfg(test)]
+impl Device {
+    /// A userspace device named `name` with `peers` and nothing else set. Other
+    /// fields can be set with `..Device::fixture(..)`.
+    pub(crate) fn fixture(name: &str, peers: Vec<PeerInfo>) -> Self {
+        Self {
+            name: name.parse().unwrap(),
+            public_key: None,
+            private_key: None,
+            fwmark: None,
+            listen_port: None,
+            peers,
+            linked_name: None,
+            backend: Backend::Userspace,
+            __cant_construct_me: (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(key: u8, endpoint: &str, allowed_ips: &[&str]) -> PeerInfo {
+        PeerInfo::fixture(Key([key; 32]))
+            .with_endpoint(endpoint)
+            .with_allowed_ips(allowed_ips)
+    }
+
+    #[test]
+    fn test_wg_show() {
+        let mut handshaken = peer(3, "192.0.2.3:51820", &["10.0.0.3/32", "fd00::3/128"]);
+        handshaken.config.preshared_key = Some(Key([4; 32]));
+        handshaken.config.persistent_keepalive_interval = Some(25);
+        let handshaken = handshaken
+            .with_handshake(SystemTime::UNIX_EPOCH + Duration::from_secs(1000))
+            .with_transfer(1536, 512);
+        let device = Device {
+            public_key: Some(Key([1; 32])),
+            private_key: Some(Key([2; 32])),
+            fwmark: Some(0x1234),
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", vec![peer(5, "192.0.2.5:51820", &[]), handshaken])
+        };
+        let clock =
+            crate::clock::ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(91_065));
+        let mut out = String::new();
+        device.write_wg_show(&mut out, &clock, &Plain).unwrap();
+        assert_eq!(
+            out,
+            "\
+interface: wg0
+  public key: AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=
+  private key: (hidden)
+  listen port: 51820
+  fwmark: 0x1234
+
+peer: AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=
+  preshared key: (hidden)
+  endpoint: 192.0.2.3:51820
+  allowed ips: 10.0.0.3/32, fd00::3/128
+  latest handshake: 1 day, 1 hour, 1 minute, 5 seconds ago
+  transfer: 1.50 KiB received, 512 B sent
+  persistent keepalive: every 25 seconds
+
+peer: BQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQU=
+  endpoint: 192.0.2.5:51820
+  allowed ips: (none)
+"
+        );
+        assert!(device.to_string().starts_with("interface: wg0\n"));
+    }
+
+    #[cfg(feature = "print")]
+    #[test]
+    fn test_print_to() {
+        let device = Device {
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", vec![peer(1, "192.0.2.1:51820", &["10.0.0.1/32"])])
+        };
+        let mut out = vec![];
+        device.print_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("wg0"));
+        assert!(out.contains("192.0.2.1:51820"));
+        assert!(!out.contains('\x1b'));
+
+        let mut out = vec![];
+        device.print_json_to(&mut out).unwrap();
+        assert_eq!(out, format!("{}\n", device.to_json()).into_bytes());
+    }
+
+    #[test]
+    fn test_find_peer() {
+        let device = Device::fixture(
+            "wg0",
+            vec![
+                peer(1, "192.0.2.1:51820", &["10.0.0.0/16"]),
+                peer(2, "192.0.2.2:51820", &["10.0.1.0/24", "fd00::/64"]),
+            ],
+        );
+
+        let key = |peer: Option<&PeerInfo>| peer.map(|peer| peer.config.public_key.0[0]);
+        assert_eq!(key(device.find_peer(Key([2; 32]))), Some(2));
+        assert_eq!(key(device.find_peer(Key([3; 32]))), None);
+        let endpoint: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        assert_eq!(key(device.find_peer(endpoint)), Some(1));
+        assert_eq!(
+            key(device.find_peer(PeerQuery::EndpointIp("192.0.2.2".parse().unwrap()))),
+            Some(2)
+        );
+        // The longest prefix wins.
+        let ip = |s: &str| PeerQuery::AllowedIp(s.parse().unwrap());
+        assert_eq!(key(device.find_peer(ip("10.0.1.7"))), Some(2));
+        assert_eq!(key(device.find_peer(ip("10.0.2.7"))), Some(1));
+        assert_eq!(key(device.find_peer(ip("fd00::1"))), Some(2));
+        assert_eq!(key(device.find_peer(ip("::ffff:10.0.1.7"))), None);
+    }
+
+    #[test]
+    fn test_update_stats() {
+        let mut device = Device::fixture(
+            "wg0",
+            vec![
+                peer(1, "192.0.2.1:51820", &["10.0.0.1/32"]),
+                peer(2, "192.0.2.2:51820", &["10.0.0.2/32"]),
+            ],
+        );
+        let stats = |rx_bytes| PeerStats {
+            last_handshake_time: None,
+            rx_bytes,
+            tx_bytes: 0,
+        };
+
+        assert!(device.update_stats(vec![(Key([2; 32]), stats(20)), (Key([1; 32]), stats(10))]));
+        assert_eq!(device.peers[0].stats.rx_bytes, 10);
+        assert_eq!(device.peers[1].stats.rx_bytes, 20);
+        assert_eq!(device.peers[0].config.allowed_ips.len(), 1);
+
+        // A repeated entry doesn't replace the first one.
+        assert!(device.update_stats(vec![
+            (Key([1; 32]), stats(15)),
+            (Key([2; 32]), stats(25)),
+            (Key([1; 32]), stats(0)),
+        ]));
+        assert_eq!(device.peers[0].stats.rx_bytes, 15);
+
+        // A peer was removed and another added since.
+        assert!(!device.update_stats(vec![(Key([1; 32]), stats(30)), (Key([3; 32]), stats(5))]));
+        assert_eq!(device.peers[0].stats.rx_bytes, 30);
+        assert_eq!(device.peers[1].stats.rx_bytes, 25);
+        assert_eq!(device.peers.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn test_wait_for_handshake() {
+        let iface: InterfaceName = "mock-handshake".parse().unwrap();
+        let key = Key([1; 32]);
+        DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(&key))
+            .apply(&iface, Backend::Mock)
+            .unwrap();
+        let wait =
+            |key: &Key, timeout| Device::wait_for_handshake(&iface, key, timeout, Backend::Mock);
+
+        let timeout = Duration::from_millis(120);
+        assert_eq!(
+            wait(&key, timeout).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+        assert_eq!(
+            wait(&Key([2; 32]), timeout).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let time = SystemTime::now();
+        let stats = PeerStats {
+            last_handshake_time: Some(time),
+            ..Default::default()
+        };
+        backends::mock::set_stats(&iface, &key, stats).unwrap();
+        assert_eq!(wait(&key, Duration::ZERO).unwrap(), time);
+        Device::get(&iface, Backend::Mock)
+            .unwrap()
+            .delete()
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn test_apply_and_get() {
+        let iface: InterfaceName = "mock-apply-get".parse().unwrap();
+        let device = DeviceUpdate::new()
+            .randomize_listen_port()
+            .add_peer(PeerConfigBuilder::new(&Key([1; 32])))
+            .apply_and_get(&iface, Backend::Mock)
+            .unwrap();
+        assert!(device.listen_port.is_some_and(|port| port != 0));
+        assert_eq!(device.peers.len(), 1);
+        device.delete().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde() {
+        let mut peer = peer(2, "192.0.2.2:51820", &["10.0.1.0/24", "fd00::/64"])
+            .with_handshake(SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+        peer.config.preshared_key = Some(Key([3; 32]));
+        let mut device = Device {
+            public_key: Some(Key([1; 32])),
+            private_key: Some(Key([4; 32])),
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", vec![peer])
+        };
+        let mut json = serde_json::to_value(&device).unwrap();
+        assert_eq!(json["name"], "wg0");
+        assert_eq!(json["public_key"], Key([1; 32]).to_base64());
+        assert_eq!(json["backend"], "userspace");
+        assert!(json.get("private_key").is_none());
+        let config = &json["peers"][0]["config"];
+        assert_eq!(config["endpoint"], "192.0.2.2:51820");
+        assert_eq!(config["allowed_ips"][1], "fd00::/64");
+        assert!(config.get("preshared_key").is_none());
+        assert_eq!(json["peers"][0]["stats"]["last_handshake_time"], 1000);
+
+        // Keys are still read when given.
+        json["private_key"] = Key([4; 32]).to_base64().into();
+        device.peers[0].config.preshared_key = None;
+        assert_eq!(serde_json::from_value::<Device>(json).unwrap(), device);
+
+        assert!(serde_json::from_str::<Key>("\"not a key\"").is_err());
+        assert!(serde_json::from_str::<AllowedIp>("\"10.0.0.0\"").is_err());
+        let stats = format!(
+            r#"{{"last_handshake_time": {}, "rx_bytes": 0, "tx_bytes": 0}}"#,
+            u64::MAX
+        );
+        assert!(serde_json::from_str::<PeerStats>(&stats).is_err());
+    }
+
+    #[test]
+    fn test_default_route_excluding_v4() {
+        let excluded: IpAddr = "1.2.3.4".parse().unwrap();
+        let routes = AllowedIp::default_route_excluding(excluded);
+        assert_eq!(routes.len(), 32);
+        assert_eq!(routes[0], "128.0.0.0/1".parse().unwrap());
+        assert_eq!(routes[1], "64.0.0.0/2".parse().unwrap());
+        assert_eq!(routes[31], "1.2.3.5/32".parse().unwrap());
+
+        let contains = |route: &AllowedIp, ip: u32| {
+            let IpAddr::V4(network) = route.address else {
+                unreachable!()
+            };
+            let mask = u32::MAX.checked_shl(32 - route.cidr as u32).unwrap_or(0);
+            ip & mask == u32::from(network)
+        };
+        // Every address except the excluded one is covered exactly once.
+        for probe in ["0.0.0.0", "1.2.3.5", "1.2.3.3", "255.255.255.255"] {
+            let ip = u32::from(probe.parse::<Ipv4Addr>().unwrap());
+            assert_eq!(routes.iter().filter(|r| contains(r, ip)).count(), 1);
+        }
+        let ip = u32::from("1.2.3.4".parse::<Ipv4Addr>().unwrap());
+        assert!(routes.iter().all(|r| !contains(r, ip)));
+    }
+
+    #[test]
+    fn test_default_route_excluding_v6() {
+        let routes = AllowedIp::default_route_excluding("::1".parse().unwrap());
+        assert_eq!(routes.len(), 128);
+        assert_eq!(routes[0], "8000::/1".parse().unwrap());
+        assert_eq!(routes[127], "::/128".parse().unwrap());
+    }
+
+    #[test]
+    fn test_handshake_age() {
+        use crate::clock::ManualClock;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1000));
+        let stats = |secs| PeerStats {
+            last_handshake_time: Some(UNIX_EPOCH + Duration::from_secs(secs)),
+            ..Default::default()
+        };
+        assert_eq!(stats(0).handshake_age(&clock), None);
+        assert_eq!(PeerStats::default().handshake_age(&clock), None);
+        assert_eq!(
+            stats(900).handshake_age(&clock),
+            Some(Duration::from_secs(100))
+        );
+        // After an NTP step backwards the handshake is in the future.
+        assert_eq!(stats(2000).handshake_age(&clock), Some(Duration::ZERO));
+    }
+}
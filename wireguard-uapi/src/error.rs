@@ -0,0 +1,261 @@
+use crate::{ApplyError, Backend, InterfaceName, InvalidKey};
+
+#[cfg(unix)]
+use crate::backends::userspace::UapiError;
+
+#[cfg(target_os = "linux")]
+use crate::netlink_request::ExtAckError;
+
+use std::{error, fmt, io};
+
+/// A failure to manage an interface, sorted into what callers act on.
+///
+/// The backends report failures as [`io::Error`]s whose kinds and messages
+/// differ: a missing interface is `ENODEV` from netlink but a missing socket for
+/// userspace implementations. [`Device`](crate::Device),
+/// [`DeviceUpdate`](crate::DeviceUpdate), [`PeerStats`](crate::PeerStats) and
+/// their async variants classify them, so telling "no such device" from
+/// "permission denied" doesn't take matching on strings.
+///
+/// Converts into an [`io::Error`] of the same [`kind`](Error::kind), so `?`
+/// keeps working in functions returning [`io::Result`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Not allowed to manage WireGuard interfaces, e.g. without `CAP_NET_ADMIN`.
+    PermissionDenied(io::Error),
+    /// The interface doesn't exist.
+    InterfaceNotFound(InterfaceName),
+    /// A key isn't valid.
+    InvalidKey,
+    /// The listen port is taken by another socket.
+    PortInUse(io::Error),
+    /// The kernel rejected a netlink request with this errno, and the reason it
+    /// gave, if it supports extended ACKs.
+    NetlinkError { errno: i32, message: Option<String> },
+    /// A userspace implementation rejected a request, or its response couldn't
+    /// be parsed.
+    UserspaceProtocolError(io::Error),
+    /// Any other failure.
+    Io(io::Error),
+}
+
+impl Error {
+    /// Classifies an error of `backend`, managing `iface` if given.
+    pub(crate) fn from_backend(
+        error: io::Error,
+        backend: Backend,
+        iface: Option<&InterfaceName>,
+    ) -> Self {
+        // Errors classified before, e.g. by a nested call, are kept.
+        let error = match classified(error) {
+            Ok(classified) => return classified,
+            Err(error) => error,
+        };
+        let errno = errno(&error);
+        if error.kind() == io::ErrorKind::PermissionDenied
+            || matches!(errno, Some(libc::EPERM | libc::EACCES))
+        {
+            return Self::PermissionDenied(error);
+        }
+        if let Some(iface) = iface {
+            // To `wg`, NotFound is a missing binary; it reports missing
+            // interfaces as ENODEV.
+            let not_found = error.kind() == io::ErrorKind::NotFound && backend != Backend::Cli;
+            if not_found || errno == Some(libc::ENODEV) {
+                return Self::InterfaceNotFound(*iface);
+            }
+            // A socket left behind by a userspace implementation that exited.
+            if backend == Backend::Userspace && error.kind() == io::ErrorKind::ConnectionRefused {
+                return Self::InterfaceNotFound(*iface);
+            }
+        }
+        if error.kind() == io::ErrorKind::AddrInUse {
+            return Self::PortInUse(error);
+        }
+        match (backend, errno) {
+            #[cfg(unix)]
+            (Backend::Userspace, _)
+                if UapiError::from_io(&error).is_some()
+                    || error.kind() == io::ErrorKind::InvalidData =>
+            {
+                Self::UserspaceProtocolError(error)
+            }
+            #[cfg(target_os = "linux")]
+            (Backend::Kernel, Some(errno)) => Self::NetlinkError {
+                errno,
+                message: ExtAckError::from_io(&error).map(|e| e.message.clone()),
+            },
+            _ => Self::Io(error),
+        }
+    }
+
+    /// The kind of the [`io::Error`] this converts into.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::PermissionDenied(_) => io::ErrorKind::PermissionDenied,
+            Self::InterfaceNotFound(_) => io::ErrorKind::NotFound,
+            Self::PortInUse(_) => io::ErrorKind::AddrInUse,
+            Self::InvalidKey => io::ErrorKind::InvalidInput,
+            Self::NetlinkError { errno, .. } => io::Error::from_raw_os_error(*errno).kind(),
+            Self::UserspaceProtocolError(e) | Self::Io(e) => e.kind(),
+        }
+    }
+}
+
+/// The [`Error`] `error` carries, if it was converted from one, or `error` back.
+fn classified(error: io::Error) -> Result<Error, io::Error> {
+    if !error.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+        return Err(error);
+    }
+    let inner = error.into_inner().expect("checked above");
+    Ok(*inner.downcast::<Error>().expect("checked above"))
+}
+
+/// The errno of `error`, also if it carries the reason of a netlink error.
+fn errno(error: &io::Error) -> Option<i32> {
+    #[cfg(target_os = "linux")]
+    if let Some(e) = ExtAckError::from_io(error) {
+        return Some(e.errno);
+    }
+    error.raw_os_error()
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PermissionDenied(e) => write!(f, "permission denied: {}", e),
+            Self::InterfaceNotFound(iface) => write!(f, "interface {} not found", iface),
+            Self::PortInUse(e) => write!(f, "listen port in use: {}", e),
+            Self::InvalidKey => write!(f, "{}", InvalidKey),
+            Self::NetlinkError { errno, message } => {
+                let error = io::Error::from_raw_os_error(*errno);
+                match message {
+                    Some(message) => write!(f, "netlink request failed: {}: {}", error, message),
+                    None => write!(f, "netlink request failed: {}", error),
+                }
+            }
+            Self::UserspaceProtocolError(e) => write!(f, "userspace WireGuard: {}", e),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::PermissionDenied(e)
+            | Self::PortInUse(e)
+            | Self::UserspaceProtocolError(e)
+            | Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ApplyError> for Error {
+    /// Backend failures keep their classification; the rest, such as invalid
+    /// peers, become [`Error::Io`].
+    fn from(e: ApplyError) -> Self {
+        if !e.source.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return Self::Io(e.into());
+        }
+        classified(e.source).expect("checked above")
+    }
+}
+
+impl From<InvalidKey> for Error {
+    fn from(_: InvalidKey) -> Self {
+        Self::InvalidKey
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::NetlinkError {
+                errno,
+                message: None,
+            } => io::Error::from_raw_os_error(errno),
+            // Kept whole, so classifying the io::Error again gets it back.
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_backend() {
+        let iface: InterfaceName = "wg0".parse().unwrap();
+        let classify = |error, backend| Error::from_backend(error, backend, Some(&iface));
+
+        let error = classify(io::Error::from_raw_os_error(libc::EPERM), Backend::Cli);
+        assert!(matches!(error, Error::PermissionDenied(_)));
+        let error = classify(io::ErrorKind::NotFound.into(), Backend::Userspace);
+        assert!(matches!(error, Error::InterfaceNotFound(name) if name == iface));
+        #[cfg(unix)]
+        {
+            let error = classify(
+                UapiError {
+                    errno: libc::EPROTO,
+                }
+                .into(),
+                Backend::Userspace,
+            );
+            assert!(matches!(error, Error::UserspaceProtocolError(_)));
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            let uapi = |errno| io::Error::from(UapiError { errno });
+            let error = classify(uapi(libc::EADDRINUSE), Backend::Userspace);
+            assert!(matches!(error, Error::PortInUse(_)));
+            let error = classify(uapi(libc::ENODEV), Backend::Userspace);
+            assert!(matches!(error, Error::InterfaceNotFound(_)));
+            let error = classify(uapi(libc::EPERM), Backend::Userspace);
+            assert!(matches!(error, Error::PermissionDenied(_)));
+        }
+        let error = classify(io::ErrorKind::ConnectionRefused.into(), Backend::Userspace);
+        assert!(matches!(error, Error::InterfaceNotFound(_)));
+        #[cfg(target_os = "linux")]
+        {
+            let ext_ack = ExtAckError {
+                errno: libc::EINVAL,
+                message: "Invalid IP address".to_string(),
+            };
+            let error = classify(ext_ack.into(), Backend::Kernel);
+            assert!(matches!(
+                error,
+                Error::NetlinkError { errno: libc::EINVAL, message: Some(ref message) }
+                    if message == "Invalid IP address"
+            ));
+        }
+        let error = classify(io::ErrorKind::TimedOut.into(), Backend::Cli);
+        assert!(matches!(error, Error::Io(_)));
+        // A missing `wg` binary isn't a missing interface.
+        let error = classify(io::ErrorKind::NotFound.into(), Backend::Cli);
+        assert!(matches!(error, Error::Io(_)));
+        let error = classify(io::Error::from_raw_os_error(libc::ENODEV), Backend::Cli);
+        assert!(matches!(error, Error::InterfaceNotFound(_)));
+
+        // Converting to io::Error and back keeps the classification.
+        let error = io::Error::from(Error::InterfaceNotFound(iface));
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(matches!(
+            Error::from_backend(error, Backend::Cli, None),
+            Error::InterfaceNotFound(_)
+        ));
+        // So does wrapping it in an ApplyError.
+        let error = ApplyError::from(io::Error::from(Error::InterfaceNotFound(iface)));
+        assert!(matches!(Error::from(error), Error::InterfaceNotFound(_)));
+        let error = ApplyError::from(io::Error::from(io::ErrorKind::InvalidInput));
+        assert!(matches!(Error::from(error), Error::Io(_)));
+    }
+}
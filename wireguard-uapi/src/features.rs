@@ -0,0 +1,99 @@
+//! The Cargo features this build of the crate was compiled with.
+//!
+//! No feature is on by default, and each optional dependency is behind one of
+//! its own, so a minimal build carries only the key handling and backends. What
+//! a binary ended up with depends on every crate in its graph enabling features,
+//! so [`enabled`] tells at runtime, e.g. for `--version` output or bug reports.
+
+/// A feature of the crate, and the optional dependencies it pulls in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Feature {
+    pub name: &'static str,
+    pub dependencies: &'static [&'static str],
+    pub enabled: bool,
+}
+
+macro_rules! features {
+    ($($name:literal => [$($dependency:literal),*],)*) => {
+        /// Every feature of the crate, by name.
+        pub const ALL: &[Feature] = &[$(Feature {
+            name: $name,
+            dependencies: &[$($dependency),*],
+            enabled: cfg!(feature = $name),
+        }),*];
+    };
+}
+
+features! {
+    "backup-encryption" => ["chacha20poly1305"],
+    "beacon" => [],
+    "caller-rng" => [],
+    "cidr" => ["cidr"],
+    "gossip" => [],
+    "mock" => [],
+    "mtls" => ["rustls"],
+    "nft" => [],
+    "otel" => ["opentelemetry"],
+    "parallel" => ["rayon"],
+    "portmap" => [],
+    "print" => ["colored"],
+    "qr" => ["qrcode"],
+    "sampling" => [],
+    "serde" => ["serde"],
+    "tokio" => ["tokio"],
+    "tools" => [],
+    "tui" => ["ratatui"],
+}
+
+/// The names of the features this build was compiled with.
+pub fn enabled() -> Vec<&'static str> {
+    ALL.iter()
+        .filter(|feature| feature.enabled)
+        .map(|feature| feature.name)
+        .collect()
+}
+
+/// Whether this build was compiled with feature `name`.
+pub fn is_enabled(name: &str) -> bool {
+    ALL.iter()
+        .any(|feature| feature.name == name && feature.enabled)
+}
+
+/// The optional dependencies this build was compiled with.
+pub fn dependencies() -> Vec<&'static str> {
+    let mut dependencies: Vec<_> = ALL
+        .iter()
+        .filter(|feature| feature.enabled)
+        .flat_map(|feature| feature.dependencies.iter().copied())
+        .collect();
+    dependencies.sort_unstable();
+    dependencies.dedup();
+    dependencies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let manifest = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"));
+        let features = manifest
+            .split("[features]")
+            .nth(1)
+            .and_then(|rest| rest.split("\n[").next())
+            .unwrap();
+        for line in features.lines() {
+            let Some((name, enables)) = line.split_once(" = ") else {
+                continue;
+            };
+            let feature = ALL.iter().find(|feature| feature.name == name);
+            let feature = feature.unwrap_or_else(|| panic!("feature {} is missing", name));
+            for dependency in feature.dependencies {
+                assert!(enables.contains(&format!("\"dep:{}\"", dependency)));
+            }
+        }
+        assert_eq!(is_enabled("mock"), cfg!(feature = "mock"));
+        assert!(enabled().iter().all(|name| is_enabled(name)));
+    }
+}
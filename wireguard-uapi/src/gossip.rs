@@ -0,0 +1,534 @@
+//! Optional peer discovery by gossip among the nodes of a mesh.
+//!
+//! Every node signs a [`Descriptor`] of itself (its public key, the endpoints it
+//! can be reached on and the IPs it routes) with its WireGuard private key, and a
+//! [`Gossip`] node periodically sends the descriptors it knows to a few random
+//! other nodes over UDP. The gossip socket can be bound to the tunnel address to
+//! gossip over the tunnel, or to an underlay address as a side channel.
+//!
+//! Descriptors are only accepted from keys the node [allows](Gossip::allow), and
+//! only if every IP they route lies within the prefixes allowed for the key, so a
+//! node can't take over another's addresses. Newer descriptors of a key replace
+//! older ones. Descriptors that aren't refreshed within the TTL are
+//! [expired](Gossip::expire), and [`update`](Gossip::update) turns the known
+//! descriptors into the update to apply with [`DeviceUpdate::sync`] on each pass
+//! of the caller's reconcile loop, removing the peers of expired nodes.
+use crate::{
+    clock::{Clock, SystemClock},
+    device::AllowedIp,
+    invite::{open_token, sign_token, verify_token, Fields},
+    DeviceUpdate, Key, PeerConfigBuilder,
+};
+
+use ipnet::IpNet;
+use rand_core::{OsRng, RngCore};
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The [context](sign_token) of descriptor signatures.
+const CONTEXT: &[u8] = b"wireguard-uapi gossip v1\n";
+
+/// Datagrams are kept below the smallest common tunnel MTU, so gossiping over
+/// the tunnel doesn't fragment.
+const MAX_DATAGRAM: usize = 1200;
+
+/// What a node announces about itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Descriptor {
+    pub public_key: Key,
+    /// The WireGuard endpoints the node can be reached on.
+    pub endpoints: Vec<SocketAddr>,
+    pub allowed_ips: Vec<AllowedIp>,
+    /// Where the node listens for gossip.
+    pub gossip: SocketAddr,
+    /// When the node signed the descriptor. Later descriptors replace earlier
+    /// ones.
+    pub issued: SystemTime,
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn list<T: ToString>(items: impl IntoIterator<Item = T>) -> String {
+    items
+        .into_iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Descriptor {
+    /// The signed text. The issue time is kept to the second.
+    fn payload(&self) -> String {
+        format!(
+            "PublicKey = {}\nEndpoints = {}\nAllowedIPs = {}\nGossip = {}\nIssued = {}\n",
+            self.public_key.to_base64(),
+            list(&self.endpoints),
+            list(
+                self.allowed_ips
+                    .iter()
+                    .map(|ip| format!("{}/{}", ip.address, ip.cidr))
+            ),
+            self.gossip,
+            secs(self.issued)
+        )
+    }
+
+    fn from_payload(payload: &str) -> Option<Self> {
+        let mut fields = Fields::new(payload);
+        fn parse_list<T: FromStr>(value: &str) -> Option<Vec<T>> {
+            value
+                .split(", ")
+                .filter(|item| !item.is_empty())
+                .map(|item| item.parse().ok())
+                .collect()
+        }
+        Some(Self {
+            public_key: Key::from_base64(fields.field("PublicKey")?).ok()?,
+            endpoints: parse_list(fields.field("Endpoints")?)?,
+            allowed_ips: parse_list(fields.field("AllowedIPs")?)?,
+            gossip: fields.field("Gossip")?.parse().ok()?,
+            issued: UNIX_EPOCH
+                .checked_add(Duration::from_secs(fields.field("Issued")?.parse().ok()?))?,
+        })
+    }
+
+    /// Signs the descriptor with the node's private key, which also sets its
+    /// public key, returning the token to gossip.
+    pub fn sign(mut self, private_key: &Key) -> String {
+        self.public_key = private_key.get_public();
+        sign_token(private_key, CONTEXT, &self.payload())
+    }
+
+    /// Decodes `token`, returning `None` unless it's signed by the key it
+    /// describes.
+    pub fn verify(token: &str) -> Option<Self> {
+        let (payload, signature) = open_token(token)?;
+        let descriptor = Self::from_payload(&payload)?;
+        verify_token(&descriptor.public_key, CONTEXT, &payload, &signature).then_some(descriptor)
+    }
+
+    /// The peer to configure for the node.
+    pub fn to_peer(&self) -> PeerConfigBuilder {
+        let peer = PeerConfigBuilder::new(&self.public_key).add_allowed_ips(&self.allowed_ips);
+        match self.endpoints.first() {
+            Some(endpoint) => peer.set_endpoint(*endpoint),
+            None => peer,
+        }
+    }
+}
+
+/// A node taking part in the gossip.
+#[derive(Debug)]
+pub struct Gossip {
+    socket: UdpSocket,
+    own: Descriptor,
+    private_key: Key,
+    /// The verified descriptors of the other nodes, with their tokens.
+    known: HashMap<Key, (Descriptor, String)>,
+    /// The nodes whose descriptors expired, and when.
+    departed: HashMap<Key, SystemTime>,
+    /// The keys accepted, with the prefixes each may announce.
+    allowed: HashMap<Key, Vec<IpNet>>,
+    /// The prefixes any other key may announce, if any key is accepted.
+    allow_any: Option<Vec<IpNet>>,
+    seeds: Vec<SocketAddr>,
+    fanout: usize,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl Gossip {
+    /// Gossips on `socket` as the node with `private_key`, announcing
+    /// `endpoints` and `allowed_ips`. The socket's local address is announced as
+    /// the gossip address.
+    ///
+    /// By default it sends to 3 nodes per round and drops descriptors after 5
+    /// minutes.
+    pub fn new(
+        socket: UdpSocket,
+        private_key: Key,
+        endpoints: Vec<SocketAddr>,
+        allowed_ips: Vec<AllowedIp>,
+    ) -> io::Result<Self> {
+        let own = Descriptor {
+            public_key: private_key.get_public(),
+            endpoints,
+            allowed_ips,
+            gossip: socket.local_addr()?,
+            issued: SystemClock.now(),
+        };
+        Ok(Self {
+            socket,
+            own,
+            private_key,
+            known: HashMap::new(),
+            departed: HashMap::new(),
+            allowed: HashMap::new(),
+            allow_any: None,
+            seeds: vec![],
+            fanout: 3,
+            ttl: Duration::from_secs(300),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Accepts descriptors of `public_key` routing IPs within `prefixes`, e.g.
+    /// the node's own address.
+    pub fn allow(mut self, public_key: Key, prefixes: Vec<IpNet>) -> Self {
+        self.allowed.insert(public_key, prefixes);
+        self
+    }
+
+    /// Accepts descriptors of any key routing IPs within `prefixes`, so anyone
+    /// who can reach the gossip socket can join the mesh. Such nodes can claim
+    /// each other's addresses within the prefixes.
+    pub fn allow_any(mut self, prefixes: Vec<IpNet>) -> Self {
+        self.allow_any = Some(prefixes);
+        self
+    }
+
+    /// The prefixes `public_key` may announce, or `None` if it isn't accepted.
+    fn prefixes(&self, public_key: &Key) -> Option<&[IpNet]> {
+        self.allowed
+            .get(public_key)
+            .or(self.allow_any.as_ref())
+            .map(Vec::as_slice)
+    }
+
+    /// Gossips with `seed` until other nodes are known.
+    pub fn add_seed(mut self, seed: SocketAddr) -> Self {
+        self.seeds.push(seed);
+        self
+    }
+
+    pub fn set_fanout(mut self, fanout: usize) -> Self {
+        self.fanout = fanout;
+        self
+    }
+
+    pub fn set_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Takes the time descriptors are issued and received at from `clock`.
+    pub fn set_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Replaces the announced gossip address, e.g. when the socket is bound to
+    /// the unspecified address.
+    pub fn set_gossip_addr(mut self, addr: SocketAddr) -> Self {
+        self.own.gossip = addr;
+        self
+    }
+
+    /// The other nodes' descriptors, ordered by public key.
+    pub fn descriptors(&self) -> Vec<&Descriptor> {
+        let mut descriptors: Vec<_> = self.known.values().map(|(d, _)| d).collect();
+        descriptors.sort_by_cached_key(|d| d.public_key.to_base64());
+        descriptors
+    }
+
+    /// Verifies `token` and records its descriptor, returning its key if it was
+    /// new or newer than the known one.
+    pub fn receive(&mut self, token: &str, now: SystemTime) -> Option<Key> {
+        let descriptor = Descriptor::verify(token)?;
+        let key = &descriptor.public_key;
+        if *key == self.own.public_key
+            || now.duration_since(descriptor.issued).unwrap_or_default() > self.ttl
+        {
+            return None;
+        }
+        let prefixes = self.prefixes(key)?;
+        let contained = descriptor.allowed_ips.iter().all(|ip| {
+            IpNet::new(ip.address, ip.cidr)
+                .is_ok_and(|net| prefixes.iter().any(|prefix| prefix.contains(&net)))
+        });
+        if !contained {
+            log::warn!(
+                "ignoring gossip of {} announcing IPs outside its prefixes",
+                key.to_base64()
+            );
+            return None;
+        }
+        if let Some((known, _)) = self.known.get(key) {
+            if known.issued >= descriptor.issued {
+                return None;
+            }
+        }
+        let key = key.clone();
+        self.departed.remove(&key);
+        self.known
+            .insert(key.clone(), (descriptor, token.to_string()));
+        Some(key)
+    }
+
+    /// Drops the descriptors that weren't refreshed within the TTL, returning
+    /// their keys. The next [`update`](Self::update)s remove their peers, until
+    /// another TTL has passed.
+    pub fn expire(&mut self, now: SystemTime) -> Vec<Key> {
+        let ttl = self.ttl;
+        let age = |time: SystemTime| now.duration_since(time).unwrap_or_default();
+        self.departed.retain(|_, departed| age(*departed) <= ttl);
+        let expired: Vec<Key> = self
+            .known
+            .iter()
+            .filter(|(_, (d, _))| age(d.issued) > ttl)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.known.remove(key);
+            self.departed.insert(key.clone(), now);
+        }
+        expired
+    }
+
+    /// Re-signs the node's own descriptor and sends it with the known ones to
+    /// `fanout` random nodes, or to the seeds while no node is known.
+    ///
+    /// Nodes that can't be sent to are skipped, so one unreachable node doesn't
+    /// stop the others from hearing the gossip. Fails only if no node could be
+    /// sent to.
+    pub fn round(&mut self) -> io::Result<()> {
+        self.own.issued = self.clock.now();
+        let own = self.own.clone().sign(&self.private_key);
+        let mut targets: Vec<SocketAddr> = self.known.values().map(|(d, _)| d.gossip).collect();
+        if targets.is_empty() {
+            targets = self.seeds.clone();
+        }
+        // A partial Fisher-Yates shuffle picks the targets.
+        let count = self.fanout.min(targets.len());
+        for i in 0..count {
+            let j = i + (OsRng.next_u32() as usize) % (targets.len() - i);
+            targets.swap(i, j);
+        }
+
+        let tokens = std::iter::once(&own).chain(self.known.values().map(|(_, token)| token));
+        let datagrams = pack(tokens);
+        let mut failed = None;
+        let mut reached = 0;
+        for target in &targets[..count] {
+            let sent = datagrams.iter().try_for_each(|datagram| {
+                self.socket.send_to(datagram.as_bytes(), target).map(drop)
+            });
+            match sent {
+                Ok(()) => reached += 1,
+                Err(e) => {
+                    log::warn!("failed to gossip to {}: {}", target, e);
+                    failed = Some(e);
+                }
+            }
+        }
+        match failed {
+            Some(e) if reached == 0 => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Receives gossip for up to `timeout`, returning the keys whose descriptors
+    /// were new or changed.
+    pub fn listen(&mut self, timeout: Duration) -> io::Result<Vec<Key>> {
+        let deadline = Instant::now() + timeout;
+        let mut changed = vec![];
+        let mut buf = [0u8; 65536];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(changed);
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+            let len = match self.socket.recv_from(&mut buf) {
+                Ok((len, _)) => len,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(changed)
+                }
+                Err(e) => return Err(e),
+            };
+            let Ok(datagram) = std::str::from_utf8(&buf[..len]) else {
+                continue;
+            };
+            let now = self.clock.now();
+            for token in datagram.lines() {
+                if let Some(key) = self.receive(token, now) {
+                    if !changed.contains(&key) {
+                        changed.push(key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The update adding or updating a peer for every known node and removing
+    /// the peers of [expired](Self::expire) ones. Peers that weren't discovered by
+    /// gossip are left alone.
+    pub fn update(&self) -> DeviceUpdate {
+        let peers: Vec<_> = self.descriptors().iter().map(|d| d.to_peer()).collect();
+        let mut departed: Vec<_> = self.departed.keys().collect();
+        departed.sort_by_cached_key(|key| key.to_base64());
+        departed
+            .into_iter()
+            .fold(DeviceUpdate::new().add_peers(&peers), |update, key| {
+                update.remove_peer_by_key(key)
+            })
+    }
+}
+
+/// Packs `tokens` into newline-separated datagrams of at most [`MAX_DATAGRAM`]
+/// bytes, unless a single token is longer.
+fn pack<'a>(tokens: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+    let mut datagrams: Vec<String> = vec![];
+    for token in tokens {
+        match datagrams.last_mut() {
+            Some(last) if last.len() + 1 + token.len() <= MAX_DATAGRAM => {
+                last.push('\n');
+                last.push_str(token);
+            }
+            _ => datagrams.push(token.clone()),
+        }
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+
+    fn descriptor(port: u16) -> Descriptor {
+        Descriptor {
+            public_key: Key::zero(),
+            endpoints: vec![([192, 0, 2, 1], port).into()],
+            allowed_ips: vec!["10.0.0.1/32".parse().unwrap()],
+            gossip: ([10, 0, 0, 1], 7946).into(),
+            issued: UNIX_EPOCH + Duration::from_secs(1_000),
+        }
+    }
+
+    fn node(private_key: &Key) -> Gossip {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        Gossip::new(socket, private_key.clone(), vec![], vec![]).unwrap()
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let private_key = Key::generate_private();
+        let token = descriptor(51820).sign(&private_key);
+        let verified = Descriptor::verify(&token).unwrap();
+        assert_eq!(verified.public_key, private_key.get_public());
+        assert_eq!(verified.endpoints, descriptor(51820).endpoints);
+
+        // Claiming another node's addresses breaks the signature.
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = descriptor(4500).sign(&Key::generate_private());
+        let (payload, _) = forged.split_once('.').unwrap();
+        assert!(Descriptor::verify(&format!("{payload}.{signature}")).is_none());
+    }
+
+    #[test]
+    fn test_issued_out_of_range() {
+        let payload = descriptor(51820)
+            .payload()
+            .replace("Issued = 1000", &format!("Issued = {}", u64::MAX));
+        assert!(Descriptor::from_payload(&payload).is_none());
+        let token = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode([0; 64])
+        );
+        assert!(Descriptor::verify(&token).is_none());
+    }
+
+    #[test]
+    fn test_receive() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_010);
+        let (a, b, c) = (
+            Key::generate_private(),
+            Key::generate_private(),
+            Key::generate_private(),
+        );
+        let prefixes = vec!["10.0.0.0/24".parse().unwrap()];
+        let mut gossip = node(&a).allow(b.get_public(), prefixes);
+
+        // IPs outside the allowed prefixes are refused.
+        let mut outside = descriptor(51820);
+        outside.allowed_ips.push("10.0.1.0/24".parse().unwrap());
+        assert!(gossip.receive(&outside.sign(&b), now).is_none());
+        assert!(gossip.receive(&descriptor(51820).sign(&b), now).is_some());
+        // Unknown keys and stale or replayed descriptors are ignored.
+        assert!(gossip.receive(&descriptor(51820).sign(&c), now).is_none());
+        assert!(gossip.receive(&descriptor(51820).sign(&b), now).is_none());
+        let mut newer = descriptor(4500);
+        newer.issued += Duration::from_secs(5);
+        assert!(gossip.receive(&newer.sign(&b), now).is_some());
+        assert_eq!(
+            gossip.descriptors()[0].endpoints,
+            descriptor(4500).endpoints
+        );
+
+        let update = gossip.update();
+        assert!(!update.replace_peers);
+        assert_eq!(update.peers.len(), 1);
+
+        assert!(gossip.expire(now).is_empty());
+        assert_eq!(
+            gossip.expire(now + Duration::from_secs(600)),
+            vec![b.get_public()]
+        );
+        // The expired node's peer is removed, until the departure expires too.
+        let update = gossip.update();
+        assert_eq!(update.peers.len(), 1);
+        assert!(update.peers[0].remove_me);
+        gossip.expire(now + Duration::from_secs(1200));
+        assert!(gossip.update().peers.is_empty());
+    }
+
+    #[test]
+    fn test_round() {
+        let (a, b) = (Key::generate_private(), Key::generate_private());
+        let mut gossip_b = node(&b).allow(a.get_public(), vec![]);
+        let seed = gossip_b.socket.local_addr().unwrap();
+        // The first seed can't be sent to, which doesn't stop the round.
+        let mut gossip_a = node(&a)
+            .allow(b.get_public(), vec![])
+            .set_fanout(2)
+            .add_seed("[::]:7946".parse().unwrap())
+            .add_seed(seed);
+
+        gossip_a.round().unwrap();
+        assert_eq!(
+            gossip_b.listen(Duration::from_secs(1)).unwrap(),
+            vec![a.get_public()]
+        );
+        // b learned where a gossips, so a hears back without seeds.
+        gossip_b.round().unwrap();
+        assert_eq!(
+            gossip_a.listen(Duration::from_secs(1)).unwrap(),
+            vec![b.get_public()]
+        );
+    }
+
+    #[test]
+    fn test_pack() {
+        let tokens = vec!["x".repeat(700), "y".repeat(400), "z".repeat(200)];
+        let datagrams = pack(&tokens);
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].lines().count(), 2);
+    }
+}
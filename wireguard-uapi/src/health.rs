@@ -0,0 +1,256 @@
+//! Boiling the state of a host's interfaces down to one health status.
+//!
+//! [`summarize`] checks a set of [`Device`]s against a [`Policy`] and returns a
+//! [`Summary`] whose status and one-line [`Display`](fmt::Display) form are meant
+//! for load balancer health checks and service watchdogs.
+use crate::{
+    clock::{Clock, SystemClock},
+    Device, InterfaceName,
+};
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+/// Overall health, ordered from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Status {
+    Healthy,
+    Degraded,
+    Down,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Healthy => "healthy",
+            Self::Degraded => "degraded",
+            Self::Down => "down",
+        })
+    }
+}
+
+/// Why a summary isn't [`Healthy`](Status::Healthy).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    /// An interface the policy expects doesn't exist.
+    Missing(InterfaceName),
+    /// The interface isn't listening on a port.
+    NoListenPort(InterfaceName),
+    /// Peers of the interface haven't completed a handshake within
+    /// [`Policy::stale_after`].
+    StalePeers {
+        iface: InterfaceName,
+        stale: usize,
+        total: usize,
+    },
+}
+
+impl Reason {
+    /// How bad this reason is on its own under `policy`.
+    fn status(&self, policy: &Policy) -> Status {
+        match self {
+            Self::Missing(_) => Status::Down,
+            Self::NoListenPort(_) => Status::Degraded,
+            Self::StalePeers { stale, total, .. } if stale == total && policy.down_if_all_stale => {
+                Status::Down
+            }
+            Self::StalePeers { .. } => Status::Degraded,
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(iface) => write!(f, "{}: interface missing", iface),
+            Self::NoListenPort(iface) => write!(f, "{}: no listen port", iface),
+            Self::StalePeers {
+                iface,
+                stale,
+                total,
+            } => write!(f, "{}: {} of {} peers stale", iface, stale, total),
+        }
+    }
+}
+
+/// What counts as healthy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    /// Interfaces that must exist.
+    pub expected: Vec<InterfaceName>,
+    /// How long after its last handshake a peer is considered stale. Active
+    /// sessions rekey every two minutes, so the default is three minutes.
+    pub stale_after: Duration,
+    /// How many stale peers an interface may have before it is degraded, e.g. to
+    /// tolerate roaming clients on a hub.
+    pub max_stale_peers: usize,
+    /// Whether an interface whose peers are all stale makes the host down.
+    pub down_if_all_stale: bool,
+    /// Whether an interface without a listen port makes the host degraded.
+    pub require_listen_port: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            expected: vec![],
+            stale_after: Duration::from_secs(180),
+            max_stale_peers: 0,
+            down_if_all_stale: true,
+            require_listen_port: true,
+        }
+    }
+}
+
+/// The overall status and everything that contributed to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub status: Status,
+    pub reasons: Vec<Reason>,
+}
+
+impl Summary {
+    pub fn is_healthy(&self) -> bool {
+        self.status == Status::Healthy
+    }
+}
+
+/// Displays as e.g. `degraded: wg0: 3 of 10 peers stale; wg1: no listen port`.
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.status)?;
+        for (i, reason) in self.reasons.iter().enumerate() {
+            f.write_str(if i == 0 { ": " } else { "; " })?;
+            write!(f, "{}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Summarizes the health of `devices` under `policy`, as of now.
+pub fn summarize(devices: &[Device], policy: &Policy) -> Summary {
+    summarize_with_clock(devices, policy, &SystemClock)
+}
+
+/// Summarizes the health of `devices` under `policy`, as of `clock`'s time.
+pub fn summarize_with_clock(devices: &[Device], policy: &Policy, clock: &dyn Clock) -> Summary {
+    summarize_at(devices, policy, clock.now())
+}
+
+/// Summarizes the health of `devices` under `policy`, as of `now`.
+pub fn summarize_at(devices: &[Device], policy: &Policy, now: SystemTime) -> Summary {
+    let mut reasons = vec![];
+    for iface in &policy.expected {
+        if !devices.iter().any(|device| device.name == *iface) {
+            reasons.push(Reason::Missing(*iface));
+        }
+    }
+
+    for device in devices {
+        if policy.require_listen_port && matches!(device.listen_port, None | Some(0)) {
+            reasons.push(Reason::NoListenPort(device.name));
+        }
+
+        let stale = device
+            .peers
+            .iter()
+            .filter(|peer| match peer.stats.last_handshake_time {
+                Some(time) => now
+                    .duration_since(time)
+                    .is_ok_and(|age| age > policy.stale_after),
+                None => true,
+            })
+            .count();
+        if stale > policy.max_stale_peers {
+            reasons.push(Reason::StalePeers {
+                iface: device.name,
+                stale,
+                total: device.peers.len(),
+            });
+        }
+    }
+
+    let status = reasons
+        .iter()
+        .map(|reason| reason.status(policy))
+        .max()
+        .unwrap_or(Status::Healthy);
+    Summary { status, reasons }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, PeerInfo};
+
+    fn device(name: &str, handshake_ages: &[Option<u64>], now: SystemTime) -> Device {
+        let peers = handshake_ages
+            .iter()
+            .enumerate()
+            .map(|(i, age)| {
+                let mut peer = PeerInfo::fixture(Key([i as u8; 32]));
+                peer.stats.last_handshake_time = age.map(|age| now - Duration::from_secs(age));
+                peer
+            })
+            .collect();
+        Device {
+            listen_port: Some(51820),
+            ..Device::fixture(name, peers)
+        }
+    }
+
+    #[test]
+    fn test_healthy() {
+        let now = SystemTime::now();
+        let devices = [device("wg0", &[Some(30), Some(100)], now)];
+        let summary = summarize_at(&devices, &Policy::default(), now);
+        assert!(summary.is_healthy());
+        assert_eq!(summary.to_string(), "healthy");
+    }
+
+    #[test]
+    fn test_degraded_and_down() {
+        let now = SystemTime::now();
+        let mut devices = vec![
+            device("wg0", &[Some(30), Some(600), None], now),
+            device("wg1", &[], now),
+        ];
+        devices[1].listen_port = None;
+        let summary = summarize_at(&devices, &Policy::default(), now);
+        assert_eq!(summary.status, Status::Degraded);
+        assert_eq!(
+            summary.to_string(),
+            "degraded: wg0: 2 of 3 peers stale; wg1: no listen port"
+        );
+
+        let policy = Policy {
+            expected: vec!["wg2".parse().unwrap()],
+            max_stale_peers: 2,
+            ..Default::default()
+        };
+        let summary = summarize_at(&devices[..1], &policy, now);
+        assert_eq!(summary.status, Status::Down);
+        assert_eq!(summary.reasons, vec![Reason::Missing(policy.expected[0])]);
+    }
+
+    #[test]
+    fn test_all_stale() {
+        let now = SystemTime::now();
+        let devices = [device("wg0", &[None], now)];
+        assert_eq!(
+            summarize_at(&devices, &Policy::default(), now).status,
+            Status::Down
+        );
+
+        let policy = Policy {
+            down_if_all_stale: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            summarize_at(&devices, &policy, now).status,
+            Status::Degraded
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Recent statistics of peers, kept in memory.
+//!
+//! A [`StatsHistory`] retains the last samples of each peer's [`PeerStats`] in a
+//! ring buffer, enough to answer "how much did this peer transfer in the last
+//! five minutes" for billing, or "how long has it been idle" for reaping peers,
+//! without an external time-series database. Counters that went down between
+//! samples, because the peer was removed and added again, count as reset.
+use crate::{Device, Key, PeerStats};
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, SystemTime},
+};
+
+/// The statistics of a peer at some time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub time: SystemTime,
+    pub stats: PeerStats,
+}
+
+fn counter_delta(previous: u64, current: u64) -> u64 {
+    current.checked_sub(previous).unwrap_or(current)
+}
+
+/// The last samples of every peer of an interface.
+#[derive(Debug, Clone)]
+pub struct StatsHistory {
+    capacity: usize,
+    peers: HashMap<Key, VecDeque<Sample>>,
+}
+
+impl StatsHistory {
+    /// Keeps up to `capacity` samples per peer, at least two. With a sample every
+    /// 10 seconds, 360 cover an hour.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(2),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Adds a sample of every peer of `device`, a snapshot taken at `now`. Peers
+    /// the device no longer has are forgotten.
+    pub fn record(&mut self, device: &Device, now: SystemTime) {
+        let present: HashSet<&Key> = device
+            .peers
+            .iter()
+            .map(|peer| &peer.config.public_key)
+            .collect();
+        self.peers.retain(|key, _| present.contains(key));
+        for peer in &device.peers {
+            self.record_peer(&peer.config.public_key, peer.stats.clone(), now);
+        }
+    }
+
+    /// Adds a sample of one peer, e.g. from [`PeerStats::get_all`].
+    ///
+    /// Only the first sample of a peer at `now` is kept, so a peer reported twice
+    /// in one read, such as one split across netlink messages, can't read as a
+    /// counter reset.
+    pub fn record_peer(&mut self, public_key: &Key, stats: PeerStats, now: SystemTime) {
+        let samples = self.peers.entry(public_key.clone()).or_default();
+        if samples.back().is_some_and(|sample| sample.time == now) {
+            return;
+        }
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(Sample { time: now, stats });
+    }
+
+    /// Drops the samples of a peer.
+    pub fn forget(&mut self, public_key: &Key) {
+        self.peers.remove(public_key);
+    }
+
+    /// The retained samples of a peer, oldest first.
+    pub fn samples(&self, public_key: &Key) -> impl Iterator<Item = &Sample> {
+        self.peers.get(public_key).into_iter().flatten()
+    }
+
+    /// The bytes received from and sent to a peer since `since`, or since its
+    /// oldest sample if that is later. `None` for peers without samples.
+    pub fn transferred_since(&self, public_key: &Key, since: SystemTime) -> Option<(u64, u64)> {
+        let samples = self.peers.get(public_key)?;
+        let start = samples
+            .iter()
+            .rposition(|sample| sample.time <= since)
+            .unwrap_or(0);
+        let transferred = samples
+            .iter()
+            .skip(start)
+            .zip(samples.iter().skip(start + 1))
+            .fold((0, 0), |(rx, tx), (previous, sample)| {
+                (
+                    rx + counter_delta(previous.stats.rx_bytes, sample.stats.rx_bytes),
+                    tx + counter_delta(previous.stats.tx_bytes, sample.stats.tx_bytes),
+                )
+            });
+        Some(transferred)
+    }
+
+    /// The bytes received from and sent to a peer in the `window` before its
+    /// latest sample.
+    pub fn transferred_in(&self, public_key: &Key, window: Duration) -> Option<(u64, u64)> {
+        let latest = self.peers.get(public_key)?.back()?.time;
+        let since = latest.checked_sub(window).unwrap_or(SystemTime::UNIX_EPOCH);
+        self.transferred_since(public_key, since)
+    }
+
+    /// When a peer was last seen passing traffic: the time of the latest sample
+    /// whose counters differ from the one before. `None` if they didn't change
+    /// over the retained samples.
+    pub fn last_traffic(&self, public_key: &Key) -> Option<SystemTime> {
+        let samples = self.peers.get(public_key)?;
+        samples
+            .iter()
+            .zip(samples.iter().skip(1))
+            .rev()
+            .find(|(previous, sample)| {
+                (previous.stats.rx_bytes, previous.stats.tx_bytes)
+                    != (sample.stats.rx_bytes, sample.stats.tx_bytes)
+            })
+            .map(|(_, sample)| sample.time)
+    }
+
+    /// How long a peer has been idle at `now`. Without traffic over the retained
+    /// samples, that's at least since the oldest one, which is returned instead.
+    pub fn idle_for(&self, public_key: &Key, now: SystemTime) -> Option<Duration> {
+        let since = self
+            .last_traffic(public_key)
+            .or_else(|| Some(self.peers.get(public_key)?.front()?.time))?;
+        Some(now.duration_since(since).unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn stats(rx_bytes: u64, tx_bytes: u64) -> PeerStats {
+        PeerStats {
+            last_handshake_time: None,
+            rx_bytes,
+            tx_bytes,
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let key = Key([1; 32]);
+        let mut history = StatsHistory::new(4);
+        for (time, rx, tx) in [(0, 100, 10), (60, 300, 20), (120, 300, 20), (180, 50, 5)] {
+            history.record_peer(&key, stats(rx, tx), at(time));
+        }
+        // Reset between the last two samples.
+        assert_eq!(history.transferred_since(&key, at(0)), Some((250, 15)));
+        assert_eq!(history.transferred_since(&key, at(90)), Some((50, 5)));
+        assert_eq!(
+            history.transferred_in(&key, Duration::from_secs(120)),
+            Some((50, 5))
+        );
+        assert_eq!(history.last_traffic(&key), Some(at(180)));
+        assert_eq!(history.transferred_since(&Key([2; 32]), at(0)), None);
+
+        // A second, empty entry of the peer in the same read is ignored.
+        history.record_peer(&key, stats(0, 0), at(180));
+        assert_eq!(history.samples(&key).count(), 4);
+        assert_eq!(history.transferred_since(&key, at(90)), Some((50, 5)));
+
+        // The oldest sample is dropped.
+        history.record_peer(&key, stats(50, 5), at(240));
+        history.record_peer(&key, stats(50, 5), at(300));
+        assert_eq!(history.samples(&key).count(), 4);
+        assert_eq!(
+            history.idle_for(&key, at(360)),
+            Some(Duration::from_secs(180))
+        );
+        history.record_peer(&key, stats(50, 5), at(360));
+        history.record_peer(&key, stats(50, 5), at(420));
+        assert_eq!(history.last_traffic(&key), None);
+        assert_eq!(
+            history.idle_for(&key, at(480)),
+            Some(Duration::from_secs(240))
+        );
+    }
+}
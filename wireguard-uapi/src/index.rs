@@ -0,0 +1,149 @@
+//! Indexes over the peers of a [`Device`], for tools doing many lookups.
+//!
+//! [`Device::find_peer`] scans every peer, which is fine for a lookup now and
+//! then but not for attributing millions of packets per second. A [`PeerIndex`]
+//! answers the same questions from a hash map of endpoints and a
+//! [`CryptokeyTable`] of allowed IPs. Each is built on its first use, so an index
+//! only ever queried by endpoint doesn't pay for the table.
+use crate::{cryptokey::CryptokeyTable, Device, PeerInfo};
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+};
+
+/// Lookups of the peers of a device by endpoint and by allowed IP.
+///
+/// Borrows the device, so it reflects the snapshot it was created from. It can be
+/// shared between threads, e.g. packet workers.
+#[derive(Debug)]
+pub struct PeerIndex<'a> {
+    device: &'a Device,
+    endpoints: OnceLock<HashMap<SocketAddr, u32>>,
+    endpoint_ips: OnceLock<HashMap<IpAddr, Vec<u32>>>,
+    allowed_ips: OnceLock<CryptokeyTable<u32>>,
+}
+
+impl<'a> PeerIndex<'a> {
+    pub fn new(device: &'a Device) -> Self {
+        Self {
+            device,
+            endpoints: OnceLock::new(),
+            endpoint_ips: OnceLock::new(),
+            allowed_ips: OnceLock::new(),
+        }
+    }
+
+    fn peer(&self, index: u32) -> &'a PeerInfo {
+        &self.device.peers[index as usize]
+    }
+
+    /// The peer at exactly this endpoint, like [`PeerQuery::Endpoint`](crate::PeerQuery::Endpoint).
+    pub fn by_endpoint(&self, endpoint: &SocketAddr) -> Option<&'a PeerInfo> {
+        let endpoints = self.endpoints.get_or_init(|| {
+            let peers = self.device.peers.iter().enumerate();
+            peers
+                .filter_map(|(i, peer)| Some((peer.config.endpoint?, i as u32)))
+                .collect()
+        });
+        endpoints.get(endpoint).map(|i| self.peer(*i))
+    }
+
+    /// The peers whose endpoint has this address, whatever the port, in the
+    /// order of the device.
+    pub fn by_endpoint_ip(&self, ip: &IpAddr) -> impl Iterator<Item = &'a PeerInfo> + '_ {
+        let endpoint_ips = self.endpoint_ips.get_or_init(|| {
+            let mut endpoint_ips: HashMap<IpAddr, Vec<u32>> = HashMap::new();
+            for (i, peer) in self.device.peers.iter().enumerate() {
+                if let Some(endpoint) = peer.config.endpoint {
+                    endpoint_ips
+                        .entry(endpoint.ip())
+                        .or_default()
+                        .push(i as u32);
+                }
+            }
+            endpoint_ips
+        });
+        endpoint_ips
+            .get(ip)
+            .into_iter()
+            .flatten()
+            .map(|i| self.peer(*i))
+    }
+
+    /// The peer whose allowed IPs route `ip`, by longest prefix match like the
+    /// kernel and [`PeerQuery::AllowedIp`](crate::PeerQuery::AllowedIp). Of peers
+    /// sharing a prefix, which a device read from the kernel never has, the
+    /// later one wins.
+    pub fn by_allowed_ip(&self, ip: &IpAddr) -> Option<&'a PeerInfo> {
+        let table = self.allowed_ips.get_or_init(|| {
+            let mut table = CryptokeyTable::new();
+            for (i, peer) in self.device.peers.iter().enumerate() {
+                for allowed_ip in &peer.config.allowed_ips {
+                    let _ = table.insert(allowed_ip, i as u32);
+                }
+            }
+            table
+        });
+        table.lookup(ip).map(|i| self.peer(*i))
+    }
+}
+
+impl Device {
+    /// An index over the peers of this device, for repeated lookups.
+    pub fn index(&self) -> PeerIndex<'_> {
+        PeerIndex::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, PeerQuery};
+
+    fn peer(key: u8, endpoint: Option<&str>, allowed_ips: &[&str]) -> PeerInfo {
+        let peer = PeerInfo::fixture(Key([key; 32])).with_allowed_ips(allowed_ips);
+        match endpoint {
+            Some(endpoint) => peer.with_endpoint(endpoint),
+            None => peer,
+        }
+    }
+
+    #[test]
+    fn test_index() {
+        let device = Device::fixture(
+            "wg0",
+            vec![
+                peer(1, Some("192.0.2.1:51820"), &["10.0.0.0/8", "fd00::/8"]),
+                peer(2, Some("192.0.2.1:51821"), &["10.1.0.0/16"]),
+                peer(3, None, &["10.1.2.3/32", "0.0.0.0/0"]),
+                peer(4, Some("[2001:db8::1]:51820"), &["fd00:1::/32"]),
+            ],
+        );
+        let index = device.index();
+        let key = |peer: Option<&PeerInfo>| peer.map(|peer| peer.config.public_key.0[0]);
+
+        assert_eq!(
+            key(index.by_endpoint(&"192.0.2.1:51821".parse().unwrap())),
+            Some(2)
+        );
+        assert_eq!(index.by_endpoint(&"192.0.2.1:1".parse().unwrap()), None);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(index.by_endpoint_ip(&ip).count(), 2);
+
+        for (ip, expected) in [
+            ("10.1.2.3", Some(3)),
+            ("10.1.2.4", Some(2)),
+            ("10.2.0.1", Some(1)),
+            ("192.168.0.1", Some(3)),
+            ("fd00:1::1", Some(4)),
+            ("fd00:2::1", Some(1)),
+            ("2001:db8::1", None),
+        ] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert_eq!(key(index.by_allowed_ip(&ip)), expected, "{}", ip);
+            assert_eq!(key(device.find_peer(PeerQuery::AllowedIp(ip))), expected);
+        }
+    }
+}
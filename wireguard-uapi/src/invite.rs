@@ -0,0 +1,608 @@
+//! Signed, time-limited peer invitations for onboarding without a central API.
+//!
+//! A node that may admit peers signs an [`Invitation`] (the invited public key,
+//! its allowed IPs and an expiry) with its own WireGuard private key and hands the
+//! resulting token out of band. Any node that trusts the issuer's public key can
+//! [`accept`] the token on its own, even while the issuer is offline, and
+//! [`prune_expired`] takes back what accepting it added once the invitation runs
+//! out.
+//!
+//! Signatures are [XEdDSA](https://signal.org/docs/specifications/xeddsa/)
+//! signatures, which are made with the X25519 keys WireGuard already uses, so no
+//! separate signing keys need to be distributed.
+use crate::{
+    allowed_ips,
+    clock::{Clock, SystemClock},
+    device::AllowedIp,
+    AllowedIpConflicts, Backend, Device, DeviceUpdate, Error, InterfaceName, Key,
+    PeerConfigBuilder,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use curve25519_dalek::{
+    constants::ED25519_BASEPOINT_TABLE,
+    edwards::{CompressedEdwardsY, EdwardsPoint},
+    montgomery::MontgomeryPoint,
+    scalar::Scalar,
+};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha512};
+use std::{
+    error, fmt, io,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The [context](sign_token) of invitation signatures.
+const CONTEXT: &[u8] = b"wireguard-uapi invitation v1\n";
+
+/// What an invitation admits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invitation {
+    /// The public key of the node that signed the invitation.
+    pub issuer: Key,
+    pub public_key: Key,
+    pub allowed_ips: Vec<AllowedIp>,
+    /// The peer isn't admitted from this time on.
+    pub expires: SystemTime,
+}
+
+/// Why an invitation token was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvitationError {
+    /// The token couldn't be decoded.
+    Malformed,
+    /// The signature doesn't match the issuer's key.
+    BadSignature,
+    /// The issuer isn't one of the trusted keys.
+    UntrustedIssuer,
+    Expired,
+}
+
+impl fmt::Display for InvitationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Malformed => "malformed invitation",
+            Self::BadSignature => "invitation signature is invalid",
+            Self::UntrustedIssuer => "invitation issuer is not trusted",
+            Self::Expired => "invitation has expired",
+        })
+    }
+}
+
+impl From<InvitationError> for io::Error {
+    fn from(e: InvitationError) -> Self {
+        let kind = match e {
+            InvitationError::Malformed => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::PermissionDenied,
+        };
+        io::Error::new(kind, e.to_string())
+    }
+}
+
+impl error::Error for InvitationError {}
+
+/// The hash XEdDSA derives its nonce with: SHA-512 with a distinct prefix.
+fn hash1(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new().chain([0xfe]).chain([0xff; 31]);
+    for part in parts {
+        hasher.update(part);
+    }
+    Scalar::from_hash(hasher)
+}
+
+fn challenge(r: &[u8], a: &[u8], message: &[u8]) -> Scalar {
+    Scalar::from_hash(Sha512::new().chain(r).chain(a).chain(message))
+}
+
+/// Signs `message` with the X25519 private key `private`.
+pub(crate) fn xeddsa_sign(private: &Key, message: &[u8]) -> [u8; 64] {
+    let k = Scalar::from_bytes_mod_order(private.0);
+    let e = &ED25519_BASEPOINT_TABLE * &k;
+    // The Edwards key matching the Montgomery one has its sign bit cleared.
+    let a = if e.compress().as_bytes()[31] & 0x80 != 0 {
+        -k
+    } else {
+        k
+    };
+    let public = (&ED25519_BASEPOINT_TABLE * &a).compress();
+
+    let mut z = [0u8; 64];
+    OsRng.fill_bytes(&mut z);
+    let r = hash1(&[a.as_bytes(), message, &z]);
+    let big_r = (&ED25519_BASEPOINT_TABLE * &r).compress();
+    let h = challenge(big_r.as_bytes(), public.as_bytes(), message);
+    let s = r + h * a;
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(big_r.as_bytes());
+    signature[32..].copy_from_slice(s.as_bytes());
+    signature
+}
+
+/// Whether `signature` is a signature over `message` by the X25519 public key
+/// `public`.
+pub(crate) fn xeddsa_verify(public: &Key, message: &[u8], signature: &[u8; 64]) -> bool {
+    let Some(a) = MontgomeryPoint(public.0).to_edwards(0) else {
+        return false;
+    };
+    let mut big_r = [0u8; 32];
+    big_r.copy_from_slice(&signature[..32]);
+    let mut s = [0u8; 32];
+    s.copy_from_slice(&signature[32..]);
+    let Some(s) = Scalar::from_canonical_bytes(s) else {
+        return false;
+    };
+    let h = challenge(&big_r, a.compress().as_bytes(), message);
+    let check = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-h, &a, &s);
+    check.compress() == CompressedEdwardsY(big_r)
+}
+
+/// Signs `payload` with `private`, returning the token carrying both: the payload
+/// and the signature in unpadded URL-safe base64, joined by a dot.
+///
+/// `context` is prefixed to the signed payload, so the signatures can't be
+/// replayed as signatures over anything else, e.g. another kind of token.
+pub(crate) fn sign_token(private: &Key, context: &[u8], payload: &str) -> String {
+    let signature = xeddsa_sign(private, &[context, payload.as_bytes()].concat());
+    format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(payload),
+        URL_SAFE_NO_PAD.encode(signature)
+    )
+}
+
+/// Decodes a token made by [`sign_token`] into its payload and signature,
+/// without checking the signature.
+pub(crate) fn open_token(token: &str) -> Option<(String, [u8; 64])> {
+    let (payload, signature) = token.trim().split_once('.')?;
+    let payload = String::from_utf8(URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?.try_into().ok()?;
+    Some((payload, signature))
+}
+
+/// Whether `signature` is `public`'s signature over `payload` made by
+/// [`sign_token`] with `context`.
+pub(crate) fn verify_token(
+    public: &Key,
+    context: &[u8],
+    payload: &str,
+    signature: &[u8; 64],
+) -> bool {
+    xeddsa_verify(public, &[context, payload.as_bytes()].concat(), signature)
+}
+
+/// The `Name = value` lines of a token's payload, read in order.
+pub(crate) struct Fields<'a>(std::str::Lines<'a>);
+
+impl<'a> Fields<'a> {
+    pub(crate) fn new(payload: &'a str) -> Self {
+        Self(payload.lines())
+    }
+
+    /// The value of the next line, if it's the one of `name`.
+    pub(crate) fn field(&mut self, name: &str) -> Option<&'a str> {
+        match self.0.next()?.split_once(" = ") {
+            Some((key, value)) if key == name => Some(value),
+            _ => None,
+        }
+    }
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Invitation {
+    /// Invites `public_key` with `allowed_ips` for `validity` from now. The issuer
+    /// is filled in when signing.
+    pub fn new(public_key: Key, allowed_ips: Vec<AllowedIp>, validity: Duration) -> Self {
+        Self::new_with_clock(public_key, allowed_ips, validity, &SystemClock)
+    }
+
+    /// Like [`new`](Self::new), with the validity starting at `clock`'s now.
+    pub fn new_with_clock(
+        public_key: Key,
+        allowed_ips: Vec<AllowedIp>,
+        validity: Duration,
+        clock: &dyn Clock,
+    ) -> Self {
+        Self {
+            issuer: Key::zero(),
+            public_key,
+            allowed_ips,
+            expires: clock.now() + validity,
+        }
+    }
+
+    /// The signed text. Expiry is kept to the second.
+    fn payload(&self) -> String {
+        let allowed_ips: Vec<_> = self
+            .allowed_ips
+            .iter()
+            .map(|ip| format!("{}/{}", ip.address, ip.cidr))
+            .collect();
+        format!(
+            "Issuer = {}\nPublicKey = {}\nAllowedIPs = {}\nExpires = {}\n",
+            self.issuer.to_base64(),
+            self.public_key.to_base64(),
+            allowed_ips.join(", "),
+            secs(self.expires)
+        )
+    }
+
+    fn from_payload(payload: &str) -> Result<Self, InvitationError> {
+        let mut fields = Fields::new(payload);
+        let mut field = |name: &str| fields.field(name).ok_or(InvitationError::Malformed);
+        let key = |value: &str| Key::from_base64(value).map_err(|_| InvitationError::Malformed);
+        let issuer = key(field("Issuer")?)?;
+        let public_key = key(field("PublicKey")?)?;
+        let allowed_ips = field("AllowedIPs")?
+            .split(", ")
+            .filter(|ip| !ip.is_empty())
+            .map(AllowedIp::from_str)
+            .collect::<Result<_, _>>()
+            .map_err(|_| InvitationError::Malformed)?;
+        let expires = field("Expires")?
+            .parse()
+            .ok()
+            .and_then(|expires| UNIX_EPOCH.checked_add(Duration::from_secs(expires)))
+            .ok_or(InvitationError::Malformed)?;
+        Ok(Self {
+            issuer,
+            public_key,
+            allowed_ips,
+            expires,
+        })
+    }
+
+    /// Signs the invitation with the issuer's private key, returning the token to
+    /// hand to the accepting nodes.
+    pub fn sign(mut self, issuer: &Key) -> String {
+        self.issuer = issuer.get_public();
+        sign_token(issuer, CONTEXT, &self.payload())
+    }
+
+    /// Checks `token`'s signature, issuer and expiry as of `now`, returning the
+    /// invitation it carries.
+    pub fn verify(token: &str, trusted: &[Key], now: SystemTime) -> Result<Self, InvitationError> {
+        let (payload, signature) = open_token(token).ok_or(InvitationError::Malformed)?;
+        let invitation = Self::from_payload(&payload)?;
+
+        if !trusted.contains(&invitation.issuer) {
+            return Err(InvitationError::UntrustedIssuer);
+        }
+        if !verify_token(&invitation.issuer, CONTEXT, &payload, &signature) {
+            return Err(InvitationError::BadSignature);
+        }
+        if now >= invitation.expires {
+            return Err(InvitationError::Expired);
+        }
+        Ok(invitation)
+    }
+
+    /// The peer the invitation admits.
+    pub fn to_peer(&self) -> PeerConfigBuilder {
+        PeerConfigBuilder::new(&self.public_key).add_allowed_ips(&self.allowed_ips)
+    }
+}
+
+/// An invitation [accepted](accept) on an interface, with what accepting it
+/// added, which is all [`prune_expired`] takes back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Accepted {
+    pub invitation: Invitation,
+    /// Whether the invited peer was added, rather than already configured.
+    pub added_peer: bool,
+    /// The invited allowed IPs the peer didn't have before.
+    pub added_ips: Vec<AllowedIp>,
+}
+
+/// Verifies `token` against the `trusted` issuers and adds the invited peer to
+/// `iface`. Keep the returned [`Accepted`] to [prune](prune_expired) the peer once
+/// the invitation expires.
+///
+/// Fails if another peer already has one of the invited allowed IPs, so an
+/// invitation can't take over an existing peer's traffic. Inviting a peer that is
+/// already configured only adds the allowed IPs it lacks.
+pub fn accept(
+    token: &str,
+    trusted: &[Key],
+    iface: &InterfaceName,
+    backend: Backend,
+) -> io::Result<Accepted> {
+    accept_with_clock(token, trusted, iface, backend, &SystemClock)
+}
+
+/// Like [`accept`], checking the expiry against `clock`.
+pub fn accept_with_clock(
+    token: &str,
+    trusted: &[Key],
+    iface: &InterfaceName,
+    backend: Backend,
+    clock: &dyn Clock,
+) -> io::Result<Accepted> {
+    let invitation = Invitation::verify(token, trusted, clock.now())?;
+    let device = match Device::get(iface, backend) {
+        Ok(device) => Some(device),
+        Err(Error::InterfaceNotFound(_)) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let existing = device.as_ref().and_then(|device| {
+        device
+            .peers
+            .iter()
+            .find(|peer| peer.config.public_key == invitation.public_key)
+    });
+    // Compared as the kernel stores them, without host bits. Invalid ones fail
+    // the update below.
+    let added_ips = invitation
+        .allowed_ips
+        .iter()
+        .filter_map(|ip| allowed_ips::normalize(ip).ok())
+        .filter(|ip| !existing.is_some_and(|peer| peer.config.allowed_ips.contains(ip)))
+        .collect();
+    DeviceUpdate::new()
+        .on_allowed_ip_conflict(AllowedIpConflicts::Error)
+        .add_peer(invitation.to_peer())
+        .apply(iface, backend)?;
+    Ok(Accepted {
+        added_peer: existing.is_none(),
+        added_ips,
+        invitation,
+    })
+}
+
+/// Takes back from `iface` what the `accepted` invitations that expired as of
+/// `now` added, returning the keys of the peers removed. A peer that was already
+/// configured keeps its own allowed IPs and only loses the invited ones.
+///
+/// The rest of the invitations are kept in `accepted`. What an expired invitation
+/// added stays while another one admits the same peer, which takes it over.
+pub fn prune_expired(
+    accepted: &mut Vec<Accepted>,
+    iface: &InterfaceName,
+    backend: Backend,
+    now: SystemTime,
+) -> io::Result<Vec<Key>> {
+    let (expired, valid): (Vec<_>, Vec<_>) = accepted
+        .drain(..)
+        .partition(|entry| now >= entry.invitation.expires);
+    *accepted = valid;
+
+    // What the expired invitations added, merged per peer.
+    let mut lapsed: Vec<Accepted> = vec![];
+    for expired in expired {
+        let key = &expired.invitation.public_key;
+        let owner = if let Some(i) = accepted
+            .iter()
+            .position(|valid| valid.invitation.public_key == *key)
+        {
+            &mut accepted[i]
+        } else if let Some(i) = lapsed
+            .iter()
+            .position(|lapsed| lapsed.invitation.public_key == *key)
+        {
+            &mut lapsed[i]
+        } else {
+            lapsed.push(expired);
+            continue;
+        };
+        owner.added_peer |= expired.added_peer;
+        for ip in expired.added_ips {
+            if !owner.added_ips.contains(&ip) {
+                owner.added_ips.push(ip);
+            }
+        }
+    }
+
+    let device = Device::get(iface, backend)?;
+    let mut update = DeviceUpdate::new();
+    let mut changed = false;
+    let mut removed = vec![];
+    for lapsed in lapsed {
+        let key = &lapsed.invitation.public_key;
+        let Some(peer) = device
+            .peers
+            .iter()
+            .find(|peer| peer.config.public_key == *key)
+        else {
+            continue;
+        };
+        if lapsed.added_peer {
+            update = update.remove_peer_by_key(key);
+            removed.push(key.clone());
+        } else {
+            let kept: Vec<_> = peer
+                .config
+                .allowed_ips
+                .iter()
+                .filter(|ip| !lapsed.added_ips.contains(ip))
+                .cloned()
+                .collect();
+            if kept.len() == peer.config.allowed_ips.len() {
+                continue;
+            }
+            update = update.add_peer(
+                PeerConfigBuilder::new(key)
+                    .replace_allowed_ips()
+                    .add_allowed_ips(&kept),
+            );
+        }
+        changed = true;
+    }
+    if changed {
+        update.apply(iface, backend)?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xeddsa() {
+        let private = Key::generate_private();
+        let public = private.get_public();
+        let signature = xeddsa_sign(&private, b"message");
+        assert!(xeddsa_verify(&public, b"message", &signature));
+        assert!(!xeddsa_verify(&public, b"massage", &signature));
+        assert!(!xeddsa_verify(
+            &Key::generate_private().get_public(),
+            b"message",
+            &signature
+        ));
+    }
+
+    #[test]
+    fn test_invitation() {
+        let issuer = Key::generate_private();
+        let trusted = [issuer.get_public()];
+        let invited = Key::generate_private().get_public();
+        let invitation = Invitation::new(
+            invited.clone(),
+            vec![
+                "10.0.0.2/32".parse().unwrap(),
+                "fd00::2/128".parse().unwrap(),
+            ],
+            Duration::from_secs(3600),
+        );
+        let token = invitation.clone().sign(&issuer);
+
+        let now = SystemTime::now();
+        let verified = Invitation::verify(&token, &trusted, now).unwrap();
+        assert_eq!(verified.issuer, trusted[0]);
+        assert_eq!(verified.public_key, invited);
+        assert_eq!(verified.allowed_ips, invitation.allowed_ips);
+        assert_eq!(verified.to_peer().allowed_ips.len(), 2);
+
+        assert_eq!(
+            Invitation::verify(&token, &trusted, now + Duration::from_secs(3600)),
+            Err(InvitationError::Expired)
+        );
+        assert_eq!(
+            Invitation::verify(&token, &[invited], now),
+            Err(InvitationError::UntrustedIssuer)
+        );
+        assert_eq!(
+            Invitation::verify("not a token", &trusted, now),
+            Err(InvitationError::Malformed)
+        );
+
+        // Extending the invitation invalidates the signature.
+        let (_, signature) = token.split_once('.').unwrap();
+        let mut forged = verified;
+        forged.expires += Duration::from_secs(3600);
+        let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode(forged.payload()), signature);
+        assert_eq!(
+            Invitation::verify(&forged, &trusted, now),
+            Err(InvitationError::BadSignature)
+        );
+
+        // An expiry past what SystemTime holds is malformed, even untrusted.
+        let payload = invitation
+            .payload()
+            .lines()
+            .map(|line| match line.starts_with("Expires") {
+                true => format!("Expires = {}\n", u64::MAX),
+                false => format!("{line}\n"),
+            })
+            .collect::<String>();
+        let huge = format!("{}.{}", URL_SAFE_NO_PAD.encode(payload), signature);
+        assert_eq!(
+            Invitation::verify(&huge, &[], now),
+            Err(InvitationError::Malformed)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn test_accept_and_prune() {
+        let iface: InterfaceName = "mock-invite".parse().unwrap();
+        DeviceUpdate::new().apply(&iface, Backend::Mock).unwrap();
+        let issuer = Key::generate_private();
+        let trusted = [issuer.get_public()];
+        let invited = Key::generate_private().get_public();
+        let allowed_ips = vec!["10.0.0.2/32".parse().unwrap()];
+        let short = Invitation::new(
+            invited.clone(),
+            allowed_ips.clone(),
+            Duration::from_secs(60),
+        );
+        let long = Invitation::new(
+            invited.clone(),
+            allowed_ips.clone(),
+            Duration::from_secs(3600),
+        );
+
+        let mut accepted = vec![
+            accept(&short.sign(&issuer), &trusted, &iface, Backend::Mock).unwrap(),
+            accept(&long.sign(&issuer), &trusted, &iface, Backend::Mock).unwrap(),
+        ];
+
+        // Another peer can't be invited onto the same addresses.
+        let other = Invitation::new(
+            Key::generate_private().get_public(),
+            allowed_ips,
+            Duration::from_secs(3600),
+        );
+        assert!(accept(&other.sign(&issuer), &trusted, &iface, Backend::Mock).is_err());
+
+        // The peer outlives its first invitation while the second is valid.
+        let later = SystemTime::now() + Duration::from_secs(600);
+        let pruned = prune_expired(&mut accepted, &iface, Backend::Mock, later).unwrap();
+        assert!(pruned.is_empty());
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(Device::get(&iface, Backend::Mock).unwrap().peers.len(), 1);
+
+        let much_later = later + Duration::from_secs(3600);
+        let pruned = prune_expired(&mut accepted, &iface, Backend::Mock, much_later).unwrap();
+        assert_eq!(pruned, [invited]);
+        assert!(Device::get(&iface, Backend::Mock).unwrap().peers.is_empty());
+        Device::get(&iface, Backend::Mock)
+            .unwrap()
+            .delete()
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn test_prune_configured_peer() {
+        let iface: InterfaceName = "mock-invite-kept".parse().unwrap();
+        let permanent = Key::generate_private().get_public();
+        let own: AllowedIp = "10.0.0.3/32".parse().unwrap();
+        let invited: AllowedIp = "10.0.0.4/32".parse().unwrap();
+        DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(&permanent).add_allowed_ip(own.address, own.cidr))
+            .apply(&iface, Backend::Mock)
+            .unwrap();
+        let issuer = Key::generate_private();
+        let invitation = Invitation::new(
+            permanent.clone(),
+            vec![own.clone(), invited.clone()],
+            Duration::from_secs(60),
+        );
+
+        let accepted = accept(
+            &invitation.sign(&issuer),
+            &[issuer.get_public()],
+            &iface,
+            Backend::Mock,
+        )
+        .unwrap();
+        assert!(!accepted.added_peer);
+        assert_eq!(accepted.added_ips, [invited]);
+
+        // Expiring takes back the invited address, not the peer.
+        let later = SystemTime::now() + Duration::from_secs(600);
+        let pruned = prune_expired(&mut vec![accepted], &iface, Backend::Mock, later).unwrap();
+        assert!(pruned.is_empty());
+        let device = Device::get(&iface, Backend::Mock).unwrap();
+        assert_eq!(device.peers.len(), 1);
+        assert_eq!(device.peers[0].config.allowed_ips, [own]);
+        device.delete().unwrap();
+    }
+}
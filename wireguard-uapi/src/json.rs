@@ -0,0 +1,113 @@
+//! Helpers for the JSON the crate writes by hand, e.g. reports and records.
+use crate::Device;
+
+use std::{fmt::Write as _, time::UNIX_EPOCH};
+
+/// `s` as a JSON string, escaping quotes, backslashes and control characters.
+pub(crate) fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                write!(out, "\\u{:04x}", c as u32).ok();
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `device` as the object documented on [`Device::to_json`].
+pub(crate) fn device(device: &Device) -> String {
+    let peers: Vec<_> = device
+        .peers
+        .iter()
+        .map(|peer| {
+            let allowed_ips: Vec<_> = peer
+                .config
+                .allowed_ips
+                .iter()
+                .map(|ip| quote(&format!("{}/{}", ip.address, ip.cidr)))
+                .collect();
+            format!(
+                "{{\"public_key\":{},\"preshared_key\":{},\"endpoint\":{},\"allowed_ips\":[{}],\"persistent_keepalive_interval\":{},\"last_handshake_time\":{},\"rx_bytes\":{},\"tx_bytes\":{}}}",
+                quote(&peer.config.public_key.to_base64()),
+                peer.config.preshared_key.is_some(),
+                or_null(peer.config.endpoint.map(|endpoint| quote(&endpoint.to_string()))),
+                allowed_ips.join(","),
+                or_null(peer.config.persistent_keepalive_interval),
+                or_null(peer.stats.last_handshake_time.map(|time| {
+                    time.duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs()
+                })),
+                peer.stats.rx_bytes,
+                peer.stats.tx_bytes
+            )
+        })
+        .collect();
+    format!(
+        "{{\"interface\":{},\"backend\":{},\"public_key\":{},\"listen_port\":{},\"fwmark\":{},\"peers\":[{}]}}",
+        quote(&device.name.as_str_lossy()),
+        quote(&device.backend.to_string()),
+        or_null(device.public_key.as_ref().map(|key| quote(&key.to_base64()))),
+        or_null(device.listen_port),
+        or_null(device.fwmark),
+        peers.join(",")
+    )
+}
+
+/// `value` as written by its `ToString`, or `null`.
+pub(crate) fn or_null(value: Option<impl ToString>) -> String {
+    value.map_or_else(|| "null".to_string(), |value| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, PeerInfo};
+    use std::time::Duration;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("wg0"), "\"wg0\"");
+        assert_eq!(quote("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(quote("a\nb\tc\u{1}\u{7f}"), "\"a\\nb\\tc\\u0001\\u007f\"");
+        assert_eq!(quote("é"), "\"é\"");
+    }
+
+    #[test]
+    fn test_device() {
+        let mut peer = PeerInfo::fixture(Key([3u8; 32]))
+            .with_allowed_ips(&["10.0.0.2/32"])
+            .with_handshake(UNIX_EPOCH + Duration::from_secs(1000))
+            .with_transfer(2048, 512);
+        peer.config.preshared_key = Some(Key([4u8; 32]));
+        let mut device = Device {
+            public_key: Some(Key([1u8; 32])),
+            private_key: Some(Key([2u8; 32])),
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", vec![peer])
+        };
+        assert_eq!(
+            super::device(&device),
+            "{\"interface\":\"wg0\",\"backend\":\"userspace\",\
+             \"public_key\":\"AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=\",\
+             \"listen_port\":51820,\"fwmark\":null,\"peers\":[{\
+             \"public_key\":\"AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=\",\
+             \"preshared_key\":true,\"endpoint\":null,\"allowed_ips\":[\"10.0.0.2/32\"],\
+             \"persistent_keepalive_interval\":null,\"last_handshake_time\":1000,\
+             \"rx_bytes\":2048,\"tx_bytes\":512}]}"
+        );
+
+        device.peers.clear();
+        assert!(super::device(&device).ends_with("\"peers\":[]}"));
+    }
+}
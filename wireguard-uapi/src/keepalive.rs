@@ -0,0 +1,191 @@
+//! Staggered persistent keepalive intervals for large peer sets.
+//!
+//! Giving thousands of peers the same keepalive interval makes their keepalives
+//! line up after every restart, which shows up on concentrators as periodic
+//! bursts of CPU and traffic. A [`KeepaliveSchedule`] spreads the intervals
+//! around a base, e.g. 25±3 seconds, so the bursts drift apart. Each peer's
+//! interval is derived from its public key, so regenerating the configs doesn't
+//! change it.
+//!
+//! Only peers that have keepalives on are staggered: peers without them, e.g.
+//! ones that aren't behind NAT, are left without.
+use crate::{
+    conf::{ConfFile, SectionKind},
+    DeviceUpdate, Key,
+};
+
+use sha2::{Digest, Sha256};
+
+/// Keepalive intervals of `base` seconds, plus or minus up to `jitter`. A base
+/// of 0 turns keepalives off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveSchedule {
+    pub base: u16,
+    pub jitter: u16,
+}
+
+impl Default for KeepaliveSchedule {
+    /// 25±3 seconds, around the interval wg(8) suggests for peers behind NAT.
+    fn default() -> Self {
+        Self::new(25, 3)
+    }
+}
+
+impl KeepaliveSchedule {
+    pub fn new(base: u16, jitter: u16) -> Self {
+        Self { base, jitter }
+    }
+
+    /// Whether the schedule turns keepalives off.
+    pub fn is_off(&self) -> bool {
+        self.base == 0
+    }
+
+    /// The range of intervals, never below a second since an interval of 0 turns
+    /// keepalives off, or `(0, 0)` if the schedule [is off](Self::is_off).
+    pub fn range(&self) -> (u16, u16) {
+        if self.is_off() {
+            return (0, 0);
+        }
+        (
+            self.base.saturating_sub(self.jitter).max(1),
+            self.base.saturating_add(self.jitter),
+        )
+    }
+
+    /// The interval of the peer with `public_key`.
+    pub fn interval(&self, public_key: &Key) -> u16 {
+        let (low, high) = self.range();
+        let hash = Sha256::new()
+            .chain(b"wireguard-uapi keepalive\n")
+            .chain(public_key.as_bytes())
+            .finalize();
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&hash[..8]);
+        let span = (high - low) as u64 + 1;
+        low + (u64::from_be_bytes(bytes) % span) as u16
+    }
+
+    /// Sets the keepalive interval of every peer of `update` that sets a non-zero
+    /// one. Peers being removed are skipped.
+    pub fn apply_to_update(&self, mut update: DeviceUpdate) -> DeviceUpdate {
+        for peer in &mut update.peers {
+            let on = peer.persistent_keepalive_interval.is_some_and(|i| i != 0);
+            if on && !peer.remove_me {
+                peer.persistent_keepalive_interval = Some(self.interval(&peer.public_key));
+            }
+        }
+        update
+    }
+
+    /// Sets the `PersistentKeepalive` of every peer of `conf` that has a non-zero
+    /// one. Peers without a valid public key are left alone.
+    pub fn apply_to_conf(&self, conf: &mut ConfFile) {
+        for section in &mut conf.sections {
+            if section.kind != SectionKind::Peer {
+                continue;
+            }
+            let on = section
+                .get("PersistentKeepalive")
+                .and_then(|interval| interval.parse::<u16>().ok())
+                .is_some_and(|interval| interval != 0);
+            if !on {
+                continue;
+            }
+            let Some(key) = section
+                .get("PublicKey")
+                .and_then(|key| Key::from_base64(key).ok())
+            else {
+                continue;
+            };
+            let interval = self.interval(&key);
+            section.set("PersistentKeepalive", interval.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerConfigBuilder;
+
+    #[test]
+    fn test_intervals() {
+        let schedule = KeepaliveSchedule::default();
+        let keys: Vec<_> = (0..=255u8).map(|i| Key([i; 32])).collect();
+        let intervals: Vec<_> = keys.iter().map(|key| schedule.interval(key)).collect();
+        assert!(intervals.iter().all(|i| (22..=28).contains(i)));
+        // Spread over the whole range, and stable.
+        for interval in 22..=28 {
+            assert!(intervals.contains(&interval));
+        }
+        assert_eq!(schedule.interval(&keys[7]), intervals[7]);
+
+        assert_eq!(KeepaliveSchedule::new(2, 5).range(), (1, 7));
+        let off = KeepaliveSchedule::new(0, 0);
+        assert!(off.is_off());
+        assert_eq!(off.range(), (0, 0));
+        assert_eq!(KeepaliveSchedule::new(0, 3).interval(&keys[7]), 0);
+    }
+
+    #[test]
+    fn test_apply() {
+        let schedule = KeepaliveSchedule::new(25, 0);
+        let update = schedule.apply_to_update(
+            DeviceUpdate::new()
+                .add_peer(
+                    PeerConfigBuilder::new(&Key([1; 32])).set_persistent_keepalive_interval(10),
+                )
+                .add_peer(PeerConfigBuilder::new(&Key([2; 32])))
+                .add_peer(
+                    PeerConfigBuilder::new(&Key([3; 32])).set_persistent_keepalive_interval(0),
+                )
+                .add_peer(
+                    PeerConfigBuilder::new(&Key([4; 32]))
+                        .set_persistent_keepalive_interval(10)
+                        .remove(),
+                ),
+        );
+        let intervals: Vec<_> = update
+            .peers
+            .iter()
+            .map(|peer| peer.persistent_keepalive_interval)
+            .collect();
+        assert_eq!(intervals, [Some(25), None, Some(0), Some(10)]);
+
+        let mut conf: ConfFile = format!(
+            "[Interface]\nListenPort = 51820\n\
+             [Peer]\nPublicKey = {}\nPersistentKeepalive = 10\n\
+             [Peer]\nPublicKey = {}\n",
+            Key([1; 32]).to_base64(),
+            Key([2; 32]).to_base64(),
+        )
+        .parse()
+        .unwrap();
+        let schedule = KeepaliveSchedule::new(25, 5);
+        schedule.apply_to_conf(&mut conf);
+        let intervals: Vec<_> = conf
+            .peers()
+            .map(|peer| peer.get("PersistentKeepalive"))
+            .collect();
+        assert_eq!(
+            intervals,
+            [
+                Some(schedule.interval(&Key([1; 32])).to_string().as_str()),
+                None
+            ]
+        );
+        assert!(conf
+            .interface()
+            .unwrap()
+            .get("PersistentKeepalive")
+            .is_none());
+
+        // An off schedule turns keepalives off.
+        KeepaliveSchedule::new(0, 0).apply_to_conf(&mut conf);
+        assert_eq!(
+            conf.peers().next().unwrap().get("PersistentKeepalive"),
+            Some("0")
+        );
+    }
+}
@@ -1,5 +1,8 @@
-use rand_core::RngCore;
+#[cfg(feature = "caller-rng")]
+use rand_core::CryptoRng;
+use rand_core::{OsRng, RngCore};
 use std::{ffi::NulError, fmt};
+use zeroize::{Zeroize, Zeroizing};
 
 /// Represents an error in base64 key parsing.
 #[derive(Eq, PartialEq, Debug, Clone)]
@@ -27,28 +30,82 @@ impl From<NulError> for InvalidKey {
 ///
 /// This means that you need to be careful when working with
 /// `Key`s, especially ones created from external data.
-#[derive(PartialEq, Eq, Clone)]
+///
+/// The bytes are wiped from memory when a key is dropped, so private and
+/// preshared keys don't linger in freed memory, and `Debug` doesn't print them.
+/// The backends wipe the requests and responses carrying keys as well. What
+/// isn't wiped:
+///
+/// - copies taken out of [`Key::0`](Key) or [`to_base64`](Key::to_base64) by
+///   callers,
+/// - the output of `wg` as read by [`std::process`] in the CLI backend, and
+///   the vectors `netlink_packet_wireguard` grows while parsing responses, before
+///   the kernel backend gets hold of them,
+/// - config text built by [`ConfFile`](crate::conf::ConfFile) and the other
+///   `Display` impls, except [`to_wg_quick_config`](crate::Device::to_wg_quick_config).
+#[derive(PartialEq, Eq, Hash, Clone)]
 pub struct Key(pub [u8; 32]);
 
+impl Zeroize for Key {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
 impl Key {
-    /// Generates and returns a new private key.
-    pub fn generate_private() -> Self {
+    fn private_from(rng: &mut impl RngCore) -> Self {
         let mut bytes = [0u8; 32];
-        rand_core::OsRng.fill_bytes(&mut bytes);
+        rng.fill_bytes(&mut bytes);
 
         // Apply key clamping.
         bytes[0] &= 248;
         bytes[31] &= 127;
         bytes[31] |= 64;
-        Self(bytes)
+        let key = Self(bytes);
+        bytes.zeroize();
+        key
+    }
+
+    fn preshared_from(rng: &mut impl RngCore) -> Self {
+        let mut key = Self::zero();
+        rng.fill_bytes(&mut key.0);
+        key
+    }
+
+    /// Generates and returns a new private key.
+    pub fn generate_private() -> Self {
+        Self::private_from(&mut OsRng)
+    }
+
+    /// Generates a new private key from `rng` instead of the operating system's
+    /// random source, e.g. a FIPS-validated DRBG or a seeded RNG in simulations.
+    ///
+    /// Only available with the `caller-rng` feature.
+    #[cfg(feature = "caller-rng")]
+    pub fn generate_private_with<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        Self::private_from(rng)
     }
 
     /// Generates and returns a new preshared key.
     #[must_use]
     pub fn generate_preshared() -> Self {
-        let mut key = [0u8; 32];
-        rand_core::OsRng.fill_bytes(&mut key);
-        Self(key)
+        Self::preshared_from(&mut OsRng)
+    }
+
+    /// Generates a new preshared key from `rng`, like
+    /// [`generate_private_with`](Key::generate_private_with).
+    ///
+    /// Only available with the `caller-rng` feature.
+    #[cfg(feature = "caller-rng")]
+    #[must_use]
+    pub fn generate_preshared_with<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        Self::preshared_from(rng)
     }
 
     /// Generates a public key for this private key.
@@ -84,21 +141,79 @@ impl Key {
     /// This can fail, as not all text input is valid base64 - in this case
     /// `Err(InvalidKey)` is returned.
     pub fn from_base64(key: &str) -> Result<Self, InvalidKey> {
-        let mut key_bytes = [0u8; 32];
-        let decoded_bytes = base64::decode(key).map_err(|_| InvalidKey)?;
+        let mut key_bytes = Self::zero();
+        let decoded_bytes = Zeroizing::new(base64::decode(key).map_err(|_| InvalidKey)?);
 
         if decoded_bytes.len() != 32 {
             return Err(InvalidKey);
         }
 
-        key_bytes.copy_from_slice(&decoded_bytes[..]);
-        Ok(Self(key_bytes))
+        key_bytes.0.copy_from_slice(&decoded_bytes[..]);
+        Ok(key_bytes)
     }
 
     pub fn from_hex(hex_str: &str) -> Result<Self, InvalidKey> {
-        let mut sized_bytes = [0u8; 32];
-        hex::decode_to_slice(hex_str, &mut sized_bytes).map_err(|_| InvalidKey)?;
-        Ok(Self(sized_bytes))
+        let mut sized_bytes = Self::zero();
+        hex::decode_to_slice(hex_str, &mut sized_bytes.0).map_err(|_| InvalidKey)?;
+        Ok(sized_bytes)
+    }
+}
+
+/// Keys are (de)serialized as base64 strings, like in configuration files.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Key {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_base64())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Key {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = Zeroizing::new(String::deserialize(deserializer)?);
+        Key::from_base64(&key).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A buffer for text carrying keys, wiped when dropped. It grows by moving into
+/// a larger buffer and wiping the old one, where a `String` would reallocate and
+/// free the old buffer with the keys still in it.
+pub(crate) struct SecretBuf(Zeroizing<String>);
+
+impl Default for SecretBuf {
+    fn default() -> Self {
+        Self(Zeroizing::new(String::new()))
+    }
+}
+
+impl SecretBuf {
+    pub(crate) fn push_str(&mut self, s: &str) {
+        if self.0.capacity() - self.0.len() < s.len() {
+            let capacity = (self.0.len() + s.len()).max(64) * 2;
+            let mut grown = Zeroizing::new(String::with_capacity(capacity));
+            grown.push_str(&self.0);
+            self.0 = grown;
+        }
+        self.0.push_str(s);
+    }
+
+    pub(crate) fn into_inner(self) -> Zeroizing<String> {
+        self.0
+    }
+}
+
+impl fmt::Write for SecretBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+impl std::ops::Deref for SecretBuf {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
     }
 }
 
@@ -106,6 +221,24 @@ impl Key {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_debug_redacted() {
+        let key = Key::generate_private();
+        assert!(!format!("{:?}", Some(&key)).contains(&key.to_base64()));
+    }
+
+    #[test]
+    fn test_secret_buf() {
+        use std::fmt::Write;
+
+        let mut buf = SecretBuf::default();
+        for i in 0..100 {
+            write!(buf, "{},", i).unwrap();
+        }
+        assert!(buf.starts_with("0,1,2,") && buf.ends_with("98,99,"));
+        assert!(buf.into_inner().capacity() >= 290);
+    }
+
     #[test]
     fn test_pubkey_generation() {
         let privy_key = "SGb+ojrRNDuMePufwtIYhXzA//k6wF3R21tEBgKlzlM=";
@@ -117,6 +250,13 @@ mod test {
         assert_eq!(public.to_base64(), pubkey);
     }
 
+    #[test]
+    fn test_zeroize() {
+        let mut key = Key::generate_private();
+        key.zeroize();
+        assert_eq!(key, Key::zero());
+    }
+
     #[test]
     fn test_rng_sanity_private() {
         let first = Key::generate_private();
@@ -152,17 +292,27 @@ pub struct KeyPair {
     pub public: Key,
 }
 
+/// Redacted, as the key may be a private or preshared one; print
+/// [`to_base64`](Key::to_base64) for public keys.
 impl fmt::Debug for Key {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Key(\"{}\")", self.to_base64())
+        f.write_str("Key(<redacted>)")
     }
 }
 
 impl KeyPair {
     pub fn generate() -> Self {
-        let private = Key::generate_private();
-        let public = private.get_public();
-        KeyPair { private, public }
+        Self::from_private(Key::generate_private())
+    }
+
+    /// Generates a keypair from `rng` instead of the operating system's random
+    /// source.
+    ///
+    /// Only available with the `caller-rng` feature, like the other `_with`
+    /// constructors, so builds that leave it off always take keys from the OS.
+    #[cfg(feature = "caller-rng")]
+    pub fn generate_with<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        Self::from_private(Key::generate_private_with(rng))
     }
 
     pub fn from_private(key: Key) -> Self {
@@ -172,6 +322,30 @@ impl KeyPair {
             public,
         }
     }
+
+    /// Deterministically derives a keypair from `seed`, for tests and reproducible
+    /// fixtures only.
+    ///
+    /// The same seed always yields the same keypair, so anyone who knows the seed
+    /// knows the private key: never use this for real interfaces, use
+    /// [`generate`](KeyPair::generate) instead. Only available in tests and with the
+    /// `caller-rng` feature.
+    #[cfg(any(test, feature = "caller-rng"))]
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        use sha2::{Digest, Sha512};
+
+        let mut hash = Sha512::new()
+            .chain(b"wireguard-uapi test keypair")
+            .chain(seed)
+            .finalize();
+        let mut key = Key::zero();
+        key.0.copy_from_slice(&hash[..32]);
+        hash[..].zeroize();
+        key.0[0] &= 248;
+        key.0[31] &= 127;
+        key.0[31] |= 64;
+        Self::from_private(key)
+    }
 }
 
 #[cfg(test)]
@@ -218,6 +392,62 @@ mod tests {
         assert_ne!(privkey, pubkey);
     }
 
+    #[test]
+    fn test_keypair_from_seed() {
+        use crate::key::KeyPair;
+
+        let pair = KeyPair::from_seed(&[1u8; 32]);
+        assert_eq!(pair, KeyPair::from_seed(&[1u8; 32]));
+        assert_ne!(pair, KeyPair::from_seed(&[2u8; 32]));
+        assert_eq!(pair.public, pair.private.get_public());
+    }
+
+    /// A counter posing as a CSPRNG, to get reproducible keys.
+    #[cfg(feature = "caller-rng")]
+    struct CountingRng(u8);
+
+    #[cfg(feature = "caller-rng")]
+    impl rand_core::RngCore for CountingRng {
+        fn next_u32(&mut self) -> u32 {
+            rand_core::impls::next_u32_via_fill(self)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            rand_core::impls::next_u64_via_fill(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                self.0 = self.0.wrapping_add(1);
+                *byte = self.0;
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "caller-rng")]
+    impl rand_core::CryptoRng for CountingRng {}
+
+    #[test]
+    #[cfg(feature = "caller-rng")]
+    fn test_generate_with_rng() {
+        use crate::key::{Key, KeyPair};
+
+        let pair = KeyPair::generate_with(&mut CountingRng(0));
+        assert_eq!(pair, KeyPair::generate_with(&mut CountingRng(0)));
+        assert_eq!(pair.public, pair.private.get_public());
+        // Clamped like any other private key.
+        assert_eq!(pair.private.0[0], 0);
+        assert_eq!(pair.private.0[31], 32 | 64);
+
+        let preshared = Key::generate_preshared_with(&mut CountingRng(0));
+        assert_eq!(preshared.0[..3], [1, 2, 3]);
+    }
+
     #[test]
     fn test_generate_keypair_helper() {
         use crate::key::KeyPair;
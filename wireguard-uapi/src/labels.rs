@@ -0,0 +1,426 @@
+//! Key/value labels on interfaces, and Kubernetes-style selectors over them.
+//!
+//! WireGuard has nowhere to keep metadata about an interface, so labels live in a
+//! [`LabelStore`]: one `<interface>.labels` file per labelled interface, by default
+//! next to the userspace sockets in `/run/wireguard`. A [`Selector`] such as
+//! `env=staging,tier in (edge, core)` picks interfaces by their labels, which
+//! [`Device::list_by_label`] and the bulk operations of the store build on.
+use crate::{Backend, Device, DeviceUpdate, InterfaceName};
+
+use std::{
+    collections::BTreeMap,
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The labels of one interface.
+pub type Labels = BTreeMap<String, String>;
+
+/// An error parsing a [`Selector`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSelector(pub String);
+
+impl fmt::Display for InvalidSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid label selector: {}", self.0)
+    }
+}
+
+impl error::Error for InvalidSelector {}
+
+impl From<InvalidSelector> for io::Error {
+    fn from(e: InvalidSelector) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// One comma-separated term of a [`Selector`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Requirement {
+    /// `key=value` or `key==value`.
+    Equals(String, String),
+    /// `key!=value`, which also matches interfaces without the label.
+    NotEquals(String, String),
+    /// `key in (a, b)`.
+    In(String, Vec<String>),
+    /// `key notin (a, b)`, which also matches interfaces without the label.
+    NotIn(String, Vec<String>),
+    /// `key`.
+    Exists(String),
+    /// `!key`.
+    DoesNotExist(String),
+}
+
+impl Requirement {
+    pub fn matches(&self, labels: &Labels) -> bool {
+        match self {
+            Self::Equals(key, value) => labels.get(key) == Some(value),
+            Self::NotEquals(key, value) => labels.get(key) != Some(value),
+            Self::In(key, values) => labels.get(key).is_some_and(|v| values.contains(v)),
+            Self::NotIn(key, values) => !labels.get(key).is_some_and(|v| values.contains(v)),
+            Self::Exists(key) => labels.contains_key(key),
+            Self::DoesNotExist(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+/// A label selector, matching interfaces whose labels meet every requirement.
+///
+/// The syntax is that of Kubernetes: `env=staging`, `env!=prod`, `tier in (edge,
+/// core)`, `tier notin (core)`, `managed` and `!legacy`, joined by commas. The
+/// empty selector matches every interface.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Selector(pub Vec<Requirement>);
+
+impl Selector {
+    pub fn matches(&self, labels: &Labels) -> bool {
+        self.0.iter().all(|requirement| requirement.matches(labels))
+    }
+}
+
+/// Splits `s` at the commas outside of parentheses.
+fn split_terms(s: &str) -> Result<Vec<&str>, InvalidSelector> {
+    let mut terms = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err(InvalidSelector(format!("unmatched `)` in {:?}", s))),
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                terms.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(InvalidSelector(format!("unmatched `(` in {:?}", s)));
+    }
+    terms.push(&s[start..]);
+    Ok(terms)
+}
+
+fn check_key(key: &str, term: &str) -> Result<String, InvalidSelector> {
+    let valid = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c));
+    match valid {
+        true => Ok(key.to_string()),
+        false => Err(InvalidSelector(format!("invalid key in {:?}", term))),
+    }
+}
+
+/// Parses the `(a, b)` of a set-based requirement.
+fn parse_set(s: &str, term: &str) -> Result<Vec<String>, InvalidSelector> {
+    let inner = s
+        .trim()
+        .strip_prefix('(')
+        .and_then(|s| s.strip_suffix(')'))
+        .ok_or_else(|| InvalidSelector(format!("expected `(values)` in {:?}", term)))?;
+    Ok(inner
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+impl FromStr for Requirement {
+    type Err = InvalidSelector;
+
+    fn from_str(term: &str) -> Result<Self, Self::Err> {
+        let term = term.trim();
+        if let Some(key) = term.strip_prefix('!') {
+            return Ok(Self::DoesNotExist(check_key(key.trim(), term)?));
+        }
+        for (operator, build) in [
+            ("!=", Self::NotEquals as fn(String, String) -> Self),
+            ("==", Self::Equals),
+            ("=", Self::Equals),
+        ] {
+            if let Some((key, value)) = term.split_once(operator) {
+                return Ok(build(
+                    check_key(key.trim(), term)?,
+                    value.trim().to_string(),
+                ));
+            }
+        }
+        let mut words = term.splitn(2, char::is_whitespace);
+        let key = check_key(words.next().unwrap_or_default(), term)?;
+        let rest = words.next().unwrap_or_default().trim_start();
+        if rest.is_empty() {
+            return Ok(Self::Exists(key));
+        }
+        if let Some(set) = rest.strip_prefix("notin") {
+            return Ok(Self::NotIn(key, parse_set(set, term)?));
+        }
+        if let Some(set) = rest.strip_prefix("in") {
+            return Ok(Self::In(key, parse_set(set, term)?));
+        }
+        Err(InvalidSelector(format!("unknown operator in {:?}", term)))
+    }
+}
+
+impl FromStr for Selector {
+    type Err = InvalidSelector;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        split_terms(s)?
+            .into_iter()
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Where interface labels are kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelStore {
+    dir: PathBuf,
+}
+
+impl Default for LabelStore {
+    /// The store in `/run/wireguard`, shared by every process on the host.
+    fn default() -> Self {
+        Self::new("/run/wireguard")
+    }
+}
+
+impl LabelStore {
+    /// A store keeping its files in `dir`, which is created on the first write.
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, iface: &InterfaceName) -> PathBuf {
+        self.dir.join(format!("{}.labels", iface.as_str_lossy()))
+    }
+
+    /// The labels of `iface`, which are empty if it was never labelled.
+    pub fn get(&self, iface: &InterfaceName) -> io::Result<Labels> {
+        let text = match fs::read_to_string(self.path(iface)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Labels::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(text
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect())
+    }
+
+    /// Replaces the labels of `iface`.
+    pub fn set(&self, iface: &InterfaceName, labels: &Labels) -> io::Result<()> {
+        if labels.is_empty() {
+            return self.remove(iface);
+        }
+        if let Some(key) = labels.keys().find(|key| check_key(key, key).is_err()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid label key {:?}", key),
+            ));
+        }
+        let text: String = labels
+            .iter()
+            .map(|(key, value)| format!("{}={}\n", key, value.replace('\n', " ")))
+            .collect();
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(iface), text)
+    }
+
+    /// Sets the label `key` of `iface` to `value`.
+    pub fn label(&self, iface: &InterfaceName, key: &str, value: &str) -> io::Result<()> {
+        let mut labels = self.get(iface)?;
+        labels.insert(key.to_string(), value.to_string());
+        self.set(iface, &labels)
+    }
+
+    /// Removes the label `key` of `iface`.
+    pub fn unlabel(&self, iface: &InterfaceName, key: &str) -> io::Result<()> {
+        let mut labels = self.get(iface)?;
+        labels.remove(key);
+        self.set(iface, &labels)
+    }
+
+    /// Forgets every label of `iface`.
+    pub fn remove(&self, iface: &InterfaceName) -> io::Result<()> {
+        match fs::remove_file(self.path(iface)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// The interfaces on `backend` whose labels match `selector`, with their labels.
+    pub fn list(
+        &self,
+        selector: &Selector,
+        backend: Backend,
+    ) -> io::Result<Vec<(InterfaceName, Labels)>> {
+        let mut matching = vec![];
+        for name in Device::list(backend)? {
+            let labels = self.get(&name)?;
+            if selector.matches(&labels) {
+                matching.push((name, labels));
+            }
+        }
+        Ok(matching)
+    }
+
+    /// Deletes every labelled interface on `backend` matching `selector`, along
+    /// with its labels, and returns their names.
+    ///
+    /// Interfaces that were never labelled are left alone, also if a selector
+    /// such as `!legacy` matches them, and an empty selector is refused rather
+    /// than deleting everything. Stops at the first interface that can't be
+    /// deleted. Interfaces that disappear in the meantime are skipped.
+    pub fn delete_by_label(
+        &self,
+        selector: &Selector,
+        backend: Backend,
+    ) -> io::Result<Vec<InterfaceName>> {
+        if selector.0.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "refusing to delete by an empty selector, which matches every interface",
+            ));
+        }
+        let mut deleted = vec![];
+        for (name, _) in self.list(selector, backend)? {
+            if !self.path(&name).is_file() {
+                continue;
+            }
+            match Device::get(&name, backend).and_then(Device::delete) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+            self.remove(&name)?;
+            deleted.push(name);
+        }
+        Ok(deleted)
+    }
+
+    /// Applies `update` to every interface on `backend` matching `selector`, and
+    /// returns their names.
+    ///
+    /// Stops at the first interface the update fails on.
+    pub fn apply_by_label(
+        &self,
+        update: &DeviceUpdate,
+        selector: &Selector,
+        backend: Backend,
+    ) -> io::Result<Vec<InterfaceName>> {
+        let mut updated = vec![];
+        for (name, _) in self.list(selector, backend)? {
+            update.clone().apply(&name, backend)?;
+            updated.push(name);
+        }
+        Ok(updated)
+    }
+}
+
+impl Device {
+    /// Enumerates the interfaces on `backend` whose labels in the default
+    /// [`LabelStore`] match `selector`.
+    pub fn list_by_label(selector: &Selector, backend: Backend) -> io::Result<Vec<InterfaceName>> {
+        Ok(LabelStore::default()
+            .list(selector, backend)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_selector() {
+        let selector: Selector = "env=staging, tier in (edge, core), !legacy"
+            .parse()
+            .unwrap();
+        assert_eq!(selector.0.len(), 3);
+        assert!(selector.matches(&labels(&[("env", "staging"), ("tier", "edge")])));
+        assert!(!selector.matches(&labels(&[("env", "staging"), ("tier", "db")])));
+        assert!(!selector.matches(&labels(&[
+            ("env", "staging"),
+            ("tier", "core"),
+            ("legacy", "")
+        ])));
+
+        let selector: Selector = "env!=prod,tier notin (core),managed".parse().unwrap();
+        assert!(selector.matches(&labels(&[("managed", "yes")])));
+        assert!(!selector.matches(&labels(&[("managed", "yes"), ("env", "prod")])));
+
+        assert!(Selector::default().matches(&Labels::new()));
+        assert!("tier in (edge".parse::<Selector>().is_err());
+        assert!("tier like edge".parse::<Selector>().is_err());
+        assert!("=staging".parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn test_label_store() {
+        let dir = std::env::temp_dir().join(format!("wg-labels-{}", std::process::id()));
+        let store = LabelStore::new(&dir);
+        let iface: InterfaceName = "wg0".parse().unwrap();
+
+        assert!(store.get(&iface).unwrap().is_empty());
+        store.label(&iface, "env", "staging").unwrap();
+        store.label(&iface, "tier", "edge").unwrap();
+        store.unlabel(&iface, "tier").unwrap();
+        assert_eq!(store.get(&iface).unwrap(), labels(&[("env", "staging")]));
+        assert!(store.label(&iface, "bad key", "x").is_err());
+
+        store.remove(&iface).unwrap();
+        assert!(store.get(&iface).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn test_delete_by_label() {
+        let dir = std::env::temp_dir().join(format!("wg-labels-delete-{}", std::process::id()));
+        let store = LabelStore::new(&dir);
+        let labelled: InterfaceName = "mock-labels1".parse().unwrap();
+        let unlabelled: InterfaceName = "mock-labels2".parse().unwrap();
+        for iface in [&labelled, &unlabelled] {
+            DeviceUpdate::new().apply(iface, Backend::Mock).unwrap();
+        }
+        store.label(&labelled, "env", "staging").unwrap();
+
+        let error = store
+            .delete_by_label(&Selector::default(), Backend::Mock)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let selector = "!legacy".parse().unwrap();
+        let deleted = store.delete_by_label(&selector, Backend::Mock).unwrap();
+        assert_eq!(deleted, [labelled]);
+        let remaining = crate::backends::mock::enumerate().unwrap();
+        assert!(!remaining.contains(&labelled));
+        assert!(remaining.contains(&unlabelled));
+
+        Device::get(&unlabelled, Backend::Mock)
+            .unwrap()
+            .delete()
+            .unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
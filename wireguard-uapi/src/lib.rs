@@ -1,25 +1,112 @@
 extern crate core;
 
+pub mod allowed_ips;
+pub mod anonymize;
+pub mod authz;
 pub mod backends;
+pub mod backup;
+#[cfg(feature = "beacon")]
+pub mod beacon;
+pub mod clock;
+pub mod compat;
+pub mod compliance;
+pub mod conf;
+pub mod crdt;
+pub mod cryptokey;
+pub mod features;
+#[cfg(feature = "gossip")]
+pub mod gossip;
+pub mod health;
+pub mod history;
+pub mod index;
+pub mod invite;
+pub mod keepalive;
+pub mod labels;
 pub mod netlink_request;
+pub mod plan;
+pub mod platform;
+#[cfg(feature = "portmap")]
+pub mod portmap;
+pub mod profiles;
+pub mod provision;
+#[cfg(feature = "qr")]
+pub mod qr;
+pub mod rates;
 
+mod apply;
 mod config;
 mod device;
+mod error;
+mod import;
+mod json;
 mod key;
+pub mod macos;
+pub mod metrics;
+#[cfg(target_os = "linux")]
+pub mod monitor;
+#[cfg(feature = "mtls")]
+pub mod mtls;
+#[cfg(all(feature = "nft", target_os = "linux"))]
+pub mod nft;
+#[cfg(feature = "tokio")]
+mod nonblocking;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(target_os = "linux")]
+pub mod persist;
+#[cfg(feature = "tokio")]
+pub mod registry;
+pub mod relay;
+#[cfg(feature = "print")]
+pub mod render;
+pub mod report;
+pub mod roaming;
+pub mod rolling_psk;
+#[cfg(all(feature = "sampling", target_os = "linux"))]
+pub mod sampling;
+pub mod sessions;
+#[cfg(unix)]
+pub mod shutdown;
+#[cfg(target_os = "linux")]
+pub mod systemd;
+pub mod timeline;
 pub mod tools;
+#[cfg(target_os = "linux")]
+pub mod trace;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod tunnel;
+#[cfg(target_os = "linux")]
+pub mod unprivileged;
+pub mod watcher;
+pub mod windows;
 
 use std::{
     fmt::{self, Display, Formatter},
     str::FromStr,
 };
 
-pub use crate::{config::*, device::*, key::*};
+pub use crate::{apply::*, config::*, device::*, error::*, key::*};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum Backend {
     #[cfg(target_os = "linux")]
     Kernel,
     Userspace,
+    /// The `wg(8)` command line tool, for hosts where neither netlink nor the
+    /// userspace sockets can be reached.
+    Cli,
+    /// The WireGuardNT driver.
+    #[cfg(windows)]
+    Windows,
+    /// Interfaces kept in process memory, for tests.
+    #[cfg(feature = "mock")]
+    Mock,
 }
 
 impl Default for Backend {
@@ -29,7 +116,12 @@ impl Default for Backend {
             Self::Kernel
         }
 
-        #[cfg(not(target_os = "linux"))]
+        #[cfg(windows)]
+        {
+            Self::Windows
+        }
+
+        #[cfg(not(any(target_os = "linux", windows)))]
         {
             Self::Userspace
         }
@@ -42,6 +134,11 @@ impl Display for Backend {
             #[cfg(target_os = "linux")]
             Self::Kernel => write!(f, "kernel"),
             Self::Userspace => write!(f, "userspace"),
+            Self::Cli => write!(f, "cli"),
+            #[cfg(windows)]
+            Self::Windows => write!(f, "windows"),
+            #[cfg(feature = "mock")]
+            Self::Mock => write!(f, "mock"),
         }
     }
 }
@@ -54,21 +151,58 @@ impl FromStr for Backend {
             #[cfg(target_os = "linux")]
             "kernel" => Ok(Self::Kernel),
             "userspace" => Ok(Self::Userspace),
+            "cli" => Ok(Self::Cli),
+            #[cfg(windows)]
+            "windows" => Ok(Self::Windows),
+            #[cfg(feature = "mock")]
+            "mock" => Ok(Self::Mock),
             _ => Err(format!("valid values: {}.", Self::variants().join(", "))),
         }
     }
 }
 
 impl Backend {
+    /// The preferred backend this host can use: the kernel module or driver if
+    /// it's there, else a userspace implementation, else the `wg` tool, as probed
+    /// by [`platform::capabilities`]. Without any, this is
+    /// [`Backend::default`], so the first operation reports what's missing.
+    pub fn auto() -> Self {
+        platform::capabilities()
+            .backends()
+            .first()
+            .copied()
+            .unwrap_or_default()
+    }
+
     pub fn variants() -> &'static [&'static str] {
-        #[cfg(target_os = "linux")]
+        #[cfg(all(target_os = "linux", not(feature = "mock")))]
+        {
+            &["kernel", "userspace", "cli"]
+        }
+
+        #[cfg(all(target_os = "linux", feature = "mock"))]
+        {
+            &["kernel", "userspace", "cli", "mock"]
+        }
+
+        #[cfg(all(windows, not(feature = "mock")))]
+        {
+            &["userspace", "cli", "windows"]
+        }
+
+        #[cfg(all(windows, feature = "mock"))]
+        {
+            &["userspace", "cli", "windows", "mock"]
+        }
+
+        #[cfg(all(not(any(target_os = "linux", windows)), not(feature = "mock")))]
         {
-            &["kernel", "userspace"]
+            &["userspace", "cli"]
         }
 
-        #[cfg(not(target_os = "linux"))]
+        #[cfg(all(not(any(target_os = "linux", windows)), feature = "mock"))]
         {
-            &["userspace"]
+            &["userspace", "cli", "mock"]
         }
     }
 }
@@ -0,0 +1,192 @@
+//! Cooperation with tunnels of the official WireGuard app on macOS.
+//!
+//! The app runs its tunnels inside a Network Extension, which exposes no
+//! configuration socket: this crate can neither read nor change them, and used to
+//! fail with unhelpful "name file can't be read" errors when asked to. Those
+//! tunnels are now [listed](extension_tunnels) read-only, and the userspace
+//! backend refuses to touch an interface named like one of them with a typed
+//! [`ManagedByExtension`] error. [`independent_name`] picks interface names that
+//! can't be mistaken for the app's tunnels.
+use crate::InterfaceName;
+
+use std::{error, fmt, io};
+
+/// The bundle identifier of the app's Network Extension provider.
+pub const PROVIDER: &str = "com.wireguard.macos";
+
+/// A tunnel configured in the WireGuard app.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionTunnel {
+    /// The identifier of the VPN configuration.
+    pub id: String,
+    /// The tunnel name shown in the app.
+    pub name: String,
+    pub connected: bool,
+}
+
+/// Extracts the app's tunnels from the output of `scutil --nc list`.
+pub fn parse_nc_list(output: &str) -> Vec<ExtensionTunnel> {
+    let provider = format!("({})", PROVIDER);
+    output
+        .lines()
+        .filter(|line| line.contains(&provider))
+        .filter_map(|line| {
+            let (_, rest) = line.split_once('(')?;
+            let (status, rest) = rest.split_once(')')?;
+            let id = rest.split_whitespace().next()?;
+            let (_, quoted) = rest.split_once('"')?;
+            let (name, _) = quoted.split_once('"')?;
+            Some(ExtensionTunnel {
+                id: id.to_string(),
+                name: name.to_string(),
+                connected: status == "Connected",
+            })
+        })
+        .collect()
+}
+
+/// The tunnels configured in the WireGuard app, connected or not.
+#[cfg(target_os = "macos")]
+pub fn extension_tunnels() -> io::Result<Vec<ExtensionTunnel>> {
+    let output = std::process::Command::new("scutil")
+        .args(["--nc", "list"])
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "scutil --nc list failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
+    }
+    Ok(parse_nc_list(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// An operation was refused because the interface belongs to the WireGuard app.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManagedByExtension {
+    pub tunnel: ExtensionTunnel,
+}
+
+impl fmt::Display for ManagedByExtension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is managed by the WireGuard app's Network Extension and is read-only here; \
+             use another name for interfaces managed by this tool",
+            self.tunnel.name
+        )
+    }
+}
+
+impl error::Error for ManagedByExtension {}
+
+impl From<ManagedByExtension> for io::Error {
+    fn from(e: ManagedByExtension) -> Self {
+        io::Error::new(io::ErrorKind::PermissionDenied, e)
+    }
+}
+
+impl ManagedByExtension {
+    /// The [`ManagedByExtension`] an I/O error was created from, if any.
+    pub fn from_io(error: &io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+/// Fails if `name` is the name of one of `tunnels`.
+pub fn check_name(
+    name: &InterfaceName,
+    tunnels: &[ExtensionTunnel],
+) -> Result<(), ManagedByExtension> {
+    let name = name.as_str_lossy();
+    match tunnels.iter().find(|tunnel| tunnel.name == name) {
+        Some(tunnel) => Err(ManagedByExtension {
+            tunnel: tunnel.clone(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Fails with a [`ManagedByExtension`] error if `name` belongs to the WireGuard
+/// app. If the app's tunnels can't be listed, nothing is refused.
+#[cfg(target_os = "macos")]
+pub fn ensure_not_managed(name: &InterfaceName) -> io::Result<()> {
+    match extension_tunnels() {
+        Ok(tunnels) => check_name(name, &tunnels).map_err(io::Error::from),
+        Err(e) => {
+            log::debug!("couldn't list Network Extension tunnels: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// The first of `prefix0`, `prefix1`, ... that is neither a tunnel of the app nor
+/// in `existing`, for creating independent tunnels alongside the app's.
+pub fn independent_name(
+    prefix: &str,
+    tunnels: &[ExtensionTunnel],
+    existing: &[InterfaceName],
+) -> Result<InterfaceName, crate::InvalidInterfaceName> {
+    let mut index = 0u32;
+    loop {
+        let name: InterfaceName = format!("{}{}", prefix, index).parse()?;
+        if check_name(&name, tunnels).is_ok() && !existing.contains(&name) {
+            return Ok(name);
+        }
+        index += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NC_LIST: &str = "\
+Available network connection services in the current set (*=enabled):
+* (Connected)      5E2F7C0A-1B2C-4D3E-8F90-A1B2C3D4E5F6 VPN (com.wireguard.macos) \"wg0\"                            [VPN/com.wireguard.macos]
+* (Disconnected)   0A1B2C3D-4E5F-6071-8293-A4B5C6D7E8F9 VPN (com.wireguard.macos) \"office\"                         [VPN/com.wireguard.macos]
+* (Disconnected)   11111111-2222-3333-4444-555555555555 IPSec                      \"corp ipsec\"                     [IPSec]
+";
+
+    #[test]
+    fn test_parse_nc_list() {
+        let tunnels = parse_nc_list(NC_LIST);
+        assert_eq!(
+            tunnels,
+            vec![
+                ExtensionTunnel {
+                    id: "5E2F7C0A-1B2C-4D3E-8F90-A1B2C3D4E5F6".to_string(),
+                    name: "wg0".to_string(),
+                    connected: true,
+                },
+                ExtensionTunnel {
+                    id: "0A1B2C3D-4E5F-6071-8293-A4B5C6D7E8F9".to_string(),
+                    name: "office".to_string(),
+                    connected: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_name() {
+        let tunnels = parse_nc_list(NC_LIST);
+        let error = check_name(&"wg0".parse().unwrap(), &tunnels).unwrap_err();
+        assert_eq!(error.tunnel.name, "wg0");
+
+        let io_error = io::Error::from(error.clone());
+        assert_eq!(io_error.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(ManagedByExtension::from_io(&io_error), Some(&error));
+        assert!(check_name(&"wg1".parse().unwrap(), &tunnels).is_ok());
+    }
+
+    #[test]
+    fn test_independent_name() {
+        let tunnels = parse_nc_list(NC_LIST);
+        let existing = ["wg1".parse().unwrap()];
+        let name = independent_name("wg", &tunnels, &existing).unwrap();
+        assert_eq!(name.as_str_lossy(), "wg2");
+    }
+}
@@ -0,0 +1,395 @@
+//! Exporting interface and peer metrics to monitoring systems.
+//!
+//! [`to_influx_lines`] renders devices in the InfluxDB line protocol, for Telegraf's
+//! `exec`/`execd` inputs or direct writes to InfluxDB. The default measurement,
+//! tag and field names match Telegraf's own `wireguard` input plugin, so existing
+//! dashboards keep working when switching to an exporter built on this crate.
+//!
+//! [`to_prometheus`] renders them in the Prometheus text exposition format, for
+//! `/metrics` endpoints of exporter daemons or node_exporter's textfile collector.
+//! Peers are labelled by interface and public key.
+use crate::{
+    clock::{Clock, SystemClock},
+    Device, PeerInfo,
+};
+
+use std::{
+    fmt::Write as _,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Options for rendering InfluxDB line protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Influx {
+    /// The measurement of per-interface lines.
+    pub device_measurement: String,
+    /// The measurement of per-peer lines.
+    pub peer_measurement: String,
+    /// The timestamp of every line. Without one, the server assigns its own.
+    pub time: Option<SystemTime>,
+}
+
+impl Default for Influx {
+    fn default() -> Self {
+        Self {
+            device_measurement: "wireguard_device".to_string(),
+            peer_measurement: "wireguard_peer".to_string(),
+            time: None,
+        }
+    }
+}
+
+/// Escapes a measurement name.
+fn escape_measurement(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(' ', "\\ ")
+}
+
+/// Escapes a tag key or value, or a field key.
+fn escape_key(s: &str) -> String {
+    escape_measurement(s).replace('=', "\\=")
+}
+
+/// Quotes a string field value.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+impl Influx {
+    /// Renders one line per device and one per peer, each tagged with `tags` in
+    /// addition to the tags identifying the interface and peer.
+    pub fn lines(&self, devices: &[Device], tags: &[(&str, &str)]) -> String {
+        let extra_tags: String = tags
+            .iter()
+            .map(|(key, value)| format!(",{}={}", escape_key(key), escape_key(value)))
+            .collect();
+        let timestamp = match self.time {
+            Some(time) => format!(" {}", nanos(time)),
+            None => String::new(),
+        };
+
+        let mut out = String::new();
+        for device in devices {
+            let name = escape_key(&device.name.as_str_lossy());
+            writeln!(
+                out,
+                "{},name={},type={}{} listen_port={}i,firewall_mark={}i,peers={}i{}",
+                escape_measurement(&self.device_measurement),
+                name,
+                device.backend,
+                extra_tags,
+                device.listen_port.unwrap_or(0),
+                device.fwmark.unwrap_or(0),
+                device.peers.len(),
+                timestamp
+            )
+            .ok();
+
+            for peer in &device.peers {
+                let config = &peer.config;
+                let allowed_ips = config
+                    .allowed_ips
+                    .iter()
+                    .map(|ip| format!("{}/{}", ip.address, ip.cidr))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let mut fields = format!(
+                    "persistent_keepalive_interval={}i,allowed_ips={}i,allowed_peer_cidr={},last_handshake_time_ns={}i,rx_bytes={}i,tx_bytes={}i",
+                    config.persistent_keepalive_interval.unwrap_or(0),
+                    config.allowed_ips.len(),
+                    quote(&allowed_ips),
+                    peer.stats.last_handshake_time.map_or(0, nanos),
+                    peer.stats.rx_bytes,
+                    peer.stats.tx_bytes
+                );
+                if let Some(endpoint) = config.endpoint {
+                    write!(fields, ",endpoint={}", quote(&endpoint.to_string())).ok();
+                }
+                writeln!(
+                    out,
+                    "{},device={},public_key={}{} {}{}",
+                    escape_measurement(&self.peer_measurement),
+                    name,
+                    escape_key(&config.public_key.to_base64()),
+                    extra_tags,
+                    fields,
+                    timestamp
+                )
+                .ok();
+            }
+        }
+        out
+    }
+}
+
+/// Renders `devices` in the InfluxDB line protocol with the default [`Influx`]
+/// options, adding `tags` (e.g. `[("host", "gw1")]`) to every line.
+pub fn to_influx_lines(devices: &[Device], tags: &[(&str, &str)]) -> String {
+    Influx::default().lines(devices, tags)
+}
+
+/// Options for rendering the Prometheus text exposition format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prometheus {
+    /// Prefixed to every metric name, followed by an underscore.
+    pub namespace: String,
+}
+
+impl Default for Prometheus {
+    fn default() -> Self {
+        Self {
+            namespace: "wireguard".to_string(),
+        }
+    }
+}
+
+/// Escapes a label value.
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Checks that `name` can be used as a label next to the ones identifying the
+/// interface and peer.
+fn check_label_name(name: &str) -> io::Result<()> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__");
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid Prometheus label name {:?}", name),
+        ));
+    }
+    if name == "interface" || name == "public_key" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("label {:?} is already set on every sample", name),
+        ));
+    }
+    Ok(())
+}
+
+impl Prometheus {
+    /// Renders every metric of `devices`, each sample labelled with `labels` in
+    /// addition to the labels identifying the interface and peer.
+    ///
+    /// Fails if a label name isn't valid in Prometheus, or is reserved (starting
+    /// with `__`) or taken by those identifying labels.
+    pub fn render(&self, devices: &[Device], labels: &[(&str, &str)]) -> io::Result<String> {
+        self.render_with_clock(devices, labels, &SystemClock)
+    }
+
+    /// Like [`render`](Prometheus::render), computing handshake ages from `clock`.
+    pub fn render_with_clock(
+        &self,
+        devices: &[Device],
+        labels: &[(&str, &str)],
+        clock: &dyn Clock,
+    ) -> io::Result<String> {
+        for (name, _) in labels {
+            check_label_name(name)?;
+        }
+        let extra_labels: String = labels
+            .iter()
+            .map(|(key, value)| format!(",{}=\"{}\"", key, escape_label(value)))
+            .collect();
+        let extra_labels = extra_labels.as_str();
+        let device_labels = |device: &Device| {
+            format!(
+                "{{interface=\"{}\"{}}}",
+                escape_label(&device.name.as_str_lossy()),
+                extra_labels
+            )
+        };
+
+        let mut out = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let name = format!("{}_{}", self.namespace, name);
+            writeln!(out, "# HELP {} {}", name, help).ok();
+            writeln!(out, "# TYPE {} {}", name, kind).ok();
+            for (labels, value) in samples {
+                writeln!(out, "{}{} {}", name, labels, value).ok();
+            }
+        };
+        let per_device = |value: &dyn Fn(&Device) -> Option<String>| {
+            devices
+                .iter()
+                .filter_map(|device| Some((device_labels(device), value(device)?)))
+                .collect::<Vec<_>>()
+        };
+        let per_peer = |value: &dyn Fn(&PeerInfo) -> Option<String>| {
+            devices
+                .iter()
+                .flat_map(|device| {
+                    device.peers.iter().filter_map(move |peer| {
+                        let labels = format!(
+                            "{{interface=\"{}\",public_key=\"{}\"{}}}",
+                            escape_label(&device.name.as_str_lossy()),
+                            peer.config.public_key.to_base64(),
+                            extra_labels
+                        );
+                        Some((labels, value(peer)?))
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+
+        family(
+            "peers",
+            "gauge",
+            "Number of peers of the interface.",
+            per_device(&|device| Some(device.peers.len().to_string())),
+        );
+        family(
+            "listen_port",
+            "gauge",
+            "UDP port the interface listens on.",
+            per_device(&|device| device.listen_port.map(|port| port.to_string())),
+        );
+        family(
+            "peer_receive_bytes_total",
+            "counter",
+            "Bytes received from the peer.",
+            per_peer(&|peer| Some(peer.stats.rx_bytes.to_string())),
+        );
+        family(
+            "peer_transmit_bytes_total",
+            "counter",
+            "Bytes sent to the peer.",
+            per_peer(&|peer| Some(peer.stats.tx_bytes.to_string())),
+        );
+        family(
+            "peer_last_handshake_seconds",
+            "gauge",
+            "Unix time of the last handshake with the peer, 0 if there was none.",
+            per_peer(&|peer| {
+                let seconds = peer.stats.last_handshake_time.map_or(0, |time| {
+                    time.duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs()
+                });
+                Some(seconds.to_string())
+            }),
+        );
+        family(
+            "peer_last_handshake_age_seconds",
+            "gauge",
+            "Seconds since the last handshake with the peer, absent if there was none.",
+            per_peer(&|peer| Some(peer.stats.handshake_age(clock)?.as_secs().to_string())),
+        );
+        Ok(out)
+    }
+}
+
+/// Renders `devices` in the Prometheus text exposition format with the default
+/// [`Prometheus`] options, adding `labels` (e.g. `[("host", "gw1")]`) to every
+/// sample.
+pub fn to_prometheus(devices: &[Device], labels: &[(&str, &str)]) -> io::Result<String> {
+    Prometheus::default().render(devices, labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, PeerInfo, PeerStats};
+    use std::time::Duration;
+
+    fn device() -> Device {
+        let mut peer = PeerInfo::fixture(Key([1u8; 32]))
+            .with_endpoint("192.0.2.1:51820")
+            .with_allowed_ips(&["10.0.0.2/32", "fd00::2/128"])
+            .with_handshake(UNIX_EPOCH + Duration::from_secs(1000))
+            .with_transfer(2048, 512);
+        peer.config.persistent_keepalive_interval = Some(25);
+        Device {
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", vec![peer])
+        }
+    }
+
+    #[test]
+    fn test_to_influx_lines() {
+        let lines = to_influx_lines(&[device()], &[("host", "gw 1")]);
+        let key = Key([1u8; 32]).to_base64();
+        assert_eq!(
+            lines,
+            format!(
+                "wireguard_device,name=wg0,type=userspace,host=gw\\ 1 listen_port=51820i,firewall_mark=0i,peers=1i\n\
+                 wireguard_peer,device=wg0,public_key={},host=gw\\ 1 persistent_keepalive_interval=25i,allowed_ips=2i,allowed_peer_cidr=\"10.0.0.2/32,fd00::2/128\",last_handshake_time_ns=1000000000000i,rx_bytes=2048i,tx_bytes=512i,endpoint=\"192.0.2.1:51820\"\n",
+                key.replace('=', "\\=")
+            )
+        );
+    }
+
+    #[test]
+    fn test_to_prometheus() {
+        let mut never = device();
+        never.name = "wg\"1".parse().unwrap();
+        never.listen_port = None;
+        never.peers[0].stats = PeerStats::default();
+        // How Linux reports a peer that never handshaked.
+        let mut epoch = never.clone();
+        epoch.name = "wg2".parse().unwrap();
+        epoch.peers[0].stats.last_handshake_time = Some(UNIX_EPOCH);
+        let clock = crate::clock::ManualClock::new(UNIX_EPOCH + Duration::from_secs(1030));
+        let text = Prometheus::default()
+            .render_with_clock(&[device(), never, epoch], &[("host", "gw1")], &clock)
+            .unwrap();
+        let key = Key([1u8; 32]).to_base64();
+        let samples: Vec<_> = text.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            samples,
+            vec![
+                "wireguard_peers{interface=\"wg0\",host=\"gw1\"} 1".to_string(),
+                "wireguard_peers{interface=\"wg\\\"1\",host=\"gw1\"} 1".to_string(),
+                "wireguard_peers{interface=\"wg2\",host=\"gw1\"} 1".to_string(),
+                "wireguard_listen_port{interface=\"wg0\",host=\"gw1\"} 51820".to_string(),
+                format!("wireguard_peer_receive_bytes_total{{interface=\"wg0\",public_key=\"{}\",host=\"gw1\"}} 2048", key),
+                format!("wireguard_peer_receive_bytes_total{{interface=\"wg\\\"1\",public_key=\"{}\",host=\"gw1\"}} 0", key),
+                format!("wireguard_peer_receive_bytes_total{{interface=\"wg2\",public_key=\"{}\",host=\"gw1\"}} 0", key),
+                format!("wireguard_peer_transmit_bytes_total{{interface=\"wg0\",public_key=\"{}\",host=\"gw1\"}} 512", key),
+                format!("wireguard_peer_transmit_bytes_total{{interface=\"wg\\\"1\",public_key=\"{}\",host=\"gw1\"}} 0", key),
+                format!("wireguard_peer_transmit_bytes_total{{interface=\"wg2\",public_key=\"{}\",host=\"gw1\"}} 0", key),
+                format!("wireguard_peer_last_handshake_seconds{{interface=\"wg0\",public_key=\"{}\",host=\"gw1\"}} 1000", key),
+                format!("wireguard_peer_last_handshake_seconds{{interface=\"wg\\\"1\",public_key=\"{}\",host=\"gw1\"}} 0", key),
+                format!("wireguard_peer_last_handshake_seconds{{interface=\"wg2\",public_key=\"{}\",host=\"gw1\"}} 0", key),
+                format!("wireguard_peer_last_handshake_age_seconds{{interface=\"wg0\",public_key=\"{}\",host=\"gw1\"}} 30", key),
+            ]
+        );
+        assert!(text.contains("# TYPE wireguard_peer_receive_bytes_total counter\n"));
+        assert_eq!(text.matches("# HELP").count(), 6);
+
+        for name in ["host-name", "1host", "", "__host", "public_key"] {
+            let error = to_prometheus(&[device()], &[(name, "gw1")]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(to_prometheus(&[device()], &[("_host2", "gw1")]).is_ok());
+    }
+
+    #[test]
+    fn test_custom_measurements() {
+        let influx = Influx {
+            device_measurement: "vpn,iface".to_string(),
+            peer_measurement: "vpn peer".to_string(),
+            time: Some(UNIX_EPOCH + Duration::from_secs(5)),
+        };
+        let lines = influx.lines(&[device()], &[]);
+        let lines: Vec<_> = lines.lines().collect();
+        assert!(lines[0].starts_with("vpn\\,iface,name=wg0,"));
+        assert!(lines[0].ends_with(" 5000000000"));
+        assert!(lines[1].starts_with("vpn\\ peer,device=wg0,"));
+    }
+}
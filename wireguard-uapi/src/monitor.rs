@@ -0,0 +1,262 @@
+//! Watching WireGuard interfaces come and go.
+//!
+//! A [`Monitor`] subscribes to the kernel's rtnetlink link notifications, so
+//! interfaces created, deleted or renamed by anyone are reported as they happen
+//! instead of by polling [`Device::list`](crate::Device::list). Only kernel
+//! interfaces are seen: userspace implementations run on plain tun devices.
+use crate::{netlink_request::netlink_request_rtnl, InterfaceName};
+
+use netlink_packet_core::{NetlinkMessage, NetlinkPayload, NLM_F_DUMP, NLM_F_REQUEST};
+use netlink_packet_route::{
+    link::nlas::{Info, InfoKind, Nla},
+    LinkMessage, RtnlMessage,
+};
+use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+};
+
+/// Link notifications carry the link's statistics and can be larger than a page.
+const RECV_BUFFER_LENGTH: usize = 32 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterfaceEvent {
+    Created(InterfaceName),
+    Deleted(InterfaceName),
+    Renamed {
+        from: InterfaceName,
+        to: InterfaceName,
+    },
+}
+
+/// The index and name of `link`, if it is a WireGuard interface.
+fn wireguard_link(link: &LinkMessage) -> Option<(u32, InterfaceName)> {
+    let is_wireguard = link.nlas.iter().any(|nla| match nla {
+        Nla::Info(infos) => infos.contains(&Info::Kind(InfoKind::Wireguard)),
+        _ => false,
+    });
+    if !is_wireguard {
+        return None;
+    }
+    let name = link.nlas.iter().find_map(|nla| match nla {
+        Nla::IfName(name) => name.parse().ok(),
+        _ => None,
+    })?;
+    Some((link.header.index, name))
+}
+
+/// The WireGuard interfaces known to the monitor, by link index.
+#[derive(Debug, Default)]
+struct Links(BTreeMap<u32, InterfaceName>);
+
+impl Links {
+    /// Tracks a link notification, returning the event it amounts to.
+    ///
+    /// Any change to a link, like it going up, is notified as a new link, so only
+    /// new indices and changed names are events.
+    fn update(&mut self, message: &RtnlMessage) -> Option<InterfaceEvent> {
+        match message {
+            RtnlMessage::NewLink(link) => {
+                let (index, name) = wireguard_link(link)?;
+                match self.0.insert(index, name) {
+                    None => Some(InterfaceEvent::Created(name)),
+                    Some(from) if from != name => Some(InterfaceEvent::Renamed { from, to: name }),
+                    Some(_) => None,
+                }
+            }
+            RtnlMessage::DelLink(link) => self
+                .0
+                .remove(&link.header.index)
+                .map(InterfaceEvent::Deleted),
+            _ => None,
+        }
+    }
+
+    /// Replaces the known links with `current`, returning the events that were
+    /// missed in between.
+    fn resync(&mut self, current: BTreeMap<u32, InterfaceName>) -> Vec<InterfaceEvent> {
+        let mut events = vec![];
+        for (index, from) in &self.0 {
+            match current.get(index) {
+                None => events.push(InterfaceEvent::Deleted(*from)),
+                Some(to) if to != from => events.push(InterfaceEvent::Renamed {
+                    from: *from,
+                    to: *to,
+                }),
+                Some(_) => {}
+            }
+        }
+        for (index, name) in &current {
+            if !self.0.contains_key(index) {
+                events.push(InterfaceEvent::Created(*name));
+            }
+        }
+        self.0 = current;
+        events
+    }
+}
+
+/// The WireGuard links that currently exist.
+fn dump() -> io::Result<BTreeMap<u32, InterfaceName>> {
+    let responses = netlink_request_rtnl(
+        RtnlMessage::GetLink(LinkMessage::default()),
+        Some(NLM_F_DUMP | NLM_F_REQUEST),
+    )?;
+    Ok(responses
+        .iter()
+        .filter_map(|response| match &response.payload {
+            NetlinkPayload::InnerMessage(RtnlMessage::NewLink(link)) => wireguard_link(link),
+            _ => None,
+        })
+        .collect())
+}
+
+/// A subscription to WireGuard interface events.
+///
+/// Iterating blocks until the next event. The interfaces existing when the
+/// monitor is created aren't reported as created.
+pub struct Monitor {
+    socket: Socket,
+    links: Links,
+    pending: VecDeque<InterfaceEvent>,
+    buf: Vec<u8>,
+}
+
+impl Monitor {
+    pub fn new() -> io::Result<Self> {
+        let mut socket = Socket::new(NETLINK_ROUTE)?;
+        socket.bind(&SocketAddr::new(0, libc::RTMGRP_LINK as u32))?;
+        // Subscribing first means no change after the dump can be missed.
+        let mut links = Links::default();
+        links.resync(dump()?);
+        Ok(Self {
+            socket,
+            links,
+            pending: VecDeque::new(),
+            buf: vec![0; RECV_BUFFER_LENGTH],
+        })
+    }
+
+    /// The WireGuard interfaces as of the last event.
+    pub fn interfaces(&self) -> impl Iterator<Item = &InterfaceName> {
+        self.links.0.values()
+    }
+
+    /// Blocks until the next event.
+    pub fn next_event(&mut self) -> io::Result<InterfaceEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            let n_received = match self.socket.recv(&mut &mut self.buf[..], 0) {
+                Ok(n_received) => n_received,
+                // The kernel dropped notifications because we didn't keep up.
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    log::warn!("missed link notifications, resynchronizing");
+                    let events = self.links.resync(dump()?);
+                    self.pending.extend(events);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let mut offset = 0;
+            while offset < n_received {
+                let message =
+                    NetlinkMessage::<RtnlMessage>::deserialize(&self.buf[offset..n_received])
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let length = message.header.length as usize;
+                if let NetlinkPayload::InnerMessage(message) = &message.payload {
+                    self.pending.extend(self.links.update(message));
+                }
+                if length == 0 {
+                    break;
+                }
+                offset += length;
+            }
+        }
+    }
+}
+
+impl Iterator for Monitor {
+    type Item = io::Result<InterfaceEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_event())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(index: u32, name: &str, wireguard: bool) -> LinkMessage {
+        let mut link = LinkMessage::default();
+        link.header.index = index;
+        link.nlas.push(Nla::IfName(name.to_string()));
+        let kind = if wireguard {
+            InfoKind::Wireguard
+        } else {
+            InfoKind::Dummy
+        };
+        link.nlas.push(Nla::Info(vec![Info::Kind(kind)]));
+        link
+    }
+
+    #[test]
+    fn test_update() {
+        let mut links = Links::default();
+        let wg0: InterfaceName = "wg0".parse().unwrap();
+        let wg1: InterfaceName = "wg1".parse().unwrap();
+        assert_eq!(
+            links.update(&RtnlMessage::NewLink(link(3, "wg0", true))),
+            Some(InterfaceEvent::Created(wg0))
+        );
+        // Going up is notified as a new link too.
+        assert_eq!(
+            links.update(&RtnlMessage::NewLink(link(3, "wg0", true))),
+            None
+        );
+        assert_eq!(
+            links.update(&RtnlMessage::NewLink(link(4, "dummy0", false))),
+            None
+        );
+        assert_eq!(
+            links.update(&RtnlMessage::NewLink(link(3, "wg1", true))),
+            Some(InterfaceEvent::Renamed { from: wg0, to: wg1 })
+        );
+        assert_eq!(
+            links.update(&RtnlMessage::DelLink(link(4, "dummy0", false))),
+            None
+        );
+        assert_eq!(
+            links.update(&RtnlMessage::DelLink(link(3, "wg1", true))),
+            Some(InterfaceEvent::Deleted(wg1))
+        );
+        assert_eq!(links.0.len(), 0);
+    }
+
+    #[test]
+    fn test_resync() {
+        let name = |name: &str| -> InterfaceName { name.parse().unwrap() };
+        let mut links = Links::default();
+        assert_eq!(
+            links.resync(BTreeMap::from([(3, name("wg0")), (5, name("wg1"))])),
+            vec![
+                InterfaceEvent::Created(name("wg0")),
+                InterfaceEvent::Created(name("wg1"))
+            ]
+        );
+        assert_eq!(
+            links.resync(BTreeMap::from([(5, name("wg2")), (7, name("wg3"))])),
+            vec![
+                InterfaceEvent::Deleted(name("wg0")),
+                InterfaceEvent::Renamed {
+                    from: name("wg1"),
+                    to: name("wg2")
+                },
+                InterfaceEvent::Created(name("wg3"))
+            ]
+        );
+    }
+}
@@ -5,8 +5,9 @@ mod linux {
         MAX_NETLINK_BUFFER_LENGTH - NETLINK_HEADER_LEN - GENL_HDRLEN;
 
     use netlink_packet_core::{
-        NetlinkDeserializable, NetlinkMessage, NetlinkPayload, NetlinkSerializable,
-        NETLINK_HEADER_LEN, NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL, NLM_F_REQUEST,
+        ErrorMessage, NetlinkDeserializable, NetlinkMessage, NetlinkPayload, NetlinkSerializable,
+        NETLINK_HEADER_LEN, NLM_F_ACK, NLM_F_ACK_TLVS, NLM_F_CAPPED, NLM_F_CREATE, NLM_F_EXCL,
+        NLM_F_REQUEST,
     };
     use netlink_packet_generic::{
         constants::GENL_HDRLEN,
@@ -15,7 +16,17 @@ mod linux {
     };
     use netlink_packet_route::RtnlMessage;
     use netlink_sys::{constants::NETLINK_GENERIC, protocols::NETLINK_ROUTE, Socket};
-    use std::{fmt::Debug, io};
+    use std::{
+        error,
+        fmt::{self, Debug},
+        io,
+        ops::{Deref, DerefMut},
+    };
+    use zeroize::Zeroize;
+
+    /// `NLMSGERR_ATTR_MSG`: the attribute of an extended ACK with the reason for
+    /// an error.
+    const NLMSGERR_ATTR_MSG: u16 = 1;
 
     macro_rules! get_nla_value {
         ($nlas:expr, $e:ident, $v:ident) => {
@@ -26,44 +37,168 @@ mod linux {
         };
     }
 
+    /// The buffer of a request and its responses, wiped when dropped as both may
+    /// carry private and preshared keys.
+    struct Buffer([u8; MAX_NETLINK_BUFFER_LENGTH]);
+
+    impl Deref for Buffer {
+        type Target = [u8; MAX_NETLINK_BUFFER_LENGTH];
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    impl DerefMut for Buffer {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.0
+        }
+    }
+
+    impl Drop for Buffer {
+        fn drop(&mut self) {
+            self.0[..].zeroize();
+        }
+    }
+
+    /// A request the kernel rejected, with the reason it gave in an extended ACK,
+    /// e.g. "Invalid IP address" rather than a bare `EINVAL`.
+    ///
+    /// Converted into an [`io::Error`] of the kind of `errno`, which can be
+    /// turned back with [`ExtAckError::from_io`]. Errors without a reason stay
+    /// plain OS errors.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ExtAckError {
+        pub errno: i32,
+        pub message: String,
+    }
+
+    impl ExtAckError {
+        /// The [`ExtAckError`] an I/O error was created from, if any.
+        pub fn from_io(error: &io::Error) -> Option<&Self> {
+            error.get_ref()?.downcast_ref()
+        }
+    }
+
+    impl fmt::Display for ExtAckError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "{}: {}",
+                io::Error::from_raw_os_error(self.errno),
+                self.message
+            )
+        }
+    }
+
+    impl error::Error for ExtAckError {}
+
+    impl From<ExtAckError> for io::Error {
+        fn from(e: ExtAckError) -> Self {
+            io::Error::new(io::Error::from_raw_os_error(e.errno).kind(), e)
+        }
+    }
+
+    /// The `NLMSGERR_ATTR_MSG` of an error message with `flags`, if the kernel
+    /// gave one.
+    fn ext_ack_message(flags: u16, error: &ErrorMessage) -> Option<String> {
+        if flags & NLM_F_ACK_TLVS == 0 {
+            return None;
+        }
+        // The attributes follow the rejected request, of which only the header
+        // is echoed if capped.
+        let request = if flags & NLM_F_CAPPED != 0 {
+            NETLINK_HEADER_LEN
+        } else {
+            u32::from_ne_bytes(error.header.get(..4)?.try_into().ok()?) as usize
+        };
+        let align = |len: usize| (len + 3) & !3;
+        let mut attrs = error.header.get(align(request)..)?;
+        while attrs.len() >= 4 {
+            let len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+            let kind = u16::from_ne_bytes([attrs[2], attrs[3]]);
+            let value = attrs.get(4..len)?;
+            if kind == NLMSGERR_ATTR_MSG {
+                let message = value.split(|&byte| byte == 0).next()?;
+                return Some(String::from_utf8_lossy(message).into_owned());
+            }
+            attrs = attrs.get(align(len)..)?;
+        }
+        None
+    }
+
+    /// Asks the kernel for the reasons of errors, echoing only the header of the
+    /// rejected request so the response fits the buffer. Kernels before 4.12
+    /// don't support it, and report bare errnos.
+    fn request_ext_ack(socket: &Socket) {
+        let _ = socket.set_cap_ack(true);
+        let _ = socket.set_ext_ack(true);
+    }
+
     pub fn netlink_request_genl<F>(
+        message: GenlMessage<F>,
+        flags: Option<u16>,
+    ) -> Result<Vec<NetlinkMessage<GenlMessage<F>>>, io::Error>
+    where
+        F: GenlFamily + Clone + Debug + Eq,
+        GenlMessage<F>: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
+    {
+        netlink_request_genl_wiped(message, flags, |_| {})
+    }
+
+    /// Like [`netlink_request_genl`], calling `wipe` on the message once it's
+    /// serialized, or dropped on an error, to wipe the keys it carries.
+    pub(crate) fn netlink_request_genl_wiped<F>(
         mut message: GenlMessage<F>,
         flags: Option<u16>,
+        wipe: fn(&mut GenlMessage<F>),
     ) -> Result<Vec<NetlinkMessage<GenlMessage<F>>>, io::Error>
     where
         F: GenlFamily + Clone + Debug + Eq,
         GenlMessage<F>: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
     {
         if message.family_id() == 0 {
-            let genlmsg: GenlMessage<GenlCtrl> = GenlMessage::from_payload(GenlCtrl {
-                cmd: GenlCtrlCmd::GetFamily,
-                nlas: vec![GenlCtrlAttrs::FamilyName(F::family_name().to_string())],
-            });
-            let responses =
-                netlink_request_genl::<GenlCtrl>(genlmsg, Some(NLM_F_REQUEST | NLM_F_ACK))?;
-
-            match responses.get(0) {
-                Some(NetlinkMessage {
-                    payload:
-                        NetlinkPayload::InnerMessage(GenlMessage {
-                            payload: GenlCtrl { nlas, .. },
-                            ..
-                        }),
-                    ..
-                }) => {
-                    let family_id = get_nla_value!(nlas, GenlCtrlAttrs, FamilyId)
-                        .ok_or_else(|| io::ErrorKind::NotFound)?;
-                    message.set_resolved_family_id(*family_id);
-                }
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Unexpected netlink payload",
-                    ))
+            let family = netlink_request_genl::<GenlCtrl>(
+                family_request::<F>(),
+                Some(NLM_F_REQUEST | NLM_F_ACK),
+            )
+            .and_then(|responses| family_id(&responses));
+            match family {
+                Ok(id) => message.set_resolved_family_id(id),
+                Err(e) => {
+                    wipe(&mut message);
+                    return Err(e);
                 }
-            };
+            }
+        }
+        request(message, flags, NETLINK_GENERIC, wipe)
+    }
+
+    /// The request resolving the id of generic netlink family `F`.
+    pub(crate) fn family_request<F: GenlFamily>() -> GenlMessage<GenlCtrl> {
+        GenlMessage::from_payload(GenlCtrl {
+            cmd: GenlCtrlCmd::GetFamily,
+            nlas: vec![GenlCtrlAttrs::FamilyName(F::family_name().to_string())],
+        })
+    }
+
+    fn family_id(responses: &[NetlinkMessage<GenlMessage<GenlCtrl>>]) -> io::Result<u16> {
+        match responses.first() {
+            Some(NetlinkMessage {
+                payload:
+                    NetlinkPayload::InnerMessage(GenlMessage {
+                        payload: GenlCtrl { nlas, .. },
+                        ..
+                    }),
+                ..
+            }) => get_nla_value!(nlas, GenlCtrlAttrs, FamilyId)
+                .copied()
+                .ok_or_else(|| io::ErrorKind::NotFound.into()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected netlink payload",
+            )),
         }
-        netlink_request(message, flags, NETLINK_GENERIC)
     }
 
     pub fn netlink_request_rtnl(
@@ -73,36 +208,111 @@ mod linux {
         netlink_request(message, flags, NETLINK_ROUTE)
     }
 
-    pub fn netlink_request<I>(
+    /// Serializes `message` with `flags` into `buf`, returning its length, and
+    /// then calls `wipe` on it.
+    fn serialize_request<I>(
         message: I,
         flags: Option<u16>,
-        socket: isize,
-    ) -> Result<Vec<NetlinkMessage<I>>, io::Error>
+        buf: &mut [u8; MAX_NETLINK_BUFFER_LENGTH],
+        wipe: fn(&mut I),
+    ) -> io::Result<usize>
     where
         NetlinkPayload<I>: From<I>,
         I: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
     {
         let mut req = NetlinkMessage::from(message);
 
-        if req.buffer_len() > MAX_NETLINK_BUFFER_LENGTH {
-            return Err(io::Error::new(
+        // Not printing the message, which may carry keys.
+        let result = if req.buffer_len() > MAX_NETLINK_BUFFER_LENGTH {
+            Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 format!(
-                    "Serialized netlink packet ({} bytes) larger than maximum size {}: {:?}",
+                    "Serialized netlink packet ({} bytes) larger than maximum size {}",
                     req.buffer_len(),
                     MAX_NETLINK_BUFFER_LENGTH,
-                    req
                 ),
-            ));
+            ))
+        } else {
+            req.header.flags =
+                flags.unwrap_or(NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL | NLM_F_CREATE);
+            req.finalize();
+            req.serialize(&mut buf[..]);
+            Ok(req.buffer_len())
+        };
+        if let NetlinkPayload::InnerMessage(message) = &mut req.payload {
+            wipe(message);
+        }
+        result
+    }
+
+    /// Adds the messages of a received datagram to `responses`, returning whether
+    /// it ended the response.
+    fn parse_datagram<I>(
+        datagram: &[u8],
+        responses: &mut Vec<NetlinkMessage<I>>,
+    ) -> io::Result<bool>
+    where
+        I: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
+    {
+        let mut offset = 0;
+        loop {
+            let bytes = &datagram[offset..];
+            let response = NetlinkMessage::<I>::deserialize(bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            match response.payload {
+                // We've parsed all parts of the response and can leave the loop.
+                NetlinkPayload::Ack(_) | NetlinkPayload::Done => return Ok(true),
+                NetlinkPayload::Error(e) => {
+                    return Err(match ext_ack_message(response.header.flags, &e) {
+                        Some(message) => ExtAckError {
+                            errno: e.code.abs(),
+                            message,
+                        }
+                        .into(),
+                        None => e.into(),
+                    })
+                }
+                _ => {}
+            }
+            offset += response.header.length as usize;
+            let length = response.header.length;
+            responses.push(response);
+            if offset == datagram.len() || length == 0 {
+                // We've fully parsed the datagram, but there may be further datagrams
+                // with additional netlink response parts.
+                return Ok(false);
+            }
         }
+    }
+
+    pub fn netlink_request<I>(
+        message: I,
+        flags: Option<u16>,
+        socket: isize,
+    ) -> Result<Vec<NetlinkMessage<I>>, io::Error>
+    where
+        NetlinkPayload<I>: From<I>,
+        I: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
+    {
+        request(message, flags, socket, |_| {})
+    }
 
-        req.header.flags = flags.unwrap_or(NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL | NLM_F_CREATE);
-        req.finalize();
-        let mut buf = [0; MAX_NETLINK_BUFFER_LENGTH];
-        req.serialize(&mut buf);
-        let len = req.buffer_len();
+    /// [`netlink_request`], calling `wipe` on the message once it's serialized.
+    fn request<I>(
+        message: I,
+        flags: Option<u16>,
+        socket: isize,
+        wipe: fn(&mut I),
+    ) -> Result<Vec<NetlinkMessage<I>>, io::Error>
+    where
+        NetlinkPayload<I>: From<I>,
+        I: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
+    {
+        let mut buf = Buffer([0; MAX_NETLINK_BUFFER_LENGTH]);
+        let len = serialize_request(message, flags, &mut buf, wipe)?;
 
         let socket = Socket::new(socket)?;
+        request_ext_ack(&socket);
         let kernel_addr = netlink_sys::SocketAddr::new(0, 0);
         socket.connect(&kernel_addr)?;
         let n_sent = socket.send(&buf[..len], 0)?;
@@ -116,31 +326,173 @@ mod linux {
         let mut responses = vec![];
         loop {
             let n_received = socket.recv(&mut &mut buf[..], 0)?;
-            let mut offset = 0;
-            loop {
-                let bytes = &buf[offset..];
-                let response = NetlinkMessage::<I>::deserialize(bytes)
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-                match response.payload {
-                    // We've parsed all parts of the response and can leave the loop.
-                    NetlinkPayload::Ack(_) | NetlinkPayload::Done => return Ok(responses),
-                    NetlinkPayload::Error(e) => return Err(e.into()),
-                    _ => {}
+            if parse_datagram(&buf[..n_received], &mut responses)? {
+                return Ok(responses);
+            }
+        }
+    }
+
+    /// Like [`netlink_request_genl`], without blocking the runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn netlink_request_genl_async<F>(
+        message: GenlMessage<F>,
+        flags: Option<u16>,
+    ) -> Result<Vec<NetlinkMessage<GenlMessage<F>>>, io::Error>
+    where
+        F: GenlFamily + Clone + Debug + Eq,
+        GenlMessage<F>: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
+    {
+        netlink_request_genl_wiped_async(message, flags, |_| {}).await
+    }
+
+    /// Like [`netlink_request_genl_wiped`], without blocking the runtime.
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn netlink_request_genl_wiped_async<F>(
+        mut message: GenlMessage<F>,
+        flags: Option<u16>,
+        wipe: fn(&mut GenlMessage<F>),
+    ) -> Result<Vec<NetlinkMessage<GenlMessage<F>>>, io::Error>
+    where
+        F: GenlFamily + Clone + Debug + Eq,
+        GenlMessage<F>: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
+    {
+        if message.family_id() == 0 {
+            let family = netlink_request_async(
+                family_request::<F>(),
+                Some(NLM_F_REQUEST | NLM_F_ACK),
+                NETLINK_GENERIC,
+            )
+            .await
+            .and_then(|responses| family_id(&responses));
+            match family {
+                Ok(id) => message.set_resolved_family_id(id),
+                Err(e) => {
+                    wipe(&mut message);
+                    return Err(e);
                 }
-                responses.push(response.clone());
-                offset += response.header.length as usize;
-                if offset == n_received || response.header.length == 0 {
-                    // We've fully parsed the datagram, but there may be further datagrams
-                    // with additional netlink response parts.
-                    break;
+            }
+        }
+        request_async(message, flags, NETLINK_GENERIC, wipe).await
+    }
+
+    /// Like [`netlink_request_rtnl`], without blocking the runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn netlink_request_rtnl_async(
+        message: RtnlMessage,
+        flags: Option<u16>,
+    ) -> Result<Vec<NetlinkMessage<RtnlMessage>>, io::Error> {
+        netlink_request_async(message, flags, NETLINK_ROUTE).await
+    }
+
+    /// Like [`netlink_request`], but waits for the socket on the tokio reactor
+    /// instead of blocking the thread.
+    #[cfg(feature = "tokio")]
+    pub async fn netlink_request_async<I>(
+        message: I,
+        flags: Option<u16>,
+        socket: isize,
+    ) -> Result<Vec<NetlinkMessage<I>>, io::Error>
+    where
+        NetlinkPayload<I>: From<I>,
+        I: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
+    {
+        request_async(message, flags, socket, |_| {}).await
+    }
+
+    /// Like [`request`], without blocking the runtime.
+    #[cfg(feature = "tokio")]
+    async fn request_async<I>(
+        message: I,
+        flags: Option<u16>,
+        socket: isize,
+        wipe: fn(&mut I),
+    ) -> Result<Vec<NetlinkMessage<I>>, io::Error>
+    where
+        NetlinkPayload<I>: From<I>,
+        I: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
+    {
+        use tokio::io::unix::AsyncFd;
+
+        let mut buf = Buffer([0; MAX_NETLINK_BUFFER_LENGTH]);
+        let len = serialize_request(message, flags, &mut buf, wipe)?;
+
+        let socket = Socket::new(socket)?;
+        request_ext_ack(&socket);
+        socket.set_non_blocking(true)?;
+        socket.connect(&netlink_sys::SocketAddr::new(0, 0))?;
+        let socket = AsyncFd::new(socket)?;
+        let n_sent = loop {
+            let mut guard = socket.writable().await?;
+            if let Ok(result) = guard.try_io(|socket| socket.get_ref().send(&buf[..len], 0)) {
+                break result?;
+            }
+        };
+        if n_sent != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to send netlink request",
+            ));
+        }
+
+        let mut responses = vec![];
+        loop {
+            let n_received = loop {
+                let mut guard = socket.readable().await?;
+                if let Ok(result) =
+                    guard.try_io(|socket| socket.get_ref().recv(&mut &mut buf[..], 0))
+                {
+                    break result?;
                 }
+            };
+            if parse_datagram(&buf[..n_received], &mut responses)? {
+                return Ok(responses);
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_ext_ack_message() {
+            let message = b"Invalid IP address\0";
+            let attr_len = 4 + message.len();
+            let len = NETLINK_HEADER_LEN + 4 + NETLINK_HEADER_LEN + ((attr_len + 3) & !3);
+            let mut datagram = vec![];
+            datagram.extend_from_slice(&(len as u32).to_ne_bytes());
+            datagram.extend_from_slice(&2u16.to_ne_bytes()); // NLMSG_ERROR
+            datagram.extend_from_slice(&(NLM_F_CAPPED | NLM_F_ACK_TLVS).to_ne_bytes());
+            datagram.extend_from_slice(&[0; 8]);
+            datagram.extend_from_slice(&(-libc::EINVAL).to_ne_bytes());
+            datagram.extend_from_slice(&[0; NETLINK_HEADER_LEN]);
+            datagram.extend_from_slice(&(attr_len as u16).to_ne_bytes());
+            datagram.extend_from_slice(&NLMSGERR_ATTR_MSG.to_ne_bytes());
+            datagram.extend_from_slice(message);
+            datagram.resize(len, 0);
+
+            let error = parse_datagram::<RtnlMessage>(&datagram, &mut vec![]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            let ext_ack = ExtAckError::from_io(&error).unwrap();
+            assert_eq!(ext_ack.errno, libc::EINVAL);
+            assert_eq!(ext_ack.message, "Invalid IP address");
+
+            // Without the flag, the error is a plain errno.
+            datagram[6..8].copy_from_slice(&0u16.to_ne_bytes());
+            let error = parse_datagram::<RtnlMessage>(&datagram, &mut vec![]).unwrap_err();
+            assert_eq!(error.raw_os_error(), Some(libc::EINVAL));
+        }
+    }
 }
 
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+pub(crate) use linux::netlink_request_genl_wiped_async;
+#[cfg(target_os = "linux")]
+pub(crate) use linux::{family_request, netlink_request_genl_wiped};
 #[cfg(target_os = "linux")]
 pub use linux::{
-    netlink_request, netlink_request_genl, netlink_request_rtnl, MAX_GENL_PAYLOAD_LENGTH,
-    MAX_NETLINK_BUFFER_LENGTH,
+    netlink_request, netlink_request_genl, netlink_request_rtnl, ExtAckError,
+    MAX_GENL_PAYLOAD_LENGTH, MAX_NETLINK_BUFFER_LENGTH,
 };
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+pub use linux::{netlink_request_async, netlink_request_genl_async, netlink_request_rtnl_async};
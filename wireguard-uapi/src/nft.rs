@@ -0,0 +1,379 @@
+//! nftables killswitch rules, behind the `nft` feature on Linux.
+//!
+//! A [`Killswitch`] drops every outgoing packet that would leave the host outside
+//! the tunnel, except WireGuard's own encrypted traffic and what keeps the host
+//! on its network: packets carrying the device's fwmark or sent from its listen
+//! port, packets to the peers' endpoints, IPv6 neighbor discovery and DHCP. Each
+//! interface gets its own table, so [removing](Killswitch::remove) the rules when
+//! the interface goes down leaves the rest of the ruleset alone.
+//!
+//! An [`MssClamp`] rewrites the MSS of TCP connections forwarded through the
+//! tunnel, so hosts behind it don't send segments too large for the tunnel and
+//! stall when ICMP "fragmentation needed" messages are filtered on the way. It
+//! can be part of a killswitch or installed on its own, and [`down`] removes
+//! both tables.
+//!
+//! There is no Windows counterpart: blocking traffic outside the tunnel there
+//! takes WFP filters, which this crate doesn't install (see [`crate::windows`]).
+use crate::{Device, InterfaceName};
+
+use ipnet::IpNet;
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    process::{Command, Stdio},
+};
+
+/// The name of the table `prefix` uses for `iface`, which can't contain every
+/// character interface names can.
+pub(crate) fn table_name(prefix: &str, iface: &InterfaceName) -> String {
+    let name: String = iface
+        .as_str_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}_{}", prefix, name)
+}
+
+/// `iface` as a quoted nft string, for matching it in rules. nft strings can't
+/// escape anything, so names containing `"` or `\`, which Linux allows, are
+/// refused rather than let them end the string and inject rules.
+pub(crate) fn quoted_iface(iface: &InterfaceName) -> io::Result<String> {
+    let name = iface.as_str_lossy();
+    if name.contains(['"', '\\']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("interface name {:?} can't be used in nft rules", name),
+        ));
+    }
+    Ok(format!("\"{}\"", name))
+}
+
+/// Runs `nft` with `args`, feeding it `script` on stdin.
+pub(crate) fn run(args: &[&str], script: Option<&str>) -> io::Result<()> {
+    let mut child = Command::new("nft")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let (Some(script), Some(mut stdin)) = (script, child.stdin.take()) {
+        stdin.write_all(script.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    log::debug!("nft {}: {:?}", args.join(" "), output.status.code());
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "nft {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// How the MSS of TCP connections through an interface is clamped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MssClamp {
+    /// To the MTU of the route the connection takes, as the kernel knows it.
+    PathMtu,
+    /// To fit packets of this many bytes: 40 bytes less for IPv4 and 60 for IPv6
+    /// headers.
+    Mtu(u32),
+}
+
+impl MssClamp {
+    /// The rules clamping the SYN packets forwarded through `iface`.
+    fn rules(&self, iface: &InterfaceName) -> io::Result<Vec<String>> {
+        let syn = "tcp flags syn / syn,rst tcp option maxseg size";
+        let iface = quoted_iface(iface)?;
+        Ok(match self {
+            // The route MTU of connections going in through the tunnel is the
+            // tunnel's on the way back out, so clamping outgoing SYNs covers both.
+            Self::PathMtu => vec![format!("oifname {} {} set rt mtu", iface, syn)],
+            Self::Mtu(mtu) => ["oifname", "iifname"]
+                .iter()
+                .flat_map(|direction| {
+                    [("ipv4", 40), ("ipv6", 60)].map(|(family, headers)| {
+                        format!(
+                            "meta nfproto {} {} {} {} set {}",
+                            family,
+                            direction,
+                            iface,
+                            syn,
+                            mtu.saturating_sub(headers)
+                        )
+                    })
+                })
+                .collect(),
+        })
+    }
+
+    /// The chain holding [`rules`](Self::rules), hooked before filtering.
+    fn chain(&self, iface: &InterfaceName) -> io::Result<String> {
+        let mut chain =
+            "\tchain forward {\n\t\ttype filter hook forward priority mangle; policy accept;\n"
+                .to_string();
+        for rule in self.rules(iface)? {
+            chain.push_str(&format!("\t\t{}\n", rule));
+        }
+        chain.push_str("\t}\n");
+        Ok(chain)
+    }
+
+    /// The nftables script installing the clamping of `iface` in a table of its
+    /// own. Installing it again replaces the previous rules.
+    ///
+    /// Fails for interface names nft strings can't hold.
+    pub fn ruleset(&self, iface: &InterfaceName) -> io::Result<String> {
+        Ok(format!(
+            "table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n{chain}}}\n",
+            table = table_name("wg_mss", iface),
+            chain = self.chain(iface)?
+        ))
+    }
+
+    /// Installs the clamping of `iface`. Requires root.
+    pub fn install(&self, iface: &InterfaceName) -> io::Result<()> {
+        run(&["-f", "-"], Some(&self.ruleset(iface)?))
+    }
+
+    /// Removes the clamping installed for `iface`, if there is any.
+    pub fn remove(iface: &InterfaceName) -> io::Result<()> {
+        let script = format!(
+            "table inet {table}\ndelete table inet {table}\n",
+            table = table_name("wg_mss", iface)
+        );
+        run(&["-f", "-"], Some(&script))
+    }
+}
+
+/// The killswitch of one interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Killswitch {
+    pub iface: InterfaceName,
+    /// The mark of the device's own packets, which may leave outside the tunnel.
+    pub fwmark: Option<u32>,
+    /// The port the device sends its encrypted packets from, which keeps
+    /// matching them when peers roam to endpoints not known at install time.
+    pub listen_port: Option<u16>,
+    /// The peer endpoints encrypted packets are sent to, as of when the rules
+    /// were built.
+    pub endpoints: Vec<SocketAddr>,
+    /// Other destinations allowed outside the tunnel, e.g. the local network.
+    pub allowed: Vec<IpNet>,
+    /// Clamping of the TCP connections forwarded through the tunnel.
+    pub mss_clamp: Option<MssClamp>,
+}
+
+impl Killswitch {
+    /// A killswitch for `device`, letting through its fwmark, its listen port and
+    /// the current endpoints of its peers.
+    pub fn for_device(device: &Device) -> Self {
+        let mut endpoints: Vec<_> = device
+            .peers
+            .iter()
+            .filter_map(|peer| peer.config.endpoint)
+            .collect();
+        endpoints.sort();
+        endpoints.dedup();
+        Self {
+            iface: device.name,
+            fwmark: device.fwmark.filter(|mark| *mark != 0),
+            listen_port: device.listen_port.filter(|port| *port != 0),
+            endpoints,
+            allowed: vec![],
+            mss_clamp: None,
+        }
+    }
+
+    /// Also allows traffic to `network` outside the tunnel.
+    pub fn allow(mut self, network: IpNet) -> Self {
+        self.allowed.push(network);
+        self
+    }
+
+    /// Also clamps the MSS of TCP connections forwarded through the tunnel.
+    pub fn clamp_mss(mut self, clamp: MssClamp) -> Self {
+        self.mss_clamp = Some(clamp);
+        self
+    }
+
+    fn table(&self) -> String {
+        table_name("wg_killswitch", &self.iface)
+    }
+
+    /// The nftables script installing the rules. Installing it again replaces the
+    /// previous rules of the interface.
+    ///
+    /// Fails for interface names nft strings can't hold.
+    pub fn ruleset(&self) -> io::Result<String> {
+        let family = |ipv4: bool| if ipv4 { "ip" } else { "ip6" };
+        let mut rules = vec![
+            "oifname \"lo\" accept".to_string(),
+            format!("oifname {} accept", quoted_iface(&self.iface)?),
+        ];
+        if let Some(fwmark) = self.fwmark {
+            rules.push(format!("meta mark {:#x} accept", fwmark));
+        }
+        if let Some(port) = self.listen_port {
+            rules.push(format!("udp sport {} accept", port));
+        }
+        // Without neighbor discovery and DHCP, the host drops off its network.
+        rules.extend([
+            "icmpv6 type { nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert, mld2-listener-report } accept".to_string(),
+            "udp sport 68 udp dport 67 accept".to_string(),
+            "udp sport 546 udp dport 547 accept".to_string(),
+        ]);
+        for endpoint in &self.endpoints {
+            rules.push(format!(
+                "{} daddr {} udp dport {} accept",
+                family(endpoint.is_ipv4()),
+                endpoint.ip(),
+                endpoint.port()
+            ));
+        }
+        for network in &self.allowed {
+            let ipv4 = matches!(network, IpNet::V4(_));
+            rules.push(format!("{} daddr {} accept", family(ipv4), network));
+        }
+        rules.push("drop".to_string());
+
+        let table = self.table();
+        let mut script = format!(
+            "table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n\
+             \tchain output {{\n\
+             \t\ttype filter hook output priority 0; policy accept;\n",
+            table = table
+        );
+        for rule in rules {
+            script.push_str(&format!("\t\t{}\n", rule));
+        }
+        script.push_str("\t}\n");
+        if let Some(clamp) = &self.mss_clamp {
+            script.push_str(&clamp.chain(&self.iface)?);
+        }
+        script.push_str("}\n");
+        Ok(script)
+    }
+
+    /// Installs the rules. Requires root.
+    pub fn install(&self) -> io::Result<()> {
+        run(&["-f", "-"], Some(&self.ruleset()?))
+    }
+
+    /// Removes the rules of `iface`, if there are any.
+    pub fn remove(iface: &InterfaceName) -> io::Result<()> {
+        let table = table_name("wg_killswitch", iface);
+        // Declaring the table first makes deleting it succeed when it is missing.
+        let script = format!(
+            "table inet {table}\ndelete table inet {table}\n",
+            table = table
+        );
+        run(&["-f", "-"], Some(&script))
+    }
+}
+
+/// Takes `device` down: deletes the interface, then removes its killswitch so
+/// traffic flows outside the tunnel again, and its MSS clamping.
+pub fn down(device: Device) -> io::Result<()> {
+    let iface = device.name;
+    device.delete()?;
+    Killswitch::remove(&iface)?;
+    MssClamp::remove(&iface)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ruleset() {
+        let killswitch = Killswitch {
+            iface: "wg-0".parse().unwrap(),
+            fwmark: Some(51820),
+            listen_port: Some(51821),
+            endpoints: vec![
+                "192.0.2.1:51820".parse().unwrap(),
+                "[2001:db8::1]:51821".parse().unwrap(),
+            ],
+            allowed: vec![],
+            mss_clamp: None,
+        }
+        .allow("192.168.1.0/24".parse().unwrap());
+
+        let ruleset = killswitch.ruleset().unwrap();
+        assert!(ruleset.starts_with("table inet wg_killswitch_wg_0\ndelete table"));
+        let rules: Vec<_> = ruleset
+            .lines()
+            .skip_while(|line| !line.contains("hook output"))
+            .skip(1)
+            .map(str::trim)
+            .take_while(|line| *line != "}")
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                "oifname \"lo\" accept",
+                "oifname \"wg-0\" accept",
+                "meta mark 0xca6c accept",
+                "udp sport 51821 accept",
+                "icmpv6 type { nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert, mld2-listener-report } accept",
+                "udp sport 68 udp dport 67 accept",
+                "udp sport 546 udp dport 547 accept",
+                "ip daddr 192.0.2.1 udp dport 51820 accept",
+                "ip6 daddr 2001:db8::1 udp dport 51821 accept",
+                "ip daddr 192.168.1.0/24 accept",
+                "drop",
+            ]
+        );
+        assert!(!ruleset.contains("maxseg"));
+
+        let ruleset = killswitch.clamp_mss(MssClamp::PathMtu).ruleset().unwrap();
+        assert!(ruleset.ends_with(
+            "\tchain forward {\n\
+             \t\ttype filter hook forward priority mangle; policy accept;\n\
+             \t\toifname \"wg-0\" tcp flags syn / syn,rst tcp option maxseg size set rt mtu\n\
+             \t}\n}\n"
+        ));
+    }
+
+    #[test]
+    fn test_mss_clamp() {
+        let iface = "wg0".parse().unwrap();
+        let ruleset = MssClamp::Mtu(1420).ruleset(&iface).unwrap();
+        assert!(ruleset.starts_with("table inet wg_mss_wg0\ndelete table inet wg_mss_wg0\n"));
+        let rules: Vec<_> = ruleset
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.contains("maxseg"))
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                "meta nfproto ipv4 oifname \"wg0\" tcp flags syn / syn,rst tcp option maxseg size set 1380",
+                "meta nfproto ipv6 oifname \"wg0\" tcp flags syn / syn,rst tcp option maxseg size set 1360",
+                "meta nfproto ipv4 iifname \"wg0\" tcp flags syn / syn,rst tcp option maxseg size set 1380",
+                "meta nfproto ipv6 iifname \"wg0\" tcp flags syn / syn,rst tcp option maxseg size set 1360",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quoted_iface() {
+        let iface: InterfaceName = "wg\"0".parse().unwrap();
+        let error = MssClamp::PathMtu.ruleset(&iface).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let killswitch = Killswitch {
+            iface,
+            fwmark: None,
+            listen_port: None,
+            endpoints: vec![],
+            allowed: vec![],
+            mss_clamp: None,
+        };
+        assert!(killswitch.ruleset().is_err());
+        assert!(quoted_iface(&"wg\\0".parse().unwrap()).is_err());
+        assert_eq!(quoted_iface(&"wg-0".parse().unwrap()).unwrap(), "\"wg-0\"");
+    }
+}
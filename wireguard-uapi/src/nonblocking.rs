@@ -0,0 +1,94 @@
+//! Async variants of the backend operations, behind the `tokio` feature.
+//!
+//! They speak the same protocols as their blocking counterparts, but wait on the
+//! netlink and unix sockets and the `wg` processes through the tokio reactor, so
+//! a control-plane daemon can call them from its tasks without `spawn_blocking`.
+//! They must be called within a tokio runtime.
+use crate::{backends, AllowedIpConflicts, Backend, Device, DeviceUpdate, Error, InterfaceName};
+
+impl Device {
+    /// Like [`list`](Device::list), without blocking.
+    pub async fn list_async(backend: Backend) -> Result<Vec<InterfaceName>, Error> {
+        let names = match backend {
+            #[cfg(target_os = "linux")]
+            Backend::Kernel => backends::kernel::enumerate_async().await,
+            #[cfg(unix)]
+            Backend::Userspace => backends::userspace::enumerate_async().await,
+            #[cfg(not(unix))]
+            Backend::Userspace => Err(backends::userspace_unsupported()),
+            Backend::Cli => backends::cli::enumerate_async().await,
+            // WireGuardNT calls are ioctls that don't wait on the network.
+            #[cfg(windows)]
+            Backend::Windows => backends::wireguard_nt::enumerate(),
+            #[cfg(feature = "mock")]
+            Backend::Mock => backends::mock::enumerate(),
+        };
+        names.map_err(|e| Error::from_backend(e, backend, None))
+    }
+
+    /// Like [`get`](Device::get), without blocking.
+    pub async fn get_async(name: &InterfaceName, backend: Backend) -> Result<Self, Error> {
+        let device = match backend {
+            #[cfg(target_os = "linux")]
+            Backend::Kernel => backends::kernel::get_by_name_async(name).await,
+            #[cfg(unix)]
+            Backend::Userspace => backends::userspace::get_by_name_async(name).await,
+            #[cfg(not(unix))]
+            Backend::Userspace => Err(backends::userspace_unsupported()),
+            Backend::Cli => backends::cli::get_by_name_async(name).await,
+            #[cfg(windows)]
+            Backend::Windows => backends::wireguard_nt::get_by_name(name),
+            #[cfg(feature = "mock")]
+            Backend::Mock => backends::mock::get_by_name(name),
+        };
+        device.map_err(|e| Error::from_backend(e, backend, Some(name)))
+    }
+
+    /// Like [`delete`](Device::delete), without blocking.
+    pub async fn delete_async(self) -> Result<(), Error> {
+        let deleted = match self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::Kernel => backends::kernel::delete_interface_async(&self.name).await,
+            #[cfg(unix)]
+            Backend::Userspace => backends::userspace::delete_interface_async(&self.name).await,
+            #[cfg(not(unix))]
+            Backend::Userspace => Err(backends::userspace_unsupported()),
+            // wg(8) can't delete interfaces, so this fails without running it.
+            Backend::Cli => backends::cli::delete_interface(&self.name),
+            #[cfg(windows)]
+            Backend::Windows => backends::wireguard_nt::delete_interface(&self.name),
+            #[cfg(feature = "mock")]
+            Backend::Mock => backends::mock::delete_interface(&self.name),
+        };
+        deleted.map_err(|e| Error::from_backend(e, self.backend, Some(&self.name)))
+    }
+}
+
+impl DeviceUpdate {
+    /// Like [`apply`](DeviceUpdate::apply), without blocking.
+    pub async fn apply_async(self, iface: &InterfaceName, backend: Backend) -> Result<(), Error> {
+        let mut update = self.resolve_duplicate_peers()?;
+        if update.allowed_ip_conflicts != AllowedIpConflicts::Steal {
+            let current = if Device::list_async(backend).await?.contains(iface) {
+                Some(Device::get_async(iface, backend).await?)
+            } else {
+                None
+            };
+            update = update.resolve_allowed_ip_conflicts(current.as_ref())?;
+        }
+        let applied = match backend {
+            #[cfg(target_os = "linux")]
+            Backend::Kernel => backends::kernel::apply_async(&update, iface).await,
+            #[cfg(unix)]
+            Backend::Userspace => backends::userspace::apply_async(&update, iface).await,
+            #[cfg(not(unix))]
+            Backend::Userspace => Err(backends::userspace_unsupported()),
+            Backend::Cli => backends::cli::apply_async(&update, iface).await,
+            #[cfg(windows)]
+            Backend::Windows => backends::wireguard_nt::apply(&update, iface),
+            #[cfg(feature = "mock")]
+            Backend::Mock => backends::mock::apply(&update, iface),
+        };
+        applied.map_err(|e| Error::from_backend(e, backend, Some(iface)))
+    }
+}
@@ -0,0 +1,181 @@
+//! OpenTelemetry instrumentation, behind the `otel` feature.
+//!
+//! [`PeerMetrics`] registers observable instruments for interface and peer stats
+//! on a [`Meter`], reporting the devices last handed to
+//! [`update`](PeerMetrics::update). [`apply`] and [`get`] wrap the backend calls
+//! in spans of the global tracer. Exporters and the SDK are configured by the
+//! application as usual.
+use crate::{
+    clock::{Clock, SystemClock},
+    Backend, Device, DeviceUpdate, InterfaceName,
+};
+
+use opentelemetry::{
+    global,
+    metrics::{Meter, ObservableCounter, ObservableGauge},
+    trace::{Status, TraceContextExt, Tracer},
+    KeyValue,
+};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+/// The instrumentation scope of the spans and default meter.
+pub const SCOPE: &str = "wireguard-uapi";
+
+fn peer_attributes(device: &Device, public_key: String) -> [KeyValue; 2] {
+    [
+        KeyValue::new(
+            "wireguard.interface",
+            device.name.as_str_lossy().into_owned(),
+        ),
+        KeyValue::new("wireguard.peer.public_key", public_key),
+    ]
+}
+
+/// Observable instruments for the stats of a set of devices.
+///
+/// Instruments are unregistered when this is dropped.
+pub struct PeerMetrics {
+    devices: Arc<Mutex<Vec<Device>>>,
+    _peers: ObservableGauge<u64>,
+    _rx_bytes: ObservableCounter<u64>,
+    _tx_bytes: ObservableCounter<u64>,
+    _handshake_age: ObservableGauge<f64>,
+}
+
+impl PeerMetrics {
+    /// Registers the instruments on the global meter provider.
+    pub fn new() -> Self {
+        Self::with_meter(&global::meter(SCOPE), Arc::new(SystemClock))
+    }
+
+    /// Registers the instruments on `meter`, measuring handshake ages with `clock`.
+    pub fn with_meter(meter: &Meter, clock: Arc<dyn Clock>) -> Self {
+        let devices: Arc<Mutex<Vec<Device>>> = Arc::default();
+
+        let source = devices.clone();
+        let peers = meter
+            .u64_observable_gauge("wireguard.interface.peers")
+            .with_description("Number of peers configured on the interface")
+            .with_callback(move |observer| {
+                for device in source.lock().unwrap().iter() {
+                    observer.observe(
+                        device.peers.len() as u64,
+                        &[KeyValue::new(
+                            "wireguard.interface",
+                            device.name.as_str_lossy().into_owned(),
+                        )],
+                    );
+                }
+            })
+            .init();
+
+        let source = devices.clone();
+        let rx_bytes = meter
+            .u64_observable_counter("wireguard.peer.rx_bytes")
+            .with_description("Bytes received from the peer")
+            .with_unit("By")
+            .with_callback(move |observer| {
+                for device in source.lock().unwrap().iter() {
+                    for peer in &device.peers {
+                        let key = peer.config.public_key.to_base64();
+                        observer.observe(peer.stats.rx_bytes, &peer_attributes(device, key));
+                    }
+                }
+            })
+            .init();
+
+        let source = devices.clone();
+        let tx_bytes = meter
+            .u64_observable_counter("wireguard.peer.tx_bytes")
+            .with_description("Bytes sent to the peer")
+            .with_unit("By")
+            .with_callback(move |observer| {
+                for device in source.lock().unwrap().iter() {
+                    for peer in &device.peers {
+                        let key = peer.config.public_key.to_base64();
+                        observer.observe(peer.stats.tx_bytes, &peer_attributes(device, key));
+                    }
+                }
+            })
+            .init();
+
+        let source = devices.clone();
+        let handshake_age = meter
+            .f64_observable_gauge("wireguard.peer.handshake_age")
+            .with_description("Time since the last handshake with the peer")
+            .with_unit("s")
+            .with_callback(move |observer| {
+                for device in source.lock().unwrap().iter() {
+                    for peer in &device.peers {
+                        if let Some(age) = peer.stats.handshake_age(clock.as_ref()) {
+                            let key = peer.config.public_key.to_base64();
+                            observer.observe(age.as_secs_f64(), &peer_attributes(device, key));
+                        }
+                    }
+                }
+            })
+            .init();
+
+        Self {
+            devices,
+            _peers: peers,
+            _rx_bytes: rx_bytes,
+            _tx_bytes: tx_bytes,
+            _handshake_age: handshake_age,
+        }
+    }
+
+    /// Replaces the devices reported at the next collection.
+    pub fn update(&self, devices: Vec<Device>) {
+        *self.devices.lock().unwrap() = devices;
+    }
+}
+
+impl Default for PeerMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs `f` in a span named `name` of the global tracer, marking the span as
+/// failed if `f` returns an error.
+fn in_span<T>(
+    name: &'static str,
+    attributes: Vec<KeyValue>,
+    f: impl FnOnce() -> io::Result<T>,
+) -> io::Result<T> {
+    global::tracer(SCOPE).in_span(name, |cx| {
+        let span = cx.span();
+        span.set_attributes(attributes);
+        let result = f();
+        if let Err(e) = &result {
+            span.set_status(Status::error(e.to_string()));
+        }
+        result
+    })
+}
+
+/// Applies `update` to `iface` in a `wireguard.apply` span.
+pub fn apply(update: DeviceUpdate, iface: &InterfaceName, backend: Backend) -> io::Result<()> {
+    let attributes = vec![
+        KeyValue::new("wireguard.interface", iface.as_str_lossy().into_owned()),
+        KeyValue::new("wireguard.backend", backend.to_string()),
+        KeyValue::new("wireguard.update.peers", update.peers.len() as i64),
+        KeyValue::new("wireguard.update.replace_peers", update.replace_peers),
+    ];
+    in_span("wireguard.apply", attributes, || {
+        update.apply(iface, backend)
+    })
+}
+
+/// Reads `iface` in a `wireguard.get` span.
+pub fn get(iface: &InterfaceName, backend: Backend) -> io::Result<Device> {
+    let attributes = vec![
+        KeyValue::new("wireguard.interface", iface.as_str_lossy().into_owned()),
+        KeyValue::new("wireguard.backend", backend.to_string()),
+    ];
+    in_span("wireguard.get", attributes, || Device::get(iface, backend))
+}
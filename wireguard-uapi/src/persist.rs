@@ -0,0 +1,651 @@
+//! Making imperatively applied interfaces survive reboots.
+//!
+//! [`to_systemd_unit`] renders a running interface as the files systemd brings
+//! it up from at boot: a wg-quick config for the `wg-quick@` template unit of
+//! wireguard-tools, or a systemd-networkd `.netdev`/`.network` pair.
+//! [`install`] writes them with permissions that keep the private key private,
+//! and [`enable`] enables the unit through systemd's D-Bus API. Like the
+//! [`systemd`](crate::systemd) module, this speaks the protocol itself instead of
+//! linking libsystemd or shelling out to `systemctl`.
+use crate::{
+    conf::{interface_section, peer_section, ConfFile},
+    Device,
+};
+
+use ipnet::IpNet;
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write as _},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+const WG_QUICK_DIR: &str = "/etc/wireguard";
+const NETWORKD_DIR: &str = "/etc/systemd/network";
+
+/// How the interface is brought up at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitKind {
+    /// `wg-quick@<name>.service`, reading `/etc/wireguard/<name>.conf`.
+    WgQuick,
+    /// systemd-networkd, reading `/etc/systemd/network/50-<name>.{netdev,network}`.
+    Networkd,
+}
+
+/// A file of a [`SystemdUnit`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitFile {
+    pub path: PathBuf,
+    pub contents: String,
+    /// Whether the file holds the private key. Secret files are only readable by
+    /// root, and by the `systemd-network` group for networkd.
+    pub secret: bool,
+}
+
+/// What persists an interface: the files to install and the unit to enable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemdUnit {
+    pub kind: UnitKind,
+    pub files: Vec<UnitFile>,
+    /// The unit that brings the interface up at boot once enabled.
+    pub unit: String,
+}
+
+/// Renders `device` as the files systemd brings it up from, with `addresses`
+/// assigned to it. The interface itself doesn't know its addresses.
+pub fn to_systemd_unit(device: &Device, kind: UnitKind, addresses: &[IpNet]) -> SystemdUnit {
+    let name = device.name.as_str_lossy();
+    let addresses: Vec<_> = addresses.iter().map(IpNet::to_string).collect();
+    match kind {
+        UnitKind::WgQuick => {
+            let mut interface = interface_section(
+                device.private_key.as_ref(),
+                device.listen_port,
+                device.fwmark,
+            );
+            if !addresses.is_empty() {
+                interface.set("Address", addresses.join(", "));
+            }
+            let mut file = ConfFile::default();
+            file.sections.push(interface);
+            file.sections
+                .extend(device.peers.iter().map(|peer| peer_section(&peer.config)));
+            SystemdUnit {
+                kind,
+                files: vec![UnitFile {
+                    path: Path::new(WG_QUICK_DIR).join(format!("{}.conf", name)),
+                    contents: file.to_string(),
+                    secret: true,
+                }],
+                unit: format!("wg-quick@{}.service", name),
+            }
+        }
+        UnitKind::Networkd => {
+            let mut netdev = format!("[NetDev]\nName={}\nKind=wireguard\n\n[WireGuard]\n", name);
+            if let Some(key) = &device.private_key {
+                writeln!(netdev, "PrivateKey={}", key.to_base64()).ok();
+            }
+            if let Some(port) = device.listen_port {
+                writeln!(netdev, "ListenPort={}", port).ok();
+            }
+            if let Some(fwmark) = device.fwmark.filter(|fwmark| *fwmark != 0) {
+                writeln!(netdev, "FirewallMark={:#x}", fwmark).ok();
+            }
+            for peer in &device.peers {
+                let peer = &peer.config;
+                write!(
+                    netdev,
+                    "\n[WireGuardPeer]\nPublicKey={}\n",
+                    peer.public_key.to_base64()
+                )
+                .ok();
+                if let Some(key) = &peer.preshared_key {
+                    writeln!(netdev, "PresharedKey={}", key.to_base64()).ok();
+                }
+                if let Some(endpoint) = peer.endpoint {
+                    writeln!(netdev, "Endpoint={}", endpoint).ok();
+                }
+                if !peer.allowed_ips.is_empty() {
+                    let ips: Vec<_> = peer
+                        .allowed_ips
+                        .iter()
+                        .map(|ip| format!("{}/{}", ip.address, ip.cidr))
+                        .collect();
+                    writeln!(netdev, "AllowedIPs={}", ips.join(",")).ok();
+                }
+                if let Some(interval) = peer.persistent_keepalive_interval {
+                    writeln!(netdev, "PersistentKeepalive={}", interval).ok();
+                }
+            }
+
+            let mut network = format!("[Match]\nName={}\n\n[Network]\n", name);
+            for address in &addresses {
+                writeln!(network, "Address={}", address).ok();
+            }
+            let dir = Path::new(NETWORKD_DIR);
+            SystemdUnit {
+                kind,
+                files: vec![
+                    UnitFile {
+                        path: dir.join(format!("50-{}.netdev", name)),
+                        contents: netdev,
+                        secret: true,
+                    },
+                    UnitFile {
+                        path: dir.join(format!("50-{}.network", name)),
+                        contents: network,
+                        secret: false,
+                    },
+                ],
+                unit: "systemd-networkd.service".to_string(),
+            }
+        }
+    }
+}
+
+/// The group id of `systemd-network`, which networkd reads secret files as.
+fn networkd_group() -> Option<libc::gid_t> {
+    // SAFETY: the name is nul-terminated, and the entry is read before any other
+    // call could overwrite it.
+    unsafe {
+        let group = libc::getgrnam(c"systemd-network".as_ptr());
+        (!group.is_null()).then(|| (*group).gr_gid)
+    }
+}
+
+/// Writes the files of `unit`, replacing existing ones.
+pub fn install(unit: &SystemdUnit) -> io::Result<()> {
+    for file in &unit.files {
+        if let Some(dir) = file.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let group = match (file.secret, unit.kind) {
+            (true, UnitKind::Networkd) => networkd_group(),
+            _ => None,
+        };
+        let mode = match (file.secret, group) {
+            (false, _) => 0o644,
+            (true, Some(_)) => 0o640,
+            (true, None) => 0o600,
+        };
+        // Restrict an existing file before rewriting it, so the key never sits in
+        // a file others can read.
+        if file.path.exists() {
+            fs::set_permissions(&file.path, fs::Permissions::from_mode(mode))?;
+        }
+        let mut out = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(&file.path)?;
+        out.write_all(file.contents.as_bytes())?;
+        if let Some(gid) = group {
+            std::os::unix::fs::fchown(&out, None, Some(gid))?;
+        }
+    }
+    Ok(())
+}
+
+/// Enables `unit` through systemd's D-Bus API, so it starts at boot. For
+/// networkd, it also reloads networkd, which creates the interface if it's
+/// missing.
+pub fn enable(unit: &SystemdUnit) -> io::Result<()> {
+    let mut bus = dbus::Connection::system()?;
+    let mut body = dbus::Body::default();
+    body.string_array(&[&unit.unit]);
+    body.boolean(false);
+    body.boolean(true);
+    bus.call(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        "org.freedesktop.systemd1.Manager",
+        "EnableUnitFiles",
+        "asbb",
+        body,
+    )?;
+    bus.call(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        "org.freedesktop.systemd1.Manager",
+        "Reload",
+        "",
+        dbus::Body::default(),
+    )?;
+    if unit.kind == UnitKind::Networkd {
+        bus.call(
+            "org.freedesktop.network1",
+            "/org/freedesktop/network1",
+            "org.freedesktop.network1.Manager",
+            "Reload",
+            "",
+            dbus::Body::default(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Persists `device` in one call: [`install`]s its files and [`enable`]s its
+/// unit.
+pub fn persist(device: &Device, kind: UnitKind, addresses: &[IpNet]) -> io::Result<SystemdUnit> {
+    let unit = to_systemd_unit(device, kind, addresses);
+    install(&unit)?;
+    enable(&unit)?;
+    Ok(unit)
+}
+
+/// Just enough of the D-Bus wire protocol to call methods with string, string
+/// array and boolean arguments.
+mod dbus {
+    use std::{
+        env,
+        io::{self, Read, Write},
+        os::unix::net::UnixStream,
+    };
+
+    const SYSTEM_BUS: &str = "/run/dbus/system_bus_socket";
+
+    const METHOD_CALL: u8 = 1;
+    const METHOD_RETURN: u8 = 2;
+    const ERROR: u8 = 3;
+
+    const FIELD_PATH: u8 = 1;
+    const FIELD_INTERFACE: u8 = 2;
+    const FIELD_MEMBER: u8 = 3;
+    const FIELD_ERROR_NAME: u8 = 4;
+    const FIELD_REPLY_SERIAL: u8 = 5;
+    const FIELD_DESTINATION: u8 = 6;
+    const FIELD_SIGNATURE: u8 = 8;
+
+    fn invalid(message: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, format!("D-Bus: {}", message))
+    }
+
+    /// Marshalled values, aligned relative to the start of the buffer.
+    #[derive(Debug, Default)]
+    pub struct Body(pub Vec<u8>);
+
+    impl Body {
+        fn align(&mut self, alignment: usize) {
+            while !self.0.len().is_multiple_of(alignment) {
+                self.0.push(0);
+            }
+        }
+
+        fn byte(&mut self, value: u8) {
+            self.0.push(value);
+        }
+
+        fn uint32(&mut self, value: u32) {
+            self.align(4);
+            self.0.extend_from_slice(&value.to_le_bytes());
+        }
+
+        pub fn string(&mut self, value: &str) {
+            self.uint32(value.len() as u32);
+            self.0.extend_from_slice(value.as_bytes());
+            self.0.push(0);
+        }
+
+        fn signature(&mut self, value: &str) {
+            self.byte(value.len() as u8);
+            self.0.extend_from_slice(value.as_bytes());
+            self.0.push(0);
+        }
+
+        pub fn boolean(&mut self, value: bool) {
+            self.uint32(value.into());
+        }
+
+        pub fn string_array(&mut self, values: &[&str]) {
+            self.uint32(0);
+            let length_at = self.0.len() - 4;
+            let start = self.0.len();
+            for value in values {
+                self.string(value);
+            }
+            let length = (self.0.len() - start) as u32;
+            self.0[length_at..start].copy_from_slice(&length.to_le_bytes());
+        }
+
+        /// A header field: a struct of the code and a variant.
+        fn field(&mut self, code: u8, signature: &str, value: &str) {
+            self.align(8);
+            self.byte(code);
+            self.signature(signature);
+            match signature {
+                "g" => self.signature(value),
+                _ => self.string(value),
+            }
+        }
+    }
+
+    /// The method call `member` with `body`, whose arguments have `signature`.
+    pub(super) fn method_call(
+        serial: u32,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        signature: &str,
+        body: &Body,
+    ) -> Vec<u8> {
+        let mut message = Body::default();
+        message.0.extend_from_slice(&[b'l', METHOD_CALL, 0, 1]);
+        message.uint32(body.0.len() as u32);
+        message.uint32(serial);
+        message.uint32(0);
+        let fields_start = message.0.len();
+        message.field(FIELD_PATH, "o", path);
+        message.field(FIELD_INTERFACE, "s", interface);
+        message.field(FIELD_MEMBER, "s", member);
+        message.field(FIELD_DESTINATION, "s", destination);
+        if !signature.is_empty() {
+            message.field(FIELD_SIGNATURE, "g", signature);
+        }
+        let length = (message.0.len() - fields_start) as u32;
+        message.0[12..16].copy_from_slice(&length.to_le_bytes());
+        message.align(8);
+        message.0.extend_from_slice(&body.0);
+        message.0
+    }
+
+    /// Reads values out of a received message.
+    struct Reader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+        big_endian: bool,
+    }
+
+    impl Reader<'_> {
+        fn take(&mut self, count: usize) -> io::Result<&[u8]> {
+            let end = self.position + count;
+            let bytes = self
+                .bytes
+                .get(self.position..end)
+                .ok_or_else(|| invalid("truncated message"))?;
+            self.position = end;
+            Ok(bytes)
+        }
+
+        fn align(&mut self, alignment: usize) {
+            self.position = self.position.div_ceil(alignment) * alignment;
+        }
+
+        fn uint32(&mut self) -> io::Result<u32> {
+            self.align(4);
+            let bytes: [u8; 4] = self.take(4)?.try_into().expect("4 bytes");
+            Ok(if self.big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            })
+        }
+
+        fn string(&mut self) -> io::Result<String> {
+            let length = self.uint32()? as usize;
+            let value = String::from_utf8_lossy(self.take(length)?).into_owned();
+            self.take(1)?;
+            Ok(value)
+        }
+
+        fn signature(&mut self) -> io::Result<String> {
+            let length = usize::from(self.take(1)?[0]);
+            let value = String::from_utf8_lossy(self.take(length)?).into_owned();
+            self.take(1)?;
+            Ok(value)
+        }
+    }
+
+    /// What a received message says about the call it answers.
+    #[derive(Debug, Default, PartialEq, Eq)]
+    pub(super) struct Reply {
+        pub kind: u8,
+        pub reply_serial: Option<u32>,
+        pub error_name: Option<String>,
+        /// The first argument of an error, its message.
+        pub error_message: Option<String>,
+    }
+
+    /// The length of the message starting with the 16 bytes of `fixed`.
+    fn message_length(fixed: &[u8; 16]) -> usize {
+        let read = |bytes: [u8; 4]| match fixed[0] {
+            b'B' => u32::from_be_bytes(bytes),
+            _ => u32::from_le_bytes(bytes),
+        } as usize;
+        let body = read(fixed[4..8].try_into().expect("4 bytes"));
+        let fields = read(fixed[12..16].try_into().expect("4 bytes"));
+        (16 + fields).div_ceil(8) * 8 + body
+    }
+
+    pub(super) fn parse_reply(message: &[u8]) -> io::Result<Reply> {
+        let mut reader = Reader {
+            bytes: message,
+            position: 0,
+            big_endian: message.first() == Some(&b'B'),
+        };
+        let mut reply = Reply {
+            kind: reader.take(4)?[1],
+            ..Default::default()
+        };
+        reader.uint32()?;
+        reader.uint32()?;
+        let fields_end = reader.uint32()? as usize + reader.position;
+        let mut signature = String::new();
+        while reader.position < fields_end {
+            reader.align(8);
+            let code = reader.take(1)?[0];
+            match (code, reader.signature()?.as_str()) {
+                (FIELD_REPLY_SERIAL, "u") => reply.reply_serial = Some(reader.uint32()?),
+                (FIELD_ERROR_NAME, "s") => reply.error_name = Some(reader.string()?),
+                (FIELD_SIGNATURE, "g") => signature = reader.signature()?,
+                (_, "s" | "o") => {
+                    reader.string()?;
+                }
+                (_, "g") => {
+                    reader.signature()?;
+                }
+                (_, "u") => {
+                    reader.uint32()?;
+                }
+                (_, other) => return Err(invalid(&format!("unexpected header type {}", other))),
+            }
+        }
+        reader.align(8);
+        if reply.kind == ERROR && signature.starts_with('s') {
+            reply.error_message = Some(reader.string()?);
+        }
+        Ok(reply)
+    }
+
+    pub struct Connection {
+        stream: UnixStream,
+        serial: u32,
+    }
+
+    impl Connection {
+        /// Connects and authenticates to the system bus.
+        pub fn system() -> io::Result<Self> {
+            let address = env::var("DBUS_SYSTEM_BUS_ADDRESS").ok();
+            let path = address
+                .as_deref()
+                .and_then(|address| address.strip_prefix("unix:path="))
+                .map(|path| path.split(',').next().unwrap_or(path))
+                .unwrap_or(SYSTEM_BUS);
+            let mut stream = UnixStream::connect(path)?;
+
+            // SAFETY: getuid can't fail.
+            let uid = unsafe { libc::getuid() };
+            let uid = hex::encode(uid.to_string());
+            stream.write_all(format!("\0AUTH EXTERNAL {}\r\n", uid).as_bytes())?;
+            let mut line = vec![];
+            let mut byte = [0u8];
+            while !line.ends_with(b"\r\n") {
+                stream.read_exact(&mut byte)?;
+                line.push(byte[0]);
+            }
+            if !line.starts_with(b"OK ") {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("D-Bus: {}", String::from_utf8_lossy(&line).trim()),
+                ));
+            }
+            stream.write_all(b"BEGIN\r\n")?;
+
+            let mut connection = Self { stream, serial: 0 };
+            connection.call(
+                "org.freedesktop.DBus",
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus",
+                "Hello",
+                "",
+                Body::default(),
+            )?;
+            Ok(connection)
+        }
+
+        /// Calls a method and waits for its reply, failing with the error it
+        /// returns.
+        pub fn call(
+            &mut self,
+            destination: &str,
+            path: &str,
+            interface: &str,
+            member: &str,
+            signature: &str,
+            body: Body,
+        ) -> io::Result<()> {
+            self.serial += 1;
+            let message = method_call(
+                self.serial,
+                destination,
+                path,
+                interface,
+                member,
+                signature,
+                &body,
+            );
+            self.stream.write_all(&message)?;
+            loop {
+                let mut fixed = [0u8; 16];
+                self.stream.read_exact(&mut fixed)?;
+                let mut message = fixed.to_vec();
+                message.resize(message_length(&fixed), 0);
+                self.stream.read_exact(&mut message[16..])?;
+                // Signals such as NameAcquired may arrive before the reply.
+                let reply = parse_reply(&message)?;
+                if reply.reply_serial != Some(self.serial) {
+                    continue;
+                }
+                return match reply.kind {
+                    METHOD_RETURN => Ok(()),
+                    ERROR => {
+                        let name = reply.error_name.unwrap_or_default();
+                        let kind = match name.as_str() {
+                            "org.freedesktop.DBus.Error.AccessDenied"
+                            | "org.freedesktop.DBus.Error.InteractiveAuthorizationRequired" => {
+                                io::ErrorKind::PermissionDenied
+                            }
+                            "org.freedesktop.DBus.Error.ServiceUnknown"
+                            | "org.freedesktop.systemd1.NoSuchUnit" => io::ErrorKind::NotFound,
+                            _ => io::ErrorKind::Other,
+                        };
+                        Err(io::Error::new(
+                            kind,
+                            format!(
+                                "{}.{}: {}: {}",
+                                interface,
+                                member,
+                                name,
+                                reply.error_message.unwrap_or_default()
+                            ),
+                        ))
+                    }
+                    _ => Err(invalid("unexpected reply")),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, PeerInfo};
+
+    fn device() -> Device {
+        let mut peer = PeerInfo::fixture(Key([2u8; 32]))
+            .with_endpoint("192.0.2.1:51820")
+            .with_allowed_ips(&["10.0.0.2/32", "10.0.1.0/24"]);
+        peer.config.persistent_keepalive_interval = Some(25);
+        Device {
+            private_key: Some(Key([1u8; 32])),
+            fwmark: Some(0x2a),
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", vec![peer])
+        }
+    }
+
+    #[test]
+    fn test_wg_quick_unit() {
+        let addresses = ["10.0.0.1/24".parse().unwrap()];
+        let unit = to_systemd_unit(&device(), UnitKind::WgQuick, &addresses);
+        assert_eq!(unit.unit, "wg-quick@wg0.service");
+        assert_eq!(unit.files[0].path, Path::new("/etc/wireguard/wg0.conf"));
+        assert!(unit.files[0].secret);
+        let config: crate::conf::QuickConfig = unit.files[0].contents.parse().unwrap();
+        assert_eq!(config.interface.addresses, addresses);
+        assert_eq!(config.update.peers.len(), 1);
+    }
+
+    #[test]
+    fn test_networkd_unit() {
+        let unit = to_systemd_unit(
+            &device(),
+            UnitKind::Networkd,
+            &["10.0.0.1/24".parse().unwrap()],
+        );
+        assert_eq!(unit.unit, "systemd-networkd.service");
+        let netdev = &unit.files[0];
+        assert_eq!(netdev.path, Path::new("/etc/systemd/network/50-wg0.netdev"));
+        assert!(netdev.contents.contains("Kind=wireguard\n"));
+        assert!(netdev.contents.contains("FirewallMark=0x2a\n"));
+        assert!(netdev
+            .contents
+            .contains("AllowedIPs=10.0.0.2/32,10.0.1.0/24\n"));
+        let network = &unit.files[1];
+        assert!(!network.secret);
+        assert_eq!(
+            network.contents,
+            "[Match]\nName=wg0\n\n[Network]\nAddress=10.0.0.1/24\n"
+        );
+    }
+
+    #[test]
+    fn test_dbus_marshalling() {
+        let mut body = dbus::Body::default();
+        body.string_array(&["wg-quick@wg0.service"]);
+        body.boolean(false);
+        body.boolean(true);
+        // Array length, then the string's length, bytes and nul, then the booleans.
+        assert_eq!(&body.0[..8], &[25, 0, 0, 0, 20, 0, 0, 0]);
+        assert_eq!(body.0.len(), 4 + 4 + 21 + 3 + 4 + 4);
+
+        let message = dbus::method_call(
+            7,
+            "org.freedesktop.systemd1",
+            "/org/freedesktop/systemd1",
+            "org.freedesktop.systemd1.Manager",
+            "EnableUnitFiles",
+            "asbb",
+            &body,
+        );
+        assert_eq!(&message[..4], &[b'l', 1, 0, 1]);
+        assert_eq!(&message[8..12], &7u32.to_le_bytes());
+        assert!(message.ends_with(&body.0));
+        let parsed = dbus::parse_reply(&message).unwrap();
+        assert_eq!(parsed.kind, 1);
+        assert_eq!(parsed.reply_serial, None);
+    }
+}
@@ -0,0 +1,205 @@
+//! Applying updates to several interfaces as one unit.
+//!
+//! A [`Plan`] collects one [`DeviceUpdate`] per interface. [`execute_atomic`]
+//! snapshots every interface first and, if any apply fails, restores all of them,
+//! so that e.g. the routes of a primary and a backup tunnel never disagree.
+//!
+//! [`execute_atomic`]: Plan::execute_atomic
+use crate::{
+    backup::DeviceBackup, ApplyError, Backend, Device, DeviceUpdate, Error, InterfaceName,
+};
+
+use std::{error, fmt, io};
+
+/// Updates to apply to several interfaces, in order.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Plan {
+    pub changes: Vec<(InterfaceName, DeviceUpdate)>,
+}
+
+/// The state of an interface before the plan touched it.
+enum Snapshot {
+    Existing(DeviceBackup),
+    Absent,
+}
+
+/// Why a plan failed, and whether the interfaces were restored.
+#[derive(Debug)]
+pub struct PlanError {
+    /// The interface whose update failed.
+    pub iface: InterfaceName,
+    /// The failure of that update.
+    pub source: ApplyError,
+    /// Interfaces that could not be restored, if any; these are left in whatever
+    /// state the failed rollback produced.
+    pub rollback_errors: Vec<(InterfaceName, io::Error)>,
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.iface, self.source)?;
+        if !self.rollback_errors.is_empty() {
+            let failed: Vec<_> = self
+                .rollback_errors
+                .iter()
+                .map(|(iface, e)| format!("{} ({})", iface, e))
+                .collect();
+            write!(f, "; rollback failed for {}", failed.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for PlanError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<PlanError> for io::Error {
+    fn from(e: PlanError) -> Self {
+        io::Error::new(e.source.source.kind(), e.to_string())
+    }
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `update` for `iface`, to be applied after the changes already added.
+    #[must_use]
+    pub fn add(mut self, iface: InterfaceName, update: DeviceUpdate) -> Self {
+        self.changes.push((iface, update));
+        self
+    }
+
+    /// Applies every change, or none of them.
+    ///
+    /// All updates are [validated](DeviceUpdate::validate) before anything is
+    /// touched. Each interface is then snapshotted and updated in turn; if one
+    /// update fails, every interface touched so far (including the failing one) is
+    /// restored in reverse order, and interfaces the plan created are deleted.
+    ///
+    /// Restoring only covers WireGuard settings: keys, listen port, fwmark and
+    /// peers. Statistics and handshakes of replaced peers are lost.
+    pub fn execute_atomic(self, backend: Backend) -> Result<(), PlanError> {
+        for (iface, update) in &self.changes {
+            update.validate().map_err(|source| PlanError {
+                iface: *iface,
+                source,
+                rollback_errors: vec![],
+            })?;
+        }
+
+        let mut touched: Vec<(InterfaceName, Snapshot)> = vec![];
+        for (iface, update) in self.changes {
+            let snapshot = match snapshot(&iface, backend) {
+                Ok(snapshot) => snapshot,
+                Err(e) => return Err(rollback(touched, iface, e.into(), backend)),
+            };
+            touched.push((iface, snapshot));
+
+            if let Err(e) = update.apply_checked(&iface, backend) {
+                return Err(rollback(touched, iface, e, backend));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The current state of `iface`, to restore on rollback.
+fn snapshot(iface: &InterfaceName, backend: Backend) -> io::Result<Snapshot> {
+    if !Device::list(backend)?.contains(iface) {
+        return Ok(Snapshot::Absent);
+    }
+    Ok(Snapshot::Existing(DeviceBackup::from(&Device::get(
+        iface, backend,
+    )?)))
+}
+
+/// Restores `touched` in reverse order after `iface` failed with `source`.
+fn rollback(
+    touched: Vec<(InterfaceName, Snapshot)>,
+    iface: InterfaceName,
+    source: ApplyError,
+    backend: Backend,
+) -> PlanError {
+    log::warn!(
+        "apply to {} failed ({}), rolling back {} interface(s)",
+        iface,
+        source,
+        touched.len()
+    );
+
+    let mut rollback_errors = vec![];
+    for (name, snapshot) in touched.into_iter().rev() {
+        let result = match snapshot {
+            Snapshot::Existing(backup) => restore_update(&backup).apply(&name, backend),
+            Snapshot::Absent => match Device::get(&name, backend) {
+                Ok(device) => device.delete(),
+                Err(Error::InterfaceNotFound(_)) => Ok(()),
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            rollback_errors.push((name, e.into()));
+        }
+    }
+    PlanError {
+        iface,
+        source,
+        rollback_errors,
+    }
+}
+
+/// An update that brings an interface back to `backup`, clearing settings the
+/// backup didn't have.
+fn restore_update(backup: &DeviceBackup) -> DeviceUpdate {
+    let mut update = backup.to_update();
+    if backup.private_key.is_none() {
+        update = update.unset_private_key();
+    }
+    if backup.fwmark.is_none() {
+        update = update.unset_fwmark();
+    }
+    update
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, PeerConfigBuilder};
+
+    #[test]
+    fn test_invalid_update_touches_nothing() {
+        let good = DeviceUpdate::new().set_listen_port(51820);
+        let bad = DeviceUpdate::new().add_peer(
+            PeerConfigBuilder::new(&Key([1u8; 32])).set_endpoint("192.0.2.1:0".parse().unwrap()),
+        );
+        let plan = Plan::new()
+            .add("wgplan0".parse().unwrap(), good)
+            .add("wgplan1".parse().unwrap(), bad);
+
+        // Validation fails before any backend is queried.
+        let error = plan.execute_atomic(Backend::Userspace).unwrap_err();
+        assert_eq!(error.iface, "wgplan1".parse::<InterfaceName>().unwrap());
+        assert!(error.rollback_errors.is_empty());
+        assert!(error.to_string().starts_with("wgplan1: peer #0"));
+    }
+
+    #[test]
+    fn test_restore_update_clears_new_settings() {
+        let backup = DeviceBackup {
+            name: "wg0".parse().unwrap(),
+            private_key: None,
+            listen_port: Some(51820),
+            fwmark: None,
+            peers: vec![],
+        };
+        let update = restore_update(&backup);
+        assert_eq!(update.private_key, Some(Key::zero()));
+        assert_eq!(update.fwmark, Some(0));
+        assert!(update.replace_peers);
+    }
+}
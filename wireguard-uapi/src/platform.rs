@@ -0,0 +1,201 @@
+//! What this build supports on the current host.
+//!
+//! Applications running on several platforms can branch on [`capabilities`]
+//! instead of repeating the crate's `target_os` and feature checks. Each
+//! capability says whether it is compiled in at all and, where that can be
+//! checked cheaply, whether the host provides what it needs.
+use crate::Backend;
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// Whether a capability can be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    /// Built in, and the host provides what it needs.
+    Available,
+    /// Built in, but the host lacks what it needs, e.g. a kernel module or a binary.
+    Unavailable,
+    /// Not part of this build or platform.
+    Unsupported,
+}
+
+impl Support {
+    fn probe(built_in: bool, host: impl FnOnce() -> bool) -> Self {
+        match (built_in, built_in && host()) {
+            (false, _) => Self::Unsupported,
+            (true, false) => Self::Unavailable,
+            (true, true) => Self::Available,
+        }
+    }
+
+    pub fn is_available(self) -> bool {
+        self == Self::Available
+    }
+}
+
+/// The capabilities of this build on the current host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// [`Backend::Kernel`]: Linux with the `wireguard` module loaded, built in or
+    /// installed.
+    pub kernel_backend: Support,
+    /// [`Backend::Userspace`]: Unix with a userspace implementation such as
+    /// `wireguard-go` installed.
+    pub userspace_backend: Support,
+    /// [`Backend::Windows`]: Windows with `wireguard.dll` from WireGuardNT.
+    pub windows_backend: Support,
+    /// [`Backend::Cli`]: the `wg` tool installed.
+    pub cli_backend: Support,
+    /// Running interfaces in other network namespaces, which this crate doesn't
+    /// manage yet.
+    pub network_namespaces: Support,
+    /// Addresses, MTU and routes of interfaces, through the `tools` module.
+    pub link_management: Support,
+    /// Source-based policy routing, through `tools::policy`.
+    pub policy_routing: Support,
+    /// Killswitch rules, with the `nft` feature and the `nft` binary.
+    pub firewall: Support,
+    /// Reading the tunnel services of WireGuard for Windows.
+    pub windows_service: Support,
+    /// The async API, with the `tokio` feature.
+    pub async_api: Support,
+}
+
+impl Capabilities {
+    /// The backends that can be used, preferred one first.
+    pub fn backends(&self) -> Vec<Backend> {
+        let mut backends = vec![];
+        #[cfg(target_os = "linux")]
+        if self.kernel_backend.is_available() {
+            backends.push(Backend::Kernel);
+        }
+        #[cfg(windows)]
+        if self.windows_backend.is_available() {
+            backends.push(Backend::Windows);
+        }
+        if self.userspace_backend.is_available() {
+            backends.push(Backend::Userspace);
+        }
+        if self.cli_backend.is_available() {
+            backends.push(Backend::Cli);
+        }
+        backends
+    }
+}
+
+/// Whether `binary` is a path to an existing file, or found in `PATH`.
+fn in_path(binary: &str, path: Option<&std::ffi::OsStr>) -> bool {
+    if binary.contains('/') {
+        return Path::new(binary).is_file();
+    }
+    path.is_some_and(|path| env::split_paths(path).any(|dir: PathBuf| dir.join(binary).is_file()))
+}
+
+/// Probes the capabilities of this build on the current host.
+pub fn capabilities() -> Capabilities {
+    let path = env::var_os("PATH");
+    let installed = |binary: &str| in_path(binary, path.as_deref());
+    Capabilities {
+        kernel_backend: Support::probe(cfg!(target_os = "linux"), || {
+            #[cfg(target_os = "linux")]
+            {
+                crate::backends::kernel::is_available()
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                false
+            }
+        }),
+        userspace_backend: Support::probe(cfg!(unix), || {
+            #[cfg(unix)]
+            {
+                installed(&crate::backends::userspace::get_userspace_implementation())
+            }
+            #[cfg(not(unix))]
+            {
+                false
+            }
+        }),
+        windows_backend: Support::probe(cfg!(windows), || {
+            #[cfg(windows)]
+            {
+                crate::backends::wireguard_nt::is_available()
+            }
+            #[cfg(not(windows))]
+            {
+                false
+            }
+        }),
+        cli_backend: Support::probe(true, || installed(&crate::backends::cli::wg_binary())),
+        network_namespaces: Support::Unsupported,
+        link_management: Support::probe(
+            cfg!(any(target_os = "linux", target_os = "macos")),
+            || true,
+        ),
+        policy_routing: Support::probe(cfg!(target_os = "linux"), || true),
+        firewall: Support::probe(cfg!(all(feature = "nft", target_os = "linux")), || {
+            installed("nft")
+        }),
+        windows_service: Support::probe(cfg!(windows), || {
+            #[cfg(windows)]
+            {
+                crate::windows::config_dir().is_dir()
+            }
+            #[cfg(not(windows))]
+            {
+                false
+            }
+        }),
+        async_api: Support::probe(cfg!(feature = "tokio"), || true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe() {
+        assert_eq!(Support::probe(false, || true), Support::Unsupported);
+        assert_eq!(Support::probe(true, || false), Support::Unavailable);
+        assert_eq!(Support::probe(true, || true), Support::Available);
+        // The host isn't probed for what isn't built in.
+        assert_eq!(
+            Support::probe(false, || unreachable!()),
+            Support::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_in_path() {
+        let dir = env::temp_dir().join(format!("wg-platform-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("wireguard-go"), "").unwrap();
+        let path = env::join_paths([Path::new("/nonexistent"), &dir]).unwrap();
+        assert!(in_path("wireguard-go", Some(&path)));
+        assert!(!in_path("boringtun", Some(&path)));
+        assert!(!in_path("wireguard-go", None));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = capabilities();
+        assert_eq!(capabilities.network_namespaces, Support::Unsupported);
+        assert_eq!(
+            capabilities.async_api.is_available(),
+            cfg!(feature = "tokio")
+        );
+        assert_eq!(
+            capabilities.kernel_backend == Support::Unsupported,
+            cfg!(not(target_os = "linux"))
+        );
+        assert_eq!(
+            Backend::auto(),
+            capabilities.backends().first().copied().unwrap_or_default()
+        );
+    }
+}
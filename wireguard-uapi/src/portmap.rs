@@ -0,0 +1,950 @@
+//! Port mappings for the listen port, behind the `portmap` feature.
+//!
+//! Peers behind a home router can only be reached once one of them has sent a
+//! packet out. Asking the gateway for a mapping of the listen port makes the
+//! interface reachable from the outside. Gateways speak one of three protocols:
+//! PCP (RFC 6887) and its predecessor NAT-PMP (RFC 6886), which share a port, or
+//! UPnP IGD, whose [`Igd`] is found by SSDP and driven by SOAP requests.
+//!
+//! A [`PortMapper`] tries them in that order and sticks to the first one the
+//! gateway answers. Mappings expire, so it is polled with
+//! [`ensure`](PortMapper::ensure) and renews the mapping at half its lifetime.
+use crate::Device;
+
+use rand_core::{OsRng, RngCore};
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket},
+    time::{Duration, Instant},
+};
+
+/// The port gateways answer NAT-PMP and PCP requests on.
+pub const NATPMP_PORT: u16 = 5351;
+
+/// The lifetime RFC 6886 recommends requesting.
+pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(7200);
+
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const RESPONSE: u8 = 128;
+const ATTEMPTS: u32 = 4;
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+
+const PCP_VERSION: u8 = 2;
+/// The PCP opcode mapping a port, whose responses have the same opcode byte as
+/// NAT-PMP's UDP mappings.
+const PCP_OP_MAP: u8 = 1;
+/// The length of a MAP request or response without options.
+const PCP_MAP_LEN: usize = 60;
+/// The largest PCP message.
+const PCP_MAX_LEN: usize = 1100;
+const IPPROTO_UDP: u8 = 17;
+
+const SSDP_ADDRESS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SSDP_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+/// The services of an IGD that map ports, by preference.
+const UPNP_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// The protocol a mapping was granted over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+/// A port mapping granted by the gateway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub gateway: Ipv4Addr,
+    pub internal_port: u16,
+    /// The port peers on the outside send to, which may differ from the one
+    /// requested.
+    pub external_port: u16,
+    pub lifetime: Duration,
+    pub protocol: Protocol,
+}
+
+fn result_error(code: u16) -> io::Error {
+    let (kind, message) = match code {
+        1 => (io::ErrorKind::Unsupported, "unsupported NAT-PMP version"),
+        2 => (
+            io::ErrorKind::PermissionDenied,
+            "port mapping not authorized",
+        ),
+        3 => (io::ErrorKind::Other, "gateway network failure"),
+        4 => (io::ErrorKind::Other, "gateway out of mapping resources"),
+        5 => (io::ErrorKind::Unsupported, "unsupported NAT-PMP opcode"),
+        _ => (io::ErrorKind::Other, "unknown NAT-PMP result"),
+    };
+    io::Error::new(kind, format!("{} (result {})", message, code))
+}
+
+fn pcp_result_error(code: u8) -> io::Error {
+    let (kind, message) = match code {
+        1 => (io::ErrorKind::Unsupported, "unsupported PCP version"),
+        2 => (
+            io::ErrorKind::PermissionDenied,
+            "port mapping not authorized",
+        ),
+        3 | 6 => (io::ErrorKind::InvalidInput, "malformed PCP request"),
+        4 | 5 | 9 => (io::ErrorKind::Unsupported, "unsupported PCP request"),
+        7 => (io::ErrorKind::Other, "gateway network failure"),
+        8 | 10 => (io::ErrorKind::Other, "gateway out of mapping resources"),
+        11 => (
+            io::ErrorKind::Other,
+            "gateway can't provide an external port",
+        ),
+        12 => (
+            io::ErrorKind::Other,
+            "PCP request went through another NAT on the way",
+        ),
+        _ => (io::ErrorKind::Other, "unknown PCP result"),
+    };
+    io::Error::new(kind, format!("{} (result {})", message, code))
+}
+
+fn upnp_error(code: Option<u16>, status: u16) -> io::Error {
+    let (kind, message) = match code {
+        Some(606) => (
+            io::ErrorKind::PermissionDenied,
+            "port mapping not authorized",
+        ),
+        Some(714) => (io::ErrorKind::NotFound, "no such port mapping"),
+        Some(718) => (
+            io::ErrorKind::AddrInUse,
+            "external port mapped for another host",
+        ),
+        Some(725) => (
+            io::ErrorKind::Unsupported,
+            "gateway only keeps permanent mappings",
+        ),
+        _ => (io::ErrorKind::Other, "UPnP request failed"),
+    };
+    match code {
+        Some(code) => io::Error::new(kind, format!("{} (error {})", message, code)),
+        None => io::Error::new(kind, format!("{} (HTTP status {})", message, status)),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn seconds(lifetime: Duration) -> u32 {
+    u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX)
+}
+
+fn map_request(internal_port: u16, external_port: u16, lifetime: Duration) -> [u8; 12] {
+    let mut request = [0u8; 12];
+    request[1] = OP_MAP_UDP;
+    request[4..6].copy_from_slice(&internal_port.to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    request[8..12].copy_from_slice(&seconds(lifetime).to_be_bytes());
+    request
+}
+
+/// Checks the header of a response to `op`, returning the rest after the epoch.
+fn parse_response(response: &[u8], op: u8) -> io::Result<&[u8]> {
+    match response {
+        [0, kind, high, low, _, _, _, _, rest @ ..] if *kind == RESPONSE | op => {
+            match u16::from_be_bytes([*high, *low]) {
+                0 => Ok(rest),
+                code => Err(result_error(code)),
+            }
+        }
+        _ => Err(invalid("unexpected NAT-PMP response")),
+    }
+}
+
+fn parse_map_response(gateway: Ipv4Addr, response: &[u8]) -> io::Result<Mapping> {
+    match parse_response(response, OP_MAP_UDP)? {
+        [ih, il, eh, el, l0, l1, l2, l3, ..] => Ok(Mapping {
+            gateway,
+            internal_port: u16::from_be_bytes([*ih, *il]),
+            external_port: u16::from_be_bytes([*eh, *el]),
+            lifetime: Duration::from_secs(u64::from(u32::from_be_bytes([*l0, *l1, *l2, *l3]))),
+            protocol: Protocol::NatPmp,
+        }),
+        _ => Err(invalid("truncated NAT-PMP mapping response")),
+    }
+}
+
+/// A PCP request from `client` to map UDP `internal_port`, identified by `nonce`.
+fn pcp_map_request(
+    client: Ipv4Addr,
+    nonce: &[u8; 12],
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> [u8; PCP_MAP_LEN] {
+    let mut request = [0u8; PCP_MAP_LEN];
+    request[0] = PCP_VERSION;
+    request[1] = PCP_OP_MAP;
+    request[4..8].copy_from_slice(&seconds(lifetime).to_be_bytes());
+    request[8..24].copy_from_slice(&client.to_ipv6_mapped().octets());
+    request[24..36].copy_from_slice(nonce);
+    request[36] = IPPROTO_UDP;
+    request[40..42].copy_from_slice(&internal_port.to_be_bytes());
+    request[42..44].copy_from_slice(&external_port.to_be_bytes());
+    // Any external address, which is the unspecified one mapped to IPv6.
+    request[44..60].copy_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    request
+}
+
+fn parse_pcp_map_response(
+    gateway: Ipv4Addr,
+    nonce: &[u8; 12],
+    response: &[u8],
+) -> io::Result<Mapping> {
+    match response {
+        // A NAT-PMP gateway, answering that it doesn't know version 2.
+        [0, ..] => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "gateway only speaks NAT-PMP",
+        )),
+        [PCP_VERSION, kind, _, result, ..] if *kind == RESPONSE | PCP_OP_MAP => {
+            if *result != 0 {
+                return Err(pcp_result_error(*result));
+            }
+            if response.len() < PCP_MAP_LEN {
+                return Err(invalid("truncated PCP mapping response"));
+            }
+            let map = &response[24..PCP_MAP_LEN];
+            if map[..12] != nonce[..] {
+                return Err(invalid("PCP response for another mapping"));
+            }
+            let lifetime = u32::from_be_bytes([response[4], response[5], response[6], response[7]]);
+            Ok(Mapping {
+                gateway,
+                internal_port: u16::from_be_bytes([map[16], map[17]]),
+                external_port: u16::from_be_bytes([map[18], map[19]]),
+                lifetime: Duration::from_secs(u64::from(lifetime)),
+                protocol: Protocol::Pcp,
+            })
+        }
+        _ => Err(invalid("unexpected PCP response")),
+    }
+}
+
+/// A socket sending to port 5351 of `gateway`.
+fn gateway_socket(gateway: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(SocketAddr::from((gateway, NATPMP_PORT)))?;
+    Ok(socket)
+}
+
+/// The address of this host on the way to `address`, found without sending
+/// anything.
+fn local_address(address: SocketAddrV4) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(address)?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(address) => Ok(address),
+        IpAddr::V6(_) => unreachable!("bound to an IPv4 address"),
+    }
+}
+
+/// Sends `request` over `socket` and waits for the response to `op`, retrying
+/// with doubling timeouts.
+fn transact(socket: &UdpSocket, request: &[u8], op: u8) -> io::Result<Vec<u8>> {
+    let mut timeout = INITIAL_TIMEOUT;
+    let mut buffer = [0u8; PCP_MAX_LEN];
+    for _ in 0..ATTEMPTS {
+        socket.send(request)?;
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv(&mut buffer) {
+            Ok(len) if buffer.get(1) == Some(&(RESPONSE | op)) => return Ok(buffer[..len].to_vec()),
+            Ok(_) => continue,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                timeout *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("no response from {}", socket.peer_addr()?),
+    ))
+}
+
+/// The public address of the gateway, asked over NAT-PMP.
+pub fn external_address(gateway: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    let socket = gateway_socket(gateway)?;
+    let response = transact(&socket, &[0, OP_EXTERNAL_ADDRESS], OP_EXTERNAL_ADDRESS)?;
+    match parse_response(&response, OP_EXTERNAL_ADDRESS)? {
+        [a, b, c, d, ..] => Ok(Ipv4Addr::new(*a, *b, *c, *d)),
+        _ => Err(invalid("truncated NAT-PMP address response")),
+    }
+}
+
+fn log_mapping(mapping: &Mapping) {
+    log::debug!(
+        "gateway {} mapped UDP port {} to {} for {:?} over {:?}",
+        mapping.gateway,
+        mapping.external_port,
+        mapping.internal_port,
+        mapping.lifetime,
+        mapping.protocol
+    );
+}
+
+/// Asks `gateway` over NAT-PMP to forward UDP `external_port` (0 for any) to
+/// `internal_port` for `lifetime`.
+pub fn map_udp(
+    gateway: Ipv4Addr,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> io::Result<Mapping> {
+    let request = map_request(internal_port, external_port, lifetime);
+    let response = transact(&gateway_socket(gateway)?, &request, OP_MAP_UDP)?;
+    let mapping = parse_map_response(gateway, &response)?;
+    log_mapping(&mapping);
+    Ok(mapping)
+}
+
+/// Deletes the NAT-PMP mapping of `internal_port` on `gateway`.
+pub fn unmap_udp(gateway: Ipv4Addr, internal_port: u16) -> io::Result<()> {
+    map_udp(gateway, internal_port, 0, Duration::ZERO).map(drop)
+}
+
+/// Asks `gateway` over PCP to forward UDP `external_port` (0 for any) to
+/// `internal_port` for `lifetime`. Renewing or deleting the mapping takes the
+/// same `nonce`.
+pub fn pcp_map_udp(
+    gateway: Ipv4Addr,
+    nonce: &[u8; 12],
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> io::Result<Mapping> {
+    let socket = gateway_socket(gateway)?;
+    let client = local_address(SocketAddrV4::new(gateway, NATPMP_PORT))?;
+    let request = pcp_map_request(client, nonce, internal_port, external_port, lifetime);
+    let response = transact(&socket, &request, PCP_OP_MAP)?;
+    let mapping = parse_pcp_map_response(gateway, nonce, &response)?;
+    log_mapping(&mapping);
+    Ok(mapping)
+}
+
+/// Deletes the PCP mapping of `internal_port` identified by `nonce` on `gateway`.
+pub fn pcp_unmap_udp(gateway: Ipv4Addr, nonce: &[u8; 12], internal_port: u16) -> io::Result<()> {
+    pcp_map_udp(gateway, nonce, internal_port, 0, Duration::ZERO).map(drop)
+}
+
+/// The text of the first `<name>` element in `xml`.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].trim())
+}
+
+/// Splits an `http://` URL with an IPv4 host, which is how gateways advertise
+/// themselves, into the address and path.
+fn parse_url(url: &str) -> Option<(SocketAddrV4, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let address = match authority.parse() {
+        Ok(address) => address,
+        Err(_) => SocketAddrV4::new(authority.parse().ok()?, 80),
+    };
+    Some((address, path.to_string()))
+}
+
+/// The `LOCATION` of an SSDP response, the URL of the device description.
+fn ssdp_location(response: &[u8]) -> Option<String> {
+    let response = std::str::from_utf8(response).ok()?;
+    let mut lines = response.lines();
+    if lines.next()?.split_whitespace().nth(1) != Some("200") {
+        return None;
+    }
+    lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("LOCATION")
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// The type and control URL of the preferred port mapping service in a device
+/// description.
+fn wan_service(description: &str) -> Option<(String, String)> {
+    let services: Vec<_> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|service| {
+            Some((
+                element(service, "serviceType")?,
+                element(service, "controlURL")?,
+            ))
+        })
+        .collect();
+    UPNP_SERVICES.iter().find_map(|wanted| {
+        services
+            .iter()
+            .find(|(service_type, _)| service_type == wanted)
+            .map(|(service_type, control)| (service_type.to_string(), control.to_string()))
+    })
+}
+
+/// Decodes a body sent with chunked transfer encoding.
+fn dechunk(mut body: &str) -> Option<String> {
+    let mut decoded = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n")?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.push_str(rest.get(..size)?);
+        body = rest.get(size..)?.strip_prefix("\r\n")?;
+    }
+}
+
+/// The status code and body of an HTTP response.
+fn parse_http_response(response: &[u8]) -> Option<(u16, String)> {
+    let response = String::from_utf8_lossy(response);
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    let mut lines = head.lines();
+    let status = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("Transfer-Encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    let body = match chunked {
+        true => dechunk(body)?,
+        false => body.to_string(),
+    };
+    Some((status, body))
+}
+
+/// Sends `request` to `address`, returning the status code and body of the
+/// response. Requests ask for the connection to be closed after the response.
+fn http(address: SocketAddrV4, request: &str) -> io::Result<(u16, String)> {
+    let mut stream = TcpStream::connect_timeout(&address.into(), HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    stream.write_all(request.as_bytes())?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+    parse_http_response(&response).ok_or_else(|| invalid("malformed HTTP response"))
+}
+
+/// A UPnP Internet Gateway Device, reached over HTTP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Igd {
+    /// Where control requests go.
+    pub control: SocketAddrV4,
+    pub control_path: String,
+    /// The service mapping ports, e.g.
+    /// `urn:schemas-upnp-org:service:WANIPConnection:1`.
+    pub service_type: String,
+}
+
+impl Igd {
+    /// Looks for the IGD of `gateway` with an SSDP search and reads its device
+    /// description. Devices other than the gateway aren't asked to map ports.
+    pub fn discover(gateway: Ipv4Addr) -> io::Result<Self> {
+        let location = ssdp_search(gateway)?;
+        let (address, path) = parse_url(&location)
+            .ok_or_else(|| invalid(&format!("unsupported IGD location {:?}", location)))?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, address
+        );
+        let (status, description) = http(address, &request)?;
+        if status != 200 {
+            return Err(upnp_error(None, status));
+        }
+        let (service_type, control) = wan_service(&description).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "gateway has no port mapping service",
+            )
+        })?;
+        // Relative control URLs are relative to the URLBase, if any.
+        let (control, control_path) = match parse_url(&control) {
+            Some(absolute) => absolute,
+            None => {
+                let base = element(&description, "URLBase")
+                    .and_then(parse_url)
+                    .map_or(address, |(base, _)| base);
+                let path = match control.starts_with('/') {
+                    true => control,
+                    false => format!("/{}", control),
+                };
+                (base, path)
+            }
+        };
+        Ok(Self {
+            control,
+            control_path,
+            service_type,
+        })
+    }
+
+    /// Calls `action` of the service with `arguments`, returning the response.
+    fn call(&self, action: &str, arguments: &[(&str, String)]) -> io::Result<String> {
+        let arguments: String = arguments
+            .iter()
+            .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body>\
+             </s:Envelope>\r\n",
+            action = action,
+            service = self.service_type,
+            arguments = arguments,
+        );
+        let request = format!(
+            "POST {path} HTTP/1.1\r\n\
+             Host: {host}\r\n\
+             Content-Type: text/xml; charset=\"utf-8\"\r\n\
+             SOAPAction: \"{service}#{action}\"\r\n\
+             Content-Length: {length}\r\n\
+             Connection: close\r\n\r\n{body}",
+            path = self.control_path,
+            host = self.control,
+            service = self.service_type,
+            action = action,
+            length = body.len(),
+            body = body,
+        );
+        match http(self.control, &request)? {
+            (200, response) => Ok(response),
+            (status, response) => Err(upnp_error(
+                element(&response, "errorCode").and_then(|code| code.parse().ok()),
+                status,
+            )),
+        }
+    }
+
+    /// The public address of the gateway.
+    pub fn external_address(&self) -> io::Result<Ipv4Addr> {
+        let response = self.call("GetExternalIPAddress", &[])?;
+        element(&response, "NewExternalIPAddress")
+            .and_then(|address| address.parse().ok())
+            .ok_or_else(|| invalid("malformed GetExternalIPAddress response"))
+    }
+
+    /// Asks the gateway to forward UDP `external_port` (the internal port for 0)
+    /// to `internal_port` of this host for `lifetime`.
+    pub fn map_udp(
+        &self,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> io::Result<Mapping> {
+        // IGD can't pick a port itself.
+        let external_port = match external_port {
+            0 => internal_port,
+            port => port,
+        };
+        let client = local_address(self.control)?;
+        let add = |lease: u32| {
+            self.call(
+                "AddPortMapping",
+                &[
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", external_port.to_string()),
+                    ("NewProtocol", "UDP".to_string()),
+                    ("NewInternalPort", internal_port.to_string()),
+                    ("NewInternalClient", client.to_string()),
+                    ("NewEnabled", "1".to_string()),
+                    ("NewPortMappingDescription", "WireGuard".to_string()),
+                    ("NewLeaseDuration", lease.to_string()),
+                ],
+            )
+        };
+        match add(seconds(lifetime)) {
+            // Older gateways only keep mappings until they are deleted. They are
+            // renewed all the same, in case the gateway restarts.
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => add(0)?,
+            result => result?,
+        };
+        let mapping = Mapping {
+            gateway: *self.control.ip(),
+            internal_port,
+            external_port,
+            lifetime,
+            protocol: Protocol::Upnp,
+        };
+        log_mapping(&mapping);
+        Ok(mapping)
+    }
+
+    /// Deletes the mapping of UDP `external_port`.
+    pub fn unmap_udp(&self, external_port: u16) -> io::Result<()> {
+        self.call(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", "UDP".to_string()),
+            ],
+        )
+        .map(drop)
+    }
+}
+
+/// Multicasts an SSDP search for gateways, returning the description URL `gateway`
+/// answers with.
+fn ssdp_search(gateway: Ipv4Addr) -> io::Result<String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let request = "M-SEARCH * HTTP/1.1\r\n\
+                   HOST: 239.255.255.250:1900\r\n\
+                   MAN: \"ssdp:discover\"\r\n\
+                   MX: 2\r\n\
+                   ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+    socket.send_to(request.as_bytes(), SSDP_ADDRESS)?;
+    let deadline = Instant::now() + SSDP_TIMEOUT;
+    let mut buffer = [0u8; 2048];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no UPnP response from {}", gateway),
+            ));
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        match socket.recv_from(&mut buffer) {
+            Ok((len, from)) if from.ip() == IpAddr::V4(gateway) => {
+                if let Some(location) = ssdp_location(&buffer[..len]) {
+                    return Ok(location);
+                }
+            }
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The IPv4 default gateway in a `/proc/net/route` table.
+#[cfg(target_os = "linux")]
+fn parse_default_gateway(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match fields[..] {
+            [_, "00000000", gateway, ..] => {
+                let gateway = u32::from_str_radix(gateway, 16).ok()?;
+                // The table prints the address as a number in host byte order.
+                Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+                    .filter(|gateway| !gateway.is_unspecified())
+            }
+            _ => None,
+        }
+    })
+}
+
+/// The IPv4 default gateway of the host.
+#[cfg(target_os = "linux")]
+pub fn default_gateway() -> io::Result<Ipv4Addr> {
+    let table = std::fs::read_to_string("/proc/net/route")?;
+    parse_default_gateway(&table)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no IPv4 default gateway"))
+}
+
+/// Keeps a mapping of a listen port alive.
+#[derive(Debug)]
+pub struct PortMapper {
+    gateway: Ipv4Addr,
+    internal_port: u16,
+    lifetime: Duration,
+    /// Identifies the mapping to a PCP gateway.
+    nonce: [u8; 12],
+    /// The protocol the gateway answered, once known.
+    protocol: Option<Protocol>,
+    igd: Option<Igd>,
+    current: Option<(Mapping, Instant)>,
+}
+
+impl PortMapper {
+    pub fn new(gateway: Ipv4Addr, internal_port: u16) -> Self {
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        Self {
+            gateway,
+            internal_port,
+            lifetime: DEFAULT_LIFETIME,
+            nonce,
+            protocol: None,
+            igd: None,
+            current: None,
+        }
+    }
+
+    /// A mapper for the listen port of `device`, which must have one.
+    pub fn for_device(gateway: Ipv4Addr, device: &Device) -> io::Result<Self> {
+        let port = device
+            .listen_port
+            .filter(|port| *port != 0)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} has no listen port", device.name),
+                )
+            })?;
+        Ok(Self::new(gateway, port))
+    }
+
+    /// Requests mappings with `lifetime` instead of [`DEFAULT_LIFETIME`].
+    pub fn set_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Whether the mapping has to be requested or renewed at `now`.
+    pub fn due(&self, now: Instant) -> bool {
+        match &self.current {
+            Some((mapping, granted)) => now.duration_since(*granted) >= mapping.lifetime / 2,
+            None => true,
+        }
+    }
+
+    /// Requests the mapping over the protocol the gateway answered before.
+    fn request(&mut self, external_port: u16) -> io::Result<Mapping> {
+        let (gateway, internal_port, lifetime) = (self.gateway, self.internal_port, self.lifetime);
+        match self.protocol {
+            Some(Protocol::Pcp) => {
+                pcp_map_udp(gateway, &self.nonce, internal_port, external_port, lifetime)
+            }
+            Some(Protocol::NatPmp) => map_udp(gateway, internal_port, external_port, lifetime),
+            Some(Protocol::Upnp) => self.igd.as_ref().expect("found with the protocol").map_udp(
+                internal_port,
+                external_port,
+                lifetime,
+            ),
+            None => self.discover(external_port),
+        }
+    }
+
+    /// Requests the first mapping, finding out which protocol the gateway speaks.
+    fn discover(&mut self, external_port: u16) -> io::Result<Mapping> {
+        let (gateway, internal_port, lifetime) = (self.gateway, self.internal_port, self.lifetime);
+        let mapping =
+            match pcp_map_udp(gateway, &self.nonce, internal_port, external_port, lifetime) {
+                // NAT-PMP gateways answer PCP requests with a version error.
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                    map_udp(gateway, internal_port, external_port, lifetime)?
+                }
+                // Nothing listens on port 5351.
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::TimedOut | io::ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    let igd = Igd::discover(gateway)?;
+                    let mapping = igd.map_udp(internal_port, external_port, lifetime)?;
+                    self.igd = Some(igd);
+                    mapping
+                }
+                result => result?,
+            };
+        self.protocol = Some(mapping.protocol);
+        Ok(mapping)
+    }
+
+    /// Requests or renews the mapping if it is [due](PortMapper::due), asking for
+    /// the external port granted before so it stays stable.
+    pub fn ensure(&mut self, now: Instant) -> io::Result<&Mapping> {
+        if self.due(now) {
+            let external_port = self
+                .current
+                .as_ref()
+                .map_or(self.internal_port, |(mapping, _)| mapping.external_port);
+            let mapping = self.request(external_port)?;
+            self.current = Some((mapping, now));
+        }
+        Ok(&self.current.as_ref().expect("mapped above").0)
+    }
+
+    /// The mapping granted last, if any.
+    pub fn mapping(&self) -> Option<&Mapping> {
+        self.current.as_ref().map(|(mapping, _)| mapping)
+    }
+
+    /// Deletes the mapping from the gateway.
+    pub fn remove(self) -> io::Result<()> {
+        let Some((mapping, _)) = &self.current else {
+            return Ok(());
+        };
+        match mapping.protocol {
+            Protocol::Pcp => pcp_unmap_udp(self.gateway, &self.nonce, self.internal_port),
+            Protocol::NatPmp => unmap_udp(self.gateway, self.internal_port),
+            Protocol::Upnp => self
+                .igd
+                .as_ref()
+                .expect("found with the protocol")
+                .unmap_udp(mapping.external_port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_messages() {
+        let request = map_request(51820, 51820, Duration::from_secs(7200));
+        assert_eq!(
+            request,
+            [0, 1, 0, 0, 0xca, 0x6c, 0xca, 0x6c, 0, 0, 0x1c, 0x20]
+        );
+
+        let gateway = Ipv4Addr::new(192, 168, 1, 1);
+        let response = [
+            0, 129, 0, 0, 0, 0, 0, 9, 0xca, 0x6c, 0xca, 0x6d, 0, 0, 0x0e, 0x10,
+        ];
+        let mapping = parse_map_response(gateway, &response).unwrap();
+        assert_eq!(mapping.external_port, 51821);
+        assert_eq!(mapping.lifetime, Duration::from_secs(3600));
+
+        let refused = [0, 129, 0, 2, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0, 0];
+        let error = parse_map_response(gateway, &refused).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_pcp_messages() {
+        let client = Ipv4Addr::new(192, 168, 1, 10);
+        let nonce = [7u8; 12];
+        let request = pcp_map_request(client, &nonce, 51820, 0, Duration::from_secs(7200));
+        assert_eq!(request[..8], [2, 1, 0, 0, 0, 0, 0x1c, 0x20]);
+        assert_eq!(request[8..24], client.to_ipv6_mapped().octets());
+        assert_eq!(request[24..36], nonce);
+        assert_eq!(request[36..44], [17, 0, 0, 0, 0xca, 0x6c, 0, 0]);
+
+        let gateway = Ipv4Addr::new(192, 168, 1, 1);
+        let mut response = request;
+        response[1] = 129;
+        response[4..8].copy_from_slice(&3600u32.to_be_bytes());
+        response[42..44].copy_from_slice(&51821u16.to_be_bytes());
+        let mapping = parse_pcp_map_response(gateway, &nonce, &response).unwrap();
+        assert_eq!(mapping.external_port, 51821);
+        assert_eq!(mapping.lifetime, Duration::from_secs(3600));
+        assert_eq!(mapping.protocol, Protocol::Pcp);
+        let other = parse_pcp_map_response(gateway, &[8u8; 12], &response).unwrap_err();
+        assert_eq!(other.kind(), io::ErrorKind::InvalidData);
+
+        response[3] = 2;
+        let refused = parse_pcp_map_response(gateway, &nonce, &response).unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::PermissionDenied);
+
+        // What a NAT-PMP gateway answers, which makes the mapper fall back to it.
+        let natpmp = [0, 129, 0, 1, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0, 0];
+        let error = parse_pcp_map_response(gateway, &nonce, &natpmp).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_igd_description() {
+        let ssdp = b"HTTP/1.1 200 OK\r\n\
+                     CACHE-CONTROL: max-age=120\r\n\
+                     ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                     Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        let location = ssdp_location(ssdp).unwrap();
+        assert_eq!(
+            parse_url(&location),
+            Some((
+                "192.168.1.1:5000".parse().unwrap(),
+                "/rootDesc.xml".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_url("http://192.168.1.1"),
+            Some(("192.168.1.1:80".parse().unwrap(), "/".to_string()))
+        );
+
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>\
+            <controlURL>/ctl/PPPConn</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+        assert_eq!(
+            wan_service(description),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1".to_string(),
+                "/ctl/IPConn".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_http_response() {
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        5\r\n<a>1<\r\n4\r\n/a>\n\r\n0\r\n\r\n";
+        assert_eq!(
+            parse_http_response(chunked),
+            Some((200, "<a>1</a>\n".to_string()))
+        );
+
+        let fault = b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 99\r\n\r\n\
+                      <s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                      <errorCode>718</errorCode></UPnPError></detail></s:Fault></s:Body></s:Envelope>";
+        let (status, body) = parse_http_response(fault).unwrap();
+        let code = element(&body, "errorCode").and_then(|code| code.parse().ok());
+        assert_eq!(code, Some(718));
+        assert_eq!(upnp_error(code, status).kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_default_gateway() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                     eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+        assert_eq!(
+            parse_default_gateway(table),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+    }
+
+    #[test]
+    fn test_due() {
+        let mut mapper = PortMapper::new(Ipv4Addr::LOCALHOST, 51820);
+        let now = Instant::now();
+        assert!(mapper.due(now));
+        mapper.current = Some((
+            Mapping {
+                gateway: Ipv4Addr::LOCALHOST,
+                internal_port: 51820,
+                external_port: 51820,
+                lifetime: Duration::from_secs(60),
+                protocol: Protocol::NatPmp,
+            },
+            now,
+        ));
+        assert!(!mapper.due(now + Duration::from_secs(29)));
+        assert!(mapper.due(now + Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,509 @@
+//! Roaming profiles: named sets of peer endpoints, DNS servers and routes for one
+//! interface, e.g. `home`, `office` and `travel`.
+//!
+//! Switching profiles only touches what differs from the active one: peers whose
+//! endpoint changes are updated without resetting the others, and only the routes
+//! that come or go are changed. A profile can name the networks it belongs to by
+//! SSID or gateway, so a [`ProfileStore::roam`] call on every network change picks
+//! the right one.
+//!
+//! Routes are only installed on Linux. DNS servers can't be set by this crate, so
+//! a [`Switch`] reports them for the caller to hand to its resolver.
+use crate::{
+    conf::{self, ConfFile, Section, SectionKind},
+    Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder,
+};
+
+use ipnet::IpNet;
+use std::{
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The network the host is attached to, as far as profiles care.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Network {
+    pub ssid: Option<String>,
+    pub gateway: Option<IpAddr>,
+}
+
+impl Network {
+    /// Detects the current network: the default gateway from `ip route`, and the
+    /// SSID from `iwgetid` when it is installed and the host is on Wi-Fi.
+    #[cfg(target_os = "linux")]
+    pub fn detect() -> Self {
+        let ssid = command_output("iwgetid", &["-r"]).filter(|ssid| !ssid.is_empty());
+        let gateway = command_output("ip", &["route", "show", "default"])
+            .and_then(|routes| parse_default_gateway(&routes));
+        Self { ssid, gateway }
+    }
+}
+
+/// The trimmed standard output of `program`, if it ran successfully.
+#[cfg(target_os = "linux")]
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    std::process::Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The gateway of the first `default via <gateway> ...` line of `ip route` output.
+fn parse_default_gateway(routes: &str) -> Option<IpAddr> {
+    routes.lines().find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match fields[..] {
+            ["default", "via", gateway, ..] => gateway.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// A named set of settings for one interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    /// The endpoints of existing peers, by public key.
+    pub endpoints: Vec<(Key, SocketAddr)>,
+    pub dns: Vec<IpAddr>,
+    /// Routes through the interface, in addition to the allowed IPs.
+    pub routes: Vec<IpNet>,
+    /// The SSIDs of the networks the profile is picked on.
+    pub ssids: Vec<String>,
+    /// The gateways of the networks the profile is picked on.
+    pub gateways: Vec<IpAddr>,
+}
+
+impl Profile {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            endpoints: vec![],
+            dns: vec![],
+            routes: vec![],
+            ssids: vec![],
+            gateways: vec![],
+        }
+    }
+
+    /// Sends the traffic of the peer with `public_key` to `endpoint`.
+    pub fn set_endpoint(mut self, public_key: Key, endpoint: SocketAddr) -> Self {
+        self.endpoints.retain(|(key, _)| *key != public_key);
+        self.endpoints.push((public_key, endpoint));
+        self
+    }
+
+    pub fn add_dns(mut self, server: IpAddr) -> Self {
+        self.dns.push(server);
+        self
+    }
+
+    pub fn add_route(mut self, route: IpNet) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Picks the profile on Wi-Fi networks named `ssid`.
+    pub fn on_ssid(mut self, ssid: &str) -> Self {
+        self.ssids.push(ssid.to_string());
+        self
+    }
+
+    /// Picks the profile on networks whose default gateway is `gateway`.
+    pub fn on_gateway(mut self, gateway: IpAddr) -> Self {
+        self.gateways.push(gateway);
+        self
+    }
+
+    /// Whether the profile is picked on `network`.
+    pub fn matches(&self, network: &Network) -> bool {
+        network
+            .ssid
+            .as_ref()
+            .is_some_and(|ssid| self.ssids.contains(ssid))
+            || network
+                .gateway
+                .is_some_and(|gateway| self.gateways.contains(&gateway))
+    }
+
+    fn to_section(&self) -> Section {
+        let mut section = Section::new(SectionKind::Other("Profile".to_string()));
+        section.set("Name", self.name.as_str());
+        let join = |items: Vec<String>| items.join(", ");
+        // SSIDs may contain commas, so like endpoints they get a line each.
+        let mut push = |key: &str, value: String| {
+            section.lines.push(conf::Line::Entry {
+                key: key.to_string(),
+                value,
+                comment: None,
+            })
+        };
+        for (key, endpoint) in &self.endpoints {
+            push("Endpoint", format!("{} {}", key.to_base64(), endpoint));
+        }
+        for ssid in &self.ssids {
+            push("SSID", ssid.clone());
+        }
+        if !self.dns.is_empty() {
+            section.set(
+                "DNS",
+                join(self.dns.iter().map(ToString::to_string).collect()),
+            );
+        }
+        if !self.routes.is_empty() {
+            section.set(
+                "Route",
+                join(self.routes.iter().map(ToString::to_string).collect()),
+            );
+        }
+        if !self.gateways.is_empty() {
+            section.set(
+                "Gateway",
+                join(self.gateways.iter().map(ToString::to_string).collect()),
+            );
+        }
+        section
+    }
+
+    fn from_section(section: &Section) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let name = section
+            .get("Name")
+            .ok_or_else(|| invalid("[Profile] without Name".to_string()))?;
+        let mut profile = Profile::new(name);
+        for value in section.get_all("Endpoint") {
+            let (key, endpoint) = value
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid(format!("invalid profile endpoint {:?}", value)))?;
+            let key = Key::from_base64(key).map_err(|e| invalid(e.to_string()))?;
+            let endpoint = endpoint
+                .trim()
+                .parse()
+                .map_err(|_| invalid(format!("invalid profile endpoint {:?}", value)))?;
+            profile.endpoints.push((key, endpoint));
+        }
+        fn list<T: FromStr>(section: &Section, key: &str) -> io::Result<Vec<T>> {
+            section
+                .get_all(key)
+                .flat_map(conf::split_list)
+                .map(|item| {
+                    item.parse().map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid profile {} {:?}", key, item),
+                        )
+                    })
+                })
+                .collect()
+        }
+        profile.dns = list(section, "DNS")?;
+        profile.routes = list(section, "Route")?;
+        profile.ssids = section.get_all("SSID").map(str::to_string).collect();
+        profile.gateways = list(section, "Gateway")?;
+        Ok(profile)
+    }
+}
+
+/// What switching to a profile changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Switch {
+    /// The profile that was active before, if any.
+    pub from: Option<String>,
+    pub to: String,
+    /// The peers whose endpoint was changed.
+    pub endpoints: Vec<Key>,
+    pub added_routes: Vec<IpNet>,
+    pub removed_routes: Vec<IpNet>,
+    /// The DNS servers to use from now on, if they changed.
+    pub dns: Option<Vec<IpAddr>>,
+}
+
+impl Switch {
+    /// Whether the switch changed nothing.
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.is_empty()
+            && self.added_routes.is_empty()
+            && self.removed_routes.is_empty()
+            && self.dns.is_none()
+    }
+}
+
+/// The update and changes taking `device` from profile `from` to `to`.
+fn plan(device: &Device, from: Option<&Profile>, to: &Profile) -> (DeviceUpdate, Switch) {
+    let mut update = DeviceUpdate::new();
+    let mut endpoints = vec![];
+    for (key, endpoint) in &to.endpoints {
+        match device.find_peer(key.clone()) {
+            Some(peer) if peer.config.endpoint == Some(*endpoint) => {}
+            Some(_) => {
+                update = update.add_peer(PeerConfigBuilder::new(key).set_endpoint(*endpoint));
+                endpoints.push(key.clone());
+            }
+            None => log::warn!(
+                "profile {} names peer {} which {} doesn't have",
+                to.name,
+                key.to_base64(),
+                device.name
+            ),
+        }
+    }
+    let old_routes = from.map_or(&[][..], |from| &from.routes);
+    let old_dns = from.map_or(&[][..], |from| &from.dns);
+    let switch = Switch {
+        from: from.map(|from| from.name.clone()),
+        to: to.name.clone(),
+        endpoints,
+        added_routes: to
+            .routes
+            .iter()
+            .filter(|route| !old_routes.contains(route))
+            .copied()
+            .collect(),
+        removed_routes: old_routes
+            .iter()
+            .filter(|route| !to.routes.contains(route))
+            .copied()
+            .collect(),
+        dns: (old_dns != &to.dns[..]).then(|| to.dns.clone()),
+    };
+    (update, switch)
+}
+
+#[cfg(target_os = "linux")]
+fn apply_routes(iface: &InterfaceName, switch: &Switch) -> io::Result<()> {
+    for route in &switch.removed_routes {
+        crate::tools::linux::del_route(iface, *route)?;
+    }
+    for route in &switch.added_routes {
+        crate::tools::linux::add_route(iface, *route)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn apply_routes(_iface: &InterfaceName, switch: &Switch) -> io::Result<()> {
+    if !switch.added_routes.is_empty() || !switch.removed_routes.is_empty() {
+        log::warn!("profile routes are only installed on Linux");
+    }
+    Ok(())
+}
+
+/// Where profiles and the active profile of each interface are kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileStore {
+    dir: PathBuf,
+}
+
+impl Default for ProfileStore {
+    /// The store in `/etc/wireguard`, next to the wg-quick configs.
+    fn default() -> Self {
+        Self::new("/etc/wireguard")
+    }
+}
+
+impl ProfileStore {
+    /// A store keeping its files in `dir`, which is created on the first write.
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, iface: &InterfaceName, extension: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", iface.as_str_lossy(), extension))
+    }
+
+    /// The profiles of `iface`, which are empty if it has none.
+    pub fn get(&self, iface: &InterfaceName) -> io::Result<Vec<Profile>> {
+        let text = match fs::read_to_string(self.path(iface, "profiles")) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let file: ConfFile = text.parse()?;
+        file.sections
+            .iter()
+            .filter(
+                |section| matches!(&section.kind, SectionKind::Other(name) if name == "Profile"),
+            )
+            .map(Profile::from_section)
+            .collect()
+    }
+
+    /// Replaces the profiles of `iface`.
+    pub fn set(&self, iface: &InterfaceName, profiles: &[Profile]) -> io::Result<()> {
+        let file = ConfFile {
+            preamble: vec![],
+            sections: profiles.iter().map(Profile::to_section).collect(),
+        };
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(iface, "profiles"), file.to_string())
+    }
+
+    /// The name of the profile last switched to on `iface`.
+    pub fn active(&self, iface: &InterfaceName) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.path(iface, "profile")) {
+            Ok(name) => Ok(Some(name.trim().to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Switches `iface` on `backend` to the profile `name`, applying only what
+    /// differs from the active profile.
+    pub fn switch(
+        &self,
+        iface: &InterfaceName,
+        name: &str,
+        backend: Backend,
+    ) -> io::Result<Switch> {
+        let profiles = self.get(iface)?;
+        let find = |name: &str| profiles.iter().find(|profile| profile.name == name);
+        let to = find(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no profile {:?}", iface, name),
+            )
+        })?;
+        let active = self.active(iface)?;
+        let from = active.as_deref().and_then(find);
+
+        let device = Device::get(iface, backend)?;
+        let (update, switch) = plan(&device, from, to);
+        if !switch.endpoints.is_empty() {
+            update.apply(iface, backend)?;
+        }
+        apply_routes(iface, &switch)?;
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(iface, "profile"), &to.name)?;
+        log::info!(
+            "switched {} from profile {:?} to {:?}",
+            iface,
+            switch.from,
+            switch.to
+        );
+        Ok(switch)
+    }
+
+    /// Switches `iface` to the first profile matching `network`, unless it is
+    /// already active. Returns `None` if nothing was switched.
+    pub fn roam(
+        &self,
+        iface: &InterfaceName,
+        network: &Network,
+        backend: Backend,
+    ) -> io::Result<Option<Switch>> {
+        let profiles = self.get(iface)?;
+        let profile = match profiles.iter().find(|profile| profile.matches(network)) {
+            Some(profile) => profile,
+            None => return Ok(None),
+        };
+        if self.active(iface)?.as_deref() == Some(profile.name.as_str()) {
+            return Ok(None);
+        }
+        self.switch(iface, &profile.name, backend).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerInfo;
+
+    fn device_with(peers: &[(Key, SocketAddr)]) -> Device {
+        Device::fixture(
+            "wg0",
+            peers
+                .iter()
+                .map(|(key, endpoint)| {
+                    PeerInfo::fixture(key.clone()).with_endpoint(&endpoint.to_string())
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_default_gateway() {
+        let routes = "default via 192.168.1.1 dev wlan0 proto dhcp metric 600\n\
+                      10.0.0.0/24 dev wg0 scope link\n";
+        assert_eq!(
+            parse_default_gateway(routes),
+            Some(IpAddr::from([192, 168, 1, 1]))
+        );
+        assert_eq!(parse_default_gateway("default dev wg0 scope link"), None);
+    }
+
+    #[test]
+    fn test_plan() {
+        let a = Key::generate_private().get_public();
+        let b = Key::generate_private().get_public();
+        let home: SocketAddr = "192.168.1.10:51820".parse().unwrap();
+        let public: SocketAddr = "198.51.100.1:51820".parse().unwrap();
+        let device = device_with(&[(a.clone(), home), (b.clone(), public)]);
+
+        let office = Profile::new("office")
+            .set_endpoint(a.clone(), public)
+            .set_endpoint(b.clone(), public)
+            .add_route("10.0.0.0/8".parse().unwrap());
+        let travel = Profile::new("travel")
+            .set_endpoint(a.clone(), public)
+            .add_dns("10.0.0.1".parse().unwrap());
+
+        let (update, switch) = plan(&device, None, &office);
+        assert_eq!(switch.endpoints, vec![a.clone()]);
+        assert!(!update.to_wg_quick_config().contains(&b.to_base64()));
+        assert_eq!(switch.added_routes.len(), 1);
+        assert_eq!(switch.dns, None);
+
+        let (_, switch) = plan(&device, Some(&office), &travel);
+        assert_eq!(switch.from.as_deref(), Some("office"));
+        assert_eq!(switch.removed_routes.len(), 1);
+        assert_eq!(switch.dns, Some(vec!["10.0.0.1".parse().unwrap()]));
+
+        let (_, switch) = plan(&device, Some(&office), &office);
+        assert_eq!(switch.endpoints, vec![a.clone()]);
+        let (_, switch) = plan(
+            &device_with(&[(a, public), (b, public)]),
+            Some(&office),
+            &office,
+        );
+        assert!(switch.is_empty());
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = std::env::temp_dir().join(format!("wg-profiles-{}", std::process::id()));
+        let store = ProfileStore::new(&dir);
+        let iface: InterfaceName = "wg0".parse().unwrap();
+        let key = Key::generate_private().get_public();
+        let profiles = vec![
+            Profile::new("home")
+                .set_endpoint(key.clone(), "192.168.1.10:51820".parse().unwrap())
+                .on_ssid("Home Wi-Fi")
+                .on_gateway("192.168.1.1".parse().unwrap()),
+            Profile::new("travel")
+                .set_endpoint(key, "[2001:db8::1]:51820".parse().unwrap())
+                .add_dns("10.0.0.1".parse().unwrap())
+                .add_route("10.0.0.0/8".parse().unwrap())
+                .add_route("fd00::/64".parse().unwrap()),
+        ];
+        assert_eq!(store.get(&iface).unwrap(), []);
+        store.set(&iface, &profiles).unwrap();
+        assert_eq!(store.get(&iface).unwrap(), profiles);
+        assert_eq!(store.active(&iface).unwrap(), None);
+
+        let network = Network {
+            ssid: None,
+            gateway: Some("192.168.1.1".parse().unwrap()),
+        };
+        assert!(profiles[0].matches(&network));
+        assert!(!profiles[1].matches(&network));
+        assert!(!profiles[0].matches(&Network::default()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,762 @@
+//! Zero-touch provisioning of interfaces from a host's identity.
+//!
+//! A [`Template`] describes the interface every edge box should get; rendering it
+//! for a [`HostIdentity`] fills in tunnel addresses derived deterministically from
+//! the hostname and machine-id, so re-provisioning a box always yields the same
+//! addresses without any central allocation.
+//!
+//! Meshes without a central allocator can instead derive IPv6 addresses from
+//! public keys: [`ula_prefix`] picks a unique local prefix for the mesh, and
+//! [`key_address`] a node's address within it.
+//!
+//! On the hub side, [`add_client`] covers the "add a new device to my VPN"
+//! workflow in one call: it allocates addresses, adds the peer to the interface
+//! and returns the client's config file. [`revoke_client`] is its inverse.
+use crate::{
+    conf::{peer_section, ClientConfig, ConfFile, Section, SectionKind},
+    tools::quick::WgQuick,
+    AllowedIpConflicts, Backend, Device, DeviceUpdate, InterfaceName, Key, KeyPair, PeerConfig,
+    PeerConfigBuilder,
+};
+
+use ipnet::{IpNet, Ipv6Net};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::SystemTime,
+};
+
+const MACHINE_ID_PATHS: &[&str] = &["/etc/machine-id", "/var/lib/dbus/machine-id"];
+
+/// The name of the running host, from `gethostname(2)`.
+#[cfg(unix)]
+fn hostname() -> io::Result<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/// The name of the running host, which Windows keeps in `COMPUTERNAME`.
+#[cfg(not(unix))]
+fn hostname() -> io::Result<String> {
+    std::env::var("COMPUTERNAME").map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))
+}
+
+/// What identifies a host across reinstalls of the provisioning tooling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostIdentity {
+    pub hostname: String,
+    pub machine_id: String,
+}
+
+impl HostIdentity {
+    pub fn new(hostname: &str, machine_id: &str) -> Self {
+        Self {
+            hostname: hostname.trim().to_string(),
+            machine_id: machine_id.trim().to_ascii_lowercase(),
+        }
+    }
+
+    /// The identity of the running host, from `gethostname(2)` and the systemd/D-Bus
+    /// machine-id file.
+    pub fn current() -> io::Result<Self> {
+        let hostname = hostname()?;
+        let machine_id = MACHINE_ID_PATHS
+            .iter()
+            .find_map(|path| fs::read_to_string(path).ok())
+            .filter(|id| !id.trim().is_empty())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no machine-id found"))?;
+        Ok(Self::new(&hostname, &machine_id))
+    }
+
+    /// A hash of the identity, salted with `pool` so that each pool gets an
+    /// independent choice of address.
+    fn digest(&self, pool: &IpNet) -> [u8; 32] {
+        let hash = Sha256::new()
+            .chain(b"wireguard-uapi provisioning")
+            .chain(self.machine_id.as_bytes())
+            .chain([0u8])
+            .chain(self.hostname.as_bytes())
+            .chain([0u8])
+            .chain(pool.to_string().as_bytes())
+            .finalize();
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hash);
+        bytes
+    }
+}
+
+/// Picks the host's address within `pool`, keeping the pool's prefix length.
+///
+/// The network address and the first host address (conventionally the hub) are
+/// never chosen, nor is the broadcast address of IPv4 pools. Different hosts can
+/// collide, with the usual birthday odds, so pools should be much larger than the
+/// fleet.
+pub fn tunnel_address(identity: &HostIdentity, pool: &IpNet) -> io::Result<IpNet> {
+    let pool = pool.trunc();
+    address_from_digest(&pool, &identity.digest(&pool))
+}
+
+/// The address within `pool` that `digest` picks, skipping the reserved ones.
+fn address_from_digest(pool: &IpNet, digest: &[u8; 32]) -> io::Result<IpNet> {
+    let host_bits = u32::from(pool.max_prefix_len() - pool.prefix_len());
+    let reserved_top = match pool {
+        IpNet::V4(_) => 1,
+        IpNet::V6(_) => 0,
+    };
+    // Offsets 0 and 1 are reserved at the bottom, `reserved_top` at the top.
+    let usable = match 1u128.checked_shl(host_bits) {
+        Some(size) => size.saturating_sub(2 + reserved_top),
+        None => u128::MAX - 2,
+    };
+    if usable == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("pool {} has no room for hosts", pool),
+        ));
+    }
+
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    let offset = 2 + u128::from_be_bytes(bytes) % usable;
+
+    let address = match pool.network() {
+        IpAddr::V4(network) => IpAddr::V4(Ipv4Addr::from(u32::from(network) + offset as u32)),
+        IpAddr::V6(network) => IpAddr::V6(Ipv6Addr::from(u128::from(network) + offset)),
+    };
+    Ok(IpNet::new(address, pool.prefix_len()).expect("prefix length taken from the pool"))
+}
+
+/// A unique local IPv6 /48 prefix (RFC 4193) whose global ID is derived from
+/// `seed`, e.g. the mesh's name or its hub's public key, so every node computes
+/// the same prefix.
+pub fn ula_prefix(seed: &[u8]) -> Ipv6Net {
+    let hash = Sha256::new()
+        .chain(b"wireguard-uapi ula")
+        .chain(seed)
+        .finalize();
+    let mut octets = [0u8; 16];
+    octets[0] = 0xfd;
+    octets[1..6].copy_from_slice(&hash[..5]);
+    Ipv6Net::new(Ipv6Addr::from(octets), 48).expect("valid prefix length")
+}
+
+/// The address of the node with `public_key` within `pool`, keeping the pool's
+/// prefix length.
+///
+/// Every node derives the same address for a key, so meshes can assign addresses
+/// without coordination. The same addresses as for [`tunnel_address`] are
+/// reserved; with a /64 or larger IPv6 pool, collisions are negligible.
+pub fn key_address(public_key: &Key, pool: &IpNet) -> io::Result<IpNet> {
+    let pool = pool.trunc();
+    let hash = Sha256::new()
+        .chain(b"wireguard-uapi key address")
+        .chain(public_key.as_bytes())
+        .chain(pool.to_string().as_bytes())
+        .finalize();
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&hash);
+    address_from_digest(&pool, &digest)
+}
+
+fn to_u128(address: IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(address) => u32::from(address).into(),
+        IpAddr::V6(address) => u128::from(address),
+    }
+}
+
+/// The first address of `pool` that none of `taken` covers, keeping the pool's
+/// prefix length.
+///
+/// The same addresses as for [`tunnel_address`] are reserved.
+pub fn allocate_address(pool: &IpNet, taken: &[IpNet]) -> io::Result<IpNet> {
+    let pool = pool.trunc();
+    let address = |value: u128| match pool {
+        IpNet::V4(_) => IpAddr::V4(Ipv4Addr::from(value as u32)),
+        IpNet::V6(_) => IpAddr::V6(Ipv6Addr::from(value)),
+    };
+    let last = match pool {
+        IpNet::V4(_) => to_u128(pool.broadcast()).checked_sub(1),
+        IpNet::V6(_) => Some(to_u128(pool.broadcast())),
+    };
+
+    let mut candidate = to_u128(pool.network()).checked_add(2);
+    while let (Some(value), Some(last)) = (candidate, last) {
+        if value > last {
+            break;
+        }
+        let candidate_address = address(value);
+        // Skip past everything the covering prefixes route.
+        match taken
+            .iter()
+            .filter(|net| net.contains(&candidate_address))
+            .map(|net| to_u128(net.broadcast()))
+            .max()
+        {
+            Some(end) => candidate = end.checked_add(1),
+            None => {
+                return Ok(IpNet::new(candidate_address, pool.prefix_len())
+                    .expect("prefix length taken from the pool"))
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrNotAvailable,
+        format!("pool {} has no free address", pool),
+    ))
+}
+
+/// What [`add_client`] puts into the client's config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// The client's name, kept in a `# Name:` annotation of its config.
+    pub name: Option<String>,
+    /// Pools to allocate one client address from each.
+    pub pools: Vec<IpNet>,
+    /// Pools to derive one client address from each with [`key_address`],
+    /// typically a [`ula_prefix`] subnet.
+    pub derived_pools: Vec<IpNet>,
+    /// Where clients reach the hub, e.g. `vpn.example.com:51820`.
+    pub endpoint: String,
+    /// What the client routes through the tunnel, everything by default.
+    pub allowed_ips: Vec<IpNet>,
+    pub dns: Vec<String>,
+    pub persistent_keepalive: Option<u16>,
+    pub backend: Backend,
+}
+
+impl ClientOptions {
+    pub fn new(endpoint: &str, pools: Vec<IpNet>) -> Self {
+        Self {
+            name: None,
+            pools,
+            derived_pools: vec![],
+            endpoint: endpoint.to_string(),
+            allowed_ips: vec![
+                "0.0.0.0/0".parse().expect("valid network"),
+                "::/0".parse().expect("valid network"),
+            ],
+            dns: vec![],
+            persistent_keepalive: Some(25),
+            backend: Backend::default(),
+        }
+    }
+}
+
+/// A client added by [`add_client`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Client {
+    pub keypair: KeyPair,
+    pub preshared_key: Key,
+    /// The client's addresses, one per pool.
+    pub addresses: Vec<IpNet>,
+    /// The client's wg-quick style config file.
+    pub config: ConfFile,
+}
+
+impl Client {
+    /// The config file as text, e.g. to save as `wg0.conf` on the client.
+    pub fn config_string(&self) -> String {
+        self.config.to_string()
+    }
+
+    /// The config file as a QR code drawn with Unicode half blocks, for scanning
+    /// with the mobile apps.
+    #[cfg(feature = "qr")]
+    pub fn qr_code(&self) -> io::Result<String> {
+        Ok(crate::qr::QrCode::new(&self.config_string())?.to_terminal())
+    }
+}
+
+/// Prepares a client of `server`: its addresses, config file, and the peer to add
+/// to the server.
+fn prepare_client(
+    server: &Device,
+    options: &ClientOptions,
+    keypair: KeyPair,
+    preshared_key: Key,
+) -> io::Result<(Client, PeerConfigBuilder)> {
+    let server_key = server.public_key.as_ref().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no private key", server.name),
+        )
+    })?;
+
+    let taken: Vec<IpNet> = server
+        .peers
+        .iter()
+        .flat_map(|peer| &peer.config.allowed_ips)
+        .filter_map(|ip| IpNet::try_from(ip.clone()).ok())
+        .collect();
+    let addresses = options
+        .pools
+        .iter()
+        .map(|pool| allocate_address(pool, &taken))
+        .chain(options.derived_pools.iter().map(|pool| {
+            let address = key_address(&keypair.public, pool)?;
+            if taken.iter().any(|net| net.contains(&address.addr())) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is already routed to a peer", address.addr()),
+                ));
+            }
+            Ok(address)
+        }))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut peer = PeerConfigBuilder::new(&keypair.public).set_preshared_key(preshared_key.clone());
+    for address in &addresses {
+        peer = peer.add_allowed_ip(address.addr(), address.max_prefix_len());
+    }
+
+    let config = ClientConfig {
+        name: options.name.clone(),
+        private_key: keypair.private.clone(),
+        addresses: addresses.clone(),
+        dns: options.dns.clone(),
+        mtu: None,
+        server_public_key: server_key.clone(),
+        preshared_key: Some(preshared_key.clone()),
+        endpoint: options.endpoint.clone(),
+        allowed_ips: options.allowed_ips.clone(),
+        persistent_keepalive: options.persistent_keepalive,
+    };
+    let client = Client {
+        keypair,
+        preshared_key,
+        addresses,
+        config: config.to_conf(),
+    };
+    Ok((client, peer))
+}
+
+/// Adds a new client to the hub interface `iface`: generates its keys and a
+/// preshared key, allocates its addresses from the pools (skipping every address
+/// a peer of `iface` already routes), adds it as a peer and returns its config.
+///
+/// The peer is added with [`AllowedIpConflicts::Error`], so a racing allocation
+/// can't take over another client's addresses.
+pub fn add_client(iface: &InterfaceName, options: &ClientOptions) -> io::Result<Client> {
+    let server = Device::get(iface, options.backend)?;
+    let (client, peer) = prepare_client(
+        &server,
+        options,
+        KeyPair::generate(),
+        Key::generate_preshared(),
+    )?;
+    DeviceUpdate::new()
+        .add_peer(peer)
+        .on_allowed_ip_conflict(AllowedIpConflicts::Error)
+        .apply(iface, options.backend)?;
+    Ok(client)
+}
+
+/// What [`revoke_client`] removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Revocation {
+    pub iface: InterfaceName,
+    pub public_key: Key,
+    /// The prefixes that were routed to the client. Addresses are allocated from
+    /// the peers present on the interface, so these are free again.
+    pub released: Vec<IpNet>,
+    /// The client's last endpoint, handshake and transfer totals, for the audit
+    /// trail.
+    pub endpoint: Option<SocketAddr>,
+    pub last_handshake_time: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Whether a snapshot held by the global registry was refreshed to drop the
+    /// client. Always `false` without the `tokio` feature.
+    pub registry_refreshed: bool,
+    /// Why refreshing the registry snapshot failed. The peer has been removed from
+    /// the interface regardless, so this doesn't fail the revocation.
+    pub registry_error: Option<String>,
+}
+
+impl fmt::Display for Revocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "revoked {} from {}",
+            self.public_key.to_base64(),
+            self.iface
+        )?;
+        if !self.released.is_empty() {
+            let released: Vec<_> = self.released.iter().map(IpNet::to_string).collect();
+            write!(f, ", released {}", released.join(", "))?;
+        }
+        if let Some(e) = &self.registry_error {
+            write!(f, " (registry not refreshed: {})", e)?;
+        }
+        Ok(())
+    }
+}
+
+/// The report of revoking `public_key` from `device`.
+fn revocation(device: &Device, public_key: &Key) -> io::Result<Revocation> {
+    let peer = device
+        .peers
+        .iter()
+        .find(|peer| peer.config.public_key == *public_key)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no peer {}", device.name, public_key.to_base64()),
+            )
+        })?;
+    Ok(Revocation {
+        iface: device.name,
+        public_key: public_key.clone(),
+        released: peer
+            .config
+            .allowed_ips
+            .iter()
+            .filter_map(|ip| IpNet::try_from(ip.clone()).ok())
+            .collect(),
+        endpoint: peer.config.endpoint,
+        last_handshake_time: peer.stats.last_handshake_time,
+        rx_bytes: peer.stats.rx_bytes,
+        tx_bytes: peer.stats.tx_bytes,
+        registry_refreshed: false,
+        registry_error: None,
+    })
+}
+
+/// Removes the client `public_key` from the hub interface `iface`, releasing its
+/// addresses, and reports what was removed.
+///
+/// If the interface is managed by the global [`Registry`](crate::registry::Registry),
+/// its snapshot is refreshed so subscribers see the peer go away. A failed refresh
+/// is reported in [`Revocation::registry_error`] rather than as an error, since the
+/// peer is already gone by then.
+pub fn revoke_client(
+    iface: &InterfaceName,
+    public_key: &Key,
+    backend: Backend,
+) -> io::Result<Revocation> {
+    let report = revocation(&Device::get(iface, backend)?, public_key)?;
+    DeviceUpdate::new()
+        .remove_peer_by_key(public_key)
+        .apply(iface, backend)?;
+
+    #[cfg(feature = "tokio")]
+    let report = {
+        let registry = crate::registry::Registry::global();
+        match registry.is_managed(iface).then(|| registry.refresh(iface)) {
+            Some(Ok(_)) => Revocation {
+                registry_refreshed: true,
+                ..report
+            },
+            Some(Err(e)) => Revocation {
+                registry_error: Some(e.to_string()),
+                ..report
+            },
+            None => report,
+        }
+    };
+    Ok(report)
+}
+
+/// The interface configuration shared by a fleet of hosts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    pub name: InterfaceName,
+    /// Pools to derive one tunnel address from each, e.g. an IPv4 and an IPv6 one.
+    pub pools: Vec<IpNet>,
+    pub listen_port: Option<u16>,
+    /// The peers every host gets, typically the hubs.
+    pub peers: Vec<PeerConfig>,
+}
+
+impl Template {
+    pub fn new(name: InterfaceName) -> Self {
+        Self {
+            name,
+            pools: vec![],
+            listen_port: None,
+            peers: vec![],
+        }
+    }
+
+    /// The tunnel addresses of `identity`, one per pool.
+    pub fn addresses(&self, identity: &HostIdentity) -> io::Result<Vec<IpNet>> {
+        self.pools
+            .iter()
+            .map(|pool| tunnel_address(identity, pool))
+            .collect()
+    }
+
+    /// Renders a wg-quick style config file for `identity`, annotated with its
+    /// hostname.
+    pub fn render(&self, identity: &HostIdentity, keypair: &KeyPair) -> io::Result<ConfFile> {
+        let addresses: Vec<_> = self
+            .addresses(identity)?
+            .iter()
+            .map(IpNet::to_string)
+            .collect();
+
+        let mut interface = Section::new(SectionKind::Interface);
+        interface.set_annotation("Host", &identity.hostname);
+        if !addresses.is_empty() {
+            interface.set("Address", addresses.join(", "));
+        }
+        interface.set("PrivateKey", keypair.private.to_base64());
+        if let Some(port) = self.listen_port {
+            interface.set("ListenPort", port.to_string());
+        }
+
+        let mut file = ConfFile::default();
+        file.sections.push(interface);
+        file.sections.extend(self.peers.iter().map(peer_section));
+        Ok(file)
+    }
+
+    /// Builds the interface for `identity`, ready to [apply](WgQuick::apply).
+    pub fn to_wg_quick(&self, identity: &HostIdentity, keypair: KeyPair) -> io::Result<WgQuick> {
+        let mut quick = WgQuick::new(&self.name.as_str_lossy())?
+            .set_private_key(keypair.private)
+            .set_address_list(&self.addresses(identity)?)
+            .replace_peers();
+        if let Some(port) = self.listen_port {
+            quick = quick.set_listen_port(port);
+        }
+        Ok(quick.add_peers(
+            &self
+                .peers
+                .iter()
+                .cloned()
+                .map(PeerConfigBuilder::from_peer_config)
+                .collect::<Vec<_>>(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+
+    fn identity(hostname: &str) -> HostIdentity {
+        HostIdentity::new(hostname, "4f0d1c2b3a4958677685a4b3c2d1e0f0\n")
+    }
+
+    #[test]
+    fn test_tunnel_address_is_deterministic() {
+        let pool: IpNet = "10.8.0.0/16".parse().unwrap();
+        let address = tunnel_address(&identity("edge-01"), &pool).unwrap();
+        assert_eq!(
+            address,
+            tunnel_address(&identity("edge-01"), &pool).unwrap()
+        );
+        assert_ne!(
+            address,
+            tunnel_address(&identity("edge-02"), &pool).unwrap()
+        );
+        assert_eq!(address.prefix_len(), 16);
+        assert!(pool.contains(&address.addr()));
+    }
+
+    #[test]
+    fn test_tunnel_address_skips_reserved() {
+        let pool: IpNet = "192.0.2.0/30".parse().unwrap();
+        for i in 0..16 {
+            let address = tunnel_address(&identity(&format!("edge-{}", i)), &pool).unwrap();
+            assert_eq!(address.addr(), "192.0.2.2".parse::<IpAddr>().unwrap());
+        }
+
+        let full: IpNet = "192.0.2.0/31".parse().unwrap();
+        let error = tunnel_address(&identity("edge-01"), &full).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let v6: IpNet = "fd00::/127".parse().unwrap();
+        assert!(tunnel_address(&identity("edge-01"), &v6).is_err());
+        let v6: IpNet = "fd00::/64".parse().unwrap();
+        assert!(tunnel_address(&identity("edge-01"), &v6).is_ok());
+    }
+
+    #[test]
+    fn test_allocate_address() {
+        let pool: IpNet = "10.8.0.0/24".parse().unwrap();
+        let taken =
+            |nets: &[&str]| -> Vec<IpNet> { nets.iter().map(|n| n.parse().unwrap()).collect() };
+        assert_eq!(
+            allocate_address(&pool, &[]).unwrap(),
+            "10.8.0.2/24".parse().unwrap()
+        );
+        assert_eq!(
+            allocate_address(
+                &pool,
+                &taken(&["10.8.0.2/32", "10.8.0.4/30", "10.8.0.3/32"])
+            )
+            .unwrap(),
+            "10.8.0.8/24".parse().unwrap()
+        );
+        let error = allocate_address(&pool, &taken(&["10.8.0.0/24"])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrNotAvailable);
+        assert!(
+            allocate_address(&"10.8.0.0/30".parse().unwrap(), &taken(&["10.8.0.2/32"])).is_err()
+        );
+        assert_eq!(
+            allocate_address(&"fd00::/64".parse().unwrap(), &taken(&["10.8.0.2/32"])).unwrap(),
+            "fd00::2/64".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_key_address() {
+        let prefix = ula_prefix(b"mesh");
+        assert_eq!(prefix, ula_prefix(b"mesh"));
+        assert_ne!(prefix, ula_prefix(b"other mesh"));
+        assert_eq!(prefix.addr().octets()[0], 0xfd);
+        assert!(prefix.addr().octets()[6..].iter().all(|&octet| octet == 0));
+
+        let pool = IpNet::V6(Ipv6Net::new(prefix.addr(), 64).unwrap());
+        let address = key_address(&Key([1u8; 32]), &pool).unwrap();
+        assert_eq!(address, key_address(&Key([1u8; 32]), &pool).unwrap());
+        assert_ne!(address, key_address(&Key([2u8; 32]), &pool).unwrap());
+        assert_eq!(address.prefix_len(), 64);
+        assert!(pool.contains(&address.addr()));
+    }
+
+    #[test]
+    fn test_prepare_client() {
+        use crate::PeerInfo;
+
+        let existing = PeerConfigBuilder::new(&Key([3u8; 32]))
+            .add_allowed_ip("10.8.0.2".parse().unwrap(), 32)
+            .into_peer_config();
+        let server = Device {
+            public_key: Some(Key([2u8; 32])),
+            listen_port: Some(51820),
+            ..Device::fixture(
+                "wg0",
+                vec![PeerInfo {
+                    config: existing,
+                    stats: Default::default(),
+                }],
+            )
+        };
+        let mut options = ClientOptions::new(
+            "vpn.example.com:51820",
+            vec![
+                "10.8.0.0/24".parse().unwrap(),
+                "fd00:8::/64".parse().unwrap(),
+            ],
+        );
+        options.name = Some("laptop".to_string());
+        options.dns = vec!["10.8.0.1".to_string()];
+
+        let keypair = KeyPair::from_seed(&[1u8; 32]);
+        let (client, peer) =
+            prepare_client(&server, &options, keypair.clone(), Key([4u8; 32])).unwrap();
+        assert_eq!(
+            client.addresses,
+            vec![
+                "10.8.0.3/24".parse::<IpNet>().unwrap(),
+                "fd00:8::2/64".parse().unwrap()
+            ]
+        );
+        let peer = peer.into_peer_config();
+        assert_eq!(peer.public_key, keypair.public);
+        assert_eq!(peer.preshared_key, Some(Key([4u8; 32])));
+        assert_eq!(
+            peer.allowed_ips,
+            vec![
+                "10.8.0.3/32".parse().unwrap(),
+                "fd00:8::2/128".parse().unwrap()
+            ]
+        );
+
+        let interface = client.config.interface().unwrap();
+        assert_eq!(interface.annotation("Name").as_deref(), Some("laptop"));
+        assert_eq!(interface.get("Address"), Some("10.8.0.3/24, fd00:8::2/64"));
+        let hub = client.config.peers().next().unwrap();
+        assert_eq!(
+            hub.get("PublicKey"),
+            Some(Key([2u8; 32]).to_base64().as_str())
+        );
+        assert_eq!(hub.get("Endpoint"), Some("vpn.example.com:51820"));
+        assert_eq!(hub.get("AllowedIPs"), Some("0.0.0.0/0, ::/0"));
+        assert!(client.config_string().contains("PersistentKeepalive = 25"));
+
+        options.pools.truncate(1);
+        options.derived_pools = vec!["fd00:9::/64".parse().unwrap()];
+        let (client, _) =
+            prepare_client(&server, &options, keypair.clone(), Key([4u8; 32])).unwrap();
+        assert_eq!(
+            client.addresses[1],
+            key_address(&keypair.public, &options.derived_pools[0]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_revocation() {
+        use crate::PeerInfo;
+
+        let client = PeerConfigBuilder::new(&Key([3u8; 32]))
+            .add_allowed_ip("10.8.0.3".parse().unwrap(), 32)
+            .add_allowed_ip("fd00:8::3".parse().unwrap(), 128)
+            .into_peer_config();
+        let peer = PeerInfo {
+            config: client,
+            stats: Default::default(),
+        };
+        let device = Device {
+            public_key: Some(Key([2u8; 32])),
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", vec![peer.with_transfer(100, 200)])
+        };
+
+        let report = revocation(&device, &Key([3u8; 32])).unwrap();
+        assert_eq!((report.rx_bytes, report.tx_bytes), (100, 200));
+        assert_eq!(
+            report.to_string(),
+            format!(
+                "revoked {} from wg0, released 10.8.0.3/32, fd00:8::3/128",
+                Key([3u8; 32]).to_base64()
+            )
+        );
+
+        let error = revocation(&device, &Key([4u8; 32])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut template = Template::new("wg0".parse().unwrap());
+        template.pools = vec![
+            "10.8.0.0/24".parse().unwrap(),
+            "fd00:8::/64".parse().unwrap(),
+        ];
+        template.listen_port = Some(51820);
+        template.peers.push(
+            PeerConfigBuilder::new(&Key([2u8; 32]))
+                .set_endpoint("192.0.2.1:51820".parse().unwrap())
+                .add_allowed_ip("10.8.0.0".parse().unwrap(), 24)
+                .into_peer_config(),
+        );
+
+        let identity = identity("edge-01");
+        let keypair = KeyPair::from_seed(&[1u8; 32]);
+        let file = template.render(&identity, &keypair).unwrap();
+        let interface = file.interface().unwrap();
+        assert_eq!(interface.annotation("Host").as_deref(), Some("edge-01"));
+        assert_eq!(interface.get("ListenPort"), Some("51820"));
+        let addresses = template.addresses(&identity).unwrap();
+        assert_eq!(
+            interface.get("Address"),
+            Some(format!("{}, {}", addresses[0], addresses[1]).as_str())
+        );
+        assert_eq!(file.peers().count(), 1);
+        assert_eq!(
+            file.to_string(),
+            template.render(&identity, &keypair).unwrap().to_string()
+        );
+    }
+}
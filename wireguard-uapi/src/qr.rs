@@ -0,0 +1,190 @@
+//! QR codes of client configs, for importing them into the mobile apps.
+//!
+//! A [`ClientConfig`] is the config file of a client reaching a single server, in
+//! the wg-quick format the WireGuard apps for Android and iOS scan. A [`QrCode`]
+//! of it renders for terminals, as SVG or as PNG without pulling in an image
+//! library.
+pub use crate::conf::ClientConfig;
+
+use qrcode::{render::svg, render::unicode::Dense1x2, EcLevel};
+use std::{fmt, io};
+
+/// Blank modules around the code, as the QR spec requires for readers to find it.
+const QUIET_ZONE: usize = 4;
+
+impl ClientConfig {
+    pub fn qr_code(&self) -> io::Result<QrCode> {
+        QrCode::new(&self.to_string())
+    }
+}
+
+/// A QR code of some text, typically a config file.
+pub struct QrCode(qrcode::QrCode);
+
+impl fmt::Debug for QrCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The encoded text usually holds a private key.
+        f.debug_struct("QrCode")
+            .field("width", &self.0.width())
+            .finish_non_exhaustive()
+    }
+}
+
+impl QrCode {
+    /// Encodes `text` with low error correction, like `qrencode`, which keeps the
+    /// code of a config with many allowed IPs small enough to scan off a screen.
+    ///
+    /// Fails if the text doesn't fit in a QR code, about 2900 bytes.
+    pub fn new(text: &str) -> io::Result<Self> {
+        qrcode::QrCode::with_error_correction_level(text, EcLevel::L)
+            .map(Self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+    }
+
+    /// The number of modules along each side, without the quiet zone.
+    pub fn width(&self) -> usize {
+        self.0.width()
+    }
+
+    /// The code drawn with Unicode half blocks, two rows of modules per line.
+    pub fn to_terminal(&self) -> String {
+        self.0.render::<Dense1x2>().quiet_zone(true).build()
+    }
+
+    /// The code as an SVG document, `module_size` pixels per module.
+    pub fn to_svg(&self, module_size: u32) -> String {
+        self.0
+            .render::<svg::Color>()
+            .quiet_zone(true)
+            .module_dimensions(module_size, module_size)
+            .build()
+    }
+
+    /// The code as a black and white PNG image, `module_size` pixels per module.
+    pub fn to_png(&self, module_size: u32) -> Vec<u8> {
+        let scale = module_size.max(1) as usize;
+        let modules = self.0.width() + 2 * QUIET_ZONE;
+        let size = modules * scale;
+        let colors = self.0.to_colors();
+        let dark = |x: usize, y: usize| {
+            let (x, y) = (x / scale, y / scale);
+            (QUIET_ZONE..QUIET_ZONE + self.0.width()).contains(&x)
+                && (QUIET_ZONE..QUIET_ZONE + self.0.width()).contains(&y)
+                && colors[(y - QUIET_ZONE) * self.0.width() + x - QUIET_ZONE] == qrcode::Color::Dark
+        };
+
+        // One bit per pixel, set for white, each row after a "no filter" byte.
+        let row_len = 1 + size.div_ceil(8);
+        let mut pixels = vec![0u8; row_len * size];
+        for (y, row) in pixels.chunks_mut(row_len).enumerate() {
+            for x in (0..size).filter(|x| !dark(*x, y)) {
+                row[1 + x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(size as u32).to_be_bytes());
+        header.extend_from_slice(&(size as u32).to_be_bytes());
+        // Bit depth 1, grayscale, deflate, no filtering, no interlacing.
+        header.extend_from_slice(&[1, 0, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &header);
+        png_chunk(&mut png, b"IDAT", &zlib_stored(&pixels));
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// A zlib stream of `data` in uncompressed deflate blocks. QR codes are small
+/// enough that compressing them isn't worth an implementation of deflate.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(u16::MAX as usize).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_client_config() {
+        let mut config = ClientConfig::new(Key([1; 32]), Key([2; 32]), "vpn.example.com:51820");
+        config.name = Some("phone".to_string());
+        config.addresses = vec!["10.0.0.2/32".parse().unwrap()];
+        config.dns = vec!["10.0.0.1".to_string()];
+        config.persistent_keepalive = Some(25);
+        assert_eq!(
+            config.to_string(),
+            format!(
+                "# Name: phone\n[Interface]\nPrivateKey = {}\nAddress = 10.0.0.2/32\nDNS = 10.0.0.1\n\
+                 [Peer]\nPublicKey = {}\nEndpoint = vpn.example.com:51820\nAllowedIPs = 0.0.0.0/0, ::/0\nPersistentKeepalive = 25\n",
+                Key([1; 32]).to_base64(),
+                Key([2; 32]).to_base64(),
+            )
+        );
+
+        let code = config.qr_code().unwrap();
+        assert!(code.to_terminal().lines().count() > code.width() / 2);
+        assert!(code.to_svg(4).contains("<svg"));
+        assert!(QrCode::new(&"x".repeat(8000)).is_err());
+    }
+
+    #[test]
+    fn test_png() {
+        let code = QrCode::new("wireguard").unwrap();
+        let png = code.to_png(2);
+        let size = ((code.width() + 2 * QUIET_ZONE) * 2) as u32;
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..20], size.to_be_bytes());
+        assert_eq!(png[20..24], size.to_be_bytes());
+        assert_eq!(crc32(&png[12..29]).to_be_bytes(), png[29..33]);
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
+}
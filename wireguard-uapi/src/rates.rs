@@ -0,0 +1,132 @@
+//! Per-peer throughput from periodic snapshots.
+//!
+//! The backends only report cumulative byte counters. A [`RateSampler`] keeps the
+//! counters of the previous snapshot and turns each new one into bytes per second
+//! for every peer. Counters that went down, because the peer was removed and
+//! added again in between, count as reset, and peers added since the previous
+//! snapshot are measured from zero.
+use crate::{Backend, Device, InterfaceName, Key};
+
+use std::{collections::HashMap, io, time::Instant};
+
+/// The throughput of a peer between two snapshots.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Rate {
+    pub rx_bytes_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+}
+
+fn counter_delta(previous: u64, current: u64) -> u64 {
+    current.checked_sub(previous).unwrap_or(current)
+}
+
+/// Turns snapshots of an interface into per-peer rates.
+#[derive(Debug, Clone, Default)]
+pub struct RateSampler {
+    last: Option<Instant>,
+    counters: HashMap<Key, (u64, u64)>,
+}
+
+impl RateSampler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The rates of the peers of `device`, a snapshot taken at `now`, since the
+    /// previous snapshot. The first snapshot only sets the baseline, so it has no
+    /// rates; neither do snapshots taken at the same instant as the previous one.
+    pub fn observe(&mut self, device: &Device, now: Instant) -> Vec<(Key, Rate)> {
+        let elapsed = self
+            .last
+            .map(|last| now.saturating_duration_since(last).as_secs_f64())
+            .filter(|elapsed| *elapsed > 0.0);
+        self.last = Some(now);
+
+        let mut counters = HashMap::with_capacity(device.peers.len());
+        let mut rates = vec![];
+        for peer in &device.peers {
+            let key = &peer.config.public_key;
+            let current = (peer.stats.rx_bytes, peer.stats.tx_bytes);
+            let previous = self.counters.get(key).copied().unwrap_or((0, 0));
+            counters.insert(key.clone(), current);
+            if let Some(elapsed) = elapsed {
+                rates.push((
+                    key.clone(),
+                    Rate {
+                        rx_bytes_per_sec: counter_delta(previous.0, current.0) as f64 / elapsed,
+                        tx_bytes_per_sec: counter_delta(previous.1, current.1) as f64 / elapsed,
+                    },
+                ));
+            }
+        }
+        // Peers that are gone are forgotten, so re-adding them counts from zero.
+        self.counters = counters;
+        rates
+    }
+
+    /// Reads `iface` now and returns the rates since the previous read.
+    pub fn sample(
+        &mut self,
+        iface: &InterfaceName,
+        backend: Backend,
+    ) -> io::Result<Vec<(Key, Rate)>> {
+        let device = Device::get(iface, backend)?;
+        Ok(self.observe(&device, Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerInfo;
+    use std::time::Duration;
+
+    fn device(peers: &[(u8, u64, u64)]) -> Device {
+        Device::fixture(
+            "wg0",
+            peers
+                .iter()
+                .map(|&(key, rx_bytes, tx_bytes)| {
+                    PeerInfo::fixture(Key([key; 32])).with_transfer(rx_bytes, tx_bytes)
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_rates() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut sampler = RateSampler::new();
+        assert!(sampler
+            .observe(&device(&[(1, 1000, 500)]), at(0))
+            .is_empty());
+
+        let rates = sampler.observe(&device(&[(1, 3000, 1500), (2, 400, 0)]), at(2));
+        assert_eq!(
+            rates,
+            vec![
+                (
+                    Key([1; 32]),
+                    Rate {
+                        rx_bytes_per_sec: 1000.0,
+                        tx_bytes_per_sec: 500.0
+                    }
+                ),
+                // Added since the previous snapshot.
+                (
+                    Key([2; 32]),
+                    Rate {
+                        rx_bytes_per_sec: 200.0,
+                        tx_bytes_per_sec: 0.0
+                    }
+                ),
+            ]
+        );
+
+        // Recycled: the counters started over.
+        let rates = sampler.observe(&device(&[(1, 100, 0)]), at(4));
+        assert_eq!(rates[0].1.rx_bytes_per_sec, 50.0);
+        assert!(sampler.observe(&device(&[(1, 100, 0)]), at(4)).is_empty());
+    }
+}
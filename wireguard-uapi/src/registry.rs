@@ -0,0 +1,436 @@
+//! A process-wide registry of the interfaces this process manages.
+//!
+//! Components within the same binary can share one [`Registry`] instead of each
+//! polling the backends on their own: [`Registry::snapshot`] hands out a cached
+//! [`Device`] when it is fresh enough and makes concurrent callers wait on a single
+//! backend read otherwise. Every change is broadcast to [`subscribers`](Registry::subscribe),
+//! and the latest state of each interface can be [watched](Registry::watch).
+//!
+//! Clients that render peers, such as desktop GUIs, can instead
+//! [subscribe to peer events](Registry::subscribe_devices): each new snapshot is
+//! [diffed](diff) against the previous one and the differences are broadcast as
+//! [`DeviceEvent`]s.
+use crate::{
+    watcher::{peer_changes, PeerChange},
+    Backend, Device, Error, InterfaceName, Key, PeerInfo,
+};
+
+pub use crate::watcher::StatsDelta;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, watch};
+
+const EVENT_CAPACITY: usize = 64;
+
+/// A change to the set of managed interfaces or to one of their snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryEvent {
+    /// The interface was added to the registry.
+    Managed(InterfaceName),
+    /// The interface was removed from the registry.
+    Unmanaged(InterfaceName),
+    /// A new snapshot of the interface was read from the backend.
+    Updated(InterfaceName),
+}
+
+/// A change to the peers of a managed interface between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    PeerAdded {
+        iface: InterfaceName,
+        peer: PeerInfo,
+    },
+    PeerRemoved {
+        iface: InterfaceName,
+        public_key: Key,
+    },
+    EndpointChanged {
+        iface: InterfaceName,
+        public_key: Key,
+        old: Option<SocketAddr>,
+        new: Option<SocketAddr>,
+    },
+    /// The peer transferred data or completed a handshake.
+    StatsUpdated {
+        iface: InterfaceName,
+        public_key: Key,
+        delta: StatsDelta,
+    },
+}
+
+/// The events that turn `old` into `new`. Without an old snapshot, every peer
+/// counts as added.
+///
+/// Counters that went down (e.g. the peer was removed and re-added in between)
+/// are treated as reset, so the delta is the new value.
+pub fn diff(old: Option<&Device>, new: &Device) -> Vec<DeviceEvent> {
+    let iface = new.name;
+    let mut events = vec![];
+    for change in peer_changes(old, new) {
+        match change {
+            PeerChange::Added(peer) => events.push(DeviceEvent::PeerAdded {
+                iface,
+                peer: peer.clone(),
+            }),
+            PeerChange::Removed(public_key) => events.push(DeviceEvent::PeerRemoved {
+                iface,
+                public_key: public_key.clone(),
+            }),
+            PeerChange::Kept { old, new, delta } => {
+                let public_key = &new.config.public_key;
+                if old.config.endpoint != new.config.endpoint {
+                    events.push(DeviceEvent::EndpointChanged {
+                        iface,
+                        public_key: public_key.clone(),
+                        old: old.config.endpoint,
+                        new: new.config.endpoint,
+                    });
+                }
+                if delta != StatsDelta::default() {
+                    events.push(DeviceEvent::StatsUpdated {
+                        iface,
+                        public_key: public_key.clone(),
+                        delta,
+                    });
+                }
+            }
+        }
+    }
+    events
+}
+
+struct Snapshot {
+    device: Arc<Device>,
+    read_at: Instant,
+}
+
+struct Entry {
+    backend: Backend,
+    // Held while reading from the backend so concurrent refreshes are deduplicated.
+    snapshot: Mutex<Option<Snapshot>>,
+    watch: watch::Sender<Option<Arc<Device>>>,
+}
+
+/// Tracks managed interfaces and shares their latest state across the process.
+pub struct Registry {
+    entries: RwLock<HashMap<InterfaceName, Arc<Entry>>>,
+    events: broadcast::Sender<RegistryEvent>,
+    device_events: broadcast::Sender<DeviceEvent>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Registry {
+    /// Creates an empty, standalone registry.
+    ///
+    /// Most applications want the shared [`global`](Registry::global) one instead.
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let (device_events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            events,
+            device_events,
+        }
+    }
+
+    /// The registry shared by the whole process.
+    pub fn global() -> &'static Registry {
+        static GLOBAL: OnceLock<Registry> = OnceLock::new();
+        GLOBAL.get_or_init(Registry::new)
+    }
+
+    fn entry(&self, name: &InterfaceName) -> Option<Arc<Entry>> {
+        self.entries
+            .read()
+            .expect("registry lock poisoned")
+            .get(name)
+            .cloned()
+    }
+
+    fn notify(&self, event: RegistryEvent) {
+        // Having no subscribers isn't an error.
+        let _ = self.events.send(event);
+    }
+
+    /// Starts tracking `name` on `backend`. Returns `false` if it was already managed.
+    pub fn manage(&self, name: &InterfaceName, backend: Backend) -> bool {
+        let mut entries = self.entries.write().expect("registry lock poisoned");
+        if entries.contains_key(name) {
+            return false;
+        }
+        let (watch, _) = watch::channel(None);
+        entries.insert(
+            *name,
+            Arc::new(Entry {
+                backend,
+                snapshot: Mutex::new(None),
+                watch,
+            }),
+        );
+        drop(entries);
+        self.notify(RegistryEvent::Managed(*name));
+        true
+    }
+
+    /// Stops tracking `name`. Returns `false` if it wasn't managed.
+    ///
+    /// This doesn't touch the interface itself.
+    pub fn unmanage(&self, name: &InterfaceName) -> bool {
+        let removed = self
+            .entries
+            .write()
+            .expect("registry lock poisoned")
+            .remove(name);
+        match removed {
+            Some(entry) => {
+                entry.watch.send_replace(None);
+                self.notify(RegistryEvent::Unmanaged(*name));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `name` is currently managed.
+    pub fn is_managed(&self, name: &InterfaceName) -> bool {
+        self.entry(name).is_some()
+    }
+
+    /// The managed interfaces and their backends.
+    pub fn managed(&self) -> Vec<(InterfaceName, Backend)> {
+        self.entries
+            .read()
+            .expect("registry lock poisoned")
+            .iter()
+            .map(|(name, entry)| (*name, entry.backend))
+            .collect()
+    }
+
+    /// The last snapshot read for `name`, without touching the backend.
+    pub fn cached(&self, name: &InterfaceName) -> Option<Arc<Device>> {
+        let entry = self.entry(name)?;
+        let snapshot = entry.snapshot.lock().expect("registry lock poisoned");
+        snapshot.as_ref().map(|snapshot| snapshot.device.clone())
+    }
+
+    /// Returns a snapshot of `name` that is at most `max_age` old, reading it from
+    /// the backend if needed.
+    ///
+    /// Callers asking at the same time share a single backend read.
+    pub fn snapshot(&self, name: &InterfaceName, max_age: Duration) -> Result<Arc<Device>, Error> {
+        let entry = self.entry(name).ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("interface {} is not managed", name),
+            ))
+        })?;
+
+        let snapshot = entry.snapshot.lock().expect("registry lock poisoned");
+        if let Some(snapshot) = snapshot.as_ref() {
+            if snapshot.read_at.elapsed() <= max_age {
+                return Ok(snapshot.device.clone());
+            }
+        }
+
+        let device = Arc::new(Device::get(name, entry.backend)?);
+        self.store(&entry, snapshot, device.clone());
+        Ok(device)
+    }
+
+    /// Replaces the snapshot held in `slot` and notifies subscribers.
+    fn store(
+        &self,
+        entry: &Entry,
+        mut slot: MutexGuard<'_, Option<Snapshot>>,
+        device: Arc<Device>,
+    ) {
+        let name = device.name;
+        let previous = slot.replace(Snapshot {
+            device: device.clone(),
+            read_at: Instant::now(),
+        });
+        drop(slot);
+
+        if self.device_events.receiver_count() > 0 {
+            let previous = previous.as_ref().map(|snapshot| &*snapshot.device);
+            for event in diff(previous, &device) {
+                let _ = self.device_events.send(event);
+            }
+        }
+        entry.watch.send_replace(Some(device));
+        self.notify(RegistryEvent::Updated(name));
+    }
+
+    /// Reads a fresh snapshot of `name` from the backend.
+    pub fn refresh(&self, name: &InterfaceName) -> Result<Arc<Device>, Error> {
+        self.snapshot(name, Duration::ZERO)
+    }
+
+    /// Stores a snapshot obtained elsewhere (e.g. right after an apply) and notifies
+    /// subscribers. Returns `false` if the interface isn't managed.
+    pub fn update(&self, device: Device) -> bool {
+        let name = device.name;
+        let entry = match self.entry(&name) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let slot = entry.snapshot.lock().expect("registry lock poisoned");
+        self.store(&entry, slot, Arc::new(device));
+        true
+    }
+
+    /// Subscribes to all registry changes.
+    ///
+    /// Slow subscribers may miss events (see [`broadcast::Receiver::recv`]); use
+    /// [`watch`](Registry::watch) when only the latest state matters.
+    pub fn subscribe(&self) -> broadcast::Receiver<RegistryEvent> {
+        self.events.subscribe()
+    }
+
+    /// Subscribes to the peer-level changes of every managed interface.
+    ///
+    /// Snapshots are only diffed while someone is subscribed, so the first snapshot
+    /// read after subscribing is compared against the cached one. Like
+    /// [`subscribe`](Registry::subscribe), slow subscribers may miss events.
+    pub fn subscribe_devices(&self) -> broadcast::Receiver<DeviceEvent> {
+        self.device_events.subscribe()
+    }
+
+    /// Watches the latest snapshot of `name`, or `None` if it isn't managed.
+    ///
+    /// The watched value becomes `None` once the interface is unmanaged.
+    pub fn watch(&self, name: &InterfaceName) -> Option<watch::Receiver<Option<Arc<Device>>>> {
+        self.entry(name).map(|entry| entry.watch.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &InterfaceName) -> Device {
+        Device {
+            listen_port: Some(51820),
+            ..Device::fixture(&name.as_str_lossy(), vec![])
+        }
+    }
+
+    #[test]
+    fn test_manage_and_broadcast() {
+        let registry = Registry::new();
+        let name: InterfaceName = "wg-test".parse().unwrap();
+        let mut events = registry.subscribe();
+
+        assert!(registry.manage(&name, Backend::Userspace));
+        assert!(!registry.manage(&name, Backend::Userspace));
+        let watch = registry.watch(&name).unwrap();
+        assert!(watch.borrow().is_none());
+
+        assert!(registry.update(device(&name)));
+        assert_eq!(registry.cached(&name).unwrap().listen_port, Some(51820));
+        assert_eq!(watch.borrow().as_ref().unwrap().listen_port, Some(51820));
+        // A fresh enough snapshot is served from the cache without a backend read.
+        let cached = registry.snapshot(&name, Duration::from_secs(60)).unwrap();
+        assert_eq!(cached.name, name);
+
+        assert!(registry.unmanage(&name));
+        assert!(watch.borrow().is_none());
+        assert_eq!(events.try_recv().unwrap(), RegistryEvent::Managed(name));
+        assert_eq!(events.try_recv().unwrap(), RegistryEvent::Updated(name));
+        assert_eq!(events.try_recv().unwrap(), RegistryEvent::Unmanaged(name));
+        assert!(registry.snapshot(&name, Duration::ZERO).is_err());
+    }
+
+    fn peer(key: u8, endpoint: &str, rx_bytes: u64) -> PeerInfo {
+        PeerInfo::fixture(Key([key; 32]))
+            .with_endpoint(endpoint)
+            .with_transfer(rx_bytes, 0)
+    }
+
+    #[test]
+    fn test_diff() {
+        let name: InterfaceName = "wg-test".parse().unwrap();
+        let mut old = device(&name);
+        old.peers = vec![
+            peer(1, "192.0.2.1:51820", 100),
+            peer(2, "192.0.2.2:51820", 100),
+        ];
+        let mut new = device(&name);
+        new.peers = vec![
+            peer(1, "198.51.100.1:51820", 150),
+            peer(3, "192.0.2.3:51820", 0),
+        ];
+
+        assert_eq!(
+            diff(Some(&old), &new),
+            vec![
+                DeviceEvent::EndpointChanged {
+                    iface: name,
+                    public_key: Key([1u8; 32]),
+                    old: Some("192.0.2.1:51820".parse().unwrap()),
+                    new: Some("198.51.100.1:51820".parse().unwrap()),
+                },
+                DeviceEvent::StatsUpdated {
+                    iface: name,
+                    public_key: Key([1u8; 32]),
+                    delta: StatsDelta {
+                        rx_bytes: 50,
+                        ..Default::default()
+                    },
+                },
+                DeviceEvent::PeerAdded {
+                    iface: name,
+                    peer: new.peers[1].clone(),
+                },
+                DeviceEvent::PeerRemoved {
+                    iface: name,
+                    public_key: Key([2u8; 32]),
+                },
+            ]
+        );
+        assert!(diff(Some(&new), &new).is_empty());
+        assert_eq!(diff(None, &new).len(), 2);
+    }
+
+    #[test]
+    fn test_subscribe_devices() {
+        let registry = Registry::new();
+        let name: InterfaceName = "wg-test".parse().unwrap();
+        registry.manage(&name, Backend::Userspace);
+        let mut events = registry.subscribe_devices();
+
+        let mut snapshot = device(&name);
+        snapshot.peers = vec![peer(1, "192.0.2.1:51820", 0)];
+        registry.update(snapshot.clone());
+        snapshot.peers[0].stats.tx_bytes = 10;
+        registry.update(snapshot);
+
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            DeviceEvent::PeerAdded { .. }
+        ));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            DeviceEvent::StatsUpdated {
+                iface: name,
+                public_key: Key([1u8; 32]),
+                delta: StatsDelta {
+                    tx_bytes: 10,
+                    ..Default::default()
+                },
+            }
+        );
+        assert!(events.try_recv().is_err());
+    }
+}
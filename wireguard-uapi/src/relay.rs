@@ -0,0 +1,357 @@
+//! Meshes whose spokes fall back to hairpinning through a relay node.
+//!
+//! In a [`RelayMesh`] every spoke peers directly with the other spokes it can
+//! reach, using host routes for their tunnel addresses, and with the relay, which
+//! gets the whole overlay network. Since the host routes are more specific,
+//! traffic goes direct while the direct path works; [`RelayMesh::failover`] drops
+//! the host routes of peers without a recent handshake, so their traffic falls
+//! back to the relay, and restores them once the handshake succeeds again.
+//!
+//! The relay itself only routes each spoke's own addresses to it, and must
+//! forward packets between the spokes: IP forwarding must be enabled for every
+//! address family of the network, which [`RelayMesh::check_relay_host`] checks,
+//! and its firewall must let packets back out of the interface they came in on.
+use crate::{
+    conf::{peer_section, ConfFile, Section, SectionKind},
+    Device, DeviceUpdate, Key, PeerConfig, PeerConfigBuilder,
+};
+
+use ipnet::IpNet;
+use std::{
+    io,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+/// How long a handshake counts as recent: WireGuard rekeys every 2 minutes while
+/// a session is in use, and gives up on a session after 3.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// A node of the mesh.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub name: String,
+    pub public_key: Key,
+    /// The node's tunnel address, within the mesh network.
+    pub address: IpNet,
+    /// Where the other nodes reach the node directly, if they can.
+    pub endpoint: Option<SocketAddr>,
+}
+
+impl Node {
+    pub fn new(name: &str, public_key: Key, address: IpNet) -> Self {
+        Self {
+            name: name.to_string(),
+            public_key,
+            address,
+            endpoint: None,
+        }
+    }
+
+    pub fn set_endpoint(mut self, endpoint: SocketAddr) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// The route to the node's own tunnel address only.
+    fn host_route(&self) -> IpNet {
+        IpNet::new(self.address.addr(), self.address.max_prefix_len()).expect("valid prefix")
+    }
+}
+
+/// What forwarding the host has enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Forwarding {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+/// Reads whether the host forwards IPv4 and IPv6 packets.
+#[cfg(target_os = "linux")]
+pub fn forwarding() -> io::Result<Forwarding> {
+    let enabled =
+        |path: &str| -> io::Result<bool> { Ok(std::fs::read_to_string(path)?.trim() == "1") };
+    Ok(Forwarding {
+        ipv4: enabled("/proc/sys/net/ipv4/ip_forward")?,
+        ipv6: enabled("/proc/sys/net/ipv6/conf/all/forwarding")?,
+    })
+}
+
+/// A relay node and the spokes hairpinning through it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayMesh {
+    /// The overlay network every tunnel address is in, routed to the relay by
+    /// the spokes.
+    pub network: IpNet,
+    pub relay: Node,
+    pub spokes: Vec<Node>,
+    /// Sent by the spokes, so paths through NATs stay open. 25 seconds by default.
+    pub persistent_keepalive: u16,
+}
+
+impl RelayMesh {
+    /// A mesh of `network` relayed by `relay`, which every spoke must be able to
+    /// reach.
+    pub fn new(network: IpNet, relay: Node) -> io::Result<Self> {
+        if relay.endpoint.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("relay {} has no endpoint", relay.name),
+            ));
+        }
+        let mesh = Self {
+            network,
+            relay,
+            spokes: vec![],
+            persistent_keepalive: 25,
+        };
+        mesh.check_node(&mesh.relay)?;
+        Ok(mesh)
+    }
+
+    fn check_node(&self, node: &Node) -> io::Result<()> {
+        if !self.network.contains(&node.address.addr()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is outside of {}", node.address.addr(), self.network),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Adds `spoke`, whose key and address must be unique in the mesh.
+    pub fn add_spoke(&mut self, spoke: Node) -> io::Result<()> {
+        self.check_node(&spoke)?;
+        let nodes = || std::iter::once(&self.relay).chain(&self.spokes);
+        if let Some(other) = nodes().find(|node| {
+            node.public_key == spoke.public_key || node.address.addr() == spoke.address.addr()
+        }) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} clashes with {}", spoke.name, other.name),
+            ));
+        }
+        self.spokes.push(spoke);
+        Ok(())
+    }
+
+    fn spoke(&self, public_key: &Key) -> io::Result<&Node> {
+        self.spokes
+            .iter()
+            .find(|spoke| spoke.public_key == *public_key)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such spoke"))
+    }
+
+    /// Checks that the relay host forwards every address family of the network.
+    #[cfg(target_os = "linux")]
+    pub fn check_relay_host(&self) -> io::Result<()> {
+        let forwarding = forwarding()?;
+        let enabled = match self.network {
+            IpNet::V4(_) => forwarding.ipv4,
+            IpNet::V6(_) => forwarding.ipv6,
+        };
+        if !enabled {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("the relay doesn't forward the packets of {}", self.network),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The peers of the relay: each spoke, routed only its own address.
+    pub fn relay_peers(&self) -> Vec<PeerConfig> {
+        self.spokes
+            .iter()
+            .map(|spoke| {
+                let peer = PeerConfigBuilder::new(&spoke.public_key)
+                    .add_allowed_ip(spoke.address.addr(), spoke.address.max_prefix_len());
+                match spoke.endpoint {
+                    Some(endpoint) => peer.set_endpoint(endpoint),
+                    None => peer,
+                }
+                .into_peer_config()
+            })
+            .collect()
+    }
+
+    /// The update making the relay's peers exactly the spokes.
+    pub fn relay_update(&self) -> DeviceUpdate {
+        let peers: Vec<_> = self
+            .relay_peers()
+            .into_iter()
+            .map(PeerConfigBuilder::from_peer_config)
+            .collect();
+        DeviceUpdate::new().replace_peers().add_peers(&peers)
+    }
+
+    /// The peers of the spoke with `public_key`: the relay, routed the whole
+    /// network, and every other spoke with an endpoint, routed its own address.
+    pub fn spoke_peers(&self, public_key: &Key) -> io::Result<Vec<PeerConfig>> {
+        self.spoke(public_key)?;
+        let relay = PeerConfigBuilder::new(&self.relay.public_key)
+            .add_allowed_ip(self.network.network(), self.network.prefix_len())
+            .set_persistent_keepalive_interval(self.persistent_keepalive);
+        let relay = match self.relay.endpoint {
+            Some(endpoint) => relay.set_endpoint(endpoint),
+            None => relay,
+        };
+        let direct = self
+            .spokes
+            .iter()
+            .filter(|spoke| spoke.public_key != *public_key)
+            .filter_map(|spoke| {
+                let route = spoke.host_route();
+                Some(
+                    PeerConfigBuilder::new(&spoke.public_key)
+                        .set_endpoint(spoke.endpoint?)
+                        .add_allowed_ip(route.addr(), route.prefix_len())
+                        .set_persistent_keepalive_interval(self.persistent_keepalive),
+                )
+            });
+        Ok(std::iter::once(relay)
+            .chain(direct)
+            .map(PeerConfigBuilder::into_peer_config)
+            .collect())
+    }
+
+    /// The wg-quick style config file of the spoke with `private_key`.
+    pub fn spoke_config(&self, private_key: &Key) -> io::Result<ConfFile> {
+        let spoke = self.spoke(&private_key.get_public())?;
+        let mut interface = Section::new(SectionKind::Interface);
+        interface.set_annotation("Name", &spoke.name);
+        interface.set("PrivateKey", private_key.to_base64());
+        interface.set("Address", spoke.address.to_string());
+        if let Some(endpoint) = spoke.endpoint {
+            interface.set("ListenPort", endpoint.port().to_string());
+        }
+
+        let mut file = ConfFile::default();
+        file.sections.push(interface);
+        for peer in self.spoke_peers(&spoke.public_key)? {
+            let mut section = peer_section(&peer);
+            let name = if peer.public_key == self.relay.public_key {
+                &self.relay.name
+            } else {
+                &self.spoke(&peer.public_key)?.name
+            };
+            section.set_annotation("Name", name);
+            file.sections.push(section);
+        }
+        Ok(file)
+    }
+
+    /// The update of a spoke's `device` routing each other spoke directly if its
+    /// last handshake as of `now` is recent, and through the relay otherwise.
+    /// Peers that already route as they should are left out.
+    pub fn failover(&self, device: &Device, now: SystemTime) -> DeviceUpdate {
+        let mut update = DeviceUpdate::new();
+        for peer in &device.peers {
+            let public_key = &peer.config.public_key;
+            let Some(spoke) = self
+                .spokes
+                .iter()
+                .find(|spoke| spoke.public_key == *public_key)
+            else {
+                continue;
+            };
+            let recent = peer.stats.last_handshake_time.is_some_and(|time| {
+                now.duration_since(time).unwrap_or_default() <= HANDSHAKE_TIMEOUT
+            });
+            let route = spoke.host_route();
+            let routed = !peer.config.allowed_ips.is_empty();
+            if recent && !routed {
+                update = update.add_peer(
+                    PeerConfigBuilder::new(public_key)
+                        .replace_allowed_ips()
+                        .add_allowed_ip(route.addr(), route.prefix_len()),
+                );
+            } else if !recent && routed {
+                update = update.add_peer(PeerConfigBuilder::new(public_key).replace_allowed_ips());
+            }
+        }
+        update
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerInfo;
+
+    fn node(name: &str, key: u8, address: &str) -> Node {
+        Node::new(name, Key([key; 32]), address.parse().unwrap())
+            .set_endpoint(([192, 0, 2, key], 51820).into())
+    }
+
+    fn mesh() -> RelayMesh {
+        let mut mesh = RelayMesh::new(
+            "10.9.0.0/24".parse().unwrap(),
+            node("relay", 1, "10.9.0.1/24"),
+        )
+        .unwrap();
+        mesh.add_spoke(node("a", 2, "10.9.0.2/24")).unwrap();
+        mesh.add_spoke(node("b", 3, "10.9.0.3/24")).unwrap();
+        mesh
+    }
+
+    #[test]
+    fn test_add_spoke() {
+        let mut mesh = mesh();
+        let clash = mesh.add_spoke(node("c", 4, "10.9.0.2/24")).unwrap_err();
+        assert_eq!(clash.kind(), io::ErrorKind::AlreadyExists);
+        let outside = mesh.add_spoke(node("c", 4, "10.8.0.4/24")).unwrap_err();
+        assert_eq!(outside.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_routes() {
+        let mesh = mesh();
+        let relay_peers = mesh.relay_peers();
+        assert_eq!(relay_peers.len(), 2);
+        assert_eq!(format!("{:?}", relay_peers[0].allowed_ips), "[10.9.0.2/32]");
+
+        let peers = mesh.spoke_peers(&Key([2; 32])).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].public_key, Key([1; 32]));
+        assert_eq!(format!("{:?}", peers[0].allowed_ips), "[10.9.0.0/24]");
+        assert_eq!(peers[1].public_key, Key([3; 32]));
+        assert_eq!(format!("{:?}", peers[1].allowed_ips), "[10.9.0.3/32]");
+
+        let config = mesh.spoke_config(&Key::generate_private());
+        assert_eq!(config.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_failover() {
+        let mesh = mesh();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let peer = |key: u8, handshake: Option<SystemTime>, routed: bool| {
+            let mut peer = PeerInfo::fixture(Key([key; 32]));
+            if routed {
+                peer = peer.with_allowed_ips(&[&format!("10.9.0.{}/32", key)]);
+            }
+            peer.stats.last_handshake_time = handshake;
+            peer
+        };
+        let device = Device::fixture(
+            "wg0",
+            vec![
+                peer(1, None, false),
+                peer(3, Some(now - Duration::from_secs(600)), true),
+            ],
+        );
+        let update = mesh.failover(&device, now);
+        assert_eq!(update.peers.len(), 1);
+        assert!(update.peers[0].replace_allowed_ips);
+        assert!(update.peers[0].allowed_ips.is_empty());
+
+        let mut recovered = device.clone();
+        recovered.peers[1] = peer(3, Some(now), false);
+        let update = mesh.failover(&recovered, now);
+        assert_eq!(
+            format!("{:?}", update.peers[0].allowed_ips),
+            "[10.9.0.3/32]"
+        );
+    }
+}
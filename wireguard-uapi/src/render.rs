@@ -0,0 +1,284 @@
+//! Human-readable rendering of devices, as printed by [`Device::print`].
+//!
+//! The renderers return a `String` so TUI/GUI applications can embed the output in
+//! their own widgets, and tests can assert on it.
+use crate::{
+    clock::{Clock, SystemClock},
+    device::{Part, ShowStyle},
+    Device, Key,
+};
+
+use colored::{ColoredString, Colorize, Styles};
+use std::{collections::HashMap, env, io::IsTerminal};
+
+/// The unit system byte counts are displayed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteUnits {
+    /// Powers of 1024: `B`, `KiB`, `MiB`, ... as printed by `wg show`.
+    Iec,
+    /// Powers of 1000: `B`, `kB`, `MB`, ...
+    Si,
+    /// The plain integer number of bytes, for machine consumption.
+    Raw,
+}
+
+/// How byte counts (e.g. transfer totals) are formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteFormat {
+    pub units: ByteUnits,
+    /// Number of decimals shown for scaled values. Ignored for [`ByteUnits::Raw`].
+    pub decimals: usize,
+}
+
+impl Default for ByteFormat {
+    fn default() -> Self {
+        Self {
+            units: ByteUnits::Iec,
+            decimals: 2,
+        }
+    }
+}
+
+impl ByteFormat {
+    /// Splits `bytes` into a value and unit suffix, e.g. `(2.0, "KiB")` for 2048
+    /// with IEC units. The suffix is empty for [`ByteUnits::Raw`].
+    pub fn scale(&self, bytes: u64) -> (f64, &'static str) {
+        let (base, units) = match self.units {
+            ByteUnits::Iec => (1024.0, ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"]),
+            ByteUnits::Si => (1000.0, ["B", "kB", "MB", "GB", "TB", "PB", "EB"]),
+            ByteUnits::Raw => return (bytes as f64, ""),
+        };
+        let mut value = bytes as f64;
+        let mut unit = 0;
+        while value >= base && unit < units.len() - 1 {
+            value /= base;
+            unit += 1;
+        }
+        (value, units[unit])
+    }
+
+    /// Formats `bytes` without any coloring, e.g. `2.00 KiB`, `2.05 kB` or `2048`.
+    pub fn format(&self, bytes: u64) -> String {
+        match self.units {
+            ByteUnits::Raw => bytes.to_string(),
+            _ => {
+                let (value, unit) = self.scale(bytes);
+                format!("{:.*} {}", self.decimals, value, unit)
+            }
+        }
+    }
+}
+
+/// Options controlling how a device is rendered.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RenderOptions {
+    /// Emit ANSI color codes. Off by default; [`color_from_env`] decides it the
+    /// way [`Device::print`] does for stdout.
+    pub color: bool,
+    /// Show the private and preshared keys instead of `(hidden)`.
+    pub show_keys: bool,
+    /// How transfer totals are formatted.
+    pub bytes: ByteFormat,
+    /// Friendly names shown below the keys of the peers that have one.
+    pub peer_names: HashMap<Key, String>,
+}
+
+/// Whether to color output by default: when `CLICOLOR_FORCE` is set to anything
+/// but `0`, otherwise unless `NO_COLOR` is set to anything non-empty, when stdout
+/// is a terminal.
+pub fn color_from_env() -> bool {
+    color_for(
+        env::var("CLICOLOR_FORCE").ok().as_deref(),
+        env::var("NO_COLOR").ok().as_deref(),
+        std::io::stdout().is_terminal(),
+    )
+}
+
+fn color_for(force: Option<&str>, no_color: Option<&str>, terminal: bool) -> bool {
+    match (force, no_color) {
+        (Some(force), _) if force != "0" => true,
+        (_, Some(no_color)) if !no_color.is_empty() => false,
+        _ => terminal,
+    }
+}
+
+impl RenderOptions {
+    /// Applies `style` when coloring. The escape codes are written here rather
+    /// than by `colored`, which decides on its own whether to color based on
+    /// stdout, so forcing colors works for any destination.
+    fn styled(&self, text: &str, style: fn(&str) -> ColoredString) -> String {
+        if !self.color {
+            return text.to_string();
+        }
+        let styled = style(text);
+        let mut codes = vec![];
+        if styled.style.contains(Styles::Bold) {
+            codes.push("1".into());
+        }
+        if let Some(color) = styled.fgcolor {
+            codes.push(color.to_fg_str());
+        }
+        format!("\x1b[{}m{}\x1b[0m", codes.join(";"), text)
+    }
+}
+
+impl ShowStyle for RenderOptions {
+    fn paint(&self, text: &str, part: Part) -> String {
+        match part {
+            Part::Interface => self.styled(text, |s| s.green()),
+            Part::Peer => self.styled(text, |s| s.yellow()),
+            Part::Label => self.styled(text, |s| s.white().bold()),
+            Part::Unit => self.styled(text, |s| s.cyan()),
+        }
+    }
+
+    /// `key` as base64 if keys are shown.
+    fn secret(&self, key: &Key) -> String {
+        if self.show_keys {
+            key.to_base64()
+        } else {
+            "(hidden)".to_string()
+        }
+    }
+
+    fn peer_name(&self, key: &Key) -> Option<&str> {
+        self.peer_names.get(key).map(String::as_str)
+    }
+
+    fn bytes(&self, bytes: u64) -> String {
+        match self.bytes.units {
+            ByteUnits::Raw => bytes.to_string(),
+            _ => {
+                let (value, unit) = self.bytes.scale(bytes);
+                let unit = self.styled(unit, |s| s.cyan());
+                format!("{:.*} {}", self.bytes.decimals, value, unit)
+            }
+        }
+    }
+}
+
+/// Renders `device` and its peers in the `wg show` layout of its `Display` impl,
+/// with the colors, keys, names and byte format of `options`.
+///
+/// This never fails: handshakes that appear to be in the future, because the
+/// clock stepped backwards, are shown as happening now.
+pub fn human(device: &Device, options: &RenderOptions) -> String {
+    human_with_clock(device, options, &SystemClock)
+}
+
+/// Like [`human`], with handshake ages measured against `clock`.
+pub fn human_with_clock(device: &Device, options: &RenderOptions, clock: &dyn Clock) -> String {
+    let mut out = String::new();
+    device
+        .write_wg_show(&mut out, clock, options)
+        .expect("writing to a String can't fail");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::ManualClock, Key, PeerInfo};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_human_plain() {
+        let mut peer = PeerInfo::fixture(Key([3u8; 32]))
+            .with_endpoint("192.0.2.1:51820")
+            .with_allowed_ips(&["10.0.0.2/32", "fd00::2/128"])
+            .with_handshake(UNIX_EPOCH + Duration::from_secs(1000))
+            .with_transfer(2048, 512);
+        peer.config.persistent_keepalive_interval = Some(25);
+        let device = Device {
+            public_key: Some(Key([1u8; 32])),
+            private_key: Some(Key([2u8; 32])),
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", vec![peer])
+        };
+
+        let options = RenderOptions {
+            color: false,
+            ..Default::default()
+        };
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1065));
+        let rendered = human_with_clock(&device, &options, &clock);
+        let expected = "\
+interface: wg0
+  public key: AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=
+  private key: (hidden)
+  listen port: 51820
+
+peer: AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=
+  endpoint: 192.0.2.1:51820
+  allowed ips: 10.0.0.2/32, fd00::2/128
+  latest handshake: 1 minute, 5 seconds ago
+  transfer: 2.00 KiB received, 512.00 B sent
+  persistent keepalive: every 25 seconds
+";
+        assert_eq!(rendered, expected);
+
+        let options = RenderOptions {
+            peer_names: [(Key([3u8; 32]), "laptop".to_string())].into(),
+            ..options
+        };
+        let rendered = human_with_clock(&device, &options, &clock);
+        assert!(rendered.contains("AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=\n  name: laptop\n"));
+
+        // A clock that stepped back before the handshake doesn't fail rendering.
+        clock.set(UNIX_EPOCH + Duration::from_secs(500));
+        let rendered = human_with_clock(&device, &options, &clock);
+        assert!(rendered.contains("  latest handshake: Now\n"));
+    }
+
+    #[test]
+    fn test_color_options() {
+        assert!(color_for(None, None, true));
+        assert!(!color_for(None, None, false));
+        assert!(!color_for(None, Some("1"), true));
+        assert!(color_for(None, Some(""), true));
+        assert!(color_for(Some("1"), Some("1"), false));
+        assert!(!color_for(Some("0"), None, false));
+
+        let device = Device {
+            private_key: Some(Key([2u8; 32])),
+            ..Device::fixture("wg0", vec![])
+        };
+        let options = RenderOptions {
+            color: true,
+            ..Default::default()
+        };
+        let rendered = human_with_clock(&device, &options, &ManualClock::new(UNIX_EPOCH));
+        assert!(rendered.starts_with("\x1b[32minterface\x1b[0m: \x1b[32mwg0\x1b[0m\n"));
+        assert!(rendered.contains("\x1b[1;37mprivate key\x1b[0m: (hidden)\n"));
+
+        let options = RenderOptions {
+            color: false,
+            show_keys: true,
+            ..Default::default()
+        };
+        let rendered = human_with_clock(&device, &options, &ManualClock::new(UNIX_EPOCH));
+        assert!(rendered.contains("  private key: AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=\n"));
+    }
+
+    #[test]
+    fn test_byte_format() {
+        let iec = ByteFormat::default();
+        assert_eq!(iec.format(512), "512.00 B");
+        assert_eq!(iec.format(1536), "1.50 KiB");
+        assert_eq!(iec.format(5 * 1024 * 1024 * 1024), "5.00 GiB");
+
+        let si = ByteFormat {
+            units: ByteUnits::Si,
+            decimals: 1,
+        };
+        assert_eq!(si.format(1536), "1.5 kB");
+        assert_eq!(si.format(999), "999.0 B");
+        assert_eq!(si.format(u64::MAX), "18.4 EB");
+
+        let raw = ByteFormat {
+            units: ByteUnits::Raw,
+            decimals: 2,
+        };
+        assert_eq!(raw.format(1536), "1536");
+    }
+}
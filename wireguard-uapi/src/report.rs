@@ -0,0 +1,428 @@
+//! Recurring usage reports built from session history.
+//!
+//! [`generate`] sums the [`SessionRecord`]s overlapping a [`Period`] into one
+//! [`PeerSummary`] per peer and one [`InterfaceSummary`] per interface, which can
+//! be written as JSON for further processing or as Markdown for mailing to
+//! admins. Sessions that straddle the edges of the period only count with the
+//! share of their transfer that falls inside it, assuming a steady rate.
+use crate::{json::quote, sessions::SessionRecord, InterfaceName, Key};
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap},
+    fmt::Write as _,
+    io::{self, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How many peers the Markdown report lists.
+const TOP_PEERS: usize = 10;
+
+/// The time span a report covers, from `start` up to but excluding `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Period {
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+impl Period {
+    pub fn new(start: SystemTime, end: SystemTime) -> Self {
+        Self { start, end }
+    }
+
+    /// The 24 hours before `end`.
+    pub fn daily(end: SystemTime) -> Self {
+        Self::new(end - DAY, end)
+    }
+
+    /// The 7 days before `end`.
+    pub fn weekly(end: SystemTime) -> Self {
+        Self::new(end - 7 * DAY, end)
+    }
+
+    /// The part of `start..end` within the period, if any.
+    fn overlap(&self, start: SystemTime, end: SystemTime) -> Option<(SystemTime, SystemTime)> {
+        let (start, end) = (start.max(self.start), end.min(self.end));
+        // Sessions without a duration count if they happened within the period.
+        (start < end || (start == end && start < self.end)).then_some((start, end))
+    }
+}
+
+/// Usage of one peer over the period.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerSummary {
+    pub iface: InterfaceName,
+    pub public_key: Key,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// The sessions that overlapped the period.
+    pub sessions: usize,
+    /// The UTC days on which the peer had a session.
+    pub active_days: usize,
+}
+
+impl PeerSummary {
+    pub fn total_bytes(&self) -> u64 {
+        self.rx_bytes + self.tx_bytes
+    }
+}
+
+/// Usage of one interface over the period.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceSummary {
+    pub iface: InterfaceName,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// The peers that had a session.
+    pub active_peers: usize,
+    /// The UTC days on which any peer had a session.
+    pub active_days: usize,
+}
+
+impl InterfaceSummary {
+    pub fn total_bytes(&self) -> u64 {
+        self.rx_bytes + self.tx_bytes
+    }
+}
+
+/// A usage report, see [`generate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub period: Period,
+    /// Ordered by name.
+    pub interfaces: Vec<InterfaceSummary>,
+    /// Ordered by total transfer, the busiest peer first.
+    pub peers: Vec<PeerSummary>,
+}
+
+/// The output formats of [`Report::write`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A single JSON object.
+    Json,
+    /// A document with an interface table and a table of the top peers.
+    Markdown,
+}
+
+/// The indices of the UTC days touched by `start..end`.
+fn days((start, end): (SystemTime, SystemTime)) -> impl Iterator<Item = u64> {
+    let day = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            / DAY.as_nanos()
+    };
+    // The end is exclusive, unless the session has no duration.
+    let last = if end > start {
+        day(end - Duration::from_nanos(1))
+    } else {
+        day(end)
+    };
+    day(start) as u64..=last as u64
+}
+
+/// The share of `bytes` transferred between `start` and `end` of a session that
+/// ran from `session_start` to `session_end`.
+fn prorate(
+    bytes: u64,
+    (session_start, session_end): (SystemTime, SystemTime),
+    (start, end): (SystemTime, SystemTime),
+) -> u64 {
+    let session = session_end
+        .duration_since(session_start)
+        .unwrap_or_default()
+        .as_millis();
+    if session == 0 {
+        return bytes;
+    }
+    let overlap = end.duration_since(start).unwrap_or_default().as_millis();
+    (u128::from(bytes) * overlap / session) as u64
+}
+
+#[derive(Default)]
+struct Usage {
+    rx_bytes: u64,
+    tx_bytes: u64,
+    sessions: usize,
+    days: BTreeSet<u64>,
+}
+
+/// Summarizes the sessions in `history` that overlap `period`.
+pub fn generate(history: &[SessionRecord], period: Period) -> Report {
+    let mut peers: HashMap<(InterfaceName, Key), Usage> = HashMap::new();
+    for record in history {
+        let Some(overlap) = period.overlap(record.start, record.end) else {
+            continue;
+        };
+        let session = (record.start, record.end);
+        let usage = peers
+            .entry((record.iface, record.public_key.clone()))
+            .or_default();
+        usage.rx_bytes += prorate(record.rx_bytes, session, overlap);
+        usage.tx_bytes += prorate(record.tx_bytes, session, overlap);
+        usage.sessions += 1;
+        usage.days.extend(days(overlap));
+    }
+
+    let mut interfaces: HashMap<InterfaceName, (InterfaceSummary, BTreeSet<u64>)> = HashMap::new();
+    for ((iface, _), usage) in &peers {
+        let (summary, days) = interfaces.entry(*iface).or_insert_with(|| {
+            let summary = InterfaceSummary {
+                iface: *iface,
+                rx_bytes: 0,
+                tx_bytes: 0,
+                active_peers: 0,
+                active_days: 0,
+            };
+            (summary, BTreeSet::new())
+        });
+        summary.rx_bytes += usage.rx_bytes;
+        summary.tx_bytes += usage.tx_bytes;
+        summary.active_peers += 1;
+        days.extend(&usage.days);
+        summary.active_days = days.len();
+    }
+
+    let mut peers: Vec<_> = peers
+        .into_iter()
+        .map(|((iface, public_key), usage)| PeerSummary {
+            iface,
+            public_key,
+            rx_bytes: usage.rx_bytes,
+            tx_bytes: usage.tx_bytes,
+            sessions: usage.sessions,
+            active_days: usage.days.len(),
+        })
+        .collect();
+    peers.sort_by_cached_key(|peer| {
+        (
+            Reverse(peer.total_bytes()),
+            peer.iface.to_string(),
+            peer.public_key.to_base64(),
+        )
+    });
+    let mut interfaces: Vec<_> = interfaces
+        .into_values()
+        .map(|(summary, _)| summary)
+        .collect();
+    interfaces.sort_by_cached_key(|summary| summary.iface.to_string());
+
+    Report {
+        period,
+        interfaces,
+        peers,
+    }
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Report {
+    /// The `n` busiest peers.
+    pub fn top_peers(&self, n: usize) -> &[PeerSummary] {
+        &self.peers[..n.min(self.peers.len())]
+    }
+
+    /// The report as a single-line JSON object. Times are in seconds since the Unix
+    /// epoch.
+    pub fn to_json(&self) -> String {
+        let interfaces: Vec<_> = self
+            .interfaces
+            .iter()
+            .map(|summary| {
+                format!(
+                    "{{\"interface\":{},\"rx_bytes\":{},\"tx_bytes\":{},\"active_peers\":{},\"active_days\":{}}}",
+                    quote(&summary.iface.as_str_lossy()),
+                    summary.rx_bytes,
+                    summary.tx_bytes,
+                    summary.active_peers,
+                    summary.active_days
+                )
+            })
+            .collect();
+        let peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|peer| {
+                format!(
+                    "{{\"interface\":{},\"public_key\":{},\"rx_bytes\":{},\"tx_bytes\":{},\"sessions\":{},\"active_days\":{}}}",
+                    quote(&peer.iface.as_str_lossy()),
+                    quote(&peer.public_key.to_base64()),
+                    peer.rx_bytes,
+                    peer.tx_bytes,
+                    peer.sessions,
+                    peer.active_days
+                )
+            })
+            .collect();
+        format!(
+            "{{\"start\":{},\"end\":{},\"interfaces\":[{}],\"peers\":[{}]}}",
+            secs(self.period.start),
+            secs(self.period.end),
+            interfaces.join(","),
+            peers.join(",")
+        )
+    }
+
+    /// The report as a Markdown document listing the interfaces and the busiest
+    /// peers.
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# WireGuard usage from {} to {}\n",
+            secs(self.period.start),
+            secs(self.period.end)
+        );
+        let _ = writeln!(out, "## Interfaces\n");
+        let _ = writeln!(
+            out,
+            "| Interface | Received (bytes) | Sent (bytes) | Active peers | Active days |"
+        );
+        let _ = writeln!(out, "|---|---:|---:|---:|---:|");
+        for summary in &self.interfaces {
+            let _ = writeln!(
+                out,
+                "| {} | {} | {} | {} | {} |",
+                summary.iface,
+                summary.rx_bytes,
+                summary.tx_bytes,
+                summary.active_peers,
+                summary.active_days
+            );
+        }
+        let _ = writeln!(out, "\n## Top peers\n");
+        let _ = writeln!(
+            out,
+            "| Interface | Peer | Received (bytes) | Sent (bytes) | Sessions | Active days |"
+        );
+        let _ = writeln!(out, "|---|---|---:|---:|---:|---:|");
+        for peer in self.top_peers(TOP_PEERS) {
+            let _ = writeln!(
+                out,
+                "| {} | `{}` | {} | {} | {} | {} |",
+                peer.iface,
+                peer.public_key.to_base64(),
+                peer.rx_bytes,
+                peer.tx_bytes,
+                peer.sessions,
+                peer.active_days
+            );
+        }
+        out
+    }
+
+    /// Writes the report to `writer` in `format`.
+    pub fn write(&self, format: Format, mut writer: impl Write) -> io::Result<()> {
+        match format {
+            Format::Json => writeln!(writer, "{}", self.to_json())?,
+            Format::Markdown => write!(writer, "{}", self.to_markdown())?,
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn record(iface: &str, key: u8, start: u64, end: u64, rx_bytes: u64) -> SessionRecord {
+        SessionRecord {
+            iface: iface.parse().unwrap(),
+            public_key: Key([key; 32]),
+            endpoint: None,
+            start: at(start),
+            end: at(end),
+            rx_bytes,
+            tx_bytes: rx_bytes / 10,
+        }
+    }
+
+    #[test]
+    fn test_generate() {
+        let day = DAY.as_secs();
+        let history = [
+            // Before the period.
+            record("wg0", 1, 0, 100, 1000),
+            record("wg0", 1, 2 * day, 2 * day + 100, 1000),
+            record("wg0", 1, 4 * day, 4 * day + 100, 3000),
+            // Half of it falls within the period.
+            record("wg0", 2, 7 * day, 9 * day, 1000),
+            record("wg1", 3, 3 * day, 3 * day + 10, 500),
+            // At the end of the period, so after it.
+            record("wg1", 3, 8 * day, 8 * day, 500),
+        ];
+        let report = generate(&history, Period::weekly(at(8 * day)));
+        assert_eq!(report.period.start, at(day));
+
+        assert_eq!(
+            report.peers,
+            vec![
+                PeerSummary {
+                    iface: "wg0".parse().unwrap(),
+                    public_key: Key([1; 32]),
+                    rx_bytes: 4000,
+                    tx_bytes: 400,
+                    sessions: 2,
+                    active_days: 2,
+                },
+                PeerSummary {
+                    iface: "wg0".parse().unwrap(),
+                    public_key: Key([2; 32]),
+                    rx_bytes: 500,
+                    tx_bytes: 50,
+                    sessions: 1,
+                    active_days: 1,
+                },
+                PeerSummary {
+                    iface: "wg1".parse().unwrap(),
+                    public_key: Key([3; 32]),
+                    rx_bytes: 500,
+                    tx_bytes: 50,
+                    sessions: 1,
+                    active_days: 1,
+                },
+            ]
+        );
+        assert_eq!(report.top_peers(1).len(), 1);
+        assert_eq!(report.top_peers(10).len(), 3);
+
+        assert_eq!(report.interfaces.len(), 2);
+        assert_eq!(report.interfaces[0].total_bytes(), 4950);
+        assert_eq!(report.interfaces[0].active_peers, 2);
+        assert_eq!(report.interfaces[0].active_days, 3);
+        assert_eq!(report.interfaces[1].active_days, 1);
+    }
+
+    #[test]
+    fn test_formats() {
+        let day = DAY.as_secs();
+        let report = generate(
+            &[record("wg0", 1, day + 10, day + 20, 100)],
+            Period::daily(at(day + 100)),
+        );
+        let key = Key([1; 32]).to_base64();
+        assert_eq!(
+            report.to_json(),
+            format!(
+                "{{\"start\":100,\"end\":86500,\"interfaces\":[{{\"interface\":\"wg0\",\"rx_bytes\":100,\"tx_bytes\":10,\"active_peers\":1,\"active_days\":1}}],\"peers\":[{{\"interface\":\"wg0\",\"public_key\":\"{}\",\"rx_bytes\":100,\"tx_bytes\":10,\"sessions\":1,\"active_days\":1}}]}}",
+                key
+            )
+        );
+
+        let mut markdown = vec![];
+        report.write(Format::Markdown, &mut markdown).unwrap();
+        let markdown = String::from_utf8(markdown).unwrap();
+        assert!(markdown.contains("| wg0 | 100 | 10 | 1 | 1 |"));
+        assert!(markdown.contains(&format!("| wg0 | `{}` | 100 | 10 | 1 | 1 |", key)));
+    }
+}
@@ -0,0 +1,336 @@
+//! Restricting where peers may roam to.
+//!
+//! WireGuard accepts a peer's packets from wherever they come and makes the
+//! source its new endpoint. Compliance-sensitive deployments that only expect
+//! peers behind known egress ranges use an [`EndpointPolicy`] listing them; a
+//! [`RoamingGuard`] watches an interface and reports every peer seen at an
+//! endpoint outside the policy as a [`Violation`], or disables it.
+//!
+//! The kernel has no way to disable a peer, so disabling removes it, which makes
+//! the interface drop its packets. The guard keeps the removed peer's config and
+//! [`restore`](RoamingGuard::restore) adds it back without the offending
+//! endpoint.
+use crate::{
+    watcher::{diff, PeerEvent},
+    Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfig, PeerConfigBuilder,
+};
+
+use ipnet::IpNet;
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{IpAddr, SocketAddr},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// What to do about a peer at an endpoint outside the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Action {
+    /// Only report it.
+    #[default]
+    Report,
+    /// Report it and remove it from the interface.
+    Disable,
+}
+
+/// The networks peer endpoints must be in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointPolicy {
+    pub allowed: Vec<IpNet>,
+    pub action: Action,
+}
+
+impl EndpointPolicy {
+    /// Allows endpoints in `allowed`, reporting the others.
+    pub fn new(allowed: Vec<IpNet>) -> Self {
+        Self {
+            allowed,
+            action: Action::default(),
+        }
+    }
+
+    pub fn set_action(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Whether `endpoint` is in an allowed network. IPv4 endpoints reached over a
+    /// dual-stack socket are checked as IPv4.
+    pub fn allows(&self, endpoint: &SocketAddr) -> bool {
+        let ip = match endpoint.ip() {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+            ip => ip,
+        };
+        self.allowed.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The peers of `device` whose current endpoint is outside the policy.
+    pub fn check(&self, device: &Device) -> Vec<Violation> {
+        device
+            .peers
+            .iter()
+            .filter_map(|peer| {
+                let endpoint = peer.config.endpoint?;
+                (!self.allows(&endpoint)).then(|| Violation {
+                    iface: device.name,
+                    public_key: peer.config.public_key.clone(),
+                    endpoint,
+                    disabled: false,
+                })
+            })
+            .collect()
+    }
+
+    /// The peers that appeared at, or roamed to, an endpoint outside the policy
+    /// according to `events`.
+    pub fn check_events(&self, events: &[PeerEvent]) -> Vec<Violation> {
+        events
+            .iter()
+            .filter_map(|event| {
+                let (iface, public_key, endpoint) = match event {
+                    PeerEvent::PeerAdded { iface, peer } => {
+                        (iface, &peer.config.public_key, peer.config.endpoint?)
+                    }
+                    PeerEvent::EndpointChanged {
+                        iface,
+                        public_key,
+                        new,
+                        ..
+                    } => (iface, public_key, (*new)?),
+                    _ => return None,
+                };
+                (!self.allows(&endpoint)).then(|| Violation {
+                    iface: *iface,
+                    public_key: public_key.clone(),
+                    endpoint,
+                    disabled: false,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A peer seen at an endpoint outside the policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub iface: InterfaceName,
+    pub public_key: Key,
+    pub endpoint: SocketAddr,
+    /// Whether the peer was removed from the interface.
+    pub disabled: bool,
+}
+
+/// Polls an interface and enforces an [`EndpointPolicy`] on its peers.
+///
+/// Peers are checked when first seen and whenever they roam, so a peer staying
+/// at a disallowed endpoint is reported once.
+#[derive(Debug)]
+pub struct RoamingGuard {
+    iface: InterfaceName,
+    backend: Backend,
+    policy: EndpointPolicy,
+    last: Option<Device>,
+    interval: Duration,
+    next_poll: Option<Instant>,
+    pending: VecDeque<Violation>,
+    disabled: HashMap<Key, PeerConfig>,
+}
+
+impl RoamingGuard {
+    /// Guards `iface`, reading it every 5 seconds.
+    pub fn new(iface: &InterfaceName, backend: Backend, policy: EndpointPolicy) -> Self {
+        Self {
+            iface: *iface,
+            backend,
+            policy,
+            last: None,
+            interval: Duration::from_secs(5),
+            next_poll: None,
+            pending: VecDeque::new(),
+            disabled: HashMap::new(),
+        }
+    }
+
+    pub fn set_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Checks `device` against the policy, disabling the violating peers if the
+    /// policy says so, and returns the violations since the previous snapshot.
+    ///
+    /// The snapshot is only replaced once the violating peers are disabled, so if
+    /// that fails they are reported again on the next call.
+    pub fn observe(&mut self, device: Device) -> io::Result<Vec<Violation>> {
+        let mut violations = self.policy.check_events(&diff(self.last.as_ref(), &device));
+        if self.policy.action != Action::Disable || violations.is_empty() {
+            self.last = Some(device);
+            return Ok(violations);
+        }
+
+        let mut update = DeviceUpdate::new();
+        for violation in &violations {
+            update = update.remove_peer_by_key(&violation.public_key);
+        }
+        update.apply(&self.iface, self.backend)?;
+        for violation in &mut violations {
+            violation.disabled = true;
+            if let Some(peer) = device
+                .peers
+                .iter()
+                .find(|peer| peer.config.public_key == violation.public_key)
+            {
+                self.disabled
+                    .insert(violation.public_key.clone(), peer.config.clone());
+            }
+        }
+        self.last = Some(device);
+        Ok(violations)
+    }
+
+    /// Reads the interface now and enforces the policy on what changed.
+    pub fn poll(&mut self) -> io::Result<Vec<Violation>> {
+        let device = Device::get(&self.iface, self.backend)?;
+        self.observe(device)
+    }
+
+    /// Blocks until the next violation, reading the interface every interval.
+    pub fn next_violation(&mut self) -> io::Result<Violation> {
+        loop {
+            if let Some(violation) = self.pending.pop_front() {
+                return Ok(violation);
+            }
+            if let Some(next_poll) = self.next_poll {
+                thread::sleep(next_poll.saturating_duration_since(Instant::now()));
+            }
+            self.next_poll = Some(Instant::now() + self.interval);
+            let violations = self.poll()?;
+            self.pending.extend(violations);
+        }
+    }
+
+    /// The peers the guard removed, and can restore.
+    pub fn disabled(&self) -> impl Iterator<Item = &Key> {
+        self.disabled.keys()
+    }
+
+    /// Adds a peer the guard removed back to the interface, without an endpoint
+    /// so it has to reach the interface again. Returns whether the guard had
+    /// removed it.
+    pub fn restore(&mut self, public_key: &Key) -> io::Result<bool> {
+        let Some(config) = self.disabled.remove(public_key) else {
+            return Ok(false);
+        };
+        let mut peer = PeerConfigBuilder::from_peer_config(config.clone());
+        peer.endpoint = None;
+        if let Err(e) = DeviceUpdate::new()
+            .add_peer(peer)
+            .apply(&self.iface, self.backend)
+        {
+            self.disabled.insert(public_key.clone(), config);
+            return Err(e.into());
+        }
+        Ok(true)
+    }
+}
+
+impl Iterator for RoamingGuard {
+    type Item = io::Result<Violation>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_violation())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerInfo;
+
+    fn device(peers: &[(u8, &str)]) -> Device {
+        Device::fixture(
+            "wg0",
+            peers
+                .iter()
+                .map(|(key, endpoint)| PeerInfo::fixture(Key([*key; 32])).with_endpoint(endpoint))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_allows() {
+        let policy = EndpointPolicy::new(vec![
+            "198.51.100.0/24".parse().unwrap(),
+            "2001:db8::/32".parse().unwrap(),
+        ]);
+        assert!(policy.allows(&"198.51.100.7:51820".parse().unwrap()));
+        assert!(policy.allows(&"[::ffff:198.51.100.7]:51820".parse().unwrap()));
+        assert!(policy.allows(&"[2001:db8::1]:51820".parse().unwrap()));
+        assert!(!policy.allows(&"203.0.113.1:51820".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_report_roaming() {
+        let policy = EndpointPolicy::new(vec!["198.51.100.0/24".parse().unwrap()]);
+        let mut guard = RoamingGuard::new(&"wg0".parse().unwrap(), Backend::Userspace, policy);
+
+        let violations = guard
+            .observe(device(&[(1, "198.51.100.1:1"), (2, "203.0.113.2:1")]))
+            .unwrap();
+        assert_eq!(
+            violations,
+            vec![Violation {
+                iface: "wg0".parse().unwrap(),
+                public_key: Key([2; 32]),
+                endpoint: "203.0.113.2:1".parse().unwrap(),
+                disabled: false,
+            }]
+        );
+        // Staying put isn't reported again, roaming out is.
+        let violations = guard
+            .observe(device(&[(1, "203.0.113.1:1"), (2, "203.0.113.2:1")]))
+            .unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].public_key, Key([1; 32]));
+        assert_eq!(guard.disabled().count(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn test_disable() {
+        let iface: InterfaceName = "mock-roam".parse().unwrap();
+        DeviceUpdate::new()
+            .add_peer(
+                PeerConfigBuilder::new(&Key([1; 32]))
+                    .set_endpoint("198.51.100.1:1".parse().unwrap()),
+            )
+            .add_peer(
+                PeerConfigBuilder::new(&Key([2; 32]))
+                    .set_endpoint("203.0.113.2:1".parse().unwrap()),
+            )
+            .apply(&iface, Backend::Mock)
+            .unwrap();
+        let policy = EndpointPolicy::new(vec!["198.51.100.0/24".parse().unwrap()])
+            .set_action(Action::Disable);
+        let mut guard = RoamingGuard::new(&iface, Backend::Mock, policy);
+
+        let violations = guard.poll().unwrap();
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].disabled);
+        let device = Device::get(&iface, Backend::Mock).unwrap();
+        assert_eq!(device.peers.len(), 1);
+        assert_eq!(guard.disabled().collect::<Vec<_>>(), vec![&Key([2; 32])]);
+
+        assert!(guard.restore(&Key([2; 32])).unwrap());
+        assert!(!guard.restore(&Key([2; 32])).unwrap());
+        let device = Device::get(&iface, Backend::Mock).unwrap();
+        let restored = device
+            .peers
+            .iter()
+            .find(|peer| peer.config.public_key == Key([2; 32]))
+            .unwrap();
+        assert_eq!(restored.config.endpoint, None);
+        device.delete().unwrap();
+    }
+}
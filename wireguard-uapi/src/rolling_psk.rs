@@ -0,0 +1,258 @@
+//! Preshared keys that roll over on a schedule, derived from a shared seed.
+//!
+//! Both ends of a high-security pair configure the same seed; a [`RollingPsk`]
+//! derives the preshared key of each time window from it, TOTP-style, so the PSK
+//! changes every window without any key exchange. [`tick`](RollingPsk::tick)
+//! applies the key due and says when to call it again.
+//!
+//! WireGuard has a single PSK per peer, so the ends must switch at the same
+//! moment. To ride out clocks that disagree by less than the overlap, a peer that
+//! hasn't handshaken since the last switch is alternately given the previous and
+//! the current key during the overlap, one probe interval each, until a handshake
+//! succeeds. Sessions established before a switch keep working until their next
+//! rekey.
+use crate::{
+    clock::{Clock, SystemClock},
+    Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder,
+};
+
+use sha2::{Digest, Sha256};
+use std::{
+    fmt, io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Prefixed to the window number, so the keys are bound to this use of the seed.
+const CONTEXT: &[u8] = b"wireguard-uapi rolling psk v1\n";
+
+/// Long enough for a handshake to be retried with the same key: WireGuard
+/// retries every 5 seconds.
+const DEFAULT_PROBE: Duration = Duration::from_secs(10);
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<_>>();
+    let inner = Sha256::new().chain(pad(0x36)).chain(message).finalize();
+    Sha256::new()
+        .chain(pad(0x5c))
+        .chain(inner)
+        .finalize()
+        .into()
+}
+
+/// The schedule of preshared keys derived from a seed.
+#[derive(Clone)]
+pub struct RollingPsk {
+    seed: Vec<u8>,
+    window: Duration,
+    overlap: Duration,
+    probe: Duration,
+}
+
+impl fmt::Debug for RollingPsk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Anyone knowing the seed knows every key.
+        f.debug_struct("RollingPsk")
+            .field("window", &self.window)
+            .field("overlap", &self.overlap)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RollingPsk {
+    /// Derives the keys from `seed`, which both ends share and keep secret. It
+    /// should be at least 32 random bytes, such as a generated [`Key`].
+    ///
+    /// By default the key changes every hour, with a 2-minute overlap.
+    pub fn new(seed: &[u8]) -> Self {
+        Self {
+            seed: seed.to_vec(),
+            window: Duration::from_secs(60 * 60),
+            overlap: Duration::from_secs(120),
+            probe: DEFAULT_PROBE,
+        }
+    }
+
+    /// How long each key is used, at least a second. Both ends must use the same
+    /// window.
+    pub fn set_window(mut self, window: Duration) -> Self {
+        self.window = window.max(Duration::from_secs(1));
+        self
+    }
+
+    /// How long after a switch the previous key is still tried, which bounds the
+    /// clock skew tolerated between the ends.
+    pub fn set_overlap(mut self, overlap: Duration) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    /// The number of the window `now` is in.
+    pub fn window(&self, now: SystemTime) -> u64 {
+        let elapsed = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        elapsed.as_secs() / self.window.as_secs()
+    }
+
+    fn window_start(&self, window: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(window * self.window.as_secs())
+    }
+
+    /// The key of window number `window`.
+    pub fn psk(&self, window: u64) -> Key {
+        Key(hmac_sha256(
+            &self.seed,
+            &[CONTEXT, &window.to_be_bytes()].concat(),
+        ))
+    }
+
+    /// The key of the window `now` is in.
+    pub fn current(&self, now: SystemTime) -> Key {
+        self.psk(self.window(now))
+    }
+
+    /// When the key changes after `now`.
+    pub fn next_change(&self, now: SystemTime) -> SystemTime {
+        self.window_start(self.window(now) + 1)
+    }
+
+    /// Whether `now` is in the overlap after a switch, and in a probe interval
+    /// of the previous key.
+    fn probing_previous(&self, now: SystemTime) -> bool {
+        let window = self.window(now);
+        let since = now
+            .duration_since(self.window_start(window))
+            .unwrap_or_default();
+        window > 0 && since < self.overlap && (since.as_secs() / self.probe.as_secs()) % 2 == 1
+    }
+
+    /// The key a peer whose last handshake was at `last_handshake` should have
+    /// at `now`.
+    pub fn expected(&self, last_handshake: Option<SystemTime>, now: SystemTime) -> Key {
+        let window = self.window(now);
+        let switched = self.window_start(window);
+        let handshaken = last_handshake.is_some_and(|time| time >= switched);
+        if !handshaken && self.probing_previous(now) {
+            self.psk(window - 1)
+        } else {
+            self.psk(window)
+        }
+    }
+
+    /// The update giving each of the `peers` of `device` the key it should have
+    /// at `now`. Peers that already have it, or that the device doesn't have,
+    /// are left out.
+    pub fn update(&self, device: &Device, peers: &[Key], now: SystemTime) -> DeviceUpdate {
+        let mut update = DeviceUpdate::new();
+        for peer in device
+            .peers
+            .iter()
+            .filter(|peer| peers.contains(&peer.config.public_key))
+        {
+            let expected = self.expected(peer.stats.last_handshake_time, now);
+            if peer.config.preshared_key.as_ref() != Some(&expected) {
+                update = update.add_peer(
+                    PeerConfigBuilder::new(&peer.config.public_key).set_preshared_key(expected),
+                );
+            }
+        }
+        update
+    }
+
+    /// Applies the keys due to the `peers` of `iface`, returning when to tick
+    /// again: at the next switch, or sooner while probing.
+    pub fn tick(
+        &self,
+        iface: &InterfaceName,
+        backend: Backend,
+        peers: &[Key],
+    ) -> io::Result<SystemTime> {
+        self.tick_with_clock(iface, backend, peers, &SystemClock)
+    }
+
+    /// Like [`tick`](Self::tick), with the keys due at `clock`'s now.
+    pub fn tick_with_clock(
+        &self,
+        iface: &InterfaceName,
+        backend: Backend,
+        peers: &[Key],
+        clock: &dyn Clock,
+    ) -> io::Result<SystemTime> {
+        let now = clock.now();
+        let device = Device::get(iface, backend)?;
+        let update = self.update(&device, peers, now);
+        if !update.peers.is_empty() {
+            update.apply(iface, backend)?;
+        }
+        let switched = self.window_start(self.window(now));
+        let probe_end = switched + self.overlap;
+        if now < probe_end {
+            let since = now.duration_since(switched).unwrap_or_default();
+            let next_probe =
+                switched + self.probe * (since.as_secs() / self.probe.as_secs() + 1) as u32;
+            return Ok(next_probe.min(probe_end));
+        }
+        Ok(self.next_change(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerInfo;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_windows() {
+        let psk = RollingPsk::new(&[7; 32]).set_window(Duration::from_secs(3600));
+        assert_eq!(psk.current(at(7200)), psk.current(at(10799)));
+        assert_ne!(psk.current(at(7199)), psk.current(at(7200)));
+        assert_eq!(psk.next_change(at(7300)), at(10800));
+        // Both ends derive the same keys, and other seeds derive others.
+        assert_eq!(psk.psk(2), RollingPsk::new(&[7; 32]).psk(2));
+        assert_ne!(psk.psk(2), RollingPsk::new(&[8; 32]).psk(2));
+    }
+
+    #[test]
+    fn test_overlap() {
+        let psk = RollingPsk::new(&[7; 32]);
+        let (previous, current) = (psk.psk(1), psk.psk(2));
+        assert_eq!(psk.expected(None, at(7205)), current);
+        assert_eq!(psk.expected(None, at(7215)), previous);
+        assert_eq!(psk.expected(None, at(7225)), current);
+        // A handshake since the switch settles on the current key.
+        assert_eq!(psk.expected(Some(at(7201)), at(7215)), current);
+        // So does the end of the overlap.
+        assert_eq!(psk.expected(None, at(7335)), current);
+
+        let peer = |key: u8, preshared_key: Option<Key>| {
+            let mut peer = PeerInfo::fixture(Key([key; 32]));
+            peer.config.preshared_key = preshared_key;
+            peer
+        };
+        let device = Device::fixture(
+            "wg0",
+            vec![peer(1, Some(current.clone())), peer(2, None), peer(3, None)],
+        );
+        let update = psk.update(&device, &[Key([1; 32]), Key([2; 32])], at(7205));
+        assert_eq!(update.peers.len(), 1);
+        assert_eq!(update.peers[0].public_key, Key([2; 32]));
+        assert_eq!(update.peers[0].preshared_key, Some(current));
+    }
+}
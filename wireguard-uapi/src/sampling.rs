@@ -0,0 +1,495 @@
+//! Sampled top-talker statistics of the traffic inside a tunnel, behind the
+//! `sampling` feature on Linux.
+//!
+//! Byte counters tell how much a peer sends, not where to. Instead of capturing
+//! packets, [`enable_nflog`] installs nftables rules that log one in every N
+//! packets crossing the interface to an NFLOG group, truncated to their headers.
+//! The headers read from an [`Nflog`] socket are fed into a [`FlowSampler`], which
+//! attributes them to peers by their allowed IPs and aggregates them into flows.
+//!
+//! Flows are keyed by addresses masked to a [`Privacy`] prefix (a /24 or /48 by
+//! default) rather than individual hosts, and ports can be dropped altogether, so
+//! the statistics show where traffic goes without tracking single users.
+use crate::{nft, Device, InterfaceName, Key};
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+/// How many bytes of each sampled packet are copied: enough for the IP and
+/// transport headers, but not the payload.
+pub const SNAPLEN: u32 = 128;
+
+/// How much of the sampled flows is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Privacy {
+    /// The prefix IPv4 addresses are masked to.
+    pub ipv4_prefix: u8,
+    /// The prefix IPv6 addresses are masked to.
+    pub ipv6_prefix: u8,
+    /// Whether flows keep their destination port.
+    pub ports: bool,
+}
+
+impl Default for Privacy {
+    fn default() -> Self {
+        Self {
+            ipv4_prefix: 24,
+            ipv6_prefix: 48,
+            ports: true,
+        }
+    }
+}
+
+impl Privacy {
+    fn mask(&self, address: IpAddr) -> IpAddr {
+        match address {
+            IpAddr::V4(address) => {
+                let bits = u32::from(address)
+                    & u32::MAX
+                        .checked_shl(32 - u32::from(self.ipv4_prefix.min(32)))
+                        .unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(bits))
+            }
+            IpAddr::V6(address) => {
+                let bits = u128::from(address)
+                    & u128::MAX
+                        .checked_shl(128 - u32::from(self.ipv6_prefix.min(128)))
+                        .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(bits))
+            }
+        }
+    }
+}
+
+/// Which way a sampled packet crossed the tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Sent by the peer.
+    FromPeer,
+    /// Sent to the peer.
+    ToPeer,
+}
+
+/// An aggregated flow of one peer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Flow {
+    pub peer: Key,
+    pub direction: Direction,
+    /// The masked source address.
+    pub source: IpAddr,
+    /// The masked destination address.
+    pub destination: IpAddr,
+    /// The IP protocol number, e.g. 6 for TCP.
+    pub protocol: u8,
+    /// The destination port of TCP and UDP flows, unless [`Privacy::ports`] is off.
+    pub port: Option<u16>,
+}
+
+/// The estimated traffic of a [`Flow`], extrapolated from the samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FlowStats {
+    pub samples: u64,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// The fields of an IP header a flow is built from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: u8,
+    port: Option<u16>,
+    /// The length of the whole packet, which may be longer than the sample.
+    length: u64,
+}
+
+fn destination_port(protocol: u8, transport: &[u8]) -> Option<u16> {
+    match (protocol, transport) {
+        (6 | 17, [_, _, high, low, ..]) => Some(u16::from_be_bytes([*high, *low])),
+        _ => None,
+    }
+}
+
+/// Parses the header of an IPv4 or IPv6 packet, possibly truncated after it.
+fn parse_header(packet: &[u8]) -> Option<Header> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let octets = |at: usize| -> [u8; 4] { packet[at..at + 4].try_into().unwrap() };
+            Some(Header {
+                source: IpAddr::from(octets(12)),
+                destination: IpAddr::from(octets(16)),
+                protocol: packet[9],
+                port: destination_port(packet[9], packet.get(header_len..)?),
+                length: u64::from(u16::from_be_bytes([packet[2], packet[3]])),
+            })
+        }
+        6 if packet.len() >= 40 => {
+            let octets = |at: usize| -> [u8; 16] { packet[at..at + 16].try_into().unwrap() };
+            Some(Header {
+                source: IpAddr::from(octets(8)),
+                destination: IpAddr::from(octets(24)),
+                protocol: packet[6],
+                port: destination_port(packet[6], &packet[40..]),
+                length: 40 + u64::from(u16::from_be_bytes([packet[4], packet[5]])),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Aggregates sampled packets of an interface into per-peer flows.
+#[derive(Debug, Clone)]
+pub struct FlowSampler {
+    one_in: u64,
+    privacy: Privacy,
+    peers: Vec<(crate::AllowedIp, Key)>,
+    flows: HashMap<Flow, FlowStats>,
+    unattributed: u64,
+}
+
+impl FlowSampler {
+    /// Creates a sampler for packets sampled one in `one_in` on `device`.
+    pub fn new(device: &Device, one_in: u32, privacy: Privacy) -> Self {
+        let mut sampler = Self {
+            one_in: u64::from(one_in.max(1)),
+            privacy,
+            peers: vec![],
+            flows: HashMap::new(),
+            unattributed: 0,
+        };
+        sampler.update_peers(device);
+        sampler
+    }
+
+    /// Picks up the peers and allowed IPs of a newer snapshot of the device.
+    pub fn update_peers(&mut self, device: &Device) {
+        self.peers = device
+            .peers
+            .iter()
+            .flat_map(|peer| {
+                peer.config
+                    .allowed_ips
+                    .iter()
+                    .map(|ip| (ip.clone(), peer.config.public_key.clone()))
+            })
+            .collect();
+    }
+
+    /// The peer whose allowed IPs contain `address` most specifically.
+    fn peer_of(&self, address: &IpAddr) -> Option<&Key> {
+        self.peers
+            .iter()
+            .filter(|(ip, _)| ip.contains(address))
+            .max_by_key(|(ip, _)| ip.cidr)
+            .map(|(_, key)| key)
+    }
+
+    /// Records one sampled packet, starting at its IP header. Returns whether it
+    /// could be parsed and attributed to a peer.
+    pub fn record(&mut self, packet: &[u8]) -> bool {
+        let header = match parse_header(packet) {
+            Some(header) => header,
+            None => return false,
+        };
+        let (peer, direction) = match (
+            self.peer_of(&header.source),
+            self.peer_of(&header.destination),
+        ) {
+            (Some(peer), _) => (peer.clone(), Direction::FromPeer),
+            (None, Some(peer)) => (peer.clone(), Direction::ToPeer),
+            (None, None) => {
+                self.unattributed += 1;
+                return false;
+            }
+        };
+        let flow = Flow {
+            peer,
+            direction,
+            source: self.privacy.mask(header.source),
+            destination: self.privacy.mask(header.destination),
+            protocol: header.protocol,
+            port: header.port.filter(|_| self.privacy.ports),
+        };
+        let stats = self.flows.entry(flow).or_default();
+        stats.samples += 1;
+        stats.packets += self.one_in;
+        stats.bytes += header.length * self.one_in;
+        true
+    }
+
+    /// The `n` flows with the most bytes, optionally of one peer only.
+    pub fn top_talkers(&self, peer: Option<&Key>, n: usize) -> Vec<(Flow, FlowStats)> {
+        let mut flows: Vec<_> = self
+            .flows
+            .iter()
+            .filter(|(flow, _)| peer.is_none_or(|peer| flow.peer == *peer))
+            .map(|(flow, stats)| (flow.clone(), *stats))
+            .collect();
+        flows.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.bytes));
+        flows.truncate(n);
+        flows
+    }
+
+    /// How many samples matched no peer, e.g. traffic of a removed peer.
+    pub fn unattributed(&self) -> u64 {
+        self.unattributed
+    }
+
+    /// Forgets every flow, e.g. at the start of a reporting interval.
+    pub fn clear(&mut self) {
+        self.flows.clear();
+        self.unattributed = 0;
+    }
+}
+
+fn table_name(iface: &InterfaceName) -> String {
+    nft::table_name("wg_sampling", iface)
+}
+
+/// The nftables ruleset logging one in `one_in` packets in and out of `iface` to
+/// NFLOG `group`. Fails for interface names nft strings can't hold.
+pub fn nft_ruleset(iface: &InterfaceName, group: u16, one_in: u32) -> io::Result<String> {
+    Ok(format!(
+        "table inet {table} {{\n\
+         \tchain prerouting {{\n\
+         \t\ttype filter hook prerouting priority -150; policy accept;\n\
+         \t\tiifname {iface} numgen random mod {one_in} 0 log group {group} snaplen {snaplen}\n\
+         \t}}\n\
+         \tchain postrouting {{\n\
+         \t\ttype filter hook postrouting priority 150; policy accept;\n\
+         \t\toifname {iface} numgen random mod {one_in} 0 log group {group} snaplen {snaplen}\n\
+         \t}}\n\
+         }}\n",
+        table = table_name(iface),
+        iface = nft::quoted_iface(iface)?,
+        one_in = one_in.max(1),
+        group = group,
+        snaplen = SNAPLEN,
+    ))
+}
+
+/// Installs the [sampling rules](nft_ruleset) for `iface`. Requires root.
+pub fn enable_nflog(iface: &InterfaceName, group: u16, one_in: u32) -> io::Result<()> {
+    nft::run(&["-f", "-"], Some(&nft_ruleset(iface, group, one_in)?))
+}
+
+/// Removes the sampling rules of `iface`.
+pub fn disable_nflog(iface: &InterfaceName) -> io::Result<()> {
+    nft::run(&["delete", "table", "inet", &table_name(iface)], None)
+}
+
+const NFNL_SUBSYS_ULOG: u16 = 4;
+const NFULNL_MSG_PACKET: u16 = 0;
+const NFULNL_MSG_CONFIG: u16 = 1;
+const NFULA_PAYLOAD: u16 = 9;
+const NFULA_CFG_CMD: u16 = 1;
+const NFULA_CFG_MODE: u16 = 2;
+const NFULNL_CFG_CMD_BIND: u8 = 1;
+const NFULNL_COPY_PACKET: u8 = 2;
+const NLMSG_HDRLEN: usize = 16;
+const NFGENMSG_LEN: usize = 4;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A netfilter config message for NFLOG `group` with one attribute.
+fn config_message(group: u16, sequence: u32, attribute: u16, payload: &[u8]) -> Vec<u8> {
+    let attribute_len = 4 + payload.len();
+    let len = NLMSG_HDRLEN + NFGENMSG_LEN + align(attribute_len);
+    let flags = (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16;
+
+    let mut message = Vec::with_capacity(len);
+    message.extend_from_slice(&(len as u32).to_ne_bytes());
+    message.extend_from_slice(&(NFNL_SUBSYS_ULOG << 8 | NFULNL_MSG_CONFIG).to_ne_bytes());
+    message.extend_from_slice(&flags.to_ne_bytes());
+    message.extend_from_slice(&sequence.to_ne_bytes());
+    message.extend_from_slice(&0u32.to_ne_bytes());
+    // nfgenmsg: AF_UNSPEC, NFNETLINK_V0 and the group as the resource id.
+    message.extend_from_slice(&[0, 0]);
+    message.extend_from_slice(&group.to_be_bytes());
+    message.extend_from_slice(&(attribute_len as u16).to_ne_bytes());
+    message.extend_from_slice(&attribute.to_ne_bytes());
+    message.extend_from_slice(payload);
+    message.resize(len, 0);
+    message
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// The packet payloads of the NFLOG messages in `buffer`. Errors carry the errno
+/// of a netlink error message.
+fn parse_messages(buffer: &[u8]) -> Result<Vec<Vec<u8>>, i32> {
+    let mut packets = vec![];
+    let mut offset = 0;
+    while let (Some(len), Some(kind)) = (read_u32(buffer, offset), read_u16(buffer, offset + 4)) {
+        let len = len as usize;
+        let message = match buffer.get(offset..offset + len) {
+            Some(message) if len >= NLMSG_HDRLEN => message,
+            _ => break,
+        };
+        if kind == libc::NLMSG_ERROR as u16 {
+            let errno = read_u32(message, NLMSG_HDRLEN).map_or(0, |e| e as i32);
+            if errno != 0 {
+                return Err(-errno);
+            }
+        } else if kind == NFNL_SUBSYS_ULOG << 8 | NFULNL_MSG_PACKET {
+            let mut at = NLMSG_HDRLEN + NFGENMSG_LEN;
+            while let (Some(attribute_len), Some(attribute)) =
+                (read_u16(message, at), read_u16(message, at + 2))
+            {
+                let attribute_len = usize::from(attribute_len);
+                if attribute_len < 4 {
+                    break;
+                }
+                if attribute & 0x3fff == NFULA_PAYLOAD {
+                    if let Some(payload) = message.get(at + 4..at + attribute_len) {
+                        packets.push(payload.to_vec());
+                    }
+                }
+                at += align(attribute_len);
+            }
+        }
+        offset += align(len);
+    }
+    Ok(packets)
+}
+
+/// A socket receiving the packets logged to an NFLOG group.
+pub struct Nflog {
+    socket: netlink_sys::Socket,
+    buffer: Vec<u8>,
+}
+
+impl Nflog {
+    /// Binds to NFLOG `group`, copying up to [`SNAPLEN`] bytes of each packet.
+    /// Requires `CAP_NET_ADMIN`.
+    pub fn bind(group: u16) -> io::Result<Self> {
+        let mut socket = netlink_sys::Socket::new(netlink_sys::protocols::NETLINK_NETFILTER)?;
+        socket.bind_auto()?;
+        socket.connect(&netlink_sys::SocketAddr::new(0, 0))?;
+        let mut nflog = Self {
+            socket,
+            buffer: vec![0; 64 * 1024],
+        };
+
+        let mut mode = SNAPLEN.to_be_bytes().to_vec();
+        mode.extend_from_slice(&[NFULNL_COPY_PACKET, 0]);
+        for (sequence, (attribute, payload)) in [
+            (NFULA_CFG_CMD, vec![NFULNL_CFG_CMD_BIND]),
+            (NFULA_CFG_MODE, mode),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let message = config_message(group, sequence as u32, attribute, &payload);
+            nflog.socket.send(&message, 0)?;
+            nflog.receive()?;
+        }
+        Ok(nflog)
+    }
+
+    fn receive(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        let len = self.socket.recv(&mut &mut self.buffer[..], 0)?;
+        parse_messages(&self.buffer[..len]).map_err(io::Error::from_raw_os_error)
+    }
+
+    /// Blocks until packets are logged and returns them, starting at their IP
+    /// headers.
+    pub fn recv(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        loop {
+            let packets = self.receive()?;
+            if !packets.is_empty() {
+                return Ok(packets);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerInfo;
+
+    fn device() -> Device {
+        let peer = |key: u8, ip: &str| PeerInfo::fixture(Key([key; 32])).with_allowed_ips(&[ip]);
+        Device::fixture("wg0", vec![peer(1, "10.0.0.2/32"), peer(2, "10.1.0.0/16")])
+    }
+
+    /// A TCP/IPv4 header from `source` to `destination`:443 of a 1000 byte packet.
+    fn ipv4_tcp(source: [u8; 4], destination: [u8; 4]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0x03, 0xe8, 0, 0, 0, 0, 64, 6, 0, 0];
+        packet.extend_from_slice(&source);
+        packet.extend_from_slice(&destination);
+        packet.extend_from_slice(&[0xc0, 0x00, 0x01, 0xbb]);
+        packet
+    }
+
+    #[test]
+    fn test_flow_sampler() {
+        let mut sampler = FlowSampler::new(&device(), 100, Privacy::default());
+        assert!(sampler.record(&ipv4_tcp([10, 0, 0, 2], [93, 184, 216, 34])));
+        assert!(sampler.record(&ipv4_tcp([10, 0, 0, 2], [93, 184, 216, 99])));
+        assert!(sampler.record(&ipv4_tcp([192, 0, 2, 1], [10, 1, 2, 3])));
+        assert!(!sampler.record(&ipv4_tcp([192, 0, 2, 1], [192, 0, 2, 2])));
+        assert!(!sampler.record(&[0x45, 0]));
+        assert_eq!(sampler.unattributed(), 1);
+
+        let top = sampler.top_talkers(None, 10);
+        assert_eq!(top.len(), 2);
+        let (flow, stats) = &top[0];
+        assert_eq!(flow.peer, Key([1; 32]));
+        assert_eq!(flow.direction, Direction::FromPeer);
+        assert_eq!(flow.destination, "93.184.216.0".parse::<IpAddr>().unwrap());
+        assert_eq!(flow.port, Some(443));
+        assert_eq!(
+            *stats,
+            FlowStats {
+                samples: 2,
+                packets: 200,
+                bytes: 200_000
+            }
+        );
+
+        let top = sampler.top_talkers(Some(&Key([2; 32])), 10);
+        assert_eq!(top[0].0.direction, Direction::ToPeer);
+    }
+
+    #[test]
+    fn test_parse_nflog_messages() {
+        let payload = ipv4_tcp([10, 0, 0, 2], [192, 0, 2, 1]);
+        // A packet message carrying only the payload attribute.
+        let len = NLMSG_HDRLEN + NFGENMSG_LEN + 4 + payload.len();
+        let mut message = vec![];
+        message.extend_from_slice(&(len as u32).to_ne_bytes());
+        message.extend_from_slice(&(NFNL_SUBSYS_ULOG << 8).to_ne_bytes());
+        message.extend_from_slice(&[0; 10 + NFGENMSG_LEN]);
+        message.extend_from_slice(&((4 + payload.len()) as u16).to_ne_bytes());
+        message.extend_from_slice(&NFULA_PAYLOAD.to_ne_bytes());
+        message.extend_from_slice(&payload);
+        assert_eq!(parse_messages(&message), Ok(vec![payload]));
+
+        let config = config_message(5, 1, NFULA_CFG_CMD, &[NFULNL_CFG_CMD_BIND]);
+        assert_eq!(config.len(), 28);
+        assert_eq!(&config[18..20], &5u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_nft_ruleset() {
+        let rules = nft_ruleset(&"wg-0".parse().unwrap(), 5, 100).unwrap();
+        assert!(rules.starts_with("table inet wg_sampling_wg_0 {"));
+        assert!(rules.contains("iifname \"wg-0\" numgen random mod 100 0 log group 5 snaplen 128"));
+        assert!(nft_ruleset(&"wg\"0".parse().unwrap(), 5, 100).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+//! Per-peer session records for accounting, derived from periodic snapshots.
+//!
+//! WireGuard has no notion of a connection, but a peer handshakes at least every
+//! two minutes while it is passing traffic. A [`SessionTracker`] fed with
+//! [`Device`] snapshots opens a session when a peer handshakes after being idle
+//! and closes it once no handshake was seen for the idle timeout, yielding a
+//! [`SessionRecord`] with the bytes transferred in between. Records can be
+//! [exported](export) as JSON lines or as IPFIX-style tab-separated values.
+use crate::{
+    json::{or_null, quote},
+    Device, InterfaceName, Key,
+};
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// One session of a peer, from the handshake that started it to its last activity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionRecord {
+    pub iface: InterfaceName,
+    pub public_key: Key,
+    /// The last endpoint seen during the session.
+    pub endpoint: Option<SocketAddr>,
+    pub start: SystemTime,
+    /// The last handshake or traffic seen during the session.
+    pub end: SystemTime,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+struct ActiveSession {
+    endpoint: Option<SocketAddr>,
+    start: SystemTime,
+    last_activity: SystemTime,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    // Counters as of the previous snapshot.
+    last_rx: u64,
+    last_tx: u64,
+}
+
+impl ActiveSession {
+    fn finish(self, iface: InterfaceName, public_key: Key) -> SessionRecord {
+        SessionRecord {
+            iface,
+            public_key,
+            endpoint: self.endpoint,
+            start: self.start,
+            end: self.last_activity,
+            rx_bytes: self.rx_bytes,
+            tx_bytes: self.tx_bytes,
+        }
+    }
+}
+
+/// How much a counter grew between snapshots, treating a decrease as a reset (e.g.
+/// the peer was removed and re-added).
+fn counter_delta(previous: u64, current: u64) -> u64 {
+    current.checked_sub(previous).unwrap_or(current)
+}
+
+/// Turns snapshots of one interface into session records.
+#[derive(Debug, Clone)]
+pub struct SessionTracker {
+    iface: InterfaceName,
+    idle_timeout: Duration,
+    active: HashMap<Key, ActiveSession>,
+}
+
+impl SessionTracker {
+    /// Creates a tracker for `iface` that closes sessions after `idle_timeout`
+    /// without a handshake. Timeouts shorter than the two minute rekey interval
+    /// split active sessions.
+    pub fn new(iface: &InterfaceName, idle_timeout: Duration) -> Self {
+        Self {
+            iface: *iface,
+            idle_timeout,
+            active: HashMap::new(),
+        }
+    }
+
+    /// The number of sessions currently open.
+    pub fn active(&self) -> usize {
+        self.active.len()
+    }
+
+    /// Updates the sessions from a snapshot of the interface taken at `now`,
+    /// returning the sessions that ended.
+    pub fn observe(&mut self, device: &Device, now: SystemTime) -> Vec<SessionRecord> {
+        let mut ended = vec![];
+        let mut seen = vec![];
+        for peer in &device.peers {
+            let key = &peer.config.public_key;
+            let stats = &peer.stats;
+            seen.push(key.clone());
+            let fresh = stats
+                .last_handshake_time
+                .filter(|&time| now.duration_since(time).unwrap_or_default() <= self.idle_timeout);
+
+            if let Some(session) = self.active.get_mut(key) {
+                let rx = counter_delta(session.last_rx, stats.rx_bytes);
+                let tx = counter_delta(session.last_tx, stats.tx_bytes);
+                session.rx_bytes += rx;
+                session.tx_bytes += tx;
+                session.last_rx = stats.rx_bytes;
+                session.last_tx = stats.tx_bytes;
+                if rx > 0 || tx > 0 {
+                    session.last_activity = now;
+                }
+                if let Some(handshake) = fresh {
+                    session.last_activity = session.last_activity.max(handshake);
+                }
+                if peer.config.endpoint.is_some() {
+                    session.endpoint = peer.config.endpoint;
+                }
+                if fresh.is_none() {
+                    let session = self.active.remove(key).expect("session is active");
+                    ended.push(session.finish(self.iface, key.clone()));
+                }
+            } else if let Some(handshake) = fresh {
+                // Traffic from before the handshake belongs to an earlier session.
+                self.active.insert(
+                    key.clone(),
+                    ActiveSession {
+                        endpoint: peer.config.endpoint,
+                        start: handshake,
+                        last_activity: handshake,
+                        rx_bytes: 0,
+                        tx_bytes: 0,
+                        last_rx: stats.rx_bytes,
+                        last_tx: stats.tx_bytes,
+                    },
+                );
+            }
+        }
+
+        let removed: Vec<_> = self
+            .active
+            .keys()
+            .filter(|key| !seen.contains(key))
+            .cloned()
+            .collect();
+        for key in removed {
+            let session = self.active.remove(&key).expect("key taken from the map");
+            ended.push(session.finish(self.iface, key));
+        }
+        ended
+    }
+
+    /// Closes every open session, e.g. on shutdown, returning their records.
+    pub fn flush(&mut self) -> Vec<SessionRecord> {
+        self.active
+            .drain()
+            .map(|(key, session)| session.finish(self.iface, key))
+            .collect()
+    }
+}
+
+/// The export formats of [`export`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per line.
+    JsonLines,
+    /// A header line of IPFIX information element names, then one tab-separated
+    /// line per record. The endpoint is split into address and port, and the
+    /// public key is carried in the non-standard `wgPeerPublicKey` column.
+    Ipfix,
+}
+
+const IPFIX_COLUMNS: &[&str] = &[
+    "interfaceName",
+    "wgPeerPublicKey",
+    "destinationIPAddress",
+    "destinationTransportPort",
+    "flowStartMilliseconds",
+    "flowEndMilliseconds",
+    "octetDeltaCount",
+    "postOctetDeltaCount",
+];
+
+fn millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+impl SessionRecord {
+    /// The record as a single-line JSON object. Times are in milliseconds since the
+    /// Unix epoch.
+    pub fn to_json(&self) -> String {
+        let endpoint = or_null(self.endpoint.map(|endpoint| quote(&endpoint.to_string())));
+        format!(
+            "{{\"interface\":{},\"public_key\":{},\"endpoint\":{},\"start_ms\":{},\"end_ms\":{},\"rx_bytes\":{},\"tx_bytes\":{}}}",
+            quote(&self.iface.as_str_lossy()),
+            quote(&self.public_key.to_base64()),
+            endpoint,
+            millis(self.start),
+            millis(self.end),
+            self.rx_bytes,
+            self.tx_bytes
+        )
+    }
+
+    /// The record as a tab-separated line in the [`Format::Ipfix`] column order.
+    /// Received bytes are the `octetDeltaCount`, sent bytes the
+    /// `postOctetDeltaCount`.
+    pub fn to_ipfix(&self) -> String {
+        let (address, port) = match self.endpoint {
+            Some(endpoint) => (endpoint.ip().to_string(), endpoint.port().to_string()),
+            None => (String::new(), String::new()),
+        };
+        [
+            self.iface.to_string(),
+            self.public_key.to_base64(),
+            address,
+            port,
+            millis(self.start).to_string(),
+            millis(self.end).to_string(),
+            self.rx_bytes.to_string(),
+            self.tx_bytes.to_string(),
+        ]
+        .join("\t")
+    }
+}
+
+/// Writes `records` to `writer` in `format`.
+pub fn export(records: &[SessionRecord], format: Format, mut writer: impl Write) -> io::Result<()> {
+    match format {
+        Format::JsonLines => {
+            for record in records {
+                writeln!(writer, "{}", record.to_json())?;
+            }
+        }
+        Format::Ipfix => {
+            writeln!(writer, "{}", IPFIX_COLUMNS.join("\t"))?;
+            for record in records {
+                writeln!(writer, "{}", record.to_ipfix())?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerInfo;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn device(peers: &[(u8, Option<u64>, u64, u64)]) -> Device {
+        let peers = peers
+            .iter()
+            .map(|&(key, handshake, rx_bytes, tx_bytes)| {
+                let mut peer = PeerInfo::fixture(Key([key; 32]))
+                    .with_endpoint("192.0.2.2:51820")
+                    .with_transfer(rx_bytes, tx_bytes);
+                peer.stats.last_handshake_time = handshake.map(at);
+                peer
+            })
+            .collect();
+        Device {
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", peers)
+        }
+    }
+
+    #[test]
+    fn test_session_lifecycle() {
+        let mut tracker = SessionTracker::new(&"wg0".parse().unwrap(), Duration::from_secs(300));
+
+        // An old handshake doesn't open a session.
+        assert!(tracker
+            .observe(&device(&[(1, Some(0), 10, 10)]), at(1000))
+            .is_empty());
+        assert_eq!(tracker.active(), 0);
+
+        assert!(tracker
+            .observe(&device(&[(1, Some(1000), 10, 10)]), at(1010))
+            .is_empty());
+        assert_eq!(tracker.active(), 1);
+        assert!(tracker
+            .observe(&device(&[(1, Some(1120), 510, 110)]), at(1130))
+            .is_empty());
+
+        let ended = tracker.observe(&device(&[(1, Some(1120), 510, 110)]), at(1500));
+        assert_eq!(
+            ended,
+            vec![SessionRecord {
+                iface: "wg0".parse().unwrap(),
+                public_key: Key([1u8; 32]),
+                endpoint: Some("192.0.2.2:51820".parse().unwrap()),
+                start: at(1000),
+                end: at(1130),
+                rx_bytes: 500,
+                tx_bytes: 100,
+            }]
+        );
+        assert_eq!(tracker.active(), 0);
+    }
+
+    #[test]
+    fn test_removed_peer_and_counter_reset() {
+        let mut tracker = SessionTracker::new(&"wg0".parse().unwrap(), Duration::from_secs(300));
+        tracker.observe(&device(&[(1, Some(1000), 100, 100)]), at(1000));
+        tracker.observe(&device(&[(1, Some(1000), 40, 0)]), at(1060));
+
+        let ended = tracker.observe(&device(&[]), at(1120));
+        assert_eq!(ended.len(), 1);
+        assert_eq!((ended[0].rx_bytes, ended[0].tx_bytes), (40, 0));
+
+        tracker.observe(&device(&[(2, Some(1200), 0, 0)]), at(1200));
+        assert_eq!(tracker.flush().len(), 1);
+        assert_eq!(tracker.active(), 0);
+    }
+
+    #[test]
+    fn test_export() {
+        let record = SessionRecord {
+            iface: "wg0".parse().unwrap(),
+            public_key: Key([1u8; 32]),
+            endpoint: Some("192.0.2.2:51820".parse().unwrap()),
+            start: at(1000),
+            end: at(1130),
+            rx_bytes: 500,
+            tx_bytes: 100,
+        };
+        let key = record.public_key.to_base64();
+
+        let mut json = vec![];
+        export(std::slice::from_ref(&record), Format::JsonLines, &mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            format!(
+                "{{\"interface\":\"wg0\",\"public_key\":\"{}\",\"endpoint\":\"192.0.2.2:51820\",\"start_ms\":1000000,\"end_ms\":1130000,\"rx_bytes\":500,\"tx_bytes\":100}}\n",
+                key
+            )
+        );
+
+        let mut ipfix = vec![];
+        export(&[record], Format::Ipfix, &mut ipfix).unwrap();
+        let ipfix = String::from_utf8(ipfix).unwrap();
+        let lines: Vec<_> = ipfix.lines().collect();
+        assert_eq!(lines[0].split('\t').count(), IPFIX_COLUMNS.len());
+        assert_eq!(
+            lines[1],
+            format!("wg0\t{}\t192.0.2.2\t51820\t1000000\t1130000\t500\t100", key)
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! What an agent does with its interfaces when it is stopped.
+//!
+//! Whether tunnels should outlive the agent managing them is a deployment choice:
+//! leaving them up keeps traffic flowing across agent restarts, quiescing them
+//! stops traffic but keeps the keys and allowed IPs for the next start, and
+//! deleting them leaves nothing behind. A [`Policy`] names the choice and the
+//! interfaces it applies to, and [`run`] carries it out once `SIGTERM` or
+//! `SIGINT` arrives, giving up after a deadline so a hung backend can't keep the
+//! service manager waiting.
+use crate::{Backend, Device, DeviceUpdate, Error, InterfaceName, PeerConfigBuilder};
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// What happens to the managed interfaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Teardown {
+    /// Leave the interfaces running as they are.
+    #[default]
+    Leave,
+    /// Keep the interfaces and peers, but drop the peers' endpoints and
+    /// keepalives so no traffic flows until they are configured again.
+    Quiesce,
+    /// Delete the interfaces.
+    Delete,
+}
+
+/// How to shut down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    pub teardown: Teardown,
+    pub backend: Backend,
+    pub interfaces: Vec<InterfaceName>,
+    /// How long the teardown may take before the rest is given up.
+    pub deadline: Duration,
+}
+
+impl Policy {
+    /// Tears down no interfaces yet with `teardown`, within 5 seconds.
+    pub fn new(teardown: Teardown, backend: Backend) -> Self {
+        Self {
+            teardown,
+            backend,
+            interfaces: vec![],
+            deadline: Duration::from_secs(5),
+        }
+    }
+
+    /// Applies the teardown to `iface` too.
+    pub fn manage(mut self, iface: InterfaceName) -> Self {
+        self.interfaces.push(iface);
+        self
+    }
+
+    pub fn set_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+}
+
+/// What [`execute`] got done.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub completed: Vec<InterfaceName>,
+    pub failed: Vec<(InterfaceName, io::Error)>,
+    /// The interfaces that weren't torn down before the deadline.
+    pub pending: Vec<InterfaceName>,
+}
+
+impl Report {
+    /// Whether every interface was torn down.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty() && self.pending.is_empty()
+    }
+}
+
+/// The update dropping the endpoints and keepalives of the peers of `device`.
+///
+/// Endpoints can't be unset, so the peers are replaced with copies without them.
+fn quiesce_update(device: &Device) -> DeviceUpdate {
+    let peers: Vec<_> = device
+        .peers
+        .iter()
+        .map(|peer| {
+            let mut peer = PeerConfigBuilder::from_peer_config(peer.config.clone());
+            peer.endpoint = None;
+            peer.persistent_keepalive_interval = None;
+            peer
+        })
+        .collect();
+    DeviceUpdate::new().replace_peers().add_peers(&peers)
+}
+
+fn tear_down(iface: &InterfaceName, teardown: Teardown, backend: Backend) -> io::Result<()> {
+    let device = match Device::get(iface, backend) {
+        Ok(device) => device,
+        // Nothing is left to tear down.
+        Err(Error::InterfaceNotFound(_)) => return Ok(()),
+        // Deleting takes only the name, so it's tried without the configuration.
+        Err(e) if teardown == Teardown::Delete => {
+            log::warn!("failed to read {} before deleting it: {}", iface, e);
+            Device {
+                name: *iface,
+                public_key: None,
+                private_key: None,
+                fwmark: None,
+                listen_port: None,
+                peers: vec![],
+                linked_name: None,
+                backend,
+                __cant_construct_me: (),
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+    match teardown {
+        Teardown::Leave => {}
+        Teardown::Quiesce => quiesce_update(&device).apply(iface, backend)?,
+        Teardown::Delete => match device.delete() {
+            Err(Error::InterfaceNotFound(_)) => {}
+            result => result?,
+        },
+    }
+    Ok(())
+}
+
+/// Carries out `policy` now, one interface after another, and returns once all of
+/// them are done or the deadline passed.
+pub fn execute(policy: &Policy) -> Report {
+    let mut report = Report::default();
+    if policy.teardown == Teardown::Leave {
+        report.completed = policy.interfaces.clone();
+        return report;
+    }
+
+    let deadline = Instant::now() + policy.deadline;
+    let (sender, receiver) = mpsc::channel();
+    let (teardown, backend) = (policy.teardown, policy.backend);
+    let interfaces = policy.interfaces.clone();
+    // The worker is left behind if it hangs past the deadline.
+    thread::spawn(move || {
+        for iface in interfaces {
+            let result = tear_down(&iface, teardown, backend);
+            if sender.send((iface, result)).is_err() {
+                break;
+            }
+        }
+    });
+
+    report.pending = policy.interfaces.clone();
+    while !report.pending.is_empty() {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let (iface, result) = match receiver.recv_timeout(timeout) {
+            Ok(done) => done,
+            Err(_) => break,
+        };
+        report.pending.retain(|pending| *pending != iface);
+        match result {
+            Ok(()) => report.completed.push(iface),
+            Err(e) => {
+                log::warn!("failed to tear down {}: {}", iface, e);
+                report.failed.push((iface, e));
+            }
+        }
+    }
+    if !report.pending.is_empty() {
+        log::warn!(
+            "shutdown deadline of {:?} passed with {} interface(s) left",
+            policy.deadline,
+            report.pending.len()
+        );
+    }
+    report
+}
+
+/// The write end of the pipe the signal handler reports to.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let fd = SIGNAL_PIPE.load(Ordering::Relaxed);
+    let byte = signal as u8;
+    // Only async-signal-safe calls are allowed here.
+    unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+}
+
+/// Blocks until the process receives `SIGTERM` or `SIGINT`, returning which.
+/// Both are back to their default disposition afterwards.
+pub fn wait_for_signal() -> io::Result<libc::c_int> {
+    wait(libc::SIG_DFL)
+}
+
+/// Blocks until the process receives `SIGTERM` or `SIGINT`, then sets both to
+/// `disposition`.
+fn wait(disposition: libc::sighandler_t) -> io::Result<libc::c_int> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let [read, write] = fds;
+    SIGNAL_PIPE.store(write, Ordering::Relaxed);
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let mut byte = 0u8;
+    let result = loop {
+        match unsafe { libc::read(read, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+            1 => break Ok(libc::c_int::from(byte)),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    break Err(e);
+                }
+            }
+        }
+    };
+    unsafe {
+        libc::signal(libc::SIGTERM, disposition);
+        libc::signal(libc::SIGINT, disposition);
+        libc::close(read);
+        libc::close(write);
+    }
+    result
+}
+
+/// Waits for `SIGTERM` or `SIGINT`, then carries out `policy` within its
+/// deadline.
+///
+/// Further signals are ignored until the teardown is done, so an impatient
+/// second `SIGINT` can't kill the process halfway through. Under systemd, the
+/// service manager is told the service is stopping first.
+pub fn run(policy: Policy) -> io::Result<Report> {
+    let signal = wait(libc::SIG_IGN)?;
+    log::info!(
+        "received signal {}, shutting down with {:?}",
+        signal,
+        policy.teardown
+    );
+    #[cfg(target_os = "linux")]
+    if let Err(e) = crate::systemd::notify_stopping() {
+        log::warn!("failed to notify the service manager: {}", e);
+    }
+    let report = execute(&policy);
+    unsafe {
+        libc::signal(libc::SIGTERM, libc::SIG_DFL);
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, PeerInfo};
+
+    #[test]
+    fn test_quiesce_update() {
+        let mut peer = PeerInfo::fixture(Key([1u8; 32]))
+            .with_endpoint("192.0.2.1:51820")
+            .with_allowed_ips(&["10.0.0.2/32"]);
+        peer.config.persistent_keepalive_interval = Some(25);
+        let device = Device {
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", vec![peer])
+        };
+        let update = quiesce_update(&device);
+        assert!(update.replace_peers);
+        assert_eq!(update.peers.len(), 1);
+        assert_eq!(update.peers[0].endpoint, None);
+        assert_eq!(update.peers[0].persistent_keepalive_interval, None);
+        assert_eq!(update.peers[0].allowed_ips.len(), 1);
+    }
+
+    #[test]
+    fn test_leave() {
+        let policy = Policy::new(Teardown::Leave, Backend::Userspace)
+            .manage("wg0".parse().unwrap())
+            .set_deadline(Duration::ZERO);
+        let report = execute(&policy);
+        assert!(report.is_complete());
+        assert_eq!(report.completed.len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn test_delete() {
+        let iface: InterfaceName = "mock-shutdown".parse().unwrap();
+        DeviceUpdate::new().apply(&iface, Backend::Mock).unwrap();
+        let policy = Policy::new(Teardown::Delete, Backend::Mock)
+            .manage(iface)
+            .manage("mock-shutdown2".parse().unwrap());
+        let report = execute(&policy);
+        assert!(report.is_complete());
+        assert_eq!(report.completed.len(), 2);
+        assert!(!crate::backends::mock::enumerate().unwrap().contains(&iface));
+    }
+}
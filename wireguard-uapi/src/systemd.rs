@@ -0,0 +1,251 @@
+//! Running a management agent as a systemd service.
+//!
+//! Implements the two halves of the systemd service protocol a long-running agent
+//! needs, without linking libsystemd: [`notify`] sends `sd_notify(3)` state
+//! updates (readiness, watchdog keep-alives, status lines), and [`listen_fds`]
+//! takes over sockets passed by socket activation, so the agent's control socket
+//! can be a `.socket` unit. Everything is a no-op when not started by systemd.
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram, UnixListener},
+        },
+    },
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// The first file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sends `state` (newline-separated `KEY=VALUE` assignments) to the service
+/// manager, returning whether there was one to send it to.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => notify_to(&path, state).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+fn notify_to(path: &OsStr, state: &str) -> io::Result<()> {
+    let bytes = path.as_bytes();
+    let address = match bytes.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None if bytes.starts_with(b"/") => SocketAddr::from_pathname(path)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported NOTIFY_SOCKET {:?}", path),
+            ))
+        }
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// Tells the service manager that start-up finished, for `Type=notify` units.
+pub fn notify_ready() -> io::Result<bool> {
+    notify("READY=1")
+}
+
+/// Tells the service manager that the service is shutting down.
+pub fn notify_stopping() -> io::Result<bool> {
+    notify("STOPPING=1")
+}
+
+/// Updates the status line shown by `systemctl status`.
+pub fn notify_status(status: &str) -> io::Result<bool> {
+    notify(&format!("STATUS={}", status.replace('\n', " ")))
+}
+
+/// Resets the watchdog timer of units with `WatchdogSec=`.
+pub fn notify_watchdog() -> io::Result<bool> {
+    notify("WATCHDOG=1")
+}
+
+/// How often to call [`notify_watchdog`], or `None` when the watchdog isn't
+/// enabled for this process.
+///
+/// This is half of `WatchdogSec=`, as recommended by `sd_watchdog_enabled(3)`.
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.parse().ok().filter(|&usec| usec > 0)?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// A socket passed by socket activation, with its `FileDescriptorName=`
+/// (`"unknown"` if the unit doesn't set one).
+#[derive(Debug)]
+pub struct ListenFd {
+    pub name: String,
+    pub fd: OwnedFd,
+}
+
+/// Takes ownership of the sockets passed by socket activation, in the order of
+/// the unit's `Listen*=` lines.
+///
+/// Only the first call gets them: like `sd_listen_fds(1)`, it unsets the
+/// `LISTEN_*` variables, so child processes don't see them either, and later
+/// calls return nothing.
+pub fn listen_fds() -> io::Result<Vec<ListenFd>> {
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(vec![]);
+    }
+    let parsed = parse_listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_FDNAMES").ok().as_deref(),
+        std::process::id(),
+    );
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    let passed = match parsed {
+        Some(passed) => passed,
+        None => return Ok(vec![]),
+    };
+
+    passed
+        .into_iter()
+        .map(|(fd, name)| {
+            // The service manager passes the descriptors without close-on-exec.
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(ListenFd {
+                name,
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            })
+        })
+        .collect()
+}
+
+fn parse_listen_fds(
+    pid: Option<&str>,
+    count: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Option<Vec<(RawFd, String)>> {
+    if pid?.parse::<u32>().ok()? != own_pid {
+        return None;
+    }
+    let count: RawFd = count?.parse().ok()?;
+    let mut names = names.map(|names| names.split(':'));
+    Some(
+        (0..count)
+            .map(|i| {
+                let name = names.as_mut().and_then(Iterator::next).unwrap_or("unknown");
+                (LISTEN_FDS_START + i, name.to_string())
+            })
+            .collect(),
+    )
+}
+
+/// Takes the control socket out of `fds`, as returned by [`listen_fds`]: the one
+/// named `name`, or the only one passed if it is unnamed. The other sockets are
+/// left in `fds`.
+///
+/// Returns `None` when there is no such socket, e.g. when the process wasn't
+/// socket activated, so callers can fall back to binding it themselves.
+pub fn control_listener(fds: &mut Vec<ListenFd>, name: &str) -> Option<UnixListener> {
+    let index = match fds.iter().position(|passed| passed.name == name) {
+        Some(index) => index,
+        None if fds.len() == 1 && fds[0].name == "unknown" => 0,
+        None => return None,
+    };
+    Some(UnixListener::from(fds.remove(index).fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_to() {
+        let path = env::temp_dir().join(format!("wg-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        notify_to(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+
+        let error = notify_to(OsStr::new("relative"), "READY=1").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_parse_watchdog() {
+        assert_eq!(
+            parse_watchdog(Some("30000000"), None, 42),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            parse_watchdog(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(parse_watchdog(Some("30000000"), Some("7"), 42), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog(None, None, 42), None);
+    }
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(
+            parse_listen_fds(Some("42"), Some("2"), Some("control:metrics"), 42),
+            Some(vec![(3, "control".to_string()), (4, "metrics".to_string())])
+        );
+        assert_eq!(
+            parse_listen_fds(Some("42"), Some("1"), None, 42),
+            Some(vec![(3, "unknown".to_string())])
+        );
+        assert_eq!(parse_listen_fds(Some("7"), Some("1"), None, 42), None);
+        assert_eq!(parse_listen_fds(None, Some("1"), None, 42), None);
+    }
+
+    #[test]
+    fn test_control_listener() {
+        let dir = env::temp_dir().join(format!("wg-listen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let listen = |name: &str| ListenFd {
+            name: name.to_string(),
+            fd: UnixListener::bind(dir.join(name)).unwrap().into(),
+        };
+        let mut fds = vec![listen("metrics"), listen("control"), listen("admin")];
+
+        let control = control_listener(&mut fds, "control").unwrap();
+        let path = control
+            .local_addr()
+            .unwrap()
+            .as_pathname()
+            .unwrap()
+            .to_owned();
+        assert_eq!(path, dir.join("control"));
+        let names: Vec<_> = fds.iter().map(|passed| passed.name.as_str()).collect();
+        assert_eq!(names, ["metrics", "admin"]);
+        assert!(control_listener(&mut fds, "control").is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+//! A timestamped history of interface configurations, for answering "what did
+//! this peer look like at 03:12 last Tuesday" after an incident.
+//!
+//! A [`Timeline`] keeps one [`Record`] per observed configuration change of each
+//! interface. It can live in memory only or be [opened](Timeline::open) on an
+//! append-only file in the [backup](crate::backup) format, with a `# Time:`
+//! annotation on each record. Point-in-time queries replay the records, and
+//! [`changes`](Timeline::changes) turns them into a list of peer level events.
+use crate::{
+    backup::{Archive, DeviceBackup},
+    conf::{ConfFile, SectionKind},
+    Device, InterfaceName, Key, PeerConfig,
+};
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The configuration of an interface from `time` until the next record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub time: SystemTime,
+    pub device: DeviceBackup,
+}
+
+/// What changed between two consecutive records of an interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The private key, listen port or fwmark changed.
+    InterfaceModified,
+    PeerAdded(Key),
+    PeerRemoved(Key),
+    /// The endpoint, allowed IPs, keepalive or preshared key of the peer changed.
+    PeerModified(Key),
+}
+
+/// A change to an interface, as first observed at `time`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub time: SystemTime,
+    pub iface: InterfaceName,
+    pub kind: ChangeKind,
+}
+
+/// A history of interface configurations, ordered by time.
+///
+/// The history contains private and preshared keys, so files are created readable
+/// by their owner only.
+#[derive(Debug, Default)]
+pub struct Timeline {
+    records: Vec<Record>,
+    file: Option<File>,
+}
+
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{}.{:09}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos()
+    )
+}
+
+fn parse_time(s: &str) -> io::Result<SystemTime> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid time {:?}", s));
+    let (secs, nanos) = s.split_once('.').unwrap_or((s, "0"));
+    let secs = secs.parse().map_err(|_| invalid())?;
+    let nanos = nanos.parse().map_err(|_| invalid())?;
+    Ok(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+impl Timeline {
+    /// Creates an empty timeline kept in memory only.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the timeline stored at `path`, creating the file if needed. New
+    /// records are appended to it.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let records = match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(path)?;
+        Ok(Self {
+            records,
+            file: Some(file),
+        })
+    }
+
+    fn parse(text: &str) -> io::Result<Vec<Record>> {
+        let times = text
+            .parse::<ConfFile>()?
+            .sections
+            .iter()
+            .filter(|section| section.kind == SectionKind::Interface)
+            .map(|section| {
+                let time = section.annotation("Time").ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "record without a time")
+                })?;
+                parse_time(&time)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let devices = text.parse::<Archive>()?.devices;
+        Ok(times
+            .into_iter()
+            .zip(devices)
+            .map(|(time, device)| Record { time, device })
+            .collect())
+    }
+
+    /// Records the configuration of `device` as of `time`, unless it is the same as
+    /// the last one recorded for the interface. Returns whether a record was added.
+    ///
+    /// Records must be added in chronological order.
+    pub fn record(&mut self, device: &Device, time: SystemTime) -> io::Result<bool> {
+        let device = DeviceBackup::from(device);
+        if self.latest(&device.name) == Some(&device) {
+            return Ok(false);
+        }
+        if let Some(file) = &mut self.file {
+            let archive = Archive {
+                devices: vec![device.clone()],
+            };
+            writeln!(file, "# Time: {}\n{}", format_time(time), archive)?;
+            file.flush()?;
+        }
+        self.records.push(Record { time, device });
+        Ok(true)
+    }
+
+    fn latest(&self, iface: &InterfaceName) -> Option<&DeviceBackup> {
+        self.records
+            .iter()
+            .rev()
+            .find(|record| record.device.name == *iface)
+            .map(|record| &record.device)
+    }
+
+    /// Every record, oldest first.
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// The records of `iface` between `from` and `to` (inclusive), oldest first.
+    pub fn replay(
+        &self,
+        iface: &InterfaceName,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> impl Iterator<Item = &Record> {
+        let iface = *iface;
+        self.records.iter().filter(move |record| {
+            record.device.name == iface && record.time >= from && record.time <= to
+        })
+    }
+
+    /// The configuration of `iface` at `time`, i.e. its last record before then.
+    pub fn device_at(&self, iface: &InterfaceName, time: SystemTime) -> Option<&DeviceBackup> {
+        self.records
+            .iter()
+            .rev()
+            .find(|record| record.device.name == *iface && record.time <= time)
+            .map(|record| &record.device)
+    }
+
+    /// The configuration of the peer `public_key` on `iface` at `time`, or `None` if
+    /// it wasn't a peer then.
+    pub fn peer_at(
+        &self,
+        iface: &InterfaceName,
+        public_key: &Key,
+        time: SystemTime,
+    ) -> Option<&PeerConfig> {
+        self.device_at(iface, time)?
+            .peers
+            .iter()
+            .find(|peer| peer.public_key == *public_key)
+    }
+
+    /// The changes to `iface` over its whole history, oldest first. The first record
+    /// counts as adding every peer it has.
+    pub fn changes(&self, iface: &InterfaceName) -> Vec<Change> {
+        let mut changes = vec![];
+        let mut previous: Option<&DeviceBackup> = None;
+        for record in self.records.iter().filter(|r| r.device.name == *iface) {
+            let mut push = |kind| {
+                changes.push(Change {
+                    time: record.time,
+                    iface: *iface,
+                    kind,
+                })
+            };
+            let current = &record.device;
+            let old_peers = previous.map_or(&[][..], |device| &device.peers[..]);
+
+            if let Some(previous) = previous {
+                if (&previous.private_key, previous.listen_port, previous.fwmark)
+                    != (&current.private_key, current.listen_port, current.fwmark)
+                {
+                    push(ChangeKind::InterfaceModified);
+                }
+            }
+            for peer in &current.peers {
+                match old_peers.iter().find(|p| p.public_key == peer.public_key) {
+                    None => push(ChangeKind::PeerAdded(peer.public_key.clone())),
+                    Some(old) if old != peer => {
+                        push(ChangeKind::PeerModified(peer.public_key.clone()))
+                    }
+                    Some(_) => {}
+                }
+            }
+            for old in old_peers {
+                if !current.peers.iter().any(|p| p.public_key == old.public_key) {
+                    push(ChangeKind::PeerRemoved(old.public_key.clone()));
+                }
+            }
+            previous = Some(current);
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PeerInfo, PeerStats};
+
+    fn peer(key: u8, endpoint: &str) -> PeerConfig {
+        PeerInfo::fixture(Key([key; 32]))
+            .with_endpoint(endpoint)
+            .with_allowed_ips(&[&format!("10.0.0.{}/32", key)])
+            .config
+    }
+
+    fn device(peers: &[PeerConfig]) -> Device {
+        let peers = peers
+            .iter()
+            .map(|config| PeerInfo {
+                config: config.clone(),
+                stats: PeerStats::default(),
+            })
+            .collect();
+        Device {
+            private_key: Some(Key([1u8; 32])),
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", peers)
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn timeline() -> Timeline {
+        let mut timeline = Timeline::new();
+        let alice = peer(2, "192.0.2.2:51820");
+        let bob = peer(3, "192.0.2.3:51820");
+        assert!(timeline
+            .record(&device(std::slice::from_ref(&alice)), at(100))
+            .unwrap());
+        assert!(!timeline.record(&device(&[alice]), at(150)).unwrap());
+        let moved = peer(2, "198.51.100.2:51820");
+        assert!(timeline.record(&device(&[moved, bob]), at(200)).unwrap());
+        timeline
+    }
+
+    #[test]
+    fn test_peer_at() {
+        let timeline = timeline();
+        let wg0 = "wg0".parse().unwrap();
+        let endpoint = |secs| {
+            timeline
+                .peer_at(&wg0, &Key([2u8; 32]), at(secs))
+                .and_then(|peer| peer.endpoint)
+                .map(|endpoint| endpoint.to_string())
+        };
+        assert_eq!(endpoint(50), None);
+        assert_eq!(endpoint(199).as_deref(), Some("192.0.2.2:51820"));
+        assert_eq!(endpoint(200).as_deref(), Some("198.51.100.2:51820"));
+        assert!(timeline.peer_at(&wg0, &Key([3u8; 32]), at(150)).is_none());
+        assert_eq!(timeline.replay(&wg0, at(150), at(250)).count(), 1);
+    }
+
+    #[test]
+    fn test_changes() {
+        let changes: Vec<_> = timeline()
+            .changes(&"wg0".parse().unwrap())
+            .into_iter()
+            .map(|change| (change.time, change.kind))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (at(100), ChangeKind::PeerAdded(Key([2u8; 32]))),
+                (at(200), ChangeKind::PeerModified(Key([2u8; 32]))),
+                (at(200), ChangeKind::PeerAdded(Key([3u8; 32]))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_roundtrip() {
+        let path = std::env::temp_dir().join(format!("wg-timeline-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut timeline = Timeline::open(&path).unwrap();
+        let alice = peer(2, "192.0.2.2:51820");
+        timeline.record(&device(&[alice]), at(100)).unwrap();
+        timeline
+            .record(&device(&[]), at(200) + Duration::from_nanos(5))
+            .unwrap();
+        let records = timeline.records().to_vec();
+        drop(timeline);
+
+        let reopened = Timeline::open(&path).unwrap();
+        assert_eq!(reopened.records(), &records[..]);
+        fs::remove_file(&path).unwrap();
+    }
+}
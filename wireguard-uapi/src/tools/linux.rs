@@ -6,8 +6,8 @@ use netlink_packet_route::{
     address,
     constants::*,
     link::{self, nlas::State},
-    route, AddressHeader, AddressMessage, LinkHeader, LinkMessage, RouteHeader, RouteMessage,
-    RtnlMessage, RTN_UNICAST, RT_SCOPE_LINK, RT_TABLE_MAIN,
+    route, rule, AddressHeader, AddressMessage, LinkHeader, LinkMessage, RouteHeader, RouteMessage,
+    RtnlMessage, RuleHeader, RuleMessage, RTN_UNICAST, RT_SCOPE_LINK, RT_TABLE_MAIN,
 };
 use std::{io, net::IpAddr};
 
@@ -72,15 +72,24 @@ pub fn set_addr(interface: &InterfaceName, addr: IpNet) -> Result<(), io::Error>
     Ok(())
 }
 
-pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error> {
-    let if_index = if_nametoindex(interface)?;
-    let (address_family, dst) = match cidr {
+fn family_and_network(cidr: IpNet) -> (u8, Vec<u8>) {
+    match cidr {
         IpNet::V4(network) => (AF_INET as u8, network.network().octets().to_vec()),
         IpNet::V6(network) => (AF_INET6 as u8, network.network().octets().to_vec()),
-    };
-    let message = RouteMessage {
+    }
+}
+
+/// The table id for message headers, which only fit ids below 256. Larger ids are
+/// passed in a `Table` attribute instead.
+fn header_table(table: u32) -> u8 {
+    u8::try_from(table).unwrap_or(RT_TABLE_UNSPEC)
+}
+
+fn route_message(if_index: u32, cidr: IpNet, table: u32) -> RouteMessage {
+    let (address_family, dst) = family_and_network(cidr);
+    RouteMessage {
         header: RouteHeader {
-            table: RT_TABLE_MAIN,
+            table: header_table(table),
             protocol: RTPROT_BOOT,
             scope: RT_SCOPE_LINK,
             kind: RTN_UNICAST,
@@ -88,12 +97,40 @@ pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Err
             address_family,
             ..Default::default()
         },
-        nlas: vec![route::Nla::Destination(dst), route::Nla::Oif(if_index)],
-    };
+        nlas: vec![
+            route::Nla::Destination(dst),
+            route::Nla::Oif(if_index),
+            route::Nla::Table(table),
+        ],
+    }
+}
 
+pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error> {
+    add_route_to_table(interface, cidr, u32::from(RT_TABLE_MAIN))
+}
+
+/// Deletes the route to `cidr` through `interface` from the main table. Returns
+/// `false` if there was none.
+pub fn del_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error> {
+    del_route_from_table(interface, cidr, u32::from(RT_TABLE_MAIN))
+}
+
+/// Adds a route to `cidr` through `interface` in routing table `table`. Returns
+/// `false` if it already existed.
+pub fn add_route_to_table(
+    interface: &InterfaceName,
+    cidr: IpNet,
+    table: u32,
+) -> Result<bool, io::Error> {
+    let message = route_message(if_nametoindex(interface)?, cidr, table);
     match netlink_request_rtnl(RtnlMessage::NewRoute(message), None) {
         Ok(_) => {
-            log::debug!("added route {} to interface {}", cidr, interface);
+            log::debug!(
+                "added route {} to interface {} in table {}",
+                cidr,
+                interface,
+                table
+            );
             Ok(true)
         }
         Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
@@ -104,6 +141,79 @@ pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Err
     }
 }
 
+/// Deletes the route to `cidr` through `interface` from `table`. Returns `false`
+/// if there was none.
+pub fn del_route_from_table(
+    interface: &InterfaceName,
+    cidr: IpNet,
+    table: u32,
+) -> Result<bool, io::Error> {
+    let message = route_message(if_nametoindex(interface)?, cidr, table);
+    match netlink_request_rtnl(
+        RtnlMessage::DelRoute(message),
+        Some(NLM_F_REQUEST | NLM_F_ACK),
+    ) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn rule_message(source: IpNet, table: u32, priority: Option<u32>) -> RuleMessage {
+    let (family, src) = family_and_network(source);
+    let mut nlas = vec![rule::Nla::Source(src), rule::Nla::Table(table)];
+    nlas.extend(priority.map(rule::Nla::Priority));
+    RuleMessage {
+        header: RuleHeader {
+            family,
+            src_len: source.prefix_len(),
+            table: header_table(table),
+            action: FR_ACT_TO_TBL,
+            ..Default::default()
+        },
+        nlas,
+    }
+}
+
+/// Adds the rule `from <source> lookup <table>`. Returns `false` if it already
+/// existed.
+pub fn add_source_rule(
+    source: IpNet,
+    table: u32,
+    priority: Option<u32>,
+) -> Result<bool, io::Error> {
+    let message = rule_message(source, table, priority);
+    match netlink_request_rtnl(RtnlMessage::NewRule(message), None) {
+        Ok(_) => {
+            log::debug!("added rule from {} lookup {}", source, table);
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Deletes the rule `from <source> lookup <table>`. Returns `false` if there was
+/// none.
+pub fn del_source_rule(
+    source: IpNet,
+    table: u32,
+    priority: Option<u32>,
+) -> Result<bool, io::Error> {
+    let message = rule_message(source, table, priority);
+    match netlink_request_rtnl(
+        RtnlMessage::DelRule(message),
+        Some(NLM_F_REQUEST | NLM_F_ACK),
+    ) {
+        Ok(_) => {
+            log::debug!("deleted rule from {} lookup {}", source, table);
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
 fn get_links() -> Result<Vec<String>, io::Error> {
     let link_responses = netlink_request_rtnl(
         RtnlMessage::GetLink(LinkMessage::default()),
@@ -1,6 +1,10 @@
+#[cfg(target_os = "linux")]
+pub mod policy;
 pub mod quick;
+#[cfg(target_os = "linux")]
+pub mod sysctl;
 
 #[cfg(target_os = "linux")]
-mod linux;
+pub(crate) mod linux;
 #[cfg(target_os = "macos")]
 mod macos;
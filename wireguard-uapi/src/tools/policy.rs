@@ -0,0 +1,118 @@
+//! Source-based policy routing for split tunnels.
+//!
+//! Instead of routing destinations through the tunnel, a [`SplitTunnel`] sends
+//! everything *from* some sources (e.g. the tunnel address, or a container subnet)
+//! through it: it installs `ip rule from <source> lookup <table>` rules and fills
+//! `<table>` with routes through the interface, leaving the main table alone.
+use crate::{tools::linux, InterfaceName};
+
+use ipnet::IpNet;
+use std::io;
+
+/// Policy routing rules and a routing table sending traffic from some sources
+/// through an interface, built up and then [installed](SplitTunnel::install).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitTunnel {
+    interface: InterfaceName,
+    table: u32,
+    priority: Option<u32>,
+    sources: Vec<IpNet>,
+    routes: Vec<IpNet>,
+}
+
+impl SplitTunnel {
+    /// Routes the sources through `interface` using routing table `table`, which
+    /// should not be used for anything else.
+    pub fn new(interface: &InterfaceName, table: u32) -> Self {
+        Self {
+            interface: *interface,
+            table,
+            priority: None,
+            sources: vec![],
+            routes: vec![],
+        }
+    }
+
+    /// Sets the priority of the rules, instead of letting the kernel pick one.
+    pub fn set_priority(mut self, priority: u32) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Sends traffic from `source` through the tunnel.
+    pub fn add_source(mut self, source: IpNet) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    /// Only sends the sources' traffic to `route` through the tunnel. Without any
+    /// routes, all of it is.
+    pub fn add_route(mut self, route: IpNet) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// The routes of the table: the ones added, or default routes for the address
+    /// families of the sources.
+    fn routes(&self) -> Vec<IpNet> {
+        if !self.routes.is_empty() {
+            return self.routes.clone();
+        }
+        let mut routes = vec![];
+        if self
+            .sources
+            .iter()
+            .any(|source| matches!(source, IpNet::V4(_)))
+        {
+            routes.push("0.0.0.0/0".parse().unwrap());
+        }
+        if self
+            .sources
+            .iter()
+            .any(|source| matches!(source, IpNet::V6(_)))
+        {
+            routes.push("::/0".parse().unwrap());
+        }
+        routes
+    }
+
+    /// Adds the routes, then the rules. Parts that already exist are kept.
+    pub fn install(&self) -> io::Result<()> {
+        for route in self.routes() {
+            linux::add_route_to_table(&self.interface, route, self.table)?;
+        }
+        for source in &self.sources {
+            linux::add_source_rule(*source, self.table, self.priority)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the rules, then the routes. Parts that are already gone are skipped.
+    pub fn remove(&self) -> io::Result<()> {
+        for source in &self.sources {
+            linux::del_source_rule(*source, self.table, self.priority)?;
+        }
+        for route in self.routes() {
+            linux::del_route_from_table(&self.interface, route, self.table)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_routes() {
+        let iface = "wg0".parse().unwrap();
+        let split = SplitTunnel::new(&iface, 51820).add_source("10.0.0.2/32".parse().unwrap());
+        assert_eq!(split.routes(), ["0.0.0.0/0".parse::<IpNet>().unwrap()]);
+
+        let split = split.add_source("fd00::2/128".parse().unwrap());
+        assert_eq!(split.routes().len(), 2);
+
+        let split = split.add_route("192.0.2.0/24".parse().unwrap());
+        assert_eq!(split.routes(), ["192.0.2.0/24".parse::<IpNet>().unwrap()]);
+    }
+}
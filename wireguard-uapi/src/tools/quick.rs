@@ -11,6 +11,10 @@ pub struct WgQuick {
     listen_port: Option<u16>,
     peers: Vec<PeerConfigBuilder>,
     replace_peers: bool,
+    #[cfg(all(feature = "nft", target_os = "linux"))]
+    mss_clamp: Option<crate::nft::MssClamp>,
+    #[cfg(target_os = "linux")]
+    sysctls: crate::tools::sysctl::Sysctls,
 }
 
 impl WgQuick {
@@ -25,6 +29,10 @@ impl WgQuick {
             listen_port: None,
             peers: vec![],
             replace_peers: false,
+            #[cfg(all(feature = "nft", target_os = "linux"))]
+            mss_clamp: None,
+            #[cfg(target_os = "linux")]
+            sysctls: crate::tools::sysctl::Sysctls::new(&interface),
         })
     }
 
@@ -61,6 +69,40 @@ impl WgQuick {
         self.set_private_key(Key::zero())
     }
 
+    pub fn set_mtu(mut self, mtu: u32) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Clamps the MSS of TCP connections forwarded through the interface when it's
+    /// brought up. [`QuickUp::down`] removes the clamping again; after
+    /// [`WgQuick::apply`], it stays until [`MssClamp::remove`](crate::nft::MssClamp::remove).
+    #[cfg(all(feature = "nft", target_os = "linux"))]
+    pub fn clamp_mss(mut self, clamp: crate::nft::MssClamp) -> Self {
+        self.mss_clamp = Some(clamp);
+        self
+    }
+
+    /// Sets a sysctl of the interface when it's brought up with [`WgQuick::up`],
+    /// which restores the values it replaced when taking it down.
+    #[cfg(target_os = "linux")]
+    pub fn set_sysctl(self, sysctl: crate::tools::sysctl::Sysctl, value: u32) -> Self {
+        self.set_sysctl_in(crate::tools::sysctl::Scope::Interface, sysctl, value)
+    }
+
+    /// Like [`set_sysctl`](WgQuick::set_sysctl), in `scope`, e.g.
+    /// [`Scope::All`](crate::tools::sysctl::Scope::All) for `rp_filter`.
+    #[cfg(target_os = "linux")]
+    pub fn set_sysctl_in(
+        mut self,
+        scope: crate::tools::sysctl::Scope,
+        sysctl: crate::tools::sysctl::Sysctl,
+        value: u32,
+    ) -> Self {
+        self.sysctls = self.sysctls.set_in(scope, sysctl, value);
+        self
+    }
+
     pub fn set_listen_port(mut self, port: u16) -> Self {
         self.listen_port = Some(port);
         self
@@ -99,7 +141,20 @@ impl WgQuick {
         self.add_peer(peer)
     }
 
+    /// Configures the interface. Sysctls are only set by [`WgQuick::up`], which
+    /// keeps what they replaced, so this fails if any are.
     pub fn apply(self, backend: crate::Backend) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if !self.sysctls.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sysctls are only set by WgQuick::up",
+            ));
+        }
+        self.configure(backend)
+    }
+
+    fn configure(self, backend: crate::Backend) -> io::Result<()> {
         let mut update = DeviceUpdate::new();
 
         if let Some(listen_port) = self.listen_port {
@@ -122,18 +177,106 @@ impl WgQuick {
             .add_peers(self.peers.as_slice())
             .apply(&self.interface, backend)?;
 
-        #[cfg(target_os = "linux")]
-        use crate::tools::linux as platform;
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            #[cfg(target_os = "linux")]
+            use crate::tools::linux as platform;
 
-        #[cfg(target_os = "macos")]
-        use crate::tools::macos as platform;
+            #[cfg(target_os = "macos")]
+            use crate::tools::macos as platform;
 
-        platform::set_up(&self.interface, self.mtu)?;
-        for address in self.cidr {
-            platform::set_addr(&self.interface, address)?;
-            platform::add_route(&self.interface, address)?;
+            platform::set_up(&self.interface, self.mtu)?;
+            for address in self.cidr {
+                platform::set_addr(&self.interface, address)?;
+                platform::add_route(&self.interface, address)?;
+            }
+        }
+
+        // Elsewhere, the interface is configured through the platform's own tools,
+        // such as `windows::NetworkPlan`.
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        if !self.cidr.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "addresses and routes are only set up on Linux and macOS",
+            ));
+        }
+
+        #[cfg(all(feature = "nft", target_os = "linux"))]
+        if let Some(clamp) = self.mss_clamp {
+            clamp.install(&self.interface)?;
         }
 
         Ok(())
     }
+
+    /// Configures the interface like [`WgQuick::apply`] and sets its sysctls,
+    /// returning what [takes it down](QuickUp::down) again like `wg-quick down`.
+    #[cfg(target_os = "linux")]
+    pub fn up(mut self, backend: crate::Backend) -> io::Result<QuickUp> {
+        let interface = self.interface;
+        let sysctls = std::mem::replace(
+            &mut self.sysctls,
+            crate::tools::sysctl::Sysctls::new(&interface),
+        );
+        #[cfg(feature = "nft")]
+        let mss_clamp = self.mss_clamp.is_some();
+        self.configure(backend)?;
+        Ok(QuickUp {
+            interface,
+            backend,
+            #[cfg(feature = "nft")]
+            mss_clamp,
+            sysctls: sysctls.apply()?,
+        })
+    }
+}
+
+/// An interface brought up by [`WgQuick::up`].
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+#[must_use = "the sysctls stay changed unless the interface is taken down"]
+pub struct QuickUp {
+    interface: InterfaceName,
+    backend: crate::Backend,
+    /// Whether an MSS clamp was installed, which is removed with the interface.
+    #[cfg(feature = "nft")]
+    mss_clamp: bool,
+    sysctls: crate::tools::sysctl::SavedSysctls,
+}
+
+#[cfg(target_os = "linux")]
+impl QuickUp {
+    pub fn interface(&self) -> &InterfaceName {
+        &self.interface
+    }
+
+    /// The sysctl values [`WgQuick::up`] replaced.
+    pub fn sysctls(&self) -> &crate::tools::sysctl::SavedSysctls {
+        &self.sysctls
+    }
+
+    /// Deletes the interface, which takes its addresses and routes with it,
+    /// removes its MSS clamping and restores the sysctls. An interface deleted
+    /// already is fine.
+    pub fn down(self) -> io::Result<()> {
+        let deleted =
+            crate::Device::get(&self.interface, self.backend).and_then(crate::Device::delete);
+        let deleted = match deleted {
+            Err(crate::Error::InterfaceNotFound(_)) => Ok(()),
+            deleted => deleted,
+        };
+        #[cfg(feature = "nft")]
+        let unclamped = if self.mss_clamp {
+            crate::nft::MssClamp::remove(&self.interface)
+        } else {
+            Ok(())
+        };
+        #[cfg(not(feature = "nft"))]
+        let unclamped: io::Result<()> = Ok(());
+        let restored = self.sysctls.restore();
+        deleted?;
+        unclamped?;
+        restored
+    }
 }
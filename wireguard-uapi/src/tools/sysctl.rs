@@ -0,0 +1,247 @@
+//! Per-interface sysctls that commonly break routing through a tunnel.
+//!
+//! A gateway needs `forwarding` on the interface, strict `rp_filter` drops
+//! packets whose source routes out of another interface (as with policy
+//! routing), and `accept_ra` decides whether IPv6 forwarding turns off router
+//! advertisements. [`Sysctls`] sets them for one interface, and for `all` or
+//! `default` where the kernel combines those with the interface's, and returns
+//! the previous values as [`SavedSysctls`], to put back when the interface goes
+//! down.
+use crate::InterfaceName;
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const PROC_SYS_NET: &str = "/proc/sys/net";
+
+/// A per-interface sysctl, under `/proc/sys/net/ipv{4,6}/conf/<iface>/`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sysctl {
+    /// `net.ipv4.conf.<iface>.forwarding`
+    Ipv4Forwarding,
+    /// `net.ipv6.conf.<iface>.forwarding`
+    Ipv6Forwarding,
+    /// `net.ipv4.conf.<iface>.rp_filter`: 0 off, 1 strict, 2 loose. The kernel
+    /// uses the higher of this and `net.ipv4.conf.all.rp_filter`, so loosening it
+    /// takes setting it in [`Scope::All`] too.
+    RpFilter,
+    /// `net.ipv6.conf.<iface>.accept_ra`: 0 never, 1 unless forwarding, 2 even
+    /// when forwarding.
+    AcceptRa,
+}
+
+/// Which `conf/` directory a sysctl is set in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// The interface's own, gone with the interface.
+    Interface,
+    /// `conf/all/`, which the kernel combines with every interface's value, e.g.
+    /// the higher `rp_filter` of the two wins.
+    All,
+    /// `conf/default/`, copied into interfaces created after it's set.
+    Default,
+}
+
+impl Sysctl {
+    fn path(self, root: &Path, iface: &InterfaceName) -> PathBuf {
+        self.scoped_path(root, iface, Scope::Interface)
+    }
+
+    fn scoped_path(self, root: &Path, iface: &InterfaceName, scope: Scope) -> PathBuf {
+        let (family, name) = match self {
+            Self::Ipv4Forwarding => ("ipv4", "forwarding"),
+            Self::Ipv6Forwarding => ("ipv6", "forwarding"),
+            Self::RpFilter => ("ipv4", "rp_filter"),
+            Self::AcceptRa => ("ipv6", "accept_ra"),
+        };
+        let dir = match scope {
+            Scope::Interface => iface.as_str_lossy().into_owned(),
+            Scope::All => "all".to_string(),
+            Scope::Default => "default".to_string(),
+        };
+        root.join(family).join("conf").join(dir).join(name)
+    }
+}
+
+fn read(root: &Path, iface: &InterfaceName, sysctl: Sysctl) -> io::Result<u32> {
+    read_path(&sysctl.path(root, iface))
+}
+
+fn read_path(path: &Path) -> io::Result<u32> {
+    let value = fs::read_to_string(path)?;
+    value.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid value {:?} in {}", value.trim(), path.display()),
+        )
+    })
+}
+
+fn write(root: &Path, iface: &InterfaceName, sysctl: Sysctl, value: u32) -> io::Result<()> {
+    write_path(&sysctl.path(root, iface), value)
+}
+
+fn write_path(path: &Path, value: u32) -> io::Result<()> {
+    fs::write(path, format!("{}\n", value))
+}
+
+/// The value of `sysctl` for `iface`.
+pub fn get(iface: &InterfaceName, sysctl: Sysctl) -> io::Result<u32> {
+    read(Path::new(PROC_SYS_NET), iface, sysctl)
+}
+
+/// Sets `sysctl` for `iface`, returning the previous value.
+pub fn set(iface: &InterfaceName, sysctl: Sysctl, value: u32) -> io::Result<u32> {
+    let root = Path::new(PROC_SYS_NET);
+    let previous = read(root, iface, sysctl)?;
+    write(root, iface, sysctl, value)?;
+    Ok(previous)
+}
+
+/// Sysctls to set for an interface.
+#[derive(Debug, Clone)]
+pub struct Sysctls {
+    interface: InterfaceName,
+    root: PathBuf,
+    values: Vec<(Scope, Sysctl, u32)>,
+}
+
+impl Sysctls {
+    pub fn new(interface: &InterfaceName) -> Self {
+        Self {
+            interface: *interface,
+            root: PathBuf::from(PROC_SYS_NET),
+            values: vec![],
+        }
+    }
+
+    /// Sets `sysctl` of the interface to `value`, replacing a value set before.
+    pub fn set(self, sysctl: Sysctl, value: u32) -> Self {
+        self.set_in(Scope::Interface, sysctl, value)
+    }
+
+    /// Sets `sysctl` in `scope` to `value`, replacing a value set before.
+    pub fn set_in(mut self, scope: Scope, sysctl: Sysctl, value: u32) -> Self {
+        self.values
+            .retain(|(other_scope, other, _)| (*other_scope, *other) != (scope, sysctl));
+        self.values.push((scope, sysctl, value));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Writes the values, returning the ones they replaced. If one can't be
+    /// written, those written before it are restored.
+    pub fn apply(&self) -> io::Result<SavedSysctls> {
+        let mut saved = SavedSysctls {
+            interface: self.interface,
+            root: self.root.clone(),
+            values: vec![],
+        };
+        for &(scope, sysctl, value) in &self.values {
+            let path = sysctl.scoped_path(&self.root, &self.interface, scope);
+            let result = read_path(&path).and_then(|previous| {
+                write_path(&path, value)?;
+                Ok(previous)
+            });
+            match result {
+                Ok(previous) => saved.values.push((scope, sysctl, previous)),
+                Err(e) => {
+                    let _ = saved.restore();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(saved)
+    }
+}
+
+/// The values [`Sysctls::apply`] replaced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedSysctls {
+    interface: InterfaceName,
+    root: PathBuf,
+    values: Vec<(Scope, Sysctl, u32)>,
+}
+
+impl SavedSysctls {
+    pub fn values(&self) -> &[(Scope, Sysctl, u32)] {
+        &self.values
+    }
+
+    /// Puts the previous values back, in reverse order. The interface's own are
+    /// skipped if it's gone, since they went with it, but `all` and `default`
+    /// are restored regardless.
+    pub fn restore(&self) -> io::Result<()> {
+        for &(scope, sysctl, value) in self.values.iter().rev() {
+            let path = sysctl.scoped_path(&self.root, &self.interface, scope);
+            match write_path(&path, value) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound && scope == Scope::Interface => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_and_restore() {
+        let root = std::env::temp_dir().join(format!("wg-sysctl-{}", std::process::id()));
+        let iface: InterfaceName = "wg0".parse().unwrap();
+        let all = |sysctl: Sysctl| sysctl.scoped_path(&root, &iface, Scope::All);
+        for (path, value) in [
+            (Sysctl::Ipv4Forwarding.path(&root, &iface), "0\n"),
+            (Sysctl::RpFilter.path(&root, &iface), "1\n"),
+            (all(Sysctl::RpFilter), "1\n"),
+        ] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, value).unwrap();
+        }
+        let mut sysctls = Sysctls::new(&iface)
+            .set(Sysctl::Ipv4Forwarding, 1)
+            .set(Sysctl::RpFilter, 2)
+            .set_in(Scope::All, Sysctl::RpFilter, 2);
+        sysctls.root = root.clone();
+
+        let saved = sysctls.apply().unwrap();
+        assert_eq!(
+            saved.values(),
+            [
+                (Scope::Interface, Sysctl::Ipv4Forwarding, 0),
+                (Scope::Interface, Sysctl::RpFilter, 1),
+                (Scope::All, Sysctl::RpFilter, 1)
+            ]
+        );
+        assert_eq!(read(&root, &iface, Sysctl::Ipv4Forwarding).unwrap(), 1);
+        assert_eq!(read(&root, &iface, Sysctl::RpFilter).unwrap(), 2);
+        assert_eq!(read_path(&all(Sysctl::RpFilter)).unwrap(), 2);
+
+        // A failing write rolls back the ones before it.
+        let failing = sysctls
+            .clone()
+            .set(Sysctl::Ipv4Forwarding, 0)
+            .set(Sysctl::AcceptRa, 2);
+        assert!(failing.apply().is_err());
+        assert_eq!(read(&root, &iface, Sysctl::Ipv4Forwarding).unwrap(), 1);
+
+        saved.restore().unwrap();
+        assert_eq!(read(&root, &iface, Sysctl::Ipv4Forwarding).unwrap(), 0);
+        assert_eq!(read(&root, &iface, Sysctl::RpFilter).unwrap(), 1);
+        assert_eq!(read_path(&all(Sysctl::RpFilter)).unwrap(), 1);
+
+        // Without the interface, `all` is still restored.
+        sysctls.apply().unwrap();
+        fs::remove_dir_all(Sysctl::RpFilter.path(&root, &iface).parent().unwrap()).unwrap();
+        saved.restore().unwrap();
+        assert_eq!(read_path(&all(Sysctl::RpFilter)).unwrap(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,672 @@
+//! Handshake history built from the kernel module's debug output.
+//!
+//! The in-kernel WireGuard implementation reports handshake attempts, retries and
+//! dropped handshake packets through dynamic debug (`dyndbg`) messages in the kernel
+//! log. None of this is exposed over netlink, so byte counters alone can't tell a
+//! peer that never answers apart from one whose packets fail MAC validation.
+//!
+//! Enable the messages once with [`enable_dyndbg`], then feed lines from
+//! [`KernelLog`] (or any `dmesg` output) into a [`HandshakeHistory`].
+//!
+//! The same messages also report dropped data packets and cookie replies, which are
+//! summarized per interface in [`TrafficCounters`] to help spot scanning or a flood
+//! against the listen port.
+use crate::{Device, InterfaceName, Key};
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, prelude::*, BufReader},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime},
+};
+
+const DYNDBG_CONTROL_PATH: &str = "/sys/kernel/debug/dynamic_debug/control";
+const KMSG_PATH: &str = "/dev/kmsg";
+const LOG_PREFIX: &str = "wireguard: ";
+
+/// Turns on the WireGuard module's debug messages in the kernel log.
+///
+/// Requires root and a mounted debugfs.
+pub fn enable_dyndbg() -> io::Result<()> {
+    set_dyndbg("module wireguard +p")
+}
+
+/// Turns the WireGuard module's debug messages back off.
+pub fn disable_dyndbg() -> io::Result<()> {
+    set_dyndbg("module wireguard -p")
+}
+
+fn set_dyndbg(query: &str) -> io::Result<()> {
+    let mut control = OpenOptions::new().write(true).open(DYNDBG_CONTROL_PATH)?;
+    control.write_all(query.as_bytes())?;
+    log::debug!("wrote '{}' to {}", query, DYNDBG_CONTROL_PATH);
+    Ok(())
+}
+
+/// Reasons the kernel gives for dropping a handshake-related packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandshakeFailure {
+    /// The packet's MAC didn't match our public key (wrong key or scanning).
+    InvalidMac,
+    /// A handshake initiation failed to decrypt or validate.
+    InvalidInitiation,
+    /// A handshake response failed to decrypt or validate.
+    InvalidResponse,
+    /// A handshake didn't complete in time and is being retried.
+    Timeout,
+    /// The kernel stopped retrying the handshake.
+    GaveUp,
+}
+
+/// Reasons the kernel gives for dropping a data packet from a known peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// The inner source address isn't within the peer's allowed IPs.
+    UnallowedSource(IpAddr),
+    /// The decrypted packet was neither IPv4 nor IPv6.
+    NotIp,
+}
+
+/// A single handshake-related event reported by the kernel module.
+///
+/// Peers are identified by the kernel's internal peer id, which is only stable for
+/// the lifetime of the peer on the interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    HandshakeInitiationSent {
+        peer_id: u64,
+        endpoint: Option<SocketAddr>,
+    },
+    HandshakeInitiationReceived {
+        peer_id: u64,
+        endpoint: Option<SocketAddr>,
+    },
+    HandshakeResponseSent {
+        peer_id: u64,
+        endpoint: Option<SocketAddr>,
+    },
+    HandshakeResponseReceived {
+        peer_id: u64,
+        endpoint: Option<SocketAddr>,
+    },
+    /// A handshake wasn't answered and is being retried (`attempt` is the new try).
+    HandshakeRetry {
+        peer_id: u64,
+        endpoint: Option<SocketAddr>,
+        attempt: u32,
+    },
+    /// The kernel gave up on handshaking after `attempts` tries.
+    HandshakeGaveUp {
+        peer_id: u64,
+        endpoint: Option<SocketAddr>,
+        attempts: u32,
+    },
+    /// A handshake packet was dropped before it could be tied to a peer.
+    HandshakeRejected {
+        reason: HandshakeFailure,
+        endpoint: Option<SocketAddr>,
+    },
+    KeypairCreated {
+        peer_id: u64,
+        keypair_id: u64,
+    },
+    /// The interface is under load and answered a handshake with a cookie reply
+    /// instead of processing it.
+    CookieReplySent {
+        endpoint: Option<SocketAddr>,
+    },
+    /// The remote side is under load and sent us a cookie reply.
+    CookieReplyReceived {
+        endpoint: Option<SocketAddr>,
+    },
+    /// A decrypted data packet from a peer was dropped.
+    InvalidPacket {
+        peer_id: u64,
+        endpoint: Option<SocketAddr>,
+        reason: DropReason,
+    },
+}
+
+impl TraceEvent {
+    /// The kernel's internal id of the peer this event belongs to, if known.
+    pub fn peer_id(&self) -> Option<u64> {
+        match *self {
+            Self::HandshakeInitiationSent { peer_id, .. }
+            | Self::HandshakeInitiationReceived { peer_id, .. }
+            | Self::HandshakeResponseSent { peer_id, .. }
+            | Self::HandshakeResponseReceived { peer_id, .. }
+            | Self::HandshakeRetry { peer_id, .. }
+            | Self::HandshakeGaveUp { peer_id, .. }
+            | Self::KeypairCreated { peer_id, .. }
+            | Self::InvalidPacket { peer_id, .. } => Some(peer_id),
+            Self::HandshakeRejected { .. }
+            | Self::CookieReplySent { .. }
+            | Self::CookieReplyReceived { .. } => None,
+        }
+    }
+
+    /// The remote endpoint this event was observed from or sent to, if reported.
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        match *self {
+            Self::HandshakeInitiationSent { endpoint, .. }
+            | Self::HandshakeInitiationReceived { endpoint, .. }
+            | Self::HandshakeResponseSent { endpoint, .. }
+            | Self::HandshakeResponseReceived { endpoint, .. }
+            | Self::HandshakeRetry { endpoint, .. }
+            | Self::HandshakeGaveUp { endpoint, .. }
+            | Self::HandshakeRejected { endpoint, .. }
+            | Self::CookieReplySent { endpoint }
+            | Self::CookieReplyReceived { endpoint }
+            | Self::InvalidPacket { endpoint, .. } => endpoint,
+            Self::KeypairCreated { .. } => None,
+        }
+    }
+}
+
+/// A parsed kernel log line belonging to a WireGuard interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceLine {
+    pub interface: InterfaceName,
+    pub event: TraceEvent,
+}
+
+impl TraceLine {
+    /// Parses a line of `dmesg` or `/dev/kmsg` output.
+    ///
+    /// Returns `None` for lines that don't come from the WireGuard module or that
+    /// don't describe a handshake event.
+    pub fn parse(line: &str) -> Option<Self> {
+        let rest = &line[line.find(LOG_PREFIX)? + LOG_PREFIX.len()..];
+        let (interface, message) = rest.split_once(": ")?;
+        let interface = interface.parse().ok()?;
+        let event = parse_event(message.trim_end())?;
+        Some(Self { interface, event })
+    }
+}
+
+/// Parses "peer <id> (<endpoint>)" at the start of `s`, returning the remainder.
+fn parse_peer(s: &str) -> Option<(u64, Option<SocketAddr>, &str)> {
+    let s = s.strip_prefix("peer ")?;
+    let (id, rest) = s.split_once(' ').unwrap_or((s, ""));
+    let peer_id = id.parse().ok()?;
+    match rest.strip_prefix('(') {
+        Some(rest) => {
+            let (endpoint, rest) = rest.split_once(')')?;
+            Some((peer_id, endpoint.parse().ok(), rest))
+        }
+        None => Some((peer_id, None, rest)),
+    }
+}
+
+/// Extracts the number following `marker`, e.g. "try 3" -> 3.
+fn number_after(s: &str, marker: &str) -> Option<u32> {
+    let s = &s[s.find(marker)? + marker.len()..];
+    let digits: String = s.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+fn parse_event(message: &str) -> Option<TraceEvent> {
+    use HandshakeFailure::*;
+    use TraceEvent::*;
+
+    if let Some(rest) = message.strip_prefix("Sending handshake initiation to ") {
+        let (peer_id, endpoint, _) = parse_peer(rest)?;
+        return Some(HandshakeInitiationSent { peer_id, endpoint });
+    }
+    if let Some(rest) = message.strip_prefix("Receiving handshake initiation from ") {
+        let (peer_id, endpoint, _) = parse_peer(rest)?;
+        return Some(HandshakeInitiationReceived { peer_id, endpoint });
+    }
+    if let Some(rest) = message.strip_prefix("Sending handshake response to ") {
+        let (peer_id, endpoint, _) = parse_peer(rest)?;
+        return Some(HandshakeResponseSent { peer_id, endpoint });
+    }
+    if let Some(rest) = message.strip_prefix("Receiving handshake response from ") {
+        let (peer_id, endpoint, _) = parse_peer(rest)?;
+        return Some(HandshakeResponseReceived { peer_id, endpoint });
+    }
+    if let Some(rest) = message.strip_prefix("Handshake for ") {
+        let (peer_id, endpoint, rest) = parse_peer(rest)?;
+        if rest.contains("giving up") {
+            let attempts = number_after(rest, "after ")?;
+            return Some(HandshakeGaveUp {
+                peer_id,
+                endpoint,
+                attempts,
+            });
+        }
+        let attempt = number_after(rest, "(try ")?;
+        return Some(HandshakeRetry {
+            peer_id,
+            endpoint,
+            attempt,
+        });
+    }
+    if let Some(rest) = message.strip_prefix("Invalid MAC of handshake, dropping packet from ") {
+        return Some(HandshakeRejected {
+            reason: InvalidMac,
+            endpoint: rest.parse().ok(),
+        });
+    }
+    if let Some(rest) = message.strip_prefix("Invalid handshake initiation from ") {
+        return Some(HandshakeRejected {
+            reason: InvalidInitiation,
+            endpoint: rest.parse().ok(),
+        });
+    }
+    if let Some(rest) = message.strip_prefix("Invalid handshake response from ") {
+        return Some(HandshakeRejected {
+            reason: InvalidResponse,
+            endpoint: rest.parse().ok(),
+        });
+    }
+    if let Some(rest) =
+        message.strip_prefix("Sending cookie response for denied handshake message for ")
+    {
+        return Some(CookieReplySent {
+            endpoint: rest.parse().ok(),
+        });
+    }
+    if let Some(rest) = message.strip_prefix("Receiving cookie response from ") {
+        return Some(CookieReplyReceived {
+            endpoint: rest.parse().ok(),
+        });
+    }
+    if let Some(rest) = message.strip_prefix("Packet has unallowed src IP (") {
+        let (source, rest) = rest.split_once(") from ")?;
+        let (peer_id, endpoint, _) = parse_peer(rest)?;
+        return Some(TraceEvent::InvalidPacket {
+            peer_id,
+            endpoint,
+            reason: DropReason::UnallowedSource(source.parse().ok()?),
+        });
+    }
+    if let Some(rest) = message.strip_prefix("Packet is neither ipv4 nor ipv6 from ") {
+        let (peer_id, endpoint, _) = parse_peer(rest)?;
+        return Some(TraceEvent::InvalidPacket {
+            peer_id,
+            endpoint,
+            reason: DropReason::NotIp,
+        });
+    }
+    if let Some(rest) = message.strip_prefix("Keypair ") {
+        let (keypair_id, rest) = rest.split_once(" created for ")?;
+        let (peer_id, _, _) = parse_peer(rest)?;
+        return Some(KeypairCreated {
+            peer_id,
+            keypair_id: keypair_id.parse().ok()?,
+        });
+    }
+    None
+}
+
+/// A [`TraceEvent`] along with the time it was recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedEvent {
+    pub time: SystemTime,
+    pub event: TraceEvent,
+}
+
+/// Accumulated handshake statistics for a single peer.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PeerHandshakeHistory {
+    /// The last endpoint the kernel reported for this peer.
+    pub endpoint: Option<SocketAddr>,
+    /// Number of handshake initiations sent to this peer.
+    pub initiations_sent: u64,
+    /// Number of handshake initiations received from this peer.
+    pub initiations_received: u64,
+    /// Number of handshakes that completed (a new keypair was derived).
+    pub completed: u64,
+    /// Number of handshake retries after a timeout.
+    pub retries: u64,
+    /// Number of times the kernel gave up on handshaking.
+    pub gave_up: u64,
+    /// Number of data packets from this peer that were dropped.
+    pub invalid_packets: u64,
+    /// Time of the last completed handshake seen in the log.
+    pub last_completed: Option<SystemTime>,
+    /// Time of the last failure (retry or give-up) seen in the log.
+    pub last_failure: Option<SystemTime>,
+    /// The most recent events for this peer, oldest first.
+    pub events: VecDeque<TimedEvent>,
+}
+
+/// Interface-wide counters of invalid traffic and load events.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TrafficCounters {
+    /// Handshake packets dropped because of an invalid MAC.
+    pub invalid_mac: u64,
+    /// Handshake initiations or responses that failed validation.
+    pub invalid_handshakes: u64,
+    /// Data packets dropped for a source address outside the peer's allowed IPs.
+    pub unallowed_source: u64,
+    /// Data packets dropped for not being IPv4 or IPv6.
+    pub not_ip: u64,
+    /// Cookie replies sent because the interface was under load.
+    pub cookie_replies_sent: u64,
+    /// Cookie replies received from peers that were under load.
+    pub cookie_replies_received: u64,
+    /// Time of the last cookie reply we sent.
+    pub last_under_load: Option<SystemTime>,
+}
+
+impl TrafficCounters {
+    /// Total number of dropped packets, handshake and data alike.
+    pub fn dropped(&self) -> u64 {
+        self.invalid_mac + self.invalid_handshakes + self.unallowed_source + self.not_ip
+    }
+
+    /// Whether the interface sent a cookie reply within `window` before `now`.
+    ///
+    /// The kernel only answers with cookies once it considers itself under load,
+    /// which in practice means a handshake flood against the listen port.
+    pub fn under_load(&self, now: SystemTime, window: Duration) -> bool {
+        self.last_under_load
+            .is_some_and(|last| now.duration_since(last).unwrap_or_default() <= window)
+    }
+}
+
+/// Per-interface handshake history assembled from kernel log lines.
+#[derive(Debug, Clone)]
+pub struct HandshakeHistory {
+    interface: InterfaceName,
+    max_events: usize,
+    peers: HashMap<u64, PeerHandshakeHistory>,
+    rejected: HashMap<HandshakeFailure, u64>,
+    rejected_by_endpoint: HashMap<SocketAddr, u64>,
+    counters: TrafficCounters,
+}
+
+impl HandshakeHistory {
+    /// Creates an empty history for `interface`, keeping at most `max_events`
+    /// recent events per peer.
+    pub fn new(interface: &InterfaceName, max_events: usize) -> Self {
+        Self {
+            interface: *interface,
+            max_events,
+            peers: HashMap::new(),
+            rejected: HashMap::new(),
+            rejected_by_endpoint: HashMap::new(),
+            counters: TrafficCounters::default(),
+        }
+    }
+
+    /// Parses and records a kernel log line, returning whether it was relevant.
+    pub fn record_line(&mut self, line: &str, time: SystemTime) -> bool {
+        match TraceLine::parse(line) {
+            Some(trace) if trace.interface == self.interface => {
+                self.record(trace.event, time);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records an already-parsed event.
+    pub fn record(&mut self, event: TraceEvent, time: SystemTime) {
+        let peer_id = match event {
+            TraceEvent::HandshakeRejected { reason, endpoint } => {
+                *self.rejected.entry(reason).or_default() += 1;
+                if let Some(endpoint) = endpoint {
+                    *self.rejected_by_endpoint.entry(endpoint).or_default() += 1;
+                }
+                match reason {
+                    HandshakeFailure::InvalidMac => self.counters.invalid_mac += 1,
+                    _ => self.counters.invalid_handshakes += 1,
+                }
+                return;
+            }
+            TraceEvent::CookieReplySent { .. } => {
+                self.counters.cookie_replies_sent += 1;
+                self.counters.last_under_load = Some(time);
+                return;
+            }
+            TraceEvent::CookieReplyReceived { .. } => {
+                self.counters.cookie_replies_received += 1;
+                return;
+            }
+            ref event => event.peer_id().expect("peer events always carry an id"),
+        };
+
+        let peer = self.peers.entry(peer_id).or_default();
+        if let Some(endpoint) = event.endpoint() {
+            peer.endpoint = Some(endpoint);
+        }
+        match event {
+            TraceEvent::HandshakeInitiationSent { .. } => peer.initiations_sent += 1,
+            TraceEvent::HandshakeInitiationReceived { .. } => peer.initiations_received += 1,
+            TraceEvent::KeypairCreated { .. } => {
+                peer.completed += 1;
+                peer.last_completed = Some(time);
+            }
+            TraceEvent::HandshakeRetry { .. } => {
+                peer.retries += 1;
+                peer.last_failure = Some(time);
+            }
+            TraceEvent::HandshakeGaveUp { .. } => {
+                peer.gave_up += 1;
+                peer.last_failure = Some(time);
+            }
+            TraceEvent::InvalidPacket { reason, .. } => {
+                peer.invalid_packets += 1;
+                match reason {
+                    DropReason::UnallowedSource(_) => self.counters.unallowed_source += 1,
+                    DropReason::NotIp => self.counters.not_ip += 1,
+                }
+            }
+            _ => {}
+        }
+
+        if self.max_events > 0 {
+            if peer.events.len() == self.max_events {
+                peer.events.pop_front();
+            }
+            peer.events.push_back(TimedEvent { time, event });
+        }
+    }
+
+    /// The interface this history belongs to.
+    pub fn interface(&self) -> &InterfaceName {
+        &self.interface
+    }
+
+    /// History of a peer by its kernel-internal id.
+    pub fn peer(&self, peer_id: u64) -> Option<&PeerHandshakeHistory> {
+        self.peers.get(&peer_id)
+    }
+
+    /// Iterates over all peers seen in the log by their kernel-internal id.
+    pub fn peers(&self) -> impl Iterator<Item = (u64, &PeerHandshakeHistory)> {
+        self.peers.iter().map(|(id, peer)| (*id, peer))
+    }
+
+    /// Number of handshake packets rejected for `reason` before reaching a peer.
+    pub fn rejected(&self, reason: HandshakeFailure) -> u64 {
+        self.rejected.get(&reason).copied().unwrap_or_default()
+    }
+
+    /// Interface-wide invalid traffic and load counters.
+    pub fn counters(&self) -> &TrafficCounters {
+        &self.counters
+    }
+
+    /// Number of rejected handshake packets per source endpoint.
+    pub fn rejected_by_endpoint(&self) -> &HashMap<SocketAddr, u64> {
+        &self.rejected_by_endpoint
+    }
+
+    /// Attributes the recorded history to public keys by matching endpoints against
+    /// the current peers of `device`.
+    ///
+    /// The kernel log only identifies peers by an internal id, so peers without a
+    /// known endpoint (or whose endpoint changed since) can't be resolved.
+    pub fn resolve(&self, device: &Device) -> HashMap<Key, &PeerHandshakeHistory> {
+        self.peers
+            .values()
+            .filter_map(|history| {
+                let endpoint = history.endpoint?;
+                device
+                    .peers
+                    .iter()
+                    .find(|peer| peer.config.endpoint == Some(endpoint))
+                    .map(|peer| (peer.config.public_key.clone(), history))
+            })
+            .collect()
+    }
+}
+
+/// A blocking reader over the kernel log (`/dev/kmsg`).
+///
+/// Each call to `next` waits for the next kernel message, yielding only the ones
+/// that parse as WireGuard handshake events.
+pub struct KernelLog {
+    reader: BufReader<File>,
+    buf: String,
+}
+
+impl KernelLog {
+    /// Opens `/dev/kmsg`, starting from the oldest message still in the ring buffer.
+    pub fn open() -> io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(File::open(KMSG_PATH)?),
+            buf: String::new(),
+        })
+    }
+}
+
+impl Iterator for KernelLog {
+    type Item = io::Result<TraceLine>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.buf.clear();
+            match self.reader.read_line(&mut self.buf) {
+                Ok(0) => return None,
+                Ok(_) => {
+                    if let Some(trace) = TraceLine::parse(&self.buf) {
+                        return Some(Ok(trace));
+                    }
+                }
+                // The ring buffer wrapped around while we were reading; keep going.
+                Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn wg0() -> InterfaceName {
+        "wg0".parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_kmsg_line() {
+        let line = "7,1234,567890,-;wireguard: wg0: Sending handshake initiation to peer 3 (10.0.0.1:51820)";
+        let trace = TraceLine::parse(line).unwrap();
+        assert_eq!(trace.interface, wg0());
+        assert_eq!(
+            trace.event,
+            TraceEvent::HandshakeInitiationSent {
+                peer_id: 3,
+                endpoint: Some("10.0.0.1:51820".parse().unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_and_give_up() {
+        let retry = "[  12.345678] wireguard: wg0: Handshake for peer 7 ([fd00::1]:51820) did not complete after 5 seconds, retrying (try 2)";
+        assert_eq!(
+            TraceLine::parse(retry).unwrap().event,
+            TraceEvent::HandshakeRetry {
+                peer_id: 7,
+                endpoint: Some("[fd00::1]:51820".parse().unwrap()),
+                attempt: 2,
+            }
+        );
+
+        let gave_up = "wireguard: wg0: Handshake for peer 7 ([fd00::1]:51820) did not complete after 20 attempts, giving up";
+        assert_eq!(
+            TraceLine::parse(gave_up).unwrap().event,
+            TraceEvent::HandshakeGaveUp {
+                peer_id: 7,
+                endpoint: Some("[fd00::1]:51820".parse().unwrap()),
+                attempts: 20,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_ignores_unrelated_lines() {
+        assert_eq!(TraceLine::parse("usb 1-1: new high-speed USB device"), None);
+        assert_eq!(
+            TraceLine::parse("wireguard: WireGuard 1.0.0 loaded. See www.wireguard.com"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_history_counters() {
+        let mut history = HandshakeHistory::new(&wg0(), 2);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let lines = [
+            "wireguard: wg0: Sending handshake initiation to peer 1 (10.0.0.1:51820)",
+            "wireguard: wg0: Handshake for peer 1 (10.0.0.1:51820) did not complete after 5 seconds, retrying (try 2)",
+            "wireguard: wg0: Sending handshake initiation to peer 1 (10.0.0.1:51820)",
+            "wireguard: wg0: Keypair 4 created for peer 1",
+            "wireguard: wg0: Invalid MAC of handshake, dropping packet from 192.0.2.9:4444",
+            "wireguard: wg1: Sending handshake initiation to peer 1 (10.0.0.2:51820)",
+        ];
+        let recorded = lines
+            .iter()
+            .filter(|line| history.record_line(line, now))
+            .count();
+        assert_eq!(recorded, 5);
+
+        let peer = history.peer(1).unwrap();
+        assert_eq!(peer.initiations_sent, 2);
+        assert_eq!(peer.retries, 1);
+        assert_eq!(peer.completed, 1);
+        assert_eq!(peer.last_completed, Some(now));
+        assert_eq!(peer.events.len(), 2);
+        assert_eq!(history.rejected(HandshakeFailure::InvalidMac), 1);
+        assert_eq!(
+            history
+                .rejected_by_endpoint()
+                .get(&"192.0.2.9:4444".parse().unwrap()),
+            Some(&1)
+        );
+    }
+
+    #[test]
+    fn test_under_load_detection() {
+        let mut history = HandshakeHistory::new(&wg0(), 0);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let lines = [
+            "wireguard: wg0: Sending cookie response for denied handshake message for 198.51.100.7:1234",
+            "wireguard: wg0: Packet has unallowed src IP (10.9.9.9) from peer 2 (10.0.0.2:51820)",
+            "wireguard: wg0: Packet is neither ipv4 nor ipv6 from peer 2 (10.0.0.2:51820)",
+        ];
+        for line in lines {
+            assert!(history.record_line(line, start));
+        }
+
+        let counters = history.counters();
+        assert_eq!(counters.cookie_replies_sent, 1);
+        assert_eq!(counters.unallowed_source, 1);
+        assert_eq!(counters.not_ip, 1);
+        assert_eq!(counters.dropped(), 2);
+        assert_eq!(history.peer(2).unwrap().invalid_packets, 2);
+
+        let window = Duration::from_secs(60);
+        assert!(counters.under_load(start + Duration::from_secs(30), window));
+        assert!(!counters.under_load(start + Duration::from_secs(120), window));
+    }
+}
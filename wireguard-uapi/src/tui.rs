@@ -0,0 +1,381 @@
+//! A live, `top`-like dashboard of the WireGuard interfaces on a backend.
+//!
+//! The left pane lists the interfaces, the right one shows a table of the selected
+//! interface's peers with their handshake age, current throughput and a sparkline
+//! of recent throughput. Run it with [`run`], or drive a [`Dashboard`] yourself to
+//! embed it in another ratatui application.
+use crate::{
+    clock::{Clock, SystemClock},
+    Backend, Device, InterfaceName, Key,
+};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, Cell, List, ListItem, ListState, Row, Sparkline, Table},
+    Frame,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Number of throughput samples kept per peer.
+const HISTORY_LEN: usize = 60;
+
+const SPARK_BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Default)]
+struct PeerHistory {
+    last: Option<(Instant, u64, u64)>,
+    // Combined rx + tx throughput in bytes per second, oldest first.
+    rates: VecDeque<u64>,
+    rx_rate: u64,
+    tx_rate: u64,
+}
+
+impl PeerHistory {
+    fn sample(&mut self, at: Instant, rx_bytes: u64, tx_bytes: u64) {
+        if let Some((last_at, last_rx, last_tx)) = self.last {
+            let elapsed = at.duration_since(last_at).as_secs_f64();
+            if elapsed > 0.0 {
+                // Counters go back to zero when a peer is re-added.
+                self.rx_rate = (rx_bytes.saturating_sub(last_rx) as f64 / elapsed) as u64;
+                self.tx_rate = (tx_bytes.saturating_sub(last_tx) as f64 / elapsed) as u64;
+                if self.rates.len() == HISTORY_LEN {
+                    self.rates.pop_front();
+                }
+                self.rates.push_back(self.rx_rate + self.tx_rate);
+            }
+        }
+        self.last = Some((at, rx_bytes, tx_bytes));
+    }
+}
+
+/// State of the dashboard: the latest device snapshots and the throughput history
+/// of their peers.
+pub struct Dashboard {
+    backend: Backend,
+    devices: Vec<Device>,
+    history: HashMap<(InterfaceName, Key), PeerHistory>,
+    selected: ListState,
+    clock: Arc<dyn Clock>,
+}
+
+impl Dashboard {
+    pub fn new(backend: Backend) -> Self {
+        Self::with_clock(backend, Arc::new(SystemClock))
+    }
+
+    /// A dashboard showing handshake ages as of `clock`.
+    pub fn with_clock(backend: Backend, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            backend,
+            devices: vec![],
+            history: HashMap::new(),
+            selected: ListState::default().with_selected(Some(0)),
+            clock,
+        }
+    }
+
+    /// Re-reads every interface from the backend and records a throughput sample
+    /// for each peer.
+    ///
+    /// Interfaces that disappear between listing and reading them are skipped.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let mut devices = vec![];
+        for name in Device::list(self.backend)? {
+            match Device::get(&name, self.backend) {
+                Ok(device) => devices.push(device),
+                Err(e) => log::debug!("skipping {}: {}", name, e),
+            }
+        }
+        devices.sort_by_key(|device| device.name.as_str_lossy().into_owned());
+
+        for device in &devices {
+            for peer in &device.peers {
+                self.history
+                    .entry((device.name, peer.config.public_key.clone()))
+                    .or_default()
+                    .sample(now, peer.stats.rx_bytes, peer.stats.tx_bytes);
+            }
+        }
+        // Forget peers that are gone.
+        self.history.retain(|(name, key), _| {
+            devices.iter().any(|device| {
+                device.name == *name
+                    && device
+                        .peers
+                        .iter()
+                        .any(|peer| peer.config.public_key == *key)
+            })
+        });
+
+        self.devices = devices;
+        if self.selected() >= self.devices.len() {
+            self.selected
+                .select(Some(self.devices.len().saturating_sub(1)));
+        }
+        Ok(())
+    }
+
+    fn selected(&self) -> usize {
+        self.selected.selected().unwrap_or(0)
+    }
+
+    /// Selects the next interface in the list.
+    pub fn next(&mut self) {
+        if !self.devices.is_empty() {
+            self.selected
+                .select(Some((self.selected() + 1) % self.devices.len()));
+        }
+    }
+
+    /// Selects the previous interface in the list.
+    pub fn previous(&mut self) {
+        if !self.devices.is_empty() {
+            let len = self.devices.len();
+            self.selected
+                .select(Some((self.selected() + len - 1) % len));
+        }
+    }
+
+    /// Draws the dashboard over the whole frame.
+    pub fn draw(&mut self, frame: &mut Frame) {
+        let [main, footer] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [interfaces, details] =
+            Layout::horizontal([Constraint::Length(20), Constraint::Min(0)]).areas(main);
+
+        let items: Vec<ListItem> = self
+            .devices
+            .iter()
+            .map(|device| ListItem::new(device.name.as_str_lossy().into_owned()))
+            .collect();
+        let list = List::new(items)
+            .block(Block::default().title("interfaces").borders(Borders::ALL))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, interfaces, &mut self.selected);
+        frame.render_widget(
+            ratatui::text::Line::from(" q: quit  ↑/↓: select interface"),
+            footer,
+        );
+
+        let device = match self.devices.get(self.selected()) {
+            Some(device) => device,
+            None => {
+                frame.render_widget(
+                    Block::default()
+                        .title("no interfaces")
+                        .borders(Borders::ALL),
+                    details,
+                );
+                return;
+            }
+        };
+
+        let [peers, total] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(5)]).areas(details);
+
+        let now = self.clock.now();
+        let mut total_rates = vec![0u64; HISTORY_LEN];
+        let rows: Vec<Row> = device
+            .peers
+            .iter()
+            .map(|peer| {
+                let history = self
+                    .history
+                    .get(&(device.name, peer.config.public_key.clone()));
+                let (rx_rate, tx_rate, rates) = match history {
+                    Some(history) => (history.rx_rate, history.tx_rate, &history.rates),
+                    None => (0, 0, &VecDeque::new() as &VecDeque<u64>),
+                };
+                // Right-align the samples so the newest ones line up across peers.
+                let offset = HISTORY_LEN - rates.len();
+                for (i, rate) in rates.iter().enumerate() {
+                    total_rates[offset + i] += rate;
+                }
+
+                let key = peer.config.public_key.to_base64();
+                Row::new(vec![
+                    Cell::from(key[..12].to_string()),
+                    Cell::from(
+                        peer.config
+                            .endpoint
+                            .map(|endpoint| endpoint.to_string())
+                            .unwrap_or_else(|| "(none)".into()),
+                    ),
+                    Cell::from(handshake_age(peer.stats.last_handshake_time, now)),
+                    Cell::from(format!("↓{}", format_rate(rx_rate))),
+                    Cell::from(format!("↑{}", format_rate(tx_rate))),
+                    Cell::from(text_sparkline(rates, 20)),
+                ])
+            })
+            .collect();
+
+        let header = Row::new(vec![
+            "peer",
+            "endpoint",
+            "handshake",
+            "rx",
+            "tx",
+            "throughput",
+        ])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(13),
+                Constraint::Min(22),
+                Constraint::Length(12),
+                Constraint::Length(12),
+                Constraint::Length(12),
+                Constraint::Length(20),
+            ],
+        )
+        .header(header)
+        .block(
+            Block::default()
+                .title(format!(
+                    "{} ({} peers)",
+                    device.name.as_str_lossy(),
+                    device.peers.len()
+                ))
+                .borders(Borders::ALL),
+        );
+        frame.render_widget(table, peers);
+
+        let sparkline = Sparkline::default()
+            .block(
+                Block::default()
+                    .title(format!(
+                        "total {}",
+                        format_rate(total_rates.last().copied().unwrap_or(0))
+                    ))
+                    .borders(Borders::ALL),
+            )
+            .data(&total_rates)
+            .style(Style::default().fg(Color::Cyan));
+        frame.render_widget(sparkline, total);
+    }
+}
+
+/// Runs the dashboard in the current terminal until `q` or `Esc` is pressed,
+/// re-reading the interfaces every `interval`.
+pub fn run(backend: Backend, interval: Duration) -> io::Result<()> {
+    let mut dashboard = Dashboard::new(backend);
+    dashboard.refresh()?;
+
+    let mut terminal = ratatui::init();
+    let result = (|| {
+        let mut last_refresh = Instant::now();
+        loop {
+            terminal.draw(|frame| dashboard.draw(frame))?;
+
+            let timeout = interval.saturating_sub(last_refresh.elapsed());
+            if event::poll(timeout)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        match key.code {
+                            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                            KeyCode::Down | KeyCode::Char('j') => dashboard.next(),
+                            KeyCode::Up | KeyCode::Char('k') => dashboard.previous(),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            if last_refresh.elapsed() >= interval {
+                dashboard.refresh()?;
+                last_refresh = Instant::now();
+            }
+        }
+    })();
+    ratatui::restore();
+    result
+}
+
+fn handshake_age(last_handshake: Option<SystemTime>, now: SystemTime) -> String {
+    // The kernel reports peers that never completed a handshake as the epoch.
+    let last_handshake = match last_handshake {
+        Some(time) if time > SystemTime::UNIX_EPOCH => time,
+        _ => return "never".into(),
+    };
+    let secs = now
+        .duration_since(last_handshake)
+        .unwrap_or_default()
+        .as_secs();
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m {}s ago", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h {}m ago", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn format_rate(bytes_per_sec: u64) -> String {
+    let mut value = bytes_per_sec as f64;
+    for unit in ["B/s", "KiB/s", "MiB/s", "GiB/s"] {
+        if value < 1024.0 {
+            return format!("{:.1} {}", value, unit);
+        }
+        value /= 1024.0;
+    }
+    format!("{:.1} TiB/s", value)
+}
+
+/// Renders the last `width` samples as a row of block characters scaled to their
+/// maximum.
+fn text_sparkline(samples: &VecDeque<u64>, width: usize) -> String {
+    let skip = samples.len().saturating_sub(width);
+    let max = samples.iter().skip(skip).copied().max().unwrap_or(0);
+    samples
+        .iter()
+        .skip(skip)
+        .map(|&sample| {
+            let level = (sample * (SPARK_BARS.len() as u64 - 1))
+                .checked_div(max)
+                .unwrap_or(0);
+            SPARK_BARS[level as usize]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_history_rates() {
+        let start = Instant::now();
+        let mut history = PeerHistory::default();
+        history.sample(start, 1000, 500);
+        assert!(history.rates.is_empty());
+
+        history.sample(start + Duration::from_secs(2), 3048, 1524);
+        assert_eq!((history.rx_rate, history.tx_rate), (1024, 512));
+        assert_eq!(history.rates, [1536]);
+
+        // A reset counter yields a zero rate rather than underflowing.
+        history.sample(start + Duration::from_secs(3), 0, 0);
+        assert_eq!(history.rates, [1536, 0]);
+    }
+
+    #[test]
+    fn test_formatting() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(handshake_age(None, now), "never");
+        assert_eq!(handshake_age(Some(SystemTime::UNIX_EPOCH), now), "never");
+        assert_eq!(
+            handshake_age(Some(now - Duration::from_secs(65)), now),
+            "1m 5s ago"
+        );
+        assert_eq!(format_rate(512), "512.0 B/s");
+        assert_eq!(format_rate(1536), "1.5 KiB/s");
+        assert_eq!(text_sparkline(&VecDeque::from(vec![0, 7, 14]), 20), "▁▄█");
+    }
+}
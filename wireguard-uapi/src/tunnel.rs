@@ -0,0 +1,235 @@
+//! Handles to single peers, for applications managing their own.
+//!
+//! Instead of building [`DeviceUpdate`]s and picking peers out of [`Device`]
+//! snapshots, an application [connects](Tunnel::connect) a peer and gets a
+//! [`PeerHandle`] to wait for its handshake, read its statistics and move its
+//! endpoint. Dropping the handle removes the peer from the interface, unless it
+//! was [detached](PeerHandle::detach), so a peer lives as long as the object
+//! that uses it.
+use crate::{
+    clock::{Clock, SystemClock},
+    Backend, Device, DeviceUpdate, Error, InterfaceName, Key, PeerConfigBuilder, PeerStats,
+};
+
+use std::{
+    io,
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// An interface to connect peers on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tunnel {
+    iface: InterfaceName,
+    backend: Backend,
+}
+
+impl Tunnel {
+    pub fn new(iface: &InterfaceName, backend: Backend) -> Self {
+        Self {
+            iface: *iface,
+            backend,
+        }
+    }
+
+    pub fn name(&self) -> &InterfaceName {
+        &self.iface
+    }
+
+    /// The interface as it is now.
+    pub fn device(&self) -> Result<Device, Error> {
+        Device::get(&self.iface, self.backend)
+    }
+
+    /// Adds `peer` to the interface and returns a handle that removes it when
+    /// dropped.
+    ///
+    /// A peer the interface has already is updated instead, and left on the
+    /// interface when the handle is dropped, as something else put it there.
+    pub fn connect(&self, peer: PeerConfigBuilder) -> Result<PeerHandle, Error> {
+        self.connect_with_clock(peer, &SystemClock)
+    }
+
+    /// Like [`connect`](Self::connect), taking the time the peer was connected,
+    /// after which [`PeerHandle::wait_for_handshake`] waits for a handshake, from
+    /// `clock`.
+    pub fn connect_with_clock(
+        &self,
+        peer: PeerConfigBuilder,
+        clock: &dyn Clock,
+    ) -> Result<PeerHandle, Error> {
+        // Whole seconds, as the `wg` backend reports handshake times.
+        let now = clock.now();
+        let connected = UNIX_EPOCH
+            + Duration::from_secs(now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+        let public_key = peer.public_key.clone();
+        let existed = match self.device() {
+            Ok(device) => device
+                .peers
+                .iter()
+                .any(|peer| peer.config.public_key == public_key),
+            Err(Error::InterfaceNotFound(_)) => false,
+            Err(e) => return Err(e),
+        };
+        DeviceUpdate::new()
+            .add_peer(peer)
+            .apply(&self.iface, self.backend)?;
+        Ok(PeerHandle {
+            tunnel: *self,
+            public_key,
+            connected,
+            remove_on_drop: !existed,
+        })
+    }
+
+    fn remove(&self, public_key: &Key) -> Result<(), Error> {
+        DeviceUpdate::new()
+            .remove_peer_by_key(public_key)
+            .apply(&self.iface, self.backend)
+    }
+}
+
+/// A peer connected through a [`Tunnel`], removed from the interface when the
+/// handle is dropped.
+#[derive(Debug)]
+pub struct PeerHandle {
+    tunnel: Tunnel,
+    public_key: Key,
+    connected: SystemTime,
+    remove_on_drop: bool,
+}
+
+impl PeerHandle {
+    pub fn public_key(&self) -> &Key {
+        &self.public_key
+    }
+
+    pub fn tunnel(&self) -> &Tunnel {
+        &self.tunnel
+    }
+
+    /// The statistics of the peer now. Fails with an [`Error::Io`] of kind
+    /// [`NotFound`](io::ErrorKind::NotFound) if something else removed it from the
+    /// interface.
+    pub fn stats(&self) -> Result<PeerStats, Error> {
+        PeerStats::get_all(&self.tunnel.iface, self.tunnel.backend)?
+            .into_iter()
+            .find(|(public_key, _)| *public_key == self.public_key)
+            .map(|(_, stats)| stats)
+            .ok_or_else(|| {
+                Error::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("peer is no longer on {}", self.tunnel.iface),
+                ))
+            })
+    }
+
+    /// Blocks until the peer completes a handshake after it was connected,
+    /// returning when, like [`Device::wait_for_handshake`].
+    pub fn wait_for_handshake(&self, timeout: Duration) -> Result<SystemTime, Error> {
+        Device::wait_for_handshake_since(
+            &self.tunnel.iface,
+            &self.public_key,
+            self.connected,
+            timeout,
+            self.tunnel.backend,
+        )
+    }
+
+    /// Points the peer at a new endpoint.
+    pub fn set_endpoint(&self, endpoint: SocketAddr) -> Result<(), Error> {
+        self.update(|peer| peer.set_endpoint(endpoint))
+    }
+
+    /// Changes other settings of the peer, e.g.
+    /// `handle.update(|peer| peer.set_persistent_keepalive_interval(25))`.
+    pub fn update(
+        &self,
+        change: impl FnOnce(PeerConfigBuilder) -> PeerConfigBuilder,
+    ) -> Result<(), Error> {
+        DeviceUpdate::new()
+            .add_peer(change(PeerConfigBuilder::new(&self.public_key)))
+            .apply(&self.tunnel.iface, self.tunnel.backend)
+    }
+
+    /// Removes the peer now, returning the error that dropping the handle would
+    /// only log.
+    pub fn remove(mut self) -> Result<(), Error> {
+        self.remove_on_drop = false;
+        self.tunnel.remove(&self.public_key)
+    }
+
+    /// Gives up the handle, leaving the peer on the interface.
+    pub fn detach(mut self) -> Key {
+        self.remove_on_drop = false;
+        self.public_key.clone()
+    }
+}
+
+impl Drop for PeerHandle {
+    fn drop(&mut self) {
+        if !self.remove_on_drop {
+            return;
+        }
+        if let Err(e) = self.tunnel.remove(&self.public_key) {
+            log::warn!(
+                "couldn't remove peer {} from {}: {}",
+                self.public_key.to_base64(),
+                self.tunnel.iface,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    #[cfg(feature = "mock")]
+    fn test_peer_handle() {
+        use super::*;
+        use crate::backends::mock;
+
+        let iface: InterfaceName = "mock-tunnel".parse().unwrap();
+        let tunnel = Tunnel::new(&iface, Backend::Mock);
+        let peers = || tunnel.device().unwrap().peers;
+
+        let handle = tunnel
+            .connect(PeerConfigBuilder::new(&Key([1; 32])))
+            .unwrap();
+        assert_eq!(handle.stats().unwrap(), PeerStats::default());
+        let error = handle.wait_for_handshake(Duration::ZERO).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        let now = SystemTime::now();
+        let stats = PeerStats {
+            last_handshake_time: Some(now),
+            rx_bytes: 148,
+            tx_bytes: 92,
+        };
+        mock::set_stats(&iface, &Key([1; 32]), stats).unwrap();
+        assert_eq!(handle.wait_for_handshake(Duration::ZERO).unwrap(), now);
+
+        let endpoint = "192.0.2.1:51820".parse().unwrap();
+        handle.set_endpoint(endpoint).unwrap();
+        assert_eq!(peers()[0].config.endpoint, Some(endpoint));
+
+        drop(handle);
+        assert!(peers().is_empty());
+
+        let detached = tunnel
+            .connect(PeerConfigBuilder::new(&Key([2; 32])))
+            .unwrap();
+        assert_eq!(detached.detach(), Key([2; 32]));
+        assert_eq!(peers().len(), 1);
+
+        // A peer that was there before stays after its handle is dropped.
+        let existing = tunnel
+            .connect(PeerConfigBuilder::new(&Key([2; 32])).set_persistent_keepalive_interval(25))
+            .unwrap();
+        drop(existing);
+        assert_eq!(peers().len(), 1);
+        assert_eq!(peers()[0].config.persistent_keepalive_interval, Some(25));
+        tunnel.device().unwrap().delete().unwrap();
+    }
+}
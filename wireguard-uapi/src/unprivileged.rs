@@ -0,0 +1,253 @@
+//! A degraded, read-only view of interfaces for processes without `CAP_NET_ADMIN`.
+//!
+//! Reading a kernel interface over netlink needs privileges, which status UIs and
+//! monitoring agents often don't have. [`read`] still tries the full read first
+//! and, when it is denied, falls back to what sysfs and procfs tell anyone: that
+//! the interface exists, its link state, MTU and traffic counters, and the
+//! listen port when it can be attributed unambiguously. Keys, the fwmark and the
+//! peers stay [unavailable](LinkInfo::unavailable).
+use crate::{backends, Backend, Device, Error, InterfaceName};
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+const PROC_NET: &str = "/proc/net";
+
+/// The traffic counters of a network link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LinkStats {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+}
+
+/// What is known about an interface without privileges. `None` marks a field that
+/// couldn't be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkInfo {
+    pub name: InterfaceName,
+    /// Whether the link is up, from its operational state.
+    pub up: Option<bool>,
+    pub mtu: Option<u32>,
+    pub stats: Option<LinkStats>,
+    pub listen_port: Option<u16>,
+}
+
+impl LinkInfo {
+    /// The names of the [`Device`] fields this reading lacks.
+    pub fn unavailable(&self) -> Vec<&'static str> {
+        let mut fields = vec!["public_key", "private_key", "fwmark", "peers"];
+        if self.listen_port.is_none() {
+            fields.insert(0, "listen_port");
+        }
+        fields
+    }
+}
+
+/// The result of [`read`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reading {
+    /// The interface could be read completely.
+    Full(Device),
+    /// Reading was denied, so only the unprivileged information is available.
+    Degraded(LinkInfo),
+}
+
+/// Whether the `uevent` file of a link says it is a WireGuard interface.
+fn is_wireguard(uevent: &str) -> bool {
+    uevent
+        .lines()
+        .any(|line| line.trim() == "DEVTYPE=wireguard")
+}
+
+/// The ports of the kernel-owned UDP sockets in a `/proc/net/udp` or `udp6` table.
+///
+/// WireGuard's kernel sockets belong to no process, which shows as inode 0.
+fn kernel_udp_ports(table: &str) -> Vec<u16> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let local = fields.get(1)?;
+            let inode = fields.get(9)?;
+            let (_, port) = local.rsplit_once(':')?;
+            let port = u16::from_str_radix(port, 16).ok()?;
+            (*inode == "0").then_some(port)
+        })
+        .collect()
+}
+
+fn read_number<T: std::str::FromStr>(path: &Path) -> Option<T> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn link_stats(dir: &Path) -> Option<LinkStats> {
+    let stat = |name: &str| read_number(&dir.join("statistics").join(name));
+    Some(LinkStats {
+        rx_bytes: stat("rx_bytes")?,
+        tx_bytes: stat("tx_bytes")?,
+        rx_packets: stat("rx_packets")?,
+        tx_packets: stat("tx_packets")?,
+        rx_errors: stat("rx_errors")?,
+        tx_errors: stat("tx_errors")?,
+    })
+}
+
+/// Where sysfs and procfs are read from, which tests point elsewhere.
+#[derive(Debug, Clone)]
+struct Roots {
+    sys_class_net: PathBuf,
+    proc_net: PathBuf,
+}
+
+impl Default for Roots {
+    fn default() -> Self {
+        Self {
+            sys_class_net: PathBuf::from(SYS_CLASS_NET),
+            proc_net: PathBuf::from(PROC_NET),
+        }
+    }
+}
+
+impl Roots {
+    fn kernel_interfaces(&self) -> io::Result<Vec<InterfaceName>> {
+        let mut names: Vec<InterfaceName> = vec![];
+        for entry in fs::read_dir(&self.sys_class_net)? {
+            let path = entry?.path();
+            let uevent = fs::read_to_string(path.join("uevent")).unwrap_or_default();
+            if !is_wireguard(&uevent) {
+                continue;
+            }
+            if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                names.push(name.parse()?);
+            }
+        }
+        names.sort_by_key(|name| name.as_str_lossy().into_owned());
+        Ok(names)
+    }
+
+    /// The listen port of the only kernel interface, if there is exactly one
+    /// kernel-owned UDP port. With more interfaces, ports can't be attributed.
+    fn listen_port(&self, interfaces: usize) -> Option<u16> {
+        if interfaces != 1 {
+            return None;
+        }
+        let mut ports: Vec<u16> = ["udp", "udp6"]
+            .iter()
+            .filter_map(|table| fs::read_to_string(self.proc_net.join(table)).ok())
+            .flat_map(|table| kernel_udp_ports(&table))
+            .collect();
+        ports.sort_unstable();
+        ports.dedup();
+        match ports[..] {
+            [port] => Some(port),
+            _ => None,
+        }
+    }
+
+    fn link_info(&self, name: &InterfaceName) -> io::Result<LinkInfo> {
+        let dir = self.sys_class_net.join(name.as_str_lossy().as_ref());
+        if !dir.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("interface {} not found", name),
+            ));
+        }
+        let up = fs::read_to_string(dir.join("operstate"))
+            .ok()
+            .map(|state| matches!(state.trim(), "up" | "unknown"));
+        Ok(LinkInfo {
+            name: *name,
+            up,
+            mtu: read_number(&dir.join("mtu")),
+            stats: link_stats(&dir),
+            listen_port: self.listen_port(self.kernel_interfaces()?.len()),
+        })
+    }
+}
+
+/// Lists the WireGuard interfaces without privileges: kernel interfaces from
+/// sysfs, and userspace ones from their socket directory.
+pub fn list() -> io::Result<Vec<InterfaceName>> {
+    let mut names = Roots::default().kernel_interfaces()?;
+    for name in backends::userspace::enumerate().unwrap_or_default() {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// Reads `name` from `backend`, falling back to the unprivileged [`LinkInfo`] if
+/// the read is denied.
+pub fn read(name: &InterfaceName, backend: Backend) -> io::Result<Reading> {
+    match Device::get(name, backend) {
+        Ok(device) => Ok(Reading::Full(device)),
+        Err(Error::PermissionDenied(_)) => Roots::default().link_info(name).map(Reading::Degraded),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UDP: &str = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  1: 00000000:CA6C 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 0 2 0000000000000000 0
+  2: 0100007F:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000   101        0 23456 2 0000000000000000 0
+";
+
+    #[test]
+    fn test_kernel_udp_ports() {
+        assert_eq!(kernel_udp_ports(UDP), [51820]);
+    }
+
+    #[test]
+    fn test_link_info() {
+        let root = std::env::temp_dir().join(format!("wg-unprivileged-{}", std::process::id()));
+        let roots = Roots {
+            sys_class_net: root.join("sys"),
+            proc_net: root.join("proc"),
+        };
+        let wg0 = roots.sys_class_net.join("wg0");
+        let eth0 = roots.sys_class_net.join("eth0");
+        fs::create_dir_all(wg0.join("statistics")).unwrap();
+        fs::create_dir_all(&eth0).unwrap();
+        fs::create_dir_all(&roots.proc_net).unwrap();
+        fs::write(wg0.join("uevent"), "DEVTYPE=wireguard\nINTERFACE=wg0\n").unwrap();
+        fs::write(eth0.join("uevent"), "INTERFACE=eth0\n").unwrap();
+        fs::write(wg0.join("operstate"), "unknown\n").unwrap();
+        fs::write(wg0.join("mtu"), "1420\n").unwrap();
+        for (stat, value) in [
+            ("rx_bytes", "2048"),
+            ("tx_bytes", "512"),
+            ("rx_packets", "4"),
+            ("tx_packets", "2"),
+            ("rx_errors", "0"),
+            ("tx_errors", "0"),
+        ] {
+            fs::write(wg0.join("statistics").join(stat), value).unwrap();
+        }
+        fs::write(roots.proc_net.join("udp"), UDP).unwrap();
+
+        let name: InterfaceName = "wg0".parse().unwrap();
+        assert_eq!(roots.kernel_interfaces().unwrap(), [name]);
+        let info = roots.link_info(&name).unwrap();
+        assert_eq!(info.up, Some(true));
+        assert_eq!(info.mtu, Some(1420));
+        assert_eq!(info.stats.unwrap().rx_bytes, 2048);
+        assert_eq!(info.listen_port, Some(51820));
+        assert!(!info.unavailable().contains(&"listen_port"));
+
+        let missing = roots.link_info(&"wg1".parse().unwrap()).unwrap_err();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
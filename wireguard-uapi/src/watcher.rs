@@ -0,0 +1,319 @@
+//! Typed peer events from periodic snapshots of an interface.
+//!
+//! The backends only expose the current state of an interface, so dashboards and
+//! keepalive logic end up polling it and diffing consecutive snapshots. A
+//! [`PeerWatcher`] does that once: it reads the interface every interval and
+//! yields a [`PeerEvent`] for each peer that was added, removed, handshook, moved
+//! to another endpoint or passed traffic. Changes that happen and revert between
+//! two reads aren't seen, so the interval bounds both latency and resolution.
+//!
+//! Async applications sharing snapshots between tasks can use the registry's
+//! device events instead, with the `tokio` feature.
+use crate::{Backend, Device, InterfaceName, Key, PeerInfo};
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// A change to a peer between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    PeerAdded {
+        iface: InterfaceName,
+        peer: PeerInfo,
+    },
+    PeerRemoved {
+        iface: InterfaceName,
+        public_key: Key,
+    },
+    HandshakeCompleted {
+        iface: InterfaceName,
+        public_key: Key,
+        time: SystemTime,
+    },
+    EndpointChanged {
+        iface: InterfaceName,
+        public_key: Key,
+        old: Option<SocketAddr>,
+        new: Option<SocketAddr>,
+    },
+    /// The peer transferred data since the previous snapshot.
+    TrafficObserved {
+        iface: InterfaceName,
+        public_key: Key,
+        rx_bytes: u64,
+        tx_bytes: u64,
+    },
+}
+
+/// How a peer's statistics changed between two snapshots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StatsDelta {
+    /// Bytes received since the previous snapshot.
+    pub rx_bytes: u64,
+    /// Bytes sent since the previous snapshot.
+    pub tx_bytes: u64,
+    /// The time of a handshake completed since the previous snapshot, if any.
+    pub handshake: Option<SystemTime>,
+}
+
+/// A peer as found in two snapshots, by [`peer_changes`].
+pub(crate) enum PeerChange<'a> {
+    Added(&'a PeerInfo),
+    Removed(&'a Key),
+    /// The peer is in both snapshots, which may or may not differ.
+    Kept {
+        old: &'a PeerInfo,
+        new: &'a PeerInfo,
+        delta: StatsDelta,
+    },
+}
+
+/// Matches the peers of `old` and `new` by key: the peers of `new` in order,
+/// then the peers removed from `old`. Both [`diff`] and the registry's device
+/// events are built from this.
+pub(crate) fn peer_changes<'a>(old: Option<&'a Device>, new: &'a Device) -> Vec<PeerChange<'a>> {
+    let old_peers = old.map_or(&[][..], |device| &device.peers[..]);
+    let by_key: HashMap<&Key, &PeerInfo> = old_peers
+        .iter()
+        .map(|peer| (&peer.config.public_key, peer))
+        .collect();
+    let mut changes = Vec::with_capacity(new.peers.len());
+    for peer in &new.peers {
+        let Some(&old) = by_key.get(&peer.config.public_key) else {
+            changes.push(PeerChange::Added(peer));
+            continue;
+        };
+        let delta = |old: u64, new: u64| new.checked_sub(old).unwrap_or(new);
+        let delta = StatsDelta {
+            rx_bytes: delta(old.stats.rx_bytes, peer.stats.rx_bytes),
+            tx_bytes: delta(old.stats.tx_bytes, peer.stats.tx_bytes),
+            handshake: peer
+                .stats
+                .last_handshake_time
+                .filter(|_| peer.stats.last_handshake_time != old.stats.last_handshake_time),
+        };
+        changes.push(PeerChange::Kept {
+            old,
+            new: peer,
+            delta,
+        });
+    }
+    let kept: HashSet<&Key> = new
+        .peers
+        .iter()
+        .map(|peer| &peer.config.public_key)
+        .collect();
+    for old in old_peers {
+        if !kept.contains(&old.config.public_key) {
+            changes.push(PeerChange::Removed(&old.config.public_key));
+        }
+    }
+    changes
+}
+
+/// The events that turn `old` into `new`. Without an old snapshot, every peer
+/// counts as added.
+///
+/// Counters that went down (e.g. the peer was removed and re-added in between)
+/// are treated as reset, so the traffic is the new value.
+pub fn diff(old: Option<&Device>, new: &Device) -> Vec<PeerEvent> {
+    let iface = new.name;
+    let mut events = vec![];
+    for change in peer_changes(old, new) {
+        let (old, peer, delta) = match change {
+            PeerChange::Added(peer) => {
+                events.push(PeerEvent::PeerAdded {
+                    iface,
+                    peer: peer.clone(),
+                });
+                continue;
+            }
+            PeerChange::Removed(public_key) => {
+                events.push(PeerEvent::PeerRemoved {
+                    iface,
+                    public_key: public_key.clone(),
+                });
+                continue;
+            }
+            PeerChange::Kept { old, new, delta } => (old, new, delta),
+        };
+
+        let public_key = &peer.config.public_key;
+        if old.config.endpoint != peer.config.endpoint {
+            events.push(PeerEvent::EndpointChanged {
+                iface,
+                public_key: public_key.clone(),
+                old: old.config.endpoint,
+                new: peer.config.endpoint,
+            });
+        }
+        if let Some(time) = delta.handshake {
+            events.push(PeerEvent::HandshakeCompleted {
+                iface,
+                public_key: public_key.clone(),
+                time,
+            });
+        }
+        if delta.rx_bytes > 0 || delta.tx_bytes > 0 {
+            events.push(PeerEvent::TrafficObserved {
+                iface,
+                public_key: public_key.clone(),
+                rx_bytes: delta.rx_bytes,
+                tx_bytes: delta.tx_bytes,
+            });
+        }
+    }
+    events
+}
+
+/// Polls an interface and yields the changes to its peers.
+///
+/// Iterating blocks until the next event. The peers present at the first read
+/// are reported as added, so consumers start out with the full state.
+#[derive(Debug)]
+pub struct PeerWatcher {
+    iface: InterfaceName,
+    backend: Backend,
+    interval: Duration,
+    last: Option<Device>,
+    next_poll: Option<Instant>,
+    pending: VecDeque<PeerEvent>,
+}
+
+impl PeerWatcher {
+    /// Watches `iface`, reading it every 5 seconds.
+    pub fn new(iface: &InterfaceName, backend: Backend) -> Self {
+        Self {
+            iface: *iface,
+            backend,
+            interval: Duration::from_secs(5),
+            last: None,
+            next_poll: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn set_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Diffs `device` against the previous snapshot, which it then replaces.
+    pub fn observe(&mut self, device: Device) -> Vec<PeerEvent> {
+        let events = diff(self.last.as_ref(), &device);
+        self.last = Some(device);
+        events
+    }
+
+    /// Reads the interface now and returns what changed since the previous read.
+    pub fn poll(&mut self) -> io::Result<Vec<PeerEvent>> {
+        let device = Device::get(&self.iface, self.backend)?;
+        Ok(self.observe(device))
+    }
+
+    /// Blocks until the next event, reading the interface every interval.
+    pub fn next_event(&mut self) -> io::Result<PeerEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            if let Some(next_poll) = self.next_poll {
+                thread::sleep(next_poll.saturating_duration_since(Instant::now()));
+            }
+            self.next_poll = Some(Instant::now() + self.interval);
+            let events = self.poll()?;
+            self.pending.extend(events);
+        }
+    }
+}
+
+impl Iterator for PeerWatcher {
+    type Item = io::Result<PeerEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_event())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn device(peers: &[(u8, &str, Option<u64>, u64)]) -> Device {
+        let peers = peers
+            .iter()
+            .map(|&(key, endpoint, handshake, rx_bytes)| {
+                let mut peer = PeerInfo::fixture(Key([key; 32]))
+                    .with_endpoint(endpoint)
+                    .with_transfer(rx_bytes, 0);
+                peer.stats.last_handshake_time =
+                    handshake.map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+                peer
+            })
+            .collect();
+        Device {
+            listen_port: Some(51820),
+            ..Device::fixture("wg0", peers)
+        }
+    }
+
+    #[test]
+    fn test_observe() {
+        let iface: InterfaceName = "wg0".parse().unwrap();
+        let mut watcher = PeerWatcher::new(&iface, Backend::Userspace);
+        let first = device(&[(1, "192.0.2.1:51820", None, 0)]);
+        assert_eq!(
+            watcher.observe(first.clone()),
+            vec![PeerEvent::PeerAdded {
+                iface,
+                peer: first.peers[0].clone(),
+            }]
+        );
+        assert!(watcher.observe(first).is_empty());
+
+        let events = watcher.observe(device(&[(1, "192.0.2.9:4500", Some(100), 500)]));
+        assert_eq!(
+            events,
+            vec![
+                PeerEvent::EndpointChanged {
+                    iface,
+                    public_key: Key([1; 32]),
+                    old: Some("192.0.2.1:51820".parse().unwrap()),
+                    new: Some("192.0.2.9:4500".parse().unwrap()),
+                },
+                PeerEvent::HandshakeCompleted {
+                    iface,
+                    public_key: Key([1; 32]),
+                    time: UNIX_EPOCH + Duration::from_secs(100),
+                },
+                PeerEvent::TrafficObserved {
+                    iface,
+                    public_key: Key([1; 32]),
+                    rx_bytes: 500,
+                    tx_bytes: 0,
+                },
+            ]
+        );
+
+        let second = device(&[(2, "192.0.2.2:51820", None, 0)]);
+        assert_eq!(
+            watcher.observe(second.clone()),
+            vec![
+                PeerEvent::PeerAdded {
+                    iface,
+                    peer: second.peers[0].clone(),
+                },
+                PeerEvent::PeerRemoved {
+                    iface,
+                    public_key: Key([1; 32]),
+                },
+            ]
+        );
+    }
+}
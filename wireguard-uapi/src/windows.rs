@@ -0,0 +1,513 @@
+//! Tunnels of the official WireGuard for Windows client.
+//!
+//! The client keeps one config per tunnel in
+//! `%ProgramFiles%\WireGuard\Data\Configurations\<name>.conf.dpapi`, encrypted with
+//! DPAPI for the LocalSystem account. On Windows, [`enumerate`] and [`read`]
+//! decrypt them, which only works from a process running as LocalSystem (e.g. a
+//! service, or `psexec -s`).
+//!
+//! Parsing is available on every platform, so plaintext configs (such as the
+//! client's "Export all tunnels to zip") can be converted when migrating hosts.
+//!
+//! A tunnel's [`NetworkPlan`] holds the host settings the client configures next
+//! to WireGuard itself: addresses, routes for the allowed IPs, DNS and MTU. On
+//! Windows, [`NetworkPlan::configure`] applies them to an adapter with a script
+//! of NetTCPIP, DnsClient and NetSecurity PowerShell cmdlets, which wrap the IP
+//! Helper API and Windows Firewall. That is enough for a tunnel to carry traffic.
+//!
+//! # Scope
+//!
+//! The client's "block untunneled traffic" kill switch is out of scope. It is a
+//! set of WFP filters owned by the tunnel service that permit the tunnel, its
+//! endpoints, DHCP and neighbor discovery and block everything else, and it has
+//! no counterpart in Windows Firewall rules, where block rules always win. Without
+//! it, traffic a default route would send through the tunnel leaves through the
+//! physical adapters while the tunnel is down, and [`NetworkPlan::configure`]
+//! warns about plans with a [default route](NetworkPlan::default_route).
+use crate::{conf::QuickConfig, DeviceUpdate, Key, PeerConfig, PeerConfigBuilder};
+
+use ipnet::IpNet;
+use std::{io, net::IpAddr};
+
+#[cfg(windows)]
+use std::{ffi::OsString, fs, path::PathBuf, process::Command};
+
+/// The file extension of encrypted tunnel configs.
+pub const ENCRYPTED_EXTENSION: &str = ".conf.dpapi";
+
+/// A tunnel config as stored by WireGuard for Windows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tunnel {
+    /// The tunnel name, which may be longer than an interface name allows.
+    pub name: String,
+    pub private_key: Option<Key>,
+    pub listen_port: Option<u16>,
+    pub addresses: Vec<IpNet>,
+    /// The `DNS` servers and search domains, verbatim.
+    pub dns: Vec<String>,
+    pub mtu: Option<u32>,
+    pub peers: Vec<PeerConfig>,
+    /// Endpoints given as host names, which the client resolves at connect time.
+    /// The matching peers have no [`endpoint`](PeerConfig::endpoint) set.
+    pub endpoint_hosts: Vec<(Key, String)>,
+}
+
+impl Tunnel {
+    /// Parses the plaintext config of the tunnel `name`, like [`QuickConfig`] does.
+    pub fn parse(name: &str, text: &str) -> io::Result<Self> {
+        let QuickConfig { update, interface } = text.parse()?;
+        Ok(Self {
+            name: name.to_string(),
+            private_key: update.private_key,
+            listen_port: update.listen_port,
+            addresses: interface.addresses,
+            dns: interface.dns,
+            mtu: interface.mtu,
+            peers: update
+                .peers
+                .into_iter()
+                .map(PeerConfigBuilder::into_peer_config)
+                .collect(),
+            endpoint_hosts: interface.endpoint_hosts,
+        })
+    }
+
+    /// An update that makes an interface match the tunnel's WireGuard settings.
+    ///
+    /// Addresses, DNS and MTU are host network settings, and peers listed in
+    /// [`endpoint_hosts`](Tunnel::endpoint_hosts) are added without an endpoint.
+    pub fn to_update(&self) -> DeviceUpdate {
+        let mut update = DeviceUpdate::new().replace_peers();
+        if let Some(key) = &self.private_key {
+            update = update.set_private_key(key.clone());
+        }
+        if let Some(port) = self.listen_port {
+            update = update.set_listen_port(port);
+        }
+        update.add_peers(
+            &self
+                .peers
+                .iter()
+                .cloned()
+                .map(PeerConfigBuilder::from_peer_config)
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+/// The host network settings of a tunnel, as the official client configures them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkPlan {
+    /// The name of the tunnel, used to name firewall rules.
+    pub name: String,
+    pub addresses: Vec<IpNet>,
+    /// The allowed IPs of every peer, each routed through the adapter.
+    pub routes: Vec<IpNet>,
+    /// The endpoints of the peers. With a [default route](Self::default_route),
+    /// each gets a host route through the current default gateway, so the
+    /// tunnel's own packets don't loop through it. Endpoints given as host names
+    /// are only known once resolved; add them here then.
+    pub endpoints: Vec<IpAddr>,
+    /// The DNS servers and search domains. Windows keeps one search domain per
+    /// adapter, its connection-specific suffix, so only the first domain is
+    /// applied and the rest are skipped with a warning.
+    pub dns: Vec<String>,
+    pub mtu: Option<u32>,
+    /// The port to let unsolicited WireGuard packets in on, with an inbound
+    /// Windows Firewall rule. This is the only firewall change.
+    pub listen_port: Option<u16>,
+    /// Whether a peer takes all traffic of a family (a `/0` allowed IP). The
+    /// adapter is then preferred over the physical ones.
+    ///
+    /// The client's "block untunneled traffic" filters are not installed, see
+    /// [the module docs](self#scope).
+    pub default_route: bool,
+}
+
+/// Quotes `s` as a PowerShell string literal.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+impl NetworkPlan {
+    fn firewall_rule(&self) -> String {
+        quote(&format!("WireGuard {}", self.name))
+    }
+
+    /// The PowerShell commands applying the plan to the adapter named `alias`.
+    pub fn powershell(&self, alias: &str) -> Vec<String> {
+        let alias = quote(alias);
+        let mut commands = vec![];
+        // While the physical adapter's default route is still the preferred one.
+        if self.default_route {
+            for &endpoint in &self.endpoints {
+                let default = if endpoint.is_ipv4() {
+                    "0.0.0.0/0"
+                } else {
+                    "::/0"
+                };
+                commands.push(format!(
+                    "$gateway = Get-NetRoute -DestinationPrefix {} -PolicyStore ActiveStore | Where-Object InterfaceAlias -ne {} | Sort-Object RouteMetric | Select-Object -First 1; \
+                     New-NetRoute -DestinationPrefix {} -InterfaceIndex $gateway.InterfaceIndex -NextHop $gateway.NextHop -PolicyStore ActiveStore",
+                    quote(default),
+                    alias,
+                    IpNet::from(endpoint)
+                ));
+            }
+        }
+        for address in &self.addresses {
+            commands.push(format!(
+                "New-NetIPAddress -InterfaceAlias {} -IPAddress {} -PrefixLength {} -PolicyStore ActiveStore",
+                alias,
+                address.addr(),
+                address.prefix_len()
+            ));
+        }
+        for route in &self.routes {
+            commands.push(format!(
+                "New-NetRoute -InterfaceAlias {} -DestinationPrefix {} -RouteMetric 0 -PolicyStore ActiveStore",
+                alias,
+                route.trunc()
+            ));
+        }
+        if self.default_route {
+            commands.push(format!(
+                "Set-NetIPInterface -InterfaceAlias {} -InterfaceMetric 0",
+                alias
+            ));
+        }
+        if let Some(mtu) = self.mtu {
+            commands.push(format!(
+                "Set-NetIPInterface -InterfaceAlias {} -NlMtuBytes {}",
+                alias, mtu
+            ));
+        }
+        let servers: Vec<_> = self
+            .dns
+            .iter()
+            .filter(|entry| entry.parse::<IpAddr>().is_ok())
+            .map(|server| quote(server))
+            .collect();
+        if !servers.is_empty() {
+            commands.push(format!(
+                "Set-DnsClientServerAddress -InterfaceAlias {} -ServerAddresses {}",
+                alias,
+                servers.join(",")
+            ));
+        }
+        let domains: Vec<_> = self
+            .dns
+            .iter()
+            .filter(|entry| entry.parse::<IpAddr>().is_err())
+            .map(|domain| quote(domain))
+            .collect();
+        if let Some(domain) = domains.first() {
+            commands.push(format!(
+                "Set-DnsClient -InterfaceAlias {} -ConnectionSpecificSuffix {}",
+                alias, domain
+            ));
+        }
+        if domains.len() > 1 {
+            log::warn!(
+                "tunnel {}: only the first of {} search domains is applied",
+                self.name,
+                domains.len()
+            );
+        }
+        if let Some(port) = self.listen_port {
+            commands.push(format!(
+                "New-NetFirewallRule -DisplayName {} -Direction Inbound -Protocol UDP -LocalPort {} -Action Allow -PolicyStore ActiveStore",
+                self.firewall_rule(),
+                port
+            ));
+        }
+        commands
+    }
+
+    /// The PowerShell commands removing what [`powershell`](Self::powershell)
+    /// set up outside the adapter: the firewall rule and the endpoint routes.
+    pub fn deconfigure_powershell(&self) -> Vec<String> {
+        let mut commands = vec![format!(
+            "Get-NetFirewallRule -DisplayName {} -PolicyStore ActiveStore -ErrorAction SilentlyContinue | Remove-NetFirewallRule",
+            self.firewall_rule()
+        )];
+        if self.default_route {
+            for &endpoint in &self.endpoints {
+                commands.push(format!(
+                    "Get-NetRoute -DestinationPrefix {} -PolicyStore ActiveStore -ErrorAction SilentlyContinue | Remove-NetRoute -Confirm:$false",
+                    IpNet::from(endpoint)
+                ));
+            }
+        }
+        commands
+    }
+
+    /// Applies the plan to the adapter named `alias`. Requires administrator
+    /// rights. Settings in the active store are lost on reboot, like the client's.
+    #[cfg(windows)]
+    pub fn configure(&self, alias: &str) -> io::Result<()> {
+        if self.default_route {
+            log::warn!(
+                "tunnel {}: no kill switch, traffic leaves untunneled while the tunnel is down",
+                self.name
+            );
+        }
+        run_powershell(
+            &self.powershell(alias),
+            &format!("configure adapter {}", alias),
+        )
+    }
+
+    /// Removes the firewall rule and the endpoint routes of the plan. Addresses
+    /// and the other routes go away with the adapter.
+    #[cfg(windows)]
+    pub fn deconfigure(&self) -> io::Result<()> {
+        run_powershell(
+            &self.deconfigure_powershell(),
+            &format!("deconfigure tunnel {}", self.name),
+        )
+    }
+}
+
+/// Runs `commands` as one script, stopping at the first that fails.
+#[cfg(windows)]
+fn run_powershell(commands: &[String], what: &str) -> io::Result<()> {
+    let mut script = String::from("$ErrorActionPreference = 'Stop'\n");
+    for command in commands {
+        script.push_str(command);
+        script.push('\n');
+    }
+    let output = Command::new("powershell.exe")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .output()?;
+    log::debug!("{}: {:?}", what, output.status.code());
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "failed to {}: {}",
+            what,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+impl Tunnel {
+    /// The host network settings of the tunnel.
+    pub fn network_plan(&self) -> NetworkPlan {
+        let routes: Vec<IpNet> = self
+            .peers
+            .iter()
+            .flat_map(|peer| &peer.allowed_ips)
+            .filter_map(|ip| IpNet::try_from(ip.clone()).ok())
+            .collect();
+        NetworkPlan {
+            name: self.name.clone(),
+            addresses: self.addresses.clone(),
+            default_route: routes.iter().any(|route| route.prefix_len() == 0),
+            routes,
+            endpoints: self
+                .peers
+                .iter()
+                .filter_map(|peer| Some(peer.endpoint?.ip()))
+                .collect(),
+            dns: self.dns.clone(),
+            mtu: self.mtu,
+            listen_port: self.listen_port,
+        }
+    }
+}
+
+#[cfg(windows)]
+mod dpapi {
+    use std::{ffi::c_void, io, ptr, slice};
+    use zeroize::{Zeroize, Zeroizing};
+
+    #[repr(C)]
+    struct DataBlob {
+        len: u32,
+        data: *mut u8,
+    }
+
+    const CRYPTPROTECT_UI_FORBIDDEN: u32 = 0x1;
+
+    #[link(name = "crypt32")]
+    extern "system" {
+        fn CryptUnprotectData(
+            data_in: *const DataBlob,
+            description: *mut *mut u16,
+            entropy: *const DataBlob,
+            reserved: *mut c_void,
+            prompt: *mut c_void,
+            flags: u32,
+            data_out: *mut DataBlob,
+        ) -> i32;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LocalFree(memory: *mut c_void) -> *mut c_void;
+    }
+
+    /// Decrypts a DPAPI blob for the current user. The plaintext is zeroed when
+    /// dropped, and so is the copy DPAPI allocated.
+    pub fn decrypt(blob: &[u8]) -> io::Result<Zeroizing<Vec<u8>>> {
+        let data_in = DataBlob {
+            len: blob.len() as u32,
+            data: blob.as_ptr() as *mut u8,
+        };
+        let mut data_out = DataBlob {
+            len: 0,
+            data: ptr::null_mut(),
+        };
+        let ok = unsafe {
+            CryptUnprotectData(
+                &data_in,
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                &mut data_out,
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let output = unsafe { slice::from_raw_parts_mut(data_out.data, data_out.len as usize) };
+        let plaintext = Zeroizing::new(output.to_vec());
+        output.zeroize();
+        unsafe { LocalFree(data_out.data as *mut c_void) };
+        Ok(plaintext)
+    }
+}
+
+/// The directory the client stores tunnel configs in.
+#[cfg(windows)]
+pub fn config_dir() -> PathBuf {
+    let program_files =
+        std::env::var_os("ProgramFiles").unwrap_or_else(|| OsString::from("C:\\Program Files"));
+    PathBuf::from(program_files)
+        .join("WireGuard")
+        .join("Data")
+        .join("Configurations")
+}
+
+/// The names of the tunnels registered with the client, sorted.
+#[cfg(windows)]
+pub fn enumerate() -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(config_dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut names = vec![];
+    for entry in entries {
+        let file_name = entry?.file_name();
+        if let Some(name) = file_name
+            .to_str()
+            .and_then(|name| name.strip_suffix(ENCRYPTED_EXTENSION))
+        {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Decrypts and parses the tunnel `name`.
+#[cfg(windows)]
+pub fn read(name: &str) -> io::Result<Tunnel> {
+    let path = config_dir().join(format!("{}{}", name, ENCRYPTED_EXTENSION));
+    let plaintext = dpapi::decrypt(&fs::read(path)?)?;
+    let text = std::str::from_utf8(&plaintext)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "config isn't valid UTF-8"))?;
+    Tunnel::parse(name, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+[Interface]
+PrivateKey = AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=
+ListenPort = 51820
+Address = 10.0.0.2/24, fd00::2/64
+DNS = 10.0.0.1, corp.example
+MTU = 1380
+
+[Peer]
+PublicKey = AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=
+AllowedIPs = 0.0.0.0/0, ::/0
+Endpoint = vpn.example.com:51820
+
+[Peer]
+PublicKey = AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=
+AllowedIPs = 10.1.0.0/16
+Endpoint = 192.0.2.3:51820
+";
+
+    #[test]
+    fn test_parse() {
+        let tunnel = Tunnel::parse("office-vpn-long-name", CONFIG).unwrap();
+        assert_eq!(tunnel.private_key, Some(Key([1u8; 32])));
+        assert_eq!(tunnel.listen_port, Some(51820));
+        assert_eq!(tunnel.addresses.len(), 2);
+        assert_eq!(tunnel.dns, vec!["10.0.0.1", "corp.example"]);
+        assert_eq!(tunnel.mtu, Some(1380));
+
+        assert_eq!(tunnel.peers.len(), 2);
+        assert_eq!(tunnel.peers[0].endpoint, None);
+        assert_eq!(
+            tunnel.endpoint_hosts,
+            vec![(Key([2u8; 32]), "vpn.example.com:51820".to_string())]
+        );
+        assert_eq!(
+            tunnel.peers[1].endpoint,
+            Some("192.0.2.3:51820".parse().unwrap())
+        );
+
+        let update = tunnel.to_update();
+        assert!(update.replace_peers);
+        assert_eq!(update.peers.len(), 2);
+    }
+
+    #[test]
+    fn test_network_plan() {
+        let plan = Tunnel::parse("office", CONFIG).unwrap().network_plan();
+        assert!(plan.default_route);
+        assert_eq!(plan.routes.len(), 3);
+
+        let commands = plan.powershell("office's vpn");
+        assert_eq!(
+            commands[1],
+            "New-NetIPAddress -InterfaceAlias 'office''s vpn' -IPAddress 10.0.0.2 -PrefixLength 24 -PolicyStore ActiveStore"
+        );
+        assert!(commands.contains(&"New-NetRoute -InterfaceAlias 'office''s vpn' -DestinationPrefix 10.1.0.0/16 -RouteMetric 0 -PolicyStore ActiveStore".to_string()));
+        assert!(commands.contains(&"Set-DnsClientServerAddress -InterfaceAlias 'office''s vpn' -ServerAddresses '10.0.0.1'".to_string()));
+        assert!(commands
+            .last()
+            .unwrap()
+            .starts_with("New-NetFirewallRule -DisplayName 'WireGuard office' -Direction Inbound -Protocol UDP -LocalPort 51820"));
+
+        // The resolved endpoint is routed around the default route, first.
+        assert_eq!(plan.endpoints, ["192.0.2.3".parse::<IpAddr>().unwrap()]);
+        assert!(commands[0].starts_with("$gateway = Get-NetRoute -DestinationPrefix '0.0.0.0/0'"));
+        assert!(commands[0].ends_with("New-NetRoute -DestinationPrefix 192.0.2.3/32 -InterfaceIndex $gateway.InterfaceIndex -NextHop $gateway.NextHop -PolicyStore ActiveStore"));
+        assert_eq!(
+            plan.deconfigure_powershell(),
+            [
+                "Get-NetFirewallRule -DisplayName 'WireGuard office' -PolicyStore ActiveStore -ErrorAction SilentlyContinue | Remove-NetFirewallRule",
+                "Get-NetRoute -DestinationPrefix 192.0.2.3/32 -PolicyStore ActiveStore -ErrorAction SilentlyContinue | Remove-NetRoute -Confirm:$false",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Tunnel::parse("t", "[Peer]\nPublicKey = x\n").is_err());
+        let error = Tunnel::parse("t", "[Interface]\nAddress = nope\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}